@@ -0,0 +1,167 @@
+use crate::config::read_config;
+use crate::ignore::Ignores;
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::worktree::{mode_from_metadata, pathspec_matches, read_worktree_contents, worktree_matches_index};
+use crate::write_object_to_store;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+// Collect the files below a directory that are not ignored, skipping nested repositories
+pub fn collect_files(directory: &str, ignores: &mut Ignores, force: bool, found: &mut BTreeSet<String>) {
+    let Ok(entries) = fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if directory.is_empty() && name == ".git" {
+            continue;
+        }
+        let path = if directory.is_empty() { name } else { format!("{}/{}", directory, name) };
+        let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+        if !force && ignores.is_ignored(&path, is_dir) {
+            continue;
+        }
+        if !is_dir {
+            found.insert(path);
+        } else if !Path::new(&path).join(".git").exists() {
+            collect_files(&path, ignores, force, found);
+        }
+    }
+}
+
+// Put a file's current contents into the index, replacing any conflict stages for it and
+// any entries a file or directory at this path would clash with
+fn stage_file(index: &mut Vec<IndexEntry>, path: &str, metadata: &fs::Metadata) -> anyhow::Result<()> {
+    let contents = read_worktree_contents(path, metadata)?;
+    let sha_hash = write_object_to_store(&contents, b"blob");
+    let directory_prefix = format!("{}/", path);
+    index.retain(|entry| {
+        entry.path != path && !entry.path.starts_with(&directory_prefix) && !path.starts_with(&format!("{}/", entry.path))
+    });
+    index.push(IndexEntry::from_metadata(path, mode_from_metadata(metadata), &sha_hash, metadata));
+    Ok(())
+}
+
+pub fn add_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "add").unwrap();
+    let mut all = false;
+    let mut update = false;
+    let mut force = false;
+    let mut dry_run = false;
+    let mut verbose = false;
+    let mut pathspecs = Vec::new();
+    let mut options_done = false;
+    for arg in &args[command_index + 1..] {
+        match arg.as_str() {
+            _ if options_done => pathspecs.push(arg.clone()),
+            "-A" | "--all" => all = true,
+            "-u" | "--update" => update = true,
+            "-f" | "--force" => force = true,
+            "-n" | "--dry-run" => dry_run = true,
+            "-v" | "--verbose" => verbose = true,
+            "--" => options_done = true,
+            _ => pathspecs.push(arg.clone()),
+        }
+    }
+    if pathspecs.is_empty() && !all && !update {
+        eprintln!("Nothing specified, nothing added.");
+        eprintln!("hint: Maybe you wanted to say 'git add .'?");
+        eprintln!("hint: Turn this message off by running");
+        eprintln!("hint: \"git config advice.addEmptyPathspec false\"");
+        return Ok(());
+    }
+    let pathspecs: Vec<String> = match pathspecs.is_empty() {
+        true => vec![".".to_string()],
+        false => pathspecs
+            .iter()
+            .map(|spec| spec.trim_start_matches("./").trim_end_matches('/').to_string())
+            .map(|spec| if spec.is_empty() { ".".to_string() } else { spec })
+            .collect(),
+    };
+
+    let mut index = read_index()?;
+    let tracked: BTreeSet<String> = index.iter().map(|entry| entry.path.clone()).collect();
+    let mut ignores = Ignores::new(&read_config());
+
+    // Tracked paths are always candidates so modifications and deletions get staged; new files
+    // come from walking the pathspecs unless only tracked files are being updated
+    let mut candidates: BTreeSet<String> = tracked
+        .iter()
+        .filter(|path| pathspecs.iter().any(|spec| pathspec_matches(spec, path)))
+        .cloned()
+        .collect();
+    let mut ignored = Vec::new();
+    for spec in &pathspecs {
+        if update {
+            break;
+        }
+        let metadata = match fs::symlink_metadata(if spec == "." { "." } else { spec }) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let has_tracked = candidates.iter().any(|path| pathspec_matches(spec, path));
+        if spec != "." && !force && !has_tracked && ignores.is_ignored(spec, metadata.is_dir()) {
+            ignored.push(spec.clone());
+        } else if metadata.is_dir() {
+            collect_files(if spec == "." { "" } else { spec }, &mut ignores, force, &mut candidates);
+        } else {
+            candidates.insert(spec.clone());
+        }
+    }
+
+    for spec in &pathspecs {
+        let matched = ignored.contains(spec) || candidates.iter().any(|path| pathspec_matches(spec, path));
+        if !matched && spec != "." {
+            anyhow::bail!("pathspec '{}' did not match any files", spec);
+        }
+    }
+
+    let mut changed = false;
+    for path in &candidates {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                let current: Vec<&IndexEntry> = index.iter().filter(|entry| entry.path == *path).collect();
+                if current.len() == 1 && current[0].stage() == 0 && worktree_matches_index(current[0]) {
+                    continue;
+                }
+                if verbose || dry_run {
+                    println!("add '{}'", path);
+                }
+                if !dry_run {
+                    stage_file(&mut index, path, &metadata)?;
+                    changed = true;
+                }
+            }
+            metadata => {
+                let is_submodule = index.iter().any(|entry| entry.path == *path && entry.mode == 0o160000);
+                if !tracked.contains(path) || (metadata.is_ok() && is_submodule) {
+                    continue;
+                }
+                if verbose || dry_run {
+                    println!("remove '{}'", path);
+                }
+                if !dry_run {
+                    index.retain(|entry| entry.path != *path);
+                    changed = true;
+                }
+            }
+        }
+    }
+    if changed {
+        write_index(&index)?;
+    }
+
+    if !ignored.is_empty() {
+        ignored.sort();
+        eprintln!("The following paths are ignored by one of your .gitignore files:");
+        for path in &ignored {
+            eprintln!("{}", path);
+        }
+        eprintln!("hint: Use -f if you really want to add them.");
+        eprintln!("hint: Turn this message off by running");
+        eprintln!("hint: \"git config advice.addIgnoredFile false\"");
+        std::process::exit(1);
+    }
+    Ok(())
+}
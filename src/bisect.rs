@@ -0,0 +1,1138 @@
+// Bisect: a binary search through history for the commit that changed some behaviour. The
+// search keeps its state in .git/BISECT_* files and under refs/bisect, and every step checks out
+// the commit that splits the commits left to test most evenly, counting for each candidate how
+// many of the others it can reach.
+
+use crate::checkout::{checkout_command, CheckoutConflict};
+use crate::log::{log_command, parse_revisions};
+use crate::reach::{is_ancestor, merge_bases_many};
+use crate::refs::{delete_ref, head_symbolic_target, list_refs, read_ref, resolve_ref, write_ref};
+use crate::revision::{peel_to_commit, resolve_revision};
+use crate::walk::{load_commit, Walk};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+const USAGE: &str = "usage: git bisect [help|start|bad|good|new|old|terms|skip|next|reset|replay|log|run]";
+
+const LONG_USAGE: &str = "
+git bisect help
+\tprint this long help message.
+git bisect start [--term-{new,bad}=<term> --term-{old,good}=<term>]
+\t\t [--no-checkout] [--first-parent] [<bad> [<good>...]] [--] [<pathspec>...]
+\treset bisect state and start bisection.
+git bisect (bad|new) [<rev>]
+\tmark <rev> a known-bad revision/
+\t\ta revision after change in a given property.
+git bisect (good|old) [<rev>...]
+\tmark <rev>... known-good revisions/
+\t\trevisions before change in a given property.
+git bisect terms [--term-good | --term-bad]
+\tshow the terms used for old and new commits (default: bad, good)
+git bisect skip [(<rev>|<range>)...]
+\tmark <rev>... untestable revisions.
+git bisect next
+\tfind next bisection to test and check it out.
+git bisect reset [<commit>]
+\tfinish bisection search and go back to commit.
+git bisect replay <logfile>
+\treplay bisection log.
+git bisect log
+\tshow bisect log.
+git bisect run <cmd>...
+\tuse <cmd>... to automatically bisect.
+
+Please use \"git help bisect\" to get the full man page.";
+
+const NEED_BAD_AND_GOOD: &str = "You need to give me at least one bad|new and good|old revision.
+You can use \"git bisect bad|new\" and \"git bisect good|old\" for that.";
+
+const NEED_START: &str = "You need to start by \"git bisect start\".
+You then need to give me at least one good|old and bad|new revision.
+You can use \"git bisect good|old\" and \"git bisect bad|new\" for that.";
+
+// Terms are the names of the two states the search tells apart, "bad" for commits after the
+// change and "good" for those before it unless the user chose others
+struct Terms {
+    bad: String,
+    good: String,
+}
+
+impl Default for Terms {
+    fn default() -> Terms {
+        Terms { bad: "bad".to_string(), good: "good".to_string() }
+    }
+}
+
+impl Terms {
+    fn read() -> Option<Terms> {
+        let contents = fs::read_to_string(bisect_path("TERMS")).ok()?;
+        let mut lines = contents.lines();
+        Some(Terms { bad: lines.next()?.to_string(), good: lines.next()?.to_string() })
+    }
+
+    fn write(&self) -> anyhow::Result<()> {
+        fs::write(bisect_path("TERMS"), format!("{}\n{}\n", self.bad, self.good))?;
+        Ok(())
+    }
+}
+
+// Outcome is how a bisect step ended, which decides the exit status of the command
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Continue,
+    Found,
+    MergeBaseCheckedOut,
+    Failed,
+    OnlySkippedLeft,
+    BadMergeBase,
+    NoTestableCommit,
+}
+
+impl Outcome {
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Continue | Outcome::Found | Outcome::MergeBaseCheckedOut => 0,
+            Outcome::Failed => 1,
+            Outcome::OnlySkippedLeft => 2,
+            Outcome::BadMergeBase => 3,
+            Outcome::NoTestableCommit => 4,
+        }
+    }
+
+    fn is_success(self) -> bool {
+        self.exit_code() == 0
+    }
+}
+
+fn error(message: &str) -> Outcome {
+    eprintln!("error: {}", message);
+    Outcome::Failed
+}
+
+fn bisect_path(name: &str) -> String {
+    format!(".git/BISECT_{}", name)
+}
+
+fn is_bisecting() -> bool {
+    fs::metadata(bisect_path("START")).map(|x| x.len() > 0).unwrap_or(false)
+}
+
+fn append_log(text: &str) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(bisect_path("LOG"))?;
+    file.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+fn log_commit(label: &str, hash: &str) -> anyhow::Result<()> {
+    let commit = load_commit(hash)?;
+    append_log(&format!("# {}: [{}] {}\n", label, commit.hash, commit.subject()))
+}
+
+// Quote arguments for the shell the way the log and BISECT_NAMES record them: each one in single
+// quotes after a space
+fn sq_quote(args: &[String]) -> String {
+    args.iter().map(|arg| format!(" '{}'", arg.replace('\'', "'\\''").replace('!', "'\\!'"))).collect()
+}
+
+// Split words quoted by sq_quote back into arguments
+fn sq_dequote(text: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = text.trim_start().chars().peekable();
+    while chars.peek().is_some() {
+        if chars.next()? != '\'' {
+            return None;
+        }
+        let mut word = String::new();
+        loop {
+            match chars.next()? {
+                '\'' => match chars.peek() {
+                    Some('\\') => {
+                        chars.next();
+                        word.push(chars.next()?);
+                        if chars.next()? != '\'' {
+                            return None;
+                        }
+                    }
+                    Some(' ') | None => break,
+                    Some(_) => return None,
+                },
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+    }
+    Some(words)
+}
+
+// The pathspecs the search is limited to
+fn read_names() -> Vec<String> {
+    let names = fs::read_to_string(bisect_path("NAMES")).unwrap_or_default();
+    let mut names = sq_dequote(names.trim_end_matches('\n')).unwrap_or_default();
+    if names.first().map(|x| x.as_str()) == Some("--") {
+        names.remove(0);
+    }
+    names
+}
+
+// The bad commit, the good ones and the skipped ones marked so far
+fn bisect_refs(terms: &Terms) -> (Option<String>, Vec<String>, Vec<String>) {
+    let bad = resolve_ref(&format!("refs/bisect/{}", terms.bad));
+    let good_prefix = format!("refs/bisect/{}-", terms.good);
+    let mut goods = Vec::new();
+    let mut skips = Vec::new();
+    for (name, hash) in list_refs() {
+        if name.starts_with(&good_prefix) {
+            goods.push(hash);
+        } else if name.starts_with("refs/bisect/skip-") {
+            skips.push(hash);
+        }
+    }
+    (bad, goods, skips)
+}
+
+// Run a checkout, reporting its failure as the checkout command itself would
+fn run_checkout(args: &[&str]) -> bool {
+    let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+    match checkout_command(&args) {
+        Ok(()) => true,
+        Err(error) => {
+            if let Some(conflict) = error.downcast_ref::<CheckoutConflict>() {
+                eprintln!("error: {}", conflict);
+            } else {
+                eprintln!("fatal: {:#}", error);
+            }
+            false
+        }
+    }
+}
+
+// Ask a yes/no question on the terminal, taking anything but an answer starting with "n" as yes
+fn confirm(question: &str) -> bool {
+    eprint!("{}", question);
+    let mut answer = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut answer);
+    !answer.starts_with(['n', 'N'])
+}
+
+// Remove every trace of a bisection
+fn clean_state() -> anyhow::Result<()> {
+    for (name, _) in list_refs() {
+        if name.starts_with("refs/bisect/") {
+            delete_ref(&name)?;
+        }
+    }
+    for name in ["EXPECTED_REV", "ANCESTORS_OK", "LOG", "TERMS", "NAMES", "RUN", "FIRST_PARENT", "HEAD", "START"] {
+        let _ = fs::remove_file(bisect_path(name));
+    }
+    Ok(())
+}
+
+// Commands other than start need a bisection to work in. Offer to start one from a terminal.
+fn autostart(terms: &mut Terms) -> anyhow::Result<Option<Outcome>> {
+    if is_bisecting() {
+        return Ok(None);
+    }
+    eprintln!("You need to start by \"git bisect start\"\n");
+    if !std::io::stdin().is_terminal() || !confirm("Do you want me to do it for you [Y/n]? ") {
+        return Ok(Some(Outcome::Failed));
+    }
+    *terms = Terms::default();
+    let outcome = start(terms, &[])?;
+    Ok(Some(outcome).filter(|outcome| !outcome.is_success()))
+}
+
+// The first good or bad mark fixes the terms of a bisection started without them
+fn check_and_set_terms(terms: &mut Terms, command: &str) -> anyhow::Result<Option<Outcome>> {
+    if ["skip", "start", "terms"].contains(&command) {
+        return Ok(None);
+    }
+    let has_terms = fs::metadata(bisect_path("TERMS")).map(|x| x.len() > 0).unwrap_or(false);
+    if has_terms {
+        if command != terms.bad && command != terms.good {
+            return Ok(Some(error(&format!("Invalid command: you're currently in a {}/{} bisect", terms.bad, terms.good))));
+        }
+        return Ok(None);
+    }
+    let chosen = match command {
+        "bad" | "good" => Terms::default(),
+        "new" | "old" => Terms { bad: "new".to_string(), good: "old".to_string() },
+        _ => return Ok(None),
+    };
+    *terms = chosen;
+    terms.write()?;
+    Ok(None)
+}
+
+// Record a mark: the ref for it and a line in the log. Marks made while starting are logged
+// without their command, since the start line that follows repeats them.
+fn bisect_write(terms: &Terms, state: &str, rev: &str, nolog: bool) -> anyhow::Result<Outcome> {
+    let refname = if state == terms.bad {
+        format!("refs/bisect/{}", state)
+    } else if state == terms.good || state == "skip" {
+        format!("refs/bisect/{}-{}", state, rev)
+    } else {
+        return Ok(error(&format!("Bad bisect_write argument: {}", state)));
+    };
+    let Ok(hash) = resolve_revision(rev) else {
+        return Ok(error(&format!("couldn't get the oid of the rev '{}'", rev)));
+    };
+    write_ref(&refname, &hash)?;
+    log_commit(state, &peel_to_commit(&hash)?)?;
+    if !nolog {
+        append_log(&format!("git bisect {} {}\n", state, rev))?;
+    }
+    Ok(Outcome::Continue)
+}
+
+// Say which marks the search is still waiting for, in the log as well
+fn print_status(terms: &Terms) -> anyhow::Result<()> {
+    let (bad, goods, _) = bisect_refs(terms);
+    let status = match (bad, goods.len()) {
+        (None, 0) => "status: waiting for both good and bad commits".to_string(),
+        (_, 0) => "status: waiting for good commit(s), bad commit known".to_string(),
+        (_, count) => format!("status: waiting for bad commit, {} good commit{} known", count, if count == 1 { "" } else { "s" }),
+    };
+    println!("{}", status);
+    append_log(&format!("# {}\n", status))
+}
+
+// Check that there are marks to bisect between. A search for the first bad commit can go on
+// without any good one, though it takes longer; anything else missing is an error when the
+// caller names the term it is about to use and silently stops it otherwise.
+fn next_check(terms: &Terms, current: Option<&str>) -> Option<Outcome> {
+    let (bad, goods, _) = bisect_refs(terms);
+    if bad.is_some() && !goods.is_empty() {
+        return None;
+    }
+    let Some(current) = current else {
+        return Some(Outcome::Failed);
+    };
+    if goods.is_empty() && bad.is_some() && current == terms.good {
+        eprintln!("warning: bisecting only with a {} commit", terms.bad);
+        if std::io::stdin().is_terminal() && !confirm("Are you sure [Y/n]? ") {
+            return Some(Outcome::Failed);
+        }
+        return None;
+    }
+    Some(error(if is_bisecting() { NEED_BAD_AND_GOOD } else { NEED_START }))
+}
+
+// Move on to the next commit to test once both kinds of marks are in, or else say what is missing
+fn auto_next(terms: &mut Terms) -> anyhow::Result<Outcome> {
+    if next_check(terms, None).is_some() {
+        print_status(terms)?;
+        return Ok(Outcome::Continue);
+    }
+    next(terms)
+}
+
+fn next(terms: &mut Terms) -> anyhow::Result<Outcome> {
+    if let Some(outcome) = autostart(terms)? {
+        return Ok(outcome);
+    }
+    let good = terms.good.clone();
+    if let Some(outcome) = next_check(terms, Some(&good)) {
+        return Ok(outcome);
+    }
+    let outcome = next_all(terms)?;
+    match outcome {
+        Outcome::Found => {
+            let bad = resolve_ref(&format!("refs/bisect/{}", terms.bad)).unwrap_or_default();
+            log_commit(&format!("first {} commit", terms.bad), &bad)?;
+        }
+        Outcome::OnlySkippedLeft => log_skipped_commits(terms)?,
+        _ => {}
+    }
+    Ok(outcome)
+}
+
+// When only skipped commits are left, log every commit the first bad one could be
+fn log_skipped_commits(terms: &Terms) -> anyhow::Result<()> {
+    append_log("# only skipped commits left to test\n")?;
+    let (bad, goods, _) = bisect_refs(terms);
+    let mut walk = Walk::default();
+    walk.include = bad.into_iter().collect();
+    walk.exclude = goods;
+    for hash in walk.run()? {
+        log_commit(&format!("possible first {} commit", terms.bad), &hash)?;
+    }
+    Ok(())
+}
+
+// Roughly how many more steps a search among this many commits takes
+fn estimate_steps(all: usize) -> usize {
+    if all < 3 {
+        return 0;
+    }
+    let n = all.ilog2() as usize;
+    let e = 1 << n;
+    let x = all - e;
+    if e < 3 * x {
+        n
+    } else {
+        n - 1
+    }
+}
+
+// The bad commit is the first one when nothing else is left to test, unless commits were
+// skipped: then any of those could be it too
+fn fail_if_skipped(terms: &Terms, tried: &[String], bad: Option<&str>) -> bool {
+    if tried.is_empty() {
+        return false;
+    }
+    println!("There are only 'skip'ped commits left to test.\nThe first {} commit could be any of:", terms.bad);
+    for hash in tried {
+        println!("{}", hash);
+    }
+    if let Some(bad) = bad {
+        println!("{}", bad);
+    }
+    println!("We cannot bisect more!");
+    true
+}
+
+// Find the next commit to test and check it out, or report the first bad commit
+fn next_all(terms: &Terms) -> anyhow::Result<Outcome> {
+    let (bad, goods, skips) = bisect_refs(terms);
+    let bad = bad.ok_or_else(|| anyhow::anyhow!("reading bisect refs failed"))?;
+    let no_checkout = read_ref("BISECT_HEAD").is_some();
+    let outcome = check_good_are_ancestors_of_bad(terms, &bad, &goods, &skips, no_checkout)?;
+    if outcome != Outcome::Continue {
+        return Ok(outcome);
+    }
+
+    let mut walk = Walk::default();
+    walk.include = vec![bad.clone()];
+    walk.exclude = goods;
+    walk.first_parent = Path::new(&bisect_path("FIRST_PARENT")).exists();
+    walk.paths = read_names();
+    let mut list = walk.run()?;
+    list.reverse();
+    let find_all = !skips.is_empty();
+    let (reaches, mut list, all) = find_bisection(&walk, list, find_all);
+
+    let mut tried = Vec::new();
+    if find_all {
+        let skipped: HashSet<&String> = skips.iter().collect();
+        if list.first().is_some_and(|first| skipped.contains(first)) {
+            let (skipped, kept): (Vec<String>, Vec<String>) = list.into_iter().partition(|hash| skipped.contains(hash));
+            tried = skipped;
+            list = skip_away(kept, &bad);
+        }
+    }
+    let Some(chosen) = list.first().cloned() else {
+        if fail_if_skipped(terms, &tried, None) {
+            return Ok(Outcome::OnlySkippedLeft);
+        }
+        println!("{} was both {} and {}", bad, terms.good, terms.bad);
+        return Ok(Outcome::Failed);
+    };
+    if all == 0 {
+        eprintln!("No testable commit found.\nMaybe you started with bad path arguments?");
+        return Ok(Outcome::NoTestableCommit);
+    }
+    if chosen == bad {
+        if fail_if_skipped(terms, &tried, Some(&bad)) {
+            return Ok(Outcome::OnlySkippedLeft);
+        }
+        println!("{} is the first {} commit", bad, terms.bad);
+        let args: Vec<String> = ["log", "-1", "--cc", "--stat", "--summary", "--format=medium", &bad].iter().map(|x| x.to_string()).collect();
+        log_command(&args)?;
+        return Ok(Outcome::Found);
+    }
+
+    // A search limited to paths can pick a commit reaching every candidate, leaving -1
+    let left = all as i64 - reaches - 1;
+    let steps = estimate_steps(all);
+    println!(
+        "Bisecting: {} revision{} left to test after this (roughly {} step{})",
+        left,
+        if left == 1 { "" } else { "s" },
+        steps,
+        if steps == 1 { "" } else { "s" }
+    );
+    check_out(&chosen, no_checkout)
+}
+
+// Make a commit the one to test: check it out, or with --no-checkout just point BISECT_HEAD at it
+fn check_out(hash: &str, no_checkout: bool) -> anyhow::Result<Outcome> {
+    write_ref("BISECT_EXPECTED_REV", hash)?;
+    if no_checkout {
+        write_ref("BISECT_HEAD", hash)?;
+    } else if !run_checkout(&["checkout", "-q", hash]) {
+        return Ok(Outcome::Failed);
+    }
+    println!("[{}] {}", hash, load_commit(hash)?.subject());
+    Ok(Outcome::Continue)
+}
+
+// Good commits are expected to be ancestors of the bad one. When one is not, the merge bases
+// between them have to be tested first: a bad one means the marks were mixed up, or that the
+// change being searched for is the fix rather than the breakage.
+fn check_good_are_ancestors_of_bad(terms: &Terms, bad: &str, goods: &[String], skips: &[String], no_checkout: bool) -> anyhow::Result<Outcome> {
+    let ancestors_ok = bisect_path("ANCESTORS_OK");
+    if Path::new(&ancestors_ok).is_file() || goods.is_empty() {
+        return Ok(Outcome::Continue);
+    }
+    let mut outcome = Outcome::Continue;
+    let mut all_ancestors = true;
+    for good in goods {
+        all_ancestors &= is_ancestor(good, bad)?;
+    }
+    if !all_ancestors {
+        let good_list = goods.join(" ");
+        for base in merge_bases_many(bad, goods)? {
+            if base == bad {
+                outcome = bad_merge_base(terms, bad, &good_list);
+                break;
+            } else if goods.contains(&base) {
+                continue;
+            } else if skips.contains(&base) {
+                eprintln!(
+                    "warning: the merge base between {} and [{}] must be skipped.\nSo we cannot be sure the first {} commit is between {} and {}.\nWe continue anyway.",
+                    bad, good_list, terms.bad, base, bad
+                );
+            } else {
+                println!("Bisecting: a merge base must be tested");
+                outcome = check_out(&base, no_checkout)?;
+                if outcome == Outcome::Continue {
+                    outcome = Outcome::MergeBaseCheckedOut;
+                }
+                break;
+            }
+        }
+    }
+    if outcome == Outcome::Continue {
+        fs::write(ancestors_ok, "")?;
+    }
+    Ok(outcome)
+}
+
+fn bad_merge_base(terms: &Terms, bad: &str, good_list: &str) -> Outcome {
+    let expected = fs::read_to_string(bisect_path("EXPECTED_REV")).unwrap_or_default();
+    if expected.trim_end() != bad {
+        eprintln!(
+            "Some {} revs are not ancestors of the {} rev.\ngit bisect cannot work properly in this case.\nMaybe you mistook {} and {} revs?",
+            terms.good, terms.bad, terms.good, terms.bad
+        );
+        return Outcome::Failed;
+    }
+    match (terms.bad.as_str(), terms.good.as_str()) {
+        ("bad", "good") => eprintln!("The merge base {} is bad.\nThis means the bug has been fixed between {} and [{}].", bad, bad, good_list),
+        ("new", "old") => eprintln!("The merge base {} is new.\nThe property has changed between {} and [{}].", bad, bad, good_list),
+        _ => eprintln!(
+            "The merge base {} is {}.\nThis means the first '{}' commit is between {} and [{}].",
+            bad, terms.bad, terms.good, bad, good_list
+        ),
+    }
+    Outcome::BadMergeBase
+}
+
+// Weigh the candidates, listed oldest first, by how many of them each one reaches, counting only
+// commits that change the paths the search is limited to. The best is the one closest to
+// reaching half of them; with find_all every changing candidate is returned, best first, so
+// skipped ones can be passed over. Returns the weight of the first commit along with the
+// commits and the number of candidates.
+fn find_bisection(walk: &Walk, list: Vec<String>, find_all: bool) -> (i64, Vec<String>, usize) {
+    let positions: HashMap<&str, usize> = list.iter().enumerate().map(|(i, hash)| (hash.as_str(), i)).collect();
+    let parents: Vec<Vec<usize>> = list.iter().map(|hash| walk.parents(hash).iter().filter_map(|parent| positions.get(parent.as_str()).copied()).collect()).collect();
+    let treesame: Vec<bool> = list.iter().map(|hash| walk.is_hidden(hash)).collect();
+    let nr = treesame.iter().filter(|x| !**x).count();
+
+    let (weights, halfway) = weigh(&parents, &treesame, nr, find_all);
+    let best = match halfway {
+        Some(index) => vec![index],
+        None => {
+            let mut ranked: Vec<(i64, usize)> = (0..list.len())
+                .filter(|i| !treesame[*i])
+                .map(|i| (weights[i].min(nr as i64 - weights[i]), i))
+                .collect();
+            if find_all {
+                ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| list[a.1].cmp(&list[b.1])));
+                ranked.into_iter().map(|(_, i)| i).collect()
+            } else {
+                let mut best: Option<(i64, usize)> = None;
+                for (distance, i) in ranked {
+                    if best.is_none_or(|(best_distance, _)| distance > best_distance) {
+                        best = Some((distance, i));
+                    }
+                }
+                best.map(|(_, i)| vec![i]).unwrap_or_default()
+            }
+        }
+    };
+    match best.first() {
+        Some(first) => (weights[*first], best.iter().map(|i| list[*i].clone()).collect(), nr),
+        None => (0, list, nr),
+    }
+}
+
+// Work out each candidate's weight: roots weigh one, commits with a single parent one more than
+// it and merges are counted out. Stops early at a commit that reaches exactly half, which
+// cannot be bettered, unless every weight is wanted.
+fn weigh(parents: &[Vec<usize>], treesame: &[bool], nr: usize, find_all: bool) -> (Vec<i64>, Option<usize>) {
+    let halfway = |weight: i64, i: usize| !find_all && !treesame[i] && (2 * weight - nr as i64).abs() <= 1;
+    let mut weights = vec![0i64; parents.len()];
+    let mut counted = 0;
+    for (i, weight) in weights.iter_mut().enumerate() {
+        match parents[i].len() {
+            0 if !treesame[i] => {
+                *weight = 1;
+                counted += 1;
+            }
+            0 => {}
+            1 => *weight = -1,
+            _ => *weight = -2,
+        }
+    }
+    for (i, commit_parents) in parents.iter().enumerate() {
+        if commit_parents.len() < 2 {
+            continue;
+        }
+        weights[i] = reach_count(parents, treesame, i) as i64;
+        if halfway(weights[i], i) {
+            return (weights, Some(i));
+        }
+        counted += 1;
+    }
+    while counted < nr {
+        let mut progress = false;
+        for (i, commit_parents) in parents.iter().enumerate() {
+            if weights[i] >= 0 {
+                continue;
+            }
+            let Some(parent) = commit_parents.iter().find(|parent| weights[**parent] >= 0) else {
+                continue;
+            };
+            progress = true;
+            if treesame[i] {
+                weights[i] = weights[*parent];
+            } else {
+                weights[i] = weights[*parent] + 1;
+                counted += 1;
+            }
+            if halfway(weights[i], i) {
+                return (weights, Some(i));
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    (weights, None)
+}
+
+// How many candidates that change the paths a commit reaches, itself included
+fn reach_count(parents: &[Vec<usize>], treesame: &[bool], start: usize) -> usize {
+    let mut seen = HashSet::new();
+    let mut pending = vec![start];
+    let mut count = 0;
+    while let Some(i) = pending.pop() {
+        if seen.insert(i) {
+            count += usize::from(!treesame[i]);
+            pending.extend(parents[i].iter().copied());
+        }
+    }
+    count
+}
+
+// Git's pseudo random choice of how far past skipped commits to move
+fn pseudo_random(count: i32) -> i32 {
+    let count = (count as u32).wrapping_mul(1103515245).wrapping_add(12345);
+    ((count / 65536) % 32768) as i32
+}
+
+fn square_root(value: i32) -> i32 {
+    if value == 0 {
+        return 0;
+    }
+    let value = value as f32;
+    let mut x = value;
+    loop {
+        let y = (x + value / x) / 2.0;
+        let difference = (y - x).abs();
+        x = y;
+        if difference < 0.5 {
+            return x as i32;
+        }
+    }
+}
+
+// The best commit to test was skipped: pick another one some way down the ranking instead, so
+// repeated skips do not keep landing next to each other
+fn skip_away(list: Vec<String>, bad: &str) -> Vec<String> {
+    let count = list.len() as i32;
+    let prn = pseudo_random(count);
+    let index = (count * prn / 32768) * square_root(prn) / square_root(32768);
+    let chosen = match list.get(index as usize) {
+        Some(hash) if hash != bad => index as usize,
+        Some(_) => (index as usize).saturating_sub(1),
+        None => 0,
+    };
+    list.into_iter().skip(chosen).collect()
+}
+
+fn start(terms: &mut Terms, args: &[String]) -> anyhow::Result<Outcome> {
+    let has_double_dash = args.iter().any(|x| x == "--");
+    let mut no_checkout = false;
+    let mut first_parent = false;
+    let mut must_write_terms = false;
+    let mut revs = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        if arg == "--" {
+            break;
+        } else if arg == "--no-checkout" {
+            no_checkout = true;
+        } else if arg == "--first-parent" {
+            first_parent = true;
+        } else if let Some(name) = arg.strip_prefix("--term-good=").or(arg.strip_prefix("--term-old=")) {
+            must_write_terms = true;
+            terms.good = name.to_string();
+        } else if let Some(name) = arg.strip_prefix("--term-bad=").or(arg.strip_prefix("--term-new=")) {
+            must_write_terms = true;
+            terms.bad = name.to_string();
+        } else if ["--term-good", "--term-old", "--term-bad", "--term-new"].contains(&arg) {
+            index += 1;
+            let Some(name) = args.get(index) else {
+                return Ok(error("'' is not a valid term"));
+            };
+            must_write_terms = true;
+            if arg == "--term-good" || arg == "--term-old" {
+                terms.good = name.clone();
+            } else {
+                terms.bad = name.clone();
+            }
+        } else if arg.starts_with("--") {
+            return Ok(error(&format!("unrecognized option: '{}'", arg)));
+        } else if let Ok(commit) = resolve_revision(arg).and_then(|hash| peel_to_commit(&hash)) {
+            revs.push(commit);
+        } else if has_double_dash {
+            anyhow::bail!("'{}' does not appear to be a valid revision", arg);
+        } else {
+            break;
+        }
+        index += 1;
+    }
+    let pathspec = &args[index..];
+    must_write_terms |= !revs.is_empty();
+
+    let head = resolve_ref("HEAD").ok_or_else(|| anyhow::anyhow!("bad HEAD - I need a HEAD"))?;
+    let start_head = if is_bisecting() {
+        // Go back to where the bisection being replaced started
+        let start_head = fs::read_to_string(bisect_path("START"))?.trim().to_string();
+        if !no_checkout && !run_checkout(&["checkout", &start_head]) {
+            return Ok(error(&format!("checking out '{}' failed. Try 'git bisect start <valid-branch>'.", start_head)));
+        }
+        start_head
+    } else {
+        match head_symbolic_target() {
+            Some(branch) => match branch.strip_prefix("refs/heads/") {
+                Some(name) => name.to_string(),
+                None => return Ok(error("bad HEAD - strange symbolic ref")),
+            },
+            None => head.clone(),
+        }
+    };
+
+    clean_state()?;
+    fs::write(bisect_path("START"), format!("{}\n", start_head))?;
+    if first_parent {
+        fs::write(bisect_path("FIRST_PARENT"), "\n")?;
+    }
+    if no_checkout {
+        let Ok(hash) = resolve_revision(&start_head) else {
+            clean_state()?;
+            return Ok(error(&format!("invalid ref: '{}'", start_head)));
+        };
+        write_ref("BISECT_HEAD", &hash)?;
+    }
+    let names = if pathspec.len() > 1 { sq_quote(pathspec) } else { String::new() };
+    fs::write(bisect_path("NAMES"), format!("{}\n", names))?;
+    for (i, rev) in revs.iter().enumerate() {
+        let state = if i == 0 { terms.bad.clone() } else { terms.good.clone() };
+        if bisect_write(terms, &state, rev, true)? != Outcome::Continue {
+            clean_state()?;
+            return Ok(Outcome::Failed);
+        }
+    }
+    if must_write_terms {
+        terms.write()?;
+    }
+    append_log(&format!("git bisect start{}\n", sq_quote(args)))?;
+
+    let outcome = auto_next(terms)?;
+    if !outcome.is_success() {
+        clean_state()?;
+    }
+    Ok(outcome)
+}
+
+// Mark commits good, bad or skipped, then go on to the next one to test. Without a revision
+// the mark is for the commit being tested.
+fn mark(terms: &mut Terms, state: &str, args: &[String]) -> anyhow::Result<Outcome> {
+    if let Some(outcome) = autostart(terms)? {
+        return Ok(outcome);
+    }
+    if let Some(outcome) = check_and_set_terms(terms, state)? {
+        return Ok(outcome);
+    }
+    if state != terms.good && state != terms.bad && state != "skip" {
+        return Ok(Outcome::Failed);
+    }
+    if args.len() > 1 && state == terms.bad {
+        return Ok(error(&format!("'git bisect {}' can take only one argument.", terms.bad)));
+    }
+    let mut revs = Vec::new();
+    if args.is_empty() {
+        let (head, hash) = match resolve_ref("BISECT_HEAD") {
+            Some(hash) => ("BISECT_HEAD", Some(hash)),
+            None => ("HEAD", resolve_ref("HEAD")),
+        };
+        match hash {
+            Some(hash) => revs.push(hash),
+            None => return Ok(error(&format!("Bad rev input: {}", head))),
+        }
+    }
+    for arg in args {
+        let Ok(hash) = resolve_revision(arg) else {
+            return Ok(error(&format!("Bad rev input: {}", arg)));
+        };
+        let commit = peel_to_commit(&hash).map_err(|_| anyhow::anyhow!("Bad rev input (not a commit): {}", arg))?;
+        revs.push(commit);
+    }
+
+    let expected = fs::read_to_string(bisect_path("EXPECTED_REV")).unwrap_or_default();
+    let mut verify_expected = expected.trim_end().len() == 40;
+    for rev in &revs {
+        if bisect_write(terms, state, rev, false)? != Outcome::Continue {
+            return Ok(Outcome::Failed);
+        }
+        // Marking anything but the commit bisect chose means the ancestry has to be checked again
+        if verify_expected && rev != expected.trim_end() {
+            let _ = fs::remove_file(bisect_path("ANCESTORS_OK"));
+            let _ = fs::remove_file(bisect_path("EXPECTED_REV"));
+            verify_expected = false;
+        }
+    }
+    auto_next(terms)
+}
+
+// Skip commits, expanding ranges into the commits in them
+fn skip(terms: &mut Terms, args: &[String]) -> anyhow::Result<Outcome> {
+    let mut revs = Vec::new();
+    for arg in args {
+        if !arg.contains("..") {
+            revs.push(arg.clone());
+            continue;
+        }
+        let revisions = parse_revisions(std::slice::from_ref(arg))?;
+        let mut walk = Walk::default();
+        walk.include = revisions.include;
+        walk.exclude = revisions.exclude;
+        revs.extend(walk.run()?);
+    }
+    mark(terms, "skip", &revs)
+}
+
+fn reset(commit: Option<&String>) -> anyhow::Result<Outcome> {
+    if !Path::new(&bisect_path("START")).exists() {
+        println!("We are not bisecting.");
+        return Ok(Outcome::Continue);
+    }
+    let branch = match commit {
+        Some(commit) => {
+            if resolve_revision(commit).and_then(|hash| peel_to_commit(&hash)).is_err() {
+                return Ok(error(&format!("'{}' is not a valid commit", commit)));
+            }
+            commit.clone()
+        }
+        None => fs::read_to_string(bisect_path("START"))?.trim_end().to_string(),
+    };
+    if read_ref("BISECT_HEAD").is_none() && !run_checkout(&["checkout", &branch]) {
+        return Ok(error(&format!("could not check out original HEAD '{}'. Try 'git bisect reset <commit>'.", branch)));
+    }
+    clean_state()?;
+    Ok(Outcome::Continue)
+}
+
+fn terms_command(args: &[String]) -> Outcome {
+    let Some(terms) = Terms::read() else {
+        return error("no terms defined");
+    };
+    match args {
+        [] => println!("Your current terms are {} for the old state\nand {} for the new state.", terms.good, terms.bad),
+        [option] if option == "--term-good" || option == "--term-old" => println!("{}", terms.good),
+        [option] if option == "--term-bad" || option == "--term-new" => println!("{}", terms.bad),
+        [option] => {
+            return error(&format!(
+                "invalid argument {} for 'git bisect terms'.\nSupported options are: --term-good|--term-old and --term-bad|--term-new.",
+                option
+            ))
+        }
+        _ => return error("--bisect-terms requires 0 or 1 argument"),
+    }
+    Outcome::Continue
+}
+
+fn log() -> Outcome {
+    match fs::read(bisect_path("LOG")) {
+        Ok(contents) if !contents.is_empty() => {
+            let _ = std::io::stdout().write_all(&contents);
+            Outcome::Continue
+        }
+        _ => error("We are not bisecting."),
+    }
+}
+
+// Start over and redo the marks a bisect log records
+fn replay(terms: &mut Terms, file: &str) -> anyhow::Result<Outcome> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) if !contents.is_empty() => contents,
+        _ => return Ok(error(&format!("cannot read file '{}' for replaying", file))),
+    };
+    if reset(None)? != Outcome::Continue {
+        return Ok(Outcome::Failed);
+    }
+    for line in contents.lines() {
+        let line = line.trim_start_matches([' ', '\t']);
+        let Some(rest) = line.strip_prefix("git bisect").or(line.strip_prefix("git-bisect")) else {
+            continue;
+        };
+        if !rest.starts_with([' ', '\t']) {
+            continue;
+        }
+        let rest = rest.trim_start_matches([' ', '\t']);
+        let (command, rev) = rest.split_once([' ', '\t']).unwrap_or((rest, ""));
+        let rev = rev.trim_start_matches([' ', '\t']);
+        *terms = Terms::read().unwrap_or_default();
+        if check_and_set_terms(terms, command)?.is_some() {
+            return Ok(Outcome::Failed);
+        }
+        let outcome = if command == "start" {
+            let args = sq_dequote(rev).unwrap_or_default();
+            *terms = Terms::default();
+            start(terms, &args)?
+        } else if command == terms.good || command == terms.bad || command == "skip" {
+            bisect_write(terms, command, rev, false)?
+        } else if command == "terms" {
+            let args = sq_dequote(rev).unwrap_or_else(|| rev.split_whitespace().map(|x| x.to_string()).collect());
+            terms_command(&args)
+        } else {
+            error(&format!("'{}'?? what are you talking about?", command))
+        };
+        if !outcome.is_success() {
+            return Ok(Outcome::Failed);
+        }
+    }
+    auto_next(terms)
+}
+
+// Run a command through the shell, returning its exit status the way a shell reports it
+fn run_shell(command: &str) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    match std::process::Command::new("sh").arg("-c").arg(command).arg(command).status() {
+        Ok(status) => status.code().or(status.signal().map(|signal| signal + 128)).unwrap_or(-1),
+        Err(_) => -1,
+    }
+}
+
+// Exit codes 126 and 127 come from a shell that could not run the command as often as from the
+// command itself, so the first time one turns up the command is tried on a good commit too
+fn verify_good(terms: &Terms, command: &str) -> anyhow::Result<Option<i32>> {
+    let (_, goods, _) = bisect_refs(terms);
+    let no_checkout = read_ref("BISECT_HEAD").is_some();
+    let (Some(good), Some(current)) = (goods.first(), resolve_ref(if no_checkout { "BISECT_HEAD" } else { "HEAD" })) else {
+        return Ok(None);
+    };
+    if check_out(good, no_checkout)? != Outcome::Continue {
+        return Ok(None);
+    }
+    println!("running {}", command);
+    let status = run_shell(command);
+    check_out(&current, no_checkout)?;
+    Ok(Some(status))
+}
+
+// Let a command decide whether each commit is good (exit 0), bad (1 to 127 other than 125) or
+// cannot be tested (125) until the first bad commit is found
+fn run(terms: &mut Terms, args: &[String]) -> anyhow::Result<Outcome> {
+    if next_check(terms, None).is_some() {
+        return Ok(Outcome::Failed);
+    }
+    if args.is_empty() {
+        return Ok(error("bisect run failed: no command provided."));
+    }
+    let command = sq_quote(args);
+    let mut first_run = true;
+    loop {
+        println!("running {}", command);
+        let status = run_shell(&command);
+        if first_run && (status == 126 || status == 127) {
+            first_run = false;
+            match verify_good(terms, &command)? {
+                None => return Ok(error(&format!("unable to verify '{}' on good revision", command))),
+                Some(good_status) if good_status == status => return Ok(error(&format!("bogus exit code {} for good revision", status))),
+                Some(_) => {}
+            }
+        }
+        if !(0..128).contains(&status) {
+            return Ok(error(&format!("bisect run failed: exit code {} from '{}' is < 0 or >= 128", status, command)));
+        }
+        let state = match status {
+            125 => "skip".to_string(),
+            0 => terms.good.clone(),
+            _ => terms.bad.clone(),
+        };
+        let outcome = mark(terms, &state, &[])?;
+        match outcome {
+            Outcome::Continue => continue,
+            Outcome::OnlySkippedLeft => eprintln!("error: bisect run cannot continue any more"),
+            Outcome::MergeBaseCheckedOut => {
+                println!("bisect run success");
+                return Ok(Outcome::Continue);
+            }
+            Outcome::Found => {
+                println!("bisect found first bad commit");
+                return Ok(Outcome::Continue);
+            }
+            _ => eprintln!("error: bisect run failed: 'git bisect {}' exited with error code {}", state, outcome.exit_code()),
+        }
+        return Ok(outcome);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(129);
+}
+
+pub fn bisect_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "bisect").unwrap();
+    let args = &args[command_index + 1..];
+    let Some(subcommand) = args.first() else {
+        usage();
+    };
+    let rest = &args[1..];
+    let mut terms = Terms::read().unwrap_or_default();
+
+    let outcome = match subcommand.as_str() {
+        "help" | "-h" => {
+            println!("{}\n{}", USAGE, LONG_USAGE);
+            Outcome::Continue
+        }
+        "start" => {
+            terms = Terms::default();
+            start(&mut terms, rest)?
+        }
+        "skip" => skip(&mut terms, rest)?,
+        "next" => next(&mut terms)?,
+        "reset" if rest.len() > 1 => error("--bisect-reset requires either no argument or a commit"),
+        "reset" => reset(rest.first())?,
+        "terms" => terms_command(rest),
+        "log" => log(),
+        "replay" => match rest {
+            [file] => replay(&mut terms, file)?,
+            _ => error("no logfile given"),
+        },
+        "run" => run(&mut terms, rest)?,
+        state if ["bad", "good", "new", "old"].contains(&state) || state == terms.bad || state == terms.good => mark(&mut terms, state, rest)?,
+        other => {
+            eprintln!("error: unknown subcommand: `{}'", other);
+            usage();
+        }
+    };
+    match outcome.exit_code() {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{commit, repository, tree};
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn weights() {
+        // A line of four commits, oldest first
+        let line = [vec![], vec![0], vec![1], vec![2]];
+        assert_eq!(weigh(&line, &[false; 4], 4, true), (vec![1, 2, 3, 4], None));
+        assert_eq!(weigh(&line, &[false; 4], 4, false), (vec![1, 2, -1, -1], Some(1)));
+
+        // A merge counts everything it reaches, and commits not touching the paths count for nothing
+        let merge = [vec![], vec![0], vec![0], vec![1, 2]];
+        assert_eq!(weigh(&merge, &[false; 4], 4, true), (vec![1, 2, 2, 4], None));
+        assert_eq!(weigh(&merge, &[false, true, false, false], 3, true), (vec![1, 1, 2, 3], None));
+        assert_eq!(reach_count(&merge, &[false, true, false, false], 3), 3);
+    }
+
+    #[test]
+    fn steps_and_skips() {
+        let estimates: Vec<usize> = [1, 2, 3, 4, 6, 7, 1024].iter().map(|all| estimate_steps(*all)).collect();
+        assert_eq!(estimates, [0, 0, 1, 1, 2, 2, 9]);
+        assert_eq!(pseudo_random(5), 18655);
+        assert_eq!(square_root(32768), 181);
+        assert_eq!(skip_away(strings(&["a", "b", "c", "d", "e"]), "e"), strings(&["b", "c", "d", "e"]));
+        assert_eq!(skip_away(strings(&["a", "b", "c", "d", "e", "f", "g"]), "g"), strings(&["d", "e", "f", "g"]));
+        assert_eq!(skip_away(strings(&["a", "b", "c", "d", "e", "f", "g"]), "d"), strings(&["c", "d", "e", "f", "g"]));
+    }
+
+    #[test]
+    fn quoting() {
+        let args = strings(&["--", "a b", "it's", "wow!"]);
+        let quoted = sq_quote(&args);
+        assert_eq!(quoted, " '--' 'a b' 'it'\\''s' 'wow'\\!''");
+        assert_eq!(sq_dequote(&quoted), Some(args));
+        assert_eq!(sq_dequote("'open"), None);
+        assert_eq!(sq_dequote("bare"), None);
+    }
+
+    #[test]
+    fn search() {
+        let _repository = repository();
+        let mut commits: Vec<String> = Vec::new();
+        for time in 1..=7 {
+            let parents: Vec<&str> = commits.last().map(|x| x.as_str()).into_iter().collect();
+            commits.push(commit(&tree(&[("f", &time.to_string())]), &parents, time, &format!("c{}", time)));
+        }
+        let terms = Terms::default();
+        let mark = |state: &str, hash: &str| {
+            let name = if state == "bad" { "refs/bisect/bad".to_string() } else { format!("refs/bisect/{}-{}", state, hash) };
+            write_ref(&name, hash).unwrap();
+        };
+        mark("good", &commits[0]);
+        mark("bad", &commits[6]);
+        write_ref("BISECT_HEAD", &commits[6]).unwrap();
+
+        // Each step halves the commits left, testing them without checking them out
+        let mut tested = Vec::new();
+        for bad in [true, false] {
+            assert!(next_all(&terms).unwrap() == Outcome::Continue);
+            let head = resolve_ref("BISECT_HEAD").unwrap();
+            tested.push(commits.iter().position(|hash| *hash == head).unwrap());
+            mark(if bad { "bad" } else { "good" }, &head);
+        }
+        assert_eq!(tested, [3, 2]);
+        assert!(next_all(&terms).unwrap() == Outcome::Found);
+        assert_eq!(resolve_ref("refs/bisect/bad").as_deref(), Some(commits[3].as_str()));
+
+        // With the commits between skipped there is no telling which was first
+        delete_ref("refs/bisect/bad").unwrap();
+        mark("bad", &commits[3]);
+        delete_ref(&format!("refs/bisect/good-{}", commits[2])).unwrap();
+        mark("skip", &commits[2]);
+        mark("good", &commits[1]);
+        assert!(next_all(&terms).unwrap() == Outcome::OnlySkippedLeft);
+    }
+}
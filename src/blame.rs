@@ -0,0 +1,1423 @@
+use crate::config::{read_config, Config};
+use crate::date::{format_date, DateFormat};
+use crate::diff::{quote_path, ChangeKind, FileChange, TreeDiff, NULL_HASH};
+use crate::ident::local_offset;
+use crate::index::read_index;
+use crate::patch::side_contents;
+use crate::refs::{read_ref, resolve_ref};
+use crate::rename::{Detect, Renames};
+use crate::revision::{peel_to_commit, reachable_commits, resolve_revision};
+use crate::walk::{abbreviate_to, basic_to_extended, load_commit, Commit, Signature};
+use crate::worktree::{flatten_tree, mode_from_metadata, read_worktree_contents};
+use crate::xdiff::{diff_lines, split_lines, Algorithm, Change};
+use crate::xmerge::Whitespace;
+use crate::{hash_object_contents, option_value, read_object_from_store, GitObjectType};
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::rc::Rc;
+
+// Blocks of lines with this many alphanumeric characters or fewer are not looked for when
+// searching for moved and copied lines
+const MOVE_SCORE: usize = 20;
+const COPY_SCORE: usize = 40;
+
+// Entry is a run of lines of the blamed file together with the origin currently suspected of
+// introducing them. lno counts in the final file and s_lno in the suspect's version.
+#[derive(Clone, Debug)]
+struct Entry {
+    lno: usize,
+    count: usize,
+    s_lno: usize,
+    origin: usize,
+    ignored: bool,
+    unblamable: bool,
+}
+
+impl Entry {
+    // The lines from..to of the suspect's version that this entry covers
+    fn piece(&self, from: usize, to: usize) -> Entry {
+        Entry { lno: self.lno + from - self.s_lno, count: to - from, s_lno: from, ..self.clone() }
+    }
+}
+
+// Origin is a file as it was at one commit. suspects are the entries waiting to be passed on
+// to its parents, and previous is the parent version the file was compared with first.
+struct Origin {
+    commit: String,
+    path: String,
+    mode: u32,
+    blob: String,
+    contents: Option<Rc<Vec<u8>>>,
+    previous: Option<usize>,
+    suspects: Vec<Entry>,
+    guilty: bool,
+}
+
+// Split is an entry cut around a block of lines found in another file: the part before the
+// block, the block blamed on that file, and the part after it
+struct Split {
+    before: Option<Entry>,
+    matched: Entry,
+    after: Option<Entry>,
+    score: usize,
+}
+
+// Options are the switches of blame and annotate
+struct Options {
+    annotate: bool,
+    porcelain: bool,
+    line_porcelain: bool,
+    show_name: bool,
+    show_number: bool,
+    no_author: bool,
+    show_email: bool,
+    long_names: bool,
+    raw_time: bool,
+    blank_boundary: bool,
+    show_root: bool,
+    date_format: DateFormat,
+    abbrev: Option<usize>,
+    whitespace: Whitespace,
+    moves: bool,
+    copies: usize,
+    move_score: usize,
+    copy_score: usize,
+    reverse: bool,
+    ranges: Vec<String>,
+    ignore_revs: Vec<String>,
+    ignore_revs_files: Vec<String>,
+    mark_ignored: bool,
+    mark_unblamable: bool,
+}
+
+impl Options {
+    fn new(config: &Config) -> anyhow::Result<Options> {
+        Ok(Options {
+            annotate: false,
+            porcelain: false,
+            line_porcelain: false,
+            show_name: false,
+            show_number: false,
+            no_author: false,
+            show_email: config.get_bool("blame.showEmail").unwrap_or(false),
+            long_names: false,
+            raw_time: false,
+            blank_boundary: config.get_bool("blame.blankBoundary").unwrap_or(false),
+            show_root: config.get_bool("blame.showRoot").unwrap_or(false),
+            date_format: config.get("blame.date").map(DateFormat::parse).transpose()?.unwrap_or(DateFormat::Iso),
+            abbrev: None,
+            whitespace: Whitespace::default(),
+            moves: false,
+            copies: 0,
+            move_score: MOVE_SCORE,
+            copy_score: COPY_SCORE,
+            reverse: false,
+            ranges: Vec::new(),
+            ignore_revs: Vec::new(),
+            ignore_revs_files: config.get_all("blame.ignoreRevsFile").into_iter().map(|x| x.to_string()).collect(),
+            mark_ignored: config.get_bool("blame.markIgnoredLines").unwrap_or(false),
+            mark_unblamable: config.get_bool("blame.markUnblamableLines").unwrap_or(false),
+        })
+    }
+
+    // Switches that take no value and may be bundled, as in "-sn"
+    fn set_flag(&mut self, flag: char) -> bool {
+        match flag {
+            'c' => self.annotate = true,
+            'p' => self.porcelain = true,
+            'f' => self.show_name = true,
+            'n' => self.show_number = true,
+            's' => self.no_author = true,
+            'e' => self.show_email = true,
+            'l' => self.long_names = true,
+            't' => self.raw_time = true,
+            'b' => self.blank_boundary = true,
+            'w' => self.whitespace.all = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn blame_usage() -> ! {
+    eprintln!("usage: git blame [<options>] [<rev-opts>] [<rev>] [--] <file>");
+    std::process::exit(129);
+}
+
+// The score given to -M and -C, a count of alphanumeric characters
+fn parse_score(value: &str) -> usize {
+    match value.parse() {
+        Ok(score) => score,
+        Err(_) => {
+            eprintln!("error: invalid argument to blame: {}", value);
+            blame_usage();
+        }
+    }
+}
+
+// How many alphanumeric characters lines hold, the measure of whether a block of lines is
+// worth attributing to a move or copy
+fn alnum_count(lines: &[Vec<u8>]) -> usize {
+    lines.iter().flatten().filter(|x| x.is_ascii_alphanumeric()).count()
+}
+
+// Find a file in a tree by its slash separated path
+fn tree_file(tree: &str, path: &str) -> anyhow::Result<Option<(u32, String)>> {
+    let mut tree = tree.to_string();
+    let mut components = path.split('/').peekable();
+    while let Some(name) = components.next() {
+        let leaves = match read_object_from_store(&tree)? {
+            GitObjectType::Tree(tree) => tree.leaves,
+            _ => return Ok(None),
+        };
+        let Some(leaf) = leaves.into_iter().find(|leaf| leaf.path == name) else {
+            return Ok(None);
+        };
+        let mode = u32::from_str_radix(&String::from_utf8_lossy(&leaf.mode), 8)?;
+        if components.peek().is_none() {
+            return Ok(Some((mode, leaf.sha_hash)));
+        }
+        if mode != 0o040000 {
+            return Ok(None);
+        }
+        tree = leaf.sha_hash;
+    }
+    Ok(None)
+}
+
+// Whether a mode is that of a regular file or symlink, whose contents can be blamed
+fn is_file_mode(mode: u32) -> bool {
+    matches!(mode & 0o170000, 0o100000 | 0o120000)
+}
+
+// The differences between a tree and the index, standing in for the tree of the working
+// tree commit, sorted by path
+fn index_changes(tree: &str) -> anyhow::Result<Vec<FileChange>> {
+    let files = flatten_tree(tree)?;
+    let index: BTreeMap<String, (u32, String)> = read_index()?
+        .into_iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.path, (entry.mode, entry.sha_hash)))
+        .collect();
+    let mut paths: Vec<&String> = files.keys().chain(index.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut changes = Vec::new();
+    for path in paths {
+        let old = files.get(path).map(|entry| (entry.mode, entry.sha_hash.as_str()));
+        let new = index.get(path).map(|(mode, hash)| (*mode, hash.as_str()));
+        let kind = match (old, new) {
+            (Some(old), Some(new)) if old == new => continue,
+            (Some(_), Some(_)) => ChangeKind::Modified,
+            (Some(_), None) => ChangeKind::Deleted,
+            (None, _) => ChangeKind::Added,
+        };
+        let (old_mode, old_hash) = old.unwrap_or((0, NULL_HASH));
+        let (new_mode, new_hash) = new.unwrap_or((0, NULL_HASH));
+        changes.push(FileChange {
+            kind,
+            old_path: path.clone(),
+            new_path: path.clone(),
+            old_mode,
+            new_mode,
+            old_hash: old_hash.to_string(),
+            new_hash: new_hash.to_string(),
+            score: 0,
+        });
+    }
+    Ok(changes)
+}
+
+// Scoreboard tracks the blame of one file: the origins seen so far, the commits still to be
+// looked at, newest first, and the entries whose origin has been found
+struct Scoreboard {
+    final_lines: Vec<Vec<u8>>,
+    origins: Vec<Origin>,
+    origin_ids: HashMap<(String, String), usize>,
+    commit_origins: HashMap<String, Vec<usize>>,
+    commits: HashMap<String, Rc<Commit>>,
+    queue: BinaryHeap<(i64, Reverse<usize>, String)>,
+    sequence: usize,
+    children: HashMap<String, Vec<String>>,
+    uninteresting: HashSet<String>,
+    ignored: HashSet<String>,
+    renames: Renames,
+    options: Options,
+    guilty: Vec<Entry>,
+}
+
+impl Scoreboard {
+    fn commit(&mut self, hash: &str) -> anyhow::Result<Rc<Commit>> {
+        if let Some(commit) = self.commits.get(hash) {
+            return Ok(commit.clone());
+        }
+        let commit = Rc::new(load_commit(hash)?);
+        self.commits.insert(hash.to_string(), commit.clone());
+        Ok(commit)
+    }
+
+    // The origin for a path at a commit, shared by everything that reaches it
+    fn get_origin(&mut self, commit: &str, path: &str, mode: u32, blob: &str) -> usize {
+        let key = (commit.to_string(), path.to_string());
+        if let Some(id) = self.origin_ids.get(&key) {
+            return *id;
+        }
+        let id = self.origins.len();
+        self.origins.push(Origin {
+            commit: commit.to_string(),
+            path: path.to_string(),
+            mode,
+            blob: blob.to_string(),
+            contents: None,
+            previous: None,
+            suspects: Vec::new(),
+            guilty: false,
+        });
+        self.origin_ids.insert(key, id);
+        self.commit_origins.entry(commit.to_string()).or_default().push(id);
+        id
+    }
+
+    fn contents(&mut self, origin: usize) -> anyhow::Result<Rc<Vec<u8>>> {
+        if let Some(contents) = &self.origins[origin].contents {
+            return Ok(contents.clone());
+        }
+        let origin = &mut self.origins[origin];
+        let contents = Rc::new(side_contents(origin.mode, &origin.blob, &origin.path)?);
+        origin.contents = Some(contents.clone());
+        Ok(contents)
+    }
+
+    // Hand entries to an origin, queueing its commit when it had nothing to look at yet
+    fn queue_blames(&mut self, origin: usize, entries: Vec<Entry>) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if self.origins[origin].suspects.is_empty() {
+            let hash = self.origins[origin].commit.clone();
+            let time = self.commit(&hash)?.committer.time;
+            // Going forward in history the oldest commit is looked at first
+            let key = if self.options.reverse { -time } else { time };
+            self.queue.push((key, Reverse(self.sequence), hash));
+            self.sequence += 1;
+        }
+        self.origins[origin].suspects.extend(entries.into_iter().map(|entry| Entry { origin, ..entry }));
+        Ok(())
+    }
+
+    fn score(&self, entry: &Entry) -> usize {
+        alnum_count(&self.final_lines[entry.lno..entry.lno + entry.count])
+    }
+
+    // Separate the entries too small to be worth searching for elsewhere
+    fn filter_small(&self, entries: Vec<Entry>, minimum: usize) -> (Vec<Entry>, Vec<Entry>) {
+        entries.into_iter().partition(|entry| self.score(entry) <= minimum)
+    }
+
+    // Changed lines between two versions, ignoring the whitespace -w asks to ignore
+    fn line_changes(&self, old: &[u8], new: &[u8]) -> Vec<Change> {
+        let old = split_lines(old);
+        let new = split_lines(new);
+        let whitespace = self.options.whitespace;
+        if whitespace == Whitespace::default() {
+            return diff_lines(&old, &new, Algorithm::Myers, false);
+        }
+        let old: Vec<Cow<[u8]>> = old.iter().map(|line| whitespace.normalize(line)).collect();
+        let new: Vec<Cow<[u8]>> = new.iter().map(|line| whitespace.normalize(line)).collect();
+        let old: Vec<&[u8]> = old.iter().map(|line| line.as_ref()).collect();
+        let new: Vec<&[u8]> = new.iter().map(|line| line.as_ref()).collect();
+        diff_lines(&old, &new, Algorithm::Myers, false)
+    }
+
+    // Changes between a tree and a commit, or the index for the working tree
+    fn tree_changes(&self, tree: &str, commit: &Commit) -> anyhow::Result<Vec<FileChange>> {
+        if commit.hash == NULL_HASH {
+            return index_changes(tree);
+        }
+        TreeDiff { recursive: true, ..Default::default() }.diff(Some(tree), Some(&commit.tree))
+    }
+
+    // The version of an origin's file in a parent: the same path, or failing that the path
+    // it was renamed from
+    fn find_origin(&mut self, parent: &str, origin: usize) -> anyhow::Result<Option<usize>> {
+        let parent_commit = self.commit(parent)?;
+        let path = self.origins[origin].path.clone();
+        if let Some((mode, blob)) = tree_file(&parent_commit.tree, &path)? {
+            if is_file_mode(mode) {
+                return Ok(Some(self.get_origin(parent, &path, mode, &blob)));
+            }
+        }
+        let commit = self.commit(&self.origins[origin].commit.clone())?;
+        let changes = self.tree_changes(&parent_commit.tree, &commit)?;
+        let changes = self.renames.detect(changes, Vec::new())?;
+        Ok(changes
+            .iter()
+            .find(|change| change.is_rename() && change.new_path == path)
+            .map(|change| self.get_origin(parent, &change.old_path, change.old_mode, &change.old_hash)))
+    }
+
+    // Pass the lines a parent already had on to it. For ignored commits the changed lines
+    // are passed too, each to the parent line in the same place of its hunk; lines with no
+    // such line stay and are unblamable.
+    fn pass_to_parent(&mut self, target: usize, parent: usize, ignore: bool) -> anyhow::Result<()> {
+        let parent_contents = self.contents(parent)?;
+        let target_contents = self.contents(target)?;
+        let changes = self.line_changes(&parent_contents, &target_contents);
+        // Runs of target lines that correspond to parent lines: start, end and parent start
+        let mut regions = Vec::new();
+        if ignore {
+            for change in &changes {
+                let count = change.new_count.min(change.old_count);
+                if count > 0 {
+                    regions.push((change.new_start, change.new_start + count, change.old_start));
+                }
+            }
+        } else {
+            let (mut target_line, mut parent_line) = (0, 0);
+            for change in &changes {
+                if change.new_start > target_line {
+                    regions.push((target_line, change.new_start, parent_line));
+                }
+                target_line = change.new_start + change.new_count;
+                parent_line = change.old_start + change.old_count;
+            }
+            regions.push((target_line, usize::MAX, parent_line));
+        }
+
+        let mut passed = Vec::new();
+        let mut kept = Vec::new();
+        for entry in std::mem::take(&mut self.origins[target].suspects) {
+            let end = entry.s_lno + entry.count;
+            let mut start = entry.s_lno;
+            for &(region_start, region_end, parent_start) in &regions {
+                if region_end <= start || region_start >= end {
+                    continue;
+                }
+                let from = region_start.max(start);
+                let to = region_end.min(end);
+                if from > start {
+                    kept.push(entry.piece(start, from));
+                }
+                let mut piece = entry.piece(from, to);
+                piece.s_lno = parent_start + from - region_start;
+                piece.ignored |= ignore;
+                passed.push(piece);
+                start = to;
+            }
+            if start < end {
+                kept.push(entry.piece(start, end));
+            }
+        }
+        for entry in kept.iter_mut() {
+            entry.unblamable |= ignore;
+        }
+        self.origins[target].suspects = kept;
+        self.queue_blames(parent, passed)
+    }
+
+    // Cut an entry around lines tlno..same of its suspect, which match the parent's lines
+    // from plno on
+    fn split_overlap(&self, entry: &Entry, tlno: usize, plno: usize, same: usize, parent: usize) -> Option<Split> {
+        let before = (entry.s_lno < tlno).then(|| entry.piece(entry.s_lno, tlno));
+        let after = (same < entry.s_lno + entry.count).then(|| entry.piece(same, entry.s_lno + entry.count));
+        let lno = entry.lno + tlno - entry.s_lno;
+        let end = after.as_ref().map_or(entry.lno + entry.count, |after| after.lno);
+        if end <= lno {
+            return None;
+        }
+        let matched = Entry { lno, count: end - lno, s_lno: plno, origin: parent, ..entry.clone() };
+        let score = self.score(&matched);
+        Some(Split { before, matched, after, score })
+    }
+
+    // The best block of an entry's lines found in another file
+    fn find_copy_in_blob(&self, entry: &Entry, parent: usize, parent_contents: &[u8]) -> Option<Split> {
+        let lines = self.final_lines[entry.lno..entry.lno + entry.count].concat();
+        let changes = self.line_changes(parent_contents, &lines);
+        let mut best = None;
+        let mut consider = |tlno: usize, plno: usize, same: usize| {
+            if tlno < same {
+                let split = self.split_overlap(entry, tlno + entry.s_lno, plno, same + entry.s_lno, parent);
+                keep_better_split(&mut best, split);
+            }
+        };
+        let (mut tlno, mut plno) = (0, 0);
+        for change in &changes {
+            consider(tlno, plno, change.new_start);
+            tlno = change.new_start + change.new_count;
+            plno = change.old_start + change.old_count;
+        }
+        consider(tlno, plno, entry.count);
+        best
+    }
+
+    // Look for lines that moved within the parent's version of the file
+    fn find_move_in_parent(&mut self, target: usize, parent: usize, too_small: &mut Vec<Entry>) -> anyhow::Result<()> {
+        let contents = self.contents(parent)?;
+        let mut unblamed = std::mem::take(&mut self.origins[target].suspects);
+        let mut leftover = Vec::new();
+        let mut passed = Vec::new();
+        while !unblamed.is_empty() {
+            let mut next = Vec::new();
+            for entry in unblamed {
+                match self.find_copy_in_blob(&entry, parent, &contents) {
+                    Some(split) if split.score > self.options.move_score => {
+                        passed.push(split.matched);
+                        next.extend(split.before);
+                        next.extend(split.after);
+                    }
+                    _ => leftover.push(entry),
+                }
+            }
+            let (small, rest) = self.filter_small(next, self.options.move_score);
+            too_small.extend(small);
+            unblamed = rest;
+        }
+        self.origins[target].suspects = leftover;
+        self.queue_blames(parent, passed)
+    }
+
+    // Look for lines copied from other files of a parent: those the commit changed, or with
+    // -C -C for a new file and -C -C -C always, every file of the parent
+    fn find_copy_in_parent(&mut self, target: usize, parent: &str, porigin: Option<usize>, too_small: &mut Vec<Entry>) -> anyhow::Result<()> {
+        let harder = self.options.copies >= 3
+            || (self.options.copies == 2 && porigin.is_none_or(|porigin| self.origins[porigin].path != self.origins[target].path));
+        let parent_commit = self.commit(parent)?;
+        let mut candidates: Vec<(String, u32, String)> = if harder {
+            flatten_tree(&parent_commit.tree)?.into_iter().map(|(path, entry)| (path, entry.mode, entry.sha_hash)).collect()
+        } else {
+            let commit = self.commit(&self.origins[target].commit.clone())?;
+            let changes = self.tree_changes(&parent_commit.tree, &commit)?;
+            changes
+                .into_iter()
+                .filter(|change| change.kind != ChangeKind::Added)
+                .map(|change| (change.old_path, change.old_mode, change.old_hash))
+                .collect()
+        };
+        let moved_path = porigin.map(|porigin| self.origins[porigin].path.clone());
+        candidates.retain(|(path, mode, _)| is_file_mode(*mode) && Some(path) != moved_path.as_ref());
+
+        let mut unblamed = std::mem::take(&mut self.origins[target].suspects);
+        let mut leftover = Vec::new();
+        let mut passed = Vec::new();
+        while !unblamed.is_empty() {
+            let mut best: Vec<Option<Split>> = unblamed.iter().map(|_| None).collect();
+            for (path, mode, blob) in &candidates {
+                let norigin = self.get_origin(parent, path, *mode, blob);
+                let contents = self.contents(norigin)?;
+                for (entry, best) in unblamed.iter().zip(best.iter_mut()) {
+                    keep_better_split(best, self.find_copy_in_blob(entry, norigin, &contents));
+                }
+            }
+            let mut next = Vec::new();
+            for (entry, split) in unblamed.into_iter().zip(best) {
+                match split {
+                    Some(split) if split.score > self.options.copy_score => {
+                        passed.push(split.matched);
+                        next.extend(split.before);
+                        next.extend(split.after);
+                    }
+                    _ => leftover.push(entry),
+                }
+            }
+            let (small, rest) = self.filter_small(next, self.options.copy_score);
+            too_small.extend(small);
+            unblamed = rest;
+        }
+        self.origins[target].suspects = leftover;
+        let mut by_origin: BTreeMap<usize, Vec<Entry>> = BTreeMap::new();
+        for entry in passed {
+            by_origin.entry(entry.origin).or_default().push(entry);
+        }
+        for (origin, entries) in by_origin {
+            self.queue_blames(origin, entries)?;
+        }
+        Ok(())
+    }
+
+    // Pass as much of an origin's blame as possible to its parents, or its children when
+    // going forward in history. What is left is the origin's own doing.
+    fn pass_blame(&mut self, origin: usize) -> anyhow::Result<()> {
+        let hash = self.origins[origin].commit.clone();
+        let scapegoats = if self.options.reverse {
+            self.children.get(&hash).cloned().unwrap_or_default()
+        } else {
+            self.commit(&hash)?.parents.clone()
+        };
+
+        let mut parent_origins: Vec<Option<usize>> = Vec::new();
+        for parent in &scapegoats {
+            let Some(porigin) = self.find_origin(parent, origin)? else {
+                parent_origins.push(None);
+                continue;
+            };
+            // A parent with the very same file takes all of the blame
+            if self.origins[porigin].blob == self.origins[origin].blob {
+                let entries = std::mem::take(&mut self.origins[origin].suspects);
+                return self.queue_blames(porigin, entries);
+            }
+            let blob = &self.origins[porigin].blob;
+            let same = parent_origins.iter().flatten().any(|other| self.origins[*other].blob == *blob);
+            parent_origins.push((!same).then_some(porigin));
+        }
+
+        for porigin in parent_origins.iter().flatten() {
+            if self.origins[origin].previous.is_none() {
+                self.origins[origin].previous = Some(*porigin);
+            }
+            self.pass_to_parent(origin, *porigin, false)?;
+            if self.origins[origin].suspects.is_empty() {
+                return Ok(());
+            }
+        }
+        if self.ignored.contains(&hash) {
+            for porigin in parent_origins.iter().flatten() {
+                self.pass_to_parent(origin, *porigin, true)?;
+                if self.origins[origin].suspects.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut too_small = Vec::new();
+        if self.options.moves {
+            let suspects = std::mem::take(&mut self.origins[origin].suspects);
+            let (small, rest) = self.filter_small(suspects, self.options.move_score);
+            too_small = small;
+            self.origins[origin].suspects = rest;
+            for porigin in parent_origins.iter().flatten() {
+                if self.origins[origin].suspects.is_empty() {
+                    break;
+                }
+                self.find_move_in_parent(origin, *porigin, &mut too_small)?;
+            }
+        }
+        if self.options.copies > 0 {
+            let mut suspects = std::mem::take(&mut self.origins[origin].suspects);
+            if self.options.copy_score < self.options.move_score {
+                suspects.append(&mut too_small);
+            }
+            let (small, rest) = self.filter_small(suspects, self.options.copy_score);
+            too_small.extend(small);
+            self.origins[origin].suspects = rest;
+            for (parent, porigin) in scapegoats.iter().zip(&parent_origins) {
+                if self.origins[origin].suspects.is_empty() {
+                    break;
+                }
+                self.find_copy_in_parent(origin, parent, *porigin, &mut too_small)?;
+            }
+        }
+        self.origins[origin].suspects.extend(too_small);
+        Ok(())
+    }
+
+    // Work through the queued commits until every entry has found its origin
+    fn assign_blame(&mut self) -> anyhow::Result<()> {
+        while let Some((_, _, hash)) = self.queue.pop() {
+            loop {
+                let origins = self.commit_origins.get(&hash).cloned().unwrap_or_default();
+                let Some(origin) = origins.into_iter().find(|origin| !self.origins[*origin].suspects.is_empty()) else {
+                    break;
+                };
+                // Commits outside the range are boundaries where the blame stops
+                if self.options.reverse || !self.uninteresting.contains(&hash) {
+                    self.pass_blame(origin)?;
+                }
+                let remaining = std::mem::take(&mut self.origins[origin].suspects);
+                if !remaining.is_empty() {
+                    self.origins[origin].guilty = true;
+                    self.guilty.extend(remaining);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether an origin's commit is shown as a boundary: one outside the range, or a root
+    // commit unless --root is given
+    fn is_boundary(&mut self, origin: usize) -> anyhow::Result<bool> {
+        let hash = self.origins[origin].commit.clone();
+        Ok(self.uninteresting.contains(&hash) || (!self.options.show_root && self.commit(&hash)?.parents.is_empty()))
+    }
+}
+
+// Keep the candidate split when it attributes at least as many characters as the best so far
+fn keep_better_split(best: &mut Option<Split>, candidate: Option<Split>) {
+    if let Some(candidate) = candidate {
+        if best.as_ref().is_none_or(|best| candidate.score >= best.score) {
+            *best = Some(candidate);
+        }
+    }
+}
+
+// Read a number at the start of a -L location, returning it and the rest
+fn leading_number(spec: &str) -> Option<(i64, &str)> {
+    let digits = spec.bytes().take_while(|x| x.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    Some((spec[..digits].parse().unwrap_or(i64::MAX), &spec[digits..]))
+}
+
+// Where "/regex/" ends: the index of its closing slash, skipping escaped characters
+fn regex_end(spec: &str) -> Option<usize> {
+    let bytes = spec.as_bytes();
+    let mut index = 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 1,
+            b'/' => return Some(index),
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+// Parse one end of a -L range: a line number, an offset from begin, or a regex searched for
+// from line begin. A negative begin is the anchor a regex start searches from, which "^"
+// resets to the top of the file. 0 stands for a location that is not given.
+fn parse_location<'a>(spec: &'a str, lines: &[Vec<u8>], begin: i64) -> anyhow::Result<(i64, &'a str)> {
+    if begin >= 1 && (spec.starts_with('+') || spec.starts_with('-')) {
+        let Some((number, rest)) = leading_number(&spec[1..]) else {
+            return Ok((0, spec));
+        };
+        if number == 0 {
+            anyhow::bail!("-L invalid empty range");
+        }
+        let line = if spec.starts_with('+') { begin + number - 2 } else { (begin - number).max(1) };
+        return Ok((line, rest));
+    }
+    if let Some((number, rest)) = leading_number(spec) {
+        if number == 0 {
+            anyhow::bail!("-L invalid line number: 0");
+        }
+        return Ok((number, rest));
+    }
+
+    let (mut begin, mut spec) = (begin, spec);
+    if begin < 0 {
+        match spec.strip_prefix('^') {
+            Some(rest) => {
+                begin = 1;
+                spec = rest;
+            }
+            None => begin = -begin,
+        }
+    }
+    if !spec.starts_with('/') {
+        return Ok((0, spec));
+    }
+    let Some(end) = regex_end(spec) else {
+        return Ok((0, spec));
+    };
+    let pattern = &spec[1..end];
+    let start = (begin as usize).saturating_sub(1).min(lines.len());
+    let regex = Regex::new(&format!("(?m){}", basic_to_extended(pattern)))
+        .map_err(|error| anyhow::anyhow!("-L parameter '{}' starting at line {}: {}", pattern, start + 1, error))?;
+    let text = lines[start..].concat();
+    let found = regex
+        .find(&text)
+        .ok_or_else(|| anyhow::anyhow!("-L parameter '{}' starting at line {}: no match", pattern, start + 1))?;
+    let line = start + text[..found.start()].iter().filter(|x| **x == b'\n').count();
+    Ok((line as i64 + 1, &spec[end + 1..]))
+}
+
+// Whether a line starts a function the way the default funcname pattern sees it
+fn is_function_line(line: &[u8]) -> bool {
+    line.first().is_some_and(|x| x.is_ascii_alphabetic() || *x == b'_' || *x == b'$')
+}
+
+// Parse ":funcname", the function whose name matches the regex, from its line up to the next
+// function. None means the range is malformed.
+fn parse_function_range(arg: &str, lines: &[Vec<u8>], anchor: usize) -> anyhow::Result<Option<(i64, i64)>> {
+    let (anchor, arg) = match arg.strip_prefix('^') {
+        Some(rest) => (1, rest),
+        None => (anchor, arg),
+    };
+    let bytes = arg.as_bytes();
+    let mut end = 1;
+    while end < bytes.len() && bytes[end] != b':' {
+        if bytes[end] == b'\\' && end + 1 < bytes.len() {
+            end += 1;
+        }
+        end += 1;
+    }
+    if end == 1 || end + 1 < bytes.len() {
+        return Ok(None);
+    }
+    let pattern = &arg[1..end];
+    let regex =
+        Regex::new(&basic_to_extended(pattern)).map_err(|error| anyhow::anyhow!("-L parameter '{}': {}", pattern, error))?;
+    let begin = (anchor - 1..lines.len())
+        .find(|index| is_function_line(&lines[*index]) && regex.is_match(&lines[*index]))
+        .ok_or_else(|| anyhow::anyhow!("-L parameter '{}' starting at line {}: no match", pattern, anchor))?;
+    let end = (begin + 1..lines.len()).find(|index| is_function_line(&lines[*index])).unwrap_or(lines.len());
+    Ok(Some((begin as i64 + 1, end as i64)))
+}
+
+// Parse a -L argument into a range of one based lines, 0 standing for an end not given.
+// None means the argument is malformed.
+fn parse_range(arg: &str, lines: &[Vec<u8>], anchor: usize) -> anyhow::Result<Option<(i64, i64)>> {
+    let anchor = anchor.clamp(1, lines.len() + 1);
+    if arg.starts_with(':') || arg.starts_with("^:") {
+        return parse_function_range(arg, lines, anchor);
+    }
+    let (mut begin, rest) = parse_location(arg, lines, -(anchor as i64))?;
+    let (mut end, rest) = match rest.strip_prefix(',') {
+        Some(rest) => parse_location(rest, lines, begin + 1)?,
+        None => (0, rest),
+    };
+    if !rest.is_empty() {
+        return Ok(None);
+    }
+    if begin != 0 && end != 0 && end < begin {
+        std::mem::swap(&mut begin, &mut end);
+    }
+    Ok(Some((begin, end)))
+}
+
+// Read the commits listed in an ignore-revs file, one full object name per line with
+// "#" starting a comment
+fn read_ignore_revs_file(path: &str, ignored: &mut HashSet<String>) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(path).map_err(|_| anyhow::anyhow!("could not open object name list: {}", path))?;
+    for line in contents.lines() {
+        let name = line.split('#').next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        if name.len() != 40 || !name.bytes().all(|x| x.is_ascii_hexdigit()) {
+            anyhow::bail!("invalid object name: {}", name);
+        }
+        ignored.insert(peel_to_commit(name)?);
+    }
+    Ok(())
+}
+
+// The commit a revision argument names, with the error git gives for a bad one
+fn revision_commit(name: &str) -> anyhow::Result<String> {
+    resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).map_err(|_| anyhow::anyhow!("bad revision '{}'", name))
+}
+
+// The commit standing for the working tree: its parents are HEAD and any commits being
+// merged, and its author the placeholder "Not Committed Yet"
+fn working_tree_commit(path: &str) -> anyhow::Result<Commit> {
+    let now = chrono::Utc::now().timestamp();
+    let signature = Signature {
+        name: "Not Committed Yet".to_string(),
+        email: "not.committed.yet".to_string(),
+        time: now,
+        offset: local_offset(now),
+    };
+    let mut parents: Vec<String> = resolve_ref("HEAD").into_iter().collect();
+    if let Some(merge_heads) = read_ref("MERGE_HEAD") {
+        parents.extend(merge_heads.lines().map(|line| line.trim().to_string()));
+    }
+    Ok(Commit {
+        hash: NULL_HASH.to_string(),
+        tree: String::new(),
+        parents,
+        author: signature.clone(),
+        committer: signature,
+        raw_header: String::new(),
+        message: format!("Version of {} from {}\n", path, path),
+    })
+}
+
+// The working tree version of the file, which must be tracked in HEAD or the index
+fn working_tree_file(path: &str, commit: &Commit) -> anyhow::Result<(u32, Vec<u8>)> {
+    let mut tracked = read_index()?.iter().any(|entry| entry.path == path);
+    for parent in &commit.parents {
+        tracked = tracked || tree_file(&load_commit(parent)?.tree, path)?.is_some();
+    }
+    if !tracked {
+        anyhow::bail!("no such path '{}' in HEAD", path);
+    }
+    let metadata = fs::symlink_metadata(path).map_err(|_| anyhow::anyhow!("Cannot lstat '{}': No such file or directory", path))?;
+    Ok((mode_from_metadata(&metadata), read_worktree_contents(path, &metadata)?))
+}
+
+fn decimal_width(number: usize) -> usize {
+    number.to_string().len()
+}
+
+// Width dates are padded to in each format so the columns line up
+fn date_width(format: &DateFormat) -> usize {
+    match format {
+        DateFormat::Rfc => 31,
+        DateFormat::Iso | DateFormat::IsoStrict => 25,
+        DateFormat::Raw => 16,
+        DateFormat::Unix | DateFormat::Short => 10,
+        DateFormat::Relative => 22,
+        DateFormat::Default => 30,
+        // Formats of their own are as wide as the start of the epoch is in them
+        DateFormat::Strftime(..) => format_date(0, "+0000", format).len(),
+    }
+}
+
+// Copy a line of the blamed file to the output, ending it with a newline
+fn write_line(out: &mut impl Write, line: &[u8]) -> std::io::Result<()> {
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Print the porcelain header of a commit the first time it is seen, or every time with
+// --line-porcelain, followed by its file
+fn write_porcelain_details(
+    out: &mut impl Write,
+    board: &mut Scoreboard,
+    origin: usize,
+    shown: &mut HashSet<String>,
+    several_paths: &HashSet<String>,
+) -> anyhow::Result<()> {
+    let hash = board.origins[origin].commit.clone();
+    let first = shown.insert(hash.clone());
+    if first || board.options.line_porcelain {
+        let commit = board.commit(&hash)?;
+        let author = &commit.author;
+        let committer = &commit.committer;
+        writeln!(out, "author {}", author.name)?;
+        writeln!(out, "author-mail <{}>", author.email)?;
+        writeln!(out, "author-time {}", author.time)?;
+        writeln!(out, "author-tz {}", author.offset)?;
+        writeln!(out, "committer {}", committer.name)?;
+        writeln!(out, "committer-mail <{}>", committer.email)?;
+        writeln!(out, "committer-time {}", committer.time)?;
+        writeln!(out, "committer-tz {}", committer.offset)?;
+        writeln!(out, "summary {}", commit.subject())?;
+        if board.is_boundary(origin)? {
+            writeln!(out, "boundary")?;
+        }
+    } else if !several_paths.contains(&hash) {
+        return Ok(());
+    }
+    if let Some(previous) = board.origins[origin].previous {
+        let previous = &board.origins[previous];
+        writeln!(out, "previous {} {}", previous.commit, quote_path(&previous.path, false))?;
+    }
+    writeln!(out, "filename {}", quote_path(&board.origins[origin].path, false))?;
+    Ok(())
+}
+
+fn write_porcelain(out: &mut impl Write, board: &mut Scoreboard, entries: &[Entry]) -> anyhow::Result<()> {
+    // Commits blamed for lines of more than one path repeat the file name every time
+    let mut paths_per_commit: HashMap<&str, usize> = HashMap::new();
+    for origin in board.origins.iter().filter(|origin| origin.guilty) {
+        *paths_per_commit.entry(&origin.commit).or_default() += 1;
+    }
+    let several_paths: HashSet<String> =
+        paths_per_commit.into_iter().filter(|(_, count)| *count > 1).map(|(hash, _)| hash.to_string()).collect();
+
+    let mut shown = HashSet::new();
+    for entry in entries {
+        let hash = board.origins[entry.origin].commit.clone();
+        writeln!(out, "{} {} {} {}", hash, entry.s_lno + 1, entry.lno + 1, entry.count)?;
+        write_porcelain_details(out, board, entry.origin, &mut shown, &several_paths)?;
+        for offset in 0..entry.count {
+            if offset > 0 {
+                writeln!(out, "{} {} {}", hash, entry.s_lno + 1 + offset, entry.lno + 1 + offset)?;
+                if board.options.line_porcelain {
+                    write_porcelain_details(out, board, entry.origin, &mut shown, &several_paths)?;
+                }
+            }
+            out.write_all(b"\t")?;
+            write_line(out, &board.final_lines[entry.lno + offset])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_blame(out: &mut impl Write, board: &mut Scoreboard, entries: &[Entry], path: &str) -> anyhow::Result<()> {
+    let options = &board.options;
+    let show_name = options.show_name || entries.iter().any(|entry| board.origins[entry.origin].path != path);
+    let longest_file = entries.iter().map(|entry| board.origins[entry.origin].path.chars().count()).max().unwrap_or(0);
+    let orig_digits = decimal_width(entries.iter().map(|entry| entry.s_lno + entry.count).max().unwrap_or(0));
+    let digits = decimal_width(entries.iter().map(|entry| entry.lno + entry.count).max().unwrap_or(0));
+    let show_email = options.show_email;
+    let name_of = |commit: &Commit| {
+        if show_email {
+            format!("<{}>", commit.author.email)
+        } else {
+            commit.author.name.clone()
+        }
+    };
+
+    let mut longest_author = 0;
+    // One more character than the longest unique abbreviation leaves room for the "^"
+    let mut auto_abbrev = 7;
+    let hashes: Vec<String> = entries.iter().map(|entry| board.origins[entry.origin].commit.clone()).collect();
+    for hash in &hashes {
+        let commit = board.commit(hash)?;
+        longest_author = longest_author.max(name_of(&commit).chars().count());
+        if board.options.abbrev.is_none() && !board.options.long_names {
+            auto_abbrev = auto_abbrev.max(abbreviate_to(hash, auto_abbrev).len());
+        }
+    }
+    let length = match board.options.abbrev {
+        _ if board.options.long_names => 40,
+        Some(0) | Some(40..) => 40,
+        Some(abbrev) => abbrev.max(4) + 1,
+        None => auto_abbrev + 1,
+    };
+
+    for entry in entries {
+        let hash = board.origins[entry.origin].commit.clone();
+        let commit = board.commit(&hash)?;
+        let boundary = board.is_boundary(entry.origin)?;
+        let options = &board.options;
+        let date = if options.raw_time {
+            format!("{} {}", commit.author.time, commit.author.offset)
+        } else {
+            let date = format_date(commit.author.time, &commit.author.offset, &options.date_format);
+            format!("{:<width$}", date, width = date_width(&options.date_format))
+        };
+        let name = name_of(&commit);
+        for offset in 0..entry.count {
+            let mut line = String::new();
+            let mut hex = hash.clone();
+            let mut length = length;
+            if boundary {
+                if options.blank_boundary {
+                    hex = " ".repeat(length);
+                } else if !options.annotate {
+                    length -= 1;
+                    line.push('^');
+                }
+            }
+            if options.mark_unblamable && entry.unblamable {
+                length -= 1;
+                line.push('*');
+            }
+            if options.mark_ignored && entry.ignored {
+                length -= 1;
+                line.push('?');
+            }
+            line.push_str(&hex[..length]);
+            if options.annotate {
+                line.push_str(&format!("\t({:>10}\t{:>10}\t{})", name, date, entry.lno + 1 + offset));
+            } else {
+                if show_name {
+                    line.push_str(&format!(" {:<width$}", board.origins[entry.origin].path, width = longest_file));
+                }
+                if options.show_number {
+                    line.push_str(&format!(" {:>width$}", entry.s_lno + 1 + offset, width = orig_digits));
+                }
+                if !options.no_author {
+                    let padding = longest_author - name.chars().count();
+                    line.push_str(&format!(" ({}{} {:>10}", name, " ".repeat(padding), date));
+                }
+                line.push_str(&format!(" {:>width$}) ", entry.lno + 1 + offset, width = digits));
+            }
+            out.write_all(line.as_bytes())?;
+            write_line(out, &board.final_lines[entry.lno + offset])?;
+        }
+    }
+    Ok(())
+}
+
+// Show for each line of a file the commit that last changed it. Lines are followed back
+// through history, passing them on to a parent whenever the parent already had them, and
+// with -M and -C also when they were moved within the file or copied from other files.
+pub fn blame_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "blame").unwrap();
+    blame(&args[command_index + 1..], false)
+}
+
+// annotate is blame with the output format of the old annotate command
+pub fn annotate_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "annotate").unwrap();
+    blame(&args[command_index + 1..], true)
+}
+
+fn blame(args: &[String], annotate: bool) -> anyhow::Result<()> {
+    let config = read_config();
+    let mut options = Options::new(&config)?;
+    options.annotate = annotate;
+    let mut positional: Vec<&str> = Vec::new();
+    let mut dashdash = None;
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = |name: &str| option_value(args, &mut index, &[name], blame_usage);
+        if let Some(range) = value("-L") {
+            options.ranges.push(range);
+        } else if let Some(date) = value("--date") {
+            options.date_format = DateFormat::parse(&date)?;
+        } else if let Some(rev) = value("--ignore-rev") {
+            options.ignore_revs.push(rev);
+        } else if let Some(file) = value("--ignore-revs-file") {
+            // An empty file name forgets the files given so far, including configured ones
+            if file.is_empty() {
+                options.ignore_revs_files.clear();
+            } else {
+                options.ignore_revs_files.push(file);
+            }
+        } else if arg == "--" {
+            dashdash = Some(positional.len());
+            positional.extend(args[index + 1..].iter().map(|x| x.as_str()));
+            break;
+        } else if let Some(abbrev) = arg.strip_prefix("--abbrev") {
+            options.abbrev = match abbrev.strip_prefix('=') {
+                Some(abbrev) => Some(abbrev.parse().map_err(|_| anyhow::anyhow!("expects a numerical value"))?),
+                None if abbrev.is_empty() => None,
+                None => {
+                    eprintln!("error: unknown option `{}'", &arg[2..]);
+                    blame_usage();
+                }
+            };
+        } else if let Some(score) = arg.strip_prefix("-M") {
+            options.moves = true;
+            if !score.is_empty() {
+                options.move_score = parse_score(score);
+            }
+        } else if let Some(score) = arg.strip_prefix("-C") {
+            options.moves = true;
+            options.copies = (options.copies + 1).min(3);
+            if !score.is_empty() {
+                options.copy_score = parse_score(score);
+            }
+        } else {
+            match arg {
+                "--porcelain" => options.porcelain = true,
+                "--line-porcelain" => {
+                    options.porcelain = true;
+                    options.line_porcelain = true;
+                }
+                "--show-name" => options.show_name = true,
+                "--show-number" => options.show_number = true,
+                "--show-email" => options.show_email = true,
+                "--root" => options.show_root = true,
+                "--reverse" => options.reverse = true,
+                _ if arg.len() > 1 && arg.starts_with('-') && !arg.starts_with("--") => {
+                    for flag in arg[1..].chars() {
+                        if !options.set_flag(flag) {
+                            eprintln!("error: unknown switch `{}'", flag);
+                            blame_usage();
+                        }
+                    }
+                }
+                _ if arg.starts_with("--") => {
+                    eprintln!("error: unknown option `{}'", &arg[2..]);
+                    blame_usage();
+                }
+                _ => positional.push(arg),
+            }
+        }
+        index += 1;
+    }
+
+    // "blame [<rev>...] [--] <path>", or the older "blame [--] <path> <rev>"
+    let (path, revisions) = match dashdash {
+        Some(position) => match positional.len() - position {
+            1 => (positional[position], positional[..position].to_vec()),
+            2 if position == 0 => (positional[0], vec![positional[1]]),
+            _ => blame_usage(),
+        },
+        None => match positional.len() {
+            0 => blame_usage(),
+            2 if revision_commit(positional[1]).is_ok() => (positional[0], vec![positional[1]]),
+            count => (positional[count - 1], positional[..count - 1].to_vec()),
+        },
+    };
+    let path = path.trim_start_matches("./").to_string();
+
+    let mut tips: Vec<(&str, String)> = Vec::new();
+    let mut bottoms = Vec::new();
+    for revision in revisions {
+        if let Some((from, to)) = revision.split_once("..").filter(|(_, to)| !to.starts_with('.')) {
+            let from = if from.is_empty() { "HEAD" } else { from };
+            let to = if to.is_empty() { "HEAD" } else { to };
+            bottoms.push(revision_commit(from)?);
+            tips.push((to, revision_commit(to)?));
+        } else if let Some(name) = revision.strip_prefix('^') {
+            bottoms.push(revision_commit(name)?);
+        } else {
+            tips.push((revision, revision_commit(revision)?));
+        }
+    }
+    if tips.len() > 1 {
+        anyhow::bail!("More than one commit to dig from {} and {}?", tips[1].0, tips[0].0);
+    }
+
+    let mut uninteresting = HashSet::new();
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    // Going forward, the file is blamed as it was at the start of the range
+    let (final_name, final_commit) = if options.reverse {
+        let (bottom_name, bottom, top) = match (bottoms.first(), tips.first()) {
+            (Some(bottom), tip) => {
+                let top = match tip {
+                    Some((_, top)) => top.clone(),
+                    None => revision_commit("HEAD")?,
+                };
+                (bottom.clone(), bottom.clone(), top)
+            }
+            (None, Some((name, tip))) => (name.to_string(), tip.clone(), revision_commit("HEAD")?),
+            (None, None) => anyhow::bail!("No commit to dig up from?"),
+        };
+        uninteresting = reachable_commits(&bottom)?;
+        let mut range: Vec<Rc<Commit>> = reachable_commits(&top)?
+            .into_iter()
+            .filter(|hash| !uninteresting.contains(hash))
+            .map(|hash| load_commit(&hash).map(Rc::new))
+            .collect::<anyhow::Result<_>>()?;
+        range.sort_by(|a, b| b.committer.time.cmp(&a.committer.time).then_with(|| a.hash.cmp(&b.hash)));
+        let in_range: HashSet<&str> = range.iter().map(|commit| commit.hash.as_str()).collect();
+        for commit in &range {
+            for parent in &commit.parents {
+                if in_range.contains(parent.as_str()) || *parent == bottom {
+                    children.entry(parent.clone()).or_default().push(commit.hash.clone());
+                }
+            }
+        }
+        (bottom_name, Some(bottom))
+    } else {
+        for bottom in &bottoms {
+            uninteresting.extend(reachable_commits(bottom)?);
+        }
+        match tips.first() {
+            Some((name, tip)) => (name.to_string(), Some(tip.clone())),
+            None => ("HEAD".to_string(), None),
+        }
+    };
+
+    let mut ignored = HashSet::new();
+    for file in &options.ignore_revs_files {
+        read_ignore_revs_file(file, &mut ignored)?;
+    }
+    for rev in &options.ignore_revs {
+        let commit = resolve_revision(rev)
+            .and_then(|hash| peel_to_commit(&hash))
+            .map_err(|_| anyhow::anyhow!("cannot find revision {} to ignore", rev))?;
+        ignored.insert(commit);
+    }
+
+    let mut renames = Renames::new(&config);
+    renames.detect = Detect::Renames;
+    let mut board = Scoreboard {
+        final_lines: Vec::new(),
+        origins: Vec::new(),
+        origin_ids: HashMap::new(),
+        commit_origins: HashMap::new(),
+        commits: HashMap::new(),
+        queue: BinaryHeap::new(),
+        sequence: 0,
+        children,
+        uninteresting,
+        ignored,
+        renames,
+        options,
+        guilty: Vec::new(),
+    };
+
+    let final_origin = match final_commit {
+        Some(hash) => {
+            let commit = board.commit(&hash)?;
+            let (mode, blob) = tree_file(&commit.tree, &path)?
+                .filter(|(mode, _)| is_file_mode(*mode))
+                .ok_or_else(|| anyhow::anyhow!("no such path {} in {}", path, final_name))?;
+            board.get_origin(&hash, &path, mode, &blob)
+        }
+        None => {
+            let commit = working_tree_commit(&path)?;
+            let (mode, contents) = working_tree_file(&path, &commit)?;
+            let blob = hash_object_contents(&contents, b"blob");
+            board.commits.insert(NULL_HASH.to_string(), Rc::new(commit));
+            let origin = board.get_origin(NULL_HASH, &path, mode, &blob);
+            board.origins[origin].contents = Some(Rc::new(contents));
+            origin
+        }
+    };
+    let contents = board.contents(final_origin)?;
+    board.final_lines = split_lines(&contents).into_iter().map(|line| line.to_vec()).collect();
+    let line_count = board.final_lines.len();
+
+    // The ranges of lines to blame, zero based and sorted with overlaps merged
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut anchor = 1;
+    for arg in &board.options.ranges {
+        let Some((bottom, top)) = parse_range(arg, &board.final_lines, anchor)? else {
+            blame_usage();
+        };
+        if (line_count == 0 && (top != 0 || bottom != 0)) || bottom > line_count as i64 {
+            anyhow::bail!("file {} has only {} line{}", path, line_count, if line_count == 1 { "" } else { "s" });
+        }
+        let bottom = bottom.max(1) as usize;
+        let top = if top < 1 || top > line_count as i64 { line_count } else { top as usize };
+        ranges.push((bottom - 1, top));
+        anchor = top + 1;
+    }
+    if board.options.ranges.is_empty() && line_count > 0 {
+        ranges.push((0, line_count));
+    }
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    let entries = merged
+        .into_iter()
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| Entry { lno: start, count: end - start, s_lno: start, origin: final_origin, ignored: false, unblamable: false })
+        .collect();
+    board.queue_blames(final_origin, entries)?;
+    board.assign_blame()?;
+
+    // Join neighbouring entries that came out of the same lines of the same origin
+    let mut entries: Vec<Entry> = std::mem::take(&mut board.guilty);
+    entries.sort_by_key(|entry| entry.lno);
+    let mut coalesced: Vec<Entry> = Vec::new();
+    for entry in entries {
+        match coalesced.last_mut() {
+            Some(last)
+                if last.origin == entry.origin
+                    && last.s_lno + last.count == entry.s_lno
+                    && last.lno + last.count == entry.lno
+                    && last.ignored == entry.ignored
+                    && last.unblamable == entry.unblamable =>
+            {
+                last.count += entry.count
+            }
+            _ => coalesced.push(entry),
+        }
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    if board.options.porcelain {
+        write_porcelain(&mut out, &mut board, &coalesced)?;
+    } else {
+        write_blame(&mut out, &mut board, &coalesced, &path)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{commit, repository, tree};
+
+    // Blame a file at a commit, returning for each line the commit, path and line number it
+    // came from
+    fn blamed(tip: &str, path: &str, configure: impl FnOnce(&mut Scoreboard)) -> Vec<(String, String, usize)> {
+        let config = read_config_file(".git/config");
+        let mut renames = Renames::new(&config);
+        renames.detect = Detect::Renames;
+        let mut board = Scoreboard {
+            final_lines: Vec::new(),
+            origins: Vec::new(),
+            origin_ids: HashMap::new(),
+            commit_origins: HashMap::new(),
+            commits: HashMap::new(),
+            queue: BinaryHeap::new(),
+            sequence: 0,
+            children: HashMap::new(),
+            uninteresting: HashSet::new(),
+            ignored: HashSet::new(),
+            renames,
+            options: Options::new(&config).unwrap(),
+            guilty: Vec::new(),
+        };
+        configure(&mut board);
+        let (mode, blob) = tree_file(&load_commit(tip).unwrap().tree, path).unwrap().unwrap();
+        let origin = board.get_origin(tip, path, mode, &blob);
+        let contents = board.contents(origin).unwrap();
+        board.final_lines = split_lines(&contents).into_iter().map(|line| line.to_vec()).collect();
+        let entry = Entry { lno: 0, count: board.final_lines.len(), s_lno: 0, origin, ignored: false, unblamable: false };
+        board.queue_blames(origin, vec![entry]).unwrap();
+        board.assign_blame().unwrap();
+
+        let mut lines = vec![(String::new(), String::new(), 0); board.final_lines.len()];
+        for entry in &board.guilty {
+            let origin = &board.origins[entry.origin];
+            for offset in 0..entry.count {
+                lines[entry.lno + offset] = (origin.commit.clone(), origin.path.clone(), entry.s_lno + offset + 1);
+            }
+        }
+        lines
+    }
+
+    fn lines(expected: &[(&str, &str, usize)]) -> Vec<(String, String, usize)> {
+        expected.iter().map(|(commit, path, line)| (commit.to_string(), path.to_string(), *line)).collect()
+    }
+
+    const FIRST: &str = "alpha beta gamma delta epsilon\nzeta eta theta iota kappa\n";
+    const SECOND: &str = "lambda mu nu xi omicron\npi rho sigma tau upsilon\n";
+
+    #[test]
+    fn changed_lines() {
+        let _repository = repository();
+        let first = commit(&tree(&[("f", "one\ntwo\nthree\n")]), &[], 1, "first");
+        let second = commit(&tree(&[("f", "one\n2\nthree\n")]), &[&first], 2, "second");
+        let third = commit(&tree(&[("g", "one\n2\nthree\nfour\n")]), &[&second], 3, "rename and add four");
+        let expected = [(&*first, "f", 1), (&*second, "f", 2), (&*first, "f", 3), (&*third, "g", 4)];
+        assert_eq!(blamed(&third, "g", |_| {}), lines(&expected));
+
+        // An ignored commit hands its changed lines on to the lines they replaced
+        let ignored = blamed(&third, "g", |board| {
+            board.ignored.insert(second.clone());
+        });
+        let expected = [(&*first, "f", 1), (&*first, "f", 2), (&*first, "f", 3), (&*third, "g", 4)];
+        assert_eq!(ignored, lines(&expected));
+
+        // And the blame stops at commits outside the range
+        let bounded = blamed(&third, "g", |board| {
+            board.uninteresting.extend([first.clone(), second.clone()]);
+        });
+        let expected = [(&*second, "f", 1), (&*second, "f", 2), (&*second, "f", 3), (&*third, "g", 4)];
+        assert_eq!(bounded, lines(&expected));
+    }
+
+    #[test]
+    fn moves_and_copies() {
+        let _repository = repository();
+        let first = commit(&tree(&[("a", &format!("{}{}", FIRST, SECOND))]), &[], 1, "first");
+        let swapped = commit(&tree(&[("a", &format!("{}{}", SECOND, FIRST))]), &[&first], 2, "swap");
+        let plain: Vec<String> = blamed(&swapped, "a", |_| {}).into_iter().map(|(commit, _, _)| commit).collect();
+        assert_eq!(plain.iter().filter(|commit| **commit == swapped).count(), 2);
+
+        let moved = blamed(&swapped, "a", |board| board.options.moves = true);
+        assert_eq!(moved, lines(&[(&*first, "a", 3), (&*first, "a", 4), (&*first, "a", 1), (&*first, "a", 2)]));
+
+        // Lines taken out of one file and put in another are found with -C
+        let split = commit(&tree(&[("a", SECOND), ("b", &format!("new\n{}", FIRST))]), &[&first], 2, "split");
+        let plain = blamed(&split, "b", |_| {});
+        assert_eq!(plain, lines(&[(&*split, "b", 1), (&*split, "b", 2), (&*split, "b", 3)]));
+        let copied = blamed(&split, "b", |board| {
+            board.options.moves = true;
+            board.options.copies = 1;
+        });
+        assert_eq!(copied, lines(&[(&*split, "b", 1), (&*first, "a", 1), (&*first, "a", 2)]));
+    }
+
+    #[test]
+    fn ranges() {
+        let file: Vec<Vec<u8>> = ["int main()", "{", "}", "static void helper()", "{", "  return;", "}"].iter().map(|line| format!("{}\n", line).into_bytes()).collect();
+        assert_eq!(parse_range("2,4", &file, 1).unwrap(), Some((2, 4)));
+        assert_eq!(parse_range("4,2", &file, 1).unwrap(), Some((2, 4)));
+        assert_eq!(parse_range("3", &file, 1).unwrap(), Some((3, 0)));
+        assert_eq!(parse_range("2,+3", &file, 1).unwrap(), Some((2, 4)));
+        assert_eq!(parse_range("5,-2", &file, 1).unwrap(), Some((4, 5)));
+        assert_eq!(parse_range("/helper/,/return/", &file, 1).unwrap(), Some((4, 6)));
+        assert_eq!(parse_range(":helper", &file, 1).unwrap(), Some((4, 7)));
+        assert_eq!(parse_range(":main", &file, 1).unwrap(), Some((1, 3)));
+        assert_eq!(parse_range("2,x", &file, 1).unwrap(), None);
+        assert_eq!(parse_range("0", &file, 1).unwrap_err().to_string(), "-L invalid line number: 0");
+        let error = parse_range("/missing/", &file, 1).unwrap_err();
+        assert_eq!(error.to_string(), "-L parameter 'missing' starting at line 1: no match");
+        // A regex start searches on from the end of the range before it, unless it begins "^"
+        assert!(parse_range("/int/", &file, 2).is_err());
+        assert_eq!(parse_range("^/int/", &file, 2).unwrap(), Some((1, 0)));
+    }
+}
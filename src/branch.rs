@@ -0,0 +1,753 @@
+use crate::config::{copy_config_section, read_config, rename_config_section, set_config, Config};
+use crate::checkout::check_branch_name;
+use crate::ignore::fnmatch;
+use crate::reach::is_ancestor;
+use crate::refs::{
+    branch_upstream, copy_ref, delete_ref, head_symbolic_target, list_refs, read_ref, rename_ref, resolve_ref, shorten_ref_name,
+    tracking_source, update_ref, write_ref,
+};
+use crate::revision::{count_ahead_behind, expand_ref_name, object_kind, peel_tags, peel_to_commit, resolve_revision};
+use crate::status::detached_description;
+use crate::walk::{abbreviate_to, load_commit};
+
+// Which refs a listing or deletion acts on
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Local,
+    Remote,
+    All,
+}
+
+// How a new branch's upstream gets configured: never, only from remote-tracking branches as
+// branch.autoSetupMerge does by default, from any branch, or because --track or -u asked for it
+#[derive(Clone, Copy, PartialEq)]
+enum Track {
+    Never,
+    Remote,
+    Always,
+    Explicit,
+}
+
+// ListOptions controls what "git branch" lists and how much it shows about each branch
+struct ListOptions {
+    kind: Kind,
+    verbose: usize,
+    abbrev: Option<usize>,
+    patterns: Vec<String>,
+    merged: Vec<String>,
+    no_merged: Vec<String>,
+    contains: Vec<String>,
+    no_contains: Vec<String>,
+}
+
+// ListedBranch is one line of a branch listing: a branch, a remote-tracking branch or a
+// detached HEAD
+struct ListedBranch {
+    short_name: String,
+    label: String,
+    hash: String,
+    symref: Option<String>,
+    local: bool,
+    current: bool,
+}
+
+fn branch_usage() -> ! {
+    eprintln!("usage: git branch [<options>] [-r | -a] [--merged] [--no-merged]");
+    eprintln!("   or: git branch [<options>] [-f] <branch-name> [<start-point>]");
+    eprintln!("   or: git branch [<options>] [-l] [<pattern>...]");
+    eprintln!("   or: git branch [<options>] [-r] (-d | -D) <branch-name>...");
+    eprintln!("   or: git branch [<options>] (-m | -M) [<old-branch>] <new-branch>");
+    eprintln!("   or: git branch [<options>] (-c | -C) [<old-branch>] <new-branch>");
+    eprintln!();
+    eprintln!("Generic options");
+    eprintln!("    -v, --verbose         show hash and subject, give twice for upstream branch");
+    eprintln!("    -q, --quiet           suppress informational messages");
+    eprintln!("    -t, --track           set branch tracking configuration");
+    eprintln!("    -u, --set-upstream-to <upstream>");
+    eprintln!("                          change the upstream info");
+    eprintln!("    --unset-upstream      unset the upstream info");
+    eprintln!("    -r, --remotes         act on remote-tracking branches");
+    eprintln!("    --contains <commit>   print only branches that contain the commit");
+    eprintln!("    --no-contains <commit>");
+    eprintln!("                          print only branches that don't contain the commit");
+    eprintln!("    --abbrev[=<n>]        use <n> digits to display object names");
+    eprintln!();
+    eprintln!("Specific git-branch actions:");
+    eprintln!("    -a, --all             list both remote-tracking and local branches");
+    eprintln!("    -d, --delete          delete fully merged branch");
+    eprintln!("    -D                    delete branch (even if not merged)");
+    eprintln!("    -m, --move            move/rename a branch and its reflog");
+    eprintln!("    -M                    move/rename a branch, even if target exists");
+    eprintln!("    -c, --copy            copy a branch and its reflog");
+    eprintln!("    -C                    copy a branch, even if target exists");
+    eprintln!("    -l, --list            list branch names");
+    eprintln!("    --show-current        show current branch name");
+    eprintln!("    -f, --force           force creation, move/rename, deletion");
+    eprintln!("    --merged <commit>     print only branches that are merged");
+    eprintln!("    --no-merged <commit>  print only branches that are not merged");
+    eprintln!();
+    std::process::exit(129);
+}
+
+// The branch HEAD points at, if it points at one
+fn current_branch() -> Option<String> {
+    head_symbolic_target().and_then(|target| target.strip_prefix("refs/heads/").map(|name| name.to_string()))
+}
+
+// Where the repository is checked out, for messages about the current branch
+fn worktree_path() -> String {
+    std::env::current_dir().map(|path| path.display().to_string()).unwrap_or_default()
+}
+
+// Refuse a name for a new branch that is malformed, already taken or, even when forced,
+// the branch that is checked out
+fn check_new_branch(name: &str, force: bool) -> anyhow::Result<()> {
+    check_branch_name(name)?;
+    if read_ref(&format!("refs/heads/{}", name)).is_none() {
+        return Ok(());
+    }
+    if !force {
+        anyhow::bail!("a branch named '{}' already exists", name);
+    }
+    if current_branch().as_deref() == Some(name) {
+        anyhow::bail!("cannot force update the branch '{}' checked out at '{}'", name, worktree_path());
+    }
+    Ok(())
+}
+
+// The full name of the ref a start point names, following symbolic refs like
+// refs/remotes/origin/HEAD to the branch they point at
+fn real_ref_name(name: &str) -> Option<String> {
+    let mut full_name = expand_ref_name(name)?;
+    for _ in 0..5 {
+        match read_ref(&full_name)?.strip_prefix("ref: ") {
+            Some(target) => full_name = target.to_string(),
+            None => break,
+        }
+    }
+    Some(full_name)
+}
+
+// Resolve the commit a branch starts from along with the full name of the branch it names,
+// if it names one that could be tracked. Explicitly requested tracking fails when it can't be.
+fn branch_start(config: &Config, start: &str, explicit: bool) -> anyhow::Result<(String, Option<String>)> {
+    let Ok(hash) = resolve_revision(start) else {
+        if explicit {
+            eprintln!("fatal: the requested upstream branch '{}' does not exist", start);
+            if config.get_bool("advice.setUpstreamFailure") != Some(false) {
+                eprintln!("hint: ");
+                eprintln!("hint: If you are planning on basing your work on an upstream");
+                eprintln!("hint: branch that already exists at the remote, you may need to");
+                eprintln!("hint: run \"git fetch\" to retrieve it.");
+                eprintln!("hint: ");
+                eprintln!("hint: If you are planning to push out a new local branch that");
+                eprintln!("hint: will track its remote counterpart, you may want to use");
+                eprintln!("hint: \"git push -u\" to set the upstream config as you push.");
+                eprintln!("hint: Disable this message with \"git config advice.setUpstreamFailure false\"");
+            }
+            std::process::exit(128);
+        }
+        anyhow::bail!("not a valid object name: '{}'", start);
+    };
+    let real_ref = real_ref_name(start).filter(|name| name.starts_with("refs/heads/") || tracking_source(config, name).is_some());
+    if real_ref.is_none() && explicit {
+        anyhow::bail!("cannot set up tracking information; starting point '{}' is not a branch", start);
+    }
+    let peeled = peel_tags(&hash)?;
+    let kind = object_kind(&peeled)?;
+    if kind != "commit" {
+        eprintln!("error: object {} is a {}, not a commit", peeled, kind);
+        anyhow::bail!("not a valid branch point: '{}'", start);
+    }
+    Ok((peeled, real_ref))
+}
+
+// Point a branch's upstream at another branch, a remote's branch when the ref is fetched from
+// one and otherwise a local branch through remote "."
+fn setup_tracking(config: &Config, branch: &str, real_ref: &str, track: Track, quiet: bool) -> anyhow::Result<()> {
+    let (remote, merge) = match tracking_source(config, real_ref) {
+        Some(source) => source,
+        None if track == Track::Remote => return Ok(()),
+        None => (".".to_string(), real_ref.to_string()),
+    };
+    let short_name = merge.strip_prefix("refs/heads/");
+    if remote == "." && short_name == Some(branch) {
+        eprintln!("warning: not setting branch '{}' as its own upstream", branch);
+        return Ok(());
+    }
+    let rebasing = match config.get("branch.autoSetupRebase") {
+        Some("always") => true,
+        Some("local") => remote == ".",
+        Some("remote") => remote != ".",
+        _ => false,
+    };
+
+    set_config(&format!("branch.{}.remote", branch), Some(&remote))?;
+    set_config(&format!("branch.{}.merge", branch), Some(&merge))?;
+    if rebasing {
+        set_config(&format!("branch.{}.rebase", branch), Some("true"))?;
+    }
+    if !quiet {
+        let short_name = short_name.unwrap_or(&merge);
+        let upstream = if remote == "." { short_name.to_string() } else { format!("{}/{}", remote, short_name) };
+        let how = if rebasing { " by rebasing" } else { "" };
+        println!("branch '{}' set up to track '{}'{}.", branch, upstream, how);
+    }
+    Ok(())
+}
+
+// The tracking mode branch.autoSetupMerge asks for when neither --track nor --no-track is given
+fn default_track(config: &Config) -> Track {
+    match config.get("branch.autoSetupMerge") {
+        Some("always") => Track::Always,
+        _ if config.get_bool("branch.autoSetupMerge") == Some(false) => Track::Never,
+        _ => Track::Remote,
+    }
+}
+
+fn create_branch(config: &Config, name: &str, start: &str, force: bool, track: Track, quiet: bool) -> anyhow::Result<()> {
+    check_new_branch(name, force)?;
+    let (commit, real_ref) = branch_start(config, start, track == Track::Explicit)?;
+    let refname = format!("refs/heads/{}", name);
+    let message = match read_ref(&refname) {
+        Some(_) => format!("branch: Reset to {}", start),
+        None => format!("branch: Created from {}", start),
+    };
+    update_ref(&refname, &commit, &message)?;
+    match real_ref {
+        Some(real_ref) if track != Track::Never => setup_tracking(config, name, &real_ref, track, quiet),
+        _ => Ok(()),
+    }
+}
+
+// Whether a branch is merged into its upstream, or into HEAD when it has none, warning when
+// the two would disagree
+fn branch_merged(config: &Config, name: &str, commit: &str, head: Option<&str>) -> anyhow::Result<bool> {
+    let upstream = branch_upstream(config, name).and_then(|upstream| resolve_ref(&upstream).map(|hash| (upstream, hash)));
+    let (reference_name, reference) = match &upstream {
+        Some((upstream, hash)) => (upstream.as_str(), Some(peel_to_commit(hash)?)),
+        None => ("HEAD", head.map(|head| head.to_string())),
+    };
+    let merged = match &reference {
+        Some(reference) => is_ancestor(commit, reference)?,
+        None => false,
+    };
+    if reference.as_deref() != head {
+        let merged_to_head = match head {
+            Some(head) => is_ancestor(commit, head)?,
+            None => false,
+        };
+        if merged && !merged_to_head {
+            eprintln!("warning: deleting branch '{}' that has been merged to", name);
+            eprintln!("         '{}', but not yet merged to HEAD.", reference_name);
+        } else if !merged && merged_to_head {
+            eprintln!("warning: not deleting branch '{}' that is not yet merged to", name);
+            eprintln!("         '{}', even though it is merged to HEAD.", reference_name);
+        }
+    }
+    Ok(merged)
+}
+
+// Delete branches, refusing the checked out one and, unless forced, any that aren't merged.
+// Every name is tried before exiting 1 if any of them failed.
+fn delete_branches(config: &Config, names: &[String], remote: bool, force: bool, quiet: bool) -> anyhow::Result<()> {
+    if names.is_empty() {
+        anyhow::bail!("branch name required");
+    }
+    let current = current_branch();
+    let head = match resolve_ref("HEAD") {
+        Some(hash) => Some(peel_to_commit(&hash)?),
+        None => None,
+    };
+    let kind = if remote { "remote-tracking branch" } else { "branch" };
+    let mut failed = false;
+    for name in names {
+        let full_name = format!("refs/{}/{}", if remote { "remotes" } else { "heads" }, name);
+        if !remote && current.as_deref() == Some(name.as_str()) {
+            eprintln!("error: Cannot delete branch '{}' checked out at '{}'", name, worktree_path());
+            failed = true;
+            continue;
+        }
+        let Some(hash) = resolve_ref(&full_name) else {
+            eprintln!("error: {} '{}' not found.", kind, name);
+            failed = true;
+            continue;
+        };
+        if !force && !remote {
+            let commit = peel_to_commit(&hash)?;
+            if !branch_merged(config, name, &commit, head.as_deref())? {
+                eprintln!("error: The branch '{}' is not fully merged.", name);
+                eprintln!("If you are sure you want to delete it, run 'git branch -D {}'.", name);
+                failed = true;
+                continue;
+            }
+        }
+        delete_ref(&full_name)?;
+        if !remote {
+            rename_config_section(&format!("branch.{}", name), None)?;
+        }
+        if !quiet {
+            println!("Deleted {} {} (was {}).", kind, name, abbreviate_to(&hash, 7));
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Rename or copy a branch along with its reflog and config, moving HEAD with a renamed
+// current branch
+fn copy_or_rename_branch(old: &str, new: &str, copy: bool, force: bool) -> anyhow::Result<()> {
+    let old_ref = format!("refs/heads/{}", old);
+    let new_ref = format!("refs/heads/{}", new);
+    let is_head = current_branch().as_deref() == Some(old);
+    let exists = read_ref(&old_ref).is_some();
+    if (copy || !is_head) && !exists {
+        if is_head {
+            anyhow::bail!("No commit on branch '{}' yet.", old);
+        }
+        anyhow::bail!("No branch named '{}'.", old);
+    }
+    if old == new {
+        check_branch_name(new)?;
+    } else {
+        check_new_branch(new, force)?;
+    }
+
+    if copy {
+        copy_ref(&old_ref, &new_ref, &format!("Branch: copied {} to {}", old_ref, new_ref))?;
+    } else if exists {
+        rename_ref(&old_ref, &new_ref, &format!("Branch: renamed {} to {}", old_ref, new_ref))?;
+    }
+    if !copy && is_head {
+        write_ref("HEAD", &format!("ref: {}", new_ref))?;
+    }
+
+    let old_section = format!("branch.{}", old);
+    let new_section = format!("branch.{}", new);
+    if !copy {
+        rename_config_section(&old_section, Some(&new_section))?;
+    } else if old != new {
+        copy_config_section(&old_section, &new_section)?;
+    }
+    Ok(())
+}
+
+// The branch -u and --unset-upstream act on: the named one, or the current branch
+fn upstream_branch(names: &[String], action: &str, detached_message: &str) -> anyhow::Result<String> {
+    let name = match names {
+        [] => None,
+        [name] => Some(name.as_str()),
+        _ => anyhow::bail!("too many arguments to {}", action),
+    };
+    match name.filter(|&name| name != "HEAD") {
+        Some(name) => {
+            check_branch_name(name).map_err(|_| anyhow::anyhow!("no such branch '{}'", name))?;
+            Ok(name.to_string())
+        }
+        None => current_branch().ok_or_else(|| anyhow::anyhow!("{}", detached_message)),
+    }
+}
+
+fn set_upstream(config: &Config, upstream: &str, names: &[String], quiet: bool) -> anyhow::Result<()> {
+    let detached = format!("could not set upstream of HEAD to {} when it does not point to any branch.", upstream);
+    let branch = upstream_branch(names, "set new upstream", &detached)?;
+    if read_ref(&format!("refs/heads/{}", branch)).is_none() {
+        if names.is_empty() || current_branch().as_deref() == Some(branch.as_str()) {
+            anyhow::bail!("no commit on branch '{}' yet", branch);
+        }
+        anyhow::bail!("branch '{}' does not exist", branch);
+    }
+    let (_, real_ref) = branch_start(config, upstream, true)?;
+    match real_ref {
+        Some(real_ref) => setup_tracking(config, &branch, &real_ref, Track::Explicit, quiet),
+        None => Ok(()),
+    }
+}
+
+fn unset_upstream(config: &Config, names: &[String]) -> anyhow::Result<()> {
+    let detached = "could not unset upstream of HEAD when it does not point to any branch.";
+    let branch = upstream_branch(names, "unset upstream", detached)?;
+    if config.get(&format!("branch.{}.merge", branch)).is_none() {
+        anyhow::bail!("Branch '{}' has no upstream information", branch);
+    }
+    set_config(&format!("branch.{}.remote", branch), None)?;
+    set_config(&format!("branch.{}.merge", branch), None)
+}
+
+// Resolve the commits given to --merged, --contains and their negations
+fn filter_commits(names: &[String]) -> anyhow::Result<Vec<String>> {
+    names
+        .iter()
+        .map(|name| {
+            let hash = resolve_revision(name).map_err(|_| anyhow::anyhow!("malformed object name {}", name))?;
+            peel_to_commit(&hash).map_err(|_| anyhow::anyhow!("object '{}' is not a commit", name))
+        })
+        .collect()
+}
+
+// The tracking summary ahead/behind counts give, such as "ahead 1, behind 2", or "gone" when
+// the upstream no longer exists
+fn tracking_summary(config: &Config, branch: &str, commit: &str) -> anyhow::Result<Option<(String, String)>> {
+    let Some(upstream) = branch_upstream(config, branch) else {
+        return Ok(None);
+    };
+    let summary = match resolve_ref(&upstream) {
+        None => "gone".to_string(),
+        Some(hash) => match count_ahead_behind(commit, &peel_to_commit(&hash)?)? {
+            (0, 0) => String::new(),
+            (ahead, 0) => format!("ahead {}", ahead),
+            (0, behind) => format!("behind {}", behind),
+            (ahead, behind) => format!("ahead {}, behind {}", ahead, behind),
+        },
+    };
+    Ok(Some((shorten_ref_name(&upstream).to_string(), summary)))
+}
+
+fn list_branches(config: &Config, options: &ListOptions) -> anyhow::Result<()> {
+    let current = head_symbolic_target();
+    let mut branches = Vec::new();
+    if options.kind != Kind::Remote && current.is_none() {
+        if let Some(hash) = resolve_ref("HEAD") {
+            let label = match detached_description(Some(&hash)) {
+                Some(description) => format!("({})", description),
+                None => "(no branch)".to_string(),
+            };
+            branches.push(ListedBranch { short_name: "HEAD".to_string(), label, hash, symref: None, local: true, current: true });
+        }
+    }
+    for (name, hash) in list_refs() {
+        let (short_name, local) = match (name.strip_prefix("refs/heads/"), name.strip_prefix("refs/remotes/")) {
+            (Some(short_name), _) if options.kind != Kind::Remote => (short_name, true),
+            (_, Some(short_name)) if options.kind != Kind::Local => (short_name, false),
+            _ => continue,
+        };
+        let label = if !local && options.kind == Kind::All { format!("remotes/{}", short_name) } else { short_name.to_string() };
+        let symref = read_ref(&name).and_then(|value| value.strip_prefix("ref: ").map(|target| shorten_ref_name(target).to_string()));
+        let current = current.as_deref() == Some(name.as_str());
+        branches.push(ListedBranch { short_name: short_name.to_string(), label, hash, symref, local, current });
+    }
+
+    let merged = filter_commits(&options.merged)?;
+    let no_merged = filter_commits(&options.no_merged)?;
+    let contains = filter_commits(&options.contains)?;
+    let no_contains = filter_commits(&options.no_contains)?;
+    let mut shown = Vec::new();
+    for branch in branches {
+        if !options.patterns.is_empty() && !options.patterns.iter().any(|pattern| fnmatch(pattern.as_bytes(), branch.short_name.as_bytes())) {
+            continue;
+        }
+        let commit = peel_to_commit(&branch.hash)?;
+        let reachable_from = |commits: &[String]| -> anyhow::Result<bool> {
+            for other in commits {
+                if is_ancestor(&commit, other)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        let reaches = |commits: &[String]| -> anyhow::Result<bool> {
+            for other in commits {
+                if is_ancestor(other, &commit)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        let keep = (merged.is_empty() || reachable_from(&merged)?)
+            && !reachable_from(&no_merged)?
+            && (contains.is_empty() || reaches(&contains)?)
+            && !reaches(&no_contains)?;
+        if keep {
+            shown.push((branch, commit));
+        }
+    }
+
+    let width = shown.iter().map(|(branch, _)| branch.label.chars().count()).max().unwrap_or(0);
+    for (branch, commit) in &shown {
+        let prefix = if branch.current { "* " } else { "  " };
+        if options.verbose == 0 || (!branch.local && branch.symref.is_some()) {
+            match &branch.symref {
+                Some(target) if options.verbose > 0 => println!("{}{:<width$} -> {}", prefix, branch.label, target),
+                Some(target) => println!("{}{} -> {}", prefix, branch.label, target),
+                None => println!("{}{}", prefix, branch.label),
+            }
+            continue;
+        }
+
+        let object_name = match options.abbrev {
+            Some(length) => abbreviate_to(commit, length),
+            None => commit.clone(),
+        };
+        let tracking = if branch.local && branch.short_name != "HEAD" { tracking_summary(config, &branch.short_name, commit)? } else { None };
+        let tracking = match tracking {
+            Some((upstream, summary)) if options.verbose > 1 && summary.is_empty() => format!("[{}] ", upstream),
+            Some((upstream, summary)) if options.verbose > 1 => format!("[{}: {}] ", upstream, summary),
+            Some((_, summary)) if !summary.is_empty() => format!("[{}] ", summary),
+            _ => String::new(),
+        };
+        let subject = load_commit(commit)?.subject();
+        println!("{}{:<width$} {} {}{}", prefix, branch.label, object_name, tracking, subject);
+    }
+    Ok(())
+}
+
+// Take the commit an option like --merged applies to: its "=value", the next argument unless
+// the option comes last, or HEAD
+pub fn optional_commit(arg: &str, option: &str, args: &[String], index: &mut usize) -> Option<String> {
+    if let Some(value) = arg.strip_prefix(option).and_then(|rest| rest.strip_prefix('=')) {
+        return Some(value.to_string());
+    }
+    if arg != option {
+        return None;
+    }
+    match args.get(*index + 1) {
+        Some(value) => {
+            *index += 1;
+            Some(value.clone())
+        }
+        None => Some("HEAD".to_string()),
+    }
+}
+
+// List, create, delete, rename and copy branches, and configure what they track
+pub fn branch_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "branch").unwrap();
+    let config = read_config();
+    let mut expanded = Vec::new();
+    for arg in &args[command_index + 1..] {
+        let bundled = arg.len() > 2 && !arg.starts_with("--") && arg.starts_with('-');
+        if bundled && arg[1..].chars().all(|x| "adDfmMcCrvqlt".contains(x)) {
+            expanded.extend(arg[1..].chars().map(|x| format!("-{}", x)));
+        } else {
+            expanded.push(arg.clone());
+        }
+    }
+
+    let mut options = ListOptions {
+        kind: Kind::Local,
+        verbose: 0,
+        abbrev: Some(7),
+        patterns: Vec::new(),
+        merged: Vec::new(),
+        no_merged: Vec::new(),
+        contains: Vec::new(),
+        no_contains: Vec::new(),
+    };
+    let (mut delete, mut rename, mut copy, mut force, mut quiet) = (0, 0, 0, false, false);
+    let (mut list, mut show_current, mut unset) = (false, false, false);
+    let mut track = None;
+    let mut new_upstream = None;
+    let mut names = Vec::new();
+    let mut only_names = false;
+    let mut index = 0;
+    while index < expanded.len() {
+        let arg = expanded[index].as_str();
+        if only_names || !arg.starts_with('-') || arg == "-" {
+            names.push(arg.to_string());
+            index += 1;
+            continue;
+        }
+        match arg {
+            "--" => only_names = true,
+            "-a" | "--all" => options.kind = Kind::All,
+            "-r" | "--remotes" => options.kind = Kind::Remote,
+            "-d" | "--delete" => delete = delete.max(1),
+            "-D" => delete = 2,
+            "-m" | "--move" => rename = rename.max(1),
+            "-M" => rename = 2,
+            "-c" | "--copy" => copy = copy.max(1),
+            "-C" => copy = 2,
+            "-f" | "--force" => force = true,
+            "-q" | "--quiet" => quiet = true,
+            "-v" | "--verbose" => options.verbose += 1,
+            "-l" | "--list" => list = true,
+            "-t" | "--track" | "--track=direct" => track = Some(Track::Explicit),
+            "--no-track" => track = Some(Track::Never),
+            "--show-current" => show_current = true,
+            "--unset-upstream" => unset = true,
+            "--no-abbrev" => options.abbrev = None,
+            "--abbrev" => options.abbrev = Some(7),
+            "-u" | "--set-upstream-to" => {
+                index += 1;
+                let Some(upstream) = expanded.get(index) else {
+                    eprintln!("error: option `{}' requires a value", arg.trim_start_matches('-'));
+                    branch_usage();
+                };
+                new_upstream = Some(upstream.clone());
+            }
+            _ if arg.starts_with("--set-upstream-to=") => new_upstream = Some(arg["--set-upstream-to=".len()..].to_string()),
+            _ if arg.starts_with("-u") => new_upstream = Some(arg[2..].to_string()),
+            _ if arg.starts_with("--abbrev=") => {
+                let length: usize = arg["--abbrev=".len()..].parse().map_err(|_| anyhow::anyhow!("option `abbrev' expects a numerical value"))?;
+                options.abbrev = if length == 0 { None } else { Some(length) };
+            }
+            _ => {
+                let filters = [
+                    ("--merged", &mut options.merged),
+                    ("--no-merged", &mut options.no_merged),
+                    ("--contains", &mut options.contains),
+                    ("--no-contains", &mut options.no_contains),
+                ];
+                let mut matched = false;
+                for (option, commits) in filters {
+                    if let Some(commit) = optional_commit(arg, option, &expanded, &mut index) {
+                        commits.push(commit);
+                        matched = true;
+                        break;
+                    }
+                }
+                if !matched {
+                    eprintln!("error: unknown option `{}'", arg.trim_start_matches('-'));
+                    branch_usage();
+                }
+            }
+        }
+        index += 1;
+    }
+
+    let filtered = !options.merged.is_empty() || !options.no_merged.is_empty() || !options.contains.is_empty() || !options.no_contains.is_empty();
+    let acting = delete > 0 || rename > 0 || copy > 0 || new_upstream.is_some() || show_current || unset;
+    if !acting && names.is_empty() || filtered || options.verbose > 0 {
+        list = true;
+    }
+    let modes = [delete > 0, rename > 0, copy > 0, new_upstream.is_some(), show_current, list, unset];
+    if modes.iter().filter(|&&mode| mode).count() > 1 {
+        branch_usage();
+    }
+    if force {
+        for mode in [&mut delete, &mut rename, &mut copy] {
+            if *mode > 0 {
+                *mode = 2;
+            }
+        }
+    }
+
+    if show_current {
+        if let Some(branch) = current_branch() {
+            println!("{}", branch);
+        }
+        Ok(())
+    } else if delete > 0 {
+        delete_branches(&config, &names, options.kind == Kind::Remote, delete > 1, quiet)
+    } else if list {
+        options.patterns = names;
+        list_branches(&config, &options)
+    } else if copy > 0 || rename > 0 {
+        let force = copy > 1 || rename > 1;
+        let copy = copy > 0;
+        let action = if copy { "copy" } else { "rename" };
+        match names.as_slice() {
+            [] => anyhow::bail!("branch name required"),
+            [new] => match current_branch() {
+                Some(current) => copy_or_rename_branch(&current, new, copy, force),
+                None => anyhow::bail!("cannot {} the current branch while not on any.", action),
+            },
+            [old, new] => copy_or_rename_branch(old, new, copy, force),
+            _ => anyhow::bail!("too many arguments for a {} operation", action),
+        }
+    } else if let Some(upstream) = new_upstream {
+        set_upstream(&config, &upstream, &names, quiet)
+    } else if unset {
+        unset_upstream(&config, &names)
+    } else {
+        if names.len() > 2 {
+            branch_usage();
+        }
+        if options.kind != Kind::Local {
+            anyhow::bail!("The -a, and -r, options to 'git branch' do not take a branch name.\nDid you mean to use: -a|-r --list <pattern>?");
+        }
+        let start = match names.get(1) {
+            Some(start) => start.clone(),
+            None => current_branch().unwrap_or_else(|| "HEAD".to_string()),
+        };
+        let track = track.unwrap_or_else(|| default_track(&config));
+        create_branch(&config, &names[0], &start, force, track, quiet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{commit, repository, tree};
+
+    fn config() -> Config {
+        read_config_file(".git/config")
+    }
+
+    // A main branch with two commits, returned oldest first
+    fn main_branch() -> (String, String) {
+        let first = commit(&tree(&[]), &[], 1, "first");
+        let second = commit(&tree(&[]), &[&first], 2, "second");
+        std::fs::write(".git/refs/heads/main", format!("{}\n", second)).unwrap();
+        (first, second)
+    }
+
+    #[test]
+    fn creating() {
+        let _repository = repository();
+        let (first, second) = main_branch();
+        create_branch(&config(), "topic", "main", false, Track::Always, true).unwrap();
+        assert_eq!(resolve_ref("refs/heads/topic").as_deref(), Some(second.as_str()));
+        assert_eq!(config().get("branch.topic.remote"), Some("."));
+        assert_eq!(config().get("branch.topic.merge"), Some("refs/heads/main"));
+
+        let error = create_branch(&config(), "topic", "main", false, Track::Never, true).unwrap_err();
+        assert_eq!(error.to_string(), "a branch named 'topic' already exists");
+        create_branch(&config(), "topic", &first, true, Track::Never, true).unwrap();
+        assert_eq!(resolve_ref("refs/heads/topic").as_deref(), Some(first.as_str()));
+        let error = create_branch(&config(), "main", &first, true, Track::Never, true).unwrap_err();
+        assert!(error.to_string().starts_with("cannot force update the branch 'main' checked out at "));
+        assert!(create_branch(&config(), "a..b", "main", false, Track::Never, true).is_err());
+        let error = create_branch(&config(), "other", &first, false, Track::Explicit, true).unwrap_err();
+        assert_eq!(error.to_string(), format!("cannot set up tracking information; starting point '{}' is not a branch", first));
+        // Tracking remote-tracking branches only is the default, so a local start point is not tracked
+        create_branch(&config(), "plain", "main", false, default_track(&config()), true).unwrap();
+        assert_eq!(config().get("branch.plain.merge"), None);
+    }
+
+    #[test]
+    fn renaming() {
+        let _repository = repository();
+        main_branch();
+        create_branch(&config(), "topic", "main", false, Track::Always, true).unwrap();
+        copy_or_rename_branch("topic", "feature", false, false).unwrap();
+        assert!(read_ref("refs/heads/topic").is_none());
+        assert!(read_ref("refs/heads/feature").is_some());
+        assert_eq!((config().get("branch.topic.merge"), config().get("branch.feature.merge")), (None, Some("refs/heads/main")));
+
+        copy_or_rename_branch("feature", "copy", true, false).unwrap();
+        assert_eq!(resolve_ref("refs/heads/copy"), resolve_ref("refs/heads/feature"));
+        assert_eq!(config().get("branch.copy.merge"), Some("refs/heads/main"));
+
+        // Renaming the current branch moves HEAD along
+        copy_or_rename_branch("main", "trunk", false, false).unwrap();
+        assert_eq!(current_branch().as_deref(), Some("trunk"));
+        assert_eq!(copy_or_rename_branch("missing", "x", false, false).unwrap_err().to_string(), "No branch named 'missing'.");
+    }
+
+    #[test]
+    fn merged_and_tracking() {
+        let _repository = repository();
+        let (first, second) = main_branch();
+        create_branch(&config(), "old", &first, false, Track::Never, true).unwrap();
+        assert!(branch_merged(&config(), "old", &first, Some(&second)).unwrap());
+        let ahead = commit(&tree(&[]), &[&second], 3, "ahead");
+        assert!(!branch_merged(&config(), "new", &ahead, Some(&second)).unwrap());
+
+        assert_eq!(tracking_summary(&config(), "old", &first).unwrap(), None);
+        set_config("branch.old.remote", Some(".")).unwrap();
+        set_config("branch.old.merge", Some("refs/heads/main")).unwrap();
+        let summary = |commit: &str| tracking_summary(&config(), "old", commit).unwrap().unwrap();
+        assert_eq!(summary(&first), ("main".to_string(), "behind 1".to_string()));
+        assert_eq!(summary(&ahead), ("main".to_string(), "ahead 1".to_string()));
+        set_config("branch.old.merge", Some("refs/heads/gone")).unwrap();
+        assert_eq!(summary(&first), ("gone".to_string(), "gone".to_string()));
+
+        unset_upstream(&config(), &["old".to_string()]).unwrap();
+        let error = unset_upstream(&config(), &["old".to_string()]).unwrap_err();
+        assert_eq!(error.to_string(), "Branch 'old' has no upstream information");
+    }
+}
@@ -0,0 +1,571 @@
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::refs::{head_symbolic_target, read_ref, resolve_ref, update_ref, update_symbolic_ref};
+use crate::revision::{peel_to_commit, peel_to_tree, resolve_revision};
+use crate::worktree::{checkout_file, flatten_tree, pathspec_matches, remove_worktree_file, worktree_matches_index, TreeEntry};
+use crate::{read_object_from_store, GitObjectType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+
+// CheckoutConflict lists the paths that stop the working tree from being moved to another tree
+#[derive(Debug)]
+pub struct CheckoutConflict {
+    pub operation: &'static str,
+    pub advice: &'static str,
+    pub local_changes: Vec<String>,
+    pub untracked: Vec<String>,
+}
+
+impl fmt::Display for CheckoutConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut blocks = Vec::new();
+        if !self.local_changes.is_empty() {
+            blocks.push(format!(
+                "Your local changes to the following files would be overwritten by {}:\n\t{}\nPlease commit your changes or stash them before you {}.",
+                self.operation,
+                self.local_changes.join("\n\t"),
+                self.advice
+            ));
+        }
+        if !self.untracked.is_empty() {
+            blocks.push(format!(
+                "The following untracked working tree files would be overwritten by {}:\n\t{}\nPlease move or remove them before you {}.",
+                self.operation,
+                self.untracked.join("\n\t"),
+                self.advice
+            ));
+        }
+        write!(f, "{}\nAborting", blocks.join("\nerror: "))
+    }
+}
+
+impl std::error::Error for CheckoutConflict {}
+
+pub fn tree_entries(tree_hash: Option<&str>) -> anyhow::Result<BTreeMap<String, TreeEntry>> {
+    match tree_hash {
+        Some(tree_hash) => flatten_tree(tree_hash),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+pub fn entry_matches(entry: Option<&IndexEntry>, tree_entry: Option<&TreeEntry>) -> bool {
+    match (entry, tree_entry) {
+        (Some(entry), Some(tree_entry)) => entry.mode == tree_entry.mode && entry.sha_hash == tree_entry.sha_hash,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Files under a directory on disk that the index does not know about
+fn untracked_files_under(directory: &str, index: &BTreeMap<String, IndexEntry>, found: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = format!("{}/{}", directory, entry.file_name().to_string_lossy());
+        if index.contains_key(&path) {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => untracked_files_under(&path, index, found),
+            _ => found.push(path),
+        }
+    }
+}
+
+// Check whether writing a new file at a path would clobber something the index does not track:
+// an untracked file at the path, untracked files inside a directory there, or an untracked
+// file where one of its parent directories should go
+pub fn has_untracked_in_the_way(path: &str, index: &BTreeMap<String, IndexEntry>) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let mut found = Vec::new();
+            untracked_files_under(path, index, &mut found);
+            if !found.is_empty() {
+                return true;
+            }
+        }
+        Ok(_) => return true,
+        Err(_) => {}
+    }
+    let mut ancestor = std::path::Path::new(path).parent();
+    while let Some(directory) = ancestor {
+        let name = directory.to_string_lossy();
+        if !name.is_empty() && !index.contains_key(name.as_ref()) {
+            if let Ok(metadata) = fs::symlink_metadata(directory) {
+                if !metadata.is_dir() {
+                    return true;
+                }
+            }
+        }
+        ancestor = directory.parent();
+    }
+    false
+}
+
+// Move the index and working tree from one tree to another, like a branch switch.
+// Paths that are the same in both trees keep any local changes; paths that differ are
+// refused if they have local changes or an untracked file is in the way, unless forced.
+pub fn switch_tree(
+    old_tree: Option<&str>,
+    new_tree: Option<&str>,
+    force: bool,
+    operation: &'static str,
+    advice: &'static str,
+) -> anyhow::Result<()> {
+    let old_entries = tree_entries(old_tree)?;
+    let new_entries = tree_entries(new_tree)?;
+    let index_entries = read_index()?;
+
+    let unmerged: BTreeSet<String> = index_entries
+        .iter()
+        .filter(|entry| entry.stage() != 0)
+        .map(|entry| entry.path.clone())
+        .collect();
+    if !unmerged.is_empty() && !force {
+        let list: Vec<String> = unmerged.iter().map(|path| format!("{}: needs merge", path)).collect();
+        anyhow::bail!("{}\nyou need to resolve your current index first", list.join("\n"));
+    }
+    let index: BTreeMap<String, IndexEntry> = index_entries
+        .into_iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+
+    let mut paths: BTreeSet<String> = BTreeSet::new();
+    paths.extend(old_entries.keys().cloned());
+    paths.extend(new_entries.keys().cloned());
+    paths.extend(index.keys().cloned());
+    paths.extend(unmerged);
+
+    let mut local_changes = Vec::new();
+    let mut untracked = Vec::new();
+    let mut keep: Vec<IndexEntry> = Vec::new();
+    let mut removals: Vec<String> = Vec::new();
+    let mut updates: Vec<(String, TreeEntry)> = Vec::new();
+
+    for path in &paths {
+        let old = old_entries.get(path);
+        let new = new_entries.get(path);
+        let staged = index.get(path);
+
+        if force {
+            match new {
+                Some(new) if entry_matches(staged, Some(new)) && worktree_matches_index(staged.unwrap()) => {
+                    keep.push(staged.unwrap().clone());
+                }
+                Some(new) => updates.push((path.clone(), new.clone())),
+                None => removals.push(path.clone()),
+            }
+            continue;
+        }
+
+        if old == new || entry_matches(staged, new) {
+            if let Some(staged) = staged {
+                keep.push(staged.clone());
+            }
+            continue;
+        }
+        if !entry_matches(staged, old) || staged.map(|entry| !worktree_matches_index(entry)).unwrap_or(false) {
+            local_changes.push(path.clone());
+            continue;
+        }
+        if staged.is_none() && new.is_some() && has_untracked_in_the_way(path, &index) {
+            untracked.push(path.clone());
+            continue;
+        }
+        match new {
+            Some(new) => updates.push((path.clone(), new.clone())),
+            None => removals.push(path.clone()),
+        }
+    }
+
+    if !local_changes.is_empty() || !untracked.is_empty() {
+        return Err(CheckoutConflict { operation, advice, local_changes, untracked }.into());
+    }
+
+    // Remove first so a file can replace a directory of the same name and vice versa
+    for path in &removals {
+        remove_worktree_file(path)?;
+    }
+    for (path, entry) in updates {
+        keep.push(checkout_file(&path, entry.mode, &entry.sha_hash)?);
+    }
+    write_index(&keep)
+}
+
+// Tree of the commit HEAD points at, or None on an unborn branch
+pub fn head_tree() -> anyhow::Result<Option<String>> {
+    match resolve_ref("HEAD") {
+        Some(hash) => Ok(Some(peel_to_tree(&hash)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn short_description(hash: &str) -> String {
+    let subject = match read_object_from_store(hash) {
+        Ok(GitObjectType::Commit(commit)) => commit.subject().to_string(),
+        _ => String::new(),
+    };
+    format!("{} {}", &hash[..7], subject)
+}
+
+// Reject branch names that git would not accept as refs
+pub fn check_branch_name(name: &str) -> anyhow::Result<()> {
+    let invalid = name.is_empty()
+        || name.starts_with('-')
+        || name.starts_with('.')
+        || name.ends_with('/')
+        || name.ends_with(".lock")
+        || name.ends_with('.')
+        || name.contains("..")
+        || name.contains("//")
+        || name.contains("@{")
+        || name == "HEAD"
+        || name.chars().any(|x| x.is_ascii_control() || " ~^:?*[\\".contains(x));
+    if invalid {
+        anyhow::bail!("'{}' is not a valid branch name", name);
+    }
+    Ok(())
+}
+
+// Where a branch switch is going: an existing branch, a new branch to create from a start point,
+// a new branch on an unborn HEAD, which has no commit to start from, or a commit to detach at
+// along with the name it was given by
+enum SwitchTarget {
+    Branch(String),
+    NewBranch { name: String, reset: bool, start: String },
+    Unborn(String),
+    Detached(String),
+}
+
+// Move HEAD to a commit, updating the index and working tree first
+fn switch_to(commit: &str, target: SwitchTarget, force: bool, quiet: bool) -> anyhow::Result<()> {
+    let old_head = resolve_ref("HEAD");
+    let old_branch = head_symbolic_target();
+    // An unborn branch has no tree, the index and working tree stay as they are
+    if !matches!(target, SwitchTarget::Unborn(_)) {
+        let new_tree = peel_to_tree(commit)?;
+        switch_tree(head_tree()?.as_deref(), Some(&new_tree), force, "checkout", "switch branches")?;
+    }
+
+    if old_branch.is_none() && !quiet {
+        if let Some(old_head) = &old_head {
+            if old_head != commit || !matches!(target, SwitchTarget::Detached(_)) {
+                eprintln!("Previous HEAD position was {}", short_description(old_head));
+            }
+        }
+    }
+
+    let from = match &old_branch {
+        Some(branch) => branch.strip_prefix("refs/heads/").unwrap_or(branch).to_string(),
+        None => old_head.clone().unwrap_or_default(),
+    };
+    let moving = |to: &str| format!("checkout: moving from {} to {}", from, to);
+    match target {
+        SwitchTarget::Branch(name) => {
+            let refname = format!("refs/heads/{}", name);
+            update_symbolic_ref("HEAD", &refname, &moving(&name))?;
+            if !quiet {
+                if old_branch.as_deref() == Some(refname.as_str()) {
+                    eprintln!("Already on '{}'", name);
+                } else {
+                    eprintln!("Switched to branch '{}'", name);
+                }
+            }
+        }
+        SwitchTarget::NewBranch { name, reset, start } => {
+            let refname = format!("refs/heads/{}", name);
+            let existed = read_ref(&refname).is_some();
+            let message = if existed { format!("branch: Reset to {}", start) } else { format!("branch: Created from {}", start) };
+            update_ref(&refname, commit, &message)?;
+            update_symbolic_ref("HEAD", &refname, &moving(&name))?;
+            if !quiet {
+                if reset && existed {
+                    eprintln!("Switched to and reset branch '{}'", name);
+                } else {
+                    eprintln!("Switched to a new branch '{}'", name);
+                }
+            }
+        }
+        SwitchTarget::Unborn(name) => {
+            update_symbolic_ref("HEAD", &format!("refs/heads/{}", name), &moving(&name))?;
+            if !quiet {
+                eprintln!("Switched to a new branch '{}'", name);
+            }
+        }
+        SwitchTarget::Detached(name) => {
+            update_ref("HEAD", commit, &moving(&name))?;
+            if !quiet {
+                eprintln!("HEAD is now at {}", short_description(commit));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Decide where a branch switch goes from the new branch option, its start point or the
+// named branch, returning the commit to check out
+fn plan_switch(
+    new_branch: Option<(String, bool)>,
+    name: Option<&str>,
+    detach: bool,
+) -> anyhow::Result<(String, SwitchTarget)> {
+    if let Some((new_name, reset)) = new_branch {
+        check_branch_name(&new_name)?;
+        if !reset && read_ref(&format!("refs/heads/{}", new_name)).is_some() {
+            anyhow::bail!("a branch named '{}' already exists", new_name);
+        }
+        if name.is_none() && resolve_ref("HEAD").is_none() {
+            return Ok((String::new(), SwitchTarget::Unborn(new_name)));
+        }
+        let start = name.unwrap_or("HEAD");
+        let commit = peel_to_commit(&resolve_revision(start)?)?;
+        return Ok((commit, SwitchTarget::NewBranch { name: new_name, reset, start: start.to_string() }));
+    }
+
+    let name = name.unwrap_or("HEAD");
+    let refname = format!("refs/heads/{}", name);
+    if !detach && read_ref(&refname).is_some() {
+        let commit = resolve_ref(&refname).ok_or_else(|| anyhow::anyhow!("branch '{}' is broken", name))?;
+        return Ok((commit, SwitchTarget::Branch(name.to_string())));
+    }
+    let commit = peel_to_commit(&resolve_revision(name)?)?;
+    Ok((commit, SwitchTarget::Detached(name.to_string())))
+}
+
+// Copy paths from a tree, or from the index when no tree is given, into the working tree.
+// Paths copied from a tree are staged as well.
+fn checkout_paths(tree_ish: Option<&str>, pathspecs: &[String]) -> anyhow::Result<()> {
+    let mut index = read_index()?;
+    let mut matched: BTreeSet<&String> = BTreeSet::new();
+
+    match tree_ish {
+        Some(tree_ish) => {
+            let tree = peel_to_tree(&resolve_revision(tree_ish)?)?;
+            let entries = flatten_tree(&tree)?;
+            for (path, entry) in &entries {
+                let Some(pathspec) = pathspecs.iter().find(|pathspec| pathspec_matches(pathspec, path)) else {
+                    continue;
+                };
+                matched.insert(pathspec);
+                index.retain(|staged| &staged.path != path);
+                index.push(checkout_file(path, entry.mode, &entry.sha_hash)?);
+            }
+        }
+        None => {
+            for staged in index.iter_mut() {
+                let Some(pathspec) = pathspecs.iter().find(|pathspec| pathspec_matches(pathspec, &staged.path)) else {
+                    continue;
+                };
+                matched.insert(pathspec);
+                if staged.stage() != 0 {
+                    anyhow::bail!("path '{}' is unmerged", staged.path);
+                }
+                if !worktree_matches_index(staged) {
+                    *staged = checkout_file(&staged.path, staged.mode, &staged.sha_hash)?;
+                }
+            }
+        }
+    }
+
+    if let Some(pathspec) = pathspecs.iter().find(|pathspec| !matched.contains(pathspec)) {
+        anyhow::bail!("pathspec '{}' did not match any file(s) known to git", pathspec);
+    }
+    write_index(&index)
+}
+
+pub fn checkout_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "checkout").unwrap();
+    let mut force = false;
+    let mut quiet = false;
+    let mut detach = false;
+    let mut new_branch = None;
+    let mut positional = Vec::new();
+    let mut pathspecs: Option<Vec<String>> = None;
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "-f" | "--force" => force = true,
+            "--detach" => detach = true,
+            "-q" | "--quiet" => quiet = true,
+            "-b" | "-B" => {
+                index += 1;
+                let name = args.get(index).ok_or_else(|| anyhow::anyhow!("switch '{}' requires a value", &arg[1..]))?;
+                new_branch = Some((name.clone(), arg == "-B"));
+            }
+            "--" => {
+                pathspecs = Some(args[index + 1..].to_vec());
+                break;
+            }
+            _ => positional.push(args[index].clone()),
+        }
+        index += 1;
+    }
+
+    if let Some(pathspecs) = pathspecs {
+        if positional.len() > 1 {
+            anyhow::bail!("only one reference expected, {} given", positional.len());
+        }
+        return checkout_paths(positional.first().map(|x| x.as_str()), &pathspecs);
+    }
+    if new_branch.is_none() && !detach {
+        // Without "--", an argument that is not a revision is taken as a path
+        if let Some(first) = positional.first() {
+            if resolve_revision(first).is_err() {
+                return checkout_paths(None, &positional);
+            }
+            if positional.len() > 1 {
+                return checkout_paths(Some(first), &positional[1..]);
+            }
+        }
+    }
+
+    let (commit, target) = plan_switch(new_branch, positional.first().map(|x| x.as_str()), detach)?;
+    switch_to(&commit, target, force, quiet)
+}
+
+pub fn switch_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "switch").unwrap();
+    let mut force = false;
+    let mut quiet = false;
+    let mut detach = false;
+    let mut new_branch = None;
+    let mut positional = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "-f" | "--force" | "--discard-changes" => force = true,
+            "-d" | "--detach" => detach = true,
+            "-q" | "--quiet" => quiet = true,
+            "-c" | "--create" | "-C" | "--force-create" => {
+                index += 1;
+                let name = args.get(index).ok_or_else(|| anyhow::anyhow!("option '{}' requires a value", arg))?;
+                new_branch = Some((name.clone(), arg == "-C" || arg == "--force-create"));
+            }
+            _ => positional.push(args[index].clone()),
+        }
+        index += 1;
+    }
+
+    if new_branch.is_none() {
+        let name = positional.first().ok_or_else(|| anyhow::anyhow!("missing branch or commit argument"))?;
+        if !detach && read_ref(&format!("refs/heads/{}", name)).is_none() {
+            resolve_revision(name)?;
+            anyhow::bail!("a branch is expected, got '{}'\nhint: If you want to detach HEAD at the commit, try again with the --detach option.", name);
+        }
+    }
+    let (commit, target) = plan_switch(new_branch, positional.first().map(|x| x.as_str()), detach)?;
+    switch_to(&commit, target, force, quiet)
+}
+
+pub fn restore_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "restore").unwrap();
+    let mut source = None;
+    let mut staged = false;
+    let mut worktree = false;
+    let mut pathspecs = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "-S" | "--staged" => staged = true,
+            "-W" | "--worktree" => worktree = true,
+            "-q" | "--quiet" | "--" => {}
+            "-s" | "--source" => {
+                index += 1;
+                source = Some(args.get(index).ok_or_else(|| anyhow::anyhow!("option '{}' requires a value", arg))?.clone());
+            }
+            _ if arg.starts_with("--source=") => source = Some(arg["--source=".len()..].to_string()),
+            _ => pathspecs.push(args[index].clone()),
+        }
+        index += 1;
+    }
+    if pathspecs.is_empty() {
+        anyhow::bail!("you must specify path(s) to restore");
+    }
+    if !staged {
+        worktree = true;
+    }
+
+    let mut index_entries = read_index()?;
+    // Restoring only the working tree copies from the index unless a source is named
+    let source_entries: BTreeMap<String, TreeEntry> = match (&source, staged) {
+        (None, false) => index_entries
+            .iter()
+            .filter(|entry| entry.stage() == 0)
+            .map(|entry| (entry.path.clone(), TreeEntry { mode: entry.mode, sha_hash: entry.sha_hash.clone() }))
+            .collect(),
+        _ => {
+            let tree = match &source {
+                Some(source) => Some(peel_to_tree(&resolve_revision(source)?)?),
+                None => head_tree()?,
+            };
+            tree_entries(tree.as_deref())?
+        }
+    };
+
+    let mut paths: BTreeSet<String> = source_entries.keys().cloned().collect();
+    paths.extend(index_entries.iter().map(|entry| entry.path.clone()));
+    let mut matched: BTreeSet<&String> = BTreeSet::new();
+
+    for path in &paths {
+        let Some(pathspec) = pathspecs.iter().find(|pathspec| pathspec_matches(pathspec, path)) else {
+            continue;
+        };
+        matched.insert(pathspec);
+        let source_entry = source_entries.get(path);
+        let current = index_entries.iter().position(|entry| &entry.path == path && entry.stage() == 0);
+
+        if worktree {
+            match source_entry {
+                Some(entry) => {
+                    let up_to_date = current
+                        .map(|position| {
+                            let staged_entry = &index_entries[position];
+                            entry_matches(Some(staged_entry), Some(entry)) && worktree_matches_index(staged_entry)
+                        })
+                        .unwrap_or(false);
+                    if !up_to_date {
+                        let written = checkout_file(path, entry.mode, &entry.sha_hash)?;
+                        // The index keeps its own version unless it is being restored too
+                        if let (Some(position), false) = (current, staged) {
+                            if entry_matches(Some(&index_entries[position]), Some(entry)) {
+                                index_entries[position] = written;
+                            }
+                        }
+                    }
+                }
+                None => {
+                    if current.is_some() {
+                        remove_worktree_file(path)?;
+                    }
+                }
+            }
+        }
+
+        if staged {
+            if current.map(|position| entry_matches(Some(&index_entries[position]), source_entry)).unwrap_or(false) {
+                continue;
+            }
+            index_entries.retain(|entry| &entry.path != path);
+            if let Some(entry) = source_entry {
+                let restored = match fs::symlink_metadata(path) {
+                    Ok(metadata) if worktree => IndexEntry::from_metadata(path, entry.mode, &entry.sha_hash, &metadata),
+                    _ => IndexEntry::without_stat(path, entry.mode, &entry.sha_hash),
+                };
+                index_entries.push(restored);
+            }
+        }
+    }
+
+    if let Some(pathspec) = pathspecs.iter().find(|pathspec| !matched.contains(pathspec)) {
+        anyhow::bail!("pathspec '{}' did not match any file(s) known to git", pathspec);
+    }
+    write_index(&index_entries)
+}
@@ -0,0 +1,562 @@
+// Combined diffs show a merge against all of its parents at once. Only paths that differ
+// from every parent are listed, and the dense form used by show drops hunks where the result
+// simply took one parent's side.
+
+use crate::diff::{quote_path, FileChange, TreeDiff, NULL_HASH};
+use crate::index::IndexEntry;
+use crate::patch::{is_binary, side_contents, DiffOptions};
+use crate::rename::Renames;
+use crate::walk::abbreviate_to;
+use crate::worktree::mode_from_metadata;
+
+// CombinedParent is one parent's version of a path in a combined diff
+#[derive(Clone)]
+pub struct CombinedParent {
+    pub mode: u32,
+    pub hash: String,
+    pub status: char,
+}
+
+// CombinedPath is a path the merge result changes relative to every parent
+#[derive(Clone)]
+pub struct CombinedPath {
+    pub path: String,
+    pub mode: u32,
+    pub hash: String,
+    pub parents: Vec<CombinedParent>,
+}
+
+// The paths a merge's tree differs in from each of its parents' trees. A parent's version of
+// a renamed path is the file it was renamed from.
+pub fn combined_paths(parent_trees: &[String], tree: &str, paths: &[String], renames: &Renames) -> anyhow::Result<Vec<CombinedPath>> {
+    let tree_diff = TreeDiff { recursive: true, show_trees: false, paths: paths.to_vec() };
+    let mut combined: Vec<CombinedPath> = Vec::new();
+    for (index, parent_tree) in parent_trees.iter().enumerate() {
+        let changes = renames.diff_trees(&tree_diff, Some(parent_tree), Some(tree))?;
+        let parent = |change: &FileChange| CombinedParent {
+            mode: change.old_mode,
+            hash: change.old_hash.clone(),
+            status: change.kind.letter(),
+        };
+        if index == 0 {
+            for change in &changes {
+                combined.push(CombinedPath {
+                    path: change.new_path.clone(),
+                    mode: change.new_mode,
+                    hash: change.new_hash.clone(),
+                    parents: vec![parent(change)],
+                });
+            }
+            continue;
+        }
+        combined.retain_mut(|path| match changes.iter().find(|change| change.new_path == path.path) {
+            Some(change) => {
+                path.parents.push(parent(change));
+                true
+            }
+            None => false,
+        });
+    }
+    Ok(combined)
+}
+
+// Lost is a line the result dropped, with a bit set for each parent that had it
+struct Lost {
+    line: Vec<u8>,
+    parents: usize,
+}
+
+// Line is one line of the result, plus the lines lost just before it. Bit n of flag is set
+// when the line is not in parent n; the bits above mark lines to show, and lines whose
+// losses are not shown since they only appear as leading context.
+#[derive(Default)]
+struct Line<'a> {
+    text: Option<&'a [u8]>,
+    lost: Vec<Lost>,
+    pending: Vec<Vec<u8>>,
+    flag: usize,
+    parent_lines: Vec<usize>,
+}
+
+// Fold the lines a parent lost into those other parents lost at the same place, lining
+// them up by their longest common subsequence so shared lines are shown once
+// A conflicted path as the file on disk against our and their stages, the way diff shows it
+// during a merge. Conflicts missing either side are left out.
+pub fn conflict_path(index: &[IndexEntry], path: &str) -> Option<CombinedPath> {
+    let stage = |stage: u16| index.iter().find(|x| x.path == path && x.stage() == stage);
+    let parents = [stage(2)?, stage(3)?]
+        .into_iter()
+        .map(|entry| CombinedParent { mode: entry.mode, hash: entry.sha_hash.clone(), status: 'M' })
+        .collect();
+    let mode = std::fs::symlink_metadata(path).map(|metadata| mode_from_metadata(&metadata)).unwrap_or(0);
+    Some(CombinedPath { path: path.to_string(), mode, hash: NULL_HASH.to_string(), parents })
+}
+
+fn coalesce_lost(base: &mut Vec<Lost>, new: Vec<Vec<u8>>, parent: usize) {
+    if new.is_empty() {
+        return;
+    }
+    if base.is_empty() {
+        base.extend(new.into_iter().map(|line| Lost { line, parents: 1 << parent }));
+        return;
+    }
+    #[derive(Clone, Copy, PartialEq)]
+    enum Direction {
+        Match,
+        Base,
+        New,
+    }
+    let mut lcs = vec![vec![0usize; new.len() + 1]; base.len() + 1];
+    let mut directions = vec![vec![Direction::Base; new.len() + 1]; base.len() + 1];
+    for direction in directions[0].iter_mut().skip(1) {
+        *direction = Direction::New;
+    }
+    for i in 1..=base.len() {
+        for j in 1..=new.len() {
+            if base[i - 1].line == new[j - 1] {
+                lcs[i][j] = lcs[i - 1][j - 1] + 1;
+                directions[i][j] = Direction::Match;
+            } else if lcs[i][j - 1] >= lcs[i - 1][j] {
+                lcs[i][j] = lcs[i][j - 1];
+                directions[i][j] = Direction::New;
+            } else {
+                lcs[i][j] = lcs[i - 1][j];
+                directions[i][j] = Direction::Base;
+            }
+        }
+    }
+    let (mut i, mut j) = (base.len(), new.len());
+    while i != 0 || j != 0 {
+        match directions[i][j] {
+            Direction::Match => {
+                base[i - 1].parents |= 1 << parent;
+                i -= 1;
+                j -= 1;
+            }
+            Direction::New => {
+                base.insert(i, Lost { line: new[j - 1].clone(), parents: 1 << parent });
+                j -= 1;
+            }
+            Direction::Base => i -= 1,
+        }
+    }
+}
+
+// Record how one parent differs from the result: lines it lacks get the parent's bit and
+// lines only it has become lost lines. Then number the parent's lines for hunk headers.
+fn compare_parent(options: &DiffOptions, lines: &mut [Line], parent_data: &[u8], result: &[u8], parent: usize) {
+    let mask = 1 << parent;
+    let (parent_lines, _, changes) = options.line_changes(parent_data, result, 0);
+    for change in changes {
+        // Lost lines hang off the result line after them, the first added one if any
+        for line in &parent_lines[change.old_start..change.old_start + change.old_count] {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            lines[change.new_start].pending.push(line.to_vec());
+        }
+        for line in &mut lines[change.new_start..change.new_start + change.new_count] {
+            line.flag |= mask;
+        }
+    }
+
+    let count = lines.len() - 2;
+    let mut number = 1;
+    for line in lines.iter_mut().take(count + 1) {
+        line.parent_lines[parent] = number;
+        let pending = std::mem::take(&mut line.pending);
+        coalesce_lost(&mut line.lost, pending, parent);
+        number += line.lost.iter().filter(|lost| lost.parents & mask != 0).count();
+        if line.text.is_some() && line.flag & mask == 0 {
+            number += 1;
+        }
+    }
+    lines[count + 1].parent_lines[parent] = number;
+}
+
+// A parent with the same contents as an earlier one shares its results
+fn reuse_parent(lines: &mut [Line], parent: usize, earlier: usize) {
+    for line in lines.iter_mut() {
+        line.parent_lines[parent] = line.parent_lines[earlier];
+        for lost in &mut line.lost {
+            if lost.parents & 1 << earlier != 0 {
+                lost.parents |= 1 << parent;
+            }
+        }
+        if line.flag & 1 << earlier != 0 {
+            line.flag |= 1 << parent;
+        }
+    }
+}
+
+fn is_interesting(line: &Line, all_mask: usize) -> bool {
+    line.flag & all_mask != 0 || !line.lost.is_empty()
+}
+
+// A hunk's last line that only carries deletions is shown anyway, so it counts as context
+fn adjust_hunk_tail(lines: &[Line], all_mask: usize, hunk_begin: usize, end: usize) -> usize {
+    match hunk_begin < end && lines[end - 1].flag & all_mask == 0 {
+        true => end - 1,
+        false => end,
+    }
+}
+
+// The next line from start, up to the sentinel, that is marked or unmarked
+fn find_next(lines: &[Line], mark: usize, start: usize, count: usize, unmarked: bool) -> usize {
+    (start..=count).find(|&i| (lines[i].flag & mark == 0) == unmarked).unwrap_or(count + 1)
+}
+
+// Mark context lines around the interesting ones, joining groups close enough together
+fn give_context(lines: &mut [Line], count: usize, num_parents: usize, context: usize) -> bool {
+    let all_mask = (1 << num_parents) - 1;
+    let mark = 1 << num_parents;
+    let no_pre_delete = 2 << num_parents;
+    let mut i = find_next(lines, mark, 0, count, false);
+    if count < i {
+        return false;
+    }
+    while i <= count {
+        for line in &mut lines[i.saturating_sub(context)..i] {
+            if line.flag & mark == 0 {
+                line.flag |= no_pre_delete;
+            }
+            line.flag |= mark;
+        }
+        loop {
+            let mut j = find_next(lines, mark, i, count, true);
+            if count < j {
+                return true;
+            }
+            let k = find_next(lines, mark, j, count, false);
+            j = adjust_hunk_tail(lines, all_mask, i, j);
+            if k < j + context {
+                // The gap to the next interesting line is small enough to show whole
+                for line in &mut lines[j..k] {
+                    line.flag |= mark;
+                }
+                i = k;
+                continue;
+            }
+            let end = (j + context).min(count + 1);
+            for line in &mut lines[j..end] {
+                line.flag |= mark;
+            }
+            i = k;
+            break;
+        }
+    }
+    true
+}
+
+// Mark the lines to show. Dense output leaves out hunks where every change comes from the
+// same set of parents other than all of them, meaning the result took one side as it was.
+fn make_hunks(lines: &mut [Line], count: usize, num_parents: usize, dense: bool, context: usize) -> bool {
+    let all_mask = (1 << num_parents) - 1;
+    let mark = 1 << num_parents;
+    for line in lines.iter_mut().take(count + 1) {
+        match is_interesting(line, all_mask) {
+            true => line.flag |= mark,
+            false => line.flag &= !mark,
+        }
+    }
+    if !dense {
+        return give_context(lines, count, num_parents, context);
+    }
+
+    let mut i = 0;
+    while i <= count {
+        while i <= count && lines[i].flag & mark == 0 {
+            i += 1;
+        }
+        if count < i {
+            break;
+        }
+        let hunk_begin = i;
+        let mut j = i + 1;
+        while j <= count {
+            if lines[j].flag & mark == 0 {
+                // Look past the end for an interesting line within the context span
+                let mut lookahead = adjust_hunk_tail(lines, all_mask, hunk_begin, j);
+                lookahead = (lookahead + context).min(count + 1);
+                let mut continues = false;
+                while lookahead > 0 && j < lookahead {
+                    lookahead -= 1;
+                    if lookahead < j {
+                        break;
+                    }
+                    if lines[lookahead].flag & mark != 0 {
+                        continues = true;
+                        break;
+                    }
+                }
+                if !continues {
+                    break;
+                }
+                j = lookahead;
+            }
+            j += 1;
+        }
+        let hunk_end = j;
+
+        let mut same_diff = 0;
+        let mut has_interesting = false;
+        'lines: for line in &lines[i..hunk_end] {
+            let this_diff = line.flag & all_mask;
+            if this_diff != 0 {
+                if same_diff == 0 {
+                    same_diff = this_diff;
+                } else if same_diff != this_diff {
+                    has_interesting = true;
+                    break;
+                }
+            }
+            for lost in &line.lost {
+                if same_diff == 0 {
+                    same_diff = lost.parents;
+                } else if same_diff != lost.parents {
+                    has_interesting = true;
+                    break 'lines;
+                }
+            }
+        }
+        if !has_interesting && same_diff != all_mask {
+            for line in &mut lines[hunk_begin..hunk_end] {
+                line.flag &= !mark;
+            }
+        }
+        i = hunk_end;
+    }
+    give_context(lines, count, num_parents, context)
+}
+
+// Lines starting with a letter, "_" or "$" name the function a hunk is in
+fn is_function_line(text: Option<&[u8]>) -> bool {
+    matches!(text.and_then(|x| x.first()), Some(x) if x.is_ascii_alphabetic() || *x == b'_' || *x == b'$')
+}
+
+fn write_lines(lines: &[Line], count: usize, num_parents: usize, context: usize, output: &mut Vec<u8>) {
+    let mark = 1 << num_parents;
+    let no_pre_delete = 2 << num_parents;
+    let mut number = 0;
+    loop {
+        let mut function = None;
+        while number <= count && lines[number].flag & mark == 0 {
+            if is_function_line(lines[number].text) {
+                function = lines[number].text;
+            }
+            number += 1;
+        }
+        if count < number {
+            break;
+        }
+        let hunk_end = (number + 1..=count).find(|&i| lines[i].flag & mark == 0).unwrap_or(count + 1);
+        let mut result_count = hunk_end - number;
+        if count < hunk_end {
+            // The sentinel only holds deletions at the end
+            result_count -= 1;
+        }
+        let mut null_context = 0;
+        if context == 0 {
+            // Lines without changes are walked for their deletions but not shown
+            null_context = lines[number..hunk_end].iter().filter(|line| line.flag & (mark - 1) == 0).count();
+            // git's unsigned arithmetic wraps here for an empty result
+            result_count = result_count.wrapping_sub(null_context);
+        }
+
+        let markers = "@".repeat(num_parents + 1);
+        let mut header = markers.clone();
+        for parent in 0..num_parents {
+            let start = lines[number].parent_lines[parent];
+            let end = lines[hunk_end].parent_lines[parent];
+            header.push_str(&format!(" -{},{}", start, (end - start).wrapping_sub(null_context)));
+        }
+        header.push_str(&format!(" +{},{} {}", number + 1, result_count, markers));
+        output.extend(header.into_bytes());
+        if let Some(function) = function {
+            // Up to the last non-blank character among the first 40, which itself is left off
+            let mut end = 0;
+            for (i, x) in function.iter().take(40).enumerate() {
+                if *x == 0 {
+                    break;
+                }
+                if !matches!(x, b' ' | b'\t' | b'\r') {
+                    end = i;
+                }
+            }
+            if end > 0 {
+                output.push(b' ');
+                output.extend_from_slice(&function[..end]);
+            }
+        }
+        output.push(b'\n');
+
+        while number < hunk_end {
+            let line = &lines[number];
+            number += 1;
+            if line.flag & no_pre_delete == 0 {
+                for lost in &line.lost {
+                    for parent in 0..num_parents {
+                        output.push(if lost.parents & 1 << parent != 0 { b'-' } else { b' ' });
+                    }
+                    output.extend_from_slice(&lost.line);
+                    output.push(b'\n');
+                }
+            }
+            if count < number {
+                break;
+            }
+            if line.flag & (mark - 1) == 0 && context == 0 {
+                // The line was only there to hold the deletions before it
+                continue;
+            }
+            for parent in 0..num_parents {
+                output.push(if line.flag & 1 << parent != 0 { b'+' } else { b' ' });
+            }
+            output.extend_from_slice(line.text.unwrap_or_default());
+            output.push(b'\n');
+        }
+    }
+}
+
+impl DiffOptions {
+    // "diff --cc" and "index" lines, plus the modes when any parent's differs
+    fn write_combined_header(&self, path: &CombinedPath, dense: bool, file_header: bool, output: &mut Vec<u8>) {
+        let kind = if dense { "cc" } else { "combined" };
+        let abbreviate = |hash: &str| match self.full_index {
+            true => hash.to_string(),
+            false => abbreviate_to(hash, 7),
+        };
+        let parent_hashes: Vec<String> = path.parents.iter().map(|parent| abbreviate(&parent.hash)).collect();
+        output.extend(format!("diff --{} {}\n", kind, quote_path(&path.path, false)).into_bytes());
+        output.extend(format!("index {}..{}\n", parent_hashes.join(","), abbreviate(&path.hash)).into_bytes());
+
+        let mut added = false;
+        let deleted = path.mode == 0;
+        if path.parents.iter().any(|parent| parent.mode != path.mode) {
+            // Only called added when no parent had it
+            added = !deleted && path.parents.iter().all(|parent| parent.status == 'A');
+            if added {
+                output.extend(format!("new file mode {:06o}", path.mode).into_bytes());
+            } else {
+                if deleted {
+                    output.extend_from_slice(b"deleted file ");
+                }
+                let modes: Vec<String> = path.parents.iter().map(|parent| format!("{:06o}", parent.mode)).collect();
+                output.extend(format!("mode {}", modes.join(",")).into_bytes());
+                if !deleted {
+                    output.extend(format!("..{:06o}", path.mode).into_bytes());
+                }
+            }
+            output.push(b'\n');
+        }
+        if !file_header {
+            return;
+        }
+        let old_label = if added { "/dev/null".to_string() } else { quote_path(&format!("a/{}", path.path), false) };
+        let new_label = if deleted { "/dev/null".to_string() } else { quote_path(&format!("b/{}", path.path), false) };
+        output.extend(format!("--- {}\n+++ {}\n", old_label, new_label).into_bytes());
+    }
+
+    fn write_combined_patch(&self, path: &CombinedPath, dense: bool, output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let result = side_contents(path.mode, &path.hash, &path.path)?;
+        let parents = path
+            .parents
+            .iter()
+            .map(|parent| side_contents(parent.mode, &parent.hash, &path.path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mode_differs = path.parents.iter().any(|parent| parent.mode != path.mode);
+        if !self.text && (is_binary(&result) || parents.iter().any(|x| is_binary(x))) {
+            self.write_combined_header(path, dense, false, output);
+            output.extend_from_slice(b"Binary files differ\n");
+            return Ok(());
+        }
+
+        // One entry per result line, plus one for deletions at the end and a trailer
+        let num_parents = path.parents.len();
+        let mut lines: Vec<Line> = result
+            .split_inclusive(|x| *x == b'\n')
+            .map(|text| Line { text: Some(text.strip_suffix(b"\n").unwrap_or(text)), ..Line::default() })
+            .collect();
+        lines.push(Line::default());
+        lines.push(Line::default());
+        for line in &mut lines {
+            line.parent_lines = vec![0; num_parents];
+        }
+        let count = lines.len() - 2;
+        for (index, parent) in path.parents.iter().enumerate() {
+            match path.parents[..index].iter().position(|earlier| earlier.hash == parent.hash) {
+                Some(earlier) => reuse_parent(&mut lines, index, earlier),
+                None => compare_parent(self, &mut lines, &parents[index], &result, index),
+            }
+        }
+
+        let show_hunks = make_hunks(&mut lines, count, num_parents, dense, self.context);
+        // A file on disk is always shown, even when it matches one side
+        if show_hunks || mode_differs || path.hash == NULL_HASH {
+            self.write_combined_header(path, dense, true, output);
+            write_lines(&lines, count, num_parents, self.context, output);
+        }
+        Ok(())
+    }
+
+    // A merge's changes as a combined diff: stats against the first parent, then the paths
+    // that differ from every parent, then their combined patches
+    pub fn render_combined(&self, paths: &[CombinedPath], first_parent: &[FileChange], dense: bool) -> anyhow::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        if self.quiet || self.no_output {
+            return Ok(output);
+        }
+        let stats = self.stat || self.numstat || self.shortstat || self.summary;
+        if stats {
+            let mut options = self.clone();
+            options.patch = false;
+            options.raw = false;
+            options.name_only = false;
+            options.name_status = false;
+            output.extend(options.render(first_parent)?);
+        }
+        if paths.is_empty() {
+            return Ok(output);
+        }
+        let mut separator = stats;
+        if self.raw || self.name_only || self.name_status {
+            let terminator = if self.nul { '\0' } else { '\n' };
+            for path in paths {
+                let mut line = String::new();
+                if self.raw {
+                    line.push_str(&":".repeat(path.parents.len()));
+                    for parent in &path.parents {
+                        line.push_str(&format!("{:06o} ", parent.mode));
+                    }
+                    line.push_str(&format!("{:06o}", path.mode));
+                    let abbreviate = |hash: &str| match self.abbrev {
+                        Some(length) => abbreviate_to(hash, length),
+                        None => hash.to_string(),
+                    };
+                    for parent in &path.parents {
+                        line.push_str(&format!(" {}", abbreviate(&parent.hash)));
+                    }
+                    line.push_str(&format!(" {} ", abbreviate(&path.hash)));
+                }
+                if self.raw || self.name_status {
+                    line.extend(path.parents.iter().map(|parent| parent.status));
+                    line.push(if self.nul { '\0' } else { '\t' });
+                }
+                match self.nul {
+                    true => line.push_str(&path.path),
+                    false => line.push_str(&quote_path(&path.path, false)),
+                }
+                line.push(terminator);
+                output.extend(line.into_bytes());
+            }
+            separator = true;
+        }
+        if self.patch {
+            if separator {
+                output.push(if self.nul { b'\0' } else { b'\n' });
+            }
+            for path in paths {
+                self.write_combined_patch(path, dense, &mut output)?;
+            }
+        }
+        Ok(output)
+    }
+}
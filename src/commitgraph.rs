@@ -0,0 +1,152 @@
+// Reading git's commit-graph files, which record each commit's generation number: one more
+// than the largest among its parents. A commit can only reach commits with a smaller
+// generation, which lets reachability walks stop early. Commits missing from the graph have
+// no generation and are treated as newer than everything in it.
+
+use crate::pack::read_u32;
+use std::fs;
+use std::sync::OnceLock;
+
+// The graph found under .git/objects/info, loaded once per process
+static GRAPH: OnceLock<Vec<Layer>> = OnceLock::new();
+
+// Generation of commits that are not in the graph
+pub const GENERATION_INFINITY: u32 = u32::MAX;
+
+// Layer is one commit-graph file: a single file, or one link of a split graph's chain
+struct Layer {
+    ids: Vec<String>,
+    generations: Vec<u32>,
+}
+
+impl Layer {
+    fn generation(&self, hash: &str) -> Option<u32> {
+        self.ids.binary_search_by(|id| id.as_str().cmp(hash)).ok().map(|position| self.generations[position])
+    }
+}
+
+// Find a chunk in the table of contents following the header, returning its offset
+fn find_chunk(data: &[u8], chunks: usize, id: &[u8; 4]) -> Option<usize> {
+    (0..chunks).find_map(|chunk| {
+        let entry = 8 + chunk * 12;
+        if data.get(entry..entry + 4)? != id {
+            return None;
+        }
+        let high = read_u32(data, entry + 4)? as usize;
+        let low = read_u32(data, entry + 8)? as usize;
+        Some((high << 32) | low)
+    })
+}
+
+// Parse a commit-graph file into its sorted commit ids and their topological levels, which
+// are kept in the upper 30 bits of the third word of each commit's data
+fn parse_layer(data: &[u8]) -> Option<Layer> {
+    if !data.starts_with(b"CGPH") || data.get(4) != Some(&1) || data.get(5) != Some(&1) {
+        return None;
+    }
+    let chunks = *data.get(6)? as usize;
+    let fanout = find_chunk(data, chunks, b"OIDF")?;
+    let lookup = find_chunk(data, chunks, b"OIDL")?;
+    let commit_data = find_chunk(data, chunks, b"CDAT")?;
+    let count = read_u32(data, fanout + 255 * 4)? as usize;
+    // Commit data is 36 bytes per commit, a graph cut short of that is not used at all
+    if data.len() < commit_data + count * 36 {
+        return None;
+    }
+
+    let mut ids = Vec::with_capacity(count);
+    let mut generations = Vec::with_capacity(count);
+    for position in 0..count {
+        ids.push(hex::encode(data.get(lookup + position * 20..lookup + position * 20 + 20)?));
+        generations.push(read_u32(data, commit_data + position * 36 + 28)? >> 2);
+    }
+    Some(Layer { ids, generations })
+}
+
+fn load_graph() -> Vec<Layer> {
+    if let Ok(data) = fs::read(".git/objects/info/commit-graph") {
+        return parse_layer(&data).into_iter().collect();
+    }
+    let chain = fs::read_to_string(".git/objects/info/commit-graphs/commit-graph-chain").unwrap_or_default();
+    chain
+        .lines()
+        .filter_map(|hash| fs::read(format!(".git/objects/info/commit-graphs/graph-{}.graph", hash.trim())).ok())
+        .filter_map(|data| parse_layer(&data))
+        .collect()
+}
+
+// Generation number of a commit, or GENERATION_INFINITY when the graph does not have it
+pub fn generation(hash: &str) -> u32 {
+    GRAPH.get_or_init(load_graph).iter().find_map(|layer| layer.generation(hash)).unwrap_or(GENERATION_INFINITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A commit-graph file for the given ids and topological levels, with chunks in the given order
+    fn graph(commits: &[(u8, u32)], order: &[&[u8; 4]]) -> Vec<u8> {
+        let mut fanout = Vec::new();
+        for byte in 0..=255u8 {
+            let count = commits.iter().filter(|(id, _)| *id <= byte).count() as u32;
+            fanout.extend_from_slice(&count.to_be_bytes());
+        }
+        let lookup: Vec<u8> = commits.iter().flat_map(|(id, _)| [*id; 20]).collect();
+        let mut commit_data = Vec::new();
+        for (_, level) in commits {
+            commit_data.extend_from_slice(&[0; 28]);
+            commit_data.extend_from_slice(&(level << 2 | 1).to_be_bytes());
+            commit_data.extend_from_slice(&[0; 4]);
+        }
+
+        let mut data = b"CGPH\x01\x01".to_vec();
+        data.extend_from_slice(&[order.len() as u8, 0]);
+        let mut offset = 8 + (order.len() + 1) * 12;
+        let mut contents = Vec::new();
+        for &id in order {
+            let chunk = match id {
+                b"OIDF" => &fanout,
+                b"OIDL" => &lookup,
+                _ => &commit_data,
+            };
+            data.extend_from_slice(id);
+            data.extend_from_slice(&(offset as u64).to_be_bytes());
+            offset += chunk.len();
+            contents.extend_from_slice(chunk);
+        }
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(offset as u64).to_be_bytes());
+        data.extend(contents);
+        data
+    }
+
+    #[test]
+    fn generations() {
+        let data = graph(&[(0x11, 1), (0x22, 2), (0xee, 3)], &[b"OIDF", b"OIDL", b"CDAT"]);
+        let layer = parse_layer(&data).unwrap();
+        assert_eq!(layer.ids, ["11".repeat(20), "22".repeat(20), "ee".repeat(20)]);
+        assert_eq!(layer.generations, [1, 2, 3]);
+        assert_eq!(layer.generation(&"22".repeat(20)), Some(2));
+        assert_eq!(layer.generation(&"33".repeat(20)), None);
+    }
+
+    #[test]
+    fn chunks_in_any_order() {
+        let data = graph(&[(0x11, 5)], &[b"CDAT", b"OIDL", b"OIDF"]);
+        assert_eq!(parse_layer(&data).unwrap().generations, [5]);
+        assert!(parse_layer(&graph(&[], &[b"OIDF", b"OIDL", b"CDAT"])).unwrap().ids.is_empty());
+    }
+
+    #[test]
+    fn rejected_files() {
+        let data = graph(&[(0x11, 1)], &[b"OIDF", b"OIDL", b"CDAT"]);
+        assert!(parse_layer(&graph(&[(0x11, 1)], &[b"OIDF", b"CDAT"])).is_none());
+        for (position, byte) in [(0, b'X'), (4, 2), (5, 2)] {
+            let mut data = data.clone();
+            data[position] = byte;
+            assert!(parse_layer(&data).is_none(), "byte {} changed", position);
+        }
+        assert!(parse_layer(&data[..data.len() - 1]).is_none());
+        assert!(parse_layer(&data[..20]).is_none());
+    }
+}
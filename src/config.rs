@@ -0,0 +1,325 @@
+use std::fs;
+
+// Config holds every key/value pair from the global and repository config files, in the
+// order they were read so later files override earlier ones
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+// Normalize "Section.Subsection.Key" so section and key compare case-insensitively
+// while the subsection stays case-sensitive
+fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_lowercase(),
+            &key[first..last],
+            key[last..].to_lowercase()
+        ),
+        _ => key.to_lowercase(),
+    }
+}
+
+// Parse a value, dropping comments and quotes and expanding escapes
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut pending_space = String::new();
+    let mut chars = raw.chars();
+    while let Some(x) = chars.next() {
+        match x {
+            '"' => in_quotes = !in_quotes,
+            '#' | ';' if !in_quotes => break,
+            '\\' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => {
+                        value.pop();
+                    }
+                    Some(other) => value.push(other),
+                    None => {}
+                }
+            }
+            ' ' | '\t' if !in_quotes => {
+                if !value.is_empty() {
+                    pending_space.push(x);
+                }
+            }
+            _ => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(x);
+            }
+        }
+    }
+    value
+}
+
+// The normalized "section.subsection" name of a "[section "subsection"]" header line
+fn parse_section_header(line: &str) -> Option<String> {
+    let close = line.rfind(']')?;
+    let header = &line[1..close];
+    Some(match header.split_once(' ') {
+        Some((name, subsection)) => {
+            let subsection = subsection.trim().trim_matches('"').replace("\\\"", "\"").replace("\\\\", "\\");
+            format!("{}.{}", name.to_lowercase(), subsection)
+        }
+        None => match header.split_once('.') {
+            Some((name, subsection)) => format!("{}.{}", name.to_lowercase(), subsection.to_lowercase()),
+            None => header.to_lowercase(),
+        },
+    })
+}
+
+fn parse_config(contents: &str, entries: &mut Vec<(String, String)>) {
+    let mut section = String::new();
+    let mut lines = contents.lines();
+    while let Some(line) = lines.next() {
+        let mut line = line.trim().to_string();
+        // A trailing backslash continues the value on the next line
+        while line.ends_with('\\') && !line.ends_with("\\\\") {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(next),
+                None => break,
+            }
+        }
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') {
+            if let Some(name) = parse_section_header(&line) {
+                section = name;
+            }
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim().to_lowercase(), parse_value(value.trim())),
+            // A key without a value is a boolean true
+            None => (line.trim().to_lowercase(), "true".to_string()),
+        };
+        entries.push((format!("{}.{}", section, name), value));
+    }
+}
+
+// Read the user's global config followed by the repository's own config
+pub fn read_config() -> Config {
+    let mut entries = Vec::new();
+    let mut paths = Vec::new();
+    if let Ok(home) = std::env::var("HOME") {
+        let xdg = std::env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| format!("{}/.config", home));
+        paths.push(format!("{}/git/config", xdg));
+        paths.push(format!("{}/.gitconfig", home));
+    }
+    paths.push(".git/config".to_string());
+    for path in paths {
+        if let Ok(contents) = fs::read_to_string(path) {
+            parse_config(&contents, &mut entries);
+        }
+    }
+    Config { entries }
+}
+
+// Read one file in config format on its own, such as the options a sequencer saved
+pub fn read_config_file(path: &str) -> Config {
+    let mut entries = Vec::new();
+    if let Ok(contents) = fs::read_to_string(path) {
+        parse_config(&contents, &mut entries);
+    }
+    Config { entries }
+}
+
+impl Config {
+    // Last value set for a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries.iter().rev().find(|(name, _)| *name == key).map(|(_, value)| value.as_str())
+    }
+
+    // Last value set for a boolean key, accepting git's spellings of true and false
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)?.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" | "" => Some(false),
+            _ => None,
+        }
+    }
+
+    // Names of the subsections of a section, such as every remote with a "remote.<name>.*"
+    // variable, in the order they were first read
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let prefix = format!("{}.", section.to_lowercase());
+        let mut names: Vec<&str> = Vec::new();
+        for (key, _) in &self.entries {
+            let name = key.strip_prefix(&prefix).and_then(|rest| rest.rsplit_once('.')).map(|(name, _)| name);
+            if let Some(name) = name.filter(|name| !names.contains(name)) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    // Every value set for a multi-valued key, in the order they were read
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.entries.iter().filter(|(name, _)| *name == key).map(|(_, value)| value.as_str()).collect()
+    }
+}
+
+// The repository's own config file, which is the one changes are written to
+const REPOSITORY_CONFIG: &str = ".git/config";
+
+// ConfigLine is one line of the repository config with the normalized section it belongs to
+// and, for variable lines, the variable's lowercased name
+#[derive(Clone)]
+struct ConfigLine {
+    section: String,
+    name: Option<String>,
+    text: String,
+}
+
+impl ConfigLine {
+    fn is_header(&self) -> bool {
+        self.text.trim_start().starts_with('[')
+    }
+}
+
+fn config_lines() -> Vec<ConfigLine> {
+    let contents = fs::read_to_string(REPOSITORY_CONFIG).unwrap_or_default();
+    let mut section = String::new();
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        let mut name = None;
+        if trimmed.starts_with('[') {
+            section = parse_section_header(trimmed).unwrap_or_default();
+        } else if !trimmed.is_empty() && !trimmed.starts_with('#') && !trimmed.starts_with(';') {
+            name = Some(trimmed.split('=').next().unwrap_or("").trim().to_lowercase());
+        }
+        lines.push(ConfigLine { section: section.clone(), name, text: line.to_string() });
+    }
+    lines
+}
+
+fn write_config_lines(lines: &[ConfigLine]) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(&line.text);
+        contents.push('\n');
+    }
+    let lock_path = format!("{}.lock", REPOSITORY_CONFIG);
+    fs::write(&lock_path, contents)?;
+    fs::rename(lock_path, REPOSITORY_CONFIG)?;
+    Ok(())
+}
+
+// The normalized name of a section such as "branch.topic"
+fn normalize_section(section: &str) -> String {
+    let normalized = normalize_key(&format!("{}.x", section));
+    normalized[..normalized.len() - 2].to_string()
+}
+
+// The header line for a normalized section name
+fn section_header(section: &str) -> String {
+    match section.split_once('.') {
+        Some((name, subsection)) => format!("[{} \"{}\"]", name, subsection.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => format!("[{}]", section),
+    }
+}
+
+// Quote a value where needed so it reads back unchanged
+fn format_value(value: &str) -> String {
+    let mut formatted = String::new();
+    for x in value.chars() {
+        match x {
+            '\\' => formatted.push_str("\\\\"),
+            '"' => formatted.push_str("\\\""),
+            '\n' => formatted.push_str("\\n"),
+            '\t' => formatted.push_str("\\t"),
+            _ => formatted.push(x),
+        }
+    }
+    let needs_quotes = value.starts_with([' ', '\t']) || value.ends_with([' ', '\t']) || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\"{}\"", formatted)
+    } else {
+        formatted
+    }
+}
+
+// Set a variable in the repository config, replacing its value if it has one and otherwise
+// adding it to the end of its section. Unsetting removes every value and leaves out a section
+// that ends up empty.
+pub fn set_config(key: &str, value: Option<&str>) -> anyhow::Result<()> {
+    let normalized = normalize_key(key);
+    let Some((section, lowercase_name)) = normalized.rsplit_once('.') else {
+        anyhow::bail!("key does not contain a section: {}", key);
+    };
+    let name = &key[key.len() - lowercase_name.len()..];
+    let mut lines = config_lines();
+    let is_variable = |line: &ConfigLine| line.section == section && line.name.as_deref() == Some(lowercase_name);
+
+    match value {
+        Some(value) => {
+            let entry = ConfigLine {
+                section: section.to_string(),
+                name: Some(lowercase_name.to_string()),
+                text: format!("\t{} = {}", name, format_value(value)),
+            };
+            if let Some(position) = lines.iter().rposition(is_variable) {
+                lines[position] = entry;
+            } else if let Some(position) = lines.iter().rposition(|line| line.section == section && (line.name.is_some() || line.is_header())) {
+                lines.insert(position + 1, entry);
+            } else {
+                lines.push(ConfigLine { section: section.to_string(), name: None, text: section_header(section) });
+                lines.push(entry);
+            }
+        }
+        None => {
+            lines.retain(|line| !is_variable(line));
+            if !lines.iter().any(|line| line.section == section && line.name.is_some()) {
+                lines.retain(|line| !(line.section == section && line.is_header()));
+            }
+        }
+    }
+    write_config_lines(&lines)
+}
+
+// Rename every occurrence of a section such as "branch.topic" in the repository config, or
+// remove the sections with all their variables when there is no new name
+pub fn rename_config_section(old: &str, new: Option<&str>) -> anyhow::Result<()> {
+    let old = normalize_section(old);
+    let mut lines = config_lines();
+    match new {
+        Some(new) => {
+            for line in lines.iter_mut().filter(|line| line.section == old && line.is_header()) {
+                line.text = section_header(new);
+            }
+        }
+        None => lines.retain(|line| line.section != old),
+    }
+    write_config_lines(&lines)
+}
+
+// Copy a section such as "branch.topic" in the repository config to a new section written
+// right after each place the old one appears
+pub fn copy_config_section(old: &str, new: &str) -> anyhow::Result<()> {
+    let old = normalize_section(old);
+    let mut copied = Vec::new();
+    let mut pending: Vec<ConfigLine> = Vec::new();
+    for line in config_lines() {
+        if line.is_header() || line.section != old {
+            copied.append(&mut pending);
+        }
+        if line.section == old {
+            let text = if line.is_header() { section_header(new) } else { line.text.clone() };
+            pending.push(ConfigLine { section: new.to_string(), text, ..line.clone() });
+        }
+        copied.push(line);
+    }
+    copied.append(&mut pending);
+    write_config_lines(&copied)
+}
@@ -0,0 +1,351 @@
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::fmt::Write;
+
+// DateFormat is how timestamps are shown, as chosen with --date
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DateFormat {
+    Default,
+    Iso,
+    IsoStrict,
+    Rfc,
+    Short,
+    Raw,
+    Unix,
+    Relative,
+    // A strftime format, and whether the time is shown in the local zone instead of its own
+    Strftime(String, bool),
+}
+
+impl DateFormat {
+    pub fn parse(name: &str) -> anyhow::Result<DateFormat> {
+        match name {
+            "default" => Ok(DateFormat::Default),
+            "iso" | "iso8601" => Ok(DateFormat::Iso),
+            "iso-strict" | "iso8601-strict" => Ok(DateFormat::IsoStrict),
+            "rfc" | "rfc2822" => Ok(DateFormat::Rfc),
+            "short" => Ok(DateFormat::Short),
+            "raw" => Ok(DateFormat::Raw),
+            "unix" => Ok(DateFormat::Unix),
+            "relative" => Ok(DateFormat::Relative),
+            _ if name.starts_with("format:") => Ok(DateFormat::Strftime(name["format:".len()..].to_string(), false)),
+            _ if name.starts_with("format-local:") => {
+                Ok(DateFormat::Strftime(name["format-local:".len()..].to_string(), true))
+            }
+            _ => anyhow::bail!("unknown date format {}", name),
+        }
+    }
+}
+
+// Parse a "+0200" style offset into seconds east of UTC
+fn offset_seconds(offset: &str) -> i32 {
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let digits = offset.trim_start_matches(['+', '-']);
+    let value: i32 = digits.parse().unwrap_or(0);
+    sign * ((value / 100) * 3600 + (value % 100) * 60)
+}
+
+fn plural(count: i64, unit: &str) -> String {
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+// Describe how long ago a time was the way git's relative dates do
+fn relative_date(time: i64, now: i64) -> String {
+    if time > now {
+        return "in the future".to_string();
+    }
+    let seconds = now - time;
+    if seconds < 90 {
+        return format!("{} ago", plural(seconds, "second"));
+    }
+    let minutes = (seconds + 30) / 60;
+    if minutes < 90 {
+        return format!("{} ago", plural(minutes, "minute"));
+    }
+    let hours = (minutes + 30) / 60;
+    if hours < 36 {
+        return format!("{} ago", plural(hours, "hour"));
+    }
+    let days = (hours + 12) / 24;
+    if days < 14 {
+        return format!("{} ago", plural(days, "day"));
+    }
+    if days < 70 {
+        return format!("{} ago", plural((days + 3) / 7, "week"));
+    }
+    if days < 365 {
+        return format!("{} ago", plural((days + 15) / 30, "month"));
+    }
+    if days < 1825 {
+        let total_months = (days * 12 * 2 + 365) / (365 * 2);
+        let years = plural(total_months / 12, "year");
+        return match total_months % 12 {
+            0 => format!("{} ago", years),
+            months => format!("{}, {} ago", years, plural(months, "month")),
+        };
+    }
+    format!("{} ago", plural((days + 183) / 365, "year"))
+}
+
+// Format a timestamp and its recorded offset in one of git's date styles
+pub fn format_date(time: i64, offset: &str, format: &DateFormat) -> String {
+    let zone = FixedOffset::east_opt(offset_seconds(offset)).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    let date = match zone.timestamp_opt(time, 0).single() {
+        Some(date) => date,
+        None => return format!("{} {}", time, offset),
+    };
+    match format {
+        DateFormat::Default => format!("{} {}", date.format("%a %b %-d %H:%M:%S %Y"), offset),
+        DateFormat::Iso => format!("{} {}", date.format("%Y-%m-%d %H:%M:%S"), offset),
+        DateFormat::IsoStrict => {
+            if offset_seconds(offset) == 0 {
+                date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+            } else {
+                date.format("%Y-%m-%dT%H:%M:%S%:z").to_string()
+            }
+        }
+        DateFormat::Rfc => format!("{} {}", date.format("%a, %-d %b %Y %H:%M:%S"), offset),
+        DateFormat::Short => date.format("%Y-%m-%d").to_string(),
+        DateFormat::Raw => format!("{} {}", time, offset),
+        DateFormat::Unix => time.to_string(),
+        DateFormat::Relative => relative_date(time, Utc::now().timestamp()),
+        DateFormat::Strftime(format, true) => strftime(&date.with_timezone(&chrono::Local), format),
+        DateFormat::Strftime(format, false) => strftime(&date, format),
+    }
+}
+
+// Format a date with a strftime format, leaving conversions chrono does not know as they are
+// the way the C library does
+fn strftime<Zone: TimeZone>(date: &DateTime<Zone>, format: &str) -> String
+where
+    Zone::Offset: std::fmt::Display,
+{
+    let mut output = String::new();
+    let mut rest = format;
+    while let Some(position) = rest.find('%') {
+        output.push_str(&rest[..position]);
+        rest = &rest[position..];
+        // A conversion is "%" and a letter, with perhaps a flag or width between them
+        let ends = rest.char_indices().skip(2).map(|(index, _)| index).chain([rest.len()]).take(3);
+        let mut converted = false;
+        for end in ends {
+            let mut piece = String::new();
+            if write!(piece, "{}", date.format(&rest[..end])).is_ok() {
+                output.push_str(&piece);
+                rest = &rest[end..];
+                converted = true;
+                break;
+            }
+        }
+        if !converted {
+            let end = rest.char_indices().nth(2).map_or(rest.len(), |(index, _)| index);
+            output.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// Parse a date given on the command line, such as "2024-01-31", "2024-01-31 12:00 +0100",
+// "18 Oct 2024", "@1700000000", "noon yesterday" or "2 weeks ago", into a unix timestamp
+pub fn parse_date(text: &str) -> Option<i64> {
+    parse_date_at(text, Local::now()).map(|(time, _)| time)
+}
+
+// Like parse_date, also giving the "+hhmm" zone when the text names one
+pub fn parse_date_zone(text: &str) -> Option<(i64, Option<String>)> {
+    parse_date_at(text, Local::now())
+}
+
+// The "+hhmm" form of an offset from UTC in seconds
+fn offset_text(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// Parse a date relative to now, giving its timestamp and the zone the text names, if any
+fn parse_date_at(text: &str, now: DateTime<Local>) -> Option<(i64, Option<String>)> {
+    let text = text.trim();
+    if let Some(seconds) = text.strip_prefix('@') {
+        return Some((seconds.parse().ok()?, None));
+    }
+    if let Ok(seconds) = text.parse::<i64>() {
+        if text.len() >= 9 {
+            return Some((seconds, None));
+        }
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(text).or_else(|_| DateTime::parse_from_rfc3339(text)) {
+        return Some((date.timestamp(), Some(offset_text(date.offset().local_minus_utc()))));
+    }
+    if let Some(date) = parse_absolute_date(text, now) {
+        return Some(date);
+    }
+    parse_relative_date(text, now).map(|time| (time, None))
+}
+
+// A date written out: "2024-01-31", "18 Oct 2024" or "October 18 2024", then optionally a time
+// of day to the minute or second, after a space or for the numeric form a "T", and a "+hhmm"
+// zone. Without a zone the date is in local time; without a time of day it keeps the current
+// one, as git's approxidate does.
+fn parse_absolute_date(text: &str, now: DateTime<Local>) -> Option<(i64, Option<String>)> {
+    let mut text = text;
+    let mut zone = None;
+    if let Some(at) = text.len().checked_sub(5).filter(|&at| at > 0 && text.is_char_boundary(at)) {
+        let (rest, offset) = text.split_at(at);
+        let digits = &offset[1..];
+        if offset.starts_with(['+', '-']) && digits.bytes().all(|x| x.is_ascii_digit()) && rest.ends_with(|x: char| x == ' ' || x.is_ascii_digit()) {
+            let minutes = digits[..2].parse::<i32>().ok()? * 60 + digits[2..].parse::<i32>().ok()?;
+            zone = Some((if offset.starts_with('-') { -minutes } else { minutes }, offset.to_string()));
+            text = rest.trim_end();
+        }
+    }
+
+    let mut date = None;
+    for day in ["%Y-%m-%d", "%d %b %Y", "%b %d %Y", "%d %B %Y", "%B %d %Y"] {
+        let separators: &[&str] = if day == "%Y-%m-%d" { &[" ", "T"] } else { &[" "] };
+        for separator in separators {
+            for time in ["%H:%M:%S", "%H:%M"] {
+                if let Ok(value) = NaiveDateTime::parse_from_str(text, &format!("{}{}{}", day, separator, time)) {
+                    date = date.or(Some(value));
+                }
+            }
+        }
+        if let Ok(value) = NaiveDate::parse_from_str(text, day) {
+            date = date.or(Some(value.and_time(now.time())));
+        }
+    }
+    let date = date?;
+    match zone {
+        Some((minutes, offset)) => Some(((date - Duration::minutes(minutes as i64)).and_utc().timestamp(), Some(offset))),
+        None => Local.from_local_datetime(&date).single().map(|date| (date.timestamp(), None)),
+    }
+}
+
+// A date in words relative to now: "yesterday", "3 days ago", "3.days", "last week", "noon",
+// "midnight" or combinations such as "noon yesterday", each word applied in turn
+fn parse_relative_date(text: &str, now: DateTime<Local>) -> Option<i64> {
+    let words: Vec<&str> = text.split([' ', '.', ',']).filter(|x| !x.is_empty()).collect();
+    if words.is_empty() {
+        return None;
+    }
+    let mut date = now;
+    let mut number: Option<i64> = None;
+    for word in words {
+        if let Ok(value) = word.parse() {
+            number = Some(value);
+            continue;
+        }
+        let count = number.take().unwrap_or(1);
+        match word {
+            "now" | "ago" => {}
+            "last" => number = Some(1),
+            "yesterday" => date -= Duration::days(1),
+            // A time of day still to come today means that time yesterday
+            "noon" | "midnight" => {
+                let hour = if word == "noon" { 12 } else { 0 };
+                if date.hour() < hour {
+                    date -= Duration::days(1);
+                }
+                date = date.with_hour(hour)?.with_minute(0)?.with_second(0)?.with_nanosecond(0)?;
+            }
+            _ => match word.trim_end_matches('s') {
+                "second" => date -= Duration::seconds(count),
+                "minute" => date -= Duration::minutes(count),
+                "hour" => date -= Duration::hours(count),
+                "day" => date -= Duration::days(count),
+                "week" => date -= Duration::weeks(count),
+                "month" => {
+                    let months = date.month0() as i64 + date.year() as i64 * 12 - count;
+                    let first = date.with_day(1)?.with_year((months / 12) as i32)?.with_month0((months % 12) as u32)?;
+                    date = first.with_day(date.day()).unwrap_or(first);
+                }
+                "year" => date -= Duration::days(365 * count),
+                _ => return None,
+            },
+        }
+    }
+    // A number left over names no unit
+    match number {
+        Some(_) => None,
+        None => Some(date.timestamp()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap()
+    }
+
+    fn parse(text: &str) -> Option<(i64, Option<String>)> {
+        parse_date_at(text, local(2026, 10, 18, 21, 57, 27))
+    }
+
+    fn time(text: &str) -> i64 {
+        parse(text).unwrap_or_else(|| panic!("{:?} did not parse", text)).0
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse("@1700000000"), Some((1700000000, None)));
+        assert_eq!(parse("1700000000"), Some((1700000000, None)));
+        assert_eq!(parse("Sun, 18 Oct 2026 21:00:00 +0200"), Some((1792350000, Some("+0200".to_string()))));
+        assert_eq!(parse("2026-10-18T21:00:00Z"), Some((1792357200, Some("+0000".to_string()))));
+    }
+
+    #[test]
+    fn dates_with_zones() {
+        assert_eq!(parse("2026-10-18 21:00 +0000"), Some((1792357200, Some("+0000".to_string()))));
+        assert_eq!(parse("2026-10-18 21:00:00 +0100"), Some((1792353600, Some("+0100".to_string()))));
+        assert_eq!(parse("2026-10-18T21:00:00+0100"), Some((1792353600, Some("+0100".to_string()))));
+        assert_eq!(parse("2026-10-18T21:00-0130"), Some((1792362600, Some("-0130".to_string()))));
+        assert_eq!(parse("18 Oct 2026 21:00 +0200"), Some((1792350000, Some("+0200".to_string()))));
+        assert_eq!(parse("18 October 2026 21:00:05 -0130"), Some((1792362605, Some("-0130".to_string()))));
+    }
+
+    #[test]
+    fn local_dates() {
+        let evening = local(2026, 10, 18, 21, 0, 0).timestamp();
+        for text in ["2026-10-18T21:00", "2026-10-18 21:00", "2026-10-18 21:00:00", "18 Oct 2026 21:00", "Oct 18 2026 21:00"] {
+            assert_eq!(parse(text), Some((evening, None)), "{}", text);
+        }
+        // A date alone keeps the time of day
+        let now = local(2026, 10, 18, 21, 57, 27).timestamp();
+        for text in ["2026-10-18", "18 Oct 2026", "Oct 18 2026"] {
+            assert_eq!(time(text), now, "{}", text);
+        }
+    }
+
+    #[test]
+    fn relative_dates() {
+        assert_eq!(time("now"), local(2026, 10, 18, 21, 57, 27).timestamp());
+        assert_eq!(time("yesterday"), local(2026, 10, 17, 21, 57, 27).timestamp());
+        assert_eq!(time("3 days ago"), local(2026, 10, 15, 21, 57, 27).timestamp());
+        assert_eq!(time("3.days.ago"), time("3 days"));
+        assert_eq!(time("2 hours ago"), local(2026, 10, 18, 19, 57, 27).timestamp());
+        assert_eq!(time("last week"), local(2026, 10, 11, 21, 57, 27).timestamp());
+        assert_eq!(time("last month"), local(2026, 9, 18, 21, 57, 27).timestamp());
+        assert_eq!(time("1 year ago"), local(2025, 10, 18, 21, 57, 27).timestamp());
+    }
+
+    #[test]
+    fn times_of_day() {
+        assert_eq!(time("noon"), local(2026, 10, 18, 12, 0, 0).timestamp());
+        assert_eq!(time("midnight"), local(2026, 10, 18, 0, 0, 0).timestamp());
+        assert_eq!(time("noon yesterday"), local(2026, 10, 17, 12, 0, 0).timestamp());
+        assert_eq!(time("yesterday noon"), local(2026, 10, 17, 12, 0, 0).timestamp());
+        // Noon has not come yet in the morning, so it is yesterday's
+        let morning = local(2026, 10, 18, 9, 30, 0);
+        assert_eq!(parse_date_at("noon", morning).unwrap().0, local(2026, 10, 17, 12, 0, 0).timestamp());
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for text in ["", "garbage", "3", "last", "3 fortnights ago", "2026-13-01", "32 Oct 2026"] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,880 @@
+use crate::diff::{diff_index_to_worktree, diff_tree_to_index};
+use crate::ignore::fnmatch;
+use crate::index::{read_index, write_index};
+use crate::refs::list_refs;
+use crate::revision::{object_kind, peel_tags, peel_to_tree, resolve_revision, shorten_unambiguous_ref};
+use crate::walk::{abbreviate, abbreviate_to, Signature, Walk};
+use crate::worktree::flatten_tree;
+use crate::{option_value, read_object_from_store, GitObjectType};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+// Every candidate tag of describe gets a flag bit of its own next to SEEN
+const MAX_CANDIDATES: usize = 27;
+const SEEN: u32 = 1;
+
+// Going through the second or later parent of a merge counts as this many steps, so names
+// along first parents win
+const MERGE_TRAVERSAL_WEIGHT: i64 = 65535;
+
+// name-rev does not walk to commits more than this many seconds older than the oldest commit
+// it was asked to name
+const CUTOFF_DATE_SLOP: i64 = 86400;
+
+// Tag is what naming needs from an annotated tag object
+struct Tag {
+    name: String,
+    target: String,
+    date: i64,
+}
+
+fn read_tag(hash: &str) -> Option<Tag> {
+    let GitObjectType::Tag(tag) = read_object_from_store(hash).ok()? else {
+        return None;
+    };
+    let header = tag.tag_data.split("\n\n").next().unwrap_or("");
+    let field = |name: &str| header.lines().find_map(|line| line.strip_prefix(name));
+    Some(Tag {
+        name: field("tag ")?.to_string(),
+        target: field("object ")?.to_string(),
+        date: field("tagger ").map(|tagger| Signature::parse(tagger).time).unwrap_or(0),
+    })
+}
+
+// Insert a commit into a list kept newest first, after the commits with the same date
+fn insert_by_date(walk: &mut Walk, list: &mut VecDeque<String>, hash: &str) -> anyhow::Result<()> {
+    let time = walk.commit(hash)?.committer.time;
+    let mut position = list.len();
+    for (index, queued) in list.iter().enumerate() {
+        if walk.commit(queued)?.committer.time < time {
+            position = index;
+            break;
+        }
+    }
+    list.insert(position, hash.to_string());
+    Ok(())
+}
+
+// Queue the parents of a commit that were not seen yet and pass its flags on to all of them
+fn push_parents(
+    walk: &mut Walk,
+    list: &mut VecDeque<String>,
+    flags: &mut HashMap<String, u32>,
+    hash: &str,
+    first_parent: bool,
+) -> anyhow::Result<()> {
+    let commit = walk.commit(hash)?;
+    let commit_flags = flags[hash];
+    let parents = if first_parent { &commit.parents[..commit.parents.len().min(1)] } else { &commit.parents[..] };
+    for parent in parents {
+        if flags.get(parent).copied().unwrap_or(0) & SEEN == 0 {
+            insert_by_date(walk, list, parent)?;
+        }
+        *flags.entry(parent.clone()).or_default() |= commit_flags;
+    }
+    Ok(())
+}
+
+// KnownName is the ref describe names a commit after. prio is 2 for annotated tags, 1 for
+// lightweight tags and 0 for the other refs --all admits.
+struct KnownName {
+    path: String,
+    prio: u8,
+    tag: Option<Tag>,
+    name_checked: bool,
+    misnamed: bool,
+}
+
+// Candidate is a name found while walking back from the described commit. depth counts the
+// commits walked that it does not reach, and flag marks the commits it does.
+struct Candidate {
+    commit: String,
+    depth: usize,
+    flag: u32,
+    found_order: usize,
+}
+
+#[derive(Default)]
+struct Describe {
+    tags: bool,
+    all: bool,
+    long: bool,
+    always: bool,
+    first_parent: bool,
+    abbrev: Option<usize>,
+    max_candidates: usize,
+    patterns: Vec<String>,
+    excludes: Vec<String>,
+    names: HashMap<String, KnownName>,
+    walk: Walk,
+}
+
+impl Describe {
+    // Gather the names commits can be described with, keyed by the commit they peel to. Of
+    // several names for one commit the annotated tag wins, and of several of those the newest.
+    fn collect_names(&mut self) {
+        for (refname, oid) in list_refs() {
+            let to_match = match refname.strip_prefix("refs/tags/") {
+                Some(rest) => Some(rest),
+                None if !self.all => continue,
+                None => refname.strip_prefix("refs/heads/").or_else(|| refname.strip_prefix("refs/remotes/")),
+            };
+            if !self.patterns.is_empty() || !self.excludes.is_empty() {
+                // Only refs of a known kind can be matched against patterns
+                let Some(to_match) = to_match else {
+                    continue;
+                };
+                if self.excludes.iter().any(|pattern| fnmatch(pattern.as_bytes(), to_match.as_bytes())) {
+                    continue;
+                }
+                if !self.patterns.is_empty()
+                    && !self.patterns.iter().any(|pattern| fnmatch(pattern.as_bytes(), to_match.as_bytes()))
+                {
+                    continue;
+                }
+            }
+
+            let peeled = peel_tags(&oid).unwrap_or_else(|_| oid.clone());
+            let prio = if peeled != oid {
+                2
+            } else if refname.starts_with("refs/tags/") {
+                1
+            } else {
+                0
+            };
+            let tag = if prio == 2 { read_tag(&oid) } else { None };
+            let replace = match self.names.get(&peeled) {
+                None => true,
+                Some(known) if known.prio != prio => known.prio < prio,
+                Some(known) => match (&known.tag, &tag) {
+                    (_, None) => false,
+                    (None, Some(_)) => prio == 2,
+                    (Some(old), Some(new)) => old.date < new.date,
+                },
+            };
+            if replace {
+                let path = refname[if self.all { 5 } else { 10 }..].to_string();
+                self.names.insert(peeled, KnownName { path, prio, tag, name_checked: false, misnamed: false });
+            }
+        }
+    }
+
+    fn abbreviated(&self, hash: &str) -> String {
+        match self.abbrev {
+            None => abbreviate(hash),
+            Some(0) => hash.to_string(),
+            Some(length) => abbreviate_to(hash, length),
+        }
+    }
+
+    // The name of a known commit: the name inside an annotated tag, with a warning the first
+    // time it differs from the ref the tag is stored under
+    fn append_name(&mut self, commit: &str) -> anyhow::Result<String> {
+        let all = self.all;
+        let known = self.names.get_mut(commit).unwrap();
+        if known.prio == 2 && known.tag.is_none() {
+            anyhow::bail!("annotated tag {} not available", known.path);
+        }
+        let Some(tag) = &known.tag else {
+            return Ok(known.path.clone());
+        };
+        if !known.name_checked {
+            let path = if all { known.path.get(5..).unwrap_or("") } else { &known.path };
+            if tag.name != path {
+                eprintln!("warning: tag '{}' is externally known as '{}'", known.path, tag.name);
+                known.misnamed = true;
+            }
+            known.name_checked = true;
+        }
+        Ok(format!("{}{}", if all { "tags/" } else { "" }, tag.name))
+    }
+
+    // Describe a commit by the name it is, or by the nearest name it descends from together
+    // with the number of commits in between
+    fn describe_commit(&mut self, commit: &str) -> anyhow::Result<String> {
+        if let Some(known) = self.names.get(commit).filter(|known| self.tags || self.all || known.prio == 2) {
+            let target = known.tag.as_ref().map(|tag| tag.target.clone()).unwrap_or_else(|| commit.to_string());
+            let mut name = self.append_name(commit)?;
+            if self.names[commit].misnamed || self.long {
+                name.push_str(&format!("-0-g{}", self.abbreviated(&target)));
+            }
+            return Ok(name);
+        }
+        if self.max_candidates == 0 {
+            anyhow::bail!("no tag exactly matches '{}'", commit);
+        }
+
+        let mut flags = HashMap::from([(commit.to_string(), SEEN)]);
+        let mut list = VecDeque::from([commit.to_string()]);
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut annotated = 0;
+        let mut unannotated = 0;
+        let mut seen_commits = 0;
+        let mut gave_up_on = None;
+        while let Some(hash) = list.pop_front() {
+            seen_commits += 1;
+            if let Some(known) = self.names.get(&hash) {
+                if !self.tags && !self.all && known.prio < 2 {
+                    unannotated += 1;
+                } else if candidates.len() < self.max_candidates {
+                    let flag = 1 << (candidates.len() + 1);
+                    candidates.push(Candidate {
+                        commit: hash.clone(),
+                        depth: seen_commits - 1,
+                        flag,
+                        found_order: candidates.len() + 1,
+                    });
+                    *flags.get_mut(&hash).unwrap() |= flag;
+                    if known.prio == 2 {
+                        annotated += 1;
+                    }
+                } else {
+                    gave_up_on = Some(hash);
+                    break;
+                }
+            }
+            let hash_flags = flags[&hash];
+            for candidate in candidates.iter_mut().filter(|candidate| hash_flags & candidate.flag == 0) {
+                candidate.depth += 1;
+            }
+            // Stop once the last path left is reached by the best candidates
+            if annotated > 0 && list.is_empty() {
+                let best_depth = candidates.iter().map(|candidate| candidate.depth).min().unwrap_or(0);
+                let best_flags = candidates
+                    .iter()
+                    .filter(|candidate| candidate.depth == best_depth)
+                    .fold(0, |flags, candidate| flags | candidate.flag);
+                if hash_flags & best_flags == best_flags {
+                    break;
+                }
+            }
+            push_parents(&mut self.walk, &mut list, &mut flags, &hash, self.first_parent)?;
+        }
+
+        if candidates.is_empty() {
+            if self.always {
+                return Ok(self.abbreviated(commit));
+            }
+            if unannotated > 0 {
+                anyhow::bail!(
+                    "No annotated tags can describe '{}'.\nHowever, there were unannotated tags: try --tags.",
+                    commit
+                );
+            }
+            anyhow::bail!("No tags can describe '{}'.\nTry --always, or create some tags.", commit);
+        }
+        candidates.sort_by_key(|candidate| (candidate.depth, candidate.found_order));
+
+        // Count the remaining commits the best candidate does not reach
+        if let Some(hash) = gave_up_on {
+            insert_by_date(&mut self.walk, &mut list, &hash)?;
+        }
+        let best = &mut candidates[0];
+        while let Some(hash) = list.pop_front() {
+            if flags[&hash] & best.flag != 0 {
+                if list.iter().all(|queued| flags[queued] & best.flag != 0) {
+                    break;
+                }
+            } else {
+                best.depth += 1;
+            }
+            push_parents(&mut self.walk, &mut list, &mut flags, &hash, false)?;
+        }
+
+        let (best, depth) = (candidates[0].commit.clone(), candidates[0].depth);
+        let mut name = self.append_name(&best)?;
+        if self.names[&best].misnamed || self.abbrev != Some(0) {
+            name.push_str(&format!("-{}-g{}", depth, self.abbreviated(commit)));
+        }
+        Ok(name)
+    }
+
+    // Describe a blob as the oldest commit reachable from HEAD that has it, followed by its path
+    fn describe_blob(&mut self, blob: &str) -> anyhow::Result<String> {
+        let mut walk = Walk::default();
+        walk.include = vec![resolve_revision("HEAD")?];
+        for commit in walk.run()?.into_iter().rev() {
+            let tree = walk.commit(&commit)?.tree.clone();
+            if let Some((path, _)) = flatten_tree(&tree)?.into_iter().find(|(_, entry)| entry.sha_hash == blob) {
+                return Ok(format!("{}:{}", self.describe_commit(&commit)?, path));
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn describe(&mut self, arg: &str) -> anyhow::Result<String> {
+        let hash = resolve_revision(arg).map_err(|_| anyhow::anyhow!("Not a valid object name {}", arg))?;
+        let peeled = peel_tags(&hash)?;
+        match object_kind(&peeled)?.as_str() {
+            "commit" => self.describe_commit(&peeled),
+            "blob" => self.describe_blob(&peeled),
+            _ => anyhow::bail!("{} is neither a commit nor blob", arg),
+        }
+    }
+}
+
+// Whether the files on disk or in the index differ from HEAD
+fn worktree_dirty() -> anyhow::Result<bool> {
+    let head = flatten_tree(&peel_to_tree(&resolve_revision("HEAD")?)?)?;
+    let mut index = read_index()?;
+    let staged = diff_tree_to_index(&head, &index);
+    let (unstaged, refreshed) = diff_index_to_worktree(&mut index);
+    if refreshed {
+        let _ = write_index(&index);
+    }
+    Ok(!staged.is_empty() || !unstaged.is_empty() || index.iter().any(|entry| entry.stage() > 0))
+}
+
+fn describe_usage() -> ! {
+    eprintln!("usage: git describe [--all] [--tags] [--contains] [--abbrev=<n>] [<commit-ish>...]");
+    std::process::exit(129);
+}
+
+// Name commits after the nearest tag they descend from
+pub fn describe_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "describe").unwrap();
+    let args = &args[command_index + 1..];
+
+    let mut describe = Describe { max_candidates: 10, ..Describe::default() };
+    let mut contains = false;
+    let mut dirty = None;
+    let mut revisions = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = |long: &str| option_value(args, &mut index, &[long], || ());
+        let number = |value: &str| -> usize {
+            value.parse().unwrap_or_else(|_| {
+                eprintln!("error: option `{}' expects a numerical value", arg[2..].split('=').next().unwrap());
+                std::process::exit(129);
+            })
+        };
+        if let Some(pattern) = value("--match") {
+            describe.patterns.push(pattern);
+        } else if let Some(pattern) = value("--exclude") {
+            describe.excludes.push(pattern);
+        } else if let Some(count) = value("--candidates") {
+            describe.max_candidates = number(&count).min(MAX_CANDIDATES);
+        } else if let Some(length) = arg.strip_prefix("--abbrev=") {
+            describe.abbrev = Some(number(length));
+        } else if let Some(mark) = arg.strip_prefix("--dirty=") {
+            dirty = Some(mark.to_string());
+        } else {
+            match arg {
+                "--contains" => contains = true,
+                "--all" => describe.all = true,
+                "--tags" => describe.tags = true,
+                "--long" => describe.long = true,
+                "--always" => describe.always = true,
+                "--first-parent" => describe.first_parent = true,
+                "--exact-match" => describe.max_candidates = 0,
+                "--abbrev" => describe.abbrev = None,
+                "--dirty" => dirty = Some("-dirty".to_string()),
+                "--debug" => {}
+                "--" => revisions.extend(args[index + 1..].iter().cloned()),
+                _ if arg.starts_with('-') && arg.len() > 1 => describe_usage(),
+                _ => revisions.push(arg.to_string()),
+            }
+            if arg == "--" {
+                break;
+            }
+        }
+        index += 1;
+    }
+    if describe.long && describe.abbrev == Some(0) {
+        anyhow::bail!("options '--long' and '--abbrev=0' cannot be used together");
+    }
+
+    if contains {
+        // Commits are named after the tags that contain them, as name-rev would
+        let prefix_patterns = |patterns: &[String]| patterns.iter().map(|x| format!("refs/tags/{}", x)).collect();
+        let mut name_rev = NameRev {
+            name_only: true,
+            peel_tag: true,
+            no_undefined: true,
+            always: describe.always,
+            tags_only: !describe.all,
+            ..NameRev::default()
+        };
+        if !describe.all {
+            name_rev.refs = prefix_patterns(&describe.patterns);
+            name_rev.excludes = prefix_patterns(&describe.excludes);
+        }
+        if revisions.is_empty() {
+            revisions.push("HEAD".to_string());
+        }
+        return name_rev.run(&revisions);
+    }
+
+    describe.collect_names();
+    if describe.names.is_empty() && !describe.always {
+        anyhow::bail!("No names found, cannot describe anything.");
+    }
+    if revisions.is_empty() {
+        let suffix = match dirty {
+            Some(mark) if worktree_dirty()? => mark,
+            _ => String::new(),
+        };
+        println!("{}{}", describe.describe("HEAD")?, suffix);
+    } else if dirty.is_some() {
+        anyhow::bail!("option '--dirty' and commit-ishes cannot be used together");
+    } else {
+        for revision in &revisions {
+            let name = describe.describe(revision)?;
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+// Tip is a ref name-rev names commits after. Tags are followed to the commit they point at,
+// marked by deref, and taggerdate is the date of the tag or else of the commit.
+struct Tip {
+    refname: String,
+    commit: Option<String>,
+    taggerdate: i64,
+    from_tag: bool,
+    deref: bool,
+}
+
+// RevName is the best name found so far for a commit: generation first parents below
+// tip_name, which is itself a tip or a path through the side of a merge
+#[derive(Clone)]
+struct RevName {
+    tip_name: Rc<str>,
+    taggerdate: i64,
+    generation: usize,
+    distance: i64,
+    from_tag: bool,
+}
+
+impl RevName {
+    // Names based on older tags win even when farther away, tags win over other refs, and
+    // between other refs the closer one wins
+    fn is_better(&self, other: &RevName) -> bool {
+        if self.from_tag && other.from_tag {
+            return other.taggerdate > self.taggerdate
+                || (other.taggerdate == self.taggerdate && other.distance > self.distance);
+        }
+        if self.from_tag != other.from_tag {
+            return self.from_tag;
+        }
+        if self.distance != other.distance {
+            return other.distance > self.distance;
+        }
+        other.taggerdate > self.taggerdate
+    }
+
+    fn display(&self) -> String {
+        if self.generation == 0 {
+            return self.tip_name.to_string();
+        }
+        format!("{}~{}", self.tip_name.strip_suffix("^0").unwrap_or(&self.tip_name), self.generation)
+    }
+
+    // The name of the given parent of the commit this names
+    fn parent_name(&self, parent_number: usize) -> String {
+        let tip_name = self.tip_name.strip_suffix("^0").unwrap_or(&self.tip_name);
+        match self.generation {
+            0 => format!("{}^{}", tip_name, parent_number),
+            generation => format!("{}~{}^{}", tip_name, generation, parent_number),
+        }
+    }
+}
+
+#[derive(Default)]
+struct NameRev {
+    name_only: bool,
+    tags_only: bool,
+    refs: Vec<String>,
+    excludes: Vec<String>,
+    no_undefined: bool,
+    always: bool,
+    peel_tag: bool,
+    all: bool,
+    annotate_stdin: bool,
+    cutoff: i64,
+    tips: Vec<Tip>,
+    exact: HashMap<String, String>,
+    names: HashMap<String, RevName>,
+    walk: Walk,
+}
+
+// Where a ref filter matches the ref name or one of its trailing parts after a "/"
+fn subpath_matches(path: &str, filter: &str) -> Option<usize> {
+    let mut offset = 0;
+    loop {
+        if fnmatch(filter.as_bytes(), &path.as_bytes()[offset..]) {
+            return Some(offset);
+        }
+        offset += path[offset..].find('/')? + 1;
+    }
+}
+
+impl NameRev {
+    // Gather the refs to name commits after, best first so that worse names spread less
+    fn collect_tips(&mut self) -> anyhow::Result<()> {
+        for (refname, oid) in list_refs() {
+            if self.tags_only && !refname.starts_with("refs/tags/") {
+                continue;
+            }
+            if self.excludes.iter().any(|filter| subpath_matches(&refname, filter).is_some()) {
+                continue;
+            }
+            let mut abbreviate = self.tags_only && self.name_only;
+            if !self.refs.is_empty() {
+                let offsets: Vec<usize> = self.refs.iter().filter_map(|filter| subpath_matches(&refname, filter)).collect();
+                if offsets.is_empty() {
+                    continue;
+                }
+                // A filter matching only the end of the ref allows its short name
+                abbreviate |= offsets.iter().any(|&offset| offset > 0);
+            }
+
+            let mut object = oid.clone();
+            let mut taggerdate = None;
+            let mut deref = false;
+            while let Some(tag) = read_tag(&object) {
+                taggerdate = Some(tag.date);
+                deref = true;
+                object = tag.target;
+            }
+            let commit = (object_kind(&object).ok().as_deref() == Some("commit")).then_some(object);
+            let taggerdate = match (taggerdate, &commit) {
+                (Some(date), _) => date,
+                (None, Some(commit)) => self.walk.commit(commit)?.committer.time,
+                (None, None) => i64::MAX,
+            };
+            let name = if abbreviate {
+                shorten_unambiguous_ref(&refname, false)
+            } else {
+                refname.strip_prefix("refs/heads/").or_else(|| refname.strip_prefix("refs/")).unwrap_or(&refname).to_string()
+            };
+            self.exact.insert(oid, name.clone());
+            self.tips.push(Tip {
+                refname: name,
+                from_tag: commit.is_some() && refname.starts_with("refs/tags/"),
+                commit,
+                taggerdate,
+                deref,
+            });
+        }
+        self.tips.sort_by(|a, b| b.from_tag.cmp(&a.from_tag).then(a.taggerdate.cmp(&b.taggerdate)));
+        Ok(())
+    }
+
+    // Give a commit the name unless it already has a better one
+    fn update_name(&mut self, hash: &str, name: RevName) -> bool {
+        match self.names.get(hash) {
+            Some(current) if !name.is_better(current) => false,
+            _ => {
+                self.names.insert(hash.to_string(), name);
+                true
+            }
+        }
+    }
+
+    // Name a tip's commit and then its ancestors, depth first along first parents
+    fn name_rev(&mut self, start: &str, tip_name: String, taggerdate: i64, from_tag: bool) -> anyhow::Result<()> {
+        if self.walk.commit(start)?.committer.time < self.cutoff {
+            return Ok(());
+        }
+        let name = RevName { tip_name: tip_name.into(), taggerdate, generation: 0, distance: 0, from_tag };
+        if !self.update_name(start, name) {
+            return Ok(());
+        }
+        let mut stack = vec![start.to_string()];
+        while let Some(hash) = stack.pop() {
+            let name = self.names[&hash].clone();
+            let commit = self.walk.commit(&hash)?;
+            let mut queued = Vec::new();
+            for (index, parent) in commit.parents.iter().enumerate() {
+                if self.walk.commit(parent)?.committer.time < self.cutoff {
+                    continue;
+                }
+                let parent_name = match index {
+                    0 => RevName { generation: name.generation + 1, distance: name.distance + 1, ..name.clone() },
+                    _ => RevName {
+                        tip_name: name.parent_name(index + 1).into(),
+                        generation: 0,
+                        distance: name.distance + MERGE_TRAVERSAL_WEIGHT,
+                        ..name.clone()
+                    },
+                };
+                if self.update_name(parent, parent_name) {
+                    queued.push(parent.clone());
+                }
+            }
+            // The first parent must come off the stack first
+            stack.extend(queued.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    fn name_tips(&mut self) -> anyhow::Result<()> {
+        for index in 0..self.tips.len() {
+            let Some(commit) = self.tips[index].commit.clone() else {
+                continue;
+            };
+            let tip = &self.tips[index];
+            let tip_name = if tip.deref { format!("{}^0", tip.refname) } else { tip.refname.clone() };
+            let (taggerdate, from_tag) = (tip.taggerdate, tip.from_tag);
+            self.name_rev(&commit, tip_name, taggerdate, from_tag)?;
+        }
+        Ok(())
+    }
+
+    // The name of a commit, or of another object a ref points at directly
+    fn object_name(&self, hash: &str) -> Option<String> {
+        match self.names.get(hash) {
+            Some(name) => Some(name.display()),
+            None => self.exact.get(hash).cloned(),
+        }
+    }
+
+    fn show_name(&self, out: &mut impl Write, hash: &str, caller_name: &str) -> anyhow::Result<()> {
+        if !self.name_only {
+            write!(out, "{} ", caller_name)?;
+        }
+        match self.object_name(hash) {
+            Some(name) => writeln!(out, "{}", name)?,
+            None if !self.no_undefined => writeln!(out, "undefined")?,
+            None if self.always => writeln!(out, "{}", abbreviate(hash))?,
+            None => {
+                out.flush()?;
+                anyhow::bail!("cannot describe '{}'", hash);
+            }
+        }
+        Ok(())
+    }
+
+    // Copy a line of input, following every full object id that can be named by its name, or
+    // replacing the id with --name-only
+    fn annotate_line(&self, out: &mut impl Write, line: &[u8]) -> anyhow::Result<()> {
+        let is_hex = |x: u8| x.is_ascii_digit() || (b'a'..=b'f').contains(&x);
+        let mut start = 0;
+        let mut counter = 0;
+        for position in 0..line.len() {
+            if !is_hex(line[position]) {
+                counter = 0;
+                continue;
+            }
+            counter += 1;
+            if counter != 40 || line.get(position + 1).is_some_and(|&x| is_hex(x)) {
+                continue;
+            }
+            counter = 0;
+            let hash_start = position + 1 - 40;
+            let Some(name) = self.object_name(&String::from_utf8_lossy(&line[hash_start..=position])) else {
+                continue;
+            };
+            if self.name_only {
+                out.write_all(&line[start..hash_start])?;
+                write!(out, "{}", name)?;
+            } else {
+                out.write_all(&line[start..=position])?;
+                write!(out, " ({})", name)?;
+            }
+            start = position + 1;
+        }
+        out.write_all(&line[start..])?;
+        Ok(())
+    }
+
+    fn run(&mut self, revisions: &[String]) -> anyhow::Result<()> {
+        if usize::from(self.all) + usize::from(self.annotate_stdin) + usize::from(!revisions.is_empty()) > 1 {
+            eprintln!("error: Specify either a list, or --all, not both!");
+            name_rev_usage();
+        }
+        self.cutoff = if self.all || self.annotate_stdin { 0 } else { i64::MAX };
+
+        let mut objects = Vec::new();
+        for revision in revisions {
+            let Ok(hash) = resolve_revision(revision) else {
+                eprintln!("Could not get sha1 for {}. Skipping.", revision);
+                continue;
+            };
+            let commit = peel_tags(&hash).ok().filter(|x| object_kind(x).ok().as_deref() == Some("commit"));
+            if let Some(commit) = &commit {
+                self.cutoff = self.cutoff.min(self.walk.commit(commit)?.committer.time);
+            }
+            let object = match commit {
+                Some(commit) if self.peel_tag => commit,
+                None if self.peel_tag => {
+                    eprintln!("Could not get commit for {}. Skipping.", revision);
+                    continue;
+                }
+                _ => hash,
+            };
+            objects.push((revision.as_str(), object));
+        }
+        if self.cutoff != 0 {
+            self.cutoff = self.cutoff.saturating_sub(CUTOFF_DATE_SLOP);
+        }
+
+        self.collect_tips()?;
+        self.name_tips()?;
+
+        let mut out = std::io::stdout().lock();
+        if self.annotate_stdin {
+            let mut input = std::io::stdin().lock();
+            let mut line = Vec::new();
+            while input.read_until(b'\n', &mut line)? > 0 {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                }
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+                line.push(b'\n');
+                self.annotate_line(&mut out, &line)?;
+                line.clear();
+            }
+        } else if self.all {
+            // Listed by object id, so the output does not depend on the order of the walk
+            let mut named: Vec<String> = self.names.keys().cloned().collect();
+            named.sort();
+            for hash in &named {
+                self.show_name(&mut out, hash, hash)?;
+            }
+        } else {
+            for (revision, object) in &objects {
+                self.show_name(&mut out, object, revision)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn name_rev_usage() -> ! {
+    eprintln!("usage: git name-rev [<options>] <commit>...");
+    std::process::exit(129);
+}
+
+// Name commits relative to the refs they can be reached from
+pub fn name_rev_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "name-rev").unwrap();
+    let args = &args[command_index + 1..];
+
+    let mut name_rev = NameRev::default();
+    let mut revisions = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = |long: &str| option_value(args, &mut index, &[long], || ());
+        if let Some(filter) = value("--refs") {
+            name_rev.refs.push(filter);
+        } else if let Some(filter) = value("--exclude") {
+            name_rev.excludes.push(filter);
+        } else {
+            match arg {
+                "--name-only" => name_rev.name_only = true,
+                "--tags" => name_rev.tags_only = true,
+                "--all" => name_rev.all = true,
+                "--annotate-stdin" => name_rev.annotate_stdin = true,
+                "--stdin" => {
+                    eprintln!(
+                        "warning: --stdin is deprecated. Please use --annotate-stdin instead, which is functionally equivalent.\nThis option will be removed in a future release."
+                    );
+                    name_rev.annotate_stdin = true;
+                }
+                "--undefined" => name_rev.no_undefined = false,
+                "--no-undefined" => name_rev.no_undefined = true,
+                "--always" => name_rev.always = true,
+                "--peel-tag" => name_rev.peel_tag = true,
+                "--" => revisions.extend(args[index + 1..].iter().cloned()),
+                _ if arg.starts_with('-') && arg.len() > 1 => name_rev_usage(),
+                _ => revisions.push(arg.to_string()),
+            }
+            if arg == "--" {
+                break;
+            }
+        }
+        index += 1;
+    }
+    name_rev.run(&revisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{commit, repository, tree};
+    use crate::write_object_to_store;
+    use std::fs;
+
+    // c1 tagged v1, c2, c3 tagged light without a tag object, and main merging side, which
+    // adds s1 to c2. Returned newest first: main, s1, c3, c2, c1.
+    fn history() -> Vec<String> {
+        let empty = tree(&[]);
+        let c1 = commit(&empty, &[], 1, "c1");
+        let c2 = commit(&empty, &[&c1], 2, "c2");
+        let c3 = commit(&empty, &[&c2], 3, "c3");
+        let s1 = commit(&empty, &[&c2], 4, "s1");
+        let main = commit(&empty, &[&c3, &s1], 5, "merge");
+        let tag = format!("object {}\ntype commit\ntag v1\ntagger C O Mitter <committer@example.com> 1 +0000\n\nv1\n", c1);
+        let tag = write_object_to_store(tag.as_bytes(), b"tag");
+        for (name, hash) in [("heads/main", &main), ("heads/side", &s1), ("tags/v1", &tag), ("tags/light", &c3)] {
+            fs::write(format!(".git/refs/{}", name), format!("{}\n", hash)).unwrap();
+        }
+        vec![main, s1, c3, c2, c1]
+    }
+
+    fn describe(commits: &[String], configure: impl FnOnce(&mut Describe)) -> Vec<String> {
+        let mut describe = Describe { max_candidates: 10, ..Describe::default() };
+        configure(&mut describe);
+        describe.collect_names();
+        commits.iter().map(|commit| describe.describe_commit(commit).unwrap_or_else(|error| error.to_string())).collect()
+    }
+
+    #[test]
+    fn describes() {
+        let _repository = repository();
+        let commits = history();
+        let [main, s1, c3, c2, _] = [0, 1, 2, 3, 4].map(|index| abbreviate(&commits[index]));
+        let expected = [format!("v1-4-g{}", main), format!("v1-2-g{}", s1), format!("v1-2-g{}", c3), format!("v1-1-g{}", c2), "v1".to_string()];
+        assert_eq!(describe(&commits, |_| {}), expected);
+        let expected = [format!("light-2-g{}", main), format!("v1-2-g{}", s1), "light".to_string(), format!("v1-1-g{}", c2), "v1".to_string()];
+        assert_eq!(describe(&commits, |describe| describe.tags = true), expected);
+        let expected = ["heads/main".to_string(), "heads/side".to_string(), "tags/light".to_string(), format!("tags/v1-1-g{}", c2), "tags/v1".to_string()];
+        assert_eq!(describe(&commits, |describe| describe.all = true), expected);
+
+        let first_parent = describe(&commits[..1], |describe| {
+            describe.tags = true;
+            describe.first_parent = true;
+        });
+        assert_eq!(first_parent, [format!("light-1-g{}", main)]);
+        let long = describe(&commits[4..], |describe| describe.long = true);
+        assert_eq!(long, [format!("v1-0-g{}", abbreviate(&commits[4]))]);
+        let exact = describe(&commits[..1], |describe| describe.max_candidates = 0);
+        assert_eq!(exact, [format!("no tag exactly matches '{}'", commits[0])]);
+        let unannotated = describe(&commits[2..3], |describe| describe.patterns = vec!["l*".to_string()]);
+        assert_eq!(unannotated[0], format!("No annotated tags can describe '{}'.\nHowever, there were unannotated tags: try --tags.", commits[2]));
+    }
+
+    fn names(commits: &[String], configure: impl FnOnce(&mut NameRev)) -> Vec<String> {
+        let mut name_rev = NameRev { name_only: true, ..NameRev::default() };
+        configure(&mut name_rev);
+        name_rev.collect_tips().unwrap();
+        name_rev.name_tips().unwrap();
+        commits.iter().map(|commit| name_rev.object_name(commit).unwrap_or_else(|| "undefined".to_string())).collect()
+    }
+
+    #[test]
+    fn names_revisions() {
+        let _repository = repository();
+        let commits = history();
+        assert_eq!(names(&commits, |_| {}), ["main", "side", "tags/light", "tags/light~1", "tags/v1^0"]);
+        assert_eq!(names(&commits, |name_rev| name_rev.tags_only = true), ["undefined", "undefined", "light", "light~1", "v1^0"]);
+        // Through a merge's second parent when no tag reaches the commit
+        let without_side = names(&commits[1..2], |name_rev| name_rev.excludes = vec!["side".to_string()]);
+        assert_eq!(without_side, ["main^2"]);
+        let filtered = names(&commits[3..], |name_rev| name_rev.refs = vec!["v*".to_string()]);
+        assert_eq!(filtered, ["undefined", "v1^0"]);
+
+        let name_rev = NameRev { name_only: false, exact: HashMap::from([(commits[0].clone(), "main".to_string())]), ..NameRev::default() };
+        let mut out = Vec::new();
+        name_rev.annotate_line(&mut out, format!("merged {} and {}\n", commits[0], commits[1]).as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("merged {} (main) and {}\n", commits[0], commits[1]));
+    }
+}
@@ -0,0 +1,746 @@
+use crate::config::read_config;
+use crate::index::{read_index, IndexEntry};
+use crate::rename::{unchanged_files, Renames};
+use crate::revision::{ambiguous_argument, commit_parents, peel_to_commit, peel_to_tree, resolve_revision};
+use crate::walk::abbreviate_to;
+use crate::worktree::{flatten_tree, mode_from_metadata, pathspec_matches, read_worktree_contents, TreeEntry};
+use crate::{hash_object_contents, read_object_from_store, GitObjectType};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+// ChangeKind is how a path differs between two sides of a comparison
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Deleted,
+    Modified,
+    TypeChanged,
+    Renamed,
+    Copied,
+    Unmerged,
+}
+
+impl ChangeKind {
+    // The status letter git uses for this kind of change
+    pub fn letter(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Deleted => 'D',
+            ChangeKind::Modified => 'M',
+            ChangeKind::TypeChanged => 'T',
+            ChangeKind::Renamed => 'R',
+            ChangeKind::Copied => 'C',
+            ChangeKind::Unmerged => 'U',
+        }
+    }
+}
+
+// FileChange records one changed path with the mode and object id on each side.
+// Absent sides have mode 0 and the null id; renames and copies keep the similarity score.
+#[derive(Clone, Debug)]
+pub struct FileChange {
+    pub kind: ChangeKind,
+    pub old_path: String,
+    pub new_path: String,
+    pub old_mode: u32,
+    pub new_mode: u32,
+    pub old_hash: String,
+    pub new_hash: String,
+    pub score: u32,
+}
+
+impl FileChange {
+    // Whether the change moved or copied a file to another path
+    pub fn is_rename(&self) -> bool {
+        matches!(self.kind, ChangeKind::Renamed | ChangeKind::Copied)
+    }
+}
+
+// Files, symlinks and submodules are different types; the executable bit is not
+fn same_file_type(old_mode: u32, new_mode: u32) -> bool {
+    old_mode & 0o170000 == new_mode & 0o170000
+}
+
+fn change(old: Option<(u32, &str)>, new: Option<(u32, &str)>, path: &str) -> Option<FileChange> {
+    let kind = match (old, new) {
+        (None, None) => return None,
+        (None, Some(_)) => ChangeKind::Added,
+        (Some(_), None) => ChangeKind::Deleted,
+        (Some(old), Some(new)) if old == new => return None,
+        (Some((old_mode, _)), Some((new_mode, _))) if !same_file_type(old_mode, new_mode) => ChangeKind::TypeChanged,
+        _ => ChangeKind::Modified,
+    };
+    let (old_mode, old_hash) = old.unwrap_or((0, NULL_HASH));
+    let (new_mode, new_hash) = new.unwrap_or((0, NULL_HASH));
+    Some(FileChange {
+        kind,
+        old_path: path.to_string(),
+        new_path: path.to_string(),
+        old_mode,
+        new_mode,
+        old_hash: old_hash.to_string(),
+        new_hash: new_hash.to_string(),
+        score: 0,
+    })
+}
+
+// Compare the files of a tree against the merged entries of the index
+pub fn diff_tree_to_index(tree: &BTreeMap<String, TreeEntry>, index: &[IndexEntry]) -> Vec<FileChange> {
+    let staged: BTreeMap<&str, &IndexEntry> = index
+        .iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let unmerged: std::collections::BTreeSet<&str> = index
+        .iter()
+        .filter(|entry| entry.stage() != 0)
+        .map(|entry| entry.path.as_str())
+        .collect();
+
+    let mut paths: Vec<&str> = tree.keys().map(|path| path.as_str()).chain(staged.keys().copied()).collect();
+    paths.sort_unstable();
+    paths.dedup();
+
+    paths
+        .into_iter()
+        .filter(|path| !unmerged.contains(path))
+        .filter_map(|path| {
+            let old = tree.get(path).map(|entry| (entry.mode, entry.sha_hash.as_str()));
+            let new = staged.get(path).map(|entry| (entry.mode, entry.sha_hash.as_str()));
+            change(old, new, path)
+        })
+        .collect()
+}
+
+// Compare merged index entries against the files on disk. Cached stat data avoids reading
+// unchanged files; entries whose contents turn out unchanged get fresh stat data, and the
+// returned flag says whether any did so the caller can write the index back.
+pub fn diff_index_to_worktree(index: &mut [IndexEntry]) -> (Vec<FileChange>, bool) {
+    let mut changes = Vec::new();
+    let mut refreshed = false;
+    for entry in index.iter_mut().filter(|entry| entry.stage() == 0) {
+        let old = Some((entry.mode, entry.sha_hash.as_str()));
+        let metadata = match fs::symlink_metadata(&entry.path) {
+            Ok(metadata) if !(metadata.is_dir() && entry.mode != 0o160000) => metadata,
+            _ => {
+                changes.extend(change(old, None, &entry.path));
+                continue;
+            }
+        };
+        if entry.mode == 0o160000 {
+            continue;
+        }
+
+        let mode = mode_from_metadata(&metadata);
+        if mode == entry.mode && entry.stat_matches(&metadata) {
+            continue;
+        }
+        let hash = match read_worktree_contents(&entry.path, &metadata) {
+            Ok(contents) => hash_object_contents(&contents, b"blob"),
+            Err(_) => NULL_HASH.to_string(),
+        };
+        if mode == entry.mode && hash == entry.sha_hash {
+            *entry = IndexEntry::from_metadata(&entry.path, entry.mode, &entry.sha_hash, &metadata);
+            refreshed = true;
+            continue;
+        }
+        changes.extend(change(old, Some((mode, hash.as_str())), &entry.path));
+    }
+    (changes, refreshed)
+}
+
+// Quote a path the way git prints it: C-style escapes inside double quotes whenever the
+// path has control characters, quotes, backslashes or non-ASCII bytes. Short formats
+// also quote paths containing spaces so each line stays unambiguous.
+pub fn quote_path(path: &str, quote_space: bool) -> String {
+    let needs_quotes = path
+        .bytes()
+        .any(|x| !(0x20..0x7f).contains(&x) || x == b'"' || x == b'\\' || (quote_space && x == b' '));
+    if !needs_quotes {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for x in path.bytes() {
+        match x {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            0x0b => quoted.push_str("\\v"),
+            0x0c => quoted.push_str("\\f"),
+            x if !(0x20..0x7f).contains(&x) => quoted.push_str(&format!("\\{:03o}", x)),
+            x => quoted.push(x as char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Read the entries of a tree keyed the way trees sort, with a trailing slash on subtrees,
+// each with its name, mode and id
+fn tree_entries(tree: Option<&str>) -> anyhow::Result<BTreeMap<String, (String, u32, String)>> {
+    let Some(tree) = tree else {
+        return Ok(BTreeMap::new());
+    };
+    let tree = match read_object_from_store(tree)? {
+        GitObjectType::Tree(tree) => tree,
+        _ => anyhow::bail!("{} is not a tree", tree),
+    };
+    let mut entries = BTreeMap::new();
+    for leaf in tree.leaves {
+        let mode = u32::from_str_radix(&String::from_utf8_lossy(&leaf.mode), 8)?;
+        let key = if mode == 0o040000 { format!("{}/", leaf.path) } else { leaf.path.clone() };
+        entries.insert(key, (leaf.path, mode, leaf.sha_hash));
+    }
+    Ok(entries)
+}
+
+// TreeDiff compares two trees path by path. Subtrees with the same id on both sides are
+// skipped without being read. Other subtrees are descended into when recursing and reported
+// as a whole otherwise; show_trees also reports the trees passed through on the way down.
+#[derive(Default)]
+pub struct TreeDiff {
+    pub recursive: bool,
+    pub show_trees: bool,
+    pub paths: Vec<String>,
+}
+
+impl TreeDiff {
+    pub fn diff(&self, old_tree: Option<&str>, new_tree: Option<&str>) -> anyhow::Result<Vec<FileChange>> {
+        let mut changes = Vec::new();
+        self.diff_into(old_tree, new_tree, "", &mut changes)?;
+        Ok(changes)
+    }
+
+    fn diff_into(&self, old_tree: Option<&str>, new_tree: Option<&str>, prefix: &str, changes: &mut Vec<FileChange>) -> anyhow::Result<()> {
+        let old_entries = tree_entries(old_tree)?;
+        let new_entries = tree_entries(new_tree)?;
+        let mut keys: Vec<&String> = old_entries.keys().chain(new_entries.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let old = old_entries.get(key).map(|(_, mode, hash)| (*mode, hash.as_str()));
+            let new = new_entries.get(key).map(|(_, mode, hash)| (*mode, hash.as_str()));
+            if old == new {
+                continue;
+            }
+            let name = &old_entries.get(key).or(new_entries.get(key)).unwrap().0;
+            let path = format!("{}{}", prefix, name);
+            let is_tree = key.ends_with('/');
+            // Trees leading towards a pathspec count as well as the paths it matches
+            let selected = self.paths.is_empty()
+                || self.paths.iter().any(|spec| pathspec_matches(spec, &path))
+                || (is_tree && self.paths.iter().any(|spec| spec.starts_with(&format!("{}/", path))));
+            if !selected {
+                continue;
+            }
+            if is_tree && self.recursive {
+                if self.show_trees {
+                    changes.extend(change(old, new, &path));
+                }
+                self.diff_into(old.map(|x| x.1), new.map(|x| x.1), &format!("{}/", path), changes)?;
+            } else {
+                changes.extend(change(old, new, &path));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The mode and id of a tracked file as it is on disk: the index's own when the cached stat
+// data shows it untouched, otherwise hashed from the file. Missing files have none.
+fn worktree_version(entry: &IndexEntry) -> Option<(u32, String)> {
+    let metadata = fs::symlink_metadata(&entry.path).ok()?;
+    if metadata.is_dir() && entry.mode != 0o160000 {
+        return None;
+    }
+    let mode = mode_from_metadata(&metadata);
+    if entry.mode == 0o160000 || (mode == entry.mode && entry.stat_matches(&metadata)) {
+        return Some((entry.mode, entry.sha_hash.clone()));
+    }
+    let hash = match read_worktree_contents(&entry.path, &metadata) {
+        Ok(contents) => hash_object_contents(&contents, b"blob"),
+        Err(_) => NULL_HASH.to_string(),
+    };
+    Some((mode, hash))
+}
+
+// A record for a path with conflict stages in the index, showing the mode and id on the
+// side being compared against if there is one, and the mode of the file on disk if that is
+// the other side
+pub fn unmerged_change(path: &str, old: Option<(u32, &str)>, new_mode: u32) -> FileChange {
+    let (old_mode, old_hash) = old.unwrap_or((0, NULL_HASH));
+    FileChange {
+        kind: ChangeKind::Unmerged,
+        old_path: path.to_string(),
+        new_path: path.to_string(),
+        old_mode,
+        new_mode,
+        old_hash: old_hash.to_string(),
+        new_hash: NULL_HASH.to_string(),
+        score: 0,
+    }
+}
+
+// Compare a tree against the working tree, looking through the index for which files are
+// tracked. Files whose contents differ from the index are shown with the null id since
+// they have not been written as objects.
+pub fn diff_tree_to_worktree(tree: &BTreeMap<String, TreeEntry>, index: &[IndexEntry]) -> Vec<FileChange> {
+    let mut worktree: BTreeMap<&str, Option<(u32, String)>> = BTreeMap::new();
+    for entry in index {
+        let version = match entry.stage() {
+            0 => worktree_version(entry),
+            // A conflicted file never matches what is recorded for it
+            _ => worktree_version(&IndexEntry::without_stat(&entry.path, entry.mode, NULL_HASH)),
+        };
+        worktree.insert(entry.path.as_str(), version);
+    }
+    let staged: BTreeMap<&str, (u32, &str)> =
+        index.iter().filter(|x| x.stage() == 0).map(|x| (x.path.as_str(), (x.mode, x.sha_hash.as_str()))).collect();
+
+    let mut paths: Vec<&str> = tree.keys().map(|path| path.as_str()).chain(worktree.keys().copied()).collect();
+    paths.sort_unstable();
+    paths.dedup();
+    let mut changes = Vec::new();
+    for path in paths {
+        let old = tree.get(path).map(|entry| (entry.mode, entry.sha_hash.as_str()));
+        let new = match worktree.get(path) {
+            Some(Some((mode, hash))) => Some((*mode, hash.as_str())),
+            // Files deleted from disk only count if the tree has them
+            Some(None) if old.is_none() => continue,
+            _ => None,
+        };
+        if let Some(mut change) = change(old, new, path) {
+            if change.new_mode != 0 && staged.get(path) != Some(&(change.new_mode, change.new_hash.as_str())) {
+                change.new_hash = NULL_HASH.to_string();
+            }
+            changes.push(change);
+        }
+    }
+    changes
+}
+
+// Output formats for lists of changed paths
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChangesFormat {
+    Raw,
+    NameOnly,
+    NameStatus,
+    None,
+}
+
+// Options shared by the diff plumbing commands for how changes are printed
+pub struct ChangesOutput {
+    pub format: ChangesFormat,
+    pub abbrev: Option<usize>,
+    pub nul: bool,
+    pub exit_code: bool,
+    pub quiet: bool,
+}
+
+impl ChangesOutput {
+    pub fn new() -> ChangesOutput {
+        ChangesOutput { format: ChangesFormat::Raw, abbrev: None, nul: false, exit_code: false, quiet: false }
+    }
+
+    // Take an output option if arg is one, returning whether it was
+    fn parse_option(&mut self, arg: &str) -> anyhow::Result<bool> {
+        match arg {
+            "--raw" => self.format = ChangesFormat::Raw,
+            "--name-only" => self.format = ChangesFormat::NameOnly,
+            "--name-status" => self.format = ChangesFormat::NameStatus,
+            "-s" | "--no-patch" => self.format = ChangesFormat::None,
+            "-z" => self.nul = true,
+            "--abbrev" => self.abbrev = Some(7),
+            "--no-abbrev" => self.abbrev = None,
+            "--exit-code" => self.exit_code = true,
+            "--quiet" => {
+                self.quiet = true;
+                self.exit_code = true;
+            }
+            _ => match arg.strip_prefix("--abbrev=") {
+                Some(length) => self.abbrev = Some(length.parse().map_err(|_| anyhow::anyhow!("--abbrev expects a numerical value"))?),
+                None => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    fn hash(&self, hash: &str) -> String {
+        match self.abbrev {
+            Some(length) => abbreviate_to(hash, length),
+            None => hash.to_string(),
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        if self.nul {
+            path.to_string()
+        } else {
+            quote_path(path, false)
+        }
+    }
+
+    // Print each change as a line, or NUL separated fields with -z
+    pub fn print(&self, changes: &[FileChange]) -> String {
+        let mut output = String::new();
+        if self.quiet {
+            return output;
+        }
+        let (field, line) = if self.nul { ('\0', '\0') } else { ('\t', '\n') };
+        for change in changes {
+            let status = match change.kind {
+                ChangeKind::Renamed | ChangeKind::Copied => format!("{}{:03}", change.kind.letter(), change.score),
+                kind => kind.letter().to_string(),
+            };
+            let mut paths = self.path(&change.new_path);
+            if change.is_rename() {
+                paths = format!("{}{}{}", self.path(&change.old_path), field, paths);
+            }
+            match self.format {
+                ChangesFormat::Raw => output.push_str(&format!(
+                    ":{:06o} {:06o} {} {} {}{}{}{}",
+                    change.old_mode,
+                    change.new_mode,
+                    self.hash(&change.old_hash),
+                    self.hash(&change.new_hash),
+                    status,
+                    field,
+                    paths,
+                    line
+                )),
+                ChangesFormat::NameOnly => output.push_str(&format!("{}{}", self.path(&change.new_path), line)),
+                ChangesFormat::NameStatus => output.push_str(&format!("{}{}{}{}", status, field, paths, line)),
+                ChangesFormat::None => {}
+            }
+        }
+        output
+    }
+
+    // Exit with status 1 when asked to report whether there were differences
+    fn finish(&self, changed: bool) {
+        if self.exit_code && changed {
+            std::process::exit(1);
+        }
+    }
+}
+
+// Split positional arguments into up to max_revisions revisions followed by paths, which must
+// exist unless they come after "--"
+fn revisions_and_paths(args: &[String], max_revisions: usize) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let mut revisions = Vec::new();
+    let mut paths = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = &args[index];
+        index += 1;
+        if arg == "--" {
+            paths.extend(args[index..].iter().cloned());
+            break;
+        }
+        if revisions.len() < max_revisions && paths.is_empty() {
+            if let Ok(hash) = resolve_revision(arg) {
+                revisions.push(hash);
+                continue;
+            }
+        }
+        if !Path::new(arg).exists() {
+            return Err(ambiguous_argument(arg, "unknown revision or path not in the working tree."));
+        }
+        paths.push(arg.clone());
+    }
+    let paths = paths
+        .iter()
+        .map(|path| path.trim_start_matches("./").trim_end_matches('/').to_string())
+        .map(|path| if path.is_empty() { ".".to_string() } else { path })
+        .collect();
+    Ok((revisions, paths))
+}
+
+fn usage(text: &str) -> ! {
+    eprintln!("usage: {}", text);
+    std::process::exit(129);
+}
+
+pub fn diff_tree_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "diff-tree").unwrap();
+    let mut output = ChangesOutput::new();
+    let mut renames = Renames::new(&read_config());
+    let mut tree_diff = TreeDiff::default();
+    let mut root = false;
+    let mut each_parent = false;
+    let mut commit_id = true;
+    let mut always = false;
+    let mut positional = Vec::new();
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if output.parse_option(arg)? || renames.parse_option(arg)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-r" => tree_diff.recursive = true,
+            "-t" => {
+                tree_diff.recursive = true;
+                tree_diff.show_trees = true;
+            }
+            "--root" => root = true,
+            "-m" => each_parent = true,
+            "--no-commit-id" => commit_id = false,
+            "--always" => always = true,
+            "--" => {
+                positional.push(arg.clone());
+                positional.extend(arguments.by_ref().cloned());
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("unrecognized argument: {}", arg),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let (revisions, paths) = revisions_and_paths(&positional, 2)?;
+    tree_diff.paths = paths;
+    renames.validate();
+
+    let mut text = String::new();
+    let mut changed = false;
+    match revisions.as_slice() {
+        [old, new] => {
+            let changes = renames.diff_trees(&tree_diff, Some(&peel_to_tree(old)?), Some(&peel_to_tree(new)?))?;
+            changed = !changes.is_empty();
+            text.push_str(&output.print(&changes));
+        }
+        [commit] => {
+            let commit = peel_to_commit(commit)?;
+            let tree = peel_to_tree(&commit)?;
+            let parents = commit_parents(&commit)?;
+            // Root commits are only compared with the empty tree when asked, merges only
+            // against each parent in turn with -m
+            let bases: Vec<Option<&String>> = match parents.len() {
+                0 if root => vec![None],
+                0 => vec![],
+                1 => vec![parents.first()],
+                _ if each_parent => parents.iter().map(Some).collect(),
+                _ => vec![],
+            };
+            for base in &bases {
+                let base_tree = base.map(|parent| peel_to_tree(parent)).transpose()?;
+                let changes = renames.diff_trees(&tree_diff, base_tree.as_deref(), Some(&tree))?;
+                changed |= !changes.is_empty();
+                if commit_id && (always || !changes.is_empty()) {
+                    text.push_str(&commit);
+                    text.push(if output.nul { '\0' } else { '\n' });
+                }
+                text.push_str(&output.print(&changes));
+            }
+            if bases.is_empty() && always && commit_id {
+                text.push_str(&commit);
+                text.push(if output.nul { '\0' } else { '\n' });
+            }
+        }
+        _ => usage("git diff-tree [<options>] <tree-ish> [<tree-ish>] [<path>...]"),
+    }
+    print!("{}", text);
+    renames.warn_limit("diff.renameLimit");
+    output.finish(changed);
+    Ok(())
+}
+
+pub fn diff_index_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "diff-index").unwrap();
+    let mut output = ChangesOutput::new();
+    let mut renames = Renames::new(&read_config());
+    let mut cached = false;
+    let mut positional = Vec::new();
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if output.parse_option(arg)? || renames.parse_option(arg)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--cached" => cached = true,
+            // Index based comparisons always list every file
+            "-r" | "-m" => {}
+            "--" => {
+                positional.push(arg.clone());
+                positional.extend(arguments.by_ref().cloned());
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("unrecognized argument: {}", arg),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let (revisions, paths) = revisions_and_paths(&positional, 1)?;
+    let Some(revision) = revisions.first() else {
+        usage("git diff-index [-m] [--cached] [<common-diff-options>] <tree-ish> [<path>...]");
+    };
+    let tree = flatten_tree(&peel_to_tree(revision)?)?;
+    let index = read_index()?;
+
+    let mut changes = if cached {
+        let mut changes = diff_tree_to_index(&tree, &index);
+        let mut unmerged: Vec<&str> = index.iter().filter(|x| x.stage() != 0).map(|x| x.path.as_str()).collect();
+        unmerged.dedup();
+        for path in unmerged {
+            let old = tree.get(path).map(|entry| (entry.mode, entry.sha_hash.as_str()));
+            changes.push(unmerged_change(path, old, 0));
+        }
+        changes.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+        changes
+    } else {
+        diff_tree_to_worktree(&tree, &index)
+    };
+    changes.retain(|change| paths.is_empty() || paths.iter().any(|spec| pathspec_matches(spec, &change.new_path)));
+    renames.validate();
+    let unchanged = match renames.wants_unchanged() {
+        true => unchanged_files(tree.iter().map(|(path, entry)| (path.as_str(), entry.mode, entry.sha_hash.as_str())), &changes, &paths),
+        false => Vec::new(),
+    };
+    let changes = renames.detect(changes, unchanged)?;
+    print!("{}", output.print(&changes));
+    renames.warn_limit("diff.renameLimit");
+    output.finish(!changes.is_empty());
+    Ok(())
+}
+
+pub fn diff_files_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "diff-files").unwrap();
+    let mut output = ChangesOutput::new();
+    let mut renames = Renames::new(&read_config());
+    let mut positional = Vec::new();
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if output.parse_option(arg)? || renames.parse_option(arg)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-r" | "-q" => {}
+            "--" => {
+                positional.push(arg.clone());
+                positional.extend(arguments.by_ref().cloned());
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("unrecognized argument: {}", arg),
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let (_, paths) = revisions_and_paths(&positional, 0)?;
+    let mut index = read_index()?;
+    let (mut changes, _) = diff_index_to_worktree(&mut index);
+
+    // Conflicted paths are listed as unmerged, then compared against our side
+    let mut unmerged: Vec<&str> = index.iter().filter(|x| x.stage() != 0).map(|x| x.path.as_str()).collect();
+    unmerged.dedup();
+    for path in unmerged {
+        let mode = fs::symlink_metadata(path).map(|metadata| mode_from_metadata(&metadata)).unwrap_or(0);
+        changes.push(unmerged_change(path, None, mode));
+        if let Some(ours) = index.iter().find(|x| x.path == path && x.stage() == 2) {
+            let new = worktree_version(&IndexEntry::without_stat(path, ours.mode, NULL_HASH));
+            changes.extend(change(Some((ours.mode, &ours.sha_hash)), new.as_ref().map(|(mode, hash)| (*mode, hash.as_str())), path));
+        }
+    }
+    changes.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+    changes.retain(|change| paths.is_empty() || paths.iter().any(|spec| pathspec_matches(spec, &change.new_path)));
+    // Files on disk have no object ids yet
+    for change in &mut changes {
+        change.new_hash = NULL_HASH.to_string();
+    }
+    renames.validate();
+    let unchanged = match renames.wants_unchanged() {
+        true => unchanged_files(
+            index.iter().filter(|x| x.stage() == 0).map(|x| (x.path.as_str(), x.mode, x.sha_hash.as_str())),
+            &changes,
+            &paths,
+        ),
+        false => Vec::new(),
+    };
+    let changes = renames.detect(changes, unchanged)?;
+    print!("{}", output.print(&changes));
+    renames.warn_limit("diff.renameLimit");
+    output.finish(!changes.is_empty());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{blob, repository, tree};
+
+    fn summary(changes: &[FileChange]) -> Vec<String> {
+        changes.iter().map(|x| format!("{} {}", x.kind.letter(), x.new_path)).collect()
+    }
+
+    #[test]
+    fn tree_diffs() {
+        let _repository = repository();
+        let old = tree(&[("a", "1\n"), ("dir/b", "1\n"), ("dir/sub/c", "1\n"), ("same/d", "1\n")]);
+        let new = tree(&[("a", "2\n"), ("dir/b", "1\n"), ("dir/sub/c", "2\n"), ("dir/e", "1\n"), ("same/d", "1\n")]);
+        let recursive = TreeDiff { recursive: true, ..TreeDiff::default() };
+        assert_eq!(summary(&recursive.diff(Some(&old), Some(&new)).unwrap()), ["M a", "A dir/e", "M dir/sub/c"]);
+        assert_eq!(summary(&TreeDiff::default().diff(Some(&old), Some(&new)).unwrap()), ["M a", "M dir"]);
+        let show_trees = TreeDiff { recursive: true, show_trees: true, ..TreeDiff::default() };
+        assert_eq!(summary(&show_trees.diff(Some(&old), Some(&new)).unwrap()), ["M a", "M dir", "A dir/e", "M dir/sub", "M dir/sub/c"]);
+        let limited = TreeDiff { recursive: true, paths: vec!["dir/sub".to_string()], ..TreeDiff::default() };
+        assert_eq!(summary(&limited.diff(Some(&old), Some(&new)).unwrap()), ["M dir/sub/c"]);
+        let added = recursive.diff(None, Some(&new)).unwrap();
+        assert_eq!(added.len(), 5);
+        assert!(added.iter().all(|x| x.kind == ChangeKind::Added && x.old_hash == NULL_HASH && x.old_mode == 0));
+    }
+
+    #[test]
+    fn index_diffs() {
+        let _repository = repository();
+        let mut head = BTreeMap::new();
+        for (path, mode, contents) in [("a", 0o100644, "1\n"), ("link", 0o100644, "target\n"), ("gone", 0o100644, "1\n"), ("exec", 0o100644, "1\n")] {
+            head.insert(path.to_string(), TreeEntry { mode, sha_hash: blob(contents) });
+        }
+        let mut conflicted = IndexEntry::without_stat("conflict", 0o100644, &blob("1\n"));
+        conflicted.flags = 2 << 12;
+        let index = [
+            IndexEntry::without_stat("a", 0o100644, &blob("1\n")),
+            IndexEntry::without_stat("conflict", 0o100644, &blob("1\n")),
+            conflicted,
+            IndexEntry::without_stat("exec", 0o100755, &blob("1\n")),
+            IndexEntry::without_stat("link", 0o120000, &blob("target")),
+            IndexEntry::without_stat("new", 0o100644, &blob("new\n")),
+        ];
+        let changes = diff_tree_to_index(&head, &index);
+        assert_eq!(summary(&changes), ["M exec", "D gone", "T link", "A new"]);
+        assert_eq!((changes[0].old_mode, changes[0].new_mode), (0o100644, 0o100755));
+    }
+
+    #[test]
+    fn worktree_diffs() {
+        let _repository = repository();
+        let mut index = vec![
+            IndexEntry::without_stat("same", 0o100644, &blob("1\n")),
+            IndexEntry::without_stat("changed", 0o100644, &blob("1\n")),
+            IndexEntry::without_stat("missing", 0o100644, &blob("1\n")),
+        ];
+        fs::write("same", "1\n").unwrap();
+        fs::write("changed", "2\n").unwrap();
+        let (changes, refreshed) = diff_index_to_worktree(&mut index);
+        assert_eq!(summary(&changes), ["M changed", "D missing"]);
+        assert_eq!(changes[0].new_hash, hash_object_contents(b"2\n", b"blob"));
+        // The unchanged file got its stat data cached
+        assert!(refreshed && index[0].stat_matches(&fs::metadata("same").unwrap()));
+
+        // Against a tree, files that differ from the index have no id yet
+        let head = flatten_tree(&tree(&[("same", "0\n"), ("changed", "1\n")])).unwrap();
+        let changes = diff_tree_to_worktree(&head, &index);
+        assert_eq!(summary(&changes), ["M changed", "M same"]);
+        assert_eq!(changes[0].new_hash, NULL_HASH);
+        assert_eq!(changes[1].new_hash, blob("1\n"));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_path("plain/path", false), "plain/path");
+        assert_eq!(quote_path("with space", false), "with space");
+        assert_eq!(quote_path("with space", true), "\"with space\"");
+        assert_eq!(quote_path("tab\there", false), "\"tab\\there\"");
+        assert_eq!(quote_path("quote\"back\\", false), "\"quote\\\"back\\\\\"");
+        assert_eq!(quote_path("caf\u{e9}", false), "\"caf\\303\\251\"");
+    }
+}
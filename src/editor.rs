@@ -0,0 +1,46 @@
+// Running the user's editor on a file, for commit messages and rebase todo lists. The editor is
+// chosen the way git chooses it and run through the shell, so that it may carry arguments.
+
+use crate::config::Config;
+use std::process::Command;
+
+pub fn is_terminal_dumb() -> bool {
+    std::env::var("TERM").map(|term| term == "dumb").unwrap_or(true)
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// The editor for commit messages: GIT_EDITOR, core.editor, VISUAL unless the terminal is dumb,
+// EDITOR, then vi. A dumb terminal with nothing configured has no editor.
+pub fn git_editor(config: &Config) -> Option<String> {
+    let editor = env("GIT_EDITOR").or_else(|| config.get("core.editor").map(|x| x.to_string()));
+    let editor = editor.or_else(|| if is_terminal_dumb() { None } else { env("VISUAL") });
+    match editor.or_else(|| env("EDITOR")) {
+        Some(editor) => Some(editor),
+        None if is_terminal_dumb() => None,
+        None => Some("vi".to_string()),
+    }
+}
+
+// The editor for todo lists: GIT_SEQUENCE_EDITOR or sequence.editor, else the commit editor
+pub fn sequence_editor(config: &Config) -> Option<String> {
+    env("GIT_SEQUENCE_EDITOR").or_else(|| config.get("sequence.editor").map(|x| x.to_string())).or_else(|| git_editor(config))
+}
+
+// Let the user edit a file, waiting for the editor to exit. ":" is the editor that changes
+// nothing.
+pub fn launch_editor(editor: Option<String>, path: &str) -> anyhow::Result<()> {
+    let Some(editor) = editor else {
+        anyhow::bail!("Terminal is dumb, but EDITOR unset");
+    };
+    if editor == ":" {
+        return Ok(());
+    }
+    let status = Command::new("sh").arg("-c").arg(format!("{} \"$@\"", editor)).arg(&editor).arg(path).status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => anyhow::bail!("There was a problem with the editor '{}'.", editor),
+    }
+}
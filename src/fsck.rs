@@ -0,0 +1,521 @@
+use crate::index::read_index;
+use crate::pack::{packs, read_object_at};
+use crate::reflog::{list_reflogs, read_reflog};
+use crate::refs::{head_symbolic_target, list_refs, resolve_ref};
+use crate::{check_git_tree, encode_object, git_tree_parse_lossy, inflate_loose_object, split_object_header, TreeError};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+
+// Bits of the exit status, matching the ones git fsck uses
+const ERROR_OBJECT: i32 = 1;
+const ERROR_REACHABLE: i32 = 2;
+const ERROR_PACK: i32 = 4;
+const ERROR_REFS: i32 = 8;
+
+// FsckOptions holds the command line switches of fsck
+#[derive(Default)]
+struct FsckOptions {
+    show_unreachable: bool,
+    show_dangling: bool,
+    show_root: bool,
+    show_tags: bool,
+    include_reflogs: bool,
+    lost_found: bool,
+    connectivity_only: bool,
+    strict: bool,
+    heads: Vec<String>,
+}
+
+// FsckObject records what fsck learned about one object: its type and the objects it links to
+struct FsckObject {
+    kind: String,
+    links: Vec<(&'static str, String)>,
+}
+
+// FsckState collects objects and problems while fsck runs.
+// Present ids are every object file or pack entry found, even ones that failed to parse.
+struct FsckState {
+    options: FsckOptions,
+    objects: BTreeMap<String, FsckObject>,
+    present: BTreeSet<String>,
+    errors: i32,
+}
+
+pub fn fsck_command(args: &[String]) -> anyhow::Result<()> {
+    let mut options = FsckOptions { show_dangling: true, include_reflogs: true, ..Default::default() };
+    let command_index = args.iter().position(|x| x == "fsck").unwrap();
+    for arg in &args[command_index + 1..] {
+        match arg.as_str() {
+            "--unreachable" => options.show_unreachable = true,
+            "--dangling" => options.show_dangling = true,
+            "--no-dangling" => options.show_dangling = false,
+            "--root" => options.show_root = true,
+            "--tags" => options.show_tags = true,
+            "--reflogs" => options.include_reflogs = true,
+            "--no-reflogs" => options.include_reflogs = false,
+            "--lost-found" => {
+                options.lost_found = true;
+                options.include_reflogs = false;
+            }
+            "--connectivity-only" => options.connectivity_only = true,
+            "--strict" => options.strict = true,
+            "--full" | "--cache" | "--no-full" | "--no-progress" => {}
+            other if other.starts_with('-') => anyhow::bail!("unknown option '{}'", other),
+            other => options.heads.push(other.to_string()),
+        }
+    }
+
+    let mut state = FsckState { options, objects: BTreeMap::new(), present: BTreeSet::new(), errors: 0 };
+    state.scan_loose_objects();
+    state.scan_packs();
+    state.check_connectivity();
+
+    if state.errors != 0 {
+        std::process::exit(state.errors);
+    }
+    Ok(())
+}
+
+impl FsckState {
+    // Re-hash every loose object and check that it is stored under its own id
+    fn scan_loose_objects(&mut self) {
+        let mut directories: Vec<String> = match fs::read_dir(".git/objects") {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.len() == 2 && name.bytes().all(|x| x.is_ascii_hexdigit()))
+                .collect(),
+            Err(_) => return,
+        };
+        directories.sort();
+
+        for directory in directories {
+            let mut files: Vec<String> = fs::read_dir(format!(".git/objects/{}", directory))
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                        .collect()
+                })
+                .unwrap_or_default();
+            files.sort();
+
+            for file in files {
+                let path = format!(".git/objects/{}/{}", directory, file);
+                if file.len() != 38 || !file.bytes().all(|x| x.is_ascii_hexdigit()) {
+                    eprintln!("warning: garbage found: {}", path);
+                    continue;
+                }
+                let expected = format!("{}{}", directory, file);
+                self.present.insert(expected.clone());
+                let decoded = match fs::read(&path).map_err(anyhow::Error::from).and_then(|data| inflate_loose_object(&data)) {
+                    Ok(decoded) => decoded,
+                    Err(error) => {
+                        eprintln!("error: unable to unpack {} ({}): {}", expected, path, error);
+                        self.errors |= ERROR_OBJECT;
+                        continue;
+                    }
+                };
+                let actual = hex::encode(Sha1::digest(&decoded));
+                if actual != expected {
+                    eprintln!("error: {}: hash-path mismatch, found at: {}", actual, path);
+                    self.errors |= ERROR_OBJECT;
+                    continue;
+                }
+                match split_object_header(&decoded) {
+                    Ok((kind, contents)) => self.check_object(&actual, &kind, contents),
+                    Err(error) => {
+                        eprintln!("error: {}: object corrupt or missing: {} ({})", expected, path, error);
+                        self.errors |= ERROR_OBJECT;
+                    }
+                }
+            }
+        }
+    }
+
+    // Verify pack checksums and re-hash every object each pack claims to contain
+    fn scan_packs(&mut self) {
+        for pack in packs() {
+            if !pack.verify_checksum() {
+                eprintln!("error: {} SHA1 checksum mismatch", pack.pack_path);
+                self.errors |= ERROR_PACK;
+            }
+            let mut cache = HashMap::new();
+            for (expected, offset) in pack.ids.iter().zip(&pack.offsets) {
+                self.present.insert(expected.clone());
+                let (kind, contents) = match read_object_at(pack, *offset, &mut cache) {
+                    Ok(object) => object,
+                    Err(error) => {
+                        eprintln!("error: cannot unpack {} from {} at offset {}: {}", expected, pack.pack_path, offset, error);
+                        self.errors |= ERROR_PACK;
+                        continue;
+                    }
+                };
+                let actual = hex::encode(Sha1::digest(encode_object(&contents, &kind)));
+                if &actual != expected {
+                    eprintln!("error: packed {} from {} is corrupt", expected, pack.pack_path);
+                    self.errors |= ERROR_PACK;
+                    continue;
+                }
+                if !self.objects.contains_key(expected) {
+                    self.check_object(expected, &kind, &contents);
+                }
+            }
+        }
+    }
+
+    // Validate the syntax of an object and remember the objects it links to
+    fn check_object(&mut self, hash: &str, kind: &[u8], contents: &[u8]) {
+        let kind = String::from_utf8_lossy(kind).into_owned();
+        let mut problems: Vec<(bool, String)> = Vec::new();
+        let mut links = Vec::new();
+
+        match kind.as_str() {
+            "blob" => {}
+            "tree" => {
+                if !self.options.connectivity_only {
+                    for problem in check_git_tree(contents) {
+                        if !self.options.strict && matches!(&problem, TreeError::BadFilemode { mode, .. } if mode == "100664") {
+                            continue;
+                        }
+                        // --strict turns tree warnings into errors, except for the mode check it enables
+                        let is_warning = problem.is_warning()
+                            && (!self.options.strict || matches!(problem, TreeError::BadFilemode { .. }));
+                        problems.push((is_warning, problem.to_string()));
+                    }
+                }
+                for leaf in git_tree_parse_lossy(contents).unwrap_or_default() {
+                    match leaf.mode.as_slice() {
+                        b"160000" => {} // Submodule commits live in another repository
+                        b"040000" => links.push(("tree", leaf.sha_hash)),
+                        _ => links.push(("blob", leaf.sha_hash)),
+                    }
+                }
+            }
+            "commit" => {
+                let text = String::from_utf8_lossy(contents);
+                let (commit_links, commit_problems) = check_commit(&text);
+                links = commit_links;
+                if self.options.show_root && commit_problems.is_empty() && !links.iter().any(|(kind, _)| *kind == "commit") {
+                    println!("root {}", hash);
+                }
+                problems.extend(commit_problems.into_iter().map(|problem| (false, problem)));
+            }
+            "tag" => {
+                let text = String::from_utf8_lossy(contents);
+                let (tag_links, tag_problems) = check_tag(&text);
+                if self.options.show_tags {
+                    if let Some((target_kind, target)) = tag_links.first() {
+                        let name = header_value(&text, "tag").unwrap_or("");
+                        println!("tagged {} {} ({}) in {}", target_kind, target, name, hash);
+                    }
+                }
+                links = tag_links;
+                problems.extend(tag_problems.into_iter().map(|problem| (false, problem)));
+            }
+            _ => problems.push((false, format!("unknown object type '{}'", kind))),
+        }
+
+        for (is_warning, problem) in problems {
+            if self.options.connectivity_only {
+                break;
+            }
+            if is_warning {
+                println!("warning in {} {}: {}", kind, hash, problem);
+            } else {
+                println!("error in {} {}: {}", kind, hash, problem);
+                self.errors |= ERROR_OBJECT;
+            }
+        }
+        self.objects.insert(hash.to_string(), FsckObject { kind, links });
+    }
+
+    // Collect the ids fsck starts its reachability walk from
+    fn collect_heads(&mut self) -> Vec<String> {
+        let mut heads = Vec::new();
+        if !self.options.heads.is_empty() {
+            for name in self.options.heads.clone() {
+                match resolve_ref(&name).or_else(|| is_object_id(&name).then(|| name.clone())) {
+                    Some(hash) => heads.push(hash),
+                    None => {
+                        eprintln!("error: invalid parameter: expected sha1, got '{}'", name);
+                        self.errors |= ERROR_REFS;
+                    }
+                }
+            }
+            return heads;
+        }
+
+        match resolve_ref("HEAD") {
+            Some(hash) => heads.push(hash),
+            None => match head_symbolic_target() {
+                Some(branch) => eprintln!("notice: HEAD points to an unborn branch ({})", branch.trim_start_matches("refs/heads/")),
+                None => {
+                    eprintln!("error: Invalid HEAD");
+                    self.errors |= ERROR_REFS;
+                }
+            },
+        }
+
+        let refs = list_refs();
+        if refs.is_empty() {
+            eprintln!("notice: No default references");
+        }
+        for (name, hash) in refs {
+            if !self.present.contains(&hash) {
+                eprintln!("error: {}: invalid sha1 pointer {}", name, hash);
+                self.errors |= ERROR_REFS;
+            }
+            heads.push(hash);
+        }
+
+        if self.options.include_reflogs {
+            for name in list_reflogs() {
+                for entry in read_reflog(&name) {
+                    for hash in [entry.old_hash, entry.new_hash] {
+                        if hash.bytes().all(|x| x == b'0') {
+                            continue;
+                        }
+                        if !self.present.contains(&hash) {
+                            eprintln!("error: {}: invalid reflog entry {}", name, hash);
+                            self.errors |= ERROR_REFS;
+                        }
+                        heads.push(hash);
+                    }
+                }
+            }
+        }
+
+        match read_index() {
+            Ok(entries) => {
+                for entry in entries {
+                    if entry.mode == 0o160000 {
+                        continue;
+                    }
+                    if !self.present.contains(&entry.sha_hash) {
+                        eprintln!("error: {}: invalid sha1 pointer in cache ({})", entry.sha_hash, entry.path);
+                        self.errors |= ERROR_REFS;
+                    }
+                    heads.push(entry.sha_hash);
+                }
+            }
+            Err(error) => {
+                eprintln!("error: {}", error);
+                self.errors |= ERROR_REFS;
+            }
+        }
+        heads
+    }
+
+    // Walk from the heads reporting broken links and missing objects, then report
+    // dangling and unreachable objects
+    fn check_connectivity(&mut self) {
+        let heads = self.collect_heads();
+
+        let mut reachable: BTreeSet<String> = BTreeSet::new();
+        let mut missing: BTreeMap<String, &'static str> = BTreeMap::new();
+        let mut queue: VecDeque<String> = heads.into_iter().collect();
+        while let Some(hash) = queue.pop_front() {
+            if !reachable.insert(hash.clone()) {
+                continue;
+            }
+            let object = match self.objects.get(&hash) {
+                Some(object) => object,
+                None => continue,
+            };
+            for (kind, link) in &object.links {
+                if !self.objects.contains_key(link) && !missing.contains_key(link) {
+                    if !self.present.contains(link) {
+                        println!("broken link from {:>7} {}", object.kind, hash);
+                        println!("              to {:>7} {}", kind, link);
+                    }
+                    missing.insert(link.clone(), kind);
+                }
+                queue.push_back(link.clone());
+            }
+        }
+
+        let mut used: BTreeSet<&String> = BTreeSet::new();
+        for object in self.objects.values() {
+            for (kind, link) in &object.links {
+                used.insert(link);
+                match self.objects.get(link) {
+                    Some(target) if target.kind != *kind => {
+                        eprintln!("error: object {} is a {}, not a {}", link, target.kind, kind);
+                        self.errors |= ERROR_OBJECT;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (hash, kind) in &missing {
+            println!("missing {} {}", kind, hash);
+            self.errors |= ERROR_REACHABLE;
+        }
+
+        for (hash, object) in &self.objects {
+            if reachable.contains(hash) {
+                continue;
+            }
+            if self.options.show_unreachable {
+                println!("unreachable {} {}", object.kind, hash);
+                continue;
+            }
+            if used.contains(hash) {
+                continue;
+            }
+            if self.options.show_dangling {
+                println!("dangling {} {}", object.kind, hash);
+            }
+            if self.options.lost_found {
+                if let Err(error) = write_lost_found(hash, &object.kind) {
+                    eprintln!("error: could not write lost-found entry for {}: {}", hash, error);
+                }
+            }
+        }
+    }
+}
+
+fn is_object_id(text: &str) -> bool {
+    text.len() == 40 && text.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+fn header_value<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    text.lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+}
+
+// Check the header of a commit, returning its links and any problems in git's wording
+fn check_commit(text: &str) -> (Vec<(&'static str, String)>, Vec<String>) {
+    let mut links = Vec::new();
+    let mut problems = Vec::new();
+    let mut lines = text.lines().peekable();
+
+    match lines.next().and_then(|line| line.strip_prefix("tree ")) {
+        Some(tree) if is_object_id(tree) => links.push(("tree", tree.to_string())),
+        Some(_) => return (links, vec!["badTreeSha1: invalid 'tree' line format - bad sha1".to_string()]),
+        None => return (links, vec!["missingTree: invalid format - expected 'tree' line".to_string()]),
+    }
+    while let Some(parent) = lines.peek().and_then(|line| line.strip_prefix("parent ")) {
+        if !is_object_id(parent) {
+            return (links, vec!["badParentSha1: invalid 'parent' line format - bad sha1".to_string()]);
+        }
+        links.push(("commit", parent.to_string()));
+        lines.next();
+    }
+
+    // Like git, only the first problem in the header is reported
+    for header in ["author", "committer"] {
+        let prefix = format!("{} ", header);
+        match lines.next().and_then(|line| line.strip_prefix(prefix.as_str())) {
+            Some(identity) => {
+                if let Some(problem) = check_identity(identity) {
+                    problems.push(problem);
+                    break;
+                }
+            }
+            None => {
+                let id = if header == "author" { "missingAuthor" } else { "missingCommitter" };
+                problems.push(format!("{}: invalid format - expected '{}' line", id, header));
+                break;
+            }
+        }
+    }
+    (links, problems)
+}
+
+// Check the header of an annotated tag, returning the tagged object and any problems
+fn check_tag(text: &str) -> (Vec<(&'static str, String)>, Vec<String>) {
+    let mut links = Vec::new();
+    let mut problems = Vec::new();
+    let mut lines = text.lines();
+
+    let object = match lines.next().and_then(|line| line.strip_prefix("object ")) {
+        Some(object) if is_object_id(object) => object.to_string(),
+        Some(_) => return (links, vec!["badObjectSha1: invalid 'object' line format - bad sha1".to_string()]),
+        None => return (links, vec!["missingObject: invalid format - expected 'object' line".to_string()]),
+    };
+    let kind = match lines.next().and_then(|line| line.strip_prefix("type ")) {
+        Some("commit") => "commit",
+        Some("tree") => "tree",
+        Some("blob") => "blob",
+        Some("tag") => "tag",
+        Some(_) => return (links, vec!["badType: invalid 'type' value".to_string()]),
+        None => return (links, vec!["missingTypeEntry: invalid format - expected 'type' line".to_string()]),
+    };
+    links.push((kind, object));
+
+    if lines.next().and_then(|line| line.strip_prefix("tag ")).is_none() {
+        problems.push("missingTagEntry: invalid format - expected 'tag' line".to_string());
+        return (links, problems);
+    }
+    if let Some(identity) = lines.next().and_then(|line| line.strip_prefix("tagger ")) {
+        if let Some(problem) = check_identity(identity) {
+            problems.push(problem);
+        }
+    }
+    (links, problems)
+}
+
+// Check "Name <email> timestamp timezone", following the order of checks in git's fsck_ident
+fn check_identity(identity: &str) -> Option<String> {
+    let fail = |id: &str, what: &str| Some(format!("{}: invalid author/committer line - {}", id, what));
+    let bytes = identity.as_bytes();
+
+    if bytes.first() == Some(&b'<') {
+        return fail("missingNameBeforeEmail", "missing space before email");
+    }
+    let mut index = bytes.iter().position(|&x| x == b'<' || x == b'>').unwrap_or(bytes.len());
+    if bytes.get(index) == Some(&b'>') {
+        return fail("badName", "bad name");
+    }
+    if bytes.get(index) != Some(&b'<') {
+        return fail("missingEmail", "missing email");
+    }
+    if bytes[index - 1] != b' ' {
+        return fail("missingSpaceBeforeEmail", "missing space before email");
+    }
+    index += 1;
+    index += bytes[index..].iter().position(|&x| x == b'<' || x == b'>').unwrap_or(bytes.len() - index);
+    if bytes.get(index) != Some(&b'>') {
+        return fail("badEmail", "bad email");
+    }
+    index += 1;
+    if bytes.get(index) != Some(&b' ') {
+        return fail("missingSpaceBeforeDate", "missing space before date");
+    }
+    index += 1;
+
+    let date_end = index + bytes[index..].iter().take_while(|x| x.is_ascii_digit()).count();
+    if bytes.get(index) == Some(&b'0') && bytes.get(index + 1) != Some(&b' ') {
+        return fail("zeroPaddedDate", "zero-padded date");
+    }
+    if date_end == index || bytes.get(date_end) != Some(&b' ') {
+        return fail("badDate", "bad date");
+    }
+    if identity[index..date_end].parse::<u64>().is_err() {
+        return fail("badDateOverflow", "date causes integer overflow");
+    }
+
+    let timezone = &bytes[date_end + 1..];
+    if timezone.len() != 5 || !matches!(timezone[0], b'+' | b'-') || !timezone[1..].iter().all(|x| x.is_ascii_digit()) {
+        return fail("badTimezone", "bad time zone");
+    }
+    None
+}
+
+// Save a dangling object under .git/lost-found, blobs by content and everything else by id
+fn write_lost_found(hash: &str, kind: &str) -> anyhow::Result<()> {
+    let directory = if kind == "commit" { ".git/lost-found/commit" } else { ".git/lost-found/other" };
+    fs::create_dir_all(directory)?;
+    let contents = if kind == "blob" {
+        crate::read_raw_object(hash)?.1
+    } else {
+        format!("{}\n", hash).into_bytes()
+    };
+    fs::write(format!("{}/{}", directory, hash), contents)?;
+    Ok(())
+}
@@ -0,0 +1,494 @@
+// A port of the ASCII history graph drawn by git log --graph. Each commit moves the graph
+// through a few kinds of rows: an optional expansion row before octopus merges, the row
+// with the commit itself, a row fanning out a merge's parents, and rows collapsing branch
+// lines that have come to point at the same commit.
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Padding,
+    Skip,
+    PreCommit,
+    Commit,
+    PostMerge,
+    Collapsing,
+}
+
+pub struct Graph {
+    commit: String,
+    parents: Vec<String>,
+    width: usize,
+    expansion_row: usize,
+    state: State,
+    prev_state: State,
+    commit_index: usize,
+    prev_commit_index: usize,
+    merge_layout: i64,
+    edges_added: i64,
+    prev_edges_added: i64,
+    columns: Vec<String>,
+    new_columns: Vec<String>,
+    mapping: Vec<i64>,
+    old_mapping: Vec<i64>,
+}
+
+const MERGE_CHARS: [char; 3] = ['/', '|', '\\'];
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph {
+            commit: String::new(),
+            parents: Vec::new(),
+            width: 0,
+            expansion_row: 0,
+            state: State::Padding,
+            prev_state: State::Padding,
+            commit_index: 0,
+            prev_commit_index: 0,
+            merge_layout: 0,
+            edges_added: 0,
+            prev_edges_added: 0,
+            columns: Vec::new(),
+            new_columns: Vec::new(),
+            mapping: Vec::new(),
+            old_mapping: Vec::new(),
+        }
+    }
+
+    fn update_state(&mut self, state: State) {
+        self.prev_state = self.state;
+        self.state = state;
+    }
+
+    fn find_new_column(&self, commit: &str) -> Option<usize> {
+        self.new_columns.iter().position(|x| x == commit)
+    }
+
+    fn insert_into_new_columns(&mut self, commit: &str, index: Option<usize>) {
+        let column = match self.find_new_column(commit) {
+            Some(column) => column,
+            None => {
+                self.new_columns.push(commit.to_string());
+                self.new_columns.len() - 1
+            }
+        };
+        let mapping_index;
+        let merge_index = index.filter(|_| self.parents.len() > 1 && self.merge_layout == -1);
+        if let Some(index) = merge_index {
+            // The first parent of a merge picks the layout: whether the merge line starts
+            // straight down or leans left towards a parent already shown to the left
+            let distance = index as i64 - column as i64;
+            let shift = if distance > 1 { 2 * distance - 3 } else { 1 };
+            self.merge_layout = if distance > 0 { 0 } else { 1 };
+            self.edges_added = self.parents.len() as i64 + self.merge_layout - 2;
+            mapping_index = (self.width as i64 + (self.merge_layout - 1) * shift) as usize;
+            self.width += 2 * self.merge_layout as usize;
+        } else if self.edges_added > 0 && self.width >= 2 && self.mapping[self.width - 2] == column as i64 {
+            // The parent is in the last existing column, so the two edges join immediately
+            mapping_index = self.width - 2;
+            self.edges_added = -1;
+        } else {
+            mapping_index = self.width;
+            self.width += 2;
+        }
+        self.mapping[mapping_index] = column as i64;
+    }
+
+    fn update_columns(&mut self) {
+        std::mem::swap(&mut self.columns, &mut self.new_columns);
+        self.new_columns.clear();
+
+        // Keep the mapping the last row was drawn from, so a commit row can continue a branch
+        // line the collapsing row before it bent towards this column
+        let max_new_columns = self.columns.len() + self.parents.len();
+        self.old_mapping = std::mem::replace(&mut self.mapping, vec![-1; 2 * max_new_columns]);
+        self.width = 0;
+        self.prev_edges_added = self.edges_added;
+        self.edges_added = 0;
+
+        let mut seen_this = false;
+        for index in 0..=self.columns.len() {
+            let column_commit = if index == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                self.commit.clone()
+            } else {
+                self.columns[index].clone()
+            };
+            if column_commit == self.commit {
+                seen_this = true;
+                self.commit_index = index;
+                self.merge_layout = -1;
+                for parent in self.parents.clone() {
+                    self.insert_into_new_columns(&parent, Some(index));
+                }
+                // The commit itself always takes up at least two columns
+                if self.parents.is_empty() {
+                    self.width += 2;
+                }
+            } else {
+                self.insert_into_new_columns(&column_commit, None);
+            }
+        }
+        while self.mapping.len() > 1 && self.mapping[self.mapping.len() - 1] < 0 {
+            self.mapping.pop();
+        }
+    }
+
+    // Octopus merges need two rows for every dashed parent line to route the branches around
+    // them, fewer when the merge leans left
+    fn expansion_rows(&self) -> i64 {
+        (self.parents.len() as i64 + self.merge_layout - 3) * 2
+    }
+
+    fn needs_pre_commit_line(&self) -> bool {
+        self.parents.len() >= 3 && self.commit_index + 1 < self.columns.len() && (self.expansion_row as i64) < self.expansion_rows()
+    }
+
+    // Start drawing a commit, given its parents that are themselves shown
+    pub fn update(&mut self, commit: &str, parents: Vec<String>) {
+        self.commit = commit.to_string();
+        self.parents = parents;
+        self.prev_commit_index = self.commit_index;
+        self.update_columns();
+        self.expansion_row = 0;
+        if self.state != State::Padding {
+            self.state = State::Skip;
+        } else if self.needs_pre_commit_line() {
+            self.state = State::PreCommit;
+        } else {
+            self.state = State::Commit;
+        }
+    }
+
+    pub fn is_commit_finished(&self) -> bool {
+        self.state == State::Padding
+    }
+
+    fn is_mapping_correct(&self) -> bool {
+        self.mapping.iter().enumerate().all(|(index, &target)| target < 0 || target as usize == index / 2)
+    }
+
+    fn pad(&self, line: &mut String) {
+        let width = line.chars().count();
+        if width < self.width {
+            line.push_str(&" ".repeat(self.width - width));
+        }
+    }
+
+    fn padding_row(&self, line: &mut String) {
+        for _ in &self.new_columns {
+            line.push_str("| ");
+        }
+    }
+
+    fn skip_row(&mut self, line: &mut String) {
+        line.push_str("...");
+        if self.needs_pre_commit_line() {
+            self.update_state(State::PreCommit);
+        } else {
+            self.update_state(State::Commit);
+        }
+    }
+
+    fn pre_commit_row(&mut self, line: &mut String) {
+        let mut seen_this = false;
+        for (index, column) in self.columns.iter().enumerate() {
+            if *column == self.commit {
+                seen_this = true;
+                line.push('|');
+                line.push_str(&" ".repeat(self.expansion_row));
+            } else if seen_this && self.expansion_row == 0 {
+                if self.prev_state == State::PostMerge && self.prev_commit_index < index {
+                    line.push('\\');
+                } else {
+                    line.push('|');
+                }
+            } else if seen_this {
+                line.push('\\');
+            } else {
+                line.push('|');
+            }
+            line.push(' ');
+        }
+        self.expansion_row += 1;
+        if !self.needs_pre_commit_line() {
+            self.update_state(State::Commit);
+        }
+    }
+
+    fn commit_row(&mut self, line: &mut String) {
+        let mut seen_this = false;
+        for index in 0..=self.columns.len() {
+            let column_commit = if index == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                &self.commit
+            } else {
+                &self.columns[index]
+            };
+            if *column_commit == self.commit {
+                seen_this = true;
+                line.push('*');
+                if self.parents.len() > 2 {
+                    // Octopus merges draw dashes towards their extra parents
+                    let dashed = self.parents.len() as i64 + self.merge_layout - 3;
+                    for dash in 0..dashed {
+                        line.push('-');
+                        line.push(if dash == dashed - 1 { '.' } else { '-' });
+                    }
+                }
+            } else if seen_this && self.edges_added > 1 {
+                line.push('\\');
+            } else if seen_this && self.edges_added == 1 {
+                if self.prev_state == State::PostMerge && self.prev_edges_added > 0 && self.prev_commit_index < index {
+                    line.push('\\');
+                } else {
+                    line.push('|');
+                }
+            } else if self.prev_state == State::Collapsing
+                && self.old_mapping.get(2 * index + 1) == Some(&(index as i64))
+                && self.mapping.get(2 * index).map(|&x| x < index as i64).unwrap_or(false)
+            {
+                line.push('/');
+            } else {
+                line.push('|');
+            }
+            line.push(' ');
+        }
+
+        if self.parents.len() > 1 {
+            self.update_state(State::PostMerge);
+        } else if self.is_mapping_correct() {
+            self.update_state(State::Padding);
+        } else {
+            self.update_state(State::Collapsing);
+        }
+    }
+
+    fn post_merge_row(&mut self, line: &mut String) {
+        let mut seen_this = false;
+        let mut parent_column_seen = false;
+        let first_parent = self.parents[0].clone();
+        for index in 0..=self.columns.len() {
+            let column_commit = if index == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                self.commit.clone()
+            } else {
+                self.columns[index].clone()
+            };
+            if column_commit == self.commit {
+                seen_this = true;
+                let mut layout = self.merge_layout as usize;
+                for parent in 0..self.parents.len() {
+                    line.push(MERGE_CHARS[layout]);
+                    if layout == 2 {
+                        if self.edges_added > 0 || parent < self.parents.len() - 1 {
+                            line.push(' ');
+                        }
+                    } else {
+                        layout += 1;
+                    }
+                }
+                if self.edges_added == 0 {
+                    line.push(' ');
+                }
+            } else if seen_this {
+                line.push(if self.edges_added > 0 { '\\' } else { '|' });
+                line.push(' ');
+            } else {
+                line.push('|');
+                if self.merge_layout != 0 || index + 1 != self.commit_index {
+                    line.push(if parent_column_seen { '_' } else { ' ' });
+                }
+            }
+            if column_commit == first_parent {
+                parent_column_seen = true;
+            }
+        }
+
+        if self.is_mapping_correct() {
+            self.update_state(State::Padding);
+        } else {
+            self.update_state(State::Collapsing);
+        }
+    }
+
+    fn collapsing_row(&mut self, line: &mut String) {
+        let mut used_horizontal = false;
+        let mut horizontal_edge: i64 = -1;
+        let mut horizontal_edge_target: i64 = -1;
+
+        std::mem::swap(&mut self.mapping, &mut self.old_mapping);
+        self.mapping = vec![-1; self.old_mapping.len()];
+
+        for index in 0..self.old_mapping.len() {
+            let target = self.old_mapping[index];
+            if target < 0 {
+                continue;
+            }
+            // Branch lines only ever move left, towards their target column
+            if target as usize * 2 == index {
+                self.mapping[index] = target;
+            } else if self.mapping[index - 1] < 0 {
+                self.mapping[index - 1] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge = index as i64;
+                    horizontal_edge_target = target;
+                    let mut j = target as usize * 2 + 3;
+                    while j + 2 < index {
+                        self.mapping[j] = target;
+                        j += 2;
+                    }
+                }
+            } else if self.mapping[index - 1] == target {
+                // Joins the branch line to its left, which already leads to the same commit
+            } else {
+                // Cross over the branch line to the left
+                self.mapping[index - 2] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge_target = target;
+                    horizontal_edge = index as i64 - 1;
+                    let mut j = target as usize * 2 + 3;
+                    while j + 2 < index {
+                        self.mapping[j] = target;
+                        j += 2;
+                    }
+                }
+            }
+        }
+
+        if self.mapping.last().map(|&x| x < 0).unwrap_or(false) {
+            self.mapping.pop();
+        }
+
+        for index in 0..self.mapping.len() {
+            let target = self.mapping[index];
+            if target < 0 {
+                line.push(' ');
+            } else if target as usize * 2 == index {
+                line.push('|');
+            } else if target == horizontal_edge_target && index as i64 != horizontal_edge - 1 {
+                // Only the first segment of a horizontal edge continues into the next row
+                if index != target as usize * 2 + 3 {
+                    self.mapping[index] = -1;
+                }
+                used_horizontal = true;
+                line.push('_');
+            } else {
+                if used_horizontal && (index as i64) < horizontal_edge {
+                    self.mapping[index] = -1;
+                }
+                line.push('/');
+            }
+        }
+
+        if self.is_mapping_correct() {
+            self.update_state(State::Padding);
+        }
+    }
+
+    // Produce the next row of the graph, returning whether it was the commit's own row
+    pub fn next_line(&mut self) -> (String, bool) {
+        let mut line = String::new();
+        let mut commit_row = false;
+        match self.state {
+            State::Padding => self.padding_row(&mut line),
+            State::Skip => self.skip_row(&mut line),
+            State::PreCommit => self.pre_commit_row(&mut line),
+            State::Commit => {
+                self.commit_row(&mut line);
+                commit_row = true;
+            }
+            State::PostMerge => self.post_merge_row(&mut line),
+            State::Collapsing => self.collapsing_row(&mut line),
+        }
+        self.pad(&mut line);
+        (line, commit_row)
+    }
+
+    // A row that keeps every branch line going, used between commits
+    pub fn padding_line(&mut self) -> String {
+        if self.state != State::Commit {
+            return self.next_line().0;
+        }
+        let mut line = String::new();
+        for column in &self.columns {
+            line.push('|');
+            if *column == self.commit && self.parents.len() > 2 {
+                line.push_str(&" ".repeat((self.parents.len() - 2) * 2));
+            } else {
+                line.push(' ');
+            }
+        }
+        self.pad(&mut line);
+        self.prev_state = State::Padding;
+        line
+    }
+
+    // All rows up to and including the commit's own row, the last without a newline
+    pub fn show_commit(&mut self) -> String {
+        let mut output = String::new();
+        if self.is_commit_finished() {
+            return self.padding_line();
+        }
+        loop {
+            let (line, commit_row) = self.next_line();
+            output.push_str(&line);
+            if commit_row {
+                break;
+            }
+            output.push('\n');
+            if self.is_commit_finished() {
+                break;
+            }
+        }
+        output
+    }
+
+    // The rows left after the commit's text, without a trailing newline
+    pub fn show_remainder(&mut self) -> String {
+        let mut lines = Vec::new();
+        while !self.is_commit_finished() {
+            lines.push(self.next_line().0);
+        }
+        lines.join("\n")
+    }
+
+    // Print a commit's text with the graph drawn in front of every line
+    pub fn show_message(&mut self, message: &str) -> String {
+        let mut output = String::new();
+        let mut remaining = message;
+        let mut newline_terminated = false;
+        while !remaining.is_empty() {
+            let (line, rest) = match remaining.find('\n') {
+                Some(position) => {
+                    newline_terminated = true;
+                    remaining.split_at(position + 1)
+                }
+                None => {
+                    newline_terminated = false;
+                    (remaining, "")
+                }
+            };
+            output.push_str(line);
+            remaining = rest;
+            if !remaining.is_empty() {
+                output.push_str(&self.next_line().0);
+            }
+        }
+        if !self.is_commit_finished() {
+            if !newline_terminated {
+                output.push('\n');
+            }
+            output.push_str(&self.show_remainder());
+            if newline_terminated {
+                output.push('\n');
+            }
+        }
+        output
+    }
+}
@@ -0,0 +1,887 @@
+use crate::diff::quote_path;
+use crate::ignore::fnmatch;
+use crate::index::read_index;
+use crate::revision::{ambiguous_argument, peel_to_tree, resolve_revision};
+use crate::walk::basic_to_extended;
+use crate::worktree::{flatten_tree, pathspec_matches, TreeEntry};
+use crate::{option_value, read_object_from_store, GitObjectType};
+use regex::bytes::{Regex, RegexBuilder};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Only this many leading bytes are checked for a NUL when deciding whether a file is binary
+const BINARY_CHECK_SIZE: usize = 8000;
+
+// Syntax is how the patterns given on the command line are read
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    Basic,
+    Extended,
+    Fixed,
+    Perl,
+}
+
+// Binary is what is done with files that contain a NUL byte
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Binary {
+    Report,
+    Text,
+    Skip,
+}
+
+// Token is one element of the pattern expression in the order it was given
+enum Token {
+    Pattern(String),
+    And,
+    Not,
+    Open,
+    Close,
+}
+
+// Atom is a single compiled pattern. Word matching is checked by hand so that a match that
+// is not a whole word can be retried further along the line.
+struct Atom {
+    regex: Regex,
+    word: bool,
+}
+
+impl Atom {
+    fn find_at(&self, line: &[u8], start: usize) -> Option<(usize, usize)> {
+        let mut start = start;
+        loop {
+            let found = self.regex.find_at(line, start)?;
+            let (begin, end) = (found.start(), found.end());
+            if !self.word {
+                return Some((begin, end));
+            }
+            let before = begin == 0 || !is_word_char(line[begin - 1]);
+            let after = end == line.len() || !is_word_char(line[end]);
+            if before && after && begin != end {
+                return Some((begin, end));
+            }
+            // Move on to the next place a word could start
+            if begin + 1 >= line.len() {
+                return None;
+            }
+            start = begin + 1;
+            while start < line.len() && is_word_char(line[start - 1]) {
+                start += 1;
+            }
+            if start >= line.len() {
+                return None;
+            }
+        }
+    }
+}
+
+fn is_word_char(x: u8) -> bool {
+    x.is_ascii_alphanumeric() || x == b'_'
+}
+
+// Expr is the pattern expression a line is matched against: patterns given one after another
+// are alternatives, --and binds tighter than that and --not tighter still
+enum Expr {
+    Atom(usize),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // Whether the line matches, lowering column to the start of the earliest match of a
+    // pattern that is not negated
+    fn matches(&self, atoms: &[Atom], line: &[u8], column: &mut Option<usize>, collect: bool) -> bool {
+        match self {
+            Expr::Atom(index) => match atoms[*index].find_at(line, 0) {
+                Some((begin, _)) => {
+                    if collect && column.is_none_or(|x| begin < x) {
+                        *column = Some(begin);
+                    }
+                    true
+                }
+                None => false,
+            },
+            Expr::Not(inner) => !inner.matches(atoms, line, column, !collect),
+            Expr::And(left, right) => {
+                left.matches(atoms, line, column, collect) && right.matches(atoms, line, column, collect)
+            }
+            Expr::Or(left, right) => {
+                // Both sides are tried so the column is that of the earliest match
+                let left = left.matches(atoms, line, column, collect);
+                let right = right.matches(atoms, line, column, collect);
+                left || right
+            }
+        }
+    }
+
+    // The alternatives of a top level chain of ORs, which --all-match requires to each match
+    // somewhere in a file
+    fn alternatives(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Or(left, right) => {
+                let mut alternatives = vec![left.as_ref()];
+                alternatives.extend(right.alternatives());
+                alternatives
+            }
+            _ => vec![self],
+        }
+    }
+}
+
+fn compile_or(tokens: &[Token], position: &mut usize) -> anyhow::Result<Option<Expr>> {
+    let Some(left) = compile_and(tokens, position)? else {
+        return Ok(None);
+    };
+    match tokens.get(*position) {
+        Some(Token::Close) | None => Ok(Some(left)),
+        Some(token) => match compile_or(tokens, position)? {
+            Some(right) => Ok(Some(Expr::Or(Box::new(left), Box::new(right)))),
+            None => anyhow::bail!("not a pattern expression {}", token_name(token)),
+        },
+    }
+}
+
+fn compile_and(tokens: &[Token], position: &mut usize) -> anyhow::Result<Option<Expr>> {
+    let Some(left) = compile_not(tokens, position)? else {
+        return Ok(None);
+    };
+    if let Some(Token::And) = tokens.get(*position) {
+        *position += 1;
+        let Some(right) = compile_and(tokens, position)? else {
+            anyhow::bail!("--and not followed by pattern expression");
+        };
+        return Ok(Some(Expr::And(Box::new(left), Box::new(right))));
+    }
+    Ok(Some(left))
+}
+
+fn compile_not(tokens: &[Token], position: &mut usize) -> anyhow::Result<Option<Expr>> {
+    if let Some(Token::Not) = tokens.get(*position) {
+        *position += 1;
+        let Some(inner) = compile_not(tokens, position)? else {
+            anyhow::bail!("--not not followed by pattern expression");
+        };
+        return Ok(Some(Expr::Not(Box::new(inner))));
+    }
+    compile_atom(tokens, position)
+}
+
+fn compile_atom(tokens: &[Token], position: &mut usize) -> anyhow::Result<Option<Expr>> {
+    match tokens.get(*position) {
+        Some(Token::Pattern(_)) => {
+            // Atoms are numbered in the order their patterns appear
+            let index = tokens[..*position].iter().filter(|x| matches!(x, Token::Pattern(_))).count();
+            *position += 1;
+            Ok(Some(Expr::Atom(index)))
+        }
+        Some(Token::Open) => {
+            *position += 1;
+            let inner = compile_or(tokens, position)?;
+            if !matches!(tokens.get(*position), Some(Token::Close)) {
+                anyhow::bail!("unmatched parenthesis");
+            }
+            *position += 1;
+            Ok(inner)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn token_name(token: &Token) -> &str {
+    match token {
+        Token::Pattern(pattern) => pattern,
+        Token::And => "--and",
+        Token::Not => "--not",
+        Token::Open => "(",
+        Token::Close => ")",
+    }
+}
+
+// Options controls how matches are reported
+#[derive(Default)]
+struct Options {
+    invert: bool,
+    line_number: bool,
+    column: bool,
+    no_name: bool,
+    files_with_matches: bool,
+    files_without_match: bool,
+    count: bool,
+    quiet: bool,
+    only_matching: bool,
+    null: bool,
+    heading: bool,
+    file_break: bool,
+    before: usize,
+    after: usize,
+    all_match: bool,
+    max_count: Option<usize>,
+}
+
+// Matcher is everything needed to search one file, shared by all the search threads
+struct Matcher {
+    options: Options,
+    binary: Binary,
+    atoms: Vec<Atom>,
+    expr: Expr,
+}
+
+// Source is where the contents of a file to search come from
+enum Source {
+    Worktree(String),
+    Blob(String),
+}
+
+// Found is the output for one file, kept until the files before it have been written
+#[derive(Default)]
+struct Found {
+    output: Vec<u8>,
+    matched: bool,
+    // Where separators were put before the file's first shown line, dropped when nothing
+    // came before the file
+    file_separators: Vec<std::ops::Range<usize>>,
+    // Some matching or context line was shown, so separators are due before the next file
+    shown: bool,
+}
+
+// Report collects the lines shown for one file
+struct Report<'a> {
+    matcher: &'a Matcher,
+    name: &'a str,
+    found: Found,
+    last_shown: usize,
+}
+
+impl Report<'_> {
+    fn separator(&mut self, separator: &[u8]) {
+        let start = self.found.output.len();
+        self.found.output.extend_from_slice(separator);
+        if self.last_shown == 0 {
+            self.found.file_separators.push(start..self.found.output.len());
+        }
+    }
+
+    // The name, line number and column in front of a line, each followed by sign
+    fn header(&mut self, line_number: usize, column: Option<usize>, sign: u8) {
+        let options = &self.matcher.options;
+        let sign = if options.null { b'\0' } else { sign };
+        let output = &mut self.found.output;
+        if !options.no_name && !options.heading {
+            output.extend_from_slice(self.name.as_bytes());
+            output.push(sign);
+        }
+        if options.line_number {
+            output.extend_from_slice(line_number.to_string().as_bytes());
+            output.push(sign);
+        }
+        if let Some(column) = column.filter(|_| options.column) {
+            output.extend_from_slice(column.to_string().as_bytes());
+            output.push(sign);
+        }
+    }
+
+    fn show_line(&mut self, line: &[u8], line_number: usize, column: Option<usize>, sign: u8) {
+        let options = &self.matcher.options;
+        if options.file_break && self.last_shown == 0 {
+            self.separator(b"\n");
+        } else if (options.before > 0 || options.after > 0)
+            && (self.last_shown == 0 || line_number > self.last_shown + 1)
+        {
+            self.separator(b"--\n");
+        }
+        if options.only_matching && sign != b':' {
+            return;
+        }
+        if options.heading && self.last_shown == 0 {
+            self.found.output.extend_from_slice(self.name.as_bytes());
+            self.found.output.push(b'\n');
+        }
+        self.last_shown = line_number;
+        self.found.shown = true;
+
+        if !options.only_matching || options.invert {
+            self.header(line_number, column, sign);
+            self.found.output.extend_from_slice(line);
+            self.found.output.push(b'\n');
+            return;
+        }
+        // Every match on the line is shown on its own, the column advancing by the distance
+        // from the end of one match to the end of the next
+        let mut column = column.unwrap_or(1);
+        let mut start = 0;
+        while let Some((begin, end)) = self.next_match(line, start) {
+            if begin == end {
+                break;
+            }
+            self.header(line_number, Some(column), sign);
+            self.found.output.extend_from_slice(&line[begin..end]);
+            self.found.output.push(b'\n');
+            column += end - start;
+            start = end;
+        }
+    }
+
+    // The earliest match of any pattern at or after start
+    fn next_match(&self, line: &[u8], start: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for atom in &self.matcher.atoms {
+            if let Some(found) = atom.find_at(line, start) {
+                if best.is_none_or(|x| found.0 < x.0) {
+                    best = Some(found);
+                }
+            }
+        }
+        best
+    }
+}
+
+impl Matcher {
+    // Whether the line is selected, with the 1-based column shown for it
+    fn line_matches(&self, line: &[u8]) -> (bool, usize) {
+        let mut column = None;
+        let matched = self.expr.matches(&self.atoms, line, &mut column, true);
+        (matched != self.options.invert, column.unwrap_or(0) + 1)
+    }
+
+    // With --all-match each of the alternatives has to match some line of the file
+    fn all_alternatives_match(&self, lines: &[&[u8]]) -> bool {
+        self.expr
+            .alternatives()
+            .iter()
+            .all(|alternative| lines.iter().any(|line| alternative.matches(&self.atoms, line, &mut None, false)))
+    }
+
+    fn search(&self, name: &str, contents: &[u8]) -> Found {
+        let options = &self.options;
+        let binary = self.binary != Binary::Text && contents[..contents.len().min(BINARY_CHECK_SIZE)].contains(&0);
+        if binary && self.binary == Binary::Skip {
+            return Found::default();
+        }
+        let mut lines: Vec<&[u8]> = contents.split(|&x| x == b'\n').collect();
+        if contents.ends_with(b"\n") || contents.is_empty() {
+            lines.pop();
+        }
+        if options.all_match && !self.all_alternatives_match(&lines) {
+            return self.finish(name, Found::default(), 0);
+        }
+
+        let mut report = Report { matcher: self, name, found: Found::default(), last_shown: 0 };
+        let listing = options.files_with_matches || options.files_without_match || options.count;
+        let mut count = 0;
+        let mut last_hit: Option<usize> = None;
+        for (index, line) in lines.iter().enumerate() {
+            let line_number = index + 1;
+            let in_after_context = last_hit.is_some_and(|x| line_number <= x + options.after);
+            // Once enough lines matched only the context after the last of them is shown
+            if options.max_count.is_some_and(|x| count >= x) {
+                if in_after_context && !listing {
+                    report.show_line(line, line_number, None, b'-');
+                    continue;
+                }
+                break;
+            }
+            let (hit, column) = self.line_matches(line);
+            if hit {
+                count += 1;
+                if options.quiet {
+                    break;
+                }
+                if listing {
+                    if options.count {
+                        continue;
+                    }
+                    break;
+                }
+                if binary {
+                    report.found.output.extend_from_slice(format!("Binary file {} matches\n", name).as_bytes());
+                    break;
+                }
+                let from = line_number.saturating_sub(options.before).max(report.last_shown + 1).max(1);
+                for context in from..line_number {
+                    report.show_line(lines[context - 1], context, None, b'-');
+                }
+                report.show_line(line, line_number, Some(column), b':');
+                last_hit = Some(line_number);
+            } else if in_after_context && !listing {
+                report.show_line(line, line_number, None, b'-');
+            }
+        }
+        let found = report.found;
+        self.finish(name, found, count)
+    }
+
+    // Add the per-file summaries of -l, -L and -c once the file has been read
+    fn finish(&self, name: &str, mut found: Found, count: usize) -> Found {
+        let options = &self.options;
+        let terminator = if options.null { b'\0' } else { b'\n' };
+        found.matched = count > 0;
+        if options.quiet {
+            return found;
+        }
+        if options.files_with_matches && count > 0 || options.files_without_match && count == 0 {
+            found.output.extend_from_slice(name.as_bytes());
+            found.output.push(terminator);
+            found.matched = true;
+        } else if options.files_without_match {
+            found.matched = false;
+        } else if options.count && count > 0 {
+            if !options.no_name {
+                found.output.extend_from_slice(name.as_bytes());
+                found.output.push(if options.null { b'\0' } else { b':' });
+            }
+            found.output.extend_from_slice(format!("{}\n", count).as_bytes());
+        }
+        found
+    }
+}
+
+// Compile one pattern in the syntax selected for it
+fn compile_pattern(pattern: &str, syntax: Syntax, ignore_case: bool, word: bool) -> anyhow::Result<Atom> {
+    let expression = match syntax {
+        Syntax::Basic => basic_to_extended(pattern),
+        Syntax::Fixed => regex::escape(pattern),
+        Syntax::Extended | Syntax::Perl => pattern.to_string(),
+    };
+    let regex = RegexBuilder::new(&expression)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|error| anyhow::anyhow!("command line, '{}': {}", pattern, error))?;
+    Ok(Atom { regex, word })
+}
+
+// A pathspec selects the path if it names it or a parent directory, or matches it as a glob
+fn path_selected(pathspecs: &[String], path: &str) -> bool {
+    pathspecs.is_empty()
+        || pathspecs
+            .iter()
+            .any(|spec| pathspec_matches(spec, path) || (has_glob(spec) && fnmatch(spec.as_bytes(), path.as_bytes())))
+}
+
+fn has_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+// The files of a tree-ish, keyed by their path below it
+fn tree_files(spec: &str) -> anyhow::Result<Vec<(String, TreeEntry)>> {
+    Ok(flatten_tree(&peel_to_tree(&resolve_revision(spec)?)?)?.into_iter().collect())
+}
+
+// Explain why a "<rev>:<path>" argument could not be used when its revision is valid
+fn resolve_error(spec: &str) -> Option<anyhow::Error> {
+    let (revision, _) = spec.split_once(':')?;
+    if !revision.is_empty() && resolve_revision(revision).is_err() {
+        return None;
+    }
+    resolve_revision(spec).err()
+}
+
+fn read_source(source: &Source) -> Option<Vec<u8>> {
+    match source {
+        Source::Worktree(path) => {
+            // Only regular files are searched, a symlink on disk is not followed
+            let metadata = fs::symlink_metadata(path).ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            fs::read(path).ok()
+        }
+        Source::Blob(hash) => match read_object_from_store(hash).ok()? {
+            GitObjectType::Blob(blob) => Some(blob.blob_data),
+            _ => None,
+        },
+    }
+}
+
+fn search_file(matcher: &Matcher, (name, source): &(String, Source)) -> Found {
+    match read_source(source) {
+        Some(contents) => matcher.search(name, &contents),
+        None => matcher.finish(name, Found::default(), 0),
+    }
+}
+
+// Search the files on as many threads, keeping each file's output in the order of the files
+fn search_files(matcher: &Matcher, files: &[(String, Source)], threads: usize) -> Vec<Found> {
+    let search = |file: &(String, Source)| search_file(matcher, file);
+    let threads = threads.clamp(1, files.len().max(1));
+    if threads == 1 {
+        return files.iter().map(search).collect();
+    }
+    let chunk = files.len().div_ceil(threads);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = files
+            .chunks(chunk)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(search).collect::<Vec<Found>>()))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    })
+}
+
+fn grep_usage() -> ! {
+    eprintln!("usage: git grep [<options>] [-e] <pattern> [<rev>...] [[--] <path>...]");
+    std::process::exit(129);
+}
+
+// Search the working tree, the index or trees for lines matching patterns
+pub fn grep_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "grep").unwrap();
+    let args = &args[command_index + 1..];
+
+    let mut options = Options::default();
+    let mut syntax = Syntax::Basic;
+    let mut ignore_case = false;
+    let mut word = false;
+    let mut binary = Binary::Report;
+    let mut cached = false;
+    let mut threads = None;
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut positional: &[String] = &[];
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = |names: &[&str]| option_value(args, &mut index, names, || ());
+        let number = |value: String| -> usize {
+            value.parse().unwrap_or_else(|_| {
+                match arg.strip_prefix("--") {
+                    Some(long) => {
+                        eprintln!("error: option `{}' expects a numerical value", long.split('=').next().unwrap())
+                    }
+                    None => eprintln!("error: switch `{}' expects a numerical value", &arg[1..2]),
+                }
+                std::process::exit(129);
+            })
+        };
+        if let Some(pattern) = value(&["--regexp", "-e"]) {
+            tokens.push(Token::Pattern(pattern));
+        } else if let Some(file) = value(&["--file", "-f"]) {
+            let contents =
+                fs::read_to_string(&file).map_err(|error| anyhow::anyhow!("cannot open '{}': {}", file, error))?;
+            tokens.extend(contents.lines().map(|x| Token::Pattern(x.to_string())));
+        } else if let Some(lines) = value(&["--after-context", "-A"]) {
+            options.after = number(lines);
+        } else if let Some(lines) = value(&["--before-context", "-B"]) {
+            options.before = number(lines);
+        } else if let Some(lines) = value(&["--context", "-C"]) {
+            options.after = number(lines);
+            options.before = options.after;
+        } else if let Some(count) = value(&["--max-count", "-m"]) {
+            options.max_count = Some(number(count));
+        } else if let Some(count) = value(&["--threads"]) {
+            threads = Some(number(count));
+        } else if arg.len() > 1 && arg.starts_with('-') && arg[1..].bytes().all(|x| x.is_ascii_digit()) {
+            options.after = number(arg[1..].to_string());
+            options.before = options.after;
+        } else {
+            match arg {
+                "--and" => tokens.push(Token::And),
+                "--or" => {}
+                "--not" => tokens.push(Token::Not),
+                "(" => tokens.push(Token::Open),
+                ")" => tokens.push(Token::Close),
+                "--cached" => cached = true,
+                "--basic-regexp" => syntax = Syntax::Basic,
+                "--extended-regexp" => syntax = Syntax::Extended,
+                "--fixed-strings" => syntax = Syntax::Fixed,
+                "--perl-regexp" => syntax = Syntax::Perl,
+                "--ignore-case" => ignore_case = true,
+                "--word-regexp" => word = true,
+                "--invert-match" => options.invert = true,
+                "--line-number" => options.line_number = true,
+                "--column" => options.column = true,
+                "--files-with-matches" | "--name-only" => options.files_with_matches = true,
+                "--files-without-match" => options.files_without_match = true,
+                "--count" => options.count = true,
+                "--quiet" => options.quiet = true,
+                "--only-matching" => options.only_matching = true,
+                "--null" => options.null = true,
+                "--heading" => options.heading = true,
+                "--break" => options.file_break = true,
+                "--all-match" => options.all_match = true,
+                "--text" => binary = Binary::Text,
+                _ if arg.len() > 1 && arg.starts_with('-') && !arg.starts_with("--") => {
+                    for flag in arg[1..].chars() {
+                        match flag {
+                            'G' => syntax = Syntax::Basic,
+                            'E' => syntax = Syntax::Extended,
+                            'F' => syntax = Syntax::Fixed,
+                            'P' => syntax = Syntax::Perl,
+                            'i' | 'y' => ignore_case = true,
+                            'w' => word = true,
+                            'v' => options.invert = true,
+                            'n' => options.line_number = true,
+                            'h' => options.no_name = true,
+                            'H' => options.no_name = false,
+                            'l' => options.files_with_matches = true,
+                            'L' => options.files_without_match = true,
+                            'c' => options.count = true,
+                            'q' => options.quiet = true,
+                            'o' => options.only_matching = true,
+                            'z' => options.null = true,
+                            'a' => binary = Binary::Text,
+                            'I' => binary = Binary::Skip,
+                            _ => {
+                                eprintln!("error: unknown switch `{}'", flag);
+                                grep_usage();
+                            }
+                        }
+                    }
+                }
+                _ if arg.starts_with("--") && arg != "--" => {
+                    eprintln!("error: unknown option `{}'", &arg[2..]);
+                    grep_usage();
+                }
+                // Options end at "--" or the first argument that is not one
+                _ => {
+                    positional = &args[index..];
+                    break;
+                }
+            }
+        }
+        index += 1;
+    }
+
+    // Without -e or -f the first argument is the pattern, a "--" before it is skipped as it
+    // can't be separating revisions from paths yet
+    if tokens.is_empty() {
+        if positional.first().is_some_and(|x| x == "--") {
+            positional = &positional[1..];
+        }
+        match positional.split_first() {
+            Some((pattern, rest)) => {
+                tokens.push(Token::Pattern(pattern.clone()));
+                positional = rest;
+            }
+            None => anyhow::bail!("no pattern given"),
+        }
+    }
+    let mut position = 0;
+    let expr = compile_or(&tokens, &mut position)?.ok_or_else(|| anyhow::anyhow!("no pattern given"))?;
+    if let Some(token) = tokens.get(position) {
+        anyhow::bail!("incomplete pattern expression: {}", token_name(token));
+    }
+    let atoms = tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Pattern(pattern) => Some(compile_pattern(pattern, syntax, ignore_case, word)),
+            _ => None,
+        })
+        .collect::<anyhow::Result<Vec<Atom>>>()?;
+
+    // Revisions come first, then everything after "--" or from the first argument that is
+    // not a revision on is a path
+    let mut trees = Vec::new();
+    let mut pathspecs: Vec<String> = Vec::new();
+    let dashdash = positional.iter().position(|x| x == "--");
+    for arg in &positional[..dashdash.unwrap_or(positional.len())] {
+        match tree_files(arg) {
+            Ok(files) => {
+                if dashdash.is_none() && Path::new(arg).exists() {
+                    return Err(ambiguous_argument(arg, "both revision and filename"));
+                }
+                trees.push((arg, files));
+            }
+            Err(_) if dashdash.is_some() => anyhow::bail!("unable to resolve revision: {}", arg),
+            Err(_) => break,
+        }
+    }
+    let revision_count = trees.len();
+    match dashdash {
+        Some(position) => pathspecs.extend(positional[position + 1..].iter().cloned()),
+        None => {
+            for (index, arg) in positional[revision_count..].iter().enumerate() {
+                if arg.starts_with('-') {
+                    anyhow::bail!("option '{}' must come before non-option arguments", arg);
+                }
+                if !Path::new(arg).exists() && !has_glob(arg) {
+                    if index > 0 {
+                        anyhow::bail!(
+                            "{}: no such path in the working tree.\nUse 'git <command> -- <path>...' to specify paths that do not exist locally.",
+                            arg
+                        );
+                    }
+                    return Err(resolve_error(arg).unwrap_or_else(|| {
+                        ambiguous_argument(arg, "unknown revision or path not in the working tree.")
+                    }));
+                }
+                pathspecs.push(arg.clone());
+            }
+        }
+    }
+    let pathspecs: Vec<String> = pathspecs.iter().map(|x| x.trim_start_matches("./").to_string()).collect();
+    if cached && !trees.is_empty() {
+        anyhow::bail!("both --cached and trees are given");
+    }
+
+    // Names are quoted like other path output unless they are NUL terminated
+    let display = |name: &str| if options.null { name.to_string() } else { quote_path(name, false) };
+    let mut files: Vec<(String, Source)> = Vec::new();
+    if trees.is_empty() {
+        let mut previous: Option<String> = None;
+        for entry in read_index()? {
+            if entry.mode & 0o170000 != 0o100000 || !path_selected(&pathspecs, &entry.path) {
+                continue;
+            }
+            if cached {
+                // Conflicted paths have no single version staged to search
+                if entry.stage() == 0 {
+                    files.push((display(&entry.path), Source::Blob(entry.sha_hash)));
+                }
+            } else if previous.as_deref() != Some(entry.path.as_str()) {
+                files.push((display(&entry.path), Source::Worktree(entry.path.clone())));
+                previous = Some(entry.path);
+            }
+        }
+    } else {
+        for (revision, tree) in trees {
+            // A subtree is named after the tree-ish with a colon as separator, like a commit.
+            // Only the path is quoted, the revision is shown as given.
+            for (path, entry) in tree {
+                if entry.mode & 0o170000 == 0o100000 && path_selected(&pathspecs, &path) {
+                    files.push((format!("{}:{}", revision, display(&path)), Source::Blob(entry.sha_hash)));
+                }
+            }
+        }
+    }
+
+    let matcher = Matcher { options, binary, atoms, expr };
+    let threads = match threads {
+        Some(0) | None => std::thread::available_parallelism().map_or(1, |x| x.get()).min(8),
+        Some(threads) => threads,
+    };
+    // Quietly only the exit status is wanted, which the first match settles
+    if matcher.options.quiet {
+        if !files.iter().any(|file| search_file(&matcher, file).matched) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let mut matched = false;
+    let mut shown = false;
+    for found in search_files(&matcher, &files, threads) {
+        matched |= found.matched;
+        // The separator between files is not needed before the first file shown
+        let mut start = 0;
+        for separator in found.file_separators.iter().filter(|_| !shown) {
+            stdout.write_all(&found.output[start..separator.start])?;
+            start = separator.end;
+        }
+        stdout.write_all(&found.output[start..])?;
+        shown |= found.shown;
+    }
+    stdout.flush()?;
+    if !matched {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "one foo\ntwo\nthree foobar\nfour foo bar\nfive\nsix\nseven foo\n";
+
+    // Compile the words of a pattern expression, "--and", "--not" and parentheses standing
+    // for themselves, into a matcher
+    fn matcher(words: &[&str], word: bool, options: Options) -> anyhow::Result<Matcher> {
+        let tokens: Vec<Token> = words
+            .iter()
+            .map(|x| match *x {
+                "--and" => Token::And,
+                "--not" => Token::Not,
+                "(" => Token::Open,
+                ")" => Token::Close,
+                pattern => Token::Pattern(pattern.to_string()),
+            })
+            .collect();
+        let mut position = 0;
+        let expr = compile_or(&tokens, &mut position)?.ok_or_else(|| anyhow::anyhow!("no pattern given"))?;
+        let atoms = tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Pattern(pattern) => Some(compile_pattern(pattern, Syntax::Extended, false, word)),
+                _ => None,
+            })
+            .collect::<anyhow::Result<Vec<Atom>>>()?;
+        Ok(Matcher { options, binary: Binary::Report, atoms, expr })
+    }
+
+    fn search(words: &[&str], word: bool, options: Options, contents: &str) -> String {
+        String::from_utf8(matcher(words, word, options).unwrap().search("f", contents.as_bytes()).output).unwrap()
+    }
+
+    #[test]
+    fn expressions() {
+        let matched = search(&["foo", "--and", "--not", "bar"], false, Options::default(), FILE);
+        assert_eq!(matched, "f:one foo\nf:seven foo\n");
+        let matched = search(&["foo", "--and", "(", "bar", "seven", ")"], false, Options::default(), FILE);
+        assert_eq!(matched, "f:three foobar\nf:four foo bar\nf:seven foo\n");
+        assert_eq!(search(&["two", "five"], false, Options { invert: true, ..Default::default() }, "two\nsix\nfive\n"), "f:six\n");
+
+        let error = |words: &[&str]| matcher(words, false, Options::default()).err().unwrap().to_string();
+        assert_eq!(error(&["foo", "--and"]), "--and not followed by pattern expression");
+        assert_eq!(error(&["--not"]), "--not not followed by pattern expression");
+        assert_eq!(error(&["(", "foo"]), "unmatched parenthesis");
+    }
+
+    #[test]
+    fn words_and_columns() {
+        let options = Options { column: true, ..Default::default() };
+        assert_eq!(search(&["foo"], true, options, FILE), "f:5:one foo\nf:6:four foo bar\nf:7:seven foo\n");
+        let options = Options { column: true, only_matching: true, ..Default::default() };
+        let matched = search(&["fo*", "bar"], false, options, FILE);
+        assert_eq!(matched, "f:5:foo\nf:7:foo\nf:16:bar\nf:1:fo\nf:3:foo\nf:9:bar\nf:1:f\nf:7:foo\n");
+    }
+
+    #[test]
+    fn context() {
+        // The separator before a file's first line is noted, to be dropped for the first file
+        let options = Options { line_number: true, before: 1, after: 1, ..Default::default() };
+        let found = matcher(&["foo"], false, options).unwrap().search("f", FILE.as_bytes());
+        assert_eq!(found.file_separators.first(), Some(&(0..3)));
+        let matched = String::from_utf8(found.output).unwrap();
+        assert_eq!(matched, "--\nf:1:one foo\nf-2-two\nf:3:three foobar\nf:4:four foo bar\nf-5-five\nf-6-six\nf:7:seven foo\n");
+        // Context is cut into groups, and stops after the last of as many matches as allowed
+        let options = Options { after: 1, ..Default::default() };
+        assert_eq!(search(&["five"], false, options, "1\n2\nfive\n4\n5\nfive\n"), "--\nf:five\nf-4\n--\nf:five\n");
+        let options = Options { after: 1, max_count: Some(2), ..Default::default() };
+        assert_eq!(search(&["foo"], false, options, FILE), "--\nf:one foo\nf-two\nf:three foobar\nf-four foo bar\n");
+    }
+
+    #[test]
+    fn summaries() {
+        let found = |words: &[&str], options: Options, contents: &str| {
+            let found = matcher(words, false, options).unwrap().search("f", contents.as_bytes());
+            (String::from_utf8(found.output).unwrap(), found.matched)
+        };
+        assert_eq!(found(&["foo"], Options { count: true, ..Default::default() }, FILE), ("f:4\n".to_string(), true));
+        assert_eq!(found(&["two"], Options { files_with_matches: true, ..Default::default() }, FILE), ("f\n".to_string(), true));
+        assert_eq!(found(&["two"], Options { files_without_match: true, ..Default::default() }, FILE), (String::new(), false));
+        assert_eq!(found(&["eight"], Options { files_without_match: true, null: true, ..Default::default() }, FILE), ("f\0".to_string(), true));
+
+        // --all-match needs every alternative to match some line
+        let all_match = || Options { all_match: true, files_with_matches: true, ..Default::default() };
+        assert_eq!(found(&["one", "five"], all_match(), FILE), ("f\n".to_string(), true));
+        assert_eq!(found(&["one", "eight"], all_match(), FILE), (String::new(), false));
+
+        // Files with a NUL are reported as a whole unless read as text
+        assert_eq!(found(&["foo"], Options::default(), "a\0b foo\n"), ("Binary file f matches\n".to_string(), true));
+        let mut text = matcher(&["foo"], false, Options::default()).unwrap();
+        text.binary = Binary::Text;
+        assert_eq!(text.search("f", b"a\0b foo\n").output, b"f:a\0b foo\n");
+        text.binary = Binary::Skip;
+        assert!(!text.search("f", b"a\0b foo\n").matched);
+    }
+
+    #[test]
+    fn pathspecs() {
+        let specs = |specs: &[&str]| specs.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        assert!(path_selected(&[], "src/main.rs"));
+        assert!(path_selected(&specs(&["src"]), "src/main.rs"));
+        assert!(path_selected(&specs(&["*.rs"]), "src/main.rs"));
+        assert!(!path_selected(&specs(&["sr"]), "src/main.rs"));
+        assert!(!path_selected(&specs(&["*.c", "doc"]), "src/main.rs"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::Config;
+use chrono::{Local, TimeZone};
+use std::env;
+
+// Role picks which of the GIT_AUTHOR_* or GIT_COMMITTER_* variables describe the person
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Role {
+    fn variable(&self, field: &str) -> String {
+        match self {
+            Role::Author => format!("GIT_AUTHOR_{}", field),
+            Role::Committer => format!("GIT_COMMITTER_{}", field),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Role::Author => "Author",
+            Role::Committer => "Committer",
+        }
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
+// The "+hhmm" offset of local time at a moment
+pub fn local_offset(time: i64) -> String {
+    let seconds = match Local.timestamp_opt(time, 0).single() {
+        Some(date) => date.offset().local_minus_utc(),
+        None => 0,
+    };
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+// Parse a date given in GIT_AUTHOR_DATE or GIT_COMMITTER_DATE: git's internal
+// "<seconds> <offset>" form, optionally with a leading "@", or any date parse_date accepts.
+// A zone in the text is kept; dates without one are in the local zone.
+fn parse_ident_date(text: &str) -> Option<(i64, String)> {
+    let text = text.trim();
+    if let Some((seconds, offset)) = text.split_once(' ') {
+        let seconds = seconds.strip_prefix('@').unwrap_or(seconds);
+        let valid_offset = offset.len() == 5
+            && (offset.starts_with('+') || offset.starts_with('-'))
+            && offset[1..].bytes().all(|x| x.is_ascii_digit());
+        if let (Ok(seconds), true) = (seconds.parse::<i64>(), valid_offset) {
+            return Some((seconds, offset.to_string()));
+        }
+    }
+    let (time, offset) = crate::date::parse_date_zone(text)?;
+    Some((time, offset.unwrap_or_else(|| local_offset(time))))
+}
+
+// The name and email configured for an author or committer, from the environment first and
+// the user.name and user.email settings after that
+fn identity(config: &Config, role: Role) -> (Option<String>, Option<String>) {
+    let name = non_empty(env::var(role.variable("NAME")).ok())
+        .or_else(|| non_empty(config.get("user.name").map(|x| x.to_string())))
+        .or_else(|| non_empty(env::var("USER").ok()));
+    let email = non_empty(env::var(role.variable("EMAIL")).ok())
+        .or_else(|| non_empty(config.get("user.email").map(|x| x.to_string())))
+        .or_else(|| non_empty(env::var("EMAIL").ok()));
+    (name, email)
+}
+
+// The "<seconds> <offset>" an author or committer acts at, from GIT_AUTHOR_DATE or
+// GIT_COMMITTER_DATE when set and the current time otherwise
+fn timestamp(role: Role) -> anyhow::Result<String> {
+    let date = match env::var(role.variable("DATE")) {
+        Ok(text) => Some(parse_ident_date(&text).ok_or_else(|| anyhow::anyhow!("invalid date format: {}", text))?),
+        Err(_) => None,
+    };
+    let (time, offset) = date.unwrap_or_else(|| {
+        let time = Local::now().timestamp();
+        (time, local_offset(time))
+    });
+    Ok(format!("{} {}", time, offset))
+}
+
+// The "Name <email> <seconds> <offset>" line recorded for an author or committer, taken
+// from the environment first and the user.name and user.email settings after that
+pub fn signature(config: &Config, role: Role) -> anyhow::Result<String> {
+    let (Some(name), Some(email)) = identity(config, role) else {
+        eprintln!(
+            "{} identity unknown\n\n*** Please tell me who you are.\n\nRun\n\n  git config --global user.email \"you@example.com\"\n  git config --global user.name \"Your Name\"\n\nto set your account's default identity.\nOmit --global to set the identity only in this repository.\n",
+            role.title()
+        );
+        anyhow::bail!("unable to auto-detect email address");
+    };
+    Ok(format!("{} <{}> {}", name.trim(), email.trim(), timestamp(role)?))
+}
+
+// The committer line a reflog entry records. Unlike commits, ref updates go ahead without a
+// configured identity, falling back to the login name and host.
+pub fn reflog_signature(config: &Config) -> anyhow::Result<String> {
+    let (name, email) = identity(config, Role::Committer);
+    let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    let name = name.unwrap_or_else(|| user.clone());
+    let email = email.unwrap_or_else(|| {
+        let host = std::fs::read_to_string("/etc/hostname").unwrap_or_default();
+        format!("{}@{}", user, host.trim())
+    });
+    Ok(format!("{} <{}> {}", name.trim(), email.trim(), timestamp(Role::Committer)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ident_dates() {
+        assert_eq!(parse_ident_date("1580547600 +0100"), Some((1580547600, "+0100".to_string())));
+        assert_eq!(parse_ident_date("@1580547600 -0500"), Some((1580547600, "-0500".to_string())));
+        assert_eq!(parse_ident_date("2020-02-01 10:00:00 +0100"), Some((1580547600, "+0100".to_string())));
+        assert_eq!(parse_ident_date("2020-02-01 00:00 +0000"), Some((1580515200, "+0000".to_string())));
+        assert_eq!(parse_ident_date("Sat, 1 Feb 2020 10:00:00 -0230"), Some((1580560200, "-0230".to_string())));
+        let (time, offset) = parse_ident_date("@1580547600").unwrap();
+        assert_eq!((time, offset), (1580547600, local_offset(1580547600)));
+        assert_eq!(parse_ident_date("not a date"), None);
+    }
+}
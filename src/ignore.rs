@@ -0,0 +1,489 @@
+use crate::config::Config;
+use crate::index::read_index;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::BufRead;
+
+// Pattern is one line of an ignore file, with where it came from for check-ignore -v
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pattern: String,
+    negated: bool,
+    directory_only: bool,
+    anchored: bool,
+    base: String,
+    source: String,
+    line_number: usize,
+}
+
+impl Pattern {
+    // Parse a line of an ignore file, skipping blank lines and comments
+    fn parse(line: &str, base: &str, source: &str, line_number: usize) -> Option<Pattern> {
+        let mut line = line.trim_end_matches(['\n', '\r']).to_string();
+        // Trailing spaces are dropped unless escaped with a backslash
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line.pop();
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let negated = line.starts_with('!');
+        let mut pattern = if negated { line[1..].to_string() } else { line };
+        let directory_only = pattern.ends_with('/');
+        if directory_only {
+            pattern.pop();
+        }
+        if pattern.is_empty() {
+            return None;
+        }
+        let anchored = pattern.contains('/');
+        Some(Pattern {
+            pattern,
+            negated,
+            directory_only,
+            anchored,
+            base: base.to_string(),
+            source: source.to_string(),
+            line_number,
+        })
+    }
+
+    // Patterns without a slash match the file name at any depth below the ignore file;
+    // patterns with one match the whole path relative to it
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(self.base.as_str()).and_then(|rest| rest.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+        if self.anchored {
+            let pattern = self.pattern.strip_prefix('/').unwrap_or(&self.pattern);
+            wildmatch(pattern.as_bytes(), relative.as_bytes())
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            wildmatch(self.pattern.as_bytes(), name.as_bytes())
+        }
+    }
+
+    // The "<source>:<line>:<pattern>" description check-ignore -v prints
+    pub fn describe(&self) -> String {
+        format!(
+            "{}:{}:{}{}{}",
+            self.source,
+            self.line_number,
+            if self.negated { "!" } else { "" },
+            self.pattern,
+            if self.directory_only { "/" } else { "" }
+        )
+    }
+}
+
+// Match a character class such as "[a-z]" or "[![:digit:]]", returning whether it matched and
+// the rest of the pattern after the closing bracket
+fn match_class(pattern: &[u8], x: u8) -> Option<(bool, &[u8])> {
+    let mut index = 0;
+    let negated = matches!(pattern.first(), Some(b'!') | Some(b'^'));
+    if negated {
+        index += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let c = *pattern.get(index)?;
+        if c == b']' && !first {
+            index += 1;
+            break;
+        }
+        first = false;
+        if c == b'[' && pattern.get(index + 1) == Some(&b':') {
+            let end = pattern[index + 2..].windows(2).position(|w| w == b":]")? + index + 2;
+            let matches_class = match &pattern[index + 2..end] {
+                b"alnum" => x.is_ascii_alphanumeric(),
+                b"alpha" => x.is_ascii_alphabetic(),
+                b"blank" => x == b' ' || x == b'\t',
+                b"cntrl" => x.is_ascii_control(),
+                b"digit" => x.is_ascii_digit(),
+                b"graph" => x.is_ascii_graphic(),
+                b"lower" => x.is_ascii_lowercase(),
+                b"print" => x.is_ascii_graphic() || x == b' ',
+                b"punct" => x.is_ascii_punctuation(),
+                b"space" => x.is_ascii_whitespace(),
+                b"upper" => x.is_ascii_uppercase(),
+                b"xdigit" => x.is_ascii_hexdigit(),
+                _ => return None,
+            };
+            matched |= matches_class;
+            index = end + 2;
+            continue;
+        }
+        let low = if c == b'\\' {
+            index += 1;
+            *pattern.get(index)?
+        } else {
+            c
+        };
+        index += 1;
+        if pattern.get(index) == Some(&b'-') && pattern.get(index + 1).map(|&x| x != b']').unwrap_or(false) {
+            let mut high = pattern[index + 1];
+            index += 2;
+            if high == b'\\' {
+                high = *pattern.get(index)?;
+                index += 1;
+            }
+            matched |= low <= x && x <= high;
+        } else {
+            matched |= low == x;
+        }
+    }
+    Some((matched != negated, &pattern[index..]))
+}
+
+// Match a path against a glob the way git's wildmatch does with WM_PATHNAME: "*", "?" and
+// classes never match "/", while "**" between slashes matches any number of directories
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    wildmatch_from(pattern, text, true)
+}
+
+fn wildmatch_from(pattern: &[u8], text: &[u8], at_segment_start: bool) -> bool {
+    let Some(&c) = pattern.first() else {
+        return text.is_empty();
+    };
+    match c {
+        b'*' if pattern.get(1) == Some(&b'*') => {
+            let stars = pattern.iter().take_while(|&&x| x == b'*').count();
+            let rest = &pattern[stars..];
+            if at_segment_start && rest.is_empty() {
+                return true;
+            }
+            if at_segment_start && rest.first() == Some(&b'/') {
+                // "**/" matches zero or more leading directories
+                let rest = &rest[1..];
+                if wildmatch_from(rest, text, true) {
+                    return true;
+                }
+                return text
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &x)| x == b'/')
+                    .any(|(position, _)| wildmatch_from(rest, &text[position + 1..], true));
+            }
+            match_star(rest, text)
+        }
+        b'*' => match_star(&pattern[1..], text),
+        b'?' => match text.first() {
+            Some(&x) if x != b'/' => wildmatch_from(&pattern[1..], &text[1..], false),
+            _ => false,
+        },
+        b'[' => match text.first() {
+            Some(&x) if x != b'/' => match match_class(&pattern[1..], x) {
+                Some((true, rest)) => wildmatch_from(rest, &text[1..], false),
+                _ => false,
+            },
+            _ => false,
+        },
+        _ => {
+            let (literal, rest) = if c == b'\\' && pattern.len() > 1 { (pattern[1], &pattern[2..]) } else { (c, &pattern[1..]) };
+            match text.first() {
+                Some(&x) if x == literal => wildmatch_from(rest, &text[1..], x == b'/'),
+                _ => false,
+            }
+        }
+    }
+}
+
+// A single "*" matches any run of characters within one path segment
+fn match_star(rest: &[u8], text: &[u8]) -> bool {
+    let rest_start = rest.iter().position(|&x| x != b'*').unwrap_or(rest.len());
+    let rest = &rest[rest_start..];
+    for position in 0..=text.len() {
+        if wildmatch_from(rest, &text[position..], false) {
+            return true;
+        }
+        if position < text.len() && text[position] == b'/' {
+            break;
+        }
+    }
+    false
+}
+
+// Match text against a glob without path semantics, where "*", "?" and classes also match "/",
+// as ref listings like "git branch --list" do
+pub fn fnmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let Some(&c) = pattern.first() else {
+        return text.is_empty();
+    };
+    match c {
+        b'*' => {
+            let rest = &pattern[pattern.iter().take_while(|&&x| x == b'*').count()..];
+            (0..=text.len()).any(|position| fnmatch(rest, &text[position..]))
+        }
+        b'?' => !text.is_empty() && fnmatch(&pattern[1..], &text[1..]),
+        b'[' => match text.first().and_then(|&x| match_class(&pattern[1..], x)) {
+            Some((true, rest)) => fnmatch(rest, &text[1..]),
+            _ => false,
+        },
+        _ => {
+            let (literal, rest) = if c == b'\\' && pattern.len() > 1 { (pattern[1], &pattern[2..]) } else { (c, &pattern[1..]) };
+            text.first() == Some(&literal) && fnmatch(rest, &text[1..])
+        }
+    }
+}
+
+fn read_patterns(path: &str, base: &str, source: &str) -> Vec<Pattern> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| Pattern::parse(line, base, source, index + 1))
+        .collect()
+}
+
+// Ignores answers whether paths are excluded. The .gitignore of each directory is read the
+// first time a path below it is checked; closer files take precedence over the repository's
+// .git/info/exclude, which takes precedence over the user's core.excludesFile.
+pub struct Ignores {
+    directories: HashMap<String, Vec<Pattern>>,
+    exclude: Vec<Pattern>,
+    global: Vec<Pattern>,
+}
+
+impl Ignores {
+    pub fn new(config: &Config) -> Ignores {
+        let home = std::env::var("HOME").unwrap_or_default();
+        let global_path = match config.get("core.excludesFile") {
+            Some(path) => match path.strip_prefix("~/") {
+                Some(rest) => format!("{}/{}", home, rest),
+                None => path.to_string(),
+            },
+            None => {
+                let xdg = std::env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| format!("{}/.config", home));
+                format!("{}/git/ignore", xdg)
+            }
+        };
+        Ignores {
+            directories: HashMap::new(),
+            exclude: read_patterns(".git/info/exclude", "", ".git/info/exclude"),
+            global: read_patterns(&global_path, "", &global_path),
+        }
+    }
+
+    // The last pattern matching a path on its own, ignoring its parent directories
+    fn last_match(&mut self, path: &str, is_dir: bool) -> Option<Pattern> {
+        let mut directories = Vec::new();
+        let mut directory = path;
+        while let Some((parent, _)) = directory.rsplit_once('/') {
+            directories.push(parent.to_string());
+            directory = parent;
+        }
+        directories.push(String::new());
+
+        for directory in directories {
+            let patterns = self.directories.entry(directory.clone()).or_insert_with(|| {
+                let source = if directory.is_empty() { ".gitignore".to_string() } else { format!("{}/.gitignore", directory) };
+                read_patterns(&source, &directory, &source)
+            });
+            if let Some(pattern) = patterns.iter().rev().find(|pattern| pattern.matches(path, is_dir)) {
+                return Some(pattern.clone());
+            }
+        }
+        [&self.exclude, &self.global]
+            .into_iter()
+            .find_map(|patterns| patterns.iter().rev().find(|pattern| pattern.matches(path, is_dir)))
+            .cloned()
+    }
+
+    // The pattern deciding whether a path is ignored. A path inside an ignored directory
+    // is ignored by that directory's pattern and cannot be re-included.
+    pub fn matching_pattern(&mut self, path: &str, is_dir: bool) -> Option<Pattern> {
+        for (position, _) in path.match_indices('/') {
+            if let Some(pattern) = self.last_match(&path[..position], true).filter(|pattern| !pattern.negated) {
+                return Some(pattern);
+            }
+        }
+        self.last_match(path, is_dir)
+    }
+
+    pub fn is_ignored(&mut self, path: &str, is_dir: bool) -> bool {
+        self.matching_pattern(path, is_dir).map(|pattern| !pattern.negated).unwrap_or(false)
+    }
+}
+
+fn is_directory(path: &str) -> bool {
+    fs::symlink_metadata(path).map(|metadata| metadata.is_dir()).unwrap_or(false)
+}
+
+pub fn check_ignore_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "check-ignore").unwrap();
+    let mut verbose = false;
+    let mut quiet = false;
+    let mut non_matching = false;
+    let mut from_stdin = false;
+    let mut use_index = true;
+    let mut paths = Vec::new();
+    for arg in &args[command_index + 1..] {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            "-q" | "--quiet" => quiet = true,
+            "-n" | "--non-matching" => non_matching = true,
+            "--stdin" => from_stdin = true,
+            "--no-index" => use_index = false,
+            "--" => {}
+            _ => paths.push(arg.clone()),
+        }
+    }
+    if quiet && verbose {
+        anyhow::bail!("cannot have both --quiet and --verbose");
+    }
+    if non_matching && !verbose {
+        anyhow::bail!("--non-matching is only valid with --verbose");
+    }
+    if from_stdin {
+        if !paths.is_empty() {
+            anyhow::bail!("cannot specify pathnames with --stdin");
+        }
+        for line in std::io::stdin().lock().lines() {
+            paths.push(line?);
+        }
+    } else if paths.is_empty() {
+        anyhow::bail!("no path specified");
+    }
+
+    // Tracked files are never subject to ignore rules
+    let tracked: HashSet<String> = if use_index {
+        read_index()?.into_iter().map(|entry| entry.path).collect()
+    } else {
+        HashSet::new()
+    };
+    let mut ignores = Ignores::new(&crate::config::read_config());
+    let mut ignored_count = 0;
+    for path in &paths {
+        let normalized = path.trim_start_matches("./").trim_end_matches('/');
+        let pattern = if tracked.contains(normalized) {
+            None
+        } else {
+            let is_dir = path.ends_with('/') || is_directory(normalized);
+            ignores.matching_pattern(normalized, is_dir).filter(|pattern| verbose || !pattern.negated)
+        };
+        if pattern.is_some() {
+            ignored_count += 1;
+        }
+        if quiet {
+            continue;
+        }
+        match (&pattern, verbose) {
+            (Some(pattern), true) => println!("{}\t{}", pattern.describe(), path),
+            (Some(_), false) => println!("{}", path),
+            (None, true) if non_matching => println!("::\t{}", path),
+            (None, _) => {}
+        }
+    }
+    if ignored_count == 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(line: &str) -> Pattern {
+        Pattern::parse(line, "", ".gitignore", 1).unwrap()
+    }
+
+    #[test]
+    fn blank_lines_and_comments() {
+        for line in ["", "   ", "# comment", "\n", "!", "/"] {
+            assert!(Pattern::parse(line, "", ".gitignore", 1).is_none(), "{:?}", line);
+        }
+        assert_eq!(pattern("\\#file").describe(), ".gitignore:1:\\#file");
+        assert!(pattern("\\#file").matches("#file", false));
+    }
+
+    #[test]
+    fn trailing_spaces() {
+        assert_eq!(pattern("file  \r\n").pattern, "file");
+        assert_eq!(pattern("file\\ ").pattern, "file\\ ");
+        assert!(pattern("file\\ ").matches("file ", false));
+    }
+
+    #[test]
+    fn negation_and_directories() {
+        let negated = pattern("!*.log");
+        assert!(negated.negated && negated.matches("a.log", false));
+        assert!(!pattern("\\!a").negated);
+        assert!(pattern("\\!a").matches("!a", false));
+        let directory = pattern("build/");
+        assert!(directory.directory_only && !directory.anchored);
+        assert!(directory.matches("src/build", true));
+        assert!(!directory.matches("src/build", false));
+        assert_eq!(directory.describe(), ".gitignore:1:build/");
+        assert_eq!(negated.describe(), ".gitignore:1:!*.log");
+    }
+
+    #[test]
+    fn anchoring() {
+        // Without a slash the name matches at any depth, with one the whole path must match
+        assert!(pattern("*.o").matches("a/b/c.o", false));
+        assert!(pattern("/top").matches("top", false));
+        assert!(!pattern("/top").matches("a/top", false));
+        assert!(pattern("doc/*.txt").matches("doc/a.txt", false));
+        assert!(!pattern("doc/*.txt").matches("doc/sub/a.txt", false));
+        assert!(!pattern("doc/*.txt").matches("x/doc/a.txt", false));
+    }
+
+    #[test]
+    fn nested_ignore_files() {
+        let nested = Pattern::parse("/out", "sub", "sub/.gitignore", 3).unwrap();
+        assert!(nested.matches("sub/out", false));
+        assert!(!nested.matches("out", false));
+        assert!(!nested.matches("subway/out", false));
+        assert_eq!(nested.describe(), "sub/.gitignore:3:/out");
+    }
+
+    #[test]
+    fn wildmatch_paths() {
+        assert!(wildmatch(b"a/**/b", b"a/b"));
+        assert!(wildmatch(b"a/**/b", b"a/x/y/b"));
+        assert!(wildmatch(b"**/foo", b"foo"));
+        assert!(wildmatch(b"**/foo", b"x/y/foo"));
+        assert!(wildmatch(b"abc/**", b"abc/x/y"));
+        assert!(!wildmatch(b"abc/**", b"abc"));
+        assert!(!wildmatch(b"a*b", b"a/b"));
+        assert!(!wildmatch(b"a?b", b"a/b"));
+        assert!(!wildmatch(b"a[/]b", b"a/b"));
+        // Two stars not forming a whole segment act like one
+        assert!(wildmatch(b"a**b", b"axxb"));
+        assert!(!wildmatch(b"a**b", b"a/b"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(wildmatch(b"[a-c]", b"b"));
+        assert!(!wildmatch(b"[!a-c]", b"b"));
+        assert!(wildmatch(b"[^a-c]", b"d"));
+        assert!(wildmatch(b"[]]", b"]"));
+        assert!(wildmatch(b"[a-]", b"-"));
+        assert!(wildmatch(b"[[:digit:]x]", b"7"));
+        assert!(wildmatch(b"[[:upper:]]", b"Q"));
+        assert!(!wildmatch(b"[[:bogus:]]", b"a"));
+        assert!(!wildmatch(b"[abc", b"a"));
+        assert!(wildmatch(b"[\\]]", b"]"));
+    }
+
+    #[test]
+    fn fnmatch_crosses_slashes() {
+        assert!(fnmatch(b"refs/*", b"refs/heads/main"));
+        assert!(fnmatch(b"a?b", b"a/b"));
+        assert!(fnmatch(b"v[0-9]*", b"v1.2"));
+        assert!(!fnmatch(b"v[0-9]*", b"version"));
+        assert!(fnmatch(b"\\*", b"*"));
+        assert!(!fnmatch(b"\\*", b"x"));
+    }
+}
@@ -0,0 +1,369 @@
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+// IndexEntry is a single staged path from .git/index together with its cached stat data
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    pub ctime_seconds: u32,
+    pub ctime_nanoseconds: u32,
+    pub mtime_seconds: u32,
+    pub mtime_nanoseconds: u32,
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha_hash: String,
+    pub flags: u16,
+    pub path: String,
+}
+
+impl IndexEntry {
+    // Build an entry for a path from its object id and the stat data of the file on disk
+    pub fn from_metadata(path: &str, mode: u32, sha_hash: &str, metadata: &fs::Metadata) -> IndexEntry {
+        IndexEntry {
+            ctime_seconds: metadata.ctime() as u32,
+            ctime_nanoseconds: metadata.ctime_nsec() as u32,
+            mtime_seconds: metadata.mtime() as u32,
+            mtime_nanoseconds: metadata.mtime_nsec() as u32,
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            sha_hash: sha_hash.to_string(),
+            flags: 0,
+            path: path.to_string(),
+        }
+    }
+
+    // Build an entry with no stat data, for paths that are staged but not checked out
+    pub fn without_stat(path: &str, mode: u32, sha_hash: &str) -> IndexEntry {
+        IndexEntry {
+            ctime_seconds: 0,
+            ctime_nanoseconds: 0,
+            mtime_seconds: 0,
+            mtime_nanoseconds: 0,
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            sha_hash: sha_hash.to_string(),
+            flags: 0,
+            path: path.to_string(),
+        }
+    }
+
+    // Merge stage of the entry, zero unless the path is conflicted
+    pub fn stage(&self) -> u16 {
+        (self.flags >> 12) & 3
+    }
+
+    // Check whether the cached stat data still describes the file on disk
+    pub fn stat_matches(&self, metadata: &fs::Metadata) -> bool {
+        self.mtime_seconds == metadata.mtime() as u32
+            && self.mtime_nanoseconds == metadata.mtime_nsec() as u32
+            && self.ctime_seconds == metadata.ctime() as u32
+            && self.ctime_nanoseconds == metadata.ctime_nsec() as u32
+            && self.ino == metadata.ino() as u32
+            && self.size == metadata.size() as u32
+    }
+}
+
+fn read_u32(data: &[u8], index: usize) -> anyhow::Result<u32> {
+    let bytes = data
+        .get(index..index + 4)
+        .ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u16(data: &[u8], index: usize) -> anyhow::Result<u16> {
+    let bytes = data
+        .get(index..index + 2)
+        .ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Paths are handled as strings, so one that is not UTF-8 is refused rather than read as a
+// different name
+fn index_path(path: Vec<u8>) -> anyhow::Result<String> {
+    String::from_utf8(path).map_err(|error| anyhow::anyhow!("index has a path that is not valid UTF-8: {}", error.as_bytes().escape_ascii()))
+}
+
+// Read the entries of .git/index, treating a missing index as empty
+pub fn read_index() -> anyhow::Result<Vec<IndexEntry>> {
+    match fs::read(".git/index") {
+        Ok(data) => parse_index(&data),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+// Parse the entries of an index file. Versions 2 to 4 are understood; extensions are skipped.
+fn parse_index(data: &[u8]) -> anyhow::Result<Vec<IndexEntry>> {
+    if !data.starts_with(b"DIRC") {
+        anyhow::bail!("index file has a bad signature");
+    }
+    let version = read_u32(data, 4)?;
+    if !(2..=4).contains(&version) {
+        anyhow::bail!("index file has unsupported version {}", version);
+    }
+    let count = read_u32(data, 8)? as usize;
+
+    let mut entries: Vec<IndexEntry> = Vec::with_capacity(count);
+    let mut index = 12;
+    for _ in 0..count {
+        let start = index;
+        let mut fields = [0u32; 10];
+        for (position, field) in fields.iter_mut().enumerate() {
+            *field = read_u32(data, index + position * 4)?;
+        }
+        index += 40;
+        let sha_hash = hex::encode(
+            data.get(index..index + 20)
+                .ok_or_else(|| anyhow::anyhow!("index file is truncated"))?,
+        );
+        index += 20;
+        let flags = read_u16(data, index)?;
+        index += 2;
+        if version >= 3 && flags & 0x4000 != 0 {
+            index += 2; // Extended flags are not used
+        }
+
+        let path = if version == 4 {
+            // Paths are stored as the number of bytes to drop from the previous path plus a new suffix
+            let mut byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+            index += 1;
+            let mut strip = (byte & 0x7f) as usize;
+            while byte & 0x80 != 0 {
+                byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+                index += 1;
+                strip = ((strip + 1) << 7) | (byte & 0x7f) as usize;
+            }
+            let previous = entries.last().map(|entry| entry.path.as_bytes()).unwrap_or(b"");
+            let keep = previous.len().saturating_sub(strip);
+            let end = index
+                + data
+                    .get(index..)
+                    .unwrap_or_default()
+                    .iter()
+                    .position(|&x| x == 0)
+                    .ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+            let mut path = previous[..keep].to_vec();
+            path.extend_from_slice(&data[index..end]);
+            index = end + 1;
+            index_path(path)?
+        } else {
+            let end = index
+                + data
+                    .get(index..)
+                    .unwrap_or_default()
+                    .iter()
+                    .position(|&x| x == 0)
+                    .ok_or_else(|| anyhow::anyhow!("index file is truncated"))?;
+            let path = index_path(data[index..end].to_vec())?;
+            // Entries are padded with NULs to a multiple of eight bytes
+            index = start + (end - start + 8) / 8 * 8;
+            path
+        };
+
+        entries.push(IndexEntry {
+            ctime_seconds: fields[0],
+            ctime_nanoseconds: fields[1],
+            mtime_seconds: fields[2],
+            mtime_nanoseconds: fields[3],
+            dev: fields[4],
+            ino: fields[5],
+            mode: fields[6],
+            uid: fields[7],
+            gid: fields[8],
+            size: fields[9],
+            sha_hash,
+            flags: flags & 0xb000, // Keep assume-valid and the stage, the name length is recomputed on write
+            path,
+        });
+    }
+    Ok(entries)
+}
+
+// Write entries to .git/index as a version 2 index, sorted by path and stage
+pub fn write_index(entries: &[IndexEntry]) -> anyhow::Result<()> {
+    let mut entries = entries.to_vec();
+    entries.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()).then(a.stage().cmp(&b.stage())));
+
+    let mut data = Vec::new();
+    data.extend_from_slice(b"DIRC");
+    data.extend_from_slice(&2u32.to_be_bytes());
+    data.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in &entries {
+        let start = data.len();
+        for field in [
+            entry.ctime_seconds,
+            entry.ctime_nanoseconds,
+            entry.mtime_seconds,
+            entry.mtime_nanoseconds,
+            entry.dev,
+            entry.ino,
+            entry.mode,
+            entry.uid,
+            entry.gid,
+            entry.size,
+        ] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.extend_from_slice(&hex::decode(&entry.sha_hash)?);
+        let name_length = entry.path.len().min(0xfff) as u16;
+        data.extend_from_slice(&((entry.flags & 0xb000) | name_length).to_be_bytes());
+        data.extend_from_slice(entry.path.as_bytes());
+        let padded_length = (data.len() - start + 8) / 8 * 8;
+        data.resize(start + padded_length, 0);
+    }
+    let checksum = Sha1::digest(&data);
+    data.extend_from_slice(&checksum);
+
+    // Write through a lock file so a failed write never leaves a truncated index behind
+    fs::write(".git/index.lock", &data)?;
+    fs::rename(".git/index.lock", ".git/index")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fixed part of an entry: stat data with the given size, an id of repeated bytes and the flags
+    fn fixed(size: u32, hash: u8, flags: u16) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for field in [1, 2, 3, 4, 5, 6, 0o100644, 7, 8, size] {
+            raw.extend_from_slice(&u32::to_be_bytes(field));
+        }
+        raw.extend_from_slice(&[hash; 20]);
+        raw.extend_from_slice(&flags.to_be_bytes());
+        raw
+    }
+
+    fn header(version: u32, count: u32) -> Vec<u8> {
+        let mut raw = b"DIRC".to_vec();
+        raw.extend_from_slice(&version.to_be_bytes());
+        raw.extend_from_slice(&count.to_be_bytes());
+        raw
+    }
+
+    // A version 2 or 3 entry, NUL padded to a multiple of eight bytes
+    fn padded(path: &str, hash: u8, flags: u16, extended: bool) -> Vec<u8> {
+        let mut raw = fixed(path.len() as u32, hash, flags | path.len() as u16);
+        if extended {
+            raw.extend_from_slice(&0x2000u16.to_be_bytes());
+        }
+        raw.extend_from_slice(path.as_bytes());
+        let length = (raw.len() + 8) / 8 * 8;
+        raw.resize(length, 0);
+        raw
+    }
+
+    #[test]
+    fn version_2() {
+        let mut raw = header(2, 2);
+        raw.extend(padded("a", 1, 0, false));
+        raw.extend(padded("dir/file.txt", 2, 0x2000, false));
+        raw.extend_from_slice(b"TREE\0\0\0\0");
+        let entries = parse_index(&raw).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "a");
+        assert_eq!(entries[0].sha_hash, "01".repeat(20));
+        assert_eq!((entries[0].mode, entries[0].size, entries[0].mtime_seconds), (0o100644, 1, 3));
+        assert_eq!(entries[1].path, "dir/file.txt");
+        assert_eq!(entries[1].stage(), 2);
+        // The name length is not kept, it is recomputed on write
+        assert_eq!(entries[1].flags, 0x2000);
+    }
+
+    #[test]
+    fn version_3_extended_flags() {
+        let mut raw = header(3, 2);
+        raw.extend(padded("a", 1, 0x4000, true));
+        raw.extend(padded("b", 2, 0, false));
+        let entries = parse_index(&raw).unwrap();
+        assert_eq!(entries.iter().map(|x| x.path.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(entries[1].sha_hash, "02".repeat(20));
+    }
+
+    #[test]
+    fn version_4_prefix_compression() {
+        let mut raw = header(4, 3);
+        for (strip, suffix, hash) in [(&[0u8][..], "dir/a", 1), (&[1], "b", 2), (&[5], "other", 3)] {
+            raw.extend(fixed(0, hash, 0));
+            raw.extend_from_slice(strip);
+            raw.extend_from_slice(suffix.as_bytes());
+            raw.push(0);
+        }
+        let entries = parse_index(&raw).unwrap();
+        assert_eq!(entries.iter().map(|x| x.path.as_str()).collect::<Vec<_>>(), ["dir/a", "dir/b", "other"]);
+        assert_eq!(entries[2].sha_hash, "03".repeat(20));
+    }
+
+    #[test]
+    fn version_4_long_strip() {
+        // Strip counts of 128 and over take several bytes, each continuation adding one
+        let long = "x".repeat(200);
+        let mut raw = header(4, 2);
+        raw.extend(fixed(0, 1, 0));
+        raw.push(0);
+        raw.extend_from_slice(long.as_bytes());
+        raw.push(0);
+        raw.extend(fixed(0, 2, 0));
+        raw.extend_from_slice(&[0x80, 0x48]);
+        raw.extend_from_slice(b"y\0");
+        let entries = parse_index(&raw).unwrap();
+        assert_eq!(entries[1].path, "y");
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert_eq!(parse_index(b"DIRX\0\0\0\x02\0\0\0\0").unwrap_err().to_string(), "index file has a bad signature");
+        assert_eq!(parse_index(&header(5, 0)).unwrap_err().to_string(), "index file has unsupported version 5");
+        assert_eq!(parse_index(b"DIRC\0\0").unwrap_err().to_string(), "index file is truncated");
+        assert!(parse_index(&header(2, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn non_utf8_paths() {
+        let mut raw = header(2, 1);
+        raw.extend(padded("bad?", 1, 0, false));
+        let at = raw.iter().position(|&x| x == b'?').unwrap();
+        raw[at] = 0xff;
+        let error = parse_index(&raw).unwrap_err();
+        assert_eq!(error.to_string(), "index has a path that is not valid UTF-8: bad\\xff");
+        let mut raw = header(4, 1);
+        raw.extend(fixed(0, 1, 0));
+        raw.extend_from_slice(b"\0bad\xff\0");
+        assert!(parse_index(&raw).is_err());
+    }
+
+    #[test]
+    fn truncated_entries() {
+        for version in [2, 4] {
+            let mut raw = header(version, 1);
+            if version == 2 {
+                raw.extend(padded("a", 1, 0, false));
+            } else {
+                raw.extend(fixed(0, 1, 0));
+                raw.extend_from_slice(b"\0a\0");
+            }
+            assert!(parse_index(&raw).is_ok());
+            // Cut in the stat data, the id, the flags and the path
+            for length in [20, 70, 73, raw.len() - 1] {
+                let error = parse_index(&raw[..length]).unwrap_err();
+                assert_eq!(error.to_string(), "index file is truncated", "version {} cut at {}", version, length);
+            }
+        }
+        // An entry counted in the header but missing altogether
+        let mut raw = header(2, 2);
+        raw.extend(padded("a", 1, 0, false));
+        assert_eq!(parse_index(&raw).unwrap_err().to_string(), "index file is truncated");
+    }
+}
@@ -0,0 +1,1379 @@
+use crate::combine::combined_paths;
+use crate::config::read_config;
+use crate::date::{format_date, parse_date, DateFormat};
+use crate::diff::{ChangeKind, FileChange, TreeDiff};
+use crate::graph::Graph;
+use crate::patch::DiffOptions;
+use crate::reach::merge_bases;
+use crate::refs::{head_symbolic_target, list_refs, resolve_ref};
+use crate::rename::{Detect, Renames};
+use crate::revision::{ambiguous_argument, peel_tags, peel_to_commit, resolve_revision};
+use crate::walk::{abbreviate, basic_to_extended, option_argument, Commit, Signature, Walk, WalkOptions};
+use crate::{read_object_from_store, GitObjectType};
+use regex::{Regex, RegexBuilder};
+use std::collections::{HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+// Format is the --pretty style commits are printed in
+#[derive(Clone, PartialEq, Eq)]
+pub enum Format {
+    Oneline,
+    Short,
+    Medium,
+    Full,
+    Fuller,
+    Raw,
+    // Mail headers and the unindented message, with "From " lines quoted for mboxrd
+    Email(bool),
+    // "<abbreviated id> (<subject>, <date>)", as used to refer to a commit in a message
+    Reference,
+    // A user format string, and whether each commit is terminated rather than separated
+    User(String, bool),
+}
+
+impl Format {
+    pub fn parse(value: &str) -> anyhow::Result<Format> {
+        Ok(match value {
+            "oneline" => Format::Oneline,
+            "short" => Format::Short,
+            "medium" => Format::Medium,
+            "full" => Format::Full,
+            "fuller" => Format::Fuller,
+            "raw" => Format::Raw,
+            "email" => Format::Email(false),
+            "mboxrd" => Format::Email(true),
+            "reference" => Format::Reference,
+            _ => {
+                if value.is_empty() {
+                    Format::User(String::new(), true)
+                } else if let Some(format) = value.strip_prefix("format:") {
+                    Format::User(format.to_string(), false)
+                } else if let Some(format) = value.strip_prefix("tformat:") {
+                    Format::User(format.to_string(), true)
+                } else if value.contains('%') {
+                    Format::User(value.to_string(), true)
+                } else {
+                    anyhow::bail!("invalid --pretty format: {}", value)
+                }
+            }
+        })
+    }
+
+    // Oneline and tformat output ends every commit with a newline instead of separating them
+    fn uses_terminator(&self) -> bool {
+        matches!(self, Format::Oneline | Format::Reference | Format::User(_, true))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Decorate {
+    No,
+    Short,
+    Full,
+}
+
+// Parse a log.decorate or --decorate value, with "auto" decorating only output to a terminal
+fn parse_decorate(value: &str) -> anyhow::Result<Decorate> {
+    match value {
+        "no" | "false" | "0" => Ok(Decorate::No),
+        "short" | "true" | "yes" | "1" => Ok(Decorate::Short),
+        "full" => Ok(Decorate::Full),
+        "auto" if std::io::stdout().is_terminal() => Ok(Decorate::Short),
+        "auto" => Ok(Decorate::No),
+        _ => anyhow::bail!("invalid --decorate option: {}", value),
+    }
+}
+
+// Ref names pointing at each commit, in the order log shows them: HEAD first, then the rest in
+// reverse order of their full names. Annotated tags decorate the commit they point at.
+pub fn ref_decorations(decorate: Decorate) -> HashMap<String, Vec<String>> {
+    let mut decorations: HashMap<String, Vec<String>> = HashMap::new();
+    if decorate == Decorate::No {
+        return decorations;
+    }
+    let display = |name: &str| match decorate {
+        Decorate::Full => name.to_string(),
+        _ => crate::refs::shorten_ref_name(name).to_string(),
+    };
+    let head = resolve_ref("HEAD");
+    let head_target = head_symbolic_target();
+    let mut head_branch = None;
+    for (name, hash) in list_refs().into_iter().rev() {
+        let shown = ["refs/heads/", "refs/remotes/", "refs/tags/"].iter().any(|prefix| name.starts_with(prefix)) || name == "refs/stash";
+        if !shown {
+            continue;
+        }
+        let Ok(commit) = peel_tags(&hash) else {
+            continue;
+        };
+        if Some(&name) == head_target.as_ref() && Some(&commit) == head.as_ref() {
+            head_branch = Some(display(&name));
+            continue;
+        }
+        let label = match name.starts_with("refs/tags/") {
+            true => format!("tag: {}", display(&name)),
+            false => display(&name),
+        };
+        decorations.entry(commit).or_default().push(label);
+    }
+    if let Some(head) = head {
+        let label = match head_branch {
+            Some(branch) => format!("HEAD -> {}", branch),
+            None => "HEAD".to_string(),
+        };
+        decorations.entry(head).or_default().insert(0, label);
+    }
+    decorations
+}
+
+// Revisions are the tips and exclusions given on the command line, plus any paths after them.
+// Left holds the tips on the left of symmetric differences like "A...B".
+pub struct Revisions {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub left: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+fn rev_commit(spec: &str) -> anyhow::Result<String> {
+    peel_to_commit(&resolve_revision(spec)?)
+}
+
+// Sort out which arguments are revisions ("A", "^A", "A..B", "A...B", --all) and which are
+// paths. Without any revision the walk starts from HEAD.
+pub fn parse_revisions(args: &[String]) -> anyhow::Result<Revisions> {
+    let mut revisions = Revisions { include: Vec::new(), exclude: Vec::new(), left: Vec::new(), paths: Vec::new() };
+    let mut negate = false;
+    let mut any_revision = false;
+    let mut index = 0;
+    while index < args.len() {
+        let arg = &args[index];
+        index += 1;
+        if arg == "--" {
+            revisions.paths.extend(args[index..].iter().cloned());
+            break;
+        }
+        match arg.as_str() {
+            "--not" => {
+                negate = !negate;
+                continue;
+            }
+            "--all" | "--branches" | "--tags" | "--remotes" => {
+                let prefix = match arg.as_str() {
+                    "--branches" => "refs/heads/",
+                    "--tags" => "refs/tags/",
+                    "--remotes" => "refs/remotes/",
+                    _ => "refs/",
+                };
+                let mut tips: Vec<String> = list_refs().into_iter().filter(|(name, _)| name.starts_with(prefix)).map(|x| x.1).collect();
+                if arg == "--all" {
+                    tips.extend(resolve_ref("HEAD"));
+                }
+                for tip in tips {
+                    // Refs to trees or blobs have no history to walk
+                    if let Ok(commit) = peel_to_commit(&tip) {
+                        if negate {
+                            revisions.exclude.push(commit);
+                        } else {
+                            revisions.include.push(commit);
+                        }
+                    }
+                }
+                any_revision = true;
+                continue;
+            }
+            _ => {}
+        }
+        // A symmetric difference leaves out what both sides share
+        if let Some((left, right)) = arg.split_once("...") {
+            let left = if left.is_empty() { "HEAD" } else { left };
+            let right = if right.is_empty() { "HEAD" } else { right };
+            if let (Ok(left), Ok(right)) = (rev_commit(left), rev_commit(right)) {
+                if negate {
+                    revisions.exclude.extend([left, right]);
+                } else {
+                    revisions.exclude.extend(merge_bases(&left, &right)?);
+                    revisions.left.push(left.clone());
+                    revisions.include.extend([left, right]);
+                }
+                any_revision = true;
+                continue;
+            }
+        } else if let Some((from, to)) = arg.split_once("..") {
+            let from = if from.is_empty() { "HEAD" } else { from };
+            let to = if to.is_empty() { "HEAD" } else { to };
+            if let (Ok(from), Ok(to)) = (rev_commit(from), rev_commit(to)) {
+                if negate {
+                    revisions.include.push(from);
+                    revisions.exclude.push(to);
+                } else {
+                    revisions.exclude.push(from);
+                    revisions.include.push(to);
+                }
+                any_revision = true;
+                continue;
+            }
+        } else if let Some(spec) = arg.strip_prefix('^') {
+            if let Ok(commit) = rev_commit(spec) {
+                if negate {
+                    revisions.include.push(commit);
+                } else {
+                    revisions.exclude.push(commit);
+                }
+                any_revision = true;
+                continue;
+            }
+        } else if let Ok(hash) = resolve_revision(arg) {
+            if Path::new(arg).exists() {
+                return Err(ambiguous_argument(arg, "both revision and filename"));
+            }
+            let commit = peel_to_commit(&hash)?;
+            if negate {
+                revisions.exclude.push(commit);
+            } else {
+                revisions.include.push(commit);
+            }
+            any_revision = true;
+            continue;
+        }
+
+        // A reflog selector that can't be resolved is an error rather than a path, and so is a
+        // path missing from a revision's tree or the index
+        if arg.contains("@{") {
+            resolve_revision(arg)?;
+        }
+        if let Some((revision, _)) = arg.split_once(':') {
+            if !Path::new(arg).exists() && (revision.is_empty() || resolve_revision(revision).is_ok()) {
+                resolve_revision(arg)?;
+            }
+        }
+
+        // Everything from the first argument that is not a revision on must be a path
+        for path in &args[index - 1..] {
+            if path == "--" {
+                continue;
+            }
+            if !Path::new(path).exists() {
+                return Err(ambiguous_argument(path, "unknown revision or path not in the working tree."));
+            }
+            revisions.paths.push(path.clone());
+        }
+        break;
+    }
+
+    if !any_revision {
+        match resolve_ref("HEAD") {
+            Some(head) => revisions.include.push(peel_to_commit(&head)?),
+            None => {
+                let branch = head_symbolic_target().unwrap_or_else(|| "HEAD".to_string());
+                let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch).to_string();
+                anyhow::bail!("your current branch '{}' does not have any commits yet", branch);
+            }
+        }
+    }
+    revisions.paths = revisions
+        .paths
+        .iter()
+        .map(|path| path.trim_start_matches("./").trim_end_matches('/').to_string())
+        .map(|path| if path.is_empty() { ".".to_string() } else { path })
+        .collect();
+    Ok(revisions)
+}
+
+// Context carries what formatting a commit needs beyond the commit itself
+pub struct Context {
+    pub decorations: HashMap<String, Vec<String>>,
+    pub date_format: DateFormat,
+    pub abbrev: bool,
+    // Columns the graph takes before the commit, counted by %<|(N) and the like
+    pub graph_width: usize,
+}
+
+impl Context {
+    fn abbreviate(&self, hash: &str) -> String {
+        match self.abbrev {
+            true => abbreviate(hash),
+            false => hash.to_string(),
+        }
+    }
+
+    // " (HEAD -> main, tag: v1)" for a decorated commit, or nothing
+    pub fn decoration(&self, hash: &str) -> String {
+        match self.decorations.get(hash) {
+            Some(names) => format!(" ({})", names.join(", ")),
+            None => String::new(),
+        }
+    }
+}
+
+// Expand tabs to the next multiple of eight columns
+fn expand_tabs(line: &str) -> String {
+    let mut result = String::new();
+    let mut column = 0;
+    for x in line.chars() {
+        if x == '\t' {
+            let width = 8 - column % 8;
+            result.push_str(&" ".repeat(width));
+            column += width;
+        } else {
+            result.push(x);
+            column += 1;
+        }
+    }
+    result
+}
+
+// Subject turned into something usable as a file name, for %f
+pub fn sanitized_subject(subject: &str) -> String {
+    let mut result = String::new();
+    let mut pending_dash = false;
+    for x in subject.chars() {
+        if x.is_ascii_alphanumeric() || x == '.' || x == '_' {
+            if pending_dash && !result.is_empty() {
+                result.push('-');
+            }
+            pending_dash = false;
+            result.push(x);
+        } else {
+            pending_dash = true;
+        }
+    }
+    result.trim_end_matches('.').to_string()
+}
+
+// Wrap text to width columns as git's strbuf_add_wrapped_text does, indenting the first line by
+// indent1 and the rest by indent2. A negative indent1 is how much of the first line is already
+// used. Line breaks are kept before blank lines and lines not starting with a letter or digit.
+fn wrap_text(text: &str, indent1: isize, indent2: usize, width: usize) -> String {
+    let mut output = String::new();
+    if width == 0 {
+        let mut indent = indent1.max(0) as usize;
+        for line in text.split_inclusive('\n') {
+            output.push_str(&" ".repeat(indent));
+            output.push_str(line);
+            indent = indent2;
+        }
+        return output;
+    }
+    let text: Vec<char> = text.chars().collect();
+    let mut position = 0;
+    let mut line_start = 0;
+    let mut indent = indent1.max(0) as usize;
+    let mut column = indent1.unsigned_abs();
+    // Where the whitespace before the word being measured is
+    let mut space = (indent1 < 0).then_some(0);
+    loop {
+        let x = text.get(position).copied();
+        if x.is_some_and(|x| !x.is_ascii_whitespace()) {
+            column += 1;
+            position += 1;
+            continue;
+        }
+        if column <= width || space.is_none() {
+            if x.is_none() && position == line_start {
+                return output;
+            }
+            if space.is_none() {
+                output.push_str(&" ".repeat(indent));
+            }
+            output.extend(&text[space.unwrap_or(line_start)..position]);
+            let Some(x) = x else {
+                return output;
+            };
+            space = Some(position);
+            let mut new_line = false;
+            if x == '\t' {
+                column |= 7;
+            } else if x == '\n' {
+                space = Some(position + 1);
+                match text.get(position + 1) {
+                    Some('\n') => {
+                        output.push('\n');
+                        new_line = true;
+                    }
+                    Some(next) if next.is_ascii_alphanumeric() => output.push(' '),
+                    _ => new_line = true,
+                }
+            }
+            if !new_line {
+                column += 1;
+                position += 1;
+                continue;
+            }
+        }
+        output.push('\n');
+        let space = space.take().unwrap();
+        line_start = space + usize::from(text.get(space).is_some_and(|x| x.is_ascii_whitespace()));
+        position = line_start;
+        indent = indent2;
+        column = indent2;
+    }
+}
+
+// Padding and wrapping are limited so a format cannot ask for output of any size
+const FORMATTING_LIMIT: usize = 16 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Truncate {
+    No,
+    Left,
+    Middle,
+    Right,
+}
+
+// Padding asked for by %<(N), %>(N), %>>(N) or %><(N) for the placeholder after it. With a "|"
+// the value is padded to reach a column rather than to a width.
+#[derive(Clone, Copy)]
+struct Padding {
+    width: usize,
+    to_column: bool,
+    align: Align,
+    // %>>(N) takes spaces from before the value when it is too wide
+    steal: bool,
+    truncate: Truncate,
+}
+
+// Parse a padding placeholder at the start of text, returning it and how many bytes it used
+fn parse_padding(text: &str) -> Option<(Padding, usize)> {
+    let (align, steal, rest) = if let Some(rest) = text.strip_prefix("><") {
+        (Align::Center, false, rest)
+    } else if let Some(rest) = text.strip_prefix(">>") {
+        (Align::Right, true, rest)
+    } else if let Some(rest) = text.strip_prefix('>') {
+        (Align::Right, false, rest)
+    } else {
+        (Align::Left, false, text.strip_prefix('<')?)
+    };
+    let to_column = rest.starts_with('|');
+    let rest = rest.strip_prefix('|').unwrap_or(rest).strip_prefix('(')?;
+    let end = rest.find(')')?;
+    let (width, truncate) = match rest[..end].split_once(',') {
+        Some((width, "trunc")) => (width, Truncate::Right),
+        Some((width, "ltrunc")) => (width, Truncate::Left),
+        Some((width, "mtrunc")) => (width, Truncate::Middle),
+        Some(_) => return None,
+        None => (&rest[..end], Truncate::No),
+    };
+    let width = width.trim().parse().ok().filter(|x| (1..=FORMATTING_LIMIT).contains(x))?;
+    let padding = Padding { width, to_column, align, steal, truncate };
+    Some((padding, text.len() - rest.len() + end + 1))
+}
+
+// Columns taken by text. Control characters such as tabs take none.
+fn display_width(text: &str) -> usize {
+    text.chars().filter(|x| !x.is_control()).count()
+}
+
+// Replace the characters in columns start..end of text with a marker. Characters taking no
+// columns are always kept.
+fn replace_columns(text: &str, start: usize, end: usize, marker: &str) -> String {
+    let mut output = String::new();
+    let mut marker = Some(marker);
+    let mut column = 0;
+    for x in text.chars() {
+        if x.is_control() {
+            output.push(x);
+            continue;
+        }
+        if (start..end).contains(&column) {
+            output.push_str(marker.take().unwrap_or_default());
+        } else {
+            output.push(x);
+        }
+        column += 1;
+    }
+    output
+}
+
+// A value padded, or truncated, as a padding placeholder asked, given the output before it.
+// Also returns how many spaces %>>(N) took from the end of that output.
+fn padded(output: &str, value: &str, padding: Padding, graph_width: usize) -> (usize, String) {
+    let mut room = padding.width as isize;
+    if padding.to_column {
+        room -= (display_width(output.rsplit('\n').next().unwrap_or("")) + graph_width) as isize;
+    }
+    let length = display_width(value) as isize;
+    let mut stolen = 0;
+    if padding.steal {
+        let spaces = output.len() - output.trim_end_matches(' ').len();
+        while length > room && stolen < spaces {
+            stolen += 1;
+            room += 1;
+        }
+    }
+    if length <= room {
+        let spare = (room - length) as usize;
+        let before = match padding.align {
+            Align::Left => 0,
+            Align::Right => spare,
+            Align::Center => spare / 2,
+        };
+        return (stolen, format!("{}{}{}", " ".repeat(before), value, " ".repeat(spare - before)));
+    }
+    // Truncated values keep room - 2 columns and mark what was cut with ".."
+    let cut = length - (room - 2);
+    let value = match padding.truncate {
+        Truncate::No => value.to_string(),
+        Truncate::Right => replace_columns(value, (room - 2).max(0) as usize, length as usize, ".."),
+        Truncate::Left => replace_columns(value, 0, cut as usize, ".."),
+        Truncate::Middle => {
+            let start = room / 2 - 1;
+            replace_columns(value, start.max(0) as usize, (start + cut).max(0) as usize, "..")
+        }
+    };
+    (stolen, value)
+}
+
+// Parse a %w(width,indent1,indent2) placeholder at the start of text, returning its values and
+// how many bytes it used. Values left out are zero.
+fn parse_wrap(text: &str) -> Option<((usize, usize, usize), usize)> {
+    let rest = text.strip_prefix("w(")?;
+    let end = rest.find(')')?;
+    let mut values = [0; 3];
+    if end > 0 {
+        let fields: Vec<&str> = rest[..end].split(',').collect();
+        if fields.len() > 3 {
+            return None;
+        }
+        for (value, field) in values.iter_mut().zip(fields) {
+            if !field.is_empty() {
+                *value = field.parse().ok().filter(|x| *x <= FORMATTING_LIMIT)?;
+            }
+        }
+    }
+    Some(((values[0], values[1], values[2]), end + 3))
+}
+
+// Expand one of the author (%a) or committer (%c) placeholders
+fn signature_placeholder(signature: &Signature, field: char, context: &Context) -> Option<String> {
+    let date = |format| format_date(signature.time, &signature.offset, &format);
+    Some(match field {
+        'n' | 'N' => signature.name.clone(),
+        'e' | 'E' => signature.email.clone(),
+        'l' | 'L' => signature.email.split('@').next().unwrap_or("").to_string(),
+        'd' => date(context.date_format.clone()),
+        'D' => date(DateFormat::Rfc),
+        'r' => date(DateFormat::Relative),
+        't' => date(DateFormat::Unix),
+        'i' => date(DateFormat::Iso),
+        'I' => date(DateFormat::IsoStrict),
+        's' => date(DateFormat::Short),
+        'h' => date(DateFormat::Default),
+        _ => return None,
+    })
+}
+
+// Expand the placeholder at the start of text, returning its value and how many bytes it used
+fn placeholder(text: &str, commit: &Commit, context: &Context) -> Option<(String, usize)> {
+    let mut chars = text.chars();
+    let first = chars.next()?;
+    let value = match first {
+        '%' => "%".to_string(),
+        'n' => "\n".to_string(),
+        'H' => commit.hash.clone(),
+        'h' => context.abbreviate(&commit.hash),
+        'T' => commit.tree.clone(),
+        't' => context.abbreviate(&commit.tree),
+        'P' => commit.parents.join(" "),
+        'p' => commit.parents.iter().map(|parent| context.abbreviate(parent)).collect::<Vec<_>>().join(" "),
+        's' => commit.subject(),
+        'f' => sanitized_subject(&commit.subject()),
+        'b' => commit.body().to_string(),
+        'B' => commit.message.trim_start_matches('\n').to_string(),
+        'd' => context.decoration(&commit.hash),
+        'D' => context.decorations.get(&commit.hash).map(|names| names.join(", ")).unwrap_or_default(),
+        'm' => ">".to_string(),
+        'e' | 'N' => String::new(),
+        'x' => {
+            let hex = text.get(1..3)?;
+            let byte = u8::from_str_radix(hex, 16).ok()?;
+            return Some(((byte as char).to_string(), 3));
+        }
+        'a' | 'c' => {
+            let field = chars.next()?;
+            let signature = if first == 'a' { &commit.author } else { &commit.committer };
+            return Some((signature_placeholder(signature, field, context)?, 2));
+        }
+        // Colours are not used, so they expand to nothing
+        'C' => {
+            if text[1..].starts_with('(') {
+                return Some((String::new(), text.find(')')? + 1));
+            }
+            let name = ["reset", "red", "green", "blue"].into_iter().find(|name| text[1..].starts_with(name))?;
+            return Some((String::new(), name.len() + 1));
+        }
+        _ => return None,
+    };
+    Some((value, first.len_utf8()))
+}
+
+// Expand a --format string for a commit. Text after a %w(...) is wrapped once the next one, or
+// the end of the format, is reached.
+pub fn expand_format(format: &str, commit: &Commit, context: &Context) -> String {
+    let mut output = String::new();
+    let mut padding = None;
+    let mut wrap = (0, 0, 0);
+    let mut wrap_start = 0;
+    let rewrap = |output: &mut String, wrap_start: usize, (width, indent1, indent2): (usize, usize, usize)| {
+        let tail = output.split_off(wrap_start);
+        output.push_str(&wrap_text(&tail, indent1 as isize, indent2, width));
+    };
+    let mut rest = format;
+    while let Some(position) = rest.find('%') {
+        output.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+        // "%+x" adds a newline before a non-empty expansion, "% x" a space, and "%-x"
+        // removes the newlines before an empty one
+        let magic = rest.chars().next().filter(|x| ['+', '-', ' '].contains(x));
+        let text = if magic.is_some() { &rest[1..] } else { rest };
+        if let Some((spec, length)) = parse_padding(text) {
+            padding = Some(spec);
+            rest = &text[length..];
+            continue;
+        }
+        if let Some((spec, length)) = parse_wrap(text) {
+            if spec != wrap {
+                rewrap(&mut output, wrap_start, wrap);
+                wrap_start = output.len();
+                wrap = spec;
+            }
+            rest = &text[length..];
+            continue;
+        }
+        match placeholder(text, commit, context) {
+            // Colours expand to nothing, so padding applies to what follows them
+            Some((_, length)) if padding.is_some() && text.starts_with('C') && text[length..].starts_with('%') => {
+                rest = &text[length..];
+            }
+            Some((value, length)) => {
+                let value = match padding.take() {
+                    Some(padding) => {
+                        let (stolen, value) = padded(&output, &value, padding, context.graph_width);
+                        output.truncate(output.len() - stolen);
+                        value
+                    }
+                    None => value,
+                };
+                match magic {
+                    Some('+') if !value.is_empty() => output.push('\n'),
+                    Some(' ') if !value.is_empty() => output.push(' '),
+                    Some('-') if value.is_empty() => output.truncate(output.trim_end_matches('\n').len()),
+                    _ => {}
+                }
+                output.push_str(&value);
+                rest = &text[length..];
+            }
+            None => output.push('%'),
+        }
+    }
+    output.push_str(rest);
+    rewrap(&mut output, wrap_start, wrap);
+    output
+}
+
+// Whether a mail header value has to be written as RFC 2047 encoded words
+fn needs_rfc2047(text: &str) -> bool {
+    !text.is_ascii() || text.contains('\n') || text.contains("=?")
+}
+
+// Write text as RFC 2047 "Q" encoded words after a header taking used columns, folding lines
+// so none is longer than 76 columns. Addresses also encode their special characters.
+fn rfc2047(text: &str, used: usize, address: bool) -> String {
+    let mut output = String::from("=?UTF-8?q?");
+    let mut line_length = used + "=?UTF-8?q?".len();
+    for x in text.chars() {
+        let special = !x.is_ascii_graphic()
+            || "=?_".contains(x)
+            || address && !(x.is_ascii_alphanumeric() || "!*+-/".contains(x));
+        let mut bytes = [0; 4];
+        let encoded: String = match special {
+            true => x.encode_utf8(&mut bytes).bytes().map(|byte| format!("={:02X}", byte)).collect(),
+            false => x.to_string(),
+        };
+        // Each encoded word has to fit with its closing "?="
+        if line_length + encoded.len() + 2 > 76 {
+            output.push_str("?=\n =?UTF-8?q?");
+            line_length = " =?UTF-8?q?".len();
+        }
+        output.push_str(&encoded);
+        line_length += encoded.len();
+    }
+    output.push_str("?=");
+    output
+}
+
+// Columns used on the last line of text
+fn last_line_length(text: &str) -> usize {
+    text.rsplit('\n').next().unwrap_or("").chars().count()
+}
+
+// A commit as the headers and body of a mail, after the "From <id>" line. Names and subjects
+// that are not plain ASCII are encoded, and long ones folded onto indented lines.
+fn email_message(commit: &Commit, mboxrd: bool) -> String {
+    let mut output = String::from("From: ");
+    let name = &commit.author.name;
+    let mut max_length = 78;
+    if needs_rfc2047(name) {
+        output.push_str(&rfc2047(name, output.len(), true));
+        max_length = 76;
+    } else if name.contains(|x| "()<>@,;:\\\".[]".contains(x)) {
+        let quoted = format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        output.push_str(&wrap_text(&quoted, -6, 1, max_length));
+    } else {
+        output.push_str(&wrap_text(name, -6, 1, max_length));
+    }
+    if max_length < last_line_length(&output) + " <>".len() + commit.author.email.len() {
+        output.push('\n');
+    }
+    output.push_str(&format!(" <{}>\n", commit.author.email));
+    output.push_str(&format!("Date: {}\n", format_date(commit.author.time, &commit.author.offset, &DateFormat::Rfc)));
+
+    output.push_str("Subject: [PATCH] ");
+    let subject = commit.subject();
+    let used = last_line_length(&output);
+    match needs_rfc2047(&subject) {
+        true => output.push_str(&rfc2047(&subject, used, false)),
+        false => output.push_str(&wrap_text(&subject, -(used as isize), 1, 78)),
+    }
+    output.push('\n');
+    if !commit.message.is_ascii() {
+        output.push_str("MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\nContent-Transfer-Encoding: 8bit\n");
+    }
+    output.push('\n');
+
+    // The blank line ending the headers stays even when there is no body
+    let body_start = output.len();
+    for line in commit.body().lines() {
+        let line = line.trim_end();
+        if mboxrd && line.trim_start_matches('>').starts_with("From ") {
+            output.push('>');
+        }
+        output.push_str(line);
+        output.push('\n');
+    }
+    output.truncate(output.trim_end().len());
+    output.push('\n');
+    if output.len() <= body_start {
+        output.push('\n');
+    }
+    output
+}
+
+// The text shown for a commit after its "commit <id>" line: the headers for the chosen format
+// followed by the indented message
+pub fn pretty_commit(commit: &Commit, format: &Format, context: &Context) -> String {
+    let mut output = String::new();
+    match format {
+        Format::Oneline => return commit.subject(),
+        Format::User(format, _) => return expand_format(format, commit, context),
+        Format::Reference => return expand_format("%h (%s, %ad)", commit, context),
+        Format::Email(mboxrd) => return email_message(commit, *mboxrd),
+        Format::Raw => {
+            for line in commit.raw_header.lines() {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+        _ => {
+            if commit.parents.len() > 1 {
+                let parents: Vec<String> = commit.parents.iter().map(|parent| context.abbreviate(parent)).collect();
+                output.push_str(&format!("Merge: {}\n", parents.join(" ")));
+            }
+            let date = |signature: &Signature| format_date(signature.time, &signature.offset, &context.date_format);
+            let author = commit.author.identity();
+            let committer = commit.committer.identity();
+            match format {
+                Format::Short => output.push_str(&format!("Author: {}\n", author)),
+                Format::Medium => output.push_str(&format!("Author: {}\nDate:   {}\n", author, date(&commit.author))),
+                Format::Full => output.push_str(&format!("Author: {}\nCommit: {}\n", author, committer)),
+                _ => output.push_str(&format!(
+                    "Author:     {}\nAuthorDate: {}\nCommit:     {}\nCommitDate: {}\n",
+                    author,
+                    date(&commit.author),
+                    committer,
+                    date(&commit.committer)
+                )),
+            }
+        }
+    }
+    output.push('\n');
+
+    let expand = matches!(format, Format::Medium | Format::Full | Format::Fuller);
+    let mut started = false;
+    for line in commit.message.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            if !started {
+                continue;
+            }
+            if *format == Format::Short {
+                break;
+            }
+        }
+        started = true;
+        output.push_str("    ");
+        if expand {
+            output.push_str(&expand_tabs(line));
+        } else {
+            output.push_str(line);
+        }
+        output.push('\n');
+    }
+    output.truncate(output.trim_end().len());
+    output.push('\n');
+    output
+}
+
+// Build a case-sensitive or insensitive regex from a --grep style pattern
+fn pattern_regex(pattern: &str, syntax: char, ignore_case: bool) -> anyhow::Result<Regex> {
+    let pattern = match syntax {
+        'F' => regex::escape(pattern),
+        'E' => pattern.to_string(),
+        _ => basic_to_extended(pattern),
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case)
+        .multi_line(true)
+        .build()
+        .map_err(|error| anyhow::anyhow!("invalid regex '{}': {}", pattern, error))
+}
+
+// Follow a parent through commits path limiting hid, to the nearest commit that is shown
+fn rewrite_parent(walk: &Walk, parent: &str) -> Option<String> {
+    let mut parent = parent.to_string();
+    while walk.is_hidden(&parent) {
+        parent = walk.parents(&parent).first()?.clone();
+    }
+    Some(parent)
+}
+
+// What a commit changed against its parent, or against nothing for a root commit. Merges
+// show no changes unless --first-parent picks the parent to compare with.
+fn commit_changes(walk: &mut Walk, commit: &Commit, paths: &[String], renames: &Renames) -> anyhow::Result<Vec<FileChange>> {
+    let parent = match commit.parents.as_slice() {
+        [] => None,
+        [parent] => Some(parent),
+        [parent, ..] if walk.first_parent => Some(parent),
+        _ => return Ok(Vec::new()),
+    };
+    let parent_tree = match parent {
+        Some(parent) => Some(walk.commit(parent)?.tree.clone()),
+        None => None,
+    };
+    let tree_diff = TreeDiff { recursive: true, show_trees: false, paths: paths.to_vec() };
+    renames.diff_trees(&tree_diff, parent_tree.as_deref(), Some(&commit.tree))
+}
+
+// A commit's change to the file --follow is tracking. When the file appears, the whole
+// commit is searched for where it came from; a rename or copy found is shown instead and
+// its source is what gets followed from then on.
+fn follow_changes(walk: &mut Walk, commit: &Commit, path: &mut String, renames: &Renames) -> anyhow::Result<Vec<FileChange>> {
+    let changes = commit_changes(walk, commit, std::slice::from_ref(path), renames)?;
+    if !changes.iter().any(|change| change.kind == ChangeKind::Added) {
+        return Ok(changes);
+    }
+    let mut search = renames.clone();
+    search.detect = Detect::Copies;
+    search.copies_harder = true;
+    search.only_destination = Some(path.clone());
+    let found = commit_changes(walk, commit, &[], &search)?.into_iter().find(|change| change.is_rename() && change.new_path == *path);
+    match found {
+        Some(found) => {
+            *path = found.old_path.clone();
+            Ok(vec![found])
+        }
+        None => Ok(changes.into_iter().take(1).collect()),
+    }
+}
+
+// A merge's changes against all its parents at once, with stats against the first
+fn combined_changes(walk: &mut Walk, commit: &Commit, options: &DiffOptions, dense: bool) -> anyhow::Result<Vec<u8>> {
+    let mut parent_trees = Vec::new();
+    for parent in &commit.parents {
+        parent_trees.push(walk.commit(parent)?.tree.clone());
+    }
+    let paths = combined_paths(&parent_trees, &commit.tree, &walk.paths, &options.renames)?;
+    let tree_diff = TreeDiff { recursive: true, show_trees: false, paths: walk.paths.clone() };
+    let first_parent = options.renames.diff_trees(&tree_diff, Some(&parent_trees[0]), Some(&commit.tree))?;
+    options.render_combined(&paths, &first_parent, dense)
+}
+
+// Shown is an object show prints. Commits are printed the way log prints them, an annotated
+// tag is followed by the object it points at.
+enum Shown {
+    Commit(String),
+    Tag(String),
+    // A tree with the name it was given as
+    Tree(String, String),
+    Blob(String),
+}
+
+// Add the objects show prints for an object named on the command line, peeling tags
+fn shown_objects(name: &str, hash: &str, items: &mut Vec<Shown>) -> anyhow::Result<()> {
+    let mut hash = hash.to_string();
+    loop {
+        match read_object_from_store(&hash)? {
+            GitObjectType::Tag(tag) => {
+                items.push(Shown::Tag(hash.clone()));
+                hash = tag.target_hash().ok_or_else(|| anyhow::anyhow!("tag {} has no object line", hash))?.to_string();
+            }
+            GitObjectType::Commit(_) => break items.push(Shown::Commit(hash)),
+            GitObjectType::Tree(_) => break items.push(Shown::Tree(name.to_string(), hash)),
+            GitObjectType::Blob(_) => break items.push(Shown::Blob(hash)),
+        }
+    }
+    Ok(())
+}
+
+fn blob_contents(hash: &str) -> anyhow::Result<Vec<u8>> {
+    match read_object_from_store(hash)? {
+        GitObjectType::Blob(blob) => Ok(blob.blob_data),
+        _ => anyhow::bail!("{} is not a blob", hash),
+    }
+}
+
+// A tag as show prints it: its name, who made it and when, then its message. A tree is its
+// name followed by the entries directly in it, directories marked with a slash.
+fn show_object(object: &Shown, format: &Format, context: &Context) -> anyhow::Result<String> {
+    let mut output = String::new();
+    match object {
+        Shown::Tag(hash) => {
+            let GitObjectType::Tag(tag) = read_object_from_store(hash)? else {
+                anyhow::bail!("{} is not a tag", hash);
+            };
+            let (header, message) = tag.tag_data.split_once("\n\n").unwrap_or((tag.tag_data.as_str(), ""));
+            let field = |name: &str| header.lines().find_map(|line| line.strip_prefix(name));
+            output.push_str(&format!("tag {}\n", field("tag ").unwrap_or_default()));
+            // The tagger is shown like a commit's author in the chosen format
+            if let Some(tagger) = field("tagger ").filter(|_| *format != Format::Oneline) {
+                let tagger = Signature::parse(tagger);
+                let date = format_date(tagger.time, &tagger.offset, &context.date_format);
+                match format {
+                    Format::Medium => output.push_str(&format!("Tagger: {}\nDate:   {}\n", tagger.identity(), date)),
+                    Format::Fuller => {
+                        output.push_str(&format!("Tagger:     {}\nTaggerDate: {}\n", tagger.identity(), date))
+                    }
+                    _ => output.push_str(&format!("Tagger: {}\n", tagger.identity())),
+                }
+            }
+            output.push('\n');
+            output.push_str(message);
+        }
+        Shown::Tree(name, hash) => {
+            let GitObjectType::Tree(tree) = read_object_from_store(hash)? else {
+                anyhow::bail!("{} is not a tree", hash);
+            };
+            output.push_str(&format!("tree {}\n\n", name));
+            for leaf in tree.leaves {
+                let mode = u32::from_str_radix(&String::from_utf8_lossy(&leaf.mode), 8)?;
+                let slash = if mode == 0o040000 { "/" } else { "" };
+                output.push_str(&format!("{}{}\n", leaf.path, slash));
+            }
+        }
+        _ => {}
+    }
+    Ok(output)
+}
+
+// log walks history from the given revisions; show prints just the objects it is given, commits
+// with their patches
+pub fn log_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "log" || x == "show").unwrap();
+    let show = args[command_index] == "show";
+    let config = read_config();
+    let mut diff_options = DiffOptions::new(&config)?;
+    // Some(dense) when merges are shown as combined diffs
+    let mut combined = None;
+    let mut format = Format::Medium;
+    let mut abbrev_commit = None;
+    let mut abbrev = true;
+    let mut date_format = None;
+    let mut decorate = None;
+    let mut graph = false;
+    let mut follow = false;
+    let mut options = WalkOptions::default();
+    let mut authors = Vec::new();
+    let mut committers = Vec::new();
+    let mut greps = Vec::new();
+    let mut syntax = 'G';
+    let mut ignore_case = false;
+
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if options.parse_option(arg, &mut arguments)? {
+            continue;
+        }
+        let mut value = |name: &str| option_argument(arg, name, &mut arguments);
+        if let Some(name) = value("--format")? {
+            format = Format::parse(&name)?;
+        } else if arg == "--pretty" {
+            format = Format::Medium;
+        } else if let Some(name) = arg.strip_prefix("--pretty=") {
+            format = Format::parse(name)?;
+        } else if arg == "--oneline" {
+            format = Format::Oneline;
+            abbrev_commit = Some(true);
+        } else if let Some(pattern) = value("--author")? {
+            authors.push(pattern);
+        } else if let Some(pattern) = value("--committer")? {
+            committers.push(pattern);
+        } else if let Some(pattern) = value("--grep")? {
+            greps.push(pattern);
+        } else if let Some(date) = value("--since")?.or(value("--after")?).or(value("--since-as-filter")?) {
+            options.filter.since = Some(parse_date(&date).ok_or_else(|| anyhow::anyhow!("invalid date '{}'", date))?);
+        } else if let Some(date) = value("--until")?.or(value("--before")?) {
+            options.filter.until = Some(parse_date(&date).ok_or_else(|| anyhow::anyhow!("invalid date '{}'", date))?);
+        } else if let Some(name) = arg.strip_prefix("--date=") {
+            date_format = Some(DateFormat::parse(name)?);
+        } else if let Some(name) = arg.strip_prefix("--decorate=") {
+            decorate = Some(parse_decorate(name)?);
+        } else {
+            match arg.as_str() {
+                "--all-match" => options.filter.all_match = true,
+                "--invert-grep" => options.filter.invert_grep = true,
+                "-i" | "--regexp-ignore-case" => ignore_case = true,
+                "-E" | "--extended-regexp" => syntax = 'E',
+                "-F" | "--fixed-strings" => syntax = 'F',
+                "-G" | "--basic-regexp" => syntax = 'G',
+                "--graph" => graph = true,
+                "--follow" => follow = true,
+                "-c" => combined = Some(false),
+                "--cc" => combined = Some(true),
+                "--decorate" => decorate = Some(Decorate::Short),
+                "--no-decorate" => decorate = Some(Decorate::No),
+                "--abbrev-commit" => abbrev_commit = Some(true),
+                "--no-abbrev-commit" => abbrev_commit = Some(false),
+                "--no-abbrev" => {
+                    abbrev = false;
+                    diff_options.abbrev = None;
+                }
+                _ if diff_options.parse_option(arg)? => {}
+                _ if arg.starts_with('-') && arg.len() > 1 && !arg.starts_with("--") && arg != "-" => {
+                    anyhow::bail!("unrecognized argument: {}", arg)
+                }
+                _ if arg.starts_with("--") => anyhow::bail!("unrecognized argument: {}", arg),
+                _ => options.revision_args.push(arg.clone()),
+            }
+        }
+    }
+    let WalkOptions { mut walk, mut filter, max_count, skip, reverse, mut revision_args } = options;
+    if graph && reverse {
+        anyhow::bail!("options '--reverse' and '--graph' cannot be used together");
+    }
+    if (show || combined.is_some()) && !diff_options.has_format() {
+        diff_options.patch = true;
+    }
+    // show gives merges a dense combined diff unless --first-parent asks for a plain one
+    if combined.is_none() && show && !walk.first_parent {
+        combined = Some(true);
+    }
+    diff_options.validate()?;
+    filter.authors = authors.iter().map(|x| pattern_regex(x, syntax, ignore_case)).collect::<anyhow::Result<_>>()?;
+    filter.committers = committers.iter().map(|x| pattern_regex(x, syntax, ignore_case)).collect::<anyhow::Result<_>>()?;
+    filter.grep = greps.iter().map(|x| pattern_regex(x, syntax, ignore_case)).collect::<anyhow::Result<_>>()?;
+
+    // show takes objects of any type. Those that are not commits are kept out of the walk.
+    let mut objects = Vec::new();
+    if show {
+        for arg in revision_args.iter().take_while(|x| *x != "--") {
+            if arg.starts_with(['-', '^']) || arg.contains("..") {
+                continue;
+            }
+            if let Ok(hash) = resolve_revision(arg) {
+                objects.push((arg.clone(), hash));
+            }
+        }
+        revision_args.retain(|arg| !objects.iter().any(|(name, hash)| name == arg && peel_to_commit(hash).is_err()));
+    }
+
+    let revisions = parse_revisions(&revision_args)?;
+    walk.include = revisions.include;
+    walk.exclude = revisions.exclude;
+    walk.paths = revisions.paths;
+    walk.topo_order = graph;
+    // Following a file through renames means its path changes, so history is not pruned by it
+    let mut followed = None;
+    if follow {
+        if walk.paths.len() != 1 {
+            anyhow::bail!("--follow requires exactly one pathspec");
+        }
+        followed = walk.paths.pop();
+    }
+
+    let mut decorate = match decorate {
+        Some(decorate) => decorate,
+        None => parse_decorate(config.get("log.decorate").unwrap_or("auto"))?,
+    };
+    // A format showing %d or %D needs the decorations whatever --decorate says
+    let wants_decorations = |format: &str| Regex::new("%[-+ ]?[dD]").unwrap().is_match(&format.replace("%%", ""));
+    if matches!(&format, Format::User(format, _) if wants_decorations(format)) && decorate == Decorate::No {
+        decorate = Decorate::Short;
+    }
+    // References show short dates unless --date says otherwise
+    let date_format = match date_format {
+        Some(date_format) => date_format,
+        None if format == Format::Reference => DateFormat::Short,
+        None => DateFormat::Default,
+    };
+    let mut context = Context {
+        decorations: ref_decorations(decorate),
+        date_format,
+        abbrev,
+        graph_width: 0,
+    };
+    let abbrev_commit = abbrev && abbrev_commit.unwrap_or(false);
+
+    // show lists exactly the commits it was given unless a range asks for a walk
+    let no_walk = show && walk.exclude.is_empty();
+    let mut items = Vec::new();
+    if no_walk && !objects.is_empty() {
+        for (name, hash) in &objects {
+            shown_objects(name, hash, &mut items)?;
+        }
+        walk.include = items
+            .iter()
+            .filter_map(|item| match item {
+                Shown::Commit(hash) => Some(hash.clone()),
+                _ => None,
+            })
+            .collect();
+    }
+    // The commits that pass every filter, before --skip and --max-count are applied
+    let list = match no_walk {
+        true => walk.run_no_walk()?,
+        false => walk.run()?,
+    };
+    let mut interesting = HashSet::new();
+    for hash in &list {
+        let commit = walk.commit(hash)?;
+        if !walk.is_hidden(hash) && filter.matches(&commit) {
+            interesting.insert(hash.clone());
+        }
+    }
+    let mut shown: Vec<String> = list.iter().filter(|hash| interesting.contains(*hash)).skip(skip).cloned().collect();
+    if let Some(max_count) = max_count.filter(|_| followed.is_none()) {
+        shown.truncate(max_count);
+    }
+    if reverse {
+        shown.reverse();
+    }
+    let shown: Vec<Shown> = match items.is_empty() {
+        true => shown.into_iter().map(Shown::Commit).collect(),
+        false => items
+            .into_iter()
+            .filter(|item| match item {
+                Shown::Commit(hash) => shown.contains(hash),
+                _ => true,
+            })
+            .collect(),
+    };
+
+    let mut graph = graph.then(Graph::new);
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let mut shown_one = false;
+    let mut missing_newline = false;
+    let mut followed_count = 0;
+    for item in &shown {
+        let hash = match item {
+            Shown::Commit(hash) => hash,
+            Shown::Blob(hash) => {
+                if out.write_all(&blob_contents(hash)?).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+            object => {
+                // Tags and trees are set apart from what came before like commits are
+                let mut output = String::new();
+                if shown_one {
+                    output.push('\n');
+                }
+                output.push_str(&show_object(object, &format, &context)?);
+                shown_one = true;
+                missing_newline = false;
+                if out.write_all(output.as_bytes()).is_err() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        let mut commit = (*walk.commit(hash)?).clone();
+        let mut output = String::new();
+        if let Some(graph) = graph.as_mut() {
+            // The graph shows history rewritten past the commits path limiting hid, and so do
+            // the parents printed for each commit
+            commit.parents.clear();
+            for parent in walk.rewritten_parents(hash) {
+                if let Some(parent) = rewrite_parent(&walk, parent) {
+                    if !commit.parents.contains(&parent) {
+                        commit.parents.push(parent);
+                    }
+                }
+            }
+            let parents = commit.parents.iter().take(if walk.first_parent { 1 } else { usize::MAX });
+            graph.update(hash, parents.filter(|parent| interesting.contains(*parent)).cloned().collect());
+        }
+        // Only commits touching the followed file are shown, and they count towards -n.
+        // The graph still moves past the others, marking the gap.
+        let follow_diff = match followed.as_mut() {
+            Some(path) => {
+                let changes = follow_changes(&mut walk, &commit, path, &diff_options.renames)?;
+                if changes.is_empty() {
+                    continue;
+                }
+                if max_count.is_some_and(|max_count| followed_count >= max_count) {
+                    break;
+                }
+                followed_count += 1;
+                Some(changes)
+            }
+            None => None,
+        };
+
+        if shown_one && !format.uses_terminator() {
+            if !missing_newline {
+                output.push_str(&graph.as_mut().map(|x| x.padding_line()).unwrap_or_default());
+            }
+            output.push('\n');
+        }
+        if let Some(graph) = graph.as_mut() {
+            let line = graph.show_commit();
+            context.graph_width = display_width(&line);
+            output.push_str(&line);
+        }
+        let id = if abbrev_commit { abbreviate(hash) } else { hash.clone() };
+        match format {
+            Format::User(..) | Format::Reference => {}
+            Format::Oneline => output.push_str(&format!("{}{} ", id, context.decoration(hash))),
+            _ => {
+                match format {
+                    Format::Email(_) => output.push_str(&format!("From {} Mon Sep 17 00:00:00 2001\n", hash)),
+                    _ => output.push_str(&format!("commit {}{}\n", id, context.decoration(hash))),
+                }
+                if let Some(graph) = graph.as_mut() {
+                    output.push_str(&graph.next_line().0);
+                }
+            }
+        }
+
+        let message = pretty_commit(&commit, &format, &context);
+        missing_newline = !message.ends_with('\n');
+        match graph.as_mut() {
+            Some(graph) => output.push_str(&graph.show_message(&message)),
+            None => output.push_str(&message),
+        }
+        let empty_format = matches!(&format, Format::User(format, _) if format.is_empty());
+        if format.uses_terminator() && !empty_format {
+            if !missing_newline {
+                output.push_str(&graph.as_mut().map(|x| x.padding_line()).unwrap_or_default());
+            }
+            output.push('\n');
+        }
+        shown_one = true;
+        let mut output = output.into_bytes();
+
+        // The changes follow the message after a blank line, which becomes "---" when both a
+        // diffstat and a patch are shown. Combined diffs get the blank line even when no path
+        // differs from every parent. Graph lines carry on down the side of every line.
+        if diff_options.shows_output() {
+            let mut prefix = || graph.as_mut().map(|x| x.padding_line()).unwrap_or_default().into_bytes();
+            let diff = match combined.filter(|_| commit.parents.len() > 1) {
+                Some(dense) => {
+                    if !empty_format {
+                        output.extend(prefix());
+                        output.push(b'\n');
+                    }
+                    combined_changes(&mut walk, &commit, &diff_options, dense)?
+                }
+                None => {
+                    let changes = match follow_diff {
+                        Some(changes) => changes,
+                        None => {
+                            let paths = walk.paths.clone();
+                            commit_changes(&mut walk, &commit, &paths, &diff_options.renames)?
+                        }
+                    };
+                    if !changes.is_empty() && format != Format::Oneline && !empty_format {
+                        output.extend(prefix());
+                        if diff_options.stat_and_patch() {
+                            output.extend_from_slice(b"---");
+                        }
+                        output.push(b'\n');
+                    }
+                    diff_options.render(&changes)?
+                }
+            };
+            for line in diff.split_inclusive(|x| *x == b'\n') {
+                output.extend(prefix());
+                output.extend_from_slice(line);
+            }
+        }
+        // Stop quietly when the reader goes away, as when piped into head
+        if out.write_all(&output).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = out.flush();
+    diff_options.renames.warn_limit("diff.renameLimit");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{blob, commit, repository, tree};
+    use crate::write_object_to_store;
+
+    fn tag(target: &str, kind: &str, name: &str) -> String {
+        let data = format!("object {}\ntype {}\ntag {}\ntagger T A Gger <tagger@example.com> 0 +0000\n\nRelease {}\n", target, kind, name, name);
+        write_object_to_store(data.as_bytes(), b"tag")
+    }
+
+    #[test]
+    fn shown_objects_and_tags() {
+        let _repository = repository();
+        let root = tree(&[("a", "a\n"), ("dir/b", "b\n")]);
+        let head = commit(&root, &[], 1, "first");
+        let inner = tag(&head, "commit", "v1");
+        let outer = tag(&inner, "tag", "v1-signed");
+
+        // Tags are shown along with everything they lead to
+        let mut items = Vec::new();
+        shown_objects("v1-signed", &outer, &mut items).unwrap();
+        shown_objects("main:", &root, &mut items).unwrap();
+        shown_objects("main:a", &blob("a\n"), &mut items).unwrap();
+        let kinds: Vec<String> = items
+            .iter()
+            .map(|item| match item {
+                Shown::Commit(hash) => format!("commit {}", hash),
+                Shown::Tag(hash) => format!("tag {}", hash),
+                Shown::Tree(name, hash) => format!("tree {} {}", name, hash),
+                Shown::Blob(hash) => format!("blob {}", hash),
+            })
+            .collect();
+        let expected = [
+            format!("tag {}", outer),
+            format!("tag {}", inner),
+            format!("commit {}", head),
+            format!("tree main: {}", root),
+            format!("blob {}", blob("a\n")),
+        ];
+        assert_eq!(kinds, expected);
+
+        let context = Context { decorations: HashMap::new(), date_format: DateFormat::Default, abbrev: false, graph_width: 0 };
+        let shown = show_object(&items[1], &Format::Medium, &context).unwrap();
+        assert_eq!(shown, "tag v1\nTagger: T A Gger <tagger@example.com>\nDate:   Thu Jan 1 00:00:00 1970 +0000\n\nRelease v1\n");
+        assert_eq!(show_object(&items[1], &Format::Short, &context).unwrap(), "tag v1\nTagger: T A Gger <tagger@example.com>\n\nRelease v1\n");
+        assert_eq!(show_object(&items[1], &Format::Oneline, &context).unwrap(), "tag v1\n\nRelease v1\n");
+        assert_eq!(show_object(&items[3], &Format::Medium, &context).unwrap(), "tree main:\n\na\ndir/\n");
+    }
+}
@@ -1,3 +1,44 @@
+mod add;
+mod bisect;
+mod blame;
+mod branch;
+mod checkout;
+mod combine;
+mod commitgraph;
+mod config;
+mod date;
+mod describe;
+mod diff;
+mod editor;
+mod fsck;
+mod graph;
+mod grep;
+mod ident;
+mod ignore;
+mod index;
+mod log;
+mod merge;
+mod pack;
+mod patch;
+mod reach;
+mod rebase;
+mod reffilter;
+mod reflog;
+mod refs;
+mod reset;
+mod rename;
+mod revision;
+mod revlist;
+mod sequencer;
+mod stash;
+mod status;
+#[cfg(test)]
+mod testrepo;
+mod walk;
+mod worktree;
+mod xdiff;
+mod xmerge;
+
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
@@ -11,7 +52,7 @@ use std::time;
 // GitObject trait defines common methods for all Git objects
 pub trait GitObject {
     fn compress(&self) -> Vec<u8>;
-    fn decompress(&mut self, data: &[u8]);
+    fn decompress(&mut self, data: &[u8]) -> anyhow::Result<()>;
     fn fmt(&self) -> &[u8];
 }
 
@@ -29,8 +70,9 @@ impl GitObject for GitBlob {
         self.blob_data.clone()
     }
 
-    fn decompress(&mut self, data: &[u8]) {
+    fn decompress(&mut self, data: &[u8]) -> anyhow::Result<()> {
         self.blob_data = data.to_vec();
+        Ok(())
     }
 }
 
@@ -39,75 +81,216 @@ enum GitObjectType {
     Blob(GitBlob),
     Tree(GitTree),
     Commit(GitCommit),
+    Tag(GitTag),
 }
 
 // GitTreeLeaf represents a single entry in a Git tree object
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct GitTreeLeaf {
     pub mode: Vec<u8>,
     pub path: String,
     pub sha_hash: String,
 }
 
-// Functions for parsing and handling Git tree objects
-fn parse_git_treee(raw_bytes: &[u8], start_index: usize) -> (GitTreeLeaf, usize) {
+// TreeError describes a problem found while parsing or checking a tree object.
+// Syntax errors stop parsing; the remaining variants are reported by fsck-style checks.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TreeError {
+    #[error("truncated tree entry at offset {0}")]
+    Truncated(usize),
+    #[error("malformed mode '{mode}' in tree entry at offset {offset}")]
+    MalformedMode { mode: String, offset: usize },
+    #[error("empty filename in tree entry at offset {0}")]
+    EmptyName(usize),
+    #[error("filename in tree entry at offset {0} is not valid UTF-8")]
+    NonUtf8Name(usize),
+    #[error("badFilemode: contains bad file modes ({mode} {path})")]
+    BadFilemode { mode: String, path: String },
+    #[error("zeroPaddedFilemode: contains zero-padded file modes ({path})")]
+    ZeroPaddedFilemode { path: String },
+    #[error("fullPathname: contains full pathnames ({0})")]
+    FullPathname(String),
+    #[error("hasDot: contains '.' ({0})")]
+    HasDot(String),
+    #[error("hasDotdot: contains '..' ({0})")]
+    HasDotdot(String),
+    #[error("hasDotgit: contains '.git' ({0})")]
+    HasDotgit(String),
+    #[error("duplicateEntries: contains duplicate file entries ({0})")]
+    DuplicateEntries(String),
+    #[error("treeNotSorted: not properly sorted ({0} after {1})")]
+    TreeNotSorted(String, String),
+    #[error("nullSha1: contains entries pointing to null sha1 ({0})")]
+    NullSha1(String),
+}
+
+impl TreeError {
+    // Warnings are reported by fsck but do not make the object invalid
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self,
+            TreeError::BadFilemode { .. }
+                | TreeError::ZeroPaddedFilemode { .. }
+                | TreeError::FullPathname(_)
+                | TreeError::HasDot(_)
+                | TreeError::HasDotdot(_)
+                | TreeError::HasDotgit(_)
+                | TreeError::NullSha1(_)
+        )
+    }
+}
+
+// Functions for parsing and handling Git tree objects. Paths are handled as strings, so a name
+// that is not UTF-8 is an error rather than silently becoming another name; lossy keeps it with
+// replacement characters, for checks that only report names.
+fn parse_git_treee(raw_bytes: &[u8], start_index: usize, lossy: bool) -> Result<(GitTreeLeaf, usize), TreeError> {
     let mut index = start_index;
-    let mode = parse_mode(raw_bytes, &mut index);
-    let path = parse_path(raw_bytes, &mut index);
-    let sha_hash = parse_sha_hash(raw_bytes, &mut index);
+    let mode = parse_mode(raw_bytes, &mut index)?;
+    let path = parse_path(raw_bytes, &mut index, lossy)?;
+    let sha_hash = parse_sha_hash(raw_bytes, &mut index)?;
 
-    (GitTreeLeaf { mode, path, sha_hash }, index)
+    Ok((GitTreeLeaf { mode, path, sha_hash }, index))
 }
 
-fn parse_mode(raw_bytes: &[u8], index: &mut usize) -> Vec<u8> {
-    let mut mode = [0; 6];
+fn parse_mode(raw_bytes: &[u8], index: &mut usize) -> Result<Vec<u8>, TreeError> {
     let start_index = *index;
-    while raw_bytes[*index] != b' ' {
-        mode[*index - start_index] = raw_bytes[*index];
-        *index += 1;
+    let end_index = raw_bytes[start_index..]
+        .iter()
+        .position(|&x| x == b' ')
+        .map(|position| start_index + position)
+        .ok_or(TreeError::Truncated(start_index))?;
+    let mode = &raw_bytes[start_index..end_index];
+    // Leading zeros are only a warning, so they do not count towards the length
+    let digits = &mode[mode.iter().position(|&x| x != b'0').unwrap_or(mode.len())..];
+    if mode.is_empty() || digits.len() > 6 || !mode.iter().all(|x| (b'0'..=b'7').contains(x)) {
+        return Err(TreeError::MalformedMode {
+            mode: String::from_utf8_lossy(mode).into_owned(),
+            offset: start_index,
+        });
     }
-    if *index - start_index == 5 {
-        mode = [b'0', mode[0], mode[1], mode[2], mode[3], mode[4]];
-    }
-    mode.to_vec()
+    *index = end_index;
+
+    let mut padded = vec![b'0'; 6 - digits.len()];
+    padded.extend_from_slice(digits);
+    Ok(padded)
 }
 
-fn parse_path(raw_bytes: &[u8], index: &mut usize) -> String {
+fn parse_path(raw_bytes: &[u8], index: &mut usize, lossy: bool) -> Result<String, TreeError> {
     *index += 1; // Skip whitespace
-    let mut path = String::new();
-    while raw_bytes[*index] != b'\0' {
-        path.push(raw_bytes[*index] as char);
-        *index += 1;
+    let start_index = *index;
+    let end_index = raw_bytes[start_index..]
+        .iter()
+        .position(|&x| x == b'\0')
+        .map(|position| start_index + position)
+        .ok_or(TreeError::Truncated(start_index))?;
+    if end_index == start_index {
+        return Err(TreeError::EmptyName(start_index));
+    }
+    *index = end_index + 1; // Skip null byte
+    let name = &raw_bytes[start_index..end_index];
+    match std::str::from_utf8(name) {
+        Ok(name) => Ok(name.to_string()),
+        Err(_) if lossy => Ok(String::from_utf8_lossy(name).into_owned()),
+        Err(_) => Err(TreeError::NonUtf8Name(start_index)),
     }
-    *index += 1; // Skip null byte
-    path
 }
 
-fn parse_sha_hash(raw_bytes: &[u8], index: &mut usize) -> String {
-    let mut sha_hash = String::new();
-    for _ in 0..20 {
-        sha_hash.push_str(&format!("{:02x}", raw_bytes[*index]));
-        *index += 1;
+fn parse_sha_hash(raw_bytes: &[u8], index: &mut usize) -> Result<String, TreeError> {
+    if raw_bytes.len() < *index + 20 {
+        return Err(TreeError::Truncated(*index));
     }
-    sha_hash
+    let sha_hash = hex::encode(&raw_bytes[*index..*index + 20]);
+    *index += 20;
+    Ok(sha_hash)
 }
 
-fn git_tree_parse(raw_bytes: &[u8]) -> Vec<GitTreeLeaf> {
+fn git_tree_parse(raw_bytes: &[u8]) -> Result<Vec<GitTreeLeaf>, TreeError> {
+    parse_tree_leaves(raw_bytes, false)
+}
+
+// Parse a tree keeping names that are not UTF-8, for fsck which only reports them
+fn git_tree_parse_lossy(raw_bytes: &[u8]) -> Result<Vec<GitTreeLeaf>, TreeError> {
+    parse_tree_leaves(raw_bytes, true)
+}
+
+fn parse_tree_leaves(raw_bytes: &[u8], lossy: bool) -> Result<Vec<GitTreeLeaf>, TreeError> {
     let mut index = 0;
     let mut result = Vec::new();
     while index < raw_bytes.len() {
-        let (leaf, new_index) = parse_git_treee(raw_bytes, index);
+        let (leaf, new_index) = parse_git_treee(raw_bytes, index, lossy)?;
         result.push(leaf);
         index = new_index;
     }
-    result
+    Ok(result)
+}
+
+// Run the fsck checks on a raw tree, returning each kind of problem found once.
+// A syntax error ends the check early since later entries cannot be located.
+fn check_git_tree(raw_bytes: &[u8]) -> Vec<TreeError> {
+    let mut problems: Vec<TreeError> = Vec::new();
+    let mut report = |problem: TreeError| {
+        if !problems.iter().any(|x| std::mem::discriminant(x) == std::mem::discriminant(&problem)) {
+            problems.push(problem);
+        }
+    };
+    let mut leaves: Vec<GitTreeLeaf> = Vec::new();
+    let mut index = 0;
+    while index < raw_bytes.len() {
+        let (leaf, new_index) = match parse_git_treee(raw_bytes, index, true) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                report(error);
+                return problems;
+            }
+        };
+
+        // Modes are padded to six digits when parsed, so look at the raw bytes
+        if raw_bytes[index] == b'0' {
+            report(TreeError::ZeroPaddedFilemode { path: leaf.path.clone() });
+        }
+        let mode = String::from_utf8_lossy(&leaf.mode).into_owned();
+        if !matches!(mode.as_str(), "100644" | "100755" | "120000" | "040000" | "160000") {
+            report(TreeError::BadFilemode {
+                mode: mode.trim_start_matches('0').to_string(),
+                path: leaf.path.clone(),
+            });
+        }
+        if leaf.path.contains('/') {
+            report(TreeError::FullPathname(leaf.path.clone()));
+        }
+        match leaf.path.as_str() {
+            "." => report(TreeError::HasDot(leaf.path.clone())),
+            ".." => report(TreeError::HasDotdot(leaf.path.clone())),
+            path if path.eq_ignore_ascii_case(".git") => report(TreeError::HasDotgit(leaf.path.clone())),
+            _ => {}
+        }
+        if leaf.sha_hash.bytes().all(|x| x == b'0') {
+            report(TreeError::NullSha1(leaf.path.clone()));
+        }
+        if let Some(previous) = leaves.last() {
+            if previous.path == leaf.path {
+                report(TreeError::DuplicateEntries(leaf.path.clone()));
+            } else if sort_git_tree_keys(previous) > sort_git_tree_keys(&leaf) {
+                report(TreeError::TreeNotSorted(leaf.path.clone(), previous.path.clone()));
+            }
+        }
+
+        leaves.push(leaf);
+        index = new_index;
+    }
+    problems
+}
+
+fn is_tree_mode(mode: &[u8]) -> bool {
+    mode == b"040000"
 }
 
+// Trees sort as if directory names had a trailing slash
 fn sort_git_tree_keys(leaf: &GitTreeLeaf) -> String {
-    if leaf.mode.starts_with(b"10") {
-        leaf.path.clone()
+    if is_tree_mode(&leaf.mode) {
+        format!("{}/", leaf.path)
     } else {
-        format!("{}\\", leaf.path)
+        leaf.path.clone()
     }
 }
 
@@ -141,8 +324,9 @@ impl GitObject for GitTree {
         result
     }
 
-    fn decompress(&mut self, data: &[u8]) {
-        self.leaves = git_tree_parse(data);
+    fn decompress(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.leaves = git_tree_parse(data)?;
+        Ok(())
     }
 }
 
@@ -160,8 +344,63 @@ impl GitObject for GitCommit {
         self.commit_data.clone().into_bytes()
     }
 
-    fn decompress(&mut self, data: &[u8]) {
-        self.commit_data = String::from_utf8(data.to_vec()).unwrap();
+    fn decompress(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.commit_data = String::from_utf8_lossy(data).into_owned();
+        Ok(())
+    }
+}
+
+impl GitCommit {
+    // Values of a header field, which ends at the blank line before the message
+    fn header_values(&self, name: &str) -> Vec<&str> {
+        self.commit_data
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+            .collect()
+    }
+
+    pub fn tree_hash(&self) -> Option<&str> {
+        self.header_values("tree").first().copied()
+    }
+
+    pub fn parent_hashes(&self) -> Vec<&str> {
+        self.header_values("parent")
+    }
+
+    // First line of the commit message
+    pub fn subject(&self) -> &str {
+        match self.commit_data.split_once("\n\n") {
+            Some((_, message)) => message.lines().next().unwrap_or(""),
+            None => "",
+        }
+    }
+}
+
+// GitTag represents an annotated Git tag object
+pub struct GitTag {
+    pub tag_data: String,
+}
+
+impl GitObject for GitTag {
+    fn fmt(&self) -> &[u8] {
+        b"tag"
+    }
+
+    fn compress(&self) -> Vec<u8> {
+        self.tag_data.clone().into_bytes()
+    }
+
+    fn decompress(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.tag_data = String::from_utf8_lossy(data).into_owned();
+        Ok(())
+    }
+}
+
+impl GitTag {
+    // The object this tag points at
+    pub fn target_hash(&self) -> Option<&str> {
+        self.tag_data.lines().next().and_then(|line| line.strip_prefix("object "))
     }
 }
 
@@ -169,15 +408,88 @@ fn main() {
     let args: Vec<String> = env::args().collect();
     match args[1].as_str() {
         "init" => init_git_directory(),
-        "cat-file" => cat_file(&args),
-        "hash-object" => hash_object(&args),
-        "ls-tree" => ls_tree(&args),
+        "cat-file" => exit_on_error(cat_file(&args)),
+        "hash-object" => exit_on_error(hash_object(&args)),
+        "ls-tree" => exit_on_error(ls_tree(&args)),
         "write-tree" => write_new_git_tree_command(),
         "commit-tree" => commit_tree(&args),
+        "fsck" => exit_on_error(fsck::fsck_command(&args)),
+        "checkout" => exit_on_error(checkout::checkout_command(&args)),
+        "switch" => exit_on_error(checkout::switch_command(&args)),
+        "restore" => exit_on_error(checkout::restore_command(&args)),
+        "status" => exit_on_error(status::status_command(&args)),
+        "add" => exit_on_error(add::add_command(&args)),
+        "check-ignore" => exit_on_error(ignore::check_ignore_command(&args)),
+        "log" | "show" => exit_on_error(log::log_command(&args)),
+        "diff-tree" => exit_on_error(diff::diff_tree_command(&args)),
+        "diff-index" => exit_on_error(diff::diff_index_command(&args)),
+        "diff-files" => exit_on_error(diff::diff_files_command(&args)),
+        "diff" => exit_on_error(patch::diff_command(&args)),
+        "merge" => exit_on_error(merge::merge_command(&args)),
+        "branch" => exit_on_error(branch::branch_command(&args)),
+        "reflog" => exit_on_error(reflog::reflog_command(&args)),
+        "merge-base" => exit_on_error(reach::merge_base_command(&args)),
+        "merge-file" => exit_on_error(xmerge::merge_file_command(&args)),
+        "reset" => exit_on_error(reset::reset_command(&args)),
+        "cherry-pick" => exit_on_error(sequencer::cherry_pick_command(&args)),
+        "revert" => exit_on_error(sequencer::revert_command(&args)),
+        "rebase" => exit_on_error(rebase::rebase_command(&args)),
+        "stash" => exit_on_error(stash::stash_command(&args)),
+        "blame" => exit_on_error(blame::blame_command(&args)),
+        "annotate" => exit_on_error(blame::annotate_command(&args)),
+        "bisect" => exit_on_error(bisect::bisect_command(&args)),
+        "grep" => exit_on_error(grep::grep_command(&args)),
+        "describe" => exit_on_error(describe::describe_command(&args)),
+        "name-rev" => exit_on_error(describe::name_rev_command(&args)),
+        "for-each-ref" => exit_on_error(reffilter::for_each_ref_command(&args)),
+        "rev-list" => exit_on_error(revlist::rev_list_command(&args)),
         _ => println!("Unknown command: {}", args[1]),
     }
 }
 
+// Report a failed command the way git does and exit with its fatal status.
+// Refusing to overwrite local changes is an ordinary error rather than a fatal one.
+fn exit_on_error(result: anyhow::Result<()>) {
+    if let Err(error) = result {
+        if let Some(conflict) = error.downcast_ref::<checkout::CheckoutConflict>() {
+            eprintln!("error: {}", conflict);
+            std::process::exit(1);
+        }
+        eprintln!("fatal: {:#}", error);
+        std::process::exit(128);
+    }
+}
+
+// The value of the option at args[*index] when it is one of names, given as "--name=value" or
+// "--name value" for long names and "-nvalue" or "-n value" for short ones. index moves past a
+// value given separately. A missing value is reported, then usage runs and the command exits.
+pub fn option_value<T>(args: &[String], index: &mut usize, names: &[&str], usage: impl Fn() -> T) -> Option<String> {
+    let arg = args[*index].as_str();
+    for name in names {
+        let attached = match name.starts_with("--") {
+            true => arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')),
+            false => arg.strip_prefix(name).filter(|rest| !rest.is_empty()),
+        };
+        if let Some(value) = attached {
+            return Some(value.to_string());
+        }
+        if arg != *name {
+            continue;
+        }
+        *index += 1;
+        if let Some(value) = args.get(*index) {
+            return Some(value.clone());
+        }
+        match name.strip_prefix("--") {
+            Some(long) => eprintln!("error: option `{}' requires a value", long),
+            None => eprintln!("error: switch `{}' requires a value", &name[1..]),
+        }
+        usage();
+        std::process::exit(129);
+    }
+    None
+}
+
 // Initialize a new Git repository
 fn init_git_directory() {
     fs::create_dir(".git").unwrap();
@@ -188,37 +500,63 @@ fn init_git_directory() {
 }
 
 // Display the contents of a Git object
-fn cat_file(args: &[String]) {
+fn cat_file(args: &[String]) -> anyhow::Result<()> {
     let hash = &args[args.len() - 1];
-    let object = read_object_from_store(hash);
+    let object = read_object_from_store(hash)?;
     match object {
         GitObjectType::Blob(blob) => {
             std::io::stdout().write_all(&blob.compress()).unwrap();
             std::io::stdout().flush().unwrap();
         }
+        GitObjectType::Commit(commit) => print!("{}", commit.commit_data),
+        GitObjectType::Tag(tag) => print!("{}", tag.tag_data),
         _ => println!("Unexpected object type for cat-file"),
     }
+    Ok(())
 }
 
-// Hash the contents of a file and store it as a Git object
-fn hash_object(args: &[String]) {
+// Hash the contents of a file and store it as a Git object.
+// Trees passed with `-t tree` are checked before they are written unless `--literally` is given.
+fn hash_object(args: &[String]) -> anyhow::Result<()> {
     let file_path = &args[args.len() - 1];
-    let data = fs::read(file_path).unwrap();
-    let object = GitBlob { blob_data: data };
-    let contents = object.compress();
-    let object_type = object.fmt();
-    let hash = write_object_to_store(contents.as_slice(), object_type);
+    let data = fs::read(file_path)?;
+    let object_type = match args.iter().position(|x| x == "-t") {
+        Some(type_index) => args[type_index + 1].as_str(),
+        None => "blob",
+    };
+
+    let hash = match object_type {
+        "blob" => {
+            let object = GitBlob { blob_data: data };
+            write_object_to_store(object.compress().as_slice(), object.fmt())
+        }
+        "tree" => {
+            if !args.iter().any(|x| x == "--literally") {
+                for problem in check_git_tree(&data) {
+                    if problem.is_warning() {
+                        eprintln!("warning: {}", problem);
+                    } else {
+                        anyhow::bail!("object fails fsck: {}", problem);
+                    }
+                }
+            }
+            write_object_to_store(data.as_slice(), b"tree")
+        }
+        _ => anyhow::bail!("unsupported object type '{}'", object_type),
+    };
     println!("{}", hash);
+    Ok(())
 }
 
 // List the contents of a Git tree object
-fn ls_tree(args: &[String]) {
+fn ls_tree(args: &[String]) -> anyhow::Result<()> {
     let hash = &args[args.len() - 1];
-    let object = read_object_from_store(hash);
+    let object = read_object_from_store(hash)?;
     match object {
         GitObjectType::Tree(tree) => ls_tree_contents(tree),
         _ => println!("Not a tree object"),
     }
+    Ok(())
 }
 
 fn ls_tree_contents(tree: GitTree) {
@@ -229,7 +567,8 @@ fn ls_tree_contents(tree: GitTree) {
 
 // Write the current directory structure as a Git tree object
 fn write_new_git_tree_command() {
-    let tree_hash = write_new_git_tree(".");
+    let mut ignores = ignore::Ignores::new(&config::read_config());
+    let tree_hash = write_new_git_tree(".", &mut ignores);
     println!("{}", tree_hash);
 }
 
@@ -245,42 +584,98 @@ fn commit_tree(args: &[String]) {
     println!("{}", commit_hash);
 }
 
-// Read a Git object from the object store
-fn read_object_from_store(hash: &str) -> GitObjectType {
-    let path = format!(".git/objects/{}/{}", &hash[..2], &hash[2..]);
-    let data = fs::read(path).unwrap();
-    let mut decoder = ZlibDecoder::new(data.as_slice());
+// Inflate a loose object file into its header and contents
+fn inflate_loose_object(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(data);
     let mut decoded_bytes = Vec::new();
-    decoder.read_to_end(&mut decoded_bytes).unwrap();
+    decoder.read_to_end(&mut decoded_bytes)?;
+    Ok(decoded_bytes)
+}
 
-    let index_of_first_whitespace = decoded_bytes.iter().position(|&x| x == b' ').unwrap();
-    let index_of_first_null = decoded_bytes.iter().position(|&x| x == 0).unwrap();
+// Split "<type> <size>\0<contents>" into the type and contents, checking the recorded size
+fn split_object_header(decoded_bytes: &[u8]) -> anyhow::Result<(Vec<u8>, &[u8])> {
+    let index_of_first_whitespace = decoded_bytes.iter().position(|&x| x == b' ');
+    let index_of_first_null = decoded_bytes.iter().position(|&x| x == 0);
+    let (index_of_first_whitespace, index_of_first_null) = match (index_of_first_whitespace, index_of_first_null) {
+        (Some(whitespace), Some(null)) if whitespace < null => (whitespace, null),
+        _ => anyhow::bail!("malformed object header"),
+    };
     let object_type = &decoded_bytes[..index_of_first_whitespace];
+    let size = &decoded_bytes[index_of_first_whitespace + 1..index_of_first_null];
     let byte_contents = &decoded_bytes[index_of_first_null + 1..];
+    if String::from_utf8_lossy(size).parse::<usize>().ok() != Some(byte_contents.len()) {
+        anyhow::bail!("object size does not match its header");
+    }
+    Ok((object_type.to_vec(), byte_contents))
+}
+
+// Read the type and contents of an object, looking at loose objects before packs
+fn read_raw_object(hash: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if hash.len() != 40 || !hash.bytes().all(|x| x.is_ascii_hexdigit()) {
+        anyhow::bail!("not a valid object name {}", hash);
+    }
+    let path = format!(".git/objects/{}/{}", &hash[..2], &hash[2..]);
+    if let Ok(data) = fs::read(path) {
+        let decoded_bytes = inflate_loose_object(&data)?;
+        let (object_type, byte_contents) =
+            split_object_header(&decoded_bytes).map_err(|error| anyhow::anyhow!("object {}: {}", hash, error))?;
+        return Ok((object_type, byte_contents.to_vec()));
+    }
+    pack::read_packed_object(hash)?.ok_or_else(|| anyhow::anyhow!("not a valid object name {}", hash))
+}
+
+// Read a Git object from the object store
+fn read_object_from_store(hash: &str) -> anyhow::Result<GitObjectType> {
+    let (object_type, byte_contents) = read_raw_object(hash)?;
+    let byte_contents = byte_contents.as_slice();
 
-    match object_type {
+    let object = match object_type.as_slice() {
         b"blob" => {
             let mut blob = GitBlob { blob_data: Vec::new() };
-            blob.decompress(byte_contents);
+            blob.decompress(byte_contents)?;
             GitObjectType::Blob(blob)
         }
         b"tree" => {
             let mut tree = GitTree { leaves: Vec::new() };
-            tree.decompress(byte_contents);
+            tree.decompress(byte_contents).map_err(|error| anyhow::anyhow!("tree {}: {}", hash, error))?;
             GitObjectType::Tree(tree)
         }
-        _ => panic!("Unknown object type"),
-    }
+        b"commit" => {
+            let mut commit = GitCommit { commit_data: String::new() };
+            commit.decompress(byte_contents)?;
+            GitObjectType::Commit(commit)
+        }
+        b"tag" => {
+            let mut tag = GitTag { tag_data: String::new() };
+            tag.decompress(byte_contents)?;
+            GitObjectType::Tag(tag)
+        }
+        _ => anyhow::bail!("object {} has unknown type '{}'", hash, String::from_utf8_lossy(&object_type)),
+    };
+    Ok(object)
 }
 
-// Write a Git object to the object store
-fn write_object_to_store(contents: &[u8], object_type: &[u8]) -> String {
+// Prefix object contents with the "<type> <size>\0" header that is hashed and stored
+fn encode_object(contents: &[u8], object_type: &[u8]) -> Vec<u8> {
     let mut result = Vec::new();
     result.extend_from_slice(object_type);
     result.push(b' ');
     result.extend_from_slice(contents.len().to_string().as_bytes());
     result.push(b'\0');
     result.extend_from_slice(contents);
+    result
+}
+
+// Compute the id an object would have without writing it
+fn hash_object_contents(contents: &[u8], object_type: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(encode_object(contents, object_type));
+    hex::encode(hasher.finalize())
+}
+
+// Write a Git object to the object store
+fn write_object_to_store(contents: &[u8], object_type: &[u8]) -> String {
+    let result = encode_object(contents, object_type);
 
     let mut hasher = Sha1::new();
     hasher.update(&result);
@@ -288,6 +683,9 @@ fn write_object_to_store(contents: &[u8], object_type: &[u8]) -> String {
     let sha_string = hex::encode(hash_result);
 
     let path = format!(".git/objects/{}/{}", &sha_string[..2], &sha_string[2..]);
+    if std::path::Path::new(&path).exists() {
+        return sha_string;
+    }
     fs::create_dir_all(format!(".git/objects/{}", &sha_string[..2])).unwrap();
 
     let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
@@ -298,8 +696,8 @@ fn write_object_to_store(contents: &[u8], object_type: &[u8]) -> String {
     sha_string
 }
 
-// Write a directory structure as a Git tree object
-fn write_new_git_tree(path: &str) -> String {
+// Write a directory structure as a Git tree object, leaving out ignored files
+fn write_new_git_tree(path: &str, ignores: &mut ignore::Ignores) -> String {
     let mut entries: Vec<(Vec<u8>, String, String)> = Vec::new();
 
     for entry in fs::read_dir(path).unwrap() {
@@ -312,9 +710,13 @@ fn write_new_git_tree(path: &str) -> String {
         if file_name == ".git" {
             continue;
         }
+        let relative_path = entry_path.to_str().unwrap().trim_start_matches("./");
+        if ignores.is_ignored(relative_path, metadata.is_dir()) {
+            continue;
+        }
 
         if metadata.is_dir() {
-            let tree_sha_hash = write_new_git_tree(entry_path.to_str().unwrap());
+            let tree_sha_hash = write_new_git_tree(entry_path.to_str().unwrap(), ignores);
             entries.push((mode, file_name, tree_sha_hash));
         } else {
             let blob_contents = fs::read(entry_path.clone()).unwrap();
@@ -361,7 +763,7 @@ fn write_new_git_commit(tree_hash: &str, message: &str, parent_hash: &str) -> St
         hardcoded_author_name, hardcoded_author_email, timestamp, offset
     );
 
-    let commit_lines = vec![
+    let commit_lines = [
         format!("tree {}", tree_hash),
         format!("parent {}", parent_hash),
         format!("author {}", author_contents),
@@ -375,4 +777,127 @@ fn write_new_git_commit(tree_hash: &str, message: &str, parent_hash: &str) -> St
     let commit = GitCommit { commit_data: commit_contents };
     let commit_contents = commit.compress();
     write_object_to_store(commit_contents.as_slice(), commit.fmt())
-}
\ No newline at end of file
+}
+
+// Write a commit of a tree with any number of parents, signed with the author and committer
+// identities from the environment or config. The message should end with a newline.
+fn write_commit(tree_hash: &str, parents: &[String], message: &str, config: &config::Config) -> anyhow::Result<String> {
+    let author = ident::signature(config, ident::Role::Author)?;
+    write_commit_as(tree_hash, parents, &author, message, config)
+}
+
+// Write a commit that keeps an existing author line, as replaying a commit elsewhere does
+fn write_commit_as(tree_hash: &str, parents: &[String], author: &str, message: &str, config: &config::Config) -> anyhow::Result<String> {
+    let mut lines = vec![format!("tree {}", tree_hash)];
+    lines.extend(parents.iter().map(|parent| format!("parent {}", parent)));
+    lines.push(format!("author {}", author));
+    lines.push(format!("committer {}", ident::signature(config, ident::Role::Committer)?));
+    let commit = GitCommit { commit_data: format!("{}\n\n{}", lines.join("\n"), message) };
+    Ok(write_object_to_store(&commit.compress(), commit.fmt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mode: &str, name: &str, hash: u8) -> Vec<u8> {
+        let mut raw = format!("{} {}\0", mode, name).into_bytes();
+        raw.extend_from_slice(&[hash; 20]);
+        raw
+    }
+
+    fn tree(entries: &[(&str, &str, u8)]) -> Vec<u8> {
+        entries.iter().flat_map(|(mode, name, hash)| entry(mode, name, *hash)).collect()
+    }
+
+    #[test]
+    fn parses_and_pads_modes() {
+        let leaves = git_tree_parse(&tree(&[("100644", "a", 1), ("40000", "d", 2)])).unwrap();
+        assert_eq!(leaves.len(), 2);
+        assert_eq!(leaves[0].mode, b"100644");
+        assert_eq!(leaves[1].mode, b"040000");
+        assert_eq!(leaves[1].sha_hash, "02".repeat(20));
+        assert!(check_git_tree(&tree(&[("100644", "a", 1), ("40000", "d", 2)])).is_empty());
+    }
+
+    #[test]
+    fn truncated_entries() {
+        let raw = tree(&[("100644", "a", 1)]);
+        assert_eq!(git_tree_parse(&raw[..raw.len() - 1]).unwrap_err(), TreeError::Truncated(9));
+        assert_eq!(git_tree_parse(b"100644 a").unwrap_err(), TreeError::Truncated(7));
+        assert_eq!(git_tree_parse(b"100644").unwrap_err(), TreeError::Truncated(0));
+        assert_eq!(check_git_tree(b"100644"), vec![TreeError::Truncated(0)]);
+    }
+
+    #[test]
+    fn empty_names() {
+        assert_eq!(git_tree_parse(&entry("100644", "", 1)).unwrap_err(), TreeError::EmptyName(7));
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let mut raw = b"100644 bad\xff\0".to_vec();
+        raw.extend_from_slice(&[1; 20]);
+        assert_eq!(git_tree_parse(&raw).unwrap_err(), TreeError::NonUtf8Name(7));
+        // fsck only reports names, so it checks such trees like any other
+        assert!(check_git_tree(&raw).is_empty());
+        assert_eq!(git_tree_parse_lossy(&raw).unwrap()[0].path, "bad\u{FFFD}");
+    }
+
+    #[test]
+    fn malformed_modes() {
+        for mode in ["", "10064x", "1000644", "-100644"] {
+            assert_eq!(
+                git_tree_parse(&entry(mode, "a", 1)).unwrap_err(),
+                TreeError::MalformedMode { mode: mode.to_string(), offset: 0 }
+            );
+        }
+    }
+
+    #[test]
+    fn zero_padded_modes_are_warnings() {
+        let raw = tree(&[("0100644", "a", 1), ("040000", "d", 2)]);
+        let leaves = git_tree_parse(&raw).unwrap();
+        assert_eq!(leaves[0].mode, b"100644");
+        assert_eq!(leaves[1].mode, b"040000");
+        let problems = check_git_tree(&raw);
+        assert_eq!(problems, vec![TreeError::ZeroPaddedFilemode { path: "a".to_string() }]);
+        assert!(problems.iter().all(TreeError::is_warning));
+    }
+
+    #[test]
+    fn bad_modes() {
+        let problems = check_git_tree(&tree(&[("100664", "a", 1)]));
+        assert_eq!(problems, vec![TreeError::BadFilemode { mode: "100664".to_string(), path: "a".to_string() }]);
+        assert!(problems[0].is_warning());
+    }
+
+    #[test]
+    fn duplicate_and_unsorted_names() {
+        assert_eq!(
+            check_git_tree(&tree(&[("100644", "a", 1), ("100644", "a", 2)])),
+            vec![TreeError::DuplicateEntries("a".to_string())]
+        );
+        let problems = check_git_tree(&tree(&[("100644", "b", 1), ("100644", "a", 2)]));
+        assert_eq!(problems, vec![TreeError::TreeNotSorted("a".to_string(), "b".to_string())]);
+        assert!(!problems[0].is_warning());
+        // Directories sort as if their names ended in a slash
+        assert!(check_git_tree(&tree(&[("100644", "a.c", 1), ("40000", "a", 2)])).is_empty());
+        assert!(!check_git_tree(&tree(&[("40000", "a", 2), ("100644", "a.c", 1)])).is_empty());
+    }
+
+    #[test]
+    fn special_names() {
+        assert_eq!(check_git_tree(&entry("40000", ".", 1)), vec![TreeError::HasDot(".".to_string())]);
+        assert_eq!(check_git_tree(&entry("40000", "..", 1)), vec![TreeError::HasDotdot("..".to_string())]);
+        assert_eq!(check_git_tree(&entry("40000", ".GIT", 1)), vec![TreeError::HasDotgit(".GIT".to_string())]);
+        assert_eq!(check_git_tree(&entry("100644", "a/b", 1)), vec![TreeError::FullPathname("a/b".to_string())]);
+    }
+
+    #[test]
+    fn null_ids() {
+        let problems = check_git_tree(&tree(&[("100644", "a", 0), ("100644", "b", 0)]));
+        assert_eq!(problems, vec![TreeError::NullSha1("a".to_string())]);
+        assert!(problems[0].is_warning());
+    }
+}
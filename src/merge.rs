@@ -0,0 +1,1042 @@
+// Three-way merges of trees and the merge porcelain. Renames on each side are found against the
+// merge base first so that changes follow a file to its new name, then every path is resolved
+// from its base, our and their versions the way git's ort strategy does. Histories with more
+// than one best merge base have the bases merged first into a virtual ancestor.
+
+use crate::checkout::{head_tree, switch_tree, CheckoutConflict};
+use crate::config::{read_config, Config};
+use crate::diff::{ChangeKind, TreeDiff};
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::patch::{is_binary, DiffOptions};
+use crate::reach::{merge_bases, merge_bases_many};
+use crate::refs::{head_symbolic_target, read_ref, resolve_ref, shorten_ref_name, update_ref, write_ref};
+use crate::rename::{Detect, Renames};
+use crate::revision::{expand_ref_name, peel_to_commit, peel_to_tree, resolve_revision};
+use crate::walk::abbreviate;
+use crate::worktree::{checkout_file, flatten_tree, remove_worktree_file, write_tree, TreeEntry};
+use crate::xdiff::Algorithm;
+use crate::xmerge::{merge_file, Favor, Level, MergeFileOptions, Style};
+use crate::{read_object_from_store, write_commit, write_object_to_store, GitObjectType};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::Write;
+
+// Positions of the three versions of a path
+const BASE: usize = 0;
+const OURS: usize = 1;
+const THEIRS: usize = 2;
+
+// The version of a path on each side, missing where a side has no file there
+type Versions = [Option<TreeEntry>; 3];
+
+// MergeOptions name the sides of a merge and choose how renames are found and how file
+// contents are merged. Depth counts how far inside the merging of merge bases a merge is;
+// those inner merges print nothing and settle some conflicts differently.
+#[derive(Clone)]
+pub struct MergeOptions {
+    pub labels: [String; 3],
+    pub renames: Renames,
+    pub file: MergeFileOptions,
+    pub depth: usize,
+}
+
+impl MergeOptions {
+    pub fn new(config: &Config, ours: &str, theirs: &str) -> anyhow::Result<MergeOptions> {
+        let mut file = MergeFileOptions { level: Level::Zealous, algorithm: Algorithm::Histogram, ..Default::default() };
+        if let Some(style) = config.get("merge.conflictstyle") {
+            file.style = Style::parse(style).ok_or_else(|| anyhow::anyhow!("unknown style '{}' given for 'merge.conflictstyle'", style))?;
+        }
+        Ok(MergeOptions {
+            labels: [String::new(), ours.to_string(), theirs.to_string()],
+            renames: Renames::for_merge(config),
+            file,
+            depth: 0,
+        })
+    }
+
+    // Apply a strategy option given with -X
+    pub fn strategy_option(&mut self, option: &str) -> anyhow::Result<()> {
+        match option {
+            "ours" => self.file.favor = Some(Favor::Ours),
+            "theirs" => self.file.favor = Some(Favor::Theirs),
+            "ignore-all-space" => self.file.whitespace.all = true,
+            "ignore-space-change" => self.file.whitespace.change = true,
+            "ignore-space-at-eol" => self.file.whitespace.at_eol = true,
+            "ignore-cr-at-eol" => self.file.whitespace.cr_at_eol = true,
+            "no-renames" => self.renames.detect = Detect::Off,
+            // Like ort, content merges always use histogram diff whatever is asked for
+            "patience" | "histogram" => {}
+            _ if option.starts_with("diff-algorithm=") => {
+                Algorithm::parse(&option["diff-algorithm=".len()..])?;
+            }
+            _ => anyhow::bail!("unknown strategy option: -X{}", option),
+        }
+        Ok(())
+    }
+}
+
+// TreeMerge is the result of merging two trees: the files of the merged tree, where conflicted
+// files carry conflict markers, the versions recorded as index stages for each conflicted path,
+// and the messages describing what happened
+pub struct TreeMerge {
+    pub entries: BTreeMap<String, TreeEntry>,
+    pub conflicts: BTreeMap<String, Versions>,
+    pub messages: Vec<String>,
+    pub clean: bool,
+}
+
+impl TreeMerge {
+    // Write the merged tree, conflict markers and all
+    pub fn tree(&self) -> String {
+        write_tree(&self.entries)
+    }
+}
+
+// Record is one path to resolve, holding each side's version and the path it had on that side,
+// which differs after a rename. Some conflicts found while matching renames are settled then.
+#[derive(Clone, Default)]
+struct Record {
+    versions: Versions,
+    paths: [String; 3],
+    outcome: Option<Outcome>,
+}
+
+impl Record {
+    fn at(path: &str) -> Record {
+        Record {
+            versions: Default::default(),
+            paths: [path.to_string(), path.to_string(), path.to_string()],
+            outcome: None,
+        }
+    }
+}
+
+// Outcome is how a path ends up: the file left in the merged tree and, for a conflict, the
+// versions to record as stages 1 to 3
+#[derive(Clone, Default)]
+struct Outcome {
+    result: Option<TreeEntry>,
+    stages: Versions,
+    clean: bool,
+}
+
+impl Outcome {
+    fn clean(result: Option<TreeEntry>) -> Outcome {
+        Outcome { result, stages: Default::default(), clean: true }
+    }
+
+    fn conflict(result: Option<TreeEntry>, stages: Versions) -> Outcome {
+        Outcome { result, stages, clean: false }
+    }
+}
+
+fn is_regular(mode: u32) -> bool {
+    mode & 0o170000 == 0o100000
+}
+
+fn same_type(a: u32, b: u32) -> bool {
+    a & 0o170000 == b & 0o170000
+}
+
+fn blob_contents(hash: &str) -> anyhow::Result<Vec<u8>> {
+    match read_object_from_store(hash)? {
+        GitObjectType::Blob(blob) => Ok(blob.blob_data),
+        _ => anyhow::bail!("{} is not a blob", hash),
+    }
+}
+
+// The unique name a file is moved to when something else needs its path
+fn unique_path(path: &str, label: &str, taken: &HashSet<String>) -> String {
+    let base = format!("{}~{}", path, label.replace('/', "_"));
+    let mut candidate = base.clone();
+    let mut suffix = 0;
+    while taken.contains(&candidate) {
+        candidate = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    candidate
+}
+
+struct Merger<'a> {
+    options: &'a MergeOptions,
+    messages: BTreeMap<String, Vec<String>>,
+}
+
+impl Merger<'_> {
+    // Note what happened at a path. Inner merges keep quiet.
+    fn say(&mut self, path: &str, message: String) {
+        if self.options.depth == 0 {
+            self.messages.entry(path.to_string()).or_default().push(message);
+        }
+    }
+
+    fn label(&self, side: usize) -> &str {
+        &self.options.labels[side]
+    }
+
+    // Renames from the base to one side, as (old path, new path)
+    fn side_renames(&self, base: Option<&str>, side: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let Some(base) = base else {
+            return Ok(Vec::new());
+        };
+        if !self.options.renames.enabled() {
+            return Ok(Vec::new());
+        }
+        let tree_diff = TreeDiff { recursive: true, ..Default::default() };
+        Ok(self
+            .options
+            .renames
+            .diff_trees(&tree_diff, Some(base), Some(side))?
+            .into_iter()
+            .filter(|change| change.kind == ChangeKind::Renamed)
+            .map(|change| (change.old_path, change.new_path))
+            .collect())
+    }
+
+    // Merge two versions of a file of the same type against their base, which is missing for
+    // files added on both sides. Returns the merged file and whether it merged cleanly.
+    fn merge_files(&mut self, path: &str, versions: &Versions, paths: &[String; 3], extra_marker_size: usize) -> anyhow::Result<(TreeEntry, bool)> {
+        let (Some(ours), Some(theirs)) = (&versions[OURS], &versions[THEIRS]) else {
+            anyhow::bail!("cannot merge {} without both sides", path);
+        };
+        let base_mode = versions[BASE].as_ref().map(|x| x.mode).unwrap_or(0);
+        let base_hash = versions[BASE].as_ref().map(|x| x.sha_hash.as_str());
+        let mut clean = true;
+
+        let mode = if ours.mode == theirs.mode || ours.mode == base_mode {
+            theirs.mode
+        } else {
+            clean = theirs.mode == base_mode;
+            ours.mode
+        };
+
+        let sha_hash = if ours.sha_hash == theirs.sha_hash || Some(ours.sha_hash.as_str()) == base_hash {
+            theirs.sha_hash.clone()
+        } else if Some(theirs.sha_hash.as_str()) == base_hash {
+            ours.sha_hash.clone()
+        } else if is_regular(ours.mode) {
+            let base = match base_hash {
+                Some(hash) => blob_contents(hash)?,
+                None => Vec::new(),
+            };
+            let ours_contents = blob_contents(&ours.sha_hash)?;
+            let theirs_contents = blob_contents(&theirs.sha_hash)?;
+            let hash = if is_binary(&base) || is_binary(&ours_contents) || is_binary(&theirs_contents) {
+                self.say(path, format!("warning: Cannot merge binary files: {} ({} vs. {})", path, self.label(OURS), self.label(THEIRS)));
+                // Inner merges keep the base so the conflict shows up again in the outer merge
+                match (self.options.depth, base_hash, self.options.file.favor) {
+                    (0, _, Some(Favor::Ours)) => ours.sha_hash.clone(),
+                    (0, _, Some(Favor::Theirs)) => theirs.sha_hash.clone(),
+                    (0, _, _) | (_, None, _) => {
+                        clean = false;
+                        ours.sha_hash.clone()
+                    }
+                    (_, Some(hash), _) => {
+                        clean = false;
+                        hash.to_string()
+                    }
+                }
+            } else {
+                let labels: Vec<String> = if paths[BASE] == paths[OURS] && paths[BASE] == paths[THEIRS] {
+                    self.options.labels.to_vec()
+                } else {
+                    (0..3).map(|side| format!("{}:{}", self.options.labels[side], paths[side])).collect()
+                };
+                let mut file = self.options.file;
+                file.marker_size += extra_marker_size;
+                let labels = [Some(labels[BASE].as_str()), Some(labels[OURS].as_str()), Some(labels[THEIRS].as_str())];
+                let (merged, conflicts) = merge_file(&base, &ours_contents, &theirs_contents, labels, &file);
+                clean &= conflicts == 0;
+                write_object_to_store(&merged, b"blob")
+            };
+            self.say(path, format!("Auto-merging {}", path));
+            hash
+        } else {
+            // Symlinks and submodules cannot be merged line by line
+            clean = false;
+            match (self.options.depth, base_hash) {
+                (0, _) | (_, None) => ours.sha_hash.clone(),
+                (_, Some(hash)) => hash.to_string(),
+            }
+        };
+        Ok((TreeEntry { mode, sha_hash }, clean))
+    }
+
+    // Carry each side's renames over to the records: the base and unrenamed side's versions
+    // move to the new path, so the path is merged there. Renames that clash with something on
+    // the other side are settled here.
+    fn apply_renames(&mut self, records: &mut BTreeMap<String, Record>, trees: &[BTreeMap<String, TreeEntry>; 3], renames: &[Vec<(String, String)>; 3]) -> anyhow::Result<()> {
+        for side in [OURS, THEIRS] {
+            let other = OURS + THEIRS - side;
+            for (from, to) in &renames[side] {
+                let other_rename = renames[other].iter().find(|(source, _)| source == from).map(|(_, target)| target.clone());
+                let base_version = trees[BASE].get(from).cloned();
+                match other_rename {
+                    // Renames on both sides are handled once, from our side
+                    Some(_) if side == THEIRS => {}
+                    Some(other_to) if other_to == *to => {
+                        let record = records.entry(to.clone()).or_insert_with(|| Record::at(to));
+                        record.versions[BASE] = base_version;
+                        record.paths[BASE] = from.clone();
+                        records.remove(from);
+                    }
+                    Some(other_to) => {
+                        // Renamed to different names on each side: the contents are merged
+                        // and left at both names, with the original path unmerged
+                        let mut versions: Versions = Default::default();
+                        versions[BASE] = base_version.clone();
+                        versions[OURS] = trees[OURS].get(to).cloned();
+                        versions[THEIRS] = trees[THEIRS].get(&other_to).cloned();
+                        let paths = [from.clone(), to.clone(), other_to.clone()];
+                        let (merged, _) = self.merge_files(from, &versions, &paths, 1 + 2 * self.options.depth)?;
+                        self.say(
+                            from,
+                            format!(
+                                "CONFLICT (rename/rename): {} renamed to {} in {} and to {} in {}.",
+                                from,
+                                to,
+                                self.label(OURS),
+                                other_to,
+                                self.label(THEIRS)
+                            ),
+                        );
+                        let mut source = Record::at(from);
+                        source.outcome = Some(Outcome::conflict(None, [base_version, None, None]));
+                        records.insert(from.clone(), source);
+                        for (target, target_side) in [(to, OURS), (&other_to, THEIRS)] {
+                            let mut stages: Versions = Default::default();
+                            let mode = versions[target_side].as_ref().map(|x| x.mode).unwrap_or(merged.mode);
+                            let entry = TreeEntry { mode, sha_hash: merged.sha_hash.clone() };
+                            stages[target_side] = Some(entry.clone());
+                            let record = records.entry(target.clone()).or_insert_with(|| Record::at(target));
+                            record.outcome = Some(Outcome::conflict(Some(entry), stages));
+                        }
+                    }
+                    None if !trees[other].contains_key(from) => {
+                        self.say(
+                            to,
+                            format!("CONFLICT (rename/delete): {} renamed to {} in {}, but deleted in {}.", from, to, self.label(side), self.label(other)),
+                        );
+                        let renamed = trees[side].get(to).cloned();
+                        let mut stages: Versions = Default::default();
+                        stages[BASE] = base_version;
+                        stages[side] = renamed.clone();
+                        let record = records.entry(to.clone()).or_insert_with(|| Record::at(to));
+                        record.outcome = Some(Outcome::conflict(renamed, stages));
+                        records.remove(from);
+                    }
+                    None if trees[other].contains_key(to) => {
+                        // The other side added a file where this one renamed to: the renamed
+                        // file takes the other side's changes, then meets the added file as an
+                        // add/add conflict
+                        let mut versions: Versions = Default::default();
+                        versions[BASE] = base_version;
+                        versions[side] = trees[side].get(to).cloned();
+                        versions[other] = trees[other].get(from).cloned();
+                        let mut paths = [from.clone(), from.clone(), from.clone()];
+                        paths[side] = to.clone();
+                        let (merged, clean) = self.merge_files(from, &versions, &paths, 1 + 2 * self.options.depth)?;
+                        if !clean {
+                            self.say(from, format!("CONFLICT (content): Merge conflict in {}", from));
+                        }
+                        let record = records.entry(to.clone()).or_insert_with(|| Record::at(to));
+                        record.versions[BASE] = None;
+                        record.versions[side] = Some(merged);
+                        records.remove(from);
+                    }
+                    None => {
+                        let record = records.entry(to.clone()).or_insert_with(|| Record::at(to));
+                        record.versions[BASE] = base_version;
+                        record.versions[other] = trees[other].get(from).cloned();
+                        record.paths[BASE] = from.clone();
+                        record.paths[other] = from.clone();
+                        records.remove(from);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Settle a path from its three versions
+    fn resolve(&mut self, path: &str, record: &Record) -> anyhow::Result<Vec<(String, Outcome)>> {
+        let [base, ours, theirs] = &record.versions;
+        if ours == theirs || base == ours {
+            return Ok(vec![(path.to_string(), Outcome::clean(theirs.clone()))]);
+        }
+        if base == theirs {
+            return Ok(vec![(path.to_string(), Outcome::clean(ours.clone()))]);
+        }
+        let outcome = match (ours, theirs) {
+            (Some(ours), Some(theirs)) if !same_type(ours.mode, theirs.mode) => return self.resolve_distinct_types(path, record),
+            (Some(_), Some(_)) => {
+                let (merged, clean) = self.merge_files(path, &record.versions, &record.paths, 2 * self.options.depth)?;
+                if clean {
+                    Outcome::clean(Some(merged))
+                } else {
+                    let reason = match base {
+                        _ if merged.mode == 0o160000 => "submodule",
+                        None => "add/add",
+                        Some(_) => "content",
+                    };
+                    self.say(path, format!("CONFLICT ({}): Merge conflict in {}", reason, path));
+                    Outcome::conflict(Some(merged), record.versions.clone())
+                }
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                let (modified, deleted) = if ours.is_some() { (OURS, THEIRS) } else { (THEIRS, OURS) };
+                self.say(
+                    path,
+                    format!(
+                        "CONFLICT (modify/delete): {} deleted in {} and modified in {}.  Version {} of {} left in tree.",
+                        path,
+                        self.label(deleted),
+                        self.label(modified),
+                        self.label(modified),
+                        path
+                    ),
+                );
+                let kept = if self.options.depth == 0 { modified } else { BASE };
+                Outcome::conflict(record.versions[kept].clone(), record.versions.clone())
+            }
+            (None, None) => Outcome::clean(None),
+        };
+        Ok(vec![(path.to_string(), outcome)])
+    }
+
+    // A file on one side and a symlink or submodule on the other cannot share a path, so the
+    // regular file moves aside, or both do when neither is one. Inner merges keep the base.
+    fn resolve_distinct_types(&mut self, path: &str, record: &Record) -> anyhow::Result<Vec<(String, Outcome)>> {
+        let [base, ours, theirs] = &record.versions;
+        let (Some(ours), Some(theirs)) = (ours, theirs) else {
+            anyhow::bail!("{} is not on both sides", path);
+        };
+        if self.options.depth > 0 {
+            return Ok(vec![(path.to_string(), Outcome::conflict(base.clone(), Default::default()))]);
+        }
+        let (move_ours, move_theirs) = if is_regular(ours.mode) {
+            (true, false)
+        } else if is_regular(theirs.mode) {
+            (false, true)
+        } else {
+            (true, true)
+        };
+        let how = if move_ours && move_theirs { "both" } else { "one" };
+        self.say(
+            path,
+            format!("CONFLICT (distinct types): {} had different types on each side; renamed {} of them so each can be recorded somewhere.", path, how),
+        );
+
+        let taken = HashSet::new();
+        let mut outcomes = Vec::new();
+        for (side, entry, moved) in [(OURS, ours, move_ours), (THEIRS, theirs, move_theirs)] {
+            let mut stages: Versions = Default::default();
+            stages[side] = Some(entry.clone());
+            if base.as_ref().map(|base| same_type(base.mode, entry.mode)).unwrap_or(false) {
+                stages[BASE] = base.clone();
+            }
+            let target = if moved { unique_path(path, self.label(side), &taken) } else { path.to_string() };
+            outcomes.push((target, Outcome::conflict(Some(entry.clone()), stages)));
+        }
+        Ok(outcomes)
+    }
+
+    fn merge(&mut self, base: Option<&str>, ours: &str, theirs: &str) -> anyhow::Result<TreeMerge> {
+        let trees = [
+            match base {
+                Some(base) => flatten_tree(base)?,
+                None => BTreeMap::new(),
+            },
+            flatten_tree(ours)?,
+            flatten_tree(theirs)?,
+        ];
+        let mut records: BTreeMap<String, Record> = BTreeMap::new();
+        for (side, tree) in trees.iter().enumerate() {
+            for (path, entry) in tree {
+                records.entry(path.clone()).or_insert_with(|| Record::at(path)).versions[side] = Some(entry.clone());
+            }
+        }
+        let renames = [Vec::new(), self.side_renames(base, ours)?, self.side_renames(base, theirs)?];
+        self.apply_renames(&mut records, &trees, &renames)?;
+
+        // Deepest paths come first, so by the time a file is reached it is known whether a
+        // directory of the same name still has anything in it
+        let taken: HashSet<String> = records.keys().cloned().collect();
+        let mut occupied: HashSet<String> = HashSet::new();
+        let mut outcomes: BTreeMap<String, Outcome> = BTreeMap::new();
+        for (path, record) in records.iter().rev() {
+            let resolved = match &record.outcome {
+                Some(outcome) => vec![(path.clone(), outcome.clone())],
+                None => self.resolve_path(path, record, &trees, &occupied, &taken)?,
+            };
+            for (path, outcome) in resolved {
+                if outcome.result.is_some() || outcome.stages.iter().any(|x| x.is_some()) {
+                    let mut directory = path.as_str();
+                    while let Some((parent, _)) = directory.rsplit_once('/') {
+                        occupied.insert(parent.to_string());
+                        directory = parent;
+                    }
+                }
+                outcomes.insert(path, outcome);
+            }
+        }
+
+        let mut merge = TreeMerge {
+            entries: BTreeMap::new(),
+            conflicts: BTreeMap::new(),
+            messages: self.messages.values().flatten().cloned().collect(),
+            clean: true,
+        };
+        for (path, outcome) in outcomes {
+            merge.clean &= outcome.clean;
+            if let Some(result) = outcome.result {
+                merge.entries.insert(path.clone(), result);
+            }
+            if !outcome.clean && outcome.stages.iter().any(|x| x.is_some()) {
+                merge.conflicts.insert(path, outcome.stages);
+            }
+        }
+        Ok(merge)
+    }
+
+    // Settle a path, first moving a file aside when a directory of the same name stays
+    fn resolve_path(
+        &mut self,
+        path: &str,
+        record: &Record,
+        trees: &[BTreeMap<String, TreeEntry>; 3],
+        occupied: &HashSet<String>,
+        taken: &HashSet<String>,
+    ) -> anyhow::Result<Vec<(String, Outcome)>> {
+        let [base, ours, theirs] = &record.versions;
+        let deleted = (ours == theirs || base == ours) && theirs.is_none() || base == theirs && ours.is_none();
+        if !occupied.contains(path) || deleted {
+            return self.resolve(path, record);
+        }
+
+        let prefix = format!("{}/", path);
+        let ours_has_directory = trees[OURS].range(prefix.clone()..).next().map(|(x, _)| x.starts_with(&prefix)).unwrap_or(false);
+        let side = if ours_has_directory { THEIRS } else { OURS };
+        let moved = unique_path(path, self.label(side), taken);
+        self.say(
+            &moved,
+            format!("CONFLICT (file/directory): directory in the way of {} from {}; moving it to {} instead.", path, self.label(side), moved),
+        );
+        let mut resolved = self.resolve(&moved, record)?;
+        for (_, outcome) in resolved.iter_mut() {
+            if outcome.clean {
+                *outcome = Outcome::conflict(outcome.result.clone(), record.versions.clone());
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+// Merge two trees against a base tree, or against nothing when the histories share no commit
+pub fn merge_trees(base: Option<&str>, ours: &str, theirs: &str, options: &MergeOptions) -> anyhow::Result<TreeMerge> {
+    let mut merger = Merger { options, messages: BTreeMap::new() };
+    merger.merge(base, ours, theirs)
+}
+
+// A side of a merge of commits: its tree and the commits it stands for, several for the
+// virtual commit made by merging merge bases
+struct Head {
+    tree: String,
+    commits: Vec<String>,
+}
+
+// Merge the bases of a criss-cross merge one by one, oldest first, into a virtual ancestor,
+// returning its tree and the label conflict markers use for it
+fn merge_base_tree(bases: &[String], options: &MergeOptions) -> anyhow::Result<(Option<String>, String)> {
+    let Some((oldest, rest)) = bases.split_last() else {
+        return Ok((None, "empty tree".to_string()));
+    };
+    if rest.is_empty() {
+        return Ok((Some(peel_to_tree(oldest)?), abbreviate(oldest)));
+    }
+    let mut merged = Head { tree: peel_to_tree(oldest)?, commits: vec![oldest.clone()] };
+    for next in rest.iter().rev() {
+        let inner = MergeOptions {
+            labels: [String::new(), "Temporary merge branch 1".to_string(), "Temporary merge branch 2".to_string()],
+            renames: options.renames.clone(),
+            file: MergeFileOptions { favor: None, ..options.file },
+            depth: options.depth + 1,
+        };
+        let theirs = Head { tree: peel_to_tree(next)?, commits: vec![next.clone()] };
+        let result = merge_heads(&merged, &theirs, inner)?;
+        merged.tree = result.tree();
+        merged.commits.push(next.clone());
+    }
+    Ok((Some(merged.tree), "merged common ancestors".to_string()))
+}
+
+fn merge_heads(ours: &Head, theirs: &Head, mut options: MergeOptions) -> anyhow::Result<TreeMerge> {
+    let bases = merge_bases_many(&theirs.commits[0], &ours.commits)?;
+    let (base_tree, base_label) = merge_base_tree(&bases, &options)?;
+    options.labels[BASE] = base_label;
+    merge_trees(base_tree.as_deref(), &ours.tree, &theirs.tree, &options)
+}
+
+// Merge two commits, first merging their merge bases when there are several
+pub fn merge_commits(ours: &str, theirs: &str, options: MergeOptions) -> anyhow::Result<TreeMerge> {
+    let ours = Head { tree: peel_to_tree(ours)?, commits: vec![ours.to_string()] };
+    let theirs = Head { tree: peel_to_tree(theirs)?, commits: vec![theirs.to_string()] };
+    merge_heads(&ours, &theirs, options)
+}
+
+// Update the index and working tree to a merge result: files that merged are checked out as
+// in the merged tree, then conflicted paths get their stages in place of a merged entry
+pub fn apply_merge(old_tree: Option<&str>, merge: &TreeMerge) -> anyhow::Result<()> {
+    switch_tree(old_tree, Some(&merge.tree()), false, "merge", "merge")?;
+    if merge.conflicts.is_empty() {
+        return Ok(());
+    }
+    let mut index = read_index()?;
+    index.retain(|entry| !merge.conflicts.contains_key(&entry.path));
+    for (path, stages) in &merge.conflicts {
+        for (stage, version) in stages.iter().enumerate() {
+            if let Some(version) = version {
+                let mut entry = IndexEntry::without_stat(path, version.mode, &version.sha_hash);
+                entry.flags = ((stage + 1) as u16) << 12;
+                index.push(entry);
+            }
+        }
+    }
+    write_index(&index)
+}
+
+// The line a merge commit's message starts with, naming what was merged the way
+// git fmt-merge-msg does
+fn merge_message(name: &str) -> String {
+    let (base, suffix) = match name.find(['~', '^']) {
+        Some(position) => name.split_at(position),
+        None => (name, ""),
+    };
+    let early_part = !matches!(suffix, "" | "~0" | "^0");
+    let refname = expand_ref_name(base).filter(|refname| resolve_ref(refname).and_then(|hash| peel_to_commit(&hash).ok()).is_some());
+    let mut message = match refname.as_deref() {
+        Some(refname) if refname.starts_with("refs/heads/") => format!("Merge branch '{}'", shorten_ref_name(refname)),
+        Some(refname) if refname.starts_with("refs/tags/") && !early_part => format!("Merge tag '{}'", shorten_ref_name(refname)),
+        Some(refname) if refname.starts_with("refs/remotes/") => format!("Merge remote-tracking branch '{}'", shorten_ref_name(refname)),
+        _ => format!("Merge commit '{}'", name),
+    };
+    if early_part && refname.as_deref().map(|x| x.starts_with("refs/heads/")).unwrap_or(false) {
+        message.push_str(" (early part)");
+    }
+    if let Some(branch) = head_symbolic_target() {
+        let branch = shorten_ref_name(&branch);
+        if branch != "main" && branch != "master" {
+            message.push_str(&format!(" into {}", branch));
+        }
+    }
+    message
+}
+
+// Print the diffstat and summary of what a merge or fast-forward brought in
+fn print_diffstat(config: &Config, old_tree: Option<&str>, new_tree: &str) -> anyhow::Result<()> {
+    let mut options = DiffOptions::new(config)?;
+    options.stat = true;
+    options.summary = true;
+    options.renames = Renames::new(config);
+    options.renames.detect = crate::rename::Detect::Renames;
+    let tree_diff = TreeDiff { recursive: true, ..Default::default() };
+    let changes = options.renames.diff_trees(&tree_diff, old_tree, Some(new_tree))?;
+    let output = options.render(&changes)?;
+    std::io::stdout().write_all(&output)?;
+    Ok(())
+}
+
+// Remove the files that record a merge in progress
+pub fn remove_merge_state() {
+    for name in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+        let _ = fs::remove_file(format!(".git/{}", name));
+    }
+}
+
+// Point HEAD, or the branch it is on, at a new commit
+pub fn update_head(commit: &str, message: &str) -> anyhow::Result<()> {
+    match head_symbolic_target() {
+        Some(branch) => update_ref(&branch, commit, message),
+        None => update_ref("HEAD", commit, message),
+    }
+}
+
+// Drop comment lines and surplus blank lines from a message being committed
+pub fn cleanup_message(message: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in message.lines().filter(|line| !line.starts_with('#')) {
+        let line = line.trim_end();
+        if line.is_empty() && lines.last().map(|x| x.is_empty()).unwrap_or(true) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().map(|x| x.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+    match lines.is_empty() {
+        true => String::new(),
+        false => format!("{}\n", lines.join("\n")),
+    }
+}
+
+// Paths with conflicts staged in the index, each once and in order
+pub fn unmerged_paths() -> anyhow::Result<BTreeSet<String>> {
+    Ok(read_index()?.into_iter().filter(|entry| entry.stage() != 0).map(|entry| entry.path).collect())
+}
+
+// Record the merge once its conflicts are resolved, with the message left in MERGE_MSG
+fn continue_merge(config: &Config) -> anyhow::Result<()> {
+    let Some(merge_head) = read_ref("MERGE_HEAD") else {
+        anyhow::bail!("There is no merge in progress (MERGE_HEAD missing).");
+    };
+    let unmerged = unmerged_paths()?;
+    if !unmerged.is_empty() {
+        eprintln!("error: Committing is not possible because you have unmerged files.");
+        eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+        eprintln!("hint: as appropriate to mark resolution and make a commit.");
+        for path in unmerged {
+            println!("U\t{}", path);
+        }
+        anyhow::bail!("Exiting because of an unresolved conflict.");
+    }
+    let head = resolve_ref("HEAD").ok_or_else(|| anyhow::anyhow!("HEAD does not point to a commit"))?;
+    let entries: BTreeMap<String, TreeEntry> = read_index()?
+        .into_iter()
+        .map(|entry| (entry.path, TreeEntry { mode: entry.mode, sha_hash: entry.sha_hash }))
+        .collect();
+    let tree = write_tree(&entries);
+    let message = cleanup_message(&fs::read_to_string(".git/MERGE_MSG").unwrap_or_default());
+    if message.is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message.");
+    }
+    let mut parents = vec![head];
+    parents.extend(merge_head.lines().map(|x| x.to_string()));
+    let commit = write_commit(&tree, &parents, &message, config)?;
+    update_head(&commit, &format!("commit (merge): {}", message.lines().next().unwrap_or("")))?;
+    remove_merge_state();
+    let branch = head_symbolic_target().map(|x| shorten_ref_name(&x).to_string()).unwrap_or_else(|| "detached HEAD".to_string());
+    println!("[{} {}] {}", branch, abbreviate(&commit), message.lines().next().unwrap_or(""));
+    Ok(())
+}
+
+// Throw away a conflicted merge: paths the merge touched go back to HEAD, while local changes
+// to other files are kept
+fn abort_merge() -> anyhow::Result<()> {
+    if read_ref("MERGE_HEAD").is_none() {
+        anyhow::bail!("There is no merge to abort (MERGE_HEAD missing).");
+    }
+    let head = match head_tree()? {
+        Some(tree) => flatten_tree(&tree)?,
+        None => BTreeMap::new(),
+    };
+    let mut kept = Vec::new();
+    let mut touched = BTreeSet::new();
+    for entry in read_index()? {
+        let matches = entry.stage() == 0 && head.get(&entry.path).map(|x| x.mode == entry.mode && x.sha_hash == entry.sha_hash).unwrap_or(false);
+        if matches {
+            kept.push(entry);
+        } else {
+            touched.insert(entry.path);
+        }
+    }
+    let staged: HashSet<String> = kept.iter().map(|entry| entry.path.clone()).collect();
+    for path in head.keys() {
+        if !staged.contains(path) {
+            touched.insert(path.clone());
+        }
+    }
+    for path in &touched {
+        match head.get(path) {
+            Some(entry) => kept.push(checkout_file(path, entry.mode, &entry.sha_hash)?),
+            None => remove_worktree_file(path)?,
+        }
+    }
+    write_index(&kept)?;
+    remove_merge_state();
+    Ok(())
+}
+
+// How a merge may finish when HEAD is an ancestor of the merged commit
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FastForward {
+    Allow,
+    Never,
+    Only,
+}
+
+pub fn merge_command(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == "merge").unwrap();
+    let mut fast_forward = match config.get("merge.ff").map(|x| x.to_lowercase()) {
+        Some(value) if value == "only" => FastForward::Only,
+        Some(_) if config.get_bool("merge.ff") == Some(false) => FastForward::Never,
+        _ => FastForward::Allow,
+    };
+    let mut message: Option<String> = None;
+    let mut commit = true;
+    let mut quiet = false;
+    let mut stat = config.get_bool("merge.stat").unwrap_or(true);
+    let mut allow_unrelated = false;
+    let mut strategy_options = Vec::new();
+    let mut names = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "--ff" => fast_forward = FastForward::Allow,
+            "--no-ff" => fast_forward = FastForward::Never,
+            "--ff-only" => fast_forward = FastForward::Only,
+            "--commit" => commit = true,
+            "--no-commit" => commit = false,
+            "-q" | "--quiet" => quiet = true,
+            "--stat" | "--summary" => stat = true,
+            "-n" | "--no-stat" | "--no-summary" => stat = false,
+            "--edit" | "-e" | "--no-edit" | "--no-verify" | "--no-progress" | "--progress" => {}
+            "--allow-unrelated-histories" => allow_unrelated = true,
+            "--abort" => return abort_merge(),
+            "--continue" => return continue_merge(&config),
+            "-m" => {
+                index += 1;
+                message = Some(args.get(index).ok_or_else(|| anyhow::anyhow!("switch `m' requires a value"))?.clone());
+            }
+            "-s" => {
+                index += 1;
+                let strategy = args.get(index).map(|x| x.as_str()).unwrap_or("");
+                if strategy != "ort" && strategy != "recursive" {
+                    anyhow::bail!("Could not find merge strategy '{}'.", strategy);
+                }
+            }
+            "-X" => {
+                index += 1;
+                strategy_options.push(args.get(index).ok_or_else(|| anyhow::anyhow!("switch `X' requires a value"))?.clone());
+            }
+            _ if arg.starts_with("--strategy-option=") => strategy_options.push(arg["--strategy-option=".len()..].to_string()),
+            _ if arg.starts_with("-X") => strategy_options.push(arg[2..].to_string()),
+            _ if arg.starts_with("--message=") => message = Some(arg["--message=".len()..].to_string()),
+            _ if arg.starts_with("-m") => message = Some(arg[2..].to_string()),
+            _ if arg.starts_with("--strategy=") => {
+                let strategy = &arg["--strategy=".len()..];
+                if strategy != "ort" && strategy != "recursive" {
+                    anyhow::bail!("Could not find merge strategy '{}'.", strategy);
+                }
+            }
+            _ if arg.starts_with('-') => anyhow::bail!("unknown option '{}'", arg.trim_start_matches('-')),
+            _ => names.push(arg.to_string()),
+        }
+        index += 1;
+    }
+
+    if read_ref("MERGE_HEAD").is_some() {
+        anyhow::bail!("You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge.");
+    }
+    if !unmerged_paths()?.is_empty() {
+        eprintln!("error: Merging is not possible because you have unmerged files.");
+        eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+        eprintln!("hint: as appropriate to mark resolution and make a commit.");
+        anyhow::bail!("Exiting because of an unresolved conflict.");
+    }
+    let name = match names.as_slice() {
+        [] => anyhow::bail!("No remote for the current branch."),
+        [name] => name.clone(),
+        _ => anyhow::bail!("merging more than one commit at once is not supported"),
+    };
+    let Some(theirs) = resolve_revision(&name).ok().and_then(|hash| peel_to_commit(&hash).ok()) else {
+        eprintln!("merge: {} - not something we can merge", name);
+        std::process::exit(1);
+    };
+
+    // Merging into an unborn branch just checks the commit out
+    let Some(head) = resolve_ref("HEAD") else {
+        switch_tree(None, Some(&peel_to_tree(&theirs)?), false, "merge", "merge")?;
+        return update_head(&theirs, "initial pull");
+    };
+    let old_tree = peel_to_tree(&head)?;
+    write_ref("ORIG_HEAD", &head)?;
+
+    let bases = merge_bases(&head, &theirs)?;
+    if bases.is_empty() && !allow_unrelated {
+        anyhow::bail!("refusing to merge unrelated histories");
+    }
+    if bases.contains(&theirs) {
+        if !quiet {
+            println!("Already up to date.");
+        }
+        return Ok(());
+    }
+    if bases.contains(&head) && fast_forward != FastForward::Never {
+        if !quiet {
+            println!("Updating {}..{}", abbreviate(&head), abbreviate(&theirs));
+        }
+        let new_tree = peel_to_tree(&theirs)?;
+        switch_tree(Some(&old_tree), Some(&new_tree), false, "merge", "merge")?;
+        update_head(&theirs, &format!("merge {}: Fast-forward", name))?;
+        if !quiet {
+            println!("Fast-forward");
+            if stat {
+                print_diffstat(&config, Some(&old_tree), &new_tree)?;
+            }
+        }
+        return Ok(());
+    }
+    if fast_forward == FastForward::Only {
+        anyhow::bail!("Not possible to fast-forward, aborting.");
+    }
+
+    // The index has to match HEAD, since the merge result replaces it
+    let staged = crate::diff::diff_tree_to_index(&flatten_tree(&old_tree)?, &read_index()?);
+    if !staged.is_empty() {
+        let paths: Vec<&str> = staged.iter().map(|change| change.new_path.as_str()).collect();
+        eprintln!("error: Your local changes to the following files would be overwritten by merge:\n  {}", paths.join(" "));
+        eprintln!("Merge with strategy ort failed.");
+        std::process::exit(2);
+    }
+
+    let mut options = MergeOptions::new(&config, "HEAD", &name)?;
+    for option in &strategy_options {
+        options.strategy_option(option)?;
+    }
+    let merge = merge_commits(&head, &theirs, options)?;
+    if let Err(error) = apply_merge(Some(&old_tree), &merge) {
+        if let Some(conflict) = error.downcast_ref::<CheckoutConflict>() {
+            eprintln!("error: {}", conflict);
+            eprintln!("Merge with strategy ort failed.");
+            std::process::exit(2);
+        }
+        return Err(error);
+    }
+    if !quiet {
+        for line in &merge.messages {
+            println!("{}", line);
+        }
+    }
+
+    let mut merge_message = match &message {
+        Some(message) => format!("{}\n", message.trim_end()),
+        None => format!("{}\n", merge_message(&name)),
+    };
+    // Merging an annotated tag carries its message over
+    if let Ok(GitObjectType::Tag(tag)) = resolve_revision(&name).and_then(|hash| read_object_from_store(&hash)) {
+        if let Some((_, tag_message)) = tag.tag_data.split_once("\n\n") {
+            if message.is_none() && !tag_message.trim().is_empty() {
+                merge_message.push('\n');
+                merge_message.push_str(tag_message);
+            }
+        }
+    }
+    if !merge.clean {
+        merge_message.push_str("\n# Conflicts:\n");
+        for path in merge.conflicts.keys() {
+            merge_message.push_str(&format!("#\t{}\n", path));
+        }
+    }
+    // The merge state is written before committing, so a commit that fails (say for want of an
+    // identity) leaves a merge the user can still conclude or abort
+    fs::write(".git/MERGE_HEAD", format!("{}\n", theirs))?;
+    fs::write(".git/MERGE_MSG", &merge_message)?;
+    fs::write(".git/MERGE_MODE", if fast_forward == FastForward::Never { "no-ff" } else { "" })?;
+    if merge.clean && commit {
+        let commit = write_commit(&merge.tree(), &[head, theirs], &cleanup_message(&merge_message), &config)?;
+        update_head(&commit, &format!("merge {}: Merge made by the 'ort' strategy.", name))?;
+        remove_merge_state();
+        if !quiet {
+            println!("Merge made by the 'ort' strategy.");
+            if stat {
+                print_diffstat(&config, Some(&old_tree), &merge.tree())?;
+            }
+        }
+        return Ok(());
+    }
+    if merge.clean {
+        eprintln!("Automatic merge went well; stopped before committing as requested");
+        return Ok(());
+    }
+    println!("Automatic merge failed; fix conflicts and then commit the result.");
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{commit, repository, tree};
+
+    fn merge(base: &[(&str, &str)], ours: &[(&str, &str)], theirs: &[(&str, &str)]) -> TreeMerge {
+        let options = MergeOptions::new(&read_config_file(".git/config"), "ours", "theirs").unwrap();
+        merge_trees(Some(&tree(base)), &tree(ours), &tree(theirs), &options).unwrap()
+    }
+
+    fn contents(merge: &TreeMerge, path: &str) -> String {
+        String::from_utf8(blob_contents(&merge.entries[path].sha_hash).unwrap()).unwrap()
+    }
+
+    // Which sides a conflicted path has a version from
+    fn stages(merge: &TreeMerge, path: &str) -> [bool; 3] {
+        merge.conflicts[path].each_ref().map(|version| version.is_some())
+    }
+
+    #[test]
+    fn clean_merge() {
+        let _repository = repository();
+        let merged = merge(&[("a", "1\n"), ("b", "1\n")], &[("a", "2\n"), ("b", "1\n")], &[("a", "1\n"), ("b", "2\n"), ("c", "3\n")]);
+        assert!(merged.clean && merged.conflicts.is_empty());
+        assert_eq!(merged.entries.keys().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!((contents(&merged, "a"), contents(&merged, "b")), ("2\n".to_string(), "2\n".to_string()));
+    }
+
+    #[test]
+    fn content_conflict() {
+        let _repository = repository();
+        let merged = merge(&[("a", "1\n2\n3\n")], &[("a", "1\nours\n3\n")], &[("a", "1\ntheirs\n3\n")]);
+        assert!(!merged.clean);
+        assert_eq!(contents(&merged, "a"), "1\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n3\n");
+        assert_eq!(stages(&merged, "a"), [true, true, true]);
+        assert_eq!(merged.messages, ["Auto-merging a", "CONFLICT (content): Merge conflict in a"]);
+    }
+
+    #[test]
+    fn add_add_conflict() {
+        let _repository = repository();
+        let merged = merge(&[("a", "1\n")], &[("a", "1\n"), ("new", "ours\n")], &[("a", "1\n"), ("new", "theirs\n")]);
+        assert!(!merged.clean);
+        assert_eq!(contents(&merged, "new"), "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n");
+        assert_eq!(stages(&merged, "new"), [false, true, true]);
+        assert_eq!(merged.messages, ["Auto-merging new", "CONFLICT (add/add): Merge conflict in new"]);
+    }
+
+    #[test]
+    fn modify_delete_conflict() {
+        let _repository = repository();
+        let merged = merge(&[("a", "1\n"), ("b", "1\n")], &[("a", "2\n"), ("b", "1\n")], &[("b", "1\n")]);
+        assert!(!merged.clean);
+        assert_eq!(contents(&merged, "a"), "2\n");
+        assert_eq!(stages(&merged, "a"), [true, true, false]);
+        assert_eq!(merged.messages, ["CONFLICT (modify/delete): a deleted in theirs and modified in ours.  Version ours of a left in tree."]);
+    }
+
+    #[test]
+    fn directory_file_conflict() {
+        let _repository = repository();
+        let merged = merge(&[("a", "1\n")], &[("a", "1\n"), ("d", "file\n")], &[("a", "1\n"), ("d/x", "nested\n")]);
+        assert!(!merged.clean);
+        assert_eq!(merged.entries.keys().collect::<Vec<_>>(), ["a", "d/x", "d~ours"]);
+        assert_eq!(contents(&merged, "d~ours"), "file\n");
+        assert_eq!(stages(&merged, "d~ours"), [false, true, false]);
+        assert_eq!(merged.messages, ["CONFLICT (file/directory): directory in the way of d from ours; moving it to d~ours instead."]);
+    }
+
+    #[test]
+    fn messages() {
+        let _repository = repository();
+        let base = commit(&tree(&[]), &[], 1, "base");
+        let tip = commit(&tree(&[]), &[&base], 2, "tip");
+        fs::write(".git/refs/heads/topic", format!("{}\n", tip)).unwrap();
+        assert_eq!(merge_message("topic"), "Merge branch 'topic'");
+        assert_eq!(merge_message("topic~1"), "Merge branch 'topic' (early part)");
+        assert_eq!(merge_message(&base), format!("Merge commit '{}'", base));
+        fs::write(".git/HEAD", "ref: refs/heads/feature\n").unwrap();
+        assert_eq!(merge_message("topic"), "Merge branch 'topic' into feature");
+        assert_eq!(cleanup_message("  \nsubject  \n\n\n# comment\nbody\n\n"), "subject\n\nbody\n");
+    }
+}
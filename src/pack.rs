@@ -0,0 +1,385 @@
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::sync::OnceLock;
+
+// Packs found under .git/objects/pack, loaded once per process
+static PACKS: OnceLock<Vec<Pack>> = OnceLock::new();
+
+// Pack holds a pack file together with the object ids and offsets from its index
+pub struct Pack {
+    pub pack_path: String,
+    pub data: Vec<u8>,
+    pub ids: Vec<String>,
+    pub offsets: Vec<u64>,
+}
+
+impl Pack {
+    // Look up the offset of an object in this pack, ids are sorted so this is a binary search
+    pub fn find(&self, hash: &str) -> Option<u64> {
+        self.ids.binary_search_by(|id| id.as_str().cmp(hash)).ok().map(|position| self.offsets[position])
+    }
+
+    // Check the trailing checksum of the pack file against its contents
+    pub fn verify_checksum(&self) -> bool {
+        if self.data.len() < 32 {
+            return false;
+        }
+        let (contents, checksum) = self.data.split_at(self.data.len() - 20);
+        Sha1::digest(contents).as_slice() == checksum
+    }
+}
+
+pub fn packs() -> &'static [Pack] {
+    PACKS.get_or_init(load_packs)
+}
+
+fn load_packs() -> Vec<Pack> {
+    let mut packs = Vec::new();
+    let entries = match fs::read_dir(".git/objects/pack") {
+        Ok(entries) => entries,
+        Err(_) => return packs,
+    };
+    let mut index_paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .filter(|path| path.ends_with(".idx"))
+        .collect();
+    index_paths.sort();
+
+    for index_path in index_paths {
+        let pack_path = format!("{}.pack", index_path.trim_end_matches(".idx"));
+        let (index_data, data) = match (fs::read(&index_path), fs::read(&pack_path)) {
+            (Ok(index_data), Ok(data)) => (index_data, data),
+            _ => continue,
+        };
+        if let Some((ids, offsets)) = parse_pack_index(&index_data) {
+            packs.push(Pack { pack_path, data, ids, offsets });
+        }
+    }
+    packs
+}
+
+pub fn read_u32(data: &[u8], index: usize) -> Option<u32> {
+    let bytes = data.get(index..index + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Parse a version 1 or version 2 pack index into sorted object ids and their pack offsets
+fn parse_pack_index(data: &[u8]) -> Option<(Vec<String>, Vec<u64>)> {
+    let mut ids = Vec::new();
+    let mut offsets = Vec::new();
+
+    if data.starts_with(b"\xfftOc") {
+        if read_u32(data, 4)? != 2 {
+            return None;
+        }
+        let count = read_u32(data, 8 + 255 * 4)? as usize;
+        let ids_start = 8 + 256 * 4;
+        let offsets_start = ids_start + count * 24;
+        let large_offsets_start = offsets_start + count * 4;
+        for position in 0..count {
+            let id = data.get(ids_start + position * 20..ids_start + position * 20 + 20)?;
+            ids.push(hex::encode(id));
+            let offset = read_u32(data, offsets_start + position * 4)?;
+            if offset & 0x8000_0000 == 0 {
+                offsets.push(offset as u64);
+            } else {
+                let large_index = large_offsets_start + (offset & 0x7fff_ffff) as usize * 8;
+                let high = read_u32(data, large_index)? as u64;
+                let low = read_u32(data, large_index + 4)? as u64;
+                offsets.push((high << 32) | low);
+            }
+        }
+    } else {
+        let count = read_u32(data, 255 * 4)? as usize;
+        let entries_start = 256 * 4;
+        for position in 0..count {
+            let entry = entries_start + position * 24;
+            offsets.push(read_u32(data, entry)? as u64);
+            ids.push(hex::encode(data.get(entry + 4..entry + 24)?));
+        }
+    }
+    Some((ids, offsets))
+}
+
+// Read an object from whichever pack contains it
+pub fn read_packed_object(hash: &str) -> anyhow::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    for pack in packs() {
+        if let Some(offset) = pack.find(hash) {
+            let mut cache = HashMap::new();
+            return read_object_at(pack, offset, &mut cache).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+// Read and fully resolve the object stored at an offset in a pack.
+// Resolved delta bases are kept in the cache so callers walking a whole pack avoid re-inflating them.
+pub fn read_object_at(
+    pack: &Pack,
+    offset: u64,
+    cache: &mut HashMap<u64, (Vec<u8>, Vec<u8>)>,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    resolve_object_at(pack, offset, cache, &mut Vec::new())
+}
+
+// Resolve the object at an offset. resolving holds the pack and offset of every delta whose
+// base is still being read, so a corrupt pack whose deltas form a cycle is an error rather
+// than endless recursion.
+fn resolve_object_at(
+    pack: &Pack,
+    offset: u64,
+    cache: &mut HashMap<u64, (Vec<u8>, Vec<u8>)>,
+    resolving: &mut Vec<(String, u64)>,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if let Some(object) = cache.get(&offset) {
+        return Ok(object.clone());
+    }
+    if resolving.iter().any(|(path, at)| *path == pack.pack_path && *at == offset) {
+        anyhow::bail!("delta chain at offset {} in {} refers back to itself", offset, pack.pack_path);
+    }
+
+    let data = &pack.data;
+    let mut index = offset as usize;
+    let mut byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("offset {} is past the end of {}", offset, pack.pack_path))?;
+    index += 1;
+    let object_type = (byte >> 4) & 7;
+    let mut size = (byte & 0x0f) as usize;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("truncated object header in {}", pack.pack_path))?;
+        index += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+
+    let object = match object_type {
+        1..=4 => {
+            let kind: &[u8] = match object_type {
+                1 => b"commit",
+                2 => b"tree",
+                3 => b"blob",
+                _ => b"tag",
+            };
+            (kind.to_vec(), inflate(&data[index..], size)?)
+        }
+        6 => {
+            let mut byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("truncated delta offset"))?;
+            index += 1;
+            let mut distance = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = *data.get(index).ok_or_else(|| anyhow::anyhow!("truncated delta offset"))?;
+                index += 1;
+                distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+            }
+            // The base must come before the delta, a distance of zero would make it its own base
+            let base_offset = offset
+                .checked_sub(distance)
+                .filter(|_| distance > 0)
+                .ok_or_else(|| anyhow::anyhow!("delta base offset out of bounds in {}", pack.pack_path))?;
+            resolving.push((pack.pack_path.clone(), offset));
+            let base = resolve_object_at(pack, base_offset, cache, resolving);
+            resolving.pop();
+            let (kind, base) = base?;
+            let delta = inflate(&data[index..], size)?;
+            (kind, apply_delta(&base, &delta)?)
+        }
+        7 => {
+            let base_hash = hex::encode(data.get(index..index + 20).ok_or_else(|| anyhow::anyhow!("truncated delta base"))?);
+            index += 20;
+            resolving.push((pack.pack_path.clone(), offset));
+            let base = read_delta_base(pack, &base_hash, cache, resolving);
+            resolving.pop();
+            let (kind, base) = base?;
+            let delta = inflate(&data[index..], size)?;
+            (kind, apply_delta(&base, &delta)?)
+        }
+        _ => anyhow::bail!("unknown object type {} at offset {} in {}", object_type, offset, pack.pack_path),
+    };
+
+    cache.insert(offset, object.clone());
+    Ok(object)
+}
+
+// Read the base of a ref-delta from the same pack, another pack, or the loose objects
+fn read_delta_base(
+    pack: &Pack,
+    hash: &str,
+    cache: &mut HashMap<u64, (Vec<u8>, Vec<u8>)>,
+    resolving: &mut Vec<(String, u64)>,
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if let Some(offset) = pack.find(hash) {
+        return resolve_object_at(pack, offset, cache, resolving);
+    }
+    for other in packs() {
+        if let Some(offset) = other.find(hash) {
+            return resolve_object_at(other, offset, &mut HashMap::new(), resolving);
+        }
+    }
+    crate::read_raw_object(hash)
+}
+
+fn inflate(data: &[u8], size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut result = Vec::with_capacity(size);
+    decoder.read_to_end(&mut result)?;
+    if result.len() != size {
+        anyhow::bail!("inflated size {} does not match expected size {}", result.len(), size);
+    }
+    Ok(result)
+}
+
+fn read_delta_size(delta: &[u8], index: &mut usize) -> anyhow::Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*index).ok_or_else(|| anyhow::anyhow!("truncated delta header"))?;
+        *index += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+// Rebuild an object from its base and a copy/insert delta
+fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut index = 0;
+    let base_size = read_delta_size(delta, &mut index)?;
+    let result_size = read_delta_size(delta, &mut index)?;
+    if base_size != base.len() {
+        anyhow::bail!("delta base size {} does not match actual size {}", base_size, base.len());
+    }
+
+    let mut result = Vec::with_capacity(result_size);
+    while index < delta.len() {
+        let instruction = delta[index];
+        index += 1;
+        if instruction & 0x80 != 0 {
+            let mut copy_offset = 0usize;
+            let mut copy_size = 0usize;
+            for bit in 0..4 {
+                if instruction & (1 << bit) != 0 {
+                    copy_offset |= (*delta.get(index).ok_or_else(|| anyhow::anyhow!("truncated delta"))? as usize) << (bit * 8);
+                    index += 1;
+                }
+            }
+            for bit in 0..3 {
+                if instruction & (0x10 << bit) != 0 {
+                    copy_size |= (*delta.get(index).ok_or_else(|| anyhow::anyhow!("truncated delta"))? as usize) << (bit * 8);
+                    index += 1;
+                }
+            }
+            if copy_size == 0 {
+                copy_size = 0x10000;
+            }
+            let chunk = base
+                .get(copy_offset..copy_offset + copy_size)
+                .ok_or_else(|| anyhow::anyhow!("delta copies past the end of its base"))?;
+            result.extend_from_slice(chunk);
+        } else if instruction != 0 {
+            let chunk = delta
+                .get(index..index + instruction as usize)
+                .ok_or_else(|| anyhow::anyhow!("truncated delta"))?;
+            result.extend_from_slice(chunk);
+            index += instruction as usize;
+        } else {
+            anyhow::bail!("unexpected delta opcode 0");
+        }
+    }
+
+    if result.len() != result_size {
+        anyhow::bail!("delta result size {} does not match expected size {}", result.len(), result_size);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // An entry header: the type in bits 4-6 of the first byte and the size in little-endian groups
+    fn header(object_type: u8, size: usize) -> Vec<u8> {
+        let mut bytes = vec![(object_type << 4) | (size & 0x0f) as u8];
+        let mut size = size >> 4;
+        while size > 0 {
+            *bytes.last_mut().unwrap() |= 0x80;
+            bytes.push((size & 0x7f) as u8);
+            size >>= 7;
+        }
+        bytes
+    }
+
+    // A delta that keeps the whole base and appends the text
+    fn append_delta(base_size: usize, text: &[u8]) -> Vec<u8> {
+        let mut delta = vec![base_size as u8, (base_size + text.len()) as u8];
+        delta.extend_from_slice(&[0x90, base_size as u8]);
+        delta.push(text.len() as u8);
+        delta.extend_from_slice(text);
+        delta
+    }
+
+    fn pack(data: Vec<u8>, objects: &[(&str, u64)]) -> Pack {
+        Pack {
+            pack_path: "test.pack".to_string(),
+            data,
+            ids: objects.iter().map(|(id, _)| id.to_string()).collect(),
+            offsets: objects.iter().map(|(_, offset)| *offset).collect(),
+        }
+    }
+
+    #[test]
+    fn offset_deltas() {
+        let mut data = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        data.extend(header(3, 5));
+        data.extend(deflate(b"hello"));
+        let delta_offset = data.len() as u64;
+        let delta = append_delta(5, b" world");
+        data.extend(header(6, delta.len()));
+        data.push((delta_offset - 12) as u8);
+        data.extend(deflate(&delta));
+        let pack = pack(data, &[]);
+        let (kind, contents) = read_object_at(&pack, delta_offset, &mut HashMap::new()).unwrap();
+        assert_eq!((kind.as_slice(), contents.as_slice()), (&b"blob"[..], &b"hello world"[..]));
+    }
+
+    #[test]
+    fn offset_delta_based_on_itself() {
+        let mut data = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        let delta = append_delta(5, b"!");
+        data.extend(header(6, delta.len()));
+        data.push(0);
+        data.extend(deflate(&delta));
+        let error = read_object_at(&pack(data, &[]), 12, &mut HashMap::new()).unwrap_err();
+        assert_eq!(error.to_string(), "delta base offset out of bounds in test.pack");
+    }
+
+    #[test]
+    fn ref_delta_cycles() {
+        let (a, b) = ("aa".repeat(20), "bb".repeat(20));
+        let mut data = b"PACK\0\0\0\x02\0\0\0\x02".to_vec();
+        let mut offsets = Vec::new();
+        for base in [&b, &a] {
+            offsets.push(data.len() as u64);
+            let delta = append_delta(5, b"!");
+            data.extend(header(7, delta.len()));
+            data.extend(hex::decode(base).unwrap());
+            data.extend(deflate(&delta));
+        }
+        let pack = pack(data, &[(&a, offsets[0]), (&b, offsets[1])]);
+        let error = read_object_at(&pack, offsets[0], &mut HashMap::new()).unwrap_err();
+        assert_eq!(error.to_string(), format!("delta chain at offset {} in test.pack refers back to itself", offsets[0]));
+    }
+}
@@ -0,0 +1,1155 @@
+// Patch output for lists of changed files: unified diffs, diffstats, summaries and word diffs,
+// plus the diff porcelain built on them.
+
+use crate::combine::conflict_path;
+use crate::config::{read_config, Config};
+use crate::diff::{diff_index_to_worktree, diff_tree_to_index, diff_tree_to_worktree, quote_path, unmerged_change, ChangeKind, ChangesFormat, ChangesOutput, FileChange, TreeDiff, NULL_HASH};
+use crate::index::{read_index, IndexEntry};
+use crate::refs::resolve_ref;
+use crate::rename::{unchanged_files, Renames};
+use crate::revision::{ambiguous_argument, peel_to_tree, resolve_revision};
+use crate::walk::{abbreviate_to, Commit};
+use crate::worktree::{flatten_tree, pathspec_matches, read_worktree_contents};
+use crate::xdiff::{diff_lines, split_lines, Algorithm, Change};
+use crate::{hash_object_contents, read_object_from_store, GitObjectType};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Contents are treated as binary when a NUL shows up this early
+const FIRST_FEW_BYTES: usize = 8000;
+
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(FIRST_FEW_BYTES)].contains(&0)
+}
+
+// DiffOptions are the output choices shared by diff, log and show
+#[derive(Clone)]
+pub struct DiffOptions {
+    pub patch: bool,
+    pub raw: bool,
+    pub name_only: bool,
+    pub name_status: bool,
+    pub stat: bool,
+    pub numstat: bool,
+    pub shortstat: bool,
+    pub summary: bool,
+    pub no_output: bool,
+    pub context: usize,
+    pub inter_hunk_context: usize,
+    pub algorithm: Algorithm,
+    pub indent_heuristic: bool,
+    pub word_diff: bool,
+    pub text: bool,
+    pub reverse: bool,
+    pub full_index: bool,
+    pub abbrev: Option<usize>,
+    pub nul: bool,
+    pub exit_code: bool,
+    pub quiet: bool,
+    pub stat_width: Option<usize>,
+    pub stat_name_width: Option<usize>,
+    pub stat_count: Option<usize>,
+    pub renames: Renames,
+}
+
+fn parse_number(option: &str, value: &str) -> anyhow::Result<usize> {
+    value.parse().map_err(|_| anyhow::anyhow!("{} expects a numerical value", option))
+}
+
+impl DiffOptions {
+    // Defaults taken from the diff.* configuration
+    pub fn new(config: &Config) -> anyhow::Result<DiffOptions> {
+        let number = |key: &str, default: usize| -> anyhow::Result<usize> {
+            match config.get(key) {
+                Some(value) => value.parse().map_err(|_| anyhow::anyhow!("bad numeric config value '{}' for '{}'", value, key)),
+                None => Ok(default),
+            }
+        };
+        Ok(DiffOptions {
+            patch: false,
+            raw: false,
+            name_only: false,
+            name_status: false,
+            stat: false,
+            numstat: false,
+            shortstat: false,
+            summary: false,
+            no_output: false,
+            context: number("diff.context", 3)?,
+            inter_hunk_context: number("diff.interHunkContext", 0)?,
+            algorithm: config.get("diff.algorithm").map(Algorithm::parse).transpose()?.unwrap_or(Algorithm::Myers),
+            indent_heuristic: config.get_bool("diff.indentHeuristic").unwrap_or(true),
+            word_diff: false,
+            text: false,
+            reverse: false,
+            full_index: false,
+            abbrev: Some(7),
+            nul: false,
+            exit_code: false,
+            quiet: false,
+            stat_width: None,
+            stat_name_width: None,
+            stat_count: None,
+            renames: Renames::porcelain(config),
+        })
+    }
+
+    // Whether any output format was chosen, including -s for none
+    pub fn has_format(&self) -> bool {
+        self.no_output || self.shows_output()
+    }
+
+    // Whether anything is printed for the changes
+    pub fn shows_output(&self) -> bool {
+        !self.no_output && (self.patch || self.raw || self.name_only || self.name_status || self.stat || self.numstat || self.shortstat || self.summary)
+    }
+
+    // Whether the output has a diffstat and a patch, which log separates from the message with "---"
+    pub fn stat_and_patch(&self) -> bool {
+        self.stat && self.patch
+    }
+
+    // Take a diff option if arg is one, returning whether it was
+    pub fn parse_option(&mut self, arg: &str) -> anyhow::Result<bool> {
+        match arg {
+            "-p" | "-u" | "--patch" => {
+                self.patch = true;
+                self.no_output = false;
+            }
+            "-s" | "--no-patch" => self.no_output = true,
+            "--raw" => self.raw = true,
+            "--name-only" => self.name_only = true,
+            "--name-status" => self.name_status = true,
+            "--stat" => self.stat = true,
+            "--numstat" => self.numstat = true,
+            "--shortstat" => self.shortstat = true,
+            "--summary" => self.summary = true,
+            "--patch-with-stat" => {
+                self.patch = true;
+                self.stat = true;
+                self.no_output = false;
+            }
+            "--patch-with-raw" => {
+                self.patch = true;
+                self.raw = true;
+                self.no_output = false;
+            }
+            "--minimal" => self.algorithm = Algorithm::Minimal,
+            "--patience" => self.algorithm = Algorithm::Patience,
+            "--histogram" => self.algorithm = Algorithm::Histogram,
+            "--indent-heuristic" => self.indent_heuristic = true,
+            "--no-indent-heuristic" => self.indent_heuristic = false,
+            "--word-diff" => self.word_diff = true,
+            "-a" | "--text" => self.text = true,
+            "-R" => self.reverse = true,
+            "--full-index" => self.full_index = true,
+            "--abbrev" => self.abbrev = Some(7),
+            "--no-abbrev" => self.abbrev = None,
+            "-z" => self.nul = true,
+            "--exit-code" => self.exit_code = true,
+            "--quiet" => {
+                self.quiet = true;
+                self.exit_code = true;
+            }
+            "--no-color" | "--no-ext-diff" | "--no-textconv" => {}
+            _ => return self.parse_option_with_value(arg),
+        }
+        Ok(true)
+    }
+
+    fn parse_option_with_value(&mut self, arg: &str) -> anyhow::Result<bool> {
+        if let Some(count) = arg.strip_prefix("-U").or(arg.strip_prefix("--unified=")) {
+            self.context = parse_number("-U", count)?;
+            self.patch = true;
+        } else if let Some(count) = arg.strip_prefix("--inter-hunk-context=") {
+            self.inter_hunk_context = parse_number("--inter-hunk-context", count)?;
+        } else if let Some(name) = arg.strip_prefix("--diff-algorithm=") {
+            self.algorithm = Algorithm::parse(name)?;
+        } else if let Some(mode) = arg.strip_prefix("--word-diff=") {
+            match mode {
+                "plain" => self.word_diff = true,
+                "none" => self.word_diff = false,
+                _ => anyhow::bail!("bad --word-diff argument: {}", mode),
+            }
+        } else if let Some(length) = arg.strip_prefix("--abbrev=") {
+            self.abbrev = Some(parse_number("--abbrev", length)?);
+        } else if let Some(values) = arg.strip_prefix("--stat=") {
+            // --stat=<width>[,<name-width>[,<count>]]
+            let mut values = values.split(',');
+            self.stat_width = values.next().filter(|x| !x.is_empty()).map(|x| parse_number("--stat", x)).transpose()?;
+            self.stat_name_width = values.next().map(|x| parse_number("--stat", x)).transpose()?;
+            self.stat_count = values.next().map(|x| parse_number("--stat", x)).transpose()?;
+            self.stat = true;
+        } else if let Some(width) = arg.strip_prefix("--stat-width=") {
+            self.stat_width = Some(parse_number("--stat-width", width)?);
+            self.stat = true;
+        } else if let Some(width) = arg.strip_prefix("--stat-name-width=") {
+            self.stat_name_width = Some(parse_number("--stat-name-width", width)?);
+            self.stat = true;
+        } else if let Some(count) = arg.strip_prefix("--stat-count=") {
+            self.stat_count = Some(parse_number("--stat-count", count)?);
+            self.stat = true;
+        } else {
+            return self.renames.parse_option(arg);
+        }
+        Ok(true)
+    }
+
+    // Check combinations git refuses. Name lists replace every other format.
+    pub fn validate(&mut self) -> anyhow::Result<()> {
+        let exclusive = [self.name_only, self.name_status, self.no_output].iter().filter(|x| **x).count();
+        if exclusive > 1 {
+            anyhow::bail!("options '--name-only', '--name-status', '--check', and '-s' cannot be used together");
+        }
+        if self.name_only || self.name_status {
+            self.raw = false;
+            self.numstat = false;
+            self.stat = false;
+            self.shortstat = false;
+            self.summary = false;
+            self.patch = false;
+        }
+        self.renames.validate();
+        Ok(())
+    }
+
+    // Print the changes in every chosen format: name lists first, then the stats, then the
+    // patch set off by a blank line if anything came before it
+    pub fn render(&self, changes: &[FileChange]) -> anyhow::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        if self.quiet || self.no_output || changes.is_empty() {
+            return Ok(output);
+        }
+        let changes: Vec<FileChange> = match self.reverse {
+            true => changes.iter().map(reverse_change).collect(),
+            false => changes.to_vec(),
+        };
+        let mut separator = false;
+        if self.raw || self.name_only || self.name_status {
+            let format = if self.name_status {
+                ChangesFormat::NameStatus
+            } else if self.raw {
+                ChangesFormat::Raw
+            } else {
+                ChangesFormat::NameOnly
+            };
+            let mut printer = ChangesOutput::new();
+            printer.format = format;
+            printer.abbrev = self.abbrev;
+            printer.nul = self.nul;
+            output.extend(printer.print(&changes).into_bytes());
+            separator = true;
+        }
+
+        let needs_contents = self.patch || self.stat || self.numstat || self.shortstat;
+        let pairs = match needs_contents {
+            true => changes.iter().map(Pair::load).collect::<anyhow::Result<Vec<_>>>()?,
+            false => Vec::new(),
+        };
+        if self.stat || self.numstat || self.shortstat {
+            let stats: Vec<FileStat> = pairs.iter().map(|pair| self.file_stat(pair)).collect();
+            if self.numstat {
+                self.write_numstat(&stats, &mut output);
+            }
+            if self.stat {
+                self.write_stat(&stats, &mut output);
+            }
+            if self.shortstat {
+                write_stat_summary(&stats, &mut output);
+            }
+            separator = true;
+        }
+        if self.summary {
+            let before = output.len();
+            for change in &changes {
+                write_summary(change, &mut output);
+            }
+            separator |= output.len() > before;
+        }
+        if self.patch {
+            if separator {
+                output.push(if self.nul { b'\0' } else { b'\n' });
+            }
+            for pair in &pairs {
+                self.write_patch(pair, &mut output);
+            }
+        }
+        Ok(output)
+    }
+
+    // Exit with status 1 when asked to report whether there were differences
+    pub fn finish(&self, changed: bool) {
+        if self.exit_code && changed {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn reverse_change(change: &FileChange) -> FileChange {
+    let kind = match change.kind {
+        ChangeKind::Added => ChangeKind::Deleted,
+        ChangeKind::Deleted => ChangeKind::Added,
+        kind => kind,
+    };
+    FileChange {
+        kind,
+        old_path: change.new_path.clone(),
+        new_path: change.old_path.clone(),
+        old_mode: change.new_mode,
+        new_mode: change.old_mode,
+        old_hash: change.new_hash.clone(),
+        new_hash: change.old_hash.clone(),
+        score: change.score,
+    }
+}
+
+// Contents of one side of a change: the blob from the store, the file on disk when the id
+// is null, or the commit line git shows for submodules. Files on disk that rename detection
+// hashed have an id but no object, so they are read from disk when it matches.
+pub fn side_contents(mode: u32, hash: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+    if mode == 0 {
+        return Ok(Vec::new());
+    }
+    if mode == 0o160000 {
+        return Ok(format!("Subproject commit {}\n", hash).into_bytes());
+    }
+    let from_disk = || -> anyhow::Result<Vec<u8>> {
+        let metadata = fs::symlink_metadata(path)?;
+        read_worktree_contents(path, &metadata)
+    };
+    if hash == NULL_HASH {
+        return from_disk();
+    }
+    match read_object_from_store(hash) {
+        Ok(GitObjectType::Blob(blob)) => Ok(blob.blob_data),
+        Ok(_) => anyhow::bail!("{} is not a blob", hash),
+        Err(error) => match from_disk() {
+            Ok(contents) if hash_object_contents(&contents, b"blob") == hash => Ok(contents),
+            _ => Err(error),
+        },
+    }
+}
+
+// Pair is a change with the contents of both sides loaded, and ids filled in for files on disk
+struct Pair<'a> {
+    change: &'a FileChange,
+    old: Vec<u8>,
+    new: Vec<u8>,
+    old_hash: String,
+    new_hash: String,
+}
+
+impl Pair<'_> {
+    fn load(change: &FileChange) -> anyhow::Result<Pair<'_>> {
+        let load_side = |mode: u32, hash: &str, path: &str| -> anyhow::Result<(Vec<u8>, String)> {
+            if change.kind == ChangeKind::Unmerged {
+                return Ok((Vec::new(), hash.to_string()));
+            }
+            let data = side_contents(mode, hash, path)?;
+            let hash = match mode != 0 && mode != 0o160000 && hash == NULL_HASH {
+                true => hash_object_contents(&data, b"blob"),
+                false => hash.to_string(),
+            };
+            Ok((data, hash))
+        };
+        let (old, old_hash) = load_side(change.old_mode, &change.old_hash, &change.old_path)?;
+        let (new, new_hash) = load_side(change.new_mode, &change.new_hash, &change.new_path)?;
+        Ok(Pair { change, old, new, old_hash, new_hash })
+    }
+}
+
+// Hunk is one "@@" section of a patch: its header line and its lines with their prefixes
+struct Hunk<'a> {
+    header: Vec<u8>,
+    lines: Vec<(u8, &'a [u8])>,
+}
+
+// How many bytes at the end of both sides can be left out of a diff without context because
+// they are the same, in whole blocks and ending on a line boundary
+fn common_tail(a: &[u8], b: &[u8]) -> usize {
+    const BLOCK: usize = 1024;
+    let smaller = a.len().min(b.len());
+    let mut trimmed = 0;
+    while BLOCK + trimmed <= smaller && a[a.len() - trimmed - BLOCK..a.len() - trimmed] == b[b.len() - trimmed - BLOCK..b.len() - trimmed] {
+        trimmed += BLOCK;
+    }
+    let mut recovered = 0;
+    while recovered < trimmed {
+        let x = a[a.len() - trimmed + recovered];
+        recovered += 1;
+        if x == b'\n' {
+            break;
+        }
+    }
+    trimmed - recovered
+}
+
+// The text shown after a hunk header: the nearest line above the hunk that starts with a
+// letter, "_" or "$", cut to 80 bytes
+fn function_name(line: &[u8]) -> Option<&[u8]> {
+    let first = *line.first()?;
+    if !(first.is_ascii_alphabetic() || first == b'_' || first == b'$') {
+        return None;
+    }
+    let mut line = &line[..line.len().min(80)];
+    while let Some((last, rest)) = line.split_last() {
+        if !matches!(last, b' ' | b'\t' | b'\n' | b'\r') {
+            break;
+        }
+        line = rest;
+    }
+    // Only the part that is valid UTF-8 is shown
+    match std::str::from_utf8(line) {
+        Ok(_) => Some(line),
+        Err(err) => Some(&line[..err.valid_up_to()]),
+    }
+}
+
+// "start,count" for a hunk header, leaving out a count of one and pointing before the
+// hunk when it is empty
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start - 1),
+        1 => start.to_string(),
+        _ => format!("{},{}", start, count),
+    }
+}
+
+// Group changes into hunks with the given context, merging those close enough to share it
+fn make_hunks<'a>(old: &[&'a [u8]], new: &[&'a [u8]], changes: &[Change], context: usize, inter_hunk_context: usize) -> Vec<Hunk<'a>> {
+    let max_common = 2 * context + inter_hunk_context;
+    let mut hunks = Vec::new();
+    let mut function: &[u8] = &[];
+    let mut previous_search: i64 = -1;
+    let mut index = 0;
+    while index < changes.len() {
+        let mut last = index;
+        while last + 1 < changes.len() && changes[last + 1].old_start - (changes[last].old_start + changes[last].old_count) <= max_common {
+            last += 1;
+        }
+        let (first, end) = (changes[index], changes[last]);
+        let s1 = first.old_start.saturating_sub(context);
+        let s2 = first.new_start.saturating_sub(context);
+        let trailing = context.min(old.len() - (end.old_start + end.old_count)).min(new.len() - (end.new_start + end.new_count));
+        let e1 = end.old_start + end.old_count + trailing;
+        let e2 = end.new_start + end.new_count + trailing;
+
+        // Look for a function line between this hunk and where the previous search started,
+        // keeping the last one found otherwise
+        let start = s1 as i64 - 1;
+        let step = if start > previous_search { -1 } else { 1 };
+        let mut line = start;
+        while line != previous_search && 0 <= line && line < old.len() as i64 {
+            if let Some(name) = function_name(old[line as usize]) {
+                function = name;
+                break;
+            }
+            line += step;
+        }
+        previous_search = start;
+
+        let mut header = format!("@@ -{} +{} @@", hunk_range(s1 + 1, e1 - s1), hunk_range(s2 + 1, e2 - s2)).into_bytes();
+        if !function.is_empty() {
+            header.push(b' ');
+            header.extend_from_slice(function);
+        }
+        let mut lines = Vec::new();
+        for line in &new[s2..first.new_start] {
+            lines.push((b' ', *line));
+        }
+        let (mut o, mut n) = (first.old_start, first.new_start);
+        for change in &changes[index..=last] {
+            while o < change.old_start && n < change.new_start {
+                lines.push((b' ', new[n]));
+                o += 1;
+                n += 1;
+            }
+            for line in &old[change.old_start..change.old_start + change.old_count] {
+                lines.push((b'-', *line));
+            }
+            for line in &new[change.new_start..change.new_start + change.new_count] {
+                lines.push((b'+', *line));
+            }
+            o = change.old_start + change.old_count;
+            n = change.new_start + change.new_count;
+        }
+        for line in &new[end.new_start + end.new_count..e2] {
+            lines.push((b' ', *line));
+        }
+        hunks.push(Hunk { header, lines });
+        index = last + 1;
+    }
+    hunks
+}
+
+// Write text split at newlines, wrapping each non-empty piece in the given markers
+fn write_words(prefix: &[u8], suffix: &[u8], text: &[u8], output: &mut Vec<u8>) {
+    let mut rest = text;
+    loop {
+        let newline = rest.iter().position(|x| *x == b'\n');
+        let piece = &rest[..newline.unwrap_or(rest.len())];
+        if !piece.is_empty() {
+            output.extend_from_slice(prefix);
+            output.extend_from_slice(piece);
+            output.extend_from_slice(suffix);
+        }
+        let Some(newline) = newline else {
+            break;
+        };
+        output.push(b'\n');
+        rest = &rest[newline + 1..];
+        if rest.is_empty() {
+            break;
+        }
+    }
+}
+
+// Split text into runs of non-space characters, as byte ranges preceded by an empty range at
+// the start so that position zero means "before the first word"
+fn word_ranges(text: &[u8]) -> Vec<(usize, usize)> {
+    let is_space = |x: u8| matches!(x, b' ' | b'\t' | b'\n' | b'\r');
+    let mut ranges = vec![(0, 0)];
+    let mut index = 0;
+    while index < text.len() {
+        if is_space(text[index]) {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < text.len() && !is_space(text[index]) {
+            index += 1;
+        }
+        ranges.push((start, index));
+    }
+    ranges
+}
+
+// Show removed and added text word by word: unchanged words as they are in the new text,
+// removed ones as [-...-] and added ones as {+...+}
+fn write_word_diff(minus: &[u8], plus: &[u8], output: &mut Vec<u8>) {
+    if minus.is_empty() && plus.is_empty() {
+        return;
+    }
+    if plus.is_empty() {
+        write_words(b"[-", b"-]", minus, output);
+        return;
+    }
+    let minus_words = word_ranges(minus);
+    let plus_words = word_ranges(plus);
+    // Each word becomes a line of its own for the line diff
+    let as_lines = |text: &[u8], ranges: &[(usize, usize)]| -> Vec<u8> {
+        let mut lines = Vec::new();
+        for (start, end) in &ranges[1..] {
+            lines.extend_from_slice(&text[*start..*end]);
+            lines.push(b'\n');
+        }
+        lines
+    };
+    let minus_text = as_lines(minus, &minus_words);
+    let plus_text = as_lines(plus, &plus_words);
+    let tail = common_tail(&minus_text, &plus_text);
+    let minus_lines = split_lines(&minus_text[..minus_text.len() - tail]);
+    let plus_lines = split_lines(&plus_text[..plus_text.len() - tail]);
+
+    let mut current = 0;
+    for change in diff_lines(&minus_lines, &plus_lines, Algorithm::Myers, false) {
+        let span = |words: &[(usize, usize)], start: usize, count: usize| match count {
+            0 => (words[start].1, words[start].1),
+            _ => (words[start + 1].0, words[start + count].1),
+        };
+        let (minus_begin, minus_end) = span(&minus_words, change.old_start, change.old_count);
+        let (plus_begin, plus_end) = span(&plus_words, change.new_start, change.new_count);
+        if current != plus_begin {
+            write_words(b"", b"", &plus[current..plus_begin], output);
+        }
+        if minus_begin != minus_end {
+            write_words(b"[-", b"-]", &minus[minus_begin..minus_end], output);
+        }
+        if plus_begin != plus_end {
+            write_words(b"{+", b"+}", &plus[plus_begin..plus_end], output);
+        }
+        current = plus_end;
+    }
+    if current != plus.len() {
+        write_words(b"", b"", &plus[current..], output);
+    }
+}
+
+// FileStat is a file's line for --stat and --numstat
+struct FileStat {
+    name: String,
+    old_name: Option<String>,
+    added: usize,
+    deleted: usize,
+    binary: bool,
+    unmerged: bool,
+}
+
+// "old => new" for a rename, with the parts both names share written once around braces
+pub fn rename_name(old: &str, new: &str) -> String {
+    if quote_path(old, false) != old || quote_path(new, false) != new {
+        return format!("{} => {}", quote_path(old, false), quote_path(new, false));
+    }
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    let mut prefix = 0;
+    let mut index = 0;
+    while index < a.len() && index < b.len() && a[index] == b[index] {
+        if a[index] == b'/' {
+            prefix = index + 1;
+        }
+        index += 1;
+    }
+    // Walk back from the end, letting the scan see the slash that ends a common prefix
+    let mut suffix = 0;
+    let adjust = if prefix > 0 { 1 } else { 0 };
+    let (mut i, mut j) = (a.len() as i64, b.len() as i64);
+    let at = |bytes: &[u8], position: i64| bytes.get(position as usize).copied().unwrap_or(0);
+    while (prefix as i64 - adjust) <= i && (prefix as i64 - adjust) <= j && at(a, i) == at(b, j) {
+        if at(a, i) == b'/' {
+            suffix = a.len() - i as usize;
+        }
+        i -= 1;
+        j -= 1;
+    }
+    let a_middle = a.len().saturating_sub(prefix + suffix);
+    let b_middle = b.len().saturating_sub(prefix + suffix);
+    let mut name = String::new();
+    if prefix + suffix > 0 {
+        name.push_str(&old[..prefix]);
+        name.push('{');
+    }
+    name.push_str(&old[prefix..prefix + a_middle]);
+    name.push_str(" => ");
+    name.push_str(&new[prefix..prefix + b_middle]);
+    if prefix + suffix > 0 {
+        name.push('}');
+        name.push_str(&old[old.len() - suffix..]);
+    }
+    name
+}
+
+// Number of digits needed to print a count
+fn decimal_width(number: usize) -> usize {
+    number.to_string().len()
+}
+
+// Scale a change count to the graph width, keeping at least one mark for any change
+fn scale_linear(count: usize, width: usize, max_change: usize) -> usize {
+    if count == 0 {
+        return 0;
+    }
+    1 + count * (width - 1) / max_change
+}
+
+// Width of the terminal, or 80 columns when output is not going to one
+pub fn term_columns() -> usize {
+    std::env::var("COLUMNS").ok().and_then(|x| x.parse().ok()).filter(|x| *x > 0).unwrap_or(80)
+}
+
+fn write_stat_summary(stats: &[FileStat], output: &mut Vec<u8>) {
+    let counted: Vec<&FileStat> = stats.iter().filter(|x| !x.unmerged).collect();
+    let files = counted.len();
+    let insertions: usize = counted.iter().filter(|x| !x.binary).map(|x| x.added).sum();
+    let deletions: usize = counted.iter().filter(|x| !x.binary).map(|x| x.deleted).sum();
+    if files == 0 {
+        output.extend_from_slice(b" 0 files changed\n");
+        return;
+    }
+    let mut line = format!(" {} file{} changed", files, if files == 1 { "" } else { "s" });
+    if insertions > 0 || deletions == 0 {
+        line.push_str(&format!(", {} insertion{}(+)", insertions, if insertions == 1 { "" } else { "s" }));
+    }
+    if deletions > 0 || insertions == 0 {
+        line.push_str(&format!(", {} deletion{}(-)", deletions, if deletions == 1 { "" } else { "s" }));
+    }
+    line.push('\n');
+    output.extend(line.into_bytes());
+}
+
+// One line of --summary for a created, deleted, renamed or mode changed file
+fn write_summary(change: &FileChange, output: &mut Vec<u8>) {
+    let mode_change = |show_name: bool| {
+        if change.old_mode != 0 && change.new_mode != 0 && change.old_mode != change.new_mode {
+            let name = if show_name { format!(" {}", quote_path(&change.new_path, false)) } else { String::new() };
+            format!(" mode change {:06o} => {:06o}{}\n", change.old_mode, change.new_mode, name)
+        } else {
+            String::new()
+        }
+    };
+    let line = match change.kind {
+        ChangeKind::Deleted => format!(" delete mode {:06o} {}\n", change.old_mode, quote_path(&change.old_path, false)),
+        ChangeKind::Added => format!(" create mode {:06o} {}\n", change.new_mode, quote_path(&change.new_path, false)),
+        ChangeKind::Renamed => format!(" rename {} ({}%)\n{}", rename_name(&change.old_path, &change.new_path), change.score, mode_change(false)),
+        ChangeKind::Copied => format!(" copy {} ({}%)\n{}", rename_name(&change.old_path, &change.new_path), change.score, mode_change(false)),
+        _ => mode_change(true),
+    };
+    output.extend(line.into_bytes());
+}
+
+impl DiffOptions {
+    fn lines_of<'a>(&self, data: &'a [u8], tail: usize) -> Vec<&'a [u8]> {
+        split_lines(&data[..data.len() - tail])
+    }
+
+    // Runs of changed lines between the two sides of a pair
+    pub fn line_changes<'a>(&self, old: &'a [u8], new: &'a [u8], context: usize) -> (Vec<&'a [u8]>, Vec<&'a [u8]>, Vec<Change>) {
+        // Without context the identical tail cannot show up in the output, so skip it
+        let tail = if context == 0 { common_tail(old, new) } else { 0 };
+        let old_lines = self.lines_of(old, tail);
+        let new_lines = self.lines_of(new, tail);
+        let changes = diff_lines(&old_lines, &new_lines, self.algorithm, self.indent_heuristic);
+        (old_lines, new_lines, changes)
+    }
+
+    fn file_stat(&self, pair: &Pair) -> FileStat {
+        let change = pair.change;
+        let mut stat = FileStat {
+            name: quote_path(&change.new_path, false),
+            old_name: None,
+            added: 0,
+            deleted: 0,
+            binary: false,
+            unmerged: change.kind == ChangeKind::Unmerged,
+        };
+        if change.is_rename() {
+            stat.old_name = Some(change.old_path.clone());
+            stat.name = rename_name(&change.old_path, &change.new_path);
+        }
+        if stat.unmerged {
+            return stat;
+        }
+        let same = pair.old_hash == pair.new_hash;
+        if !self.text && (is_binary(&pair.old) || is_binary(&pair.new)) {
+            stat.binary = true;
+            if !same {
+                stat.added = pair.new.len();
+                stat.deleted = pair.old.len();
+            }
+        } else if !same {
+            let (_, _, changes) = self.line_changes(&pair.old, &pair.new, self.context);
+            stat.added = changes.iter().map(|x| x.new_count).sum();
+            stat.deleted = changes.iter().map(|x| x.old_count).sum();
+        }
+        stat
+    }
+
+    fn write_numstat(&self, stats: &[FileStat], output: &mut Vec<u8>) {
+        for stat in stats {
+            let counts = match stat.binary {
+                true => "-\t-\t".to_string(),
+                false => format!("{}\t{}\t", stat.added, stat.deleted),
+            };
+            output.extend(counts.into_bytes());
+            if self.nul {
+                if let Some(old_name) = &stat.old_name {
+                    output.push(b'\0');
+                    output.extend_from_slice(old_name.as_bytes());
+                    output.push(b'\0');
+                }
+                let name = stat.name.rsplit(" => ").next().unwrap_or(&stat.name);
+                output.extend_from_slice(if stat.old_name.is_some() { name } else { &stat.name }.as_bytes());
+                output.push(b'\0');
+            } else {
+                output.extend_from_slice(stat.name.as_bytes());
+                output.push(b'\n');
+            }
+        }
+    }
+
+    // The diffstat: a line per file with its name, change count and a +/- graph scaled to
+    // fit the width, then the totals
+    fn write_stat(&self, stats: &[FileStat], output: &mut Vec<u8>) {
+        let count = self.stat_count.unwrap_or(stats.len()).min(stats.len());
+        let mut max_len = 0;
+        let mut max_change = 0;
+        let mut bin_width = 0;
+        let mut number_width = 0;
+        for stat in &stats[..count] {
+            max_len = max_len.max(stat.name.chars().count());
+            if stat.unmerged {
+                bin_width = bin_width.max(8);
+            } else if stat.binary {
+                // "Bin XXX -> YYY bytes"
+                bin_width = bin_width.max(14 + decimal_width(stat.added) + decimal_width(stat.deleted));
+                number_width = 3;
+            } else {
+                max_change = max_change.max(stat.added + stat.deleted);
+            }
+        }
+
+        let mut width = self.stat_width.filter(|x| *x > 0).unwrap_or_else(term_columns);
+        number_width = number_width.max(decimal_width(max_change));
+        width = width.max(16 + 6 + number_width);
+        let mut graph_width = if max_change + 4 > bin_width { max_change } else { bin_width - 4 };
+        let mut name_width = match self.stat_name_width {
+            Some(limit) if limit > 0 && limit < max_len => limit,
+            _ => max_len,
+        };
+        if name_width + number_width + 6 + graph_width > width {
+            let share = (width * 3 / 8).saturating_sub(number_width + 6);
+            if graph_width > share {
+                graph_width = share.max(6);
+            }
+            if name_width > width.saturating_sub(number_width + 6 + graph_width) {
+                name_width = width.saturating_sub(number_width + 6 + graph_width);
+            } else {
+                graph_width = width - number_width - 6 - name_width;
+            }
+        }
+
+        for stat in &stats[..count] {
+            // Names too long for their column keep their end, from a directory boundary
+            let mut name: &str = &stat.name;
+            let mut prefix = "";
+            let mut length = name_width;
+            let name_length = name.chars().count();
+            if name_width < name_length {
+                prefix = "...";
+                length = length.saturating_sub(3);
+                let skip = name_length - length;
+                name = &name[name.char_indices().nth(skip).map(|x| x.0).unwrap_or(name.len())..];
+                if let Some(slash) = name.find('/') {
+                    name = &name[slash..];
+                }
+            }
+            let padding = " ".repeat(length.saturating_sub(name.chars().count()));
+            let mut line = format!(" {}{}{} |", prefix, name, padding);
+            if stat.binary {
+                line.push_str(&format!(" {:>width$}", "Bin", width = number_width));
+                if stat.added > 0 || stat.deleted > 0 {
+                    line.push_str(&format!(" {} -> {} bytes", stat.deleted, stat.added));
+                }
+            } else if stat.unmerged {
+                line.push_str(" Unmerged");
+            } else {
+                let (mut added, mut deleted) = (stat.added, stat.deleted);
+                if graph_width <= max_change {
+                    let mut total = scale_linear(added + deleted, graph_width, max_change);
+                    if total < 2 && added > 0 && deleted > 0 {
+                        total = 2;
+                    }
+                    if added < deleted {
+                        added = scale_linear(added, graph_width, max_change);
+                        deleted = total - added;
+                    } else {
+                        deleted = scale_linear(deleted, graph_width, max_change);
+                        added = total - deleted;
+                    }
+                }
+                let total = stat.added + stat.deleted;
+                line.push_str(&format!(" {:>width$}{}", total, if total > 0 { " " } else { "" }, width = number_width));
+                line.push_str(&"+".repeat(added));
+                line.push_str(&"-".repeat(deleted));
+            }
+            line.push('\n');
+            output.extend(line.into_bytes());
+        }
+        if count < stats.len() {
+            output.extend_from_slice(b" ...\n");
+        }
+        write_stat_summary(stats, output);
+    }
+
+    pub fn abbreviate(&self, hash: &str) -> String {
+        match self.full_index {
+            true => hash.to_string(),
+            false => abbreviate_to(hash, self.abbrev.unwrap_or(7)),
+        }
+    }
+
+    fn write_patch(&self, pair: &Pair, output: &mut Vec<u8>) {
+        let change = pair.change;
+        if change.kind == ChangeKind::Unmerged {
+            output.extend(format!("* Unmerged path {}\n", change.new_path).into_bytes());
+            return;
+        }
+        let old = (change.old_mode, pair.old_hash.as_str(), pair.old.as_slice());
+        let new = (change.new_mode, pair.new_hash.as_str(), pair.new.as_slice());
+        let empty = (0, NULL_HASH, &[][..]);
+        // A file replaced by a symlink or the like is shown as a deletion and a creation
+        if change.old_mode != 0 && change.new_mode != 0 && (change.old_mode ^ change.new_mode) & 0o170000 != 0 {
+            self.write_file_patch(change, old, empty, output);
+            self.write_file_patch(change, empty, new, output);
+        } else {
+            self.write_file_patch(change, old, new, output);
+        }
+    }
+
+    fn write_file_patch(&self, change: &FileChange, old: (u32, &str, &[u8]), new: (u32, &str, &[u8]), output: &mut Vec<u8>) {
+        let (old_mode, old_hash, old_data) = old;
+        let (new_mode, new_hash, new_data) = new;
+        // Reversed diffs keep each side's prefix with its contents
+        let (a_prefix, b_prefix) = if self.reverse { ("b/", "a/") } else { ("a/", "b/") };
+        let a_name = quote_path(&format!("{}{}", a_prefix, change.old_path), false);
+        let b_name = quote_path(&format!("{}{}", b_prefix, change.new_path), false);
+        let old_label = if old_mode != 0 { a_name.as_str() } else { "/dev/null" };
+        let new_label = if new_mode != 0 { b_name.as_str() } else { "/dev/null" };
+
+        let mut header = format!("diff --git {} {}\n", a_name, b_name);
+        let mut must_show_header = true;
+        if old_mode == 0 {
+            header.push_str(&format!("new file mode {:06o}\n", new_mode));
+        } else if new_mode == 0 {
+            header.push_str(&format!("deleted file mode {:06o}\n", old_mode));
+        } else if old_mode != new_mode {
+            header.push_str(&format!("old mode {:06o}\nnew mode {:06o}\n", old_mode, new_mode));
+        } else {
+            must_show_header = false;
+        }
+        if change.is_rename() {
+            let verb = if change.kind == ChangeKind::Copied { "copy" } else { "rename" };
+            header.push_str(&format!(
+                "similarity index {}%\n{verb} from {}\n{verb} to {}\n",
+                change.score,
+                quote_path(&change.old_path, false),
+                quote_path(&change.new_path, false)
+            ));
+            must_show_header = true;
+        }
+        if old_hash != new_hash {
+            header.push_str(&format!("index {}..{}", self.abbreviate(old_hash), self.abbreviate(new_hash)));
+            if old_mode == new_mode {
+                header.push_str(&format!(" {:06o}", old_mode));
+            }
+            header.push('\n');
+        }
+
+        if !self.text && (is_binary(old_data) || is_binary(new_data)) {
+            if old_data != new_data {
+                output.extend(header.into_bytes());
+                output.extend(format!("Binary files {} and {} differ\n", old_label, new_label).into_bytes());
+            } else if must_show_header {
+                output.extend(header.into_bytes());
+            }
+            return;
+        }
+
+        let (old_lines, new_lines, changes) = self.line_changes(old_data, new_data, self.context);
+        if changes.is_empty() {
+            if must_show_header {
+                output.extend(header.into_bytes());
+            }
+            return;
+        }
+        output.extend(header.into_bytes());
+        // Names with spaces get a trailing tab so patch tools can find their end
+        let old_tab = if old_mode != 0 && change.old_path.contains(' ') { "\t" } else { "" };
+        let new_tab = if new_mode != 0 && change.new_path.contains(' ') { "\t" } else { "" };
+        output.extend(format!("--- {}{}\n+++ {}{}\n", old_label, old_tab, new_label, new_tab).into_bytes());
+        for hunk in make_hunks(&old_lines, &new_lines, &changes, self.context, self.inter_hunk_context) {
+            output.extend_from_slice(&hunk.header);
+            output.push(b'\n');
+            if self.word_diff {
+                // Every line counts as ending in a newline; the missing newline marker
+                // is not shown
+                let (mut minus, mut plus) = (Vec::new(), Vec::new());
+                for (prefix, line) in &hunk.lines {
+                    let text = match prefix {
+                        b'-' => &mut minus,
+                        b'+' => &mut plus,
+                        _ => {
+                            write_word_diff(&minus, &plus, output);
+                            minus.clear();
+                            plus.clear();
+                            &mut *output
+                        }
+                    };
+                    text.extend_from_slice(line);
+                    if !line.ends_with(b"\n") {
+                        text.push(b'\n');
+                    }
+                }
+                write_word_diff(&minus, &plus, output);
+                continue;
+            }
+            for (prefix, line) in &hunk.lines {
+                output.push(*prefix);
+                output.extend_from_slice(line);
+                if !line.ends_with(b"\n") {
+                    output.extend_from_slice(b"\n\\ No newline at end of file\n");
+                }
+            }
+        }
+    }
+}
+
+// An id for the change a commit makes, the same for commits making the same change wherever
+// they are, like the one git patch-id computes
+pub fn patch_id(config: &Config, commit: &Commit) -> anyhow::Result<String> {
+    let mut options = DiffOptions::new(config)?;
+    let parent_tree = commit.parents.first().map(|x| peel_to_tree(x)).transpose()?;
+    options.patch = true;
+    let changes = TreeDiff { recursive: true, ..Default::default() }.diff(parent_tree.as_deref(), Some(&commit.tree))?;
+    let mut hasher = Sha1::new();
+    for line in options.render(&changes)?.split(|x| *x == b'\n') {
+        if line.starts_with(b"index ") {
+            continue;
+        }
+        if line.starts_with(b"@@ ") {
+            hasher.update(b"@@");
+            continue;
+        }
+        let line: Vec<u8> = line.iter().copied().filter(|x| !x.is_ascii_whitespace()).collect();
+        hasher.update(&line);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Split a revision argument of the form "A..B" into its two ends
+fn revision_range(arg: &str) -> Option<(String, String)> {
+    let (from, to) = arg.split_once("..").filter(|(_, to)| !to.starts_with('.'))?;
+    let from = if from.is_empty() { "HEAD" } else { from };
+    let to = if to.is_empty() { "HEAD" } else { to };
+    Some((resolve_revision(from).ok()?, resolve_revision(to).ok()?))
+}
+
+// Conflicted paths are only reported, not compared
+fn unmerged_changes(index: &[IndexEntry]) -> Vec<FileChange> {
+    let mut paths: Vec<&str> = index.iter().filter(|x| x.stage() != 0).map(|x| x.path.as_str()).collect();
+    paths.dedup();
+    paths.into_iter().map(|path| unmerged_change(path, None, 0)).collect()
+}
+
+pub fn diff_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "diff").unwrap();
+    let config = read_config();
+    let mut options = DiffOptions::new(&config)?;
+    let mut cached = false;
+    let mut revisions = Vec::new();
+    let mut paths = Vec::new();
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if options.parse_option(arg)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--cached" | "--staged" => cached = true,
+            "--" => {
+                paths.extend(arguments.by_ref().cloned());
+                break;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => anyhow::bail!("invalid option: {}", arg),
+            _ if paths.is_empty() => {
+                if let Some((from, to)) = revision_range(arg) {
+                    revisions.push(from);
+                    revisions.push(to);
+                } else if let Ok(hash) = resolve_revision(arg) {
+                    if Path::new(arg).exists() {
+                        return Err(ambiguous_argument(arg, "both revision and filename"));
+                    }
+                    revisions.push(hash);
+                } else if Path::new(arg).exists() {
+                    paths.push(arg.clone());
+                } else {
+                    return Err(ambiguous_argument(arg, "unknown revision or path not in the working tree."));
+                }
+            }
+            _ => {
+                if !Path::new(arg).exists() {
+                    return Err(ambiguous_argument(arg, "unknown revision or path not in the working tree."));
+                }
+                paths.push(arg.clone());
+            }
+        }
+    }
+    if !options.has_format() {
+        options.patch = true;
+    }
+    options.validate()?;
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|path| path.trim_start_matches("./").trim_end_matches('/').to_string())
+        .map(|path| if path.is_empty() { ".".to_string() } else { path })
+        .collect();
+
+    let index = read_index()?;
+    // The files on the old side, kept as copy sources when looking for copies harder
+    let mut old_files: Vec<(String, u32, String)> = Vec::new();
+    let wants_unchanged = options.renames.wants_unchanged();
+    // Conflicts on disk, compared with both sides of the merge
+    let mut conflicts = Vec::new();
+    let mut changes = match (revisions.as_slice(), cached) {
+        ([], false) => {
+            let mut index = index;
+            let (mut changes, _) = diff_index_to_worktree(&mut index);
+            // Files on disk have no object ids yet; they are read back from disk when needed
+            for change in &mut changes {
+                change.new_hash = NULL_HASH.to_string();
+            }
+            changes.extend(unmerged_changes(&index));
+            changes.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+            conflicts = changes
+                .iter()
+                .filter(|change| change.kind == ChangeKind::Unmerged)
+                .filter_map(|change| conflict_path(&index, &change.new_path))
+                .collect();
+            if wants_unchanged {
+                old_files = index.iter().filter(|x| x.stage() == 0).map(|x| (x.path.clone(), x.mode, x.sha_hash.clone())).collect();
+            }
+            changes
+        }
+        (revisions, true) if revisions.len() <= 1 => {
+            let base = match revisions.first() {
+                Some(revision) => Some(revision.clone()),
+                None => resolve_ref("HEAD"),
+            };
+            let tree = match base {
+                Some(base) => flatten_tree(&peel_to_tree(&base)?)?,
+                None => BTreeMap::new(),
+            };
+            let mut changes = diff_tree_to_index(&tree, &index);
+            changes.extend(unmerged_changes(&index));
+            changes.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+            if wants_unchanged {
+                old_files = tree.into_iter().map(|(path, entry)| (path, entry.mode, entry.sha_hash)).collect();
+            }
+            changes
+        }
+        ([revision], false) => {
+            let tree = flatten_tree(&peel_to_tree(revision)?)?;
+            let changes = diff_tree_to_worktree(&tree, &index);
+            if wants_unchanged {
+                old_files = tree.into_iter().map(|(path, entry)| (path, entry.mode, entry.sha_hash)).collect();
+            }
+            changes
+        }
+        ([old, new], false) => {
+            let tree_diff = TreeDiff { recursive: true, show_trees: false, paths: paths.clone() };
+            let old_tree = peel_to_tree(old)?;
+            if wants_unchanged {
+                old_files = flatten_tree(&old_tree)?.into_iter().map(|(path, entry)| (path, entry.mode, entry.sha_hash)).collect();
+            }
+            tree_diff.diff(Some(&old_tree), Some(&peel_to_tree(new)?))?
+        }
+        _ => {
+            eprintln!("usage: git diff [<options>] [<commit>] [--] [<path>...]");
+            std::process::exit(129);
+        }
+    };
+    changes.retain(|change| paths.is_empty() || paths.iter().any(|spec| pathspec_matches(spec, &change.new_path)));
+    let unchanged = unchanged_files(old_files.iter().map(|(path, mode, hash)| (path.as_str(), *mode, hash.as_str())), &changes, &paths);
+    let mut changes = options.renames.detect(changes, unchanged)?;
+
+    // Patches of conflicts are combined diffs, shown before everything else
+    let mut output = Vec::new();
+    if options.patch {
+        conflicts.retain(|path| changes.iter().any(|change| change.kind == ChangeKind::Unmerged && change.new_path == path.path));
+        changes.retain(|change| change.kind != ChangeKind::Unmerged || !conflicts.iter().any(|path| path.path == change.new_path));
+        let mut combined = options.clone();
+        (combined.raw, combined.name_only, combined.name_status) = (false, false, false);
+        (combined.stat, combined.numstat, combined.shortstat, combined.summary) = (false, false, false, false);
+        output = combined.render_combined(&conflicts, &[], true)?;
+    }
+    output.extend(options.render(&changes)?);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if out.write_all(&output).and_then(|_| out.flush()).is_err() {
+        return Ok(());
+    }
+    options.renames.warn_limit("diff.renameLimit");
+    options.finish(!changes.is_empty());
+    Ok(())
+}
@@ -0,0 +1,398 @@
+// Reachability between commits: merge bases, ancestry checks and the fork point of a branch.
+// Walks go newest first, by generation number where the commit-graph has one and by
+// committer date otherwise, so they can stop as soon as nothing new can be found.
+
+use crate::commitgraph::generation;
+use crate::diff::NULL_HASH;
+use crate::reflog::read_reflog;
+use crate::refs::resolve_ref;
+use crate::revision::{matching_ref_names, peel_to_commit, resolve_revision};
+use crate::walk::load_commit;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// Marks painted on commits while looking for merge bases
+const PARENT1: u8 = 1;
+const PARENT2: u8 = 2;
+const STALE: u8 = 4;
+const RESULT: u8 = 8;
+
+// Node is what a walk needs to know about a commit
+struct Node {
+    time: i64,
+    generation: u32,
+    parents: Vec<String>,
+}
+
+// Commits loaded so far, shared by the walks of one query
+#[derive(Default)]
+struct Graph {
+    nodes: HashMap<String, Node>,
+}
+
+impl Graph {
+    fn node(&mut self, hash: &str) -> anyhow::Result<&Node> {
+        if !self.nodes.contains_key(hash) {
+            let commit = load_commit(hash)?;
+            let node = Node { time: commit.committer.time, generation: generation(hash), parents: commit.parents };
+            self.nodes.insert(hash.to_string(), node);
+        }
+        Ok(&self.nodes[hash])
+    }
+
+    // Walk down from one commit and a set of others, newest first, painting each commit with
+    // the sides it is reachable from. Commits reachable from both are common ancestors; their
+    // own ancestors are marked stale so the walk can stop once only stale commits are queued.
+    // Commits with a generation below min_generation are not walked. Returns the common
+    // ancestors no other common ancestor reaches, newest first, and the paint.
+    fn paint_down_to_common(&mut self, one: &str, twos: &[String], min_generation: u32) -> anyhow::Result<(Vec<String>, HashMap<String, u8>)> {
+        let mut flags: HashMap<String, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut sequence = 0;
+        let mut push = |graph: &mut Graph, queue: &mut BinaryHeap<(u32, i64, Reverse<usize>, String)>, hash: &str| -> anyhow::Result<()> {
+            let node = graph.node(hash)?;
+            sequence += 1;
+            queue.push((node.generation, node.time, Reverse(sequence), hash.to_string()));
+            Ok(())
+        };
+
+        flags.insert(one.to_string(), PARENT1);
+        push(self, &mut queue, one)?;
+        for two in twos {
+            *flags.entry(two.clone()).or_default() |= PARENT2;
+            push(self, &mut queue, two)?;
+        }
+
+        let mut result = Vec::new();
+        while queue.iter().any(|(_, _, _, hash)| flags[hash] & STALE == 0) {
+            let Some((generation, _, _, hash)) = queue.pop() else {
+                break;
+            };
+            if generation < min_generation {
+                break;
+            }
+            let mut paint = flags[&hash] & (PARENT1 | PARENT2 | STALE);
+            if paint == PARENT1 | PARENT2 {
+                if flags[&hash] & RESULT == 0 {
+                    *flags.get_mut(&hash).unwrap() |= RESULT;
+                    result.push(hash.clone());
+                }
+                paint |= STALE;
+            }
+            for parent in self.node(&hash)?.parents.clone() {
+                let mark = flags.entry(parent.clone()).or_default();
+                if *mark & paint == paint {
+                    continue;
+                }
+                *mark |= paint;
+                push(self, &mut queue, &parent)?;
+            }
+        }
+        // A common ancestor reached again from a newer one is not among the best
+        result.retain(|hash| flags[hash] & STALE == 0);
+        self.sort_by_date(&mut result)?;
+        Ok((result, flags))
+    }
+
+    // Newest commits first, keeping the order of commits made at the same time
+    fn sort_by_date(&mut self, commits: &mut [String]) -> anyhow::Result<()> {
+        for hash in commits.iter() {
+            self.node(hash)?;
+        }
+        commits.sort_by_key(|hash| Reverse(self.nodes[hash].time));
+        Ok(())
+    }
+
+    // Whether ancestor is reachable from any of the descendants
+    fn reaches(&mut self, ancestor: &str, descendants: &[String]) -> anyhow::Result<bool> {
+        let min_generation = self.node(ancestor)?.generation;
+        let mut max_generation = 0;
+        for descendant in descendants {
+            max_generation = max_generation.max(self.node(descendant)?.generation);
+        }
+        if min_generation > max_generation {
+            return Ok(false);
+        }
+        let (_, flags) = self.paint_down_to_common(ancestor, descendants, min_generation)?;
+        Ok(flags[ancestor] & PARENT2 != 0)
+    }
+
+    // Drop the commits that another one of them reaches, keeping the order of the rest
+    fn remove_redundant(&mut self, commits: Vec<String>) -> anyhow::Result<Vec<String>> {
+        let mut redundant = vec![false; commits.len()];
+        for (index, commit) in commits.iter().enumerate() {
+            let others: Vec<String> = commits
+                .iter()
+                .enumerate()
+                .filter(|(other, hash)| *other != index && !redundant[*other] && *hash != commit)
+                .map(|(_, hash)| hash.clone())
+                .collect();
+            redundant[index] = !others.is_empty() && self.reaches(commit, &others)?;
+        }
+        Ok(commits.into_iter().zip(redundant).filter(|(_, redundant)| !redundant).map(|(hash, _)| hash).collect())
+    }
+
+    fn merge_bases_many(&mut self, one: &str, twos: &[String]) -> anyhow::Result<Vec<String>> {
+        if twos.iter().any(|two| two == one) {
+            return Ok(vec![one.to_string()]);
+        }
+        let (candidates, _) = self.paint_down_to_common(one, twos, 0)?;
+        if candidates.len() < 2 {
+            return Ok(candidates);
+        }
+        let mut bases = self.remove_redundant(candidates)?;
+        self.sort_by_date(&mut bases)?;
+        Ok(bases)
+    }
+}
+
+// Whether one commit can be reached from another by following parents
+pub fn is_ancestor(ancestor: &str, descendant: &str) -> anyhow::Result<bool> {
+    Graph::default().reaches(ancestor, &[descendant.to_string()])
+}
+
+// The best common ancestors of one commit and a set of others, newest first. A common
+// ancestor is left out when it can be reached from another one.
+pub fn merge_bases_many(one: &str, twos: &[String]) -> anyhow::Result<Vec<String>> {
+    Graph::default().merge_bases_many(one, twos)
+}
+
+pub fn merge_bases(one: &str, two: &str) -> anyhow::Result<Vec<String>> {
+    merge_bases_many(one, &[two.to_string()])
+}
+
+// The best common ancestors for merging all the commits at once, found by merging the
+// bases of the first ones with each further commit in turn
+pub fn octopus_merge_bases(commits: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut graph = Graph::default();
+    let Some((first, rest)) = commits.split_first() else {
+        return Ok(Vec::new());
+    };
+    let mut bases = vec![first.clone()];
+    for commit in rest {
+        let mut next = Vec::new();
+        for base in &bases {
+            next.extend(graph.merge_bases_many(commit, std::slice::from_ref(base))?);
+        }
+        bases = next;
+    }
+    Ok(bases)
+}
+
+// The commits that none of the others reach, in the order given and without duplicates
+pub fn independent_commits(commits: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut seen = HashSet::new();
+    let unique: Vec<String> = commits.iter().filter(|hash| seen.insert(hash.as_str())).cloned().collect();
+    Graph::default().remove_redundant(unique)
+}
+
+// Where a commit forked from the history of a ref: the merge base of the commit and every
+// value the ref's reflog says it had, as long as that base is one of those values
+pub fn fork_point(full_name: &str, commit: &str) -> anyhow::Result<Option<String>> {
+    let reflog = read_reflog(full_name);
+    let logged = reflog.first().map(|first| &first.old_hash).into_iter().chain(reflog.iter().map(|entry| &entry.new_hash));
+    let mut values: Vec<String> = Vec::new();
+    for hash in logged {
+        if hash != NULL_HASH && !values.contains(hash) && peel_to_commit(hash).is_ok() {
+            values.push(hash.clone());
+        }
+    }
+    if values.is_empty() {
+        values.extend(resolve_ref(full_name));
+    }
+
+    let bases = merge_bases_many(commit, &values)?;
+    match bases.as_slice() {
+        [base] if values.contains(base) => Ok(Some(base.clone())),
+        _ => Ok(None),
+    }
+}
+
+fn merge_base_usage() -> ! {
+    eprintln!("usage: git merge-base [-a | --all] <commit> <commit>...");
+    eprintln!("   or: git merge-base [-a | --all] --octopus <commit>...");
+    eprintln!("   or: git merge-base --is-ancestor <commit> <commit>");
+    eprintln!("   or: git merge-base --independent <commit>...");
+    eprintln!("   or: git merge-base --fork-point <ref> [<commit>]");
+    eprintln!();
+    eprintln!("    -a, --all             output all common ancestors");
+    eprintln!("    --octopus             find ancestors for a single n-way merge");
+    eprintln!("    --independent         list revs not reachable from others");
+    eprintln!("    --is-ancestor         is the first one ancestor of the other?");
+    eprintln!("    --fork-point          find where <commit> forked from reflog of <ref>");
+    eprintln!();
+    std::process::exit(129);
+}
+
+fn commit_reference(name: &str) -> anyhow::Result<String> {
+    let hash = resolve_revision(name).map_err(|_| anyhow::anyhow!("Not a valid object name {}", name))?;
+    peel_to_commit(&hash).map_err(|_| anyhow::anyhow!("Not a valid commit name {}", name))
+}
+
+// Print commits one per line, only the first unless all are wanted; finding none exits 1
+fn print_commits(commits: &[String], all: bool) {
+    if commits.is_empty() {
+        std::process::exit(1);
+    }
+    let count = if all { commits.len() } else { 1 };
+    for hash in &commits[..count] {
+        println!("{}", hash);
+    }
+}
+
+// Find common ancestors, check ancestry, or list independent commits and fork points
+pub fn merge_base_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "merge-base").unwrap();
+    let mut all = false;
+    let mut mode: Option<&str> = None;
+    let mut names = Vec::new();
+    for arg in &args[command_index + 1..] {
+        match arg.as_str() {
+            "-a" | "--all" => all = true,
+            "--octopus" | "--independent" | "--is-ancestor" | "--fork-point" => {
+                if let Some(previous) = mode.filter(|previous| previous != arg) {
+                    eprintln!("error: option `{}' is incompatible with {}", arg.trim_start_matches('-'), previous);
+                    merge_base_usage();
+                }
+                mode = Some(arg);
+            }
+            _ if arg.starts_with('-') && arg != "-" => merge_base_usage(),
+            _ => names.push(arg.as_str()),
+        }
+    }
+
+    match mode {
+        Some("--is-ancestor") => {
+            if names.len() < 2 {
+                merge_base_usage();
+            }
+            if all {
+                anyhow::bail!("options '--is-ancestor' and '--all' cannot be used together");
+            }
+            if names.len() != 2 {
+                anyhow::bail!("--is-ancestor takes exactly two commits");
+            }
+            let ancestor = commit_reference(names[0])?;
+            let descendant = commit_reference(names[1])?;
+            if !is_ancestor(&ancestor, &descendant)? {
+                std::process::exit(1);
+            }
+        }
+        Some("--fork-point") => {
+            if names.is_empty() || names.len() > 2 {
+                merge_base_usage();
+            }
+            let name = names.get(1).copied().unwrap_or("HEAD");
+            let commit = resolve_revision(name).map_err(|_| anyhow::anyhow!("Not a valid object name: '{}'", name))?;
+            let commit = peel_to_commit(&commit)?;
+            let full_name = match matching_ref_names(names[0]).as_slice() {
+                [] => anyhow::bail!("No such ref: '{}'", names[0]),
+                [full_name] => full_name.clone(),
+                _ => anyhow::bail!("Ambiguous refname: '{}'", names[0]),
+            };
+            match fork_point(&full_name, &commit)? {
+                Some(hash) => println!("{}", hash),
+                None => std::process::exit(1),
+            }
+        }
+        Some("--independent") => {
+            let commits = names.iter().map(|name| commit_reference(name)).collect::<anyhow::Result<Vec<_>>>()?;
+            print_commits(&independent_commits(&commits)?, true);
+        }
+        Some(_) => {
+            let commits = names.iter().map(|name| commit_reference(name)).collect::<anyhow::Result<Vec<_>>>()?;
+            let bases = octopus_merge_bases(&commits)?;
+            print_commits(&independent_commits(&bases)?, all);
+        }
+        None => {
+            if names.len() < 2 {
+                merge_base_usage();
+            }
+            let commits = names.iter().map(|name| commit_reference(name)).collect::<anyhow::Result<Vec<_>>>()?;
+            print_commits(&merge_bases_many(&commits[0], &commits[1..])?, all);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{commit, repository, tree};
+    use std::fs;
+
+    // A criss-cross history: both sides merge the other's first commit
+    //
+    //   base - a1 - a2
+    //      \     X
+    //       b1 ---- b2
+    //
+    // with c also made on base
+    struct History {
+        base: String,
+        a1: String,
+        b1: String,
+        a2: String,
+        b2: String,
+        c: String,
+    }
+
+    fn history() -> History {
+        let empty = tree(&[]);
+        let base = commit(&empty, &[], 1, "base");
+        let a1 = commit(&empty, &[&base], 2, "a1");
+        let b1 = commit(&empty, &[&base], 3, "b1");
+        let a2 = commit(&empty, &[&a1, &b1], 4, "a2");
+        let b2 = commit(&empty, &[&b1, &a1], 5, "b2");
+        let c = commit(&empty, &[&base], 6, "c");
+        History { base, a1, b1, a2, b2, c }
+    }
+
+    #[test]
+    fn ancestry() {
+        let _repository = repository();
+        let h = history();
+        assert!(is_ancestor(&h.base, &h.a2).unwrap());
+        assert!(is_ancestor(&h.b1, &h.a2).unwrap());
+        assert!(is_ancestor(&h.a2, &h.a2).unwrap());
+        assert!(!is_ancestor(&h.a2, &h.b2).unwrap());
+        assert!(!is_ancestor(&h.c, &h.a2).unwrap());
+    }
+
+    #[test]
+    fn bases() {
+        let _repository = repository();
+        let h = history();
+        assert_eq!(merge_bases(&h.a1, &h.b1).unwrap(), [h.base.as_str()]);
+        assert_eq!(merge_bases(&h.a1, &h.a2).unwrap(), [h.a1.as_str()]);
+        // Neither base of the criss-cross reaches the other, so both are kept, newest first
+        assert_eq!(merge_bases(&h.a2, &h.b2).unwrap(), [h.b1.as_str(), h.a1.as_str()]);
+        assert_eq!(merge_bases_many(&h.c, &[h.a2.clone(), h.b2.clone()]).unwrap(), [h.base.as_str()]);
+        assert_eq!(merge_bases_many(&h.a1, &[h.b1.clone(), h.a2.clone()]).unwrap(), [h.a1.as_str()]);
+        // Each base found along the way is kept; merge-base reduces them before printing
+        assert_eq!(octopus_merge_bases(&[h.a2.clone(), h.b2.clone(), h.c.clone()]).unwrap(), [h.base.as_str(), h.base.as_str()]);
+    }
+
+    #[test]
+    fn independent() {
+        let _repository = repository();
+        let h = history();
+        let commits = [h.a2.clone(), h.a1.clone(), h.b2.clone(), h.a2.clone(), h.base.clone()];
+        assert_eq!(independent_commits(&commits).unwrap(), [h.a2.as_str(), h.b2.as_str()]);
+    }
+
+    #[test]
+    fn fork_points() {
+        let _repository = repository();
+        let h = history();
+        // The branch was at a1 and then rewritten onto c; x was made on the old a1
+        let x = commit(&tree(&[]), &[&h.a1], 7, "x");
+        let line = |old: &str, new: &str| format!("{} {} A U Thor <author@example.com> 1 +0000\tmoved\n", old, new);
+        fs::create_dir_all(".git/logs/refs/heads").unwrap();
+        fs::write(".git/logs/refs/heads/topic", line(NULL_HASH, &h.base) + &line(&h.base, &h.a1) + &line(&h.a1, &h.c)).unwrap();
+        fs::write(".git/refs/heads/topic", format!("{}\n", h.c)).unwrap();
+        assert_eq!(fork_point("refs/heads/topic", &x).unwrap().as_deref(), Some(h.a1.as_str()));
+        assert_eq!(fork_point("refs/heads/topic", &h.b2).unwrap().as_deref(), Some(h.a1.as_str()));
+        assert_eq!(fork_point("refs/heads/topic", &h.b1).unwrap().as_deref(), Some(h.base.as_str()));
+    }
+}
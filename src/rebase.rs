@@ -0,0 +1,2016 @@
+// Rebase: replay the commits of a branch on top of another commit. The commits to replay are
+// written to a todo list of commands (picks, and with --interactive or --rebase-merges also
+// edits, squashes, execs, labels, resets and merges) which the user may edit, then carried out
+// one by one. The state lives in .git/rebase-merge the way git keeps it, so that a rebase
+// stopped for the user can be continued, skipped or aborted by either tool.
+
+use crate::checkout::{check_branch_name, head_tree, switch_tree, CheckoutConflict};
+use crate::config::{read_config, Config};
+use crate::date::{format_date, DateFormat};
+use crate::diff::{diff_index_to_worktree, TreeDiff};
+use crate::editor::{git_editor, is_terminal_dumb, launch_editor, sequence_editor};
+use crate::ident::{signature, Role};
+use crate::index::read_index;
+use crate::merge::{apply_merge, cleanup_message, merge_commits, merge_trees, remove_merge_state, unmerged_paths, MergeOptions};
+use crate::patch::{patch_id, term_columns, DiffOptions};
+use crate::reach::{fork_point, merge_bases};
+use crate::refs::{branch_upstream, delete_ref, head_symbolic_target, resolve_ref, shorten_ref_name, update_ref, update_symbolic_ref, write_ref};
+use crate::reset::{reset_index_and_worktree, ResetMode};
+use crate::revision::{expand_ref_name, peel_to_commit, peel_to_tree, resolve_revision};
+use crate::sequencer::{first_line, index_matches_head, print_commit_summary, remove_branch_state};
+use crate::status::{commit_template_status, print_long_status};
+use crate::walk::{abbreviate, load_commit, Commit, Signature, Walk};
+use crate::worktree::write_tree;
+use crate::write_commit_as;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const STATE_DIR: &str = ".git/rebase-merge";
+
+fn state_path(name: &str) -> String {
+    format!("{}/{}", STATE_DIR, name)
+}
+
+fn read_state(name: &str) -> Option<String> {
+    fs::read_to_string(state_path(name)).ok()
+}
+
+// The first line of a state file, for the files that hold a single value
+fn read_state_line(name: &str) -> Option<String> {
+    read_state(name).map(|x| x.lines().next().unwrap_or("").to_string())
+}
+
+fn write_state(name: &str, contents: &str) -> anyhow::Result<()> {
+    fs::write(state_path(name), contents)?;
+    Ok(())
+}
+
+fn append_state(name: &str, contents: &str) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(state_path(name))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn remove_state(name: &str) {
+    let _ = fs::remove_file(state_path(name));
+}
+
+fn state_exists(name: &str) -> bool {
+    Path::new(&state_path(name)).exists()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Command {
+    Pick,
+    Reword,
+    Edit,
+    Squash,
+    Fixup,
+    Exec,
+    Break,
+    Label,
+    Reset,
+    Merge,
+    Noop,
+    Drop,
+    Comment,
+}
+
+// Each command with the letter it may be abbreviated to
+const COMMANDS: [(Command, &str, Option<char>); 12] = [
+    (Command::Pick, "pick", Some('p')),
+    (Command::Reword, "reword", Some('r')),
+    (Command::Edit, "edit", Some('e')),
+    (Command::Squash, "squash", Some('s')),
+    (Command::Fixup, "fixup", Some('f')),
+    (Command::Exec, "exec", Some('x')),
+    (Command::Break, "break", Some('b')),
+    (Command::Label, "label", Some('l')),
+    (Command::Reset, "reset", Some('t')),
+    (Command::Merge, "merge", Some('m')),
+    (Command::Noop, "noop", None),
+    (Command::Drop, "drop", Some('d')),
+];
+
+impl Command {
+    fn name(self) -> &'static str {
+        COMMANDS.iter().find(|(command, _, _)| *command == self).map(|(_, name, _)| *name).unwrap_or("")
+    }
+
+    fn from_word(word: &str) -> Option<Command> {
+        COMMANDS
+            .iter()
+            .find(|(_, name, letter)| *name == word || (word.len() == 1 && word.starts_with(|x| Some(x) == *letter)))
+            .map(|(command, _, _)| *command)
+    }
+
+    fn is_fixup(self) -> bool {
+        matches!(self, Command::Squash | Command::Fixup)
+    }
+
+    // Commands that do nothing when carried out
+    fn is_noop(self) -> bool {
+        matches!(self, Command::Noop | Command::Drop | Command::Comment)
+    }
+
+    // Commands that replay a commit on top of HEAD
+    fn picks(self) -> bool {
+        matches!(self, Command::Pick | Command::Reword | Command::Edit | Command::Squash | Command::Fixup)
+    }
+}
+
+// Item is one line of a todo list. The flag is the -C or -c given to a fixup or merge; the
+// argument is what follows the commit, or the whole rest of the line for commands without one.
+// Comment lines keep their text in the argument.
+#[derive(Clone, Debug)]
+struct Item {
+    command: Command,
+    flag: Option<char>,
+    commit: Option<String>,
+    arg: String,
+}
+
+impl Item {
+    fn new(command: Command, commit: Option<String>, arg: &str) -> Item {
+        Item { command, flag: None, commit, arg: arg.to_string() }
+    }
+
+    fn comment(text: &str) -> Item {
+        Item::new(Command::Comment, None, text)
+    }
+
+    // The line as it is written to the todo list, with the commit id abbreviated for the user
+    // to edit or in full for the rebase to carry out
+    fn line(&self, abbreviated: bool) -> String {
+        if self.command == Command::Comment {
+            return self.arg.clone();
+        }
+        let mut line = self.command.name().to_string();
+        if let Some(commit) = &self.commit {
+            match (self.command, self.flag) {
+                (Command::Fixup, Some(flag)) => line.push_str(&format!(" -{}", flag)),
+                (Command::Merge, flag) => line.push_str(&format!(" -{}", flag.unwrap_or('C'))),
+                _ => {}
+            }
+            line.push(' ');
+            line.push_str(&if abbreviated { abbreviate(commit) } else { commit.clone() });
+        }
+        if !self.arg.is_empty() {
+            line.push(' ');
+            line.push_str(&self.arg);
+        }
+        line
+    }
+}
+
+fn count_commands(items: &[Item]) -> usize {
+    items.iter().filter(|item| item.command != Command::Comment).count()
+}
+
+fn todo_text(items: &[Item], abbreviated: bool) -> String {
+    items.iter().map(|item| format!("{}\n", item.line(abbreviated))).collect()
+}
+
+// Parse one todo line, reporting what is wrong with it as git does
+fn parse_line(line: &str) -> Option<Item> {
+    let line = line.trim_start_matches([' ', '\t']);
+    if line.is_empty() || line.starts_with('#') {
+        return Some(Item::comment(line));
+    }
+    let end = line.find([' ', '\t']).unwrap_or(line.len());
+    let command = Command::from_word(&line[..end])?;
+    let rest = &line[end..];
+    let arg = rest.trim_start_matches([' ', '\t']);
+    if matches!(command, Command::Noop | Command::Break) {
+        if !arg.is_empty() {
+            eprintln!("error: {} does not accept arguments: '{}'", command.name(), arg);
+            return None;
+        }
+        return Some(Item::new(command, None, ""));
+    }
+    if arg.len() == rest.len() {
+        eprintln!("error: missing arguments for {}", command.name());
+        return None;
+    }
+    if matches!(command, Command::Exec | Command::Label | Command::Reset) {
+        return Some(Item::new(command, None, arg));
+    }
+
+    let mut flag = None;
+    let mut arg = arg;
+    if matches!(command, Command::Fixup | Command::Merge) {
+        for option in ['C', 'c'] {
+            let prefix = format!("-{}", option);
+            if let Some(after) = arg.strip_prefix(&prefix).filter(|after| after.starts_with([' ', '\t'])) {
+                flag = Some(option);
+                arg = after.trim_start_matches([' ', '\t']);
+                break;
+            }
+        }
+        if command == Command::Merge && flag.is_none() {
+            return Some(Item::new(command, None, arg));
+        }
+    }
+    let end = arg.find([' ', '\t']).unwrap_or(arg.len());
+    let name = &arg[..end];
+    let Some(commit) = resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).ok() else {
+        eprintln!("error: could not parse '{}'", name);
+        return None;
+    };
+    let mut item = Item::new(command, Some(commit), arg[end..].trim_start_matches([' ', '\t']));
+    item.flag = flag;
+    Some(item)
+}
+
+// Parse a todo list, reporting every line that is wrong. A fixup or squash needs a commit
+// before it to fold into, which a list resumed part way through has already picked.
+fn parse_todo(text: &str, mut fixup_okay: bool) -> Option<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut valid = true;
+    for (number, line) in text.lines().enumerate() {
+        let Some(item) = parse_line(line) else {
+            eprintln!("error: invalid line {}: {}", number + 1, line);
+            valid = false;
+            continue;
+        };
+        if !fixup_okay && item.command.is_fixup() {
+            eprintln!("error: cannot '{}' without a previous commit", item.command.name());
+            valid = false;
+        } else if !item.command.is_noop() {
+            fixup_okay = true;
+        }
+        items.push(item);
+    }
+    valid.then_some(items)
+}
+
+fn write_todo(items: &[Item]) -> anyhow::Result<()> {
+    write_state("git-rebase-todo", &todo_text(items, false))
+}
+
+// The remaining todo list of the rebase in progress
+fn read_todo() -> anyhow::Result<Vec<Item>> {
+    let text = read_state("git-rebase-todo").ok_or_else(|| anyhow::anyhow!("could not read '{}'", state_path("git-rebase-todo")))?;
+    match parse_todo(&text, state_exists("done")) {
+        Some(items) => Ok(items),
+        None => {
+            eprintln!("error: please fix this using 'git rebase --edit-todo'.");
+            std::process::exit(1);
+        }
+    }
+}
+
+// A todo line as status shows it: the commit abbreviated where the second word names one
+fn abbreviate_line(line: &str) -> String {
+    let mut words = line.splitn(3, ' ');
+    let (Some(command), Some(name)) = (words.next(), words.next()) else {
+        return line.to_string();
+    };
+    if matches!(command, "exec" | "x" | "label" | "l") {
+        return line.to_string();
+    }
+    match resolve_revision(name) {
+        Ok(hash) => [command.to_string(), abbreviate(&hash)].into_iter().chain(words.map(|x| x.to_string())).collect::<Vec<_>>().join(" "),
+        Err(_) => line.to_string(),
+    }
+}
+
+// RebaseProgress is what status shows of a rebase in progress: the branch being rebased, the
+// abbreviated commit it goes onto, and the commands done and left to do
+pub struct RebaseProgress {
+    pub branch: Option<String>,
+    pub onto: String,
+    pub done: Vec<String>,
+    pub todo: Option<Vec<String>>,
+}
+
+pub fn rebase_progress() -> Option<RebaseProgress> {
+    if !Path::new(STATE_DIR).is_dir() {
+        return None;
+    }
+    let head_name = read_state_line("head-name").unwrap_or_default();
+    let lines = |name: &str| -> Option<Vec<String>> {
+        let text = read_state(name)?;
+        Some(text.lines().filter(|line| !line.starts_with('#')).map(|line| line.trim()).filter(|line| !line.is_empty()).map(abbreviate_line).collect())
+    };
+    Some(RebaseProgress {
+        branch: head_name.strip_prefix("refs/heads/").map(|x| x.to_string()),
+        onto: abbreviate(&read_state_line("onto").unwrap_or_default()),
+        done: lines("done").unwrap_or_default(),
+        todo: lines("git-rebase-todo"),
+    })
+}
+
+// What to do about a commit that is left with nothing to change: drop it, keep it, or stop
+// for the user to decide
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Empty {
+    Drop,
+    Keep,
+    Ask,
+}
+
+// Rebase holds the settings of the rebase in progress, kept in the state directory between
+// runs. The reflog action names the command being carried out in the messages of the commits
+// it makes.
+struct Rebase {
+    head_name: String,
+    onto: String,
+    orig_head: String,
+    quiet: bool,
+    allow_ff: bool,
+    empty: Empty,
+    strategy_options: Vec<String>,
+    reflog_action: String,
+}
+
+impl Rebase {
+    fn load(reflog_action: &str) -> anyhow::Result<Rebase> {
+        let read = |name: &str| read_state_line(name).ok_or_else(|| anyhow::anyhow!("could not read '{}'", state_path(name)));
+        let empty = if state_exists("drop_redundant_commits") {
+            Empty::Drop
+        } else if state_exists("keep_redundant_commits") {
+            Empty::Keep
+        } else {
+            Empty::Ask
+        };
+        Ok(Rebase {
+            head_name: read("head-name")?,
+            onto: read("onto")?,
+            orig_head: read("orig-head")?,
+            quiet: state_exists("quiet"),
+            allow_ff: true,
+            empty,
+            strategy_options: read_state("strategy_opts").unwrap_or_default().split_whitespace().map(|x| x.trim_start_matches("--").to_string()).collect(),
+            reflog_action: reflog_action.to_string(),
+        })
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(STATE_DIR)?;
+        write_state("head-name", &format!("{}\n", self.head_name))?;
+        write_state("onto", &format!("{}\n", self.onto))?;
+        write_state("orig-head", &format!("{}\n", self.orig_head))?;
+        write_state("interactive", "")?;
+        if self.quiet {
+            write_state("quiet", "")?;
+        }
+        match self.empty {
+            Empty::Drop => write_state("drop_redundant_commits", "")?,
+            Empty::Keep => write_state("keep_redundant_commits", "")?,
+            Empty::Ask => {}
+        }
+        write_state("no-reschedule-failed-exec", "")?;
+        if !self.strategy_options.is_empty() {
+            let options: String = self.strategy_options.iter().map(|option| format!(" --{}", option)).collect();
+            write_state("strategy_opts", &format!("{}\n", options))?;
+        }
+        Ok(())
+    }
+}
+
+// How a command went: on to the next one, stopped for the user as asked, failed for the user
+// to sort out, or failed before it changed anything so that it is to be tried again
+enum Next {
+    Continue,
+    Stop,
+    Fail,
+    Reschedule,
+}
+
+// Wipe the progress line off the terminal before a message of its own
+fn term_clear_line() {
+    if is_terminal_dumb() {
+        eprint!("\r{}\r", " ".repeat(term_columns()));
+    } else {
+        eprint!("\r\x1b[K");
+    }
+}
+
+fn raw_author(commit: &Commit) -> String {
+    commit.raw_header.lines().find_map(|line| line.strip_prefix("author ")).unwrap_or("").to_string()
+}
+
+fn parent_tree_of(commit: &Commit) -> anyhow::Result<String> {
+    match commit.parents.first() {
+        Some(parent) => peel_to_tree(parent),
+        None => Ok(write_tree(&BTreeMap::new())),
+    }
+}
+
+fn head_commit() -> anyhow::Result<String> {
+    resolve_ref("HEAD").ok_or_else(|| anyhow::anyhow!("could not read HEAD"))
+}
+
+// Report local changes in the way of moving the working tree, leaving anything else an error
+fn check_update(result: anyhow::Result<()>) -> anyhow::Result<bool> {
+    match result {
+        Err(error) => match error.downcast_ref::<CheckoutConflict>() {
+            Some(conflict) => {
+                eprintln!("error: {}", conflict);
+                Ok(false)
+            }
+            None => Err(error),
+        },
+        Ok(()) => Ok(true),
+    }
+}
+
+fn has_unstaged_changes() -> anyhow::Result<bool> {
+    let mut index = read_index()?;
+    if index.iter().any(|entry| entry.stage() != 0) {
+        return Ok(true);
+    }
+    Ok(!diff_index_to_worktree(&mut index).0.is_empty())
+}
+
+// Complain about unstaged changes and changes staged but not committed, with a hint of what to
+// do about them. Returns whether the working tree was clean.
+fn require_clean_work_tree(hint: Option<&str>) -> anyhow::Result<bool> {
+    let unstaged = has_unstaged_changes()?;
+    if unstaged {
+        eprintln!("error: cannot rebase: You have unstaged changes.");
+    }
+    let uncommitted = !index_matches_head()?;
+    if uncommitted && unstaged {
+        eprintln!("error: additionally, your index contains uncommitted changes.");
+    } else if uncommitted {
+        eprintln!("error: cannot rebase: Your index contains uncommitted changes.");
+    }
+    if let (true, Some(hint)) = (unstaged || uncommitted, hint) {
+        eprintln!("error: {}", hint);
+    }
+    Ok(!unstaged && !uncommitted)
+}
+
+// Record the author of a commit being replayed as shell assignments, the form git keeps it in
+fn write_author_script(commit: &Commit) -> anyhow::Result<()> {
+    let quote = |value: &str| format!("'{}'", value.replace('\'', "'\\''"));
+    let author = &commit.author;
+    let date = format!("@{} {}", author.time, author.offset);
+    write_state(
+        "author-script",
+        &format!("GIT_AUTHOR_NAME={}\nGIT_AUTHOR_EMAIL={}\nGIT_AUTHOR_DATE={}\n", quote(&author.name), quote(&author.email), quote(&date)),
+    )
+}
+
+// The author line recorded in the author script
+fn read_author_script() -> Option<String> {
+    let script = read_state("author-script")?;
+    let mut values = HashMap::new();
+    for line in script.lines() {
+        let (key, value) = line.split_once('=')?;
+        let value = value.strip_prefix('\'')?.strip_suffix('\'')?.replace("'\\''", "'");
+        values.insert(key.to_string(), value);
+    }
+    let date = values.get("GIT_AUTHOR_DATE")?.trim_start_matches('@').to_string();
+    Some(format!("{} <{}> {}", values.get("GIT_AUTHOR_NAME")?, values.get("GIT_AUTHOR_EMAIL")?, date))
+}
+
+// Let the user word the message of the commit being made, as "git commit" does: the message
+// goes to COMMIT_EDITMSG above the commented template, and comes back cleaned up
+fn edit_message(config: &Config, message: &str, author: &str, parent: Option<&str>, show_date: bool) -> anyhow::Result<String> {
+    let mut text = message.trim_end_matches('\n').to_string();
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str("\n# Please enter the commit message for your changes. Lines starting\n");
+    text.push_str("# with '#' will be ignored, and an empty message aborts the commit.\n#\n");
+    let author = Signature::parse(author);
+    if author.identity() != Signature::parse(&signature(config, Role::Committer)?).identity() {
+        text.push_str(&format!("# Author:    {}\n", author.identity()));
+    }
+    if show_date {
+        text.push_str(&format!("# Date:      {}\n", format_date(author.time, &author.offset, &DateFormat::Default)));
+    }
+    text.push_str("#\n");
+    for line in commit_template_status(config, parent)? {
+        text.push_str(&format!("{}\n", line));
+    }
+    fs::write(".git/COMMIT_EDITMSG", &text)?;
+    launch_editor(git_editor(config), ".git/COMMIT_EDITMSG")?;
+    let message = cleanup_message(&fs::read_to_string(".git/COMMIT_EDITMSG")?);
+    if message.is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message.");
+    }
+    Ok(message)
+}
+
+// Make a commit of a tree and move the detached HEAD to it
+fn commit_tree(config: &Config, tree: &str, parents: &[String], author: &str, message: &str, action: &str) -> anyhow::Result<String> {
+    let commit = write_commit_as(tree, parents, author, message, config)?;
+    update_ref("HEAD", &commit, &format!("{}: {}", action, message.lines().next().unwrap_or("")))?;
+    Ok(commit)
+}
+
+// The patch a commit makes, as the user sees it while the rebase is stopped at the commit
+fn commit_patch(config: &Config, commit: &Commit) -> anyhow::Result<Vec<u8>> {
+    if commit.parents.len() > 1 {
+        return Ok(Vec::new());
+    }
+    let mut options = DiffOptions::new(config)?;
+    options.patch = true;
+    let parent_tree = parent_tree_of(commit)?;
+    let changes = TreeDiff { recursive: true, ..Default::default() }.diff(Some(&parent_tree), Some(&commit.tree))?;
+    options.render(&changes)
+}
+
+// Leave what the user needs to carry on from a command that stopped at a commit: its patch,
+// message and author, with REBASE_HEAD pointing at it
+fn record_stop(config: &Config, commit: &Commit) -> anyhow::Result<()> {
+    write_state("stopped-sha", &format!("{}\n", commit.hash))?;
+    write_ref("REBASE_HEAD", &commit.hash)?;
+    fs::write(state_path("patch"), commit_patch(config, commit)?)?;
+    if !state_exists("message") {
+        write_state("message", &format!("{}\n", commit.message.trim_start_matches('\n')))?;
+    }
+    if !state_exists("author-script") {
+        write_author_script(commit)?;
+    }
+    Ok(())
+}
+
+// Note that a commit was replayed, as "old new" lines in rewritten-list. Commits folded into
+// one by fixups and squashes wait in rewritten-pending until the last of them is done.
+fn record_in_rewritten(commit: &str, next: Option<Command>) -> anyhow::Result<()> {
+    append_state("rewritten-pending", &format!("{}\n", commit))?;
+    if next.map(|command| command.is_fixup()).unwrap_or(false) {
+        return Ok(());
+    }
+    let head = head_commit()?;
+    let pending = read_state("rewritten-pending").unwrap_or_default();
+    let lines: String = pending.lines().map(|old| format!("{} {}\n", old, head)).collect();
+    append_state("rewritten-list", &lines)?;
+    remove_state("rewritten-pending");
+    Ok(())
+}
+
+// Comment out every line of a text
+fn commented(text: &str) -> String {
+    text.lines().map(|line| if line.is_empty() { "#\n".to_string() } else { format!("# {}\n", line) }).collect()
+}
+
+// The length of the first paragraph of a message, newline included
+fn subject_length(message: &str) -> usize {
+    match message.find("\n\n") {
+        Some(index) => index + 1,
+        None => message.len(),
+    }
+}
+
+// Add a fixup or squash to message-squash, the message the chain of commits being folded into
+// one will get. It starts with HEAD's message; the messages of fixups are commented out, and
+// fixup-msg keeps the message to use as it is when no squash asks for an editor.
+fn update_squash_messages(item: &Item, commit: &Commit) -> anyhow::Result<()> {
+    let fixups = read_state("current-fixups").unwrap_or_default();
+    let count = fixups.lines().count();
+    let replace = item.command == Command::Fixup && item.flag.is_some();
+    let had_squash_message = state_exists("message-squash");
+    let mut buffer = if count > 0 {
+        let old = read_state("message-squash").unwrap_or_default();
+        let rest = old.split_once('\n').map(|x| x.1).unwrap_or("");
+        format!("# This is a combination of {} commits.\n{}", count + 2, rest)
+    } else {
+        let head = load_commit(&head_commit()?)?;
+        let body = head.message.trim_start_matches('\n');
+        if item.command == Command::Fixup && !replace {
+            write_state("fixup-msg", body)?;
+        }
+        match replace {
+            true => format!("# This is a combination of 2 commits.\n# The 1st commit message will be skipped:\n\n{}", commented(body)),
+            false => format!("# This is a combination of 2 commits.\n# This is the 1st commit message:\n\n{}", body),
+        }
+    };
+
+    let body = commit.message.trim_start_matches('\n');
+    let seen_squash = fixups.starts_with("squash") || fixups.contains("\nsquash");
+    if item.command == Command::Squash || replace {
+        let fixupish = body.starts_with("squash!") || body.starts_with("fixup!");
+        let skipped = match body.starts_with("amend!") || ((item.command == Command::Squash || seen_squash) && fixupish) {
+            true => subject_length(body),
+            false => 0,
+        };
+        buffer.push_str(&format!("\n# This is the commit message #{}:\n\n{}{}", count + 2, commented(&body[..skipped]), &body[skipped..]));
+        if replace && !seen_squash && item.flag == Some('C') && (state_exists("fixup-msg") || !had_squash_message) {
+            write_state("fixup-msg", body[skipped..].trim_start_matches('\n'))?;
+        } else {
+            remove_state("fixup-msg");
+        }
+    } else {
+        buffer.push_str(&format!("\n# The commit message #{} will be skipped:\n\n{}", count + 2, commented(body)));
+    }
+    write_state("message-squash", &buffer)?;
+
+    let mut command = item.command.name().to_string();
+    if let Some(flag) = item.flag {
+        command.push_str(&format!(" -{}", flag));
+    }
+    let separator = if fixups.is_empty() { "" } else { "\n" };
+    append_state("current-fixups", &format!("{}{} {}", separator, command, commit.hash))
+}
+
+fn clear_squash_state() {
+    for name in ["message-squash", "current-fixups", "fixup-msg"] {
+        remove_state(name);
+    }
+}
+
+// Fold a fixup or squash into HEAD. Along a chain HEAD is amended with message-squash as it
+// stands; the last of the chain takes fixup-msg, or has the user word the message when a
+// squash is in the chain.
+fn squash_into_head(config: &Config, rebase: &Rebase, tree: &str, final_fixup: bool) -> anyhow::Result<()> {
+    let head = load_commit(&head_commit()?)?;
+    let author = raw_author(&head);
+    let fixup_message = read_state("fixup-msg").filter(|_| final_fixup);
+    let edit = final_fixup && fixup_message.is_none();
+    let mut message = fixup_message.unwrap_or_else(|| read_state("message-squash").unwrap_or_default());
+    if edit {
+        message = edit_message(config, &message, &author, head.parents.first().map(|x| x.as_str()), true)?;
+    }
+    let commit = commit_tree(config, tree, &head.parents, &author, &message, &rebase.reflog_action)?;
+    let _ = fs::remove_file(".git/MERGE_MSG");
+    if final_fixup {
+        clear_squash_state();
+    }
+    if edit {
+        print_commit_summary(config, &commit, true)?;
+    }
+    Ok(())
+}
+
+// Amend HEAD with a message the user words, for "reword"
+fn reword_head(config: &Config, rebase: &Rebase) -> anyhow::Result<()> {
+    let head = load_commit(&head_commit()?)?;
+    let author = raw_author(&head);
+    let message = edit_message(config, &head.message, &author, head.parents.first().map(|x| x.as_str()), true)?;
+    let commit = commit_tree(config, &head.tree, &head.parents, &author, &message, &rebase.reflog_action)?;
+    print_commit_summary(config, &commit, true)
+}
+
+// Tell the user a replayed commit conflicted and how to go on from there
+fn report_conflict(commit: &Commit) {
+    eprintln!("error: could not apply {}... {}", abbreviate(&commit.hash), first_line(commit));
+    eprintln!("hint: Resolve all conflicts manually, mark them as resolved with");
+    eprintln!("hint: \"git add/rm <conflicted_files>\", then run \"git rebase --continue\".");
+    eprintln!("hint: You can instead skip this commit: run \"git rebase --skip\".");
+    eprintln!("hint: To abort and get back to the state before \"git rebase\", run \"git rebase --abort\".");
+}
+
+// Replay the commit of a pick, reword, edit, fixup or squash on top of HEAD. A commit whose
+// parent is HEAD is fast-forwarded to rather than made again, unless the rebase is forced.
+fn pick(config: &Config, rebase: &mut Rebase, item: &Item, next: Option<Command>) -> anyhow::Result<Next> {
+    let commit = load_commit(item.commit.as_deref().unwrap_or(""))?;
+    rebase.reflog_action = format!("rebase ({})", item.command.name());
+    if !index_matches_head()? {
+        eprintln!("error: your local changes would be overwritten by rebase.");
+        eprintln!("hint: commit your changes or stash them to proceed.");
+        return Ok(Next::Reschedule);
+    }
+    if commit.parents.len() > 1 {
+        eprintln!("error: commit {} is a merge but no -m option was given.", commit.hash);
+        return Ok(Next::Reschedule);
+    }
+    let head = head_commit()?;
+    let head_tree = peel_to_tree(&head)?;
+    write_author_script(&commit)?;
+
+    if rebase.allow_ff && !item.command.is_fixup() && commit.parents.first() == Some(&head) {
+        if !check_update(switch_tree(Some(&head_tree), Some(&commit.tree), false, "merge", "merge"))? {
+            return Ok(Next::Reschedule);
+        }
+        update_ref("HEAD", &commit.hash, "rebase: fast-forward")?;
+        if item.command == Command::Reword {
+            reword_head(config, rebase)?;
+        }
+        return Ok(Next::Continue);
+    }
+
+    if item.command.is_fixup() {
+        update_squash_messages(item, &commit)?;
+    }
+    let label = format!("{} ({})", abbreviate(&commit.hash), first_line(&commit));
+    let mut merge_options = MergeOptions::new(config, "HEAD", &label)?;
+    merge_options.labels[0] = format!("parent of {}", label);
+    for option in &rebase.strategy_options {
+        merge_options.strategy_option(option)?;
+    }
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(peel_to_tree(parent)?),
+        None => None,
+    };
+    let merge = merge_trees(parent_tree.as_deref(), &head_tree, &commit.tree, &merge_options)?;
+    if !check_update(apply_merge(Some(&head_tree), &merge))? {
+        return Ok(Next::Reschedule);
+    }
+    if !merge.clean {
+        for line in &merge.messages {
+            println!("{}", line);
+        }
+    }
+
+    let message = match item.command.is_fixup() {
+        true => read_state("message-squash").unwrap_or_default(),
+        false => commit.message.clone(),
+    };
+    if !merge.clean {
+        if item.command.is_fixup() {
+            fs::write(".git/MERGE_MSG", &message)?;
+            write_state("message", &message)?;
+            write_state("amend", &format!("{}\n", head))?;
+        } else {
+            let mut conflicts = message.clone();
+            if !conflicts.ends_with('\n') {
+                conflicts.push('\n');
+            }
+            conflicts.push_str("\n# Conflicts:\n");
+            for path in merge.conflicts.keys() {
+                conflicts.push_str(&format!("#\t{}\n", path));
+            }
+            fs::write(".git/MERGE_MSG", conflicts)?;
+        }
+        report_conflict(&commit);
+        record_stop(config, &commit)?;
+        eprintln!("Could not apply {}... {}", abbreviate(&commit.hash), item.arg);
+        return Ok(Next::Fail);
+    }
+
+    let tree = merge.tree();
+    if tree == head_tree && !item.command.is_fixup() && parent_tree_of(&commit)? != commit.tree {
+        match rebase.empty {
+            Empty::Drop => {
+                eprintln!("dropping {} {} -- patch contents already upstream", commit.hash, first_line(&commit));
+                let _ = fs::remove_file(".git/MERGE_MSG");
+                return Ok(Next::Continue);
+            }
+            Empty::Keep => {}
+            Empty::Ask => {
+                fs::write(".git/MERGE_MSG", &message)?;
+                fs::write(".git/COMMIT_EDITMSG", &message)?;
+                fs::write(".git/CHERRY_PICK_HEAD", format!("{}\n", commit.hash))?;
+                eprintln!("The previous cherry-pick is now empty, possibly due to conflict resolution.");
+                eprintln!("If you wish to commit it anyway, use:");
+                eprintln!();
+                eprintln!("    git commit --allow-empty");
+                eprintln!();
+                eprintln!("Otherwise, please use 'git rebase --skip'");
+                print_long_status(config)?;
+                record_stop(config, &commit)?;
+                eprintln!("Could not apply {}... {}", abbreviate(&commit.hash), item.arg);
+                return Ok(Next::Fail);
+            }
+        }
+    }
+
+    if item.command.is_fixup() {
+        fs::write(".git/MERGE_MSG", &commit.message)?;
+        squash_into_head(config, rebase, &tree, !next.map(|command| command.is_fixup()).unwrap_or(false))?;
+        return Ok(Next::Continue);
+    }
+    commit_tree(config, &tree, &[head], &raw_author(&commit), &message, &rebase.reflog_action)?;
+    let _ = fs::remove_file(".git/MERGE_MSG");
+    if item.command == Command::Reword {
+        reword_head(config, rebase)?;
+    }
+    Ok(Next::Continue)
+}
+
+// Stop after an "edit" pick, leaving the commit for the user to amend
+fn stop_for_edit(config: &Config, item: &Item) -> anyhow::Result<Next> {
+    let commit = load_commit(item.commit.as_deref().unwrap_or(""))?;
+    term_clear_line();
+    eprintln!("Stopped at {}...  {}", abbreviate(&commit.hash), item.arg);
+    record_stop(config, &commit)?;
+    write_state("amend", &format!("{}\n", head_commit()?))?;
+    eprint!("You can amend the commit now, with\n\n  git commit --amend \n\nOnce you are satisfied with your changes, run\n\n  git rebase --continue\n");
+    Ok(Next::Stop)
+}
+
+// Run an "exec" command through the shell. It fails when the command does, or leaves changes
+// behind that are not committed.
+fn exec(command: &str) -> anyhow::Result<Next> {
+    term_clear_line();
+    eprintln!("Executing: {}", command);
+    let status = std::process::Command::new("sh").arg("-c").arg(command).status();
+    let succeeded = status.map(|status| status.success()).unwrap_or(false);
+    let dirty = !require_clean_work_tree(None)?;
+    if !succeeded {
+        let changes = if dirty { "and made changes to the index and/or the working tree\n" } else { "" };
+        eprintln!("warning: execution failed: {}\n{}You can fix the problem, and then run\n\n  git rebase --continue\n", command, changes);
+        return Ok(Next::Fail);
+    }
+    if dirty {
+        eprintln!(
+            "warning: execution succeeded: {}\nbut left changes to the index and/or the working tree\nCommit or stash your changes, and then run\n\n  git rebase --continue\n\n",
+            command
+        );
+        return Ok(Next::Fail);
+    }
+    Ok(Next::Continue)
+}
+
+// Name HEAD with a label under refs/rewritten, for a later reset or merge to refer to
+fn label(name: &str) -> anyhow::Result<Next> {
+    if check_branch_name(name).is_err() {
+        eprintln!("error: '{}' is not a valid label", name);
+        return Ok(Next::Reschedule);
+    }
+    let refname = format!("refs/rewritten/{}", name);
+    write_ref(&refname, &head_commit()?)?;
+    append_state("refs-to-delete", &format!("{}\n", refname))?;
+    Ok(Next::Continue)
+}
+
+// The commit a label names: the one it was set to, or else whatever the name resolves to.
+// Also returns the name to show for it.
+fn lookup_label(name: &str) -> Option<(String, String)> {
+    let refname = format!("refs/rewritten/{}", name);
+    if let Some(commit) = resolve_ref(&refname) {
+        return Some((commit, refname));
+    }
+    let commit = resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).ok()?;
+    Some((commit, name.to_string()))
+}
+
+// Move HEAD, the index and the working tree to a label
+fn reset(arg: &str) -> anyhow::Result<Next> {
+    let name = arg.split([' ', '\t']).next().unwrap_or("");
+    let Some((commit, _)) = lookup_label(name) else {
+        eprintln!("error: could not resolve '{}'", name);
+        return Ok(Next::Reschedule);
+    };
+    if !check_update(switch_tree(head_tree()?.as_deref(), Some(&peel_to_tree(&commit)?), false, "merge", "merge"))? {
+        return Ok(Next::Reschedule);
+    }
+    update_ref("HEAD", &commit, &format!("rebase (reset): '{}'", name))?;
+    Ok(Next::Continue)
+}
+
+// Merge a label into HEAD, with the message of the original merge commit given by -C or -c,
+// or one the user words. A merge whose original already has exactly these parents is
+// fast-forwarded to.
+fn merge(config: &Config, rebase: &Rebase, item: &Item) -> anyhow::Result<Next> {
+    let arg = item.arg.split(" # ").next().unwrap_or("");
+    let labels: Vec<&str> = arg.split_whitespace().collect();
+    let [name] = labels.as_slice() else {
+        eprintln!("error: octopus merges are not supported");
+        return Ok(Next::Reschedule);
+    };
+    let Some((merge_commit, merge_label)) = lookup_label(name) else {
+        eprintln!("error: could not resolve '{}'", name);
+        return Ok(Next::Reschedule);
+    };
+    let head = head_commit()?;
+    let head_tree = peel_to_tree(&head)?;
+    let original = match &item.commit {
+        Some(commit) => Some(load_commit(commit)?),
+        None => None,
+    };
+    if let Some(original) = original.as_ref().filter(|original| rebase.allow_ff && original.parents == [head.clone(), merge_commit.clone()]) {
+        if !check_update(switch_tree(Some(&head_tree), Some(&original.tree), false, "merge", "merge"))? {
+            return Ok(Next::Reschedule);
+        }
+        update_ref("HEAD", &original.hash, "rebase: fast-forward")?;
+        return Ok(Next::Continue);
+    }
+    if !index_matches_head()? {
+        eprintln!("error: your local changes would be overwritten by rebase.");
+        eprintln!("hint: commit your changes or stash them to proceed.");
+        return Ok(Next::Reschedule);
+    }
+
+    let message = match &original {
+        Some(original) => original.message.trim_start_matches('\n').to_string(),
+        None => match item.arg.split_once(" # ") {
+            Some((_, oneline)) => format!("{}\n", oneline),
+            None => format!("Merge branch '{}'\n", name),
+        },
+    };
+    fs::write(".git/MERGE_MSG", &message)?;
+    fs::write(".git/MERGE_HEAD", format!("{}\n", merge_commit))?;
+    if merge_bases(&head, &merge_commit)?.first() == Some(&merge_commit) {
+        remove_merge_state();
+        return Ok(Next::Continue);
+    }
+    let mut merge_options = MergeOptions::new(config, "HEAD", &merge_label)?;
+    for option in &rebase.strategy_options {
+        merge_options.strategy_option(option)?;
+    }
+    let merge = merge_commits(&head, &merge_commit, merge_options)?;
+    if !check_update(apply_merge(Some(&head_tree), &merge))? {
+        remove_merge_state();
+        return Ok(Next::Reschedule);
+    }
+    if !merge.clean {
+        for line in &merge.messages {
+            println!("{}", line);
+        }
+        match &original {
+            Some(original) => {
+                record_stop(config, original)?;
+                eprintln!("Could not apply {}... {}", abbreviate(&original.hash), item.arg);
+            }
+            None => {
+                write_state("message", &message)?;
+                eprintln!("Could not merge {}", item.arg);
+            }
+        }
+        return Ok(Next::Fail);
+    }
+
+    let author = match &original {
+        Some(original) => raw_author(original),
+        None => signature(config, Role::Author)?,
+    };
+    let edit = item.flag != Some('C');
+    let parents = [head.clone(), merge_commit.clone()];
+    if !rebase.strategy_options.is_empty() {
+        // Merges with strategy options are made the way "git merge" makes them
+        let message = match edit {
+            true => edit_message(config, &message, &author, Some(&head), false)?,
+            false => cleanup_message(&message),
+        };
+        let commit = write_commit_as(&merge.tree(), &parents, &author, &message, config)?;
+        update_ref("ORIG_HEAD", &head, "")?;
+        update_ref("HEAD", &commit, &format!("merge {}: Merge made by the 'ort' strategy.", merge_commit))?;
+        remove_merge_state();
+        println!("Merge made by the 'ort' strategy.");
+        return Ok(Next::Continue);
+    }
+    let message = cleanup_message(&message);
+    fs::write(".git/COMMIT_EDITMSG", &message)?;
+    commit_tree(config, &merge.tree(), &parents, &author, &message, &rebase.reflog_action)?;
+    remove_merge_state();
+    if edit {
+        // The merge is made first, then amended with the message the user words
+        let message = edit_message(config, &message, &author, Some(&head), true)?;
+        let commit = commit_tree(config, &merge.tree(), &parents, &author, &message, &rebase.reflog_action)?;
+        print_commit_summary(config, &commit, true)?;
+    }
+    Ok(Next::Continue)
+}
+
+// Carry out one command
+fn run_command(config: &Config, rebase: &mut Rebase, item: &Item, next: Option<Command>) -> anyhow::Result<Next> {
+    match item.command {
+        Command::Break => {
+            term_clear_line();
+            match resolve_ref("HEAD").and_then(|head| load_commit(&head).ok()) {
+                Some(head) => eprintln!("Stopped at {} ({})", abbreviate(&head.hash), first_line(&head)),
+                None => eprintln!("Stopped at HEAD"),
+            }
+            Ok(Next::Stop)
+        }
+        command if command.picks() => {
+            let result = pick(config, rebase, item, next)?;
+            if let Next::Continue = result {
+                if command == Command::Edit {
+                    return stop_for_edit(config, item);
+                }
+                record_in_rewritten(item.commit.as_deref().unwrap_or(""), next)?;
+            }
+            Ok(result)
+        }
+        Command::Exec => exec(&item.arg),
+        Command::Label => label(&item.arg),
+        Command::Reset => reset(&item.arg),
+        Command::Merge => {
+            let result = merge(config, rebase, item)?;
+            if let (Some(commit), Next::Continue | Next::Fail) = (&item.commit, &result) {
+                record_in_rewritten(commit, next)?;
+            }
+            Ok(result)
+        }
+        _ => Ok(Next::Continue),
+    }
+}
+
+// Carry out the todo list from the top. Each command moves to the done list as it starts; the
+// rebase finishes once the list is empty, and stops with the state in place when a command
+// needs the user.
+fn run_todo(config: &Config, rebase: &mut Rebase, mut todo: Vec<Item>) -> anyhow::Result<()> {
+    let total: usize = read_state_line("end").and_then(|x| x.parse().ok()).unwrap_or(0);
+    let mut done = count_commands(&parse_todo(&read_state("done").unwrap_or_default(), true).unwrap_or_default());
+    while !todo.is_empty() {
+        let item = todo.remove(0);
+        write_todo(&todo)?;
+        append_state("done", &format!("{}\n", item.line(false)))?;
+        if item.command != Command::Comment {
+            done += 1;
+            write_state("msgnum", &format!("{}\n", done))?;
+            if !rebase.quiet {
+                eprint!("Rebasing ({}/{})\r", done, total);
+            }
+        }
+        for name in ["message", "author-script", "stopped-sha", "amend"] {
+            remove_state(name);
+        }
+        for name in ["MERGE_HEAD", "AUTO_MERGE", "REBASE_HEAD"] {
+            let _ = fs::remove_file(format!(".git/{}", name));
+        }
+
+        let next = todo.iter().find(|item| item.command != Command::Comment).map(|item| item.command);
+        match run_command(config, rebase, &item, next)? {
+            Next::Continue if item.command == Command::Exec => todo = read_todo()?,
+            Next::Continue => {}
+            Next::Stop => return Ok(()),
+            Next::Fail => std::process::exit(1),
+            Next::Reschedule => {
+                let line = item.line(false);
+                todo.insert(0, item);
+                write_todo(&todo)?;
+                eprintln!("hint: Could not execute the todo command\nhint: \nhint:     {}\nhint: ", line);
+                eprintln!("hint: It has been rescheduled; To edit the command before continuing, please");
+                eprintln!("hint: edit the todo list first:\nhint: \nhint:     git rebase --edit-todo\nhint:     git rebase --continue");
+                std::process::exit(1);
+            }
+        }
+    }
+    finish(rebase)
+}
+
+// Delete the labels the rebase made
+fn delete_labels() {
+    for refname in read_state("refs-to-delete").unwrap_or_default().lines() {
+        let _ = delete_ref(refname);
+    }
+}
+
+// Point the branch being rebased at the result and check it out again
+fn finish(rebase: &Rebase) -> anyhow::Result<()> {
+    if rebase.head_name.starts_with("refs/") {
+        let head = head_commit()?;
+        update_ref(&rebase.head_name, &head, &format!("rebase (finish): {} onto {}", rebase.head_name, rebase.onto))?;
+        update_symbolic_ref("HEAD", &rebase.head_name, &format!("rebase (finish): returning to {}", rebase.head_name))?;
+    }
+    if !rebase.quiet {
+        term_clear_line();
+        eprintln!("Successfully rebased and updated {}.", rebase.head_name);
+    }
+    delete_labels();
+    let _ = fs::remove_dir_all(STATE_DIR);
+    Ok(())
+}
+
+// Commit what the user staged while the rebase was stopped: the resolution of a conflicted
+// pick or merge, or changes to amend the commit an "edit" stopped at. Nothing staged leaves
+// the stopped command's commit out.
+fn commit_staged_changes(config: &Config, rebase: &Rebase) -> anyhow::Result<()> {
+    if has_unstaged_changes()? {
+        eprintln!("error: cannot rebase: You have unstaged changes.");
+        std::process::exit(1);
+    }
+    let clean = index_matches_head()?;
+    let head = head_commit()?;
+    let amend = read_state_line("amend");
+    if let Some(amend) = &amend {
+        if !clean && *amend != head {
+            eprintln!("error: \nYou have uncommitted changes in your working tree. Please, commit them\nfirst and then run 'git rebase --continue' again.");
+            std::process::exit(1);
+        }
+    }
+    if clean {
+        let _ = fs::remove_file(".git/CHERRY_PICK_HEAD");
+        let _ = fs::remove_file(".git/MERGE_MSG");
+        return Ok(());
+    }
+    let Some(message) = read_state("message") else {
+        if !state_exists("author-script") {
+            eprintln!("error: could not open '{}' for reading: No such file or directory", state_path("author-script"));
+        }
+        eprintln!("error: you have staged changes in your working tree");
+        eprint!("If these changes are meant to be squashed into the previous commit, run:\n\n  git commit --amend \n\n");
+        eprint!("If they are meant to go into a new commit, run:\n\n  git commit \n\n");
+        eprint!("In both cases, once you're done, continue with:\n\n  git rebase --continue\n\n");
+        eprintln!("error: could not commit staged changes.");
+        std::process::exit(1);
+    };
+
+    let head_commit = load_commit(&head)?;
+    let (parents, author) = match amend {
+        Some(_) => (head_commit.parents.clone(), raw_author(&head_commit)),
+        None => {
+            let mut parents = vec![head.clone()];
+            parents.extend(crate::refs::read_ref("MERGE_HEAD"));
+            let author = read_author_script().map(Ok).unwrap_or_else(|| signature(config, Role::Author))?;
+            (parents, author)
+        }
+    };
+    let message = edit_message(config, &message, &author, parents.first().map(|x| x.as_str()), amend.is_some())?;
+    let tree = crate::sequencer::index_tree()?;
+    let commit = commit_tree(config, &tree, &parents, &author, &message, &rebase.reflog_action)?;
+    remove_state("amend");
+    remove_merge_state();
+    let _ = fs::remove_file(".git/CHERRY_PICK_HEAD");
+    if state_exists("current-fixups") {
+        clear_squash_state();
+    }
+    print_commit_summary(config, &commit, amend.is_some())
+}
+
+fn no_rebase_in_progress() -> anyhow::Result<()> {
+    if !Path::new(STATE_DIR).is_dir() {
+        anyhow::bail!("No rebase in progress?");
+    }
+    Ok(())
+}
+
+// Pick up a stopped rebase where it left off
+fn continue_rebase(config: &Config, action: &str) -> anyhow::Result<()> {
+    no_rebase_in_progress()?;
+    for path in unmerged_paths()? {
+        println!("{}: needs merge", path);
+    }
+    if has_unstaged_changes()? {
+        println!("You must edit all merge conflicts and then\nmark them as resolved using git add");
+        std::process::exit(1);
+    }
+    let mut rebase = Rebase::load(&format!("rebase ({})", action))?;
+    commit_staged_changes(config, &rebase)?;
+    let todo = read_todo()?;
+    if let Some(stopped) = read_state_line("stopped-sha") {
+        let next = todo.iter().find(|item| item.command != Command::Comment).map(|item| item.command);
+        record_in_rewritten(&stopped, next)?;
+        remove_state("stopped-sha");
+    }
+    let done = count_commands(&parse_todo(&read_state("done").unwrap_or_default(), true).unwrap_or_default());
+    write_state("end", &format!("{}\n", done + count_commands(&todo)))?;
+    run_todo(config, &mut rebase, todo)
+}
+
+// Throw away whatever the stopped command left and go on with the next one
+fn skip_rebase(config: &Config) -> anyhow::Result<()> {
+    no_rebase_in_progress()?;
+    let head = head_commit()?;
+    reset_index_and_worktree(ResetMode::Hard, "HEAD", Some(&peel_to_tree(&head)?), &[], true, false)?;
+    remove_branch_state();
+    continue_rebase(config, "skip")
+}
+
+// Go back to the branch as it was before the rebase started
+fn abort_rebase() -> anyhow::Result<()> {
+    no_rebase_in_progress()?;
+    let rebase = Rebase::load("rebase (abort)")?;
+    reset_index_and_worktree(ResetMode::Hard, &rebase.orig_head, Some(&peel_to_tree(&rebase.orig_head)?), &[], true, false)?;
+    let message = format!("rebase (abort): returning to {}", rebase.head_name);
+    if rebase.head_name.starts_with("refs/") {
+        update_symbolic_ref("HEAD", &rebase.head_name, &message)?;
+    } else {
+        update_ref("HEAD", &rebase.orig_head, &message)?;
+    }
+    remove_branch_state();
+    let _ = fs::remove_file(".git/REBASE_HEAD");
+    delete_labels();
+    let _ = fs::remove_dir_all(STATE_DIR);
+    Ok(())
+}
+
+// The help appended to a todo list for the user to edit. A list being edited part way through
+// the rebase gets no summary line.
+fn todo_help(summary: Option<String>) -> String {
+    let mut help = String::new();
+    let initial = summary.is_some();
+    if let Some(summary) = summary {
+        help.push_str(&format!("\n# {}\n", summary));
+    }
+    let text = "\nCommands:
+p, pick <commit> = use commit
+r, reword <commit> = use commit, but edit the commit message
+e, edit <commit> = use commit, but stop for amending
+s, squash <commit> = use commit, but meld into previous commit
+f, fixup [-C | -c] <commit> = like \"squash\" but keep only the previous
+                   commit's log message, unless -C is used, in which case
+                   keep only this commit's message; -c is same as -C but
+                   opens the editor
+x, exec <command> = run command (the rest of the line) using shell
+b, break = stop here (continue rebase later with 'git rebase --continue')
+d, drop <commit> = remove commit
+l, label <label> = label current HEAD with a name
+t, reset <label> = reset HEAD to a label
+m, merge [-C <commit> | -c <commit>] <label> [# <oneline>]
+        create a merge commit using the original merge commit's
+        message (or the oneline, if no original merge commit was
+        specified); use -c <commit> to reword the commit message
+
+These lines can be re-ordered; they are executed from top to bottom.
+
+If you remove a line here THAT COMMIT WILL BE LOST.
+";
+    help.push_str(&commented(text));
+    match initial {
+        true => help.push_str(&commented("\nHowever, if you remove everything, the rebase will be aborted.\n\n")),
+        false => help.push_str(&commented(
+            "\nYou are editing the todo file of an ongoing interactive rebase.\nTo continue rebase after editing, run:\n    git rebase --continue\n\n",
+        )),
+    }
+    help
+}
+
+// Let the user edit the todo list of the rebase in progress
+fn edit_todo(config: &Config) -> anyhow::Result<()> {
+    no_rebase_in_progress()?;
+    let text = read_state("git-rebase-todo").unwrap_or_default();
+    let mut edited = String::new();
+    for (number, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Some(item) if item.command != Command::Comment => edited.push_str(&format!("{}\n", item.line(true))),
+            Some(_) => edited.push_str(&format!("{}\n", line)),
+            None => {
+                eprintln!("error: invalid line {}: {}", number + 1, line);
+                edited.push_str(&format!("{}\n", line));
+            }
+        }
+    }
+    edited.push_str(&todo_help(None));
+    write_state("git-rebase-todo", &edited)?;
+    launch_editor(sequence_editor(config), &state_path("git-rebase-todo"))?;
+    let text = cleanup_message(&read_state("git-rebase-todo").unwrap_or_default());
+    write_state("git-rebase-todo", &text)?;
+    match parse_todo(&text, true) {
+        Some(items) => write_todo(&items),
+        None => {
+            eprintln!("You can fix this with 'git rebase --edit-todo' and then run 'git rebase --continue'.");
+            eprintln!("Or you can abort the rebase with 'git rebase --abort'.");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Labels for the commits of a todo list made with --rebase-merges, unique regardless of case
+#[derive(Default)]
+struct Labels {
+    names: HashMap<String, String>,
+    taken: HashSet<String>,
+}
+
+impl Labels {
+    // Give a commit a label made from the text, or its abbreviated id when there is none
+    fn assign(&mut self, commit: &str, text: Option<&str>) -> String {
+        let mut label = String::new();
+        if let Some(text) = text {
+            for x in text.chars() {
+                if x.is_alphanumeric() || x == '_' || x == '.' {
+                    label.push(x);
+                } else if !label.is_empty() && !label.ends_with('-') {
+                    label.push('-');
+                }
+            }
+            while label.ends_with('-') {
+                label.pop();
+            }
+        }
+        if text.is_some() && label.is_empty() {
+            label = format!("rev-{}", abbreviate(commit));
+        }
+        if label.is_empty() {
+            label = abbreviate(commit);
+        }
+        if self.taken.contains(&label.to_lowercase()) || check_branch_name(&label).is_err() {
+            let base = label.clone();
+            let mut number = 2;
+            while self.taken.contains(&label.to_lowercase()) {
+                label = format!("{}-{}", base, number);
+                number += 1;
+            }
+        }
+        self.taken.insert(label.to_lowercase());
+        self.names.insert(commit.to_string(), label.clone());
+        label
+    }
+
+    fn get(&self, commit: &str) -> Option<&String> {
+        self.names.get(commit)
+    }
+}
+
+// The name a merge commit's message gives the branch it merged
+fn merge_label_text(subject: &str) -> &str {
+    if let Some(rest) = subject.strip_prefix("Merge ") {
+        if let Some(start) = rest.find('\'') {
+            if let Some(end) = rest[start + 1..].find('\'') {
+                return &rest[start + 1..start + 1 + end];
+            }
+        }
+        if let Some(index) = rest.find(" from ").filter(|_| rest.starts_with("pull request ")) {
+            return &rest[index + " from ".len()..];
+        }
+    }
+    subject
+}
+
+// The todo list for --rebase-merges: each branch of the history is picked after a reset to
+// where it starts and labelled where it ends, and merges are remade from the labels
+fn merge_script(commits: &[Commit], onto: &str, skip: &dyn Fn(&Commit) -> bool) -> Vec<Item> {
+    let mut labels = Labels::default();
+    labels.taken.insert("onto".to_string());
+    labels.names.insert(onto.to_string(), "onto".to_string());
+    let mut interesting = HashSet::new();
+    let mut todo_of: HashMap<String, Item> = HashMap::new();
+    let mut tips: Vec<String> = Vec::new();
+
+    for commit in commits {
+        interesting.insert(commit.hash.clone());
+        if skip(commit) {
+            continue;
+        }
+        let subject = commit.subject();
+        if commit.parents.len() < 2 {
+            todo_of.insert(commit.hash.clone(), Item::new(Command::Pick, Some(commit.hash.clone()), &subject));
+            continue;
+        }
+        let mut names = Vec::new();
+        for parent in &commit.parents[1..] {
+            let label = match labels.get(parent) {
+                Some(label) => label.clone(),
+                None if !interesting.contains(parent) => labels.assign(parent, None),
+                None => {
+                    tips.push(parent.clone());
+                    labels.assign(parent, Some(merge_label_text(&subject)))
+                }
+            };
+            names.push(label);
+        }
+        let mut item = Item::new(Command::Merge, Some(commit.hash.clone()), &format!("{} # {}", names.join(" "), subject));
+        item.flag = Some('C');
+        todo_of.insert(commit.hash.clone(), item);
+    }
+
+    let mut child_seen = HashSet::new();
+    for commit in commits {
+        for parent in &commit.parents {
+            if interesting.contains(parent) && !child_seen.insert(parent.clone()) && labels.get(parent).is_none() {
+                labels.assign(parent, Some("branch-point"));
+            }
+        }
+    }
+    if let Some(last) = commits.last() {
+        tips.push(last.hash.clone());
+    }
+
+    let mut items = vec![Item::new(Command::Label, None, "onto")];
+    let mut shown = HashSet::new();
+    let by_hash: HashMap<&str, &Commit> = commits.iter().map(|commit| (commit.hash.as_str(), commit)).collect();
+    for tip in tips {
+        if shown.contains(&tip) {
+            continue;
+        }
+        items.push(Item::comment(""));
+        if let Some(label) = labels.get(&tip).filter(|label| *label != "onto") {
+            items.push(Item::comment(&format!("# Branch {}", label)));
+        }
+        let mut branch = Vec::new();
+        let mut next = Some(tip.clone());
+        while let Some(hash) = next.take() {
+            let Some(commit) = by_hash.get(hash.as_str()).filter(|_| !shown.contains(&hash)) else {
+                next = Some(hash);
+                break;
+            };
+            branch.push(hash.clone());
+            shown.insert(hash);
+            next = commit.parents.first().cloned();
+        }
+        match next {
+            None => items.push(Item::new(Command::Reset, None, "[new root]")),
+            Some(base) => {
+                let label = match labels.get(&base) {
+                    Some(label) => label.clone(),
+                    None => labels.assign(&base, None),
+                };
+                match label.as_str() {
+                    "onto" => items.push(Item::new(Command::Reset, None, "onto")),
+                    _ => {
+                        let subject = load_commit(&base).map(|commit| commit.subject()).unwrap_or_default();
+                        items.push(Item::new(Command::Reset, None, &format!("{} # {}", label, subject)));
+                    }
+                }
+            }
+        }
+        for hash in branch.iter().rev() {
+            if let Some(item) = todo_of.get(hash) {
+                items.push(item.clone());
+            }
+            if let Some(label) = labels.get(hash) {
+                items.push(Item::new(Command::Label, None, label));
+            }
+        }
+    }
+    items
+}
+
+// The subject a "fixup! ", "amend! " or "squash! " commit names as its target
+fn fixup_target(subject: &str) -> Option<&str> {
+    let mut rest = ["fixup! ", "amend! ", "squash! "].iter().find_map(|prefix| subject.strip_prefix(prefix))?;
+    loop {
+        rest = rest.trim_start();
+        match ["fixup! ", "amend! ", "squash! "].iter().find_map(|prefix| rest.strip_prefix(prefix)) {
+            Some(after) => rest = after,
+            None => return Some(rest),
+        }
+    }
+}
+
+// Move each "fixup! ", "amend! " or "squash! " commit to follow the commit it names, turned
+// into the matching command, as --autosquash does
+fn rearrange_squash(mut items: Vec<Item>) -> anyhow::Result<Vec<Item>> {
+    let count = items.len();
+    let mut next: Vec<Option<usize>> = vec![None; count];
+    let mut tail: Vec<Option<usize>> = vec![None; count];
+    let mut subjects: Vec<Option<String>> = vec![None; count];
+    let mut by_subject: HashMap<String, usize> = HashMap::new();
+    let mut by_commit: HashMap<String, usize> = HashMap::new();
+    let mut moved = vec![false; count];
+
+    for i in 0..count {
+        let Some(hash) = items[i].commit.clone().filter(|_| items[i].command != Command::Drop) else {
+            continue;
+        };
+        let subject = load_commit(&hash)?.subject();
+        subjects[i] = Some(subject.clone());
+        let target = fixup_target(&subject).and_then(|name| {
+            by_subject
+                .get(name)
+                .copied()
+                .or_else(|| {
+                    let commit = (!name.contains(' ')).then(|| resolve_revision(name).and_then(|x| peel_to_commit(&x)).ok()).flatten()?;
+                    by_commit.get(&commit).copied()
+                })
+                .or_else(|| (0..i).find(|j| subjects[*j].as_deref().map(|x| x.starts_with(name)).unwrap_or(false)))
+        });
+        match target {
+            Some(target) => {
+                moved[i] = true;
+                if subject.starts_with("fixup!") {
+                    items[i].command = Command::Fixup;
+                } else if subject.starts_with("amend!") {
+                    items[i].command = Command::Fixup;
+                    items[i].flag = Some('C');
+                } else {
+                    items[i].command = Command::Squash;
+                }
+                let after = tail[target].unwrap_or(target);
+                next[i] = next[after];
+                next[after] = Some(i);
+                tail[target] = Some(i);
+            }
+            None => {
+                by_subject.entry(subject).or_insert(i);
+            }
+        }
+        by_commit.insert(hash, i);
+    }
+
+    let mut rearranged = Vec::new();
+    for (i, _) in moved.iter().enumerate().filter(|(_, moved)| !**moved) {
+        let mut index = Some(i);
+        while let Some(current) = index {
+            rearranged.push(items[current].clone());
+            index = next[current];
+        }
+    }
+    Ok(rearranged)
+}
+
+// Run the --exec commands after each commit picked or merged, and after the fixups and
+// squashes folded into it
+fn add_exec_commands(items: Vec<Item>, commands: &[String]) -> Vec<Item> {
+    let mut result = Vec::new();
+    let mut insert = false;
+    for item in items {
+        if insert && !item.command.is_fixup() {
+            result.extend(commands.iter().map(|command| Item::new(Command::Exec, None, command)));
+            insert = false;
+        }
+        if matches!(item.command, Command::Pick | Command::Merge) {
+            insert = true;
+        }
+        result.push(item);
+    }
+    if insert {
+        result.extend(commands.iter().map(|command| Item::new(Command::Exec, None, command)));
+    }
+    result
+}
+
+// StartOptions are the choices a new rebase is started with
+#[derive(Default)]
+struct StartOptions {
+    upstream: Option<String>,
+    onto: Option<String>,
+    branch: Option<String>,
+    interactive: bool,
+    rebase_merges: bool,
+    execs: Vec<String>,
+    autosquash: Option<bool>,
+    force: bool,
+    quiet: bool,
+    reapply_cherry_picks: bool,
+    empty: Option<Empty>,
+    strategy_options: Vec<String>,
+    fork_point: Option<bool>,
+}
+
+fn resolve_commit(name: &str) -> Option<String> {
+    resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).ok()
+}
+
+// Tell the user there is no upstream to rebase against when none was given
+fn missing_upstream(branch: Option<&str>) -> ! {
+    match branch {
+        None => {
+            println!("You are not currently on a branch.");
+            println!("Please specify which branch you want to rebase against.");
+        }
+        Some(_) => {
+            println!("There is no tracking information for the current branch.");
+            println!("Please specify which branch you want to rebase against.");
+        }
+    }
+    println!("See git-rebase(1) for details.");
+    println!();
+    println!("    git rebase '<branch>'");
+    println!();
+    if let Some(branch) = branch {
+        println!("If you wish to set tracking information for this branch you can do so with:");
+        println!();
+        println!("    git branch --set-upstream-to=<remote>/<branch> {}", branch);
+        println!();
+    }
+    std::process::exit(1);
+}
+
+// Whether the history from a commit back to from has no merges
+fn is_linear_history(from: &str, to: &str) -> anyhow::Result<bool> {
+    let mut hash = to.to_string();
+    while hash != from {
+        let commit = load_commit(&hash)?;
+        match commit.parents.as_slice() {
+            [] => return Ok(true),
+            [parent] => hash = parent.clone(),
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+// Whether the branch already sits on onto with no merges since, so that there is nothing to
+// replay
+fn can_fast_forward(onto: &str, upstream: &str, restrict: Option<&str>, head: &str) -> anyhow::Result<bool> {
+    let bases = merge_bases(onto, head)?;
+    let [branch_base] = bases.as_slice() else {
+        return Ok(false);
+    };
+    if restrict.map(|restrict| restrict != branch_base).unwrap_or(false) || branch_base != onto {
+        return Ok(false);
+    }
+    let bases = merge_bases(upstream, head)?;
+    if bases.len() != 1 || bases[0] != onto {
+        return Ok(false);
+    }
+    is_linear_history(onto, head)
+}
+
+// Detach HEAD at the commit the rebase starts from, remembering the branch's tip in ORIG_HEAD
+fn checkout_onto(onto_name: &str, onto: &str, orig_head: &str) -> anyhow::Result<()> {
+    switch_tree(head_tree()?.as_deref(), Some(&peel_to_tree(onto)?), false, "checkout", "checkout")?;
+    update_ref("ORIG_HEAD", orig_head, "rebase (start): updating ORIG_HEAD")?;
+    update_ref("HEAD", onto, &format!("rebase (start): checkout {}", onto_name))
+}
+
+// The commits to replay, oldest first, as a todo list. Commits whose change upstream already
+// has are left out unless asked for.
+fn make_script(config: &Config, options: &StartOptions, upstream: &str, restrict: Option<&str>, orig_head: &str) -> anyhow::Result<Vec<Item>> {
+    let mut walk = Walk::default();
+    walk.include = vec![orig_head.to_string()];
+    walk.exclude = vec![upstream.to_string()];
+    walk.exclude.extend(restrict.map(|x| x.to_string()));
+    walk.topo_order = true;
+    let mut commits = Vec::new();
+    for hash in walk.run()?.iter().rev() {
+        commits.push(load_commit(hash)?);
+    }
+
+    let mut upstream_ids = HashSet::new();
+    if !options.reapply_cherry_picks && !commits.is_empty() {
+        let mut walk = Walk::default();
+        walk.include = vec![upstream.to_string()];
+        walk.exclude = vec![orig_head.to_string()];
+        for hash in walk.run()? {
+            let commit = load_commit(&hash)?;
+            if commit.parents.len() < 2 {
+                upstream_ids.insert(patch_id(config, &commit)?);
+            }
+        }
+    }
+    let mut skipped = Vec::new();
+    let mut empty = HashSet::new();
+    for commit in &commits {
+        if commit.parents.len() > 1 {
+            continue;
+        }
+        if parent_tree_of(commit)? == commit.tree {
+            empty.insert(commit.hash.clone());
+        } else if !upstream_ids.is_empty() && upstream_ids.contains(&patch_id(config, commit)?) {
+            skipped.push(commit.hash.clone());
+        }
+    }
+    if !options.quiet {
+        for hash in &skipped {
+            eprintln!("warning: skipped previously applied commit {}", abbreviate(hash));
+        }
+        if !skipped.is_empty() && config.get_bool("advice.skippedCherryPicks") != Some(false) {
+            eprintln!("hint: use --reapply-cherry-picks to include skipped commits");
+            eprintln!("hint: Disable this message with \"git config advice.skippedCherryPicks false\"");
+        }
+    }
+
+    let subject = |commit: &Commit| match empty.contains(&commit.hash) {
+        true => format!("{} # empty", commit.subject()),
+        false => commit.subject(),
+    };
+    let mut items = if options.rebase_merges {
+        let base = merge_bases(upstream, orig_head)?.into_iter().next().unwrap_or_default();
+        let mut items = merge_script(&commits, &base, &|commit| skipped.contains(&commit.hash));
+        for item in items.iter_mut().filter(|item| item.command == Command::Pick) {
+            if let Some(commit) = commits.iter().find(|commit| Some(&commit.hash) == item.commit.as_ref()) {
+                item.arg = subject(commit);
+            }
+        }
+        items
+    } else {
+        commits
+            .iter()
+            .filter(|commit| commit.parents.len() < 2 && !skipped.contains(&commit.hash))
+            .map(|commit| Item::new(Command::Pick, Some(commit.hash.clone()), &subject(commit)))
+            .collect()
+    };
+    if items.is_empty() {
+        items.push(Item::new(Command::Noop, None, ""));
+    }
+    Ok(items)
+}
+
+// Move the leading picks of commits that already sit on onto to the done list, advancing onto
+// past them instead of making them again
+fn skip_unnecessary_picks(items: &mut Vec<Item>, onto: &mut String) -> anyhow::Result<()> {
+    let mut count = 0;
+    for item in items.iter() {
+        if item.command.is_noop() {
+            count += 1;
+            continue;
+        }
+        if item.command != Command::Pick {
+            break;
+        }
+        let commit = load_commit(item.commit.as_deref().unwrap_or(""))?;
+        if commit.parents.len() != 1 || commit.parents[0] != *onto {
+            break;
+        }
+        *onto = commit.hash;
+        count += 1;
+    }
+    if count > 0 {
+        let done: Vec<Item> = items.drain(..count).collect();
+        write_state("done", &todo_text(&done, false))?;
+        if let Some(next) = items.iter().find(|item| item.command != Command::Comment).filter(|item| item.command.is_fixup()) {
+            record_in_rewritten(onto, Some(next.command))?;
+        }
+    }
+    Ok(())
+}
+
+fn start_rebase(config: &Config, options: StartOptions) -> anyhow::Result<()> {
+    if Path::new(STATE_DIR).is_dir() {
+        anyhow::bail!(
+            "It seems that there is already a rebase-merge directory, and\nI wonder if you are in the middle of another rebase.  If that is the\ncase, please try\n\tgit rebase (--continue | --abort | --skip)\nIf that is not the case, please\n\trm -fr \"{}\"\nand run me again.  I am stopping in case you still have something\nvaluable there.\n",
+            STATE_DIR
+        );
+    }
+    let current_branch = head_symbolic_target();
+    let (upstream_name, upstream, fork_point_default) = match &options.upstream {
+        Some(name) => {
+            let name = if name == "-" { "@{-1}" } else { name.as_str() };
+            let upstream = resolve_commit(name).ok_or_else(|| anyhow::anyhow!("invalid upstream '{}'", name))?;
+            (name.to_string(), upstream, false)
+        }
+        None => {
+            let branch = current_branch.as_deref().map(shorten_ref_name);
+            let upstream_name = branch.and_then(|branch| branch_upstream(config, branch)).unwrap_or_else(|| missing_upstream(branch));
+            let upstream = resolve_commit(&upstream_name).ok_or_else(|| anyhow::anyhow!("invalid upstream '{}'", upstream_name))?;
+            (upstream_name, upstream, true)
+        }
+    };
+    let onto_name = options.onto.clone().unwrap_or_else(|| upstream_name.clone());
+    let onto = match onto_name.split_once("...") {
+        Some((left, right)) => {
+            let side = |name: &str| resolve_commit(if name.is_empty() { "HEAD" } else { name });
+            let bases = match (side(left), side(right)) {
+                (Some(left), Some(right)) => merge_bases(&left, &right)?,
+                _ => Vec::new(),
+            };
+            match bases.as_slice() {
+                [base] => base.clone(),
+                _ => anyhow::bail!("'{}': need exactly one merge base", onto_name),
+            }
+        }
+        None => resolve_commit(&onto_name).ok_or_else(|| anyhow::anyhow!("Does not point to a valid commit '{}'", onto_name))?,
+    };
+
+    let (head_name, orig_head, branch_name) = match &options.branch {
+        Some(name) => match resolve_ref(&format!("refs/heads/{}", name)) {
+            Some(commit) => (format!("refs/heads/{}", name), commit, name.clone()),
+            None => match resolve_commit(name) {
+                Some(commit) => ("detached HEAD".to_string(), commit, name.clone()),
+                None => anyhow::bail!("no such branch/commit '{}'", name),
+            },
+        },
+        None => {
+            let head = resolve_ref("HEAD").ok_or_else(|| anyhow::anyhow!("Could not resolve HEAD to a revision"))?;
+            match &current_branch {
+                Some(branch) => (branch.clone(), head, shorten_ref_name(branch).to_string()),
+                None => ("detached HEAD".to_string(), head, "HEAD".to_string()),
+            }
+        }
+    };
+    let restrict = match options.fork_point.unwrap_or(fork_point_default) {
+        true => match expand_ref_name(&upstream_name).or_else(|| upstream_name.starts_with("refs/").then(|| upstream_name.clone())) {
+            Some(full_name) => fork_point(&full_name, &orig_head)?.filter(|base| *base != upstream),
+            None => None,
+        },
+        false => None,
+    };
+
+    if !require_clean_work_tree(Some("Please commit or stash them."))? {
+        std::process::exit(1);
+    }
+    let autosquash = options.interactive && options.autosquash.or_else(|| config.get_bool("rebase.autoSquash")).unwrap_or(false);
+    let preemptive_ff = !options.interactive && !options.rebase_merges && options.execs.is_empty() && !options.autosquash.unwrap_or(false);
+    if preemptive_ff && can_fast_forward(&onto, &upstream, restrict.as_deref(), &orig_head)? {
+        let detached = head_name == "detached HEAD";
+        if !options.force {
+            if options.branch.is_some() {
+                switch_tree(head_tree()?.as_deref(), Some(&peel_to_tree(&orig_head)?), false, "checkout", "checkout")?;
+                let message = format!("rebase: checkout {}", branch_name);
+                match detached {
+                    true => update_ref("HEAD", &orig_head, &message)?,
+                    false => update_symbolic_ref("HEAD", &head_name, &message)?,
+                }
+            }
+            if !options.quiet {
+                match branch_name == "HEAD" && detached {
+                    true => println!("HEAD is up to date."),
+                    false => println!("Current branch {} is up to date.", branch_name),
+                }
+            }
+            return Ok(());
+        }
+        if !options.quiet {
+            match branch_name == "HEAD" && detached {
+                true => println!("HEAD is up to date, rebase forced."),
+                false => println!("Current branch {} is up to date, rebase forced.", branch_name),
+            }
+        }
+    }
+
+    let empty = options.empty.unwrap_or(match (options.interactive, options.execs.is_empty()) {
+        (true, _) => Empty::Ask,
+        (false, false) => Empty::Keep,
+        (false, true) => Empty::Drop,
+    });
+    let mut rebase = Rebase {
+        head_name,
+        onto: onto.clone(),
+        orig_head: orig_head.clone(),
+        quiet: options.quiet,
+        allow_ff: !options.force,
+        empty,
+        strategy_options: options.strategy_options.clone(),
+        reflog_action: "rebase".to_string(),
+    };
+    rebase.save()?;
+
+    let mut items = make_script(config, &options, &upstream, restrict.as_deref(), &orig_head)?;
+    if autosquash {
+        items = rearrange_squash(items)?;
+    }
+    if !options.execs.is_empty() {
+        items = add_exec_commands(items, &options.execs);
+    }
+    let commands = count_commands(&items);
+    let summary = format!(
+        "Rebase {}..{} onto {} ({} command{})",
+        abbreviate(&upstream),
+        abbreviate(&orig_head),
+        abbreviate(&onto),
+        commands,
+        if commands == 1 { "" } else { "s" }
+    );
+    let text = format!("{}{}", todo_text(&items, options.interactive), todo_help(Some(summary.clone())));
+    write_state("git-rebase-todo", &text)?;
+    write_state("git-rebase-todo.backup", &format!("{}{}", todo_text(&items, false), todo_help(Some(summary))))?;
+    let editor = match options.interactive {
+        true => sequence_editor(config),
+        false => Some(":".to_string()),
+    };
+    if let Err(error) = launch_editor(editor, &state_path("git-rebase-todo")) {
+        let _ = fs::remove_dir_all(STATE_DIR);
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+    let text = cleanup_message(&read_state("git-rebase-todo").unwrap_or_default());
+    if text.is_empty() {
+        let _ = fs::remove_dir_all(STATE_DIR);
+        eprintln!("error: nothing to do");
+        std::process::exit(1);
+    }
+    let Some(mut items) = parse_todo(&text, false) else {
+        eprintln!("You can fix this with 'git rebase --edit-todo' and then run 'git rebase --continue'.");
+        eprintln!("Or you can abort the rebase with 'git rebase --abort'.");
+        checkout_onto(&onto_name, &onto, &orig_head)?;
+        std::process::exit(1);
+    };
+    write_state("end", &format!("{}\n", items.len()))?;
+
+    let mut onto = onto;
+    if rebase.allow_ff {
+        skip_unnecessary_picks(&mut items, &mut onto)?;
+    }
+    write_todo(&items)?;
+    checkout_onto(&onto_name, &onto, &orig_head)?;
+    run_todo(config, &mut rebase, items)
+}
+
+fn usage() -> ! {
+    eprintln!("usage: git rebase [-i] [options] [--exec <cmd>] [--onto <newbase> | --keep-base] [<upstream> [<branch>]]");
+    eprintln!("   or: git rebase [-i] [options] [--exec <cmd>] [--onto <newbase>] --root [<branch>]");
+    eprintln!("   or: git rebase --continue | --abort | --skip | --edit-todo");
+    std::process::exit(129);
+}
+
+fn parse_empty(value: &str) -> Empty {
+    match value {
+        "drop" => Empty::Drop,
+        "keep" => Empty::Keep,
+        "ask" => Empty::Ask,
+        _ => {
+            eprintln!("fatal: unrecognized empty type '{}'; valid values are \"drop\", \"keep\", and \"ask\".", value);
+            std::process::exit(128);
+        }
+    }
+}
+
+// Reapply the commits of a branch on top of another base
+pub fn rebase_command(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == "rebase").unwrap();
+    let mut options = StartOptions::default();
+    let mut action: Option<&str> = None;
+    let mut positional = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = || {
+            index += 1;
+            args.get(index).cloned().unwrap_or_else(|| usage())
+        };
+        match arg {
+            "--continue" | "--skip" | "--abort" | "--quit" | "--edit-todo" => action = Some(arg),
+            "-i" | "--interactive" => options.interactive = true,
+            "-r" | "--rebase-merges" => options.rebase_merges = true,
+            "--no-rebase-merges" => options.rebase_merges = false,
+            "-x" | "--exec" => options.execs.push(value()),
+            "--onto" => options.onto = Some(value()),
+            "--autosquash" => options.autosquash = Some(true),
+            "--no-autosquash" => options.autosquash = Some(false),
+            "-f" | "--force-rebase" | "--no-ff" => options.force = true,
+            "-q" | "--quiet" => options.quiet = true,
+            "-v" | "--verbose" | "--no-quiet" => options.quiet = false,
+            "--reapply-cherry-picks" => options.reapply_cherry_picks = true,
+            "--no-reapply-cherry-picks" => options.reapply_cherry_picks = false,
+            "--fork-point" => options.fork_point = Some(true),
+            "--no-fork-point" => options.fork_point = Some(false),
+            "-m" | "--merge" | "-k" | "--keep-empty" | "--no-keep-empty" | "--rerere-autoupdate" | "--no-rerere-autoupdate" => {}
+            "-X" | "--strategy-option" => options.strategy_options.push(value()),
+            "-s" | "--strategy" => {
+                value();
+            }
+            "--empty" => options.empty = Some(parse_empty(&value())),
+            _ if arg.starts_with("--onto=") => options.onto = Some(arg["--onto=".len()..].to_string()),
+            _ if arg.starts_with("--exec=") => options.execs.push(arg["--exec=".len()..].to_string()),
+            _ if arg.starts_with("--empty=") => options.empty = Some(parse_empty(&arg["--empty=".len()..])),
+            _ if arg.starts_with("--rebase-merges=") => options.rebase_merges = true,
+            _ if arg.starts_with("--strategy-option=") => options.strategy_options.push(arg["--strategy-option=".len()..].to_string()),
+            _ if arg.starts_with("-X") => options.strategy_options.push(arg[2..].to_string()),
+            _ if arg.starts_with("--strategy=") || arg.starts_with("-s") => {}
+            _ if arg.starts_with("-x") => options.execs.push(arg[2..].to_string()),
+            _ if arg.starts_with('-') && arg != "-" => usage(),
+            _ => positional.push(arg.to_string()),
+        }
+        index += 1;
+    }
+
+    if let Some(action) = action {
+        if !positional.is_empty() {
+            usage();
+        }
+        return match action {
+            "--continue" => continue_rebase(&config, "continue"),
+            "--skip" => skip_rebase(&config),
+            "--abort" => abort_rebase(),
+            "--edit-todo" => edit_todo(&config),
+            _ => {
+                no_rebase_in_progress()?;
+                let _ = fs::remove_dir_all(STATE_DIR);
+                Ok(())
+            }
+        };
+    }
+    if positional.len() > 2 {
+        usage();
+    }
+    let mut positional = positional.into_iter();
+    options.upstream = positional.next();
+    options.branch = positional.next();
+    start_rebase(&config, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_words() {
+        for (command, name, letter) in COMMANDS {
+            assert_eq!(Command::from_word(name), Some(command));
+            assert_eq!(command.name(), name);
+            if let Some(letter) = letter {
+                assert_eq!(Command::from_word(&letter.to_string()), Some(command));
+            }
+        }
+        assert_eq!(Command::from_word("n"), None);
+        assert_eq!(Command::from_word("picks"), None);
+        assert_eq!(Command::from_word("pi"), None);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        for line in ["", "# comment", "  \t# indented", "   "] {
+            let item = parse_line(line).unwrap();
+            assert_eq!(item.command, Command::Comment);
+            assert_eq!(item.line(false), line.trim_start());
+        }
+    }
+
+    #[test]
+    fn commands_without_commits() {
+        let exec = parse_line("x \t make test  ").unwrap();
+        assert_eq!((exec.command, exec.arg.as_str()), (Command::Exec, "make test  "));
+        assert_eq!(exec.line(false), "exec make test  ");
+        let label = parse_line("  label onto").unwrap();
+        assert_eq!((label.command, label.arg.as_str()), (Command::Label, "onto"));
+        assert_eq!(parse_line("t onto").unwrap().line(false), "reset onto");
+        // Only the separating blank is required, an empty label is left for later to refuse
+        assert_eq!(parse_line("label\t").unwrap().arg, "");
+        assert_eq!(parse_line("b").unwrap().command, Command::Break);
+        assert_eq!(parse_line("noop").unwrap().line(false), "noop");
+        // A merge without -C or -c creates a new merge commit with the given parents
+        let merge = parse_line("merge side # Merge side").unwrap();
+        assert_eq!((merge.command, merge.commit, merge.flag), (Command::Merge, None, None));
+        assert_eq!(merge.arg, "side # Merge side");
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(parse_line("frobnicate HEAD").is_none());
+        assert!(parse_line("noop now").is_none());
+        assert!(parse_line("break it").is_none());
+        assert!(parse_line("exec").is_none());
+        assert!(parse_line("pick").is_none());
+        assert!(parse_line("pick no-such-commit-anywhere").is_none());
+        assert!(parse_line("fixup -C no-such-commit-anywhere").is_none());
+    }
+
+    #[test]
+    fn todo_lists() {
+        let items = parse_todo("# header\nlabel onto\n\nexec true\nreset onto\n", false).unwrap();
+        assert_eq!(items.len(), 5);
+        assert_eq!(count_commands(&items), 3);
+        assert_eq!(todo_text(&items, false), "# header\nlabel onto\n\nexec true\nreset onto\n");
+        // Every bad line is reported, not just the first
+        assert!(parse_todo("exec true\nbogus\nnoop x\n", false).is_none());
+    }
+
+    #[test]
+    fn lines_with_commits() {
+        let commit = "ab".repeat(20);
+        let mut fixup = Item::new(Command::Fixup, Some(commit.clone()), "subject");
+        assert_eq!(fixup.line(false), format!("fixup {} subject", commit));
+        fixup.flag = Some('c');
+        assert_eq!(fixup.line(false), format!("fixup -c {} subject", commit));
+        let merge = Item::new(Command::Merge, Some(commit.clone()), "side");
+        assert_eq!(merge.line(false), format!("merge -C {} side", commit));
+        assert!(Command::Fixup.is_fixup() && Command::Squash.is_fixup() && !Command::Pick.is_fixup());
+        assert!(Command::Drop.is_noop() && !Command::Exec.is_noop());
+    }
+}
@@ -0,0 +1,1294 @@
+use crate::branch::optional_commit;
+use crate::config::{read_config, Config};
+use crate::date::{format_date, DateFormat};
+use crate::ignore::wildmatch;
+use crate::log::sanitized_subject;
+use crate::reach::is_ancestor;
+use crate::refs::{
+    branch_push_ref, branch_push_remote, branch_push_remote_ref, branch_upstream, head_symbolic_target, list_refs, read_ref,
+    resolve_ref,
+};
+use crate::revision::{count_ahead_behind, object_kind, peel_tags, peel_to_commit, resolve_revision, shorten_unambiguous_ref};
+use crate::walk::{abbreviate, abbreviate_to, Signature};
+use crate::{option_value, read_raw_object};
+use std::cmp::Ordering;
+use std::io::{IsTerminal, Write};
+
+const DEFAULT_FORMAT: &str = "%(objectname) %(objecttype)\t%(refname)";
+
+// How a ref name is shown: whole, shortened while staying unambiguous, or with path
+// components stripped from the left or right
+#[derive(Clone, Copy)]
+enum RefName {
+    Full,
+    Short,
+    Lstrip(i32),
+    Rstrip(i32),
+}
+
+// What %(upstream) and %(push) show about the ref a branch tracks or is pushed to
+#[derive(Clone, Copy)]
+enum Tracking {
+    Name(RefName),
+    Track { bracket: bool },
+    TrackShort,
+    RemoteName,
+    RemoteRef,
+}
+
+// Which part of a %(contents) to show
+#[derive(Clone, Copy)]
+enum Contents {
+    Bare,
+    Subject,
+    Body,
+    Signature,
+    Size,
+    Lines(usize),
+}
+
+// Which part of an author, committer or tagger line to show. Whole lines and names given
+// an argument show nothing.
+#[derive(Clone)]
+enum Person {
+    Unknown,
+    Line,
+    Name,
+    Email { trim: bool, local_part: bool },
+    Date(DateFormat),
+}
+
+// Field is a placeholder filled in from the ref or the object it points at. A length of
+// None abbreviates object names to the default length.
+#[derive(Clone)]
+enum Field {
+    Refname(RefName),
+    Symref(RefName),
+    Upstream(Tracking),
+    Push(Tracking),
+    Head,
+    ObjectType,
+    ObjectSize,
+    ObjectName(Option<Option<usize>>),
+    Tree(Option<Option<usize>>),
+    Parent(Option<Option<usize>>),
+    NumParent,
+    TagHeader(&'static str),
+    Person(&'static str, Person),
+    Subject { sanitize: bool },
+    Body,
+    Contents(Contents),
+    Raw { size: bool },
+    Color(String),
+}
+
+#[derive(Clone, Copy)]
+enum Position {
+    Left,
+    Middle,
+    Right,
+}
+
+// Item is a piece of a parsed format: literal text, a field, or one of the atoms that pad
+// or choose between parts of the format
+enum Item {
+    Literal(Vec<u8>),
+    Field { deref: bool, field: Field },
+    Align { width: usize, position: Position },
+    If(Option<(bool, String)>),
+    Then,
+    Else,
+    End,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Quote {
+    None,
+    Shell,
+    Perl,
+    Python,
+    Tcl,
+}
+
+// Value is what a field expands to. Sizes, parent counts and dates sort by number.
+#[derive(Default)]
+struct Value {
+    text: String,
+    number: Option<i64>,
+}
+
+impl Value {
+    fn text(text: impl Into<String>) -> Value {
+        Value { text: text.into(), number: None }
+    }
+
+    fn number(number: i64) -> Value {
+        Value { text: number.to_string(), number: Some(number) }
+    }
+}
+
+// Loaded is an object a listed ref points at
+struct Loaded {
+    hash: String,
+    kind: String,
+    data: Vec<u8>,
+}
+
+impl Loaded {
+    fn read(hash: &str) -> anyhow::Result<Loaded> {
+        let (kind, data) = read_raw_object(hash)?;
+        Ok(Loaded { hash: hash.to_string(), kind: String::from_utf8_lossy(&kind).into_owned(), data })
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.data).into_owned()
+    }
+
+    // Header lines of a commit or tag
+    fn header_values(&self, name: &str) -> Vec<String> {
+        if self.kind != "commit" && self.kind != "tag" {
+            return Vec::new();
+        }
+        let text = self.text();
+        let header = text.split("\n\n").next().unwrap_or("");
+        header.lines().filter_map(|line| line.strip_prefix(name)?.strip_prefix(' ')).map(str::to_string).collect()
+    }
+}
+
+// Listed is a ref that passed the filters, with the object it points at and, for tags, the
+// object the tag points at
+struct Listed {
+    refname: String,
+    symref: Option<String>,
+    object: Loaded,
+    tagged: Option<Loaded>,
+}
+
+// SortKey is a --sort field, compared as version numbers with "version:" and in descending
+// order with "-"
+struct SortKey {
+    deref: bool,
+    field: Field,
+    reverse: bool,
+    version: bool,
+}
+
+// Where the signature at the end of a tag message starts
+fn signature_start(message: &str) -> usize {
+    let markers = [
+        "-----BEGIN PGP SIGNATURE-----",
+        "-----BEGIN PGP MESSAGE-----",
+        "-----BEGIN SIGNED MESSAGE-----",
+        "-----BEGIN SSH SIGNATURE-----",
+    ];
+    let mut start = message.len();
+    let mut offset = 0;
+    for line in message.split_inclusive('\n') {
+        if markers.iter().any(|marker| line.starts_with(marker)) {
+            start = offset;
+        }
+        offset += line.len();
+    }
+    start
+}
+
+// Message is a commit or tag message split the way git's ref-filter splits it: the first
+// paragraph, the rest with and without the signature, and everything from the subject on
+struct Message<'a> {
+    subject: &'a str,
+    body: &'a str,
+    body_without_signature: &'a str,
+    signature: &'a str,
+    bare: &'a str,
+}
+
+fn split_message(data: &str) -> Message<'_> {
+    let mut rest = data;
+    while !rest.is_empty() && !rest.starts_with('\n') {
+        rest = rest.split_once('\n').map(|(_, rest)| rest).unwrap_or("");
+    }
+    let bare = rest.trim_start_matches('\n');
+    let signature = signature_start(bare);
+    let subject_end = bare.find("\n\n").map(|end| end.min(signature)).unwrap_or(signature);
+    let subject = bare[..subject_end].trim_end_matches(['\n', '\r']);
+    let body = bare[subject_end..].trim_start_matches(['\n', '\r']);
+    let body_start = bare.len() - body.len();
+    Message {
+        subject,
+        body,
+        body_without_signature: &body[..signature.saturating_sub(body_start).min(body.len())],
+        signature: &bare[signature..],
+        bare,
+    }
+}
+
+// A subject on one line, with CRs of CRLF line ends dropped
+fn join_subject(subject: &str) -> String {
+    subject.replace("\r\n", "\n").replace('\n', " ")
+}
+
+// Strip path components from the front or back of a ref name. A negative count keeps that
+// many components instead.
+fn strip_components(name: &str, count: i32, from_left: bool) -> String {
+    let components: Vec<&str> = name.split('/').collect();
+    let remove = if count < 0 { components.len().saturating_sub(count.unsigned_abs() as usize) } else { count as usize };
+    if remove >= components.len() {
+        return String::new();
+    }
+    let kept = if from_left { &components[remove..] } else { &components[..components.len() - remove] };
+    kept.join("/")
+}
+
+fn show_ref_name(name: &str, option: RefName) -> String {
+    match option {
+        RefName::Full => name.to_string(),
+        RefName::Short => shorten_unambiguous_ref(name, true),
+        RefName::Lstrip(count) => strip_components(name, count, true),
+        RefName::Rstrip(count) => strip_components(name, count, false),
+    }
+}
+
+fn parse_ref_name(argument: Option<&str>, atom: &str) -> anyhow::Result<RefName> {
+    let Some(argument) = argument else {
+        return Ok(RefName::Full);
+    };
+    let count = |value: &str, side: &str| -> anyhow::Result<i32> {
+        value.parse().map_err(|_| anyhow::anyhow!("Integer value expected refname:{}={}", side, value))
+    };
+    if argument == "short" {
+        Ok(RefName::Short)
+    } else if let Some(value) = argument.strip_prefix("lstrip=").or_else(|| argument.strip_prefix("strip=")) {
+        Ok(RefName::Lstrip(count(value, "lstrip")?))
+    } else if let Some(value) = argument.strip_prefix("rstrip=") {
+        Ok(RefName::Rstrip(count(value, "rstrip")?))
+    } else {
+        anyhow::bail!("unrecognized %({}) argument: {}", atom, argument)
+    }
+}
+
+fn parse_remote(argument: Option<&str>, atom: &str) -> anyhow::Result<Tracking> {
+    let Some(argument) = argument else {
+        return Ok(Tracking::Name(RefName::Full));
+    };
+    let mut remote = Tracking::Name(RefName::Full);
+    let mut bracket = true;
+    for option in argument.split(',') {
+        remote = match option {
+            "track" => Tracking::Track { bracket: true },
+            "trackshort" => Tracking::TrackShort,
+            "nobracket" => {
+                bracket = false;
+                continue;
+            }
+            "remotename" => Tracking::RemoteName,
+            "remoteref" => Tracking::RemoteRef,
+            _ => Tracking::Name(parse_ref_name(Some(option), atom)?),
+        };
+    }
+    if let Tracking::Track { .. } = remote {
+        remote = Tracking::Track { bracket };
+    }
+    Ok(remote)
+}
+
+fn parse_hash(argument: Option<&str>, atom: &str) -> anyhow::Result<Option<Option<usize>>> {
+    match argument {
+        None => Ok(None),
+        Some("short") => Ok(Some(None)),
+        Some(argument) => match argument.strip_prefix("short=") {
+            Some(length) => match length.parse::<usize>() {
+                Ok(length) if length > 0 => Ok(Some(Some(length))),
+                _ => anyhow::bail!("positive value expected '{}' in %({})", length, atom),
+            },
+            None => anyhow::bail!("unrecognized %({}) argument: {}", atom, argument),
+        },
+    }
+}
+
+// Turn a color setting such as "bold red" or "#ff0000 ul" into its escape sequence
+fn parse_color(value: &str) -> Option<String> {
+    if value.eq_ignore_ascii_case("reset") {
+        return Some("\x1b[m".to_string());
+    }
+    let names = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+    let attributes = [("bold", 1, 22), ("dim", 2, 22), ("italic", 3, 23), ("ul", 4, 24), ("blink", 5, 25), ("reverse", 7, 27), ("strike", 9, 29)];
+    let color = |word: &str, background: bool| -> Option<Option<String>> {
+        let base = if background { 40 } else { 30 };
+        let word = word.to_lowercase();
+        if word == "normal" {
+            return Some(None);
+        }
+        if word == "default" {
+            return Some(Some((base + 9).to_string()));
+        }
+        if let Some(index) = names.iter().position(|&name| name == word) {
+            return Some(Some((base + index).to_string()));
+        }
+        if let Some(index) = word.strip_prefix("bright").and_then(|name| names.iter().position(|&x| x == name)) {
+            return Some(Some((base + 60 + index).to_string()));
+        }
+        if let Some(hex) = word.strip_prefix('#').filter(|hex| hex.len() == 6) {
+            let channel = |range: std::ops::Range<usize>| u8::from_str_radix(&hex[range], 16).ok();
+            return Some(Some(format!("{};2;{};{};{}", base + 8, channel(0..2)?, channel(2..4)?, channel(4..6)?)));
+        }
+        match word.parse::<i32>().ok()? {
+            -1 => Some(None),
+            value @ 0..=7 => Some(Some((base + value as usize).to_string())),
+            value @ 8..=255 => Some(Some(format!("{};5;{}", base + 8, value))),
+            _ => None,
+        }
+    };
+    let mut colors: Vec<Option<String>> = Vec::new();
+    let mut codes = Vec::new();
+    for word in value.split_whitespace() {
+        if colors.len() < 2 {
+            if let Some(parsed) = color(word, colors.len() == 1) {
+                colors.push(parsed);
+                continue;
+            }
+        }
+        let lower = word.to_lowercase();
+        let negated = lower.strip_prefix("no-").or_else(|| lower.strip_prefix("no"));
+        let attribute = attributes.iter().find_map(|&(name, on, off)| match negated {
+            Some(negated) if negated == name => Some(off),
+            _ if lower == name => Some(on),
+            _ => None,
+        })?;
+        if !codes.contains(&attribute) {
+            codes.push(attribute);
+        }
+    }
+    codes.sort();
+    let mut parts: Vec<String> = codes.iter().map(|code| code.to_string()).collect();
+    parts.extend(colors.into_iter().flatten());
+    if parts.is_empty() {
+        return Some(String::new());
+    }
+    Some(format!("\x1b[{}m", parts.join(";")))
+}
+
+// Parse the text between "%(" and ")"
+fn parse_atom(atom: &str, use_color: bool) -> anyhow::Result<Item> {
+    let body = atom.strip_prefix('*').unwrap_or(atom);
+    let deref = body.len() < atom.len();
+    if body.is_empty() {
+        anyhow::bail!("malformed field name: {}", atom);
+    }
+    let (name, argument) = match body.split_once(':') {
+        Some((name, argument)) => (name, Some(argument).filter(|x| !x.is_empty())),
+        None => (body, None),
+    };
+    let bad = |which: &str, argument: &str| anyhow::anyhow!("unrecognized %({}) argument: {}", which, argument);
+    let field = match name {
+        "refname" => Field::Refname(parse_ref_name(argument, body)?),
+        "symref" => Field::Symref(parse_ref_name(argument, body)?),
+        "upstream" => Field::Upstream(parse_remote(argument, body)?),
+        "push" => Field::Push(parse_remote(argument, body)?),
+        "HEAD" => Field::Head,
+        "objecttype" => match argument {
+            None => Field::ObjectType,
+            Some(_) => anyhow::bail!("%(objecttype) does not take arguments"),
+        },
+        "objectsize" => match argument {
+            None => Field::ObjectSize,
+            Some(argument) => return Err(bad("objectsize", argument)),
+        },
+        "objectname" => Field::ObjectName(parse_hash(argument, body)?),
+        "tree" => Field::Tree(parse_hash(argument, body)?),
+        "parent" => Field::Parent(parse_hash(argument, body)?),
+        "numparent" => Field::NumParent,
+        "object" => Field::TagHeader("object"),
+        "type" => Field::TagHeader("type"),
+        "tag" => Field::TagHeader("tag"),
+        "subject" => match argument {
+            None => Field::Subject { sanitize: false },
+            Some("sanitize") => Field::Subject { sanitize: true },
+            Some(argument) => return Err(bad("subject", argument)),
+        },
+        "body" => match argument {
+            None => Field::Body,
+            Some(_) => anyhow::bail!("%(body) does not take arguments"),
+        },
+        "contents" => Field::Contents(match argument {
+            None => Contents::Bare,
+            Some("subject") => Contents::Subject,
+            Some("body") => Contents::Body,
+            Some("signature") => Contents::Signature,
+            Some("size") => Contents::Size,
+            Some(argument) => match argument.strip_prefix("lines=") {
+                Some(lines) => Contents::Lines(
+                    lines.parse().map_err(|_| anyhow::anyhow!("positive value expected contents:lines={}", lines))?,
+                ),
+                None => return Err(bad("contents", argument)),
+            },
+        }),
+        "raw" => match argument {
+            None => Field::Raw { size: false },
+            Some("size") => Field::Raw { size: true },
+            Some(argument) => return Err(bad("raw", argument)),
+        },
+        "color" => {
+            let Some(argument) = argument else {
+                anyhow::bail!("expected format: %(color:<color>)");
+            };
+            let Some(color) = parse_color(argument) else {
+                eprintln!("error: invalid color value: {}", argument);
+                anyhow::bail!("unrecognized color: %(color:{})", argument);
+            };
+            Field::Color(if use_color { color } else { String::new() })
+        }
+        "align" => {
+            let usage = || anyhow::anyhow!("expected format: %(align:<width>,<position>)");
+            let position_named = |name: &str| match name {
+                "left" => Some(Position::Left),
+                "middle" => Some(Position::Middle),
+                "right" => Some(Position::Right),
+                _ => None,
+            };
+            let mut width = None;
+            let mut position = Position::Left;
+            for part in argument.ok_or_else(usage)?.split(',') {
+                if let Some(name) = part.strip_prefix("position=") {
+                    position = position_named(name).ok_or_else(|| anyhow::anyhow!("unrecognized position:{}", name))?;
+                } else if let Some(value) = part.strip_prefix("width=") {
+                    width = Some(value.parse().map_err(|_| anyhow::anyhow!("unrecognized width:{}", value))?);
+                } else if let Ok(value) = part.parse() {
+                    width = Some(value);
+                } else {
+                    position = position_named(part).ok_or_else(|| bad("align", part))?;
+                }
+            }
+            let Some(width) = width else {
+                anyhow::bail!("positive width expected with the %(align) atom");
+            };
+            return Ok(Item::Align { width, position });
+        }
+        "if" => {
+            return Ok(Item::If(match argument {
+                None => None,
+                Some(argument) => match (argument.strip_prefix("equals="), argument.strip_prefix("notequals=")) {
+                    (Some(value), _) => Some((true, value.to_string())),
+                    (_, Some(value)) => Some((false, value.to_string())),
+                    _ => return Err(bad("if", argument)),
+                },
+            }))
+        }
+        "then" => return Ok(Item::Then),
+        "else" => return Ok(Item::Else),
+        "end" => return Ok(Item::End),
+        _ => {
+            let person = ["author", "committer", "tagger"].into_iter().find_map(|who| {
+                let part = name.strip_prefix(who)?;
+                Some((who, part))
+            });
+            match (name, person) {
+                ("creator", _) => Field::Person("creator", Person::Line),
+                ("creatordate", _) => Field::Person("creator", Person::Date(parse_date_format(argument)?)),
+                (_, Some((who, ""))) if argument.is_none() => Field::Person(who, Person::Line),
+                (_, Some((who, "name"))) if argument.is_none() => Field::Person(who, Person::Name),
+                (_, Some((who, "" | "name"))) => Field::Person(who, Person::Unknown),
+                (_, Some((who, "date"))) => Field::Person(who, Person::Date(parse_date_format(argument)?)),
+                (_, Some((who, "email"))) => Field::Person(
+                    who,
+                    match argument {
+                        None => Person::Email { trim: false, local_part: false },
+                        Some("trim") => Person::Email { trim: true, local_part: false },
+                        Some("localpart") => Person::Email { trim: true, local_part: true },
+                        Some(argument) => anyhow::bail!("unrecognized email option: {}", argument),
+                    },
+                ),
+                _ => anyhow::bail!("unknown field name: {}", atom),
+            }
+        }
+    };
+    Ok(Item::Field { deref, field })
+}
+
+fn parse_date_format(argument: Option<&str>) -> anyhow::Result<DateFormat> {
+    argument.map(DateFormat::parse).unwrap_or(Ok(DateFormat::Default))
+}
+
+// Literal format text, where "%%" stands for "%" and "%xx" for the byte with that hex value
+fn literal(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            if bytes.get(index + 1) == Some(&b'%') {
+                index += 1;
+            } else if let Some(byte) = text.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                output.push(byte);
+                index += 3;
+                continue;
+            }
+        }
+        output.push(bytes[index]);
+        index += 1;
+    }
+    output
+}
+
+fn parse_format(format: &str, use_color: bool) -> anyhow::Result<Vec<Item>> {
+    let mut items = Vec::new();
+    let mut rest = format;
+    loop {
+        // Find the next "%(" that is not a quoted "%%("
+        let bytes = rest.as_bytes();
+        let mut index = 0;
+        let start = loop {
+            match bytes.get(index) {
+                None => break None,
+                Some(b'%') if bytes.get(index + 1) == Some(&b'(') => break Some(index),
+                Some(b'%') if bytes.get(index + 1) == Some(&b'%') => index += 2,
+                Some(_) => index += 1,
+            }
+        };
+        let Some(start) = start else {
+            if !rest.is_empty() {
+                items.push(Item::Literal(literal(rest)));
+            }
+            return Ok(items);
+        };
+        if start > 0 {
+            items.push(Item::Literal(literal(&rest[..start])));
+        }
+        let Some(end) = rest[start..].find(')').map(|end| start + end) else {
+            eprintln!("error: malformed format string {}", &rest[start..]);
+            for_each_ref_usage();
+        };
+        items.push(parse_atom(&rest[start + 2..end], use_color)?);
+        rest = &rest[end + 1..];
+    }
+}
+
+fn quote(value: &[u8], style: Quote) -> Vec<u8> {
+    let mut output = Vec::new();
+    match style {
+        Quote::None => output.extend_from_slice(value),
+        Quote::Shell => {
+            output.push(b'\'');
+            for &x in value {
+                match x {
+                    b'\'' | b'!' => output.extend_from_slice(&[b'\'', b'\\', x, b'\'']),
+                    _ => output.push(x),
+                }
+            }
+            output.push(b'\'');
+        }
+        Quote::Perl => {
+            output.push(b'\'');
+            for &x in value {
+                if x == b'\'' || x == b'\\' {
+                    output.push(b'\\');
+                }
+                output.push(x);
+            }
+            output.push(b'\'');
+        }
+        Quote::Python => {
+            output.push(b'\'');
+            for &x in value {
+                match x {
+                    b'\n' => output.extend_from_slice(b"\\n"),
+                    b'\'' | b'\\' => output.extend_from_slice(&[b'\\', x]),
+                    _ => output.push(x),
+                }
+            }
+            output.push(b'\'');
+        }
+        Quote::Tcl => {
+            output.push(b'"');
+            for &x in value {
+                match x {
+                    b'[' | b']' | b'{' | b'}' | b'$' | b'\\' | b'"' => output.extend_from_slice(&[b'\\', x]),
+                    b'\x0c' => output.extend_from_slice(b"\\f"),
+                    b'\r' => output.extend_from_slice(b"\\r"),
+                    b'\n' => output.extend_from_slice(b"\\n"),
+                    b'\t' => output.extend_from_slice(b"\\t"),
+                    b'\x0b' => output.extend_from_slice(b"\\v"),
+                    _ => output.push(x),
+                }
+            }
+            output.push(b'"');
+        }
+    }
+    output
+}
+
+// Compare version numbers embedded in strings the way git's versioncmp does, so that
+// "v1.10" sorts after "v1.9"
+fn versioncmp(a: &str, b: &str) -> Ordering {
+    const S_N: usize = 0;
+    const S_I: usize = 3;
+    const S_F: usize = 6;
+    const S_Z: usize = 9;
+    const CMP: i8 = 2;
+    const LEN: i8 = 3;
+    const NEXT_STATE: [usize; 12] = [S_N, S_I, S_Z, S_N, S_I, S_I, S_N, S_F, S_F, S_N, S_F, S_Z];
+    const RESULT_TYPE: [i8; 36] = [
+        CMP, CMP, CMP, CMP, LEN, CMP, CMP, CMP, CMP, //
+        CMP, -1, -1, 1, LEN, LEN, 1, LEN, LEN, //
+        CMP, CMP, CMP, CMP, CMP, CMP, CMP, CMP, CMP, //
+        CMP, 1, 1, -1, CMP, CMP, -1, CMP, CMP,
+    ];
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let at = |bytes: &[u8], index: usize| bytes.get(index).copied().unwrap_or(0);
+    let class = |x: u8| usize::from(x == b'0') + usize::from(x.is_ascii_digit());
+
+    let mut index = 0;
+    let (mut x, mut y) = (at(a, 0), at(b, 0));
+    let mut state = S_N + class(x);
+    while x == y {
+        if x == 0 {
+            return Ordering::Equal;
+        }
+        state = NEXT_STATE[state];
+        index += 1;
+        (x, y) = (at(a, index), at(b, index));
+        state += class(x);
+    }
+    let difference = x.cmp(&y);
+    match RESULT_TYPE[state * 3 + class(y)] {
+        CMP => difference,
+        LEN => {
+            let mut index = index + 1;
+            while at(a, index).is_ascii_digit() {
+                if !at(b, index).is_ascii_digit() {
+                    return Ordering::Greater;
+                }
+                index += 1;
+            }
+            if at(b, index).is_ascii_digit() {
+                Ordering::Less
+            } else {
+                difference
+            }
+        }
+        result => result.cmp(&0),
+    }
+}
+
+// Frame is an %(align) or %(if) whose output is being collected until its %(end)
+enum Frame {
+    Align { width: usize, position: Position },
+    If { compare: Option<(bool, String)>, then_seen: bool, else_seen: bool, satisfied: bool },
+    Else { satisfied: bool },
+}
+
+struct Formatter {
+    config: Config,
+    head: Option<String>,
+    quote: Quote,
+}
+
+impl Formatter {
+    fn remote_value(&self, listed: &Listed, remote: Tracking, push: bool) -> anyhow::Result<Value> {
+        let Some(branch) = listed.refname.strip_prefix("refs/heads/") else {
+            return Ok(Value::default());
+        };
+        if push && matches!(remote, Tracking::RemoteName | Tracking::RemoteRef) {
+            return Ok(Value::text(match remote {
+                Tracking::RemoteName => branch_push_remote(&self.config, branch).to_string(),
+                _ => branch_push_remote_ref(&self.config, branch).unwrap_or_default(),
+            }));
+        }
+        let target = if push { branch_push_ref(&self.config, branch) } else { branch_upstream(&self.config, branch) };
+        let Some(target) = target else {
+            return Ok(Value::default());
+        };
+        // Counts of commits only on the branch and only on the ref it is compared with, or
+        // None when that ref is gone
+        let counts = || -> anyhow::Result<Option<(usize, usize)>> {
+            let (Some(ours), Some(theirs)) = (resolve_ref(&listed.refname), resolve_ref(&target)) else {
+                return Ok(None);
+            };
+            Ok(Some(count_ahead_behind(&peel_to_commit(&ours)?, &peel_to_commit(&theirs)?)?))
+        };
+        Ok(Value::text(match remote {
+            Tracking::Name(option) => show_ref_name(&target, option),
+            Tracking::Track { bracket } => {
+                let summary = match counts()? {
+                    None => "gone".to_string(),
+                    Some((0, 0)) => String::new(),
+                    Some((ahead, 0)) => format!("ahead {}", ahead),
+                    Some((0, behind)) => format!("behind {}", behind),
+                    Some((ahead, behind)) => format!("ahead {}, behind {}", ahead, behind),
+                };
+                if bracket && !summary.is_empty() {
+                    format!("[{}]", summary)
+                } else {
+                    summary
+                }
+            }
+            Tracking::TrackShort => match counts()? {
+                None => "",
+                Some((0, 0)) => "=",
+                Some((0, _)) => "<",
+                Some((_, 0)) => ">",
+                Some(_) => "<>",
+            }
+            .to_string(),
+            Tracking::RemoteName => self.config.get(&format!("branch.{}.remote", branch)).unwrap_or_default().to_string(),
+            Tracking::RemoteRef => self.config.get(&format!("branch.{}.merge", branch)).unwrap_or_default().to_string(),
+        }))
+    }
+
+    fn value(&self, listed: &Listed, deref: bool, field: &Field) -> anyhow::Result<Value> {
+        let hash = |hash: &str, length: Option<Option<usize>>| match length {
+            None => hash.to_string(),
+            Some(None) => abbreviate(hash),
+            Some(Some(length)) => abbreviate_to(hash, length),
+        };
+        let with_deref = |name: String| if deref { format!("{}^{{}}", name) } else { name };
+        match field {
+            Field::Refname(option) => return Ok(Value::text(with_deref(show_ref_name(&listed.refname, *option)))),
+            Field::Symref(option) => {
+                return Ok(match &listed.symref {
+                    Some(target) => Value::text(with_deref(show_ref_name(target, *option))),
+                    None => Value::default(),
+                })
+            }
+            Field::Upstream(remote) => return self.remote_value(listed, *remote, false),
+            Field::Push(remote) => return self.remote_value(listed, *remote, true),
+            Field::Head => {
+                return Ok(Value::text(if self.head.as_deref() == Some(listed.refname.as_str()) { "*" } else { " " }))
+            }
+            Field::Color(color) => return Ok(Value::text(color.clone())),
+            _ => {}
+        }
+
+        let object = if deref { listed.tagged.as_ref() } else { Some(&listed.object) };
+        let Some(object) = object else {
+            return Ok(Value::default());
+        };
+        let message = || {
+            let text = object.text();
+            (object.kind == "commit" || object.kind == "tag").then_some(text)
+        };
+        Ok(match field {
+            Field::ObjectType => Value::text(object.kind.clone()),
+            Field::ObjectSize => Value::number(object.data.len() as i64),
+            Field::ObjectName(length) => Value::text(hash(&object.hash, *length)),
+            Field::Tree(length) => Value::text(object.header_values("tree").iter().map(|x| hash(x, *length)).collect::<Vec<_>>().join(" ")),
+            Field::Parent(length) => {
+                Value::text(object.header_values("parent").iter().map(|x| hash(x, *length)).collect::<Vec<_>>().join(" "))
+            }
+            Field::NumParent if object.kind == "commit" => Value::number(object.header_values("parent").len() as i64),
+            Field::NumParent => Value::default(),
+            Field::TagHeader(name) if object.kind == "tag" => Value::text(object.header_values(name).join(" ")),
+            Field::TagHeader(_) => Value::default(),
+            Field::Person(who, part) => {
+                let who = match *who {
+                    "creator" if object.kind == "commit" => "committer",
+                    "creator" => "tagger",
+                    who => who,
+                };
+                let Some(line) = object.header_values(who).into_iter().next() else {
+                    return Ok(Value::default());
+                };
+                match part {
+                    Person::Unknown => Value::default(),
+                    Person::Line => Value::text(line),
+                    Person::Name => Value::text(line.find(" <").map(|end| line[..end].to_string()).unwrap_or_default()),
+                    Person::Email { trim, local_part } => {
+                        let Some(start) = line.find('<') else {
+                            return Ok(Value::default());
+                        };
+                        let email = &line[start..];
+                        let end = match (trim, local_part) {
+                            (_, true) => email.find('@').or_else(|| email.find('>')),
+                            (true, false) => email.find('>'),
+                            _ => email.find('>').map(|end| end + 1),
+                        };
+                        let start = usize::from(*trim);
+                        Value::text(end.filter(|&end| end >= start).map(|end| email[start..end].to_string()).unwrap_or_default())
+                    }
+                    Person::Date(format) => match line.find('>') {
+                        Some(_) => {
+                            let signature = Signature::parse(&line);
+                            Value {
+                                text: format_date(signature.time, &signature.offset, format),
+                                number: Some(signature.time),
+                            }
+                        }
+                        None => Value { text: String::new(), number: Some(0) },
+                    },
+                }
+            }
+            Field::Subject { sanitize } => match message() {
+                Some(text) => {
+                    let subject = join_subject(split_message(&text).subject);
+                    Value::text(if *sanitize { sanitized_subject(&subject) } else { subject })
+                }
+                None => Value::default(),
+            },
+            Field::Body => Value::text(message().map(|text| split_message(&text).body.to_string()).unwrap_or_default()),
+            Field::Contents(part) => {
+                let Some(text) = message() else {
+                    return Ok(Value::default());
+                };
+                let message = split_message(&text);
+                match part {
+                    Contents::Bare => Value::text(message.bare),
+                    Contents::Subject => Value::text(join_subject(message.subject)),
+                    Contents::Body => Value::text(message.body_without_signature),
+                    Contents::Signature => Value::text(message.signature),
+                    Contents::Size => Value::number(message.bare.len() as i64),
+                    Contents::Lines(lines) => {
+                        // The message without its signature, limited to the given number of lines
+                        let end = message.bare.len() - message.body.len() + message.body_without_signature.len();
+                        let shown: Vec<&str> = message.bare[..end].split('\n').take(*lines).collect();
+                        let mut shown = shown.join("\n    ");
+                        if message.bare[..end].split('\n').count() <= *lines && message.bare[..end].ends_with('\n') {
+                            shown.truncate(shown.len() - "\n    ".len());
+                        }
+                        Value::text(shown)
+                    }
+                }
+            }
+            Field::Raw { size: true } => Value::number(object.data.len() as i64),
+            Field::Raw { size: false } => Value::text(object.text()),
+            _ => Value::default(),
+        })
+    }
+
+    // Expand the format for one ref
+    fn format(&self, items: &[Item], listed: &Listed) -> anyhow::Result<Vec<u8>> {
+        let mut frames: Vec<(Frame, Vec<u8>)> = Vec::new();
+        let mut output = Vec::new();
+        for item in items {
+            let top_level = frames.is_empty();
+            let current = match frames.last_mut() {
+                Some((_, buffer)) => buffer,
+                None => &mut output,
+            };
+            match item {
+                Item::Literal(text) => current.extend_from_slice(text),
+                Item::Field { deref, field } => {
+                    let value = self.value(listed, *deref, field)?;
+                    if top_level {
+                        current.extend(quote(value.text.as_bytes(), self.quote));
+                    } else {
+                        current.extend_from_slice(value.text.as_bytes());
+                    }
+                }
+                Item::Align { width, position } => frames.push((Frame::Align { width: *width, position: *position }, Vec::new())),
+                Item::If(compare) => frames.push((
+                    Frame::If { compare: compare.clone(), then_seen: false, else_seen: false, satisfied: false },
+                    Vec::new(),
+                )),
+                Item::Then => {
+                    let Some((Frame::If { compare, then_seen, else_seen, satisfied }, buffer)) = frames.last_mut() else {
+                        match frames.last() {
+                            Some((Frame::Else { .. }, _)) => anyhow::bail!("format: %(then) atom used after %(else)"),
+                            _ => anyhow::bail!("format: %(then) atom used without a %(if) atom"),
+                        }
+                    };
+                    if *then_seen {
+                        anyhow::bail!("format: %(then) atom used more than once");
+                    }
+                    if *else_seen {
+                        anyhow::bail!("format: %(then) atom used after %(else)");
+                    }
+                    *then_seen = true;
+                    *satisfied = match compare {
+                        Some((true, value)) => buffer.as_slice() == value.as_bytes(),
+                        Some((false, value)) => buffer.as_slice() != value.as_bytes(),
+                        None => buffer.iter().any(|x| !x.is_ascii_whitespace()),
+                    };
+                    buffer.clear();
+                }
+                Item::Else => {
+                    let satisfied = match frames.last_mut() {
+                        Some((Frame::If { then_seen: false, .. }, _)) => {
+                            anyhow::bail!("format: %(else) atom used without a %(then) atom")
+                        }
+                        Some((Frame::If { else_seen, satisfied, .. }, _)) => {
+                            *else_seen = true;
+                            *satisfied
+                        }
+                        Some((Frame::Else { .. }, _)) => anyhow::bail!("format: %(else) atom used more than once"),
+                        _ => anyhow::bail!("format: %(else) atom used without a %(if) atom"),
+                    };
+                    frames.push((Frame::Else { satisfied }, Vec::new()));
+                }
+                Item::End => {
+                    let Some((frame, mut buffer)) = frames.pop() else {
+                        anyhow::bail!("format: %(end) atom used without corresponding atom");
+                    };
+                    match frame {
+                        Frame::Align { width, position } => {
+                            let length = String::from_utf8_lossy(&buffer).chars().count();
+                            if length < width {
+                                let padding = width - length;
+                                let (left, right) = match position {
+                                    Position::Left => (0, padding),
+                                    Position::Middle => (padding / 2, padding - padding / 2),
+                                    Position::Right => (padding, 0),
+                                };
+                                buffer = [vec![b' '; left], buffer, vec![b' '; right]].concat();
+                            }
+                        }
+                        Frame::If { then_seen: false, .. } => anyhow::bail!("format: %(if) atom used without a %(then) atom"),
+                        Frame::If { satisfied, .. } => {
+                            if !satisfied {
+                                buffer.clear();
+                            }
+                        }
+                        Frame::Else { satisfied } => {
+                            // The %(then) part is still on the stack below the %(else) part
+                            let (_, then_buffer) = frames.pop().unwrap();
+                            if satisfied {
+                                buffer = then_buffer;
+                            }
+                        }
+                    }
+                    match frames.last_mut() {
+                        Some((_, outer)) => outer.extend(buffer),
+                        None => output.extend(quote(&buffer, self.quote)),
+                    }
+                }
+            }
+        }
+        if !frames.is_empty() {
+            anyhow::bail!("format: %(end) atom missing");
+        }
+        Ok(output)
+    }
+}
+
+// Whether a ref is selected by a pattern: a leading part of its name that ends at a "/",
+// or a glob matching the whole name. Only the glob ignores case with --ignore-case.
+fn pattern_matches(pattern: &str, refname: &str, ignore_case: bool) -> bool {
+    match refname.strip_prefix(pattern) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') || pattern.ends_with('/') => true,
+        _ if ignore_case => wildmatch(pattern.to_lowercase().as_bytes(), refname.to_lowercase().as_bytes()),
+        _ => wildmatch(pattern.as_bytes(), refname.as_bytes()),
+    }
+}
+
+// Resolve the commits of --merged, --no-merged, --contains and --no-contains. The merge
+// filters die on a bad name where the containment ones only complain.
+fn filter_commit(name: &str, merge: Option<&str>) -> anyhow::Result<String> {
+    let Ok(hash) = resolve_revision(name) else {
+        if merge.is_some() {
+            anyhow::bail!("malformed object name {}", name);
+        }
+        eprintln!("error: malformed object name {}", name);
+        std::process::exit(129);
+    };
+    match peel_to_commit(&hash) {
+        Ok(commit) => Ok(commit),
+        Err(_) => {
+            let peeled = peel_tags(&hash)?;
+            eprintln!("error: object {} is a {}, not a commit", peeled, object_kind(&peeled)?);
+            match merge {
+                Some(option) => eprintln!("error: option `{}' must point to a commit", option),
+                None => eprintln!("error: no such commit {}", name),
+            }
+            std::process::exit(129);
+        }
+    }
+}
+
+fn for_each_ref_usage() -> ! {
+    eprintln!("usage: git for-each-ref [<options>] [<pattern>]");
+    std::process::exit(129);
+}
+
+// List refs with their objects in a chosen format, filtered and sorted
+pub fn for_each_ref_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "for-each-ref").unwrap();
+    let args = &args[command_index + 1..];
+    let config = read_config();
+
+    let mut format = DEFAULT_FORMAT.to_string();
+    let mut sort_keys: Vec<String> = Vec::new();
+    let mut count = None;
+    let mut quote_styles = Vec::new();
+    let mut points_at = Vec::new();
+    let (mut merged, mut no_merged, mut contains, mut no_contains) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut color = None;
+    let mut ignore_case = false;
+    let mut patterns = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        let mut value = |long: &str| option_value(args, &mut index, &[long], for_each_ref_usage);
+        if let Some(value) = value("--format") {
+            format = value;
+        } else if let Some(key) = value("--sort") {
+            sort_keys.push(key);
+        } else if let Some(value) = value("--count") {
+            let value: i64 = value.parse().unwrap_or_else(|_| {
+                eprintln!("error: option `count' expects a numerical value");
+                std::process::exit(129);
+            });
+            if value < 0 {
+                eprintln!("error: invalid --count argument: `{}'", value);
+                for_each_ref_usage();
+            }
+            count = Some(value as usize);
+        } else if let Some(object) = value("--points-at") {
+            let Ok(hash) = resolve_revision(&object) else {
+                eprintln!("error: malformed object name '{}'", object);
+                std::process::exit(129);
+            };
+            points_at.push(hash);
+        } else if let Some(commit) = optional_commit(arg, "--merged", args, &mut index) {
+            merged.push(filter_commit(&commit, Some("merged"))?);
+        } else if let Some(commit) = optional_commit(arg, "--no-merged", args, &mut index) {
+            no_merged.push(filter_commit(&commit, Some("no-merged"))?);
+        } else if let Some(commit) = optional_commit(arg, "--contains", args, &mut index) {
+            contains.push(filter_commit(&commit, None)?);
+        } else if let Some(commit) = optional_commit(arg, "--no-contains", args, &mut index) {
+            no_contains.push(filter_commit(&commit, None)?);
+        } else if let Some(when) = arg.strip_prefix("--color=") {
+            color = Some(when.to_string());
+        } else {
+            match arg {
+                "-s" | "--shell" => quote_styles.push(Quote::Shell),
+                "-p" | "--perl" => quote_styles.push(Quote::Perl),
+                "--python" => quote_styles.push(Quote::Python),
+                "--tcl" => quote_styles.push(Quote::Tcl),
+                "--color" => color = Some("always".to_string()),
+                "--no-color" => color = Some("never".to_string()),
+                "--ignore-case" => ignore_case = true,
+                "--" => {
+                    patterns.extend(args[index + 1..].iter().cloned());
+                    break;
+                }
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    eprintln!("error: unknown option `{}'", arg.trim_start_matches('-'));
+                    for_each_ref_usage();
+                }
+                _ => patterns.push(arg.to_string()),
+            }
+        }
+        index += 1;
+    }
+    if quote_styles.len() > 1 {
+        eprintln!("error: more than one quoting style?");
+        for_each_ref_usage();
+    }
+
+    let use_color = match color.as_deref().or_else(|| config.get("color.ui")) {
+        Some("always") | Some("true") => true,
+        Some("never") | Some("false") => false,
+        _ => std::io::stdout().is_terminal(),
+    };
+    let items = parse_format(&format, use_color)?;
+    let reset_at_end = use_color
+        && items.iter().rev().find_map(|item| match item {
+            Item::Field { field: Field::Color(color), .. } => Some(color != "\x1b[m"),
+            _ => None,
+        }) == Some(true);
+    if sort_keys.is_empty() {
+        sort_keys.push("refname".to_string());
+    }
+    let mut keys = Vec::new();
+    for key in sort_keys.iter().rev() {
+        let (reverse, key) = match key.strip_prefix('-') {
+            Some(key) => (true, key),
+            None => (false, key.as_str()),
+        };
+        let (version, key) = match key.strip_prefix("version:").or_else(|| key.strip_prefix("v:")) {
+            Some(key) => (true, key),
+            None => (false, key),
+        };
+        match parse_atom(key, use_color)? {
+            Item::Field { deref, field } => keys.push(SortKey { deref, field, reverse, version }),
+            _ => keys.push(SortKey { deref: false, field: Field::Color(String::new()), reverse, version }),
+        }
+    }
+
+    let fold = |text: &str| if ignore_case { text.to_lowercase() } else { text.to_string() };
+    let filtered = !merged.is_empty() || !no_merged.is_empty() || !contains.is_empty() || !no_contains.is_empty();
+    let mut listed = Vec::new();
+    for (refname, hash) in list_refs() {
+        if !patterns.is_empty() && !patterns.iter().any(|pattern| pattern_matches(pattern, &refname, ignore_case)) {
+            continue;
+        }
+        let object = Loaded::read(&hash)?;
+        let tagged = match object.header_values("object").into_iter().next() {
+            Some(target) if object.kind == "tag" => Some(Loaded::read(&target)?),
+            _ => None,
+        };
+        if !points_at.is_empty()
+            && !points_at.iter().any(|target| *target == hash || tagged.as_ref().is_some_and(|tagged| tagged.hash == *target))
+        {
+            continue;
+        }
+        if filtered {
+            let Ok(commit) = peel_to_commit(&hash) else {
+                continue;
+            };
+            let reachable_from = |commits: &[String]| -> anyhow::Result<bool> {
+                for other in commits {
+                    if is_ancestor(&commit, other)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            };
+            let reaches = |commits: &[String]| -> anyhow::Result<bool> {
+                for other in commits {
+                    if is_ancestor(other, &commit)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            };
+            let keep = (merged.is_empty() || reachable_from(&merged)?)
+                && !reachable_from(&no_merged)?
+                && (contains.is_empty() || reaches(&contains)?)
+                && !reaches(&no_contains)?;
+            if !keep {
+                continue;
+            }
+        }
+        let symref = read_ref(&refname).and_then(|value| value.strip_prefix("ref: ").map(str::to_string));
+        listed.push(Listed { refname, symref, object, tagged });
+    }
+
+    let formatter = Formatter {
+        config,
+        head: head_symbolic_target(),
+        quote: quote_styles.first().copied().unwrap_or(Quote::None),
+    };
+    let mut sorted = Vec::new();
+    for item in listed {
+        let values = keys.iter().map(|key| formatter.value(&item, key.deref, &key.field)).collect::<anyhow::Result<Vec<_>>>()?;
+        sorted.push((values, item));
+    }
+    // Ties on every key fall back to the ref name, in ascending order even for reversed keys
+    sorted.sort_by(|(a_values, a), (b_values, b)| {
+        for ((key, a_value), b_value) in keys.iter().zip(a_values).zip(b_values) {
+            let ordering = if key.version {
+                versioncmp(&a_value.text, &b_value.text)
+            } else {
+                match (a_value.number, b_value.number) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    _ => fold(&a_value.text).cmp(&fold(&b_value.text)),
+                }
+            };
+            let ordering = if key.reverse { ordering.reverse() } else { ordering };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        fold(&a.refname).cmp(&fold(&b.refname))
+    });
+    sorted.truncate(count.filter(|&count| count > 0).unwrap_or(sorted.len()));
+
+    let mut out = std::io::stdout().lock();
+    for (_, item) in &sorted {
+        let mut line = formatter.format(&items, item)?;
+        if reset_at_end {
+            line.extend_from_slice(b"\x1b[m");
+        }
+        line.push(b'\n');
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{commit, repository, tree};
+    use crate::write_object_to_store;
+    use std::fs;
+
+    // main merging side into c1, with v1 an annotated tag of c1 and light a tag of side
+    fn refs() -> Vec<Listed> {
+        let empty = tree(&[]);
+        let c1 = commit(&empty, &[], 1, "c1");
+        let side = commit(&empty, &[&c1], 2, "s1\n\nbody\n");
+        let main = commit(&empty, &[&c1, &side], 3, "merge");
+        let tag = format!("object {}\ntype commit\ntag v1\ntagger C O Mitter <committer@example.com> 1 +0000\n\nv1\n", c1);
+        let tag = write_object_to_store(tag.as_bytes(), b"tag");
+        for (name, hash) in [("heads/main", &main), ("heads/side", &side), ("tags/light", &side), ("tags/v1", &tag)] {
+            fs::write(format!(".git/refs/{}", name), format!("{}\n", hash)).unwrap();
+        }
+        ["refs/heads/main", "refs/heads/side", "refs/tags/light", "refs/tags/v1"]
+            .iter()
+            .map(|refname| {
+                let object = Loaded::read(&resolve_ref(refname).unwrap()).unwrap();
+                let tagged = object.header_values("object").first().map(|target| Loaded::read(target).unwrap());
+                Listed { refname: refname.to_string(), symref: None, object, tagged }
+            })
+            .collect()
+    }
+
+    fn format(format: &str, quote: Quote, listed: &[Listed]) -> anyhow::Result<Vec<String>> {
+        let formatter = Formatter { config: read_config_file(".git/config"), head: Some("refs/heads/main".to_string()), quote };
+        let items = parse_format(format, false)?;
+        listed.iter().map(|listed| Ok(String::from_utf8(formatter.format(&items, listed)?).unwrap())).collect()
+    }
+
+    #[test]
+    fn formats() {
+        let _repository = repository();
+        let listed = refs();
+        let names = format("%(refname:lstrip=1)|%(refname:rstrip=-1)|%(refname:lstrip=-2)|%(align:8,right)%(refname:short)%(end)", Quote::None, &listed);
+        let expected = ["heads/main|refs|heads/main|    main", "heads/side|refs|heads/side|    side", "tags/light|refs|tags/light|   light", "tags/v1|refs|tags/v1|      v1"];
+        assert_eq!(names.unwrap(), expected);
+        let choices = format("%(if)%(upstream)%(then)up%(else)none%(end)|%(if:equals=v1)%(refname:short)%(then)is v1%(end)|%(HEAD)", Quote::None, &listed);
+        assert_eq!(choices.unwrap(), ["none||*", "none|| ", "none|| ", "none|is v1| "]);
+        let objects = format("%(objecttype) %(numparent) %(subject) %(*objecttype) %(*subject) %%%41", Quote::None, &listed);
+        assert_eq!(objects.unwrap(), ["commit 2 merge   %A", "commit 1 s1   %A", "commit 1 s1   %A", "tag  v1 commit c1 %A"]);
+
+        // Only what is outside %(if) and %(align) is quoted, as a whole once it ends
+        let quoted = format("%(refname:short) %(if)x%(then)%(subject)%(end)", Quote::Shell, &listed[2..]);
+        assert_eq!(quoted.unwrap(), ["'light' 's1'", "'v1' 'v1'"]);
+
+        let error = |text: &str| format(text, Quote::None, &listed).unwrap_err().to_string();
+        assert_eq!(error("%(then)"), "format: %(then) atom used without a %(if) atom");
+        assert_eq!(error("%(if)x"), "format: %(end) atom missing");
+        assert_eq!(error("%(else)"), "format: %(else) atom used without a %(if) atom");
+        assert_eq!(error("%(end)"), "format: %(end) atom used without corresponding atom");
+        assert_eq!(error("%(refname:bogus)"), "unrecognized %(refname:bogus) argument: bogus");
+    }
+
+    #[test]
+    fn messages() {
+        let message = split_message("tree x\n\nsubject\nline\n\nbody\n-----BEGIN PGP SIGNATURE-----\nsig\n");
+        assert_eq!(message.subject, "subject\nline");
+        assert_eq!(join_subject(message.subject), "subject line");
+        assert_eq!(message.body, "body\n-----BEGIN PGP SIGNATURE-----\nsig\n");
+        assert_eq!(message.body_without_signature, "body\n");
+        assert_eq!(message.signature, "-----BEGIN PGP SIGNATURE-----\nsig\n");
+        assert_eq!(message.bare, "subject\nline\n\nbody\n-----BEGIN PGP SIGNATURE-----\nsig\n");
+    }
+
+    #[test]
+    fn names_and_patterns() {
+        assert_eq!(strip_components("refs/heads/topic/a", 2, true), "topic/a");
+        assert_eq!(strip_components("refs/heads/topic/a", -1, true), "a");
+        assert_eq!(strip_components("refs/heads/topic/a", 1, false), "refs/heads/topic");
+        assert_eq!(strip_components("refs/heads/topic/a", 5, true), "");
+        assert!(pattern_matches("refs/heads", "refs/heads/main", false));
+        assert!(pattern_matches("refs/heads/", "refs/heads/main", false));
+        assert!(!pattern_matches("refs/hea", "refs/heads/main", false));
+        assert!(pattern_matches("refs/*/m*", "refs/heads/main", false));
+        assert!(pattern_matches("refs/*/M*", "refs/heads/main", true));
+        assert!(!pattern_matches("refs/HEADS", "refs/heads/main", true));
+    }
+
+    #[test]
+    fn quoting_and_versions() {
+        assert_eq!(quote(b"it's!", Quote::Shell), b"'it'\\''s'\\!''");
+        assert_eq!(quote(b"a'\\", Quote::Perl), b"'a\\'\\\\'");
+        assert_eq!(quote(b"a\n'", Quote::Python), b"'a\\n\\''");
+        assert_eq!(quote(b"$x [\t]", Quote::Tcl), b"\"\\$x \\[\\t\\]\"");
+        assert_eq!(literal("100%% %41%zz"), b"100% A%zz");
+
+        let mut tags = vec!["v1.10", "v1.9", "v1.0", "v1.09", "v10", "v1.9.1", "v1.9-rc1"];
+        tags.sort_by(|a, b| versioncmp(a, b));
+        // As in git, numbers with a leading zero compare as fractions
+        assert_eq!(tags, ["v1.09", "v1.0", "v1.9", "v1.9-rc1", "v1.9.1", "v1.10", "v10"]);
+    }
+}
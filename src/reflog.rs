@@ -0,0 +1,500 @@
+use crate::config::{read_config, Config};
+use crate::date::{format_date, parse_date, DateFormat};
+use crate::diff::NULL_HASH;
+use crate::ident::reflog_signature;
+use crate::refs::{list_refs, read_ref, resolve_ref, write_ref};
+use crate::revision::{ambiguous_argument, matching_ref_names, object_kind, peel_to_commit, reachable_commits};
+use crate::walk::abbreviate;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+// ReflogEntry is one line of a ref's log: the ids before and after an update and why it happened
+#[derive(Clone, Debug)]
+pub struct ReflogEntry {
+    pub old_hash: String,
+    pub new_hash: String,
+    pub identity: String,
+    pub time: i64,
+    pub offset: String,
+    pub message: String,
+}
+
+// Read the log of a ref, oldest entry first. Unparseable lines are skipped.
+pub fn read_reflog(refname: &str) -> Vec<ReflogEntry> {
+    let contents = fs::read_to_string(format!(".git/logs/{}", refname)).unwrap_or_default();
+    contents.lines().filter_map(parse_reflog_line).collect()
+}
+
+fn parse_reflog_line(line: &str) -> Option<ReflogEntry> {
+    let (old_hash, rest) = line.split_once(' ')?;
+    let (new_hash, rest) = rest.split_once(' ')?;
+    if old_hash.len() != 40 || new_hash.len() != 40 {
+        return None;
+    }
+    let (signature, message) = rest.split_once('\t').unwrap_or((rest, ""));
+    // The signature is "Name <email> <seconds> <offset>"
+    let (identity, date) = signature.rsplit_once("> ").map(|(identity, date)| (format!("{}>", identity), date)).unwrap_or_default();
+    let (time, offset) = date.split_once(' ').unwrap_or((date, ""));
+    Some(ReflogEntry {
+        old_hash: old_hash.to_string(),
+        new_hash: new_hash.to_string(),
+        identity,
+        time: time.parse().unwrap_or(0),
+        offset: offset.to_string(),
+        message: message.to_string(),
+    })
+}
+
+// Whether updates to a ref are logged. With core.logAllRefUpdates at its default of true that is
+// HEAD and the refs under refs/heads, refs/remotes and refs/notes, with "always" every ref, and
+// otherwise only refs that already have a log.
+fn should_log(config: &Config, refname: &str) -> bool {
+    if Path::new(&format!(".git/logs/{}", refname)).is_file() {
+        return true;
+    }
+    match config.get("core.logAllRefUpdates") {
+        Some("always") => true,
+        _ if config.get_bool("core.logAllRefUpdates") == Some(false) => false,
+        _ => refname == "HEAD" || ["refs/heads/", "refs/remotes/", "refs/notes/"].iter().any(|prefix| refname.starts_with(prefix)),
+    }
+}
+
+fn format_reflog_entry(entry: &ReflogEntry) -> String {
+    format!("{} {} {} {} {}\t{}\n", entry.old_hash, entry.new_hash, entry.identity, entry.time, entry.offset, entry.message)
+}
+
+// Append an entry to a ref's log recording its change from one object id to another, where
+// None stands for a ref that did not exist or no longer does. Whitespace in the message is
+// collapsed onto one line.
+pub fn append_reflog(refname: &str, old_hash: Option<&str>, new_hash: Option<&str>, message: &str) -> anyhow::Result<()> {
+    let config = read_config();
+    if !should_log(&config, refname) {
+        return Ok(());
+    }
+    let signature = reflog_signature(&config)?;
+    let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+    let line = format!("{} {} {}\t{}\n", old_hash.unwrap_or(NULL_HASH), new_hash.unwrap_or(NULL_HASH), signature, message);
+
+    let path = format!(".git/logs/{}", refname);
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    std::io::Write::write_all(&mut file, line.as_bytes())?;
+    Ok(())
+}
+
+// Replace a ref's log with the given entries
+pub fn write_reflog(refname: &str, entries: &[ReflogEntry]) -> anyhow::Result<()> {
+    let path = format!(".git/logs/{}", refname);
+    let lock_path = format!("{}.lock", path);
+    fs::write(&lock_path, entries.iter().map(format_reflog_entry).collect::<String>())?;
+    fs::rename(lock_path, path)?;
+    Ok(())
+}
+
+fn collect_reflogs(directory: &Path, prefix: &str, names: &mut Vec<String>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let name = if prefix.is_empty() { file_name } else { format!("{}/{}", prefix, file_name) };
+        if entry.path().is_dir() {
+            collect_reflogs(&entry.path(), &name, names);
+        } else {
+            names.push(name);
+        }
+    }
+}
+
+// Names of every ref that has a log under .git/logs
+pub fn list_reflogs() -> Vec<String> {
+    let mut names = Vec::new();
+    collect_reflogs(Path::new(".git/logs"), "", &mut names);
+    names.sort();
+    names
+}
+
+fn reflog_usage() -> ! {
+    eprintln!("usage: git reflog [show] [<log-options>] [<ref>]");
+    eprintln!("   or: git reflog expire [--expire=<time>] [--expire-unreachable=<time>]");
+    eprintln!("                         [--rewrite] [--updateref] [--stale-fix]");
+    eprintln!("                         [--dry-run | -n] [--verbose] [--all | <refs>...]");
+    eprintln!("   or: git reflog delete [--rewrite] [--updateref]");
+    eprintln!("                         [--dry-run | -n] [--verbose] <ref>@{{<specifier>}}...");
+    eprintln!("   or: git reflog exists <ref>");
+    eprintln!();
+    std::process::exit(129);
+}
+
+// The ref whose log a name given on the command line refers to: the first ref it could stand
+// for that has a log, or failing that the first one that exists
+fn reflog_ref_name(name: &str) -> Option<String> {
+    let candidates = matching_ref_names(name);
+    candidates
+        .iter()
+        .find(|candidate| Path::new(&format!(".git/logs/{}", candidate)).is_file())
+        .or(candidates.first())
+        .cloned()
+}
+
+// Print a ref's log newest entry first, each with the "<ref>@{<n>}" selector that names it
+fn show_reflog(args: &[String]) -> anyhow::Result<()> {
+    let mut max_count = None;
+    let mut date_format = None;
+    let mut names = Vec::new();
+    let mut index = 0;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        if arg == "-n" || arg == "--max-count" {
+            index += 1;
+            let value = args.get(index).ok_or_else(|| anyhow::anyhow!("switch `n' requires a value"))?;
+            max_count = Some(value.parse::<usize>().map_err(|_| anyhow::anyhow!("'{}': not an integer", value))?);
+        } else if let Some(value) = arg.strip_prefix("--max-count=").or_else(|| arg.strip_prefix("-n")) {
+            max_count = Some(value.parse::<usize>().map_err(|_| anyhow::anyhow!("'{}': not an integer", value))?);
+        } else if let Some(value) = arg.strip_prefix("--date=") {
+            date_format = Some(DateFormat::parse(value)?);
+        } else if arg.len() > 1 && arg.starts_with('-') && arg[1..].bytes().all(|x| x.is_ascii_digit()) {
+            max_count = Some(arg[1..].parse()?);
+        } else if arg.starts_with('-') {
+            anyhow::bail!("unrecognized argument: {}", arg);
+        } else {
+            names.push(arg);
+        }
+        index += 1;
+    }
+    if names.len() > 1 {
+        anyhow::bail!("reflog show takes at most one ref");
+    }
+
+    let name = names.first().copied().unwrap_or("HEAD");
+    let refname = reflog_ref_name(name).ok_or_else(|| ambiguous_argument(name, "unknown revision or path not in the working tree."))?;
+    let entries = read_reflog(&refname);
+    let shown = entries.iter().rev().enumerate().filter(|(_, entry)| entry.new_hash != NULL_HASH);
+    for (number, entry) in shown.take(max_count.unwrap_or(usize::MAX)) {
+        let selector = match &date_format {
+            Some(format) => format_date(entry.time, &entry.offset, format),
+            None => number.to_string(),
+        };
+        println!("{} {}@{{{}}}: {}", abbreviate(&entry.new_hash), name, selector, entry.message);
+    }
+    Ok(())
+}
+
+// ExpireOptions are the switches shared by "reflog expire" and "reflog delete"
+#[derive(Default)]
+struct ExpireOptions {
+    dry_run: bool,
+    rewrite: bool,
+    update_ref: bool,
+    verbose: bool,
+}
+
+impl ExpireOptions {
+    fn parse(&mut self, arg: &str) -> bool {
+        match arg {
+            "-n" | "--dry-run" => self.dry_run = true,
+            "--rewrite" => self.rewrite = true,
+            "--updateref" => self.update_ref = true,
+            "--verbose" => self.verbose = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
+// Drop the entries of a ref's log that should_prune picks. With --rewrite each remaining entry
+// starts from where the one before it left off, and with --updateref the ref is moved to the
+// newest remaining entry.
+fn prune_reflog(refname: &str, options: &ExpireOptions, mut should_prune: impl FnMut(usize, &ReflogEntry) -> bool) -> anyhow::Result<()> {
+    let mut kept: Vec<ReflogEntry> = Vec::new();
+    for (position, entry) in read_reflog(refname).into_iter().enumerate() {
+        let prune = should_prune(position, &entry);
+        if options.verbose {
+            match (prune, options.dry_run) {
+                (true, true) => println!("would prune {}", entry.message),
+                (true, false) => println!("prune {}", entry.message),
+                (false, _) => println!("keep {}", entry.message),
+            }
+        }
+        if prune {
+            continue;
+        }
+        let mut entry = entry;
+        if options.rewrite {
+            entry.old_hash = kept.last().map(|last| last.new_hash.clone()).unwrap_or_else(|| NULL_HASH.to_string());
+        }
+        kept.push(entry);
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+    write_reflog(refname, &kept)?;
+    let symbolic = read_ref(refname).is_some_and(|value| value.starts_with("ref: "));
+    if let Some(last) = kept.last().filter(|_| options.update_ref && !symbolic) {
+        write_ref(refname, &last.new_hash)?;
+    }
+    Ok(())
+}
+
+// Parse an expiry time: a date, "now" or "all" for everything, or "never" or "false" for nothing
+fn parse_expiry(value: &str) -> anyhow::Result<i64> {
+    match value {
+        "never" | "false" => Ok(0),
+        "all" | "now" => Ok(i64::MAX),
+        _ => parse_date(value).ok_or_else(|| anyhow::anyhow!("malformed expiration date '{}'", value)),
+    }
+}
+
+// Prune log entries older than --expire, and ones older than --expire-unreachable whose
+// commits can no longer be reached from the ref, from any ref in the case of HEAD
+fn expire_reflogs(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let expiry = |key: &str, default: &str| parse_expiry(config.get(key).unwrap_or(default));
+    let mut expire = expiry("gc.reflogExpire", "90.days.ago")?;
+    let mut expire_unreachable = expiry("gc.reflogExpireUnreachable", "30.days.ago")?;
+    let mut options = ExpireOptions::default();
+    let mut stale_fix = false;
+    let mut all = false;
+    let mut names = Vec::new();
+    for arg in args {
+        if options.parse(arg) {
+            continue;
+        }
+        match arg.as_str() {
+            "--stale-fix" => stale_fix = true,
+            "--all" => all = true,
+            _ if arg.starts_with("--expire=") => expire = parse_expiry(&arg["--expire=".len()..])?,
+            _ if arg.starts_with("--expire-unreachable=") => expire_unreachable = parse_expiry(&arg["--expire-unreachable=".len()..])?,
+            _ if arg.starts_with('-') => {
+                eprintln!("error: unknown option `{}'", arg.trim_start_matches('-'));
+                reflog_usage();
+            }
+            _ => names.push(arg.as_str()),
+        }
+    }
+
+    let mut refnames = if all { list_reflogs() } else { Vec::new() };
+    let mut failed = false;
+    for name in names {
+        match reflog_ref_name(name).filter(|refname| Path::new(&format!(".git/logs/{}", refname)).is_file()) {
+            Some(refname) => refnames.push(refname),
+            None => {
+                eprintln!("error: {} points nowhere!", name);
+                failed = true;
+            }
+        }
+    }
+
+    let exists = |hash: &str| hash == NULL_HASH || object_kind(hash).is_ok();
+    for refname in refnames {
+        let mut tips = Vec::new();
+        if refname == "HEAD" {
+            tips.extend(list_refs().into_iter().map(|(_, hash)| hash));
+        }
+        tips.extend(resolve_ref(&refname));
+        let mut reachable = HashSet::new();
+        for tip in tips.iter().filter_map(|tip| peel_to_commit(tip).ok()) {
+            reachable.extend(reachable_commits(&tip)?);
+        }
+        let unreachable = |hash: &str| hash != NULL_HASH && !reachable.contains(hash);
+
+        prune_reflog(&refname, &options, |_, entry| {
+            entry.time < expire
+                || (stale_fix && !(exists(&entry.old_hash) && exists(&entry.new_hash)))
+                || (entry.time < expire_unreachable && (expire_unreachable <= expire || unreachable(&entry.old_hash) || unreachable(&entry.new_hash)))
+        })?;
+    }
+    if failed {
+        std::process::exit(255);
+    }
+    Ok(())
+}
+
+// Remove single entries named "<ref>@{<n>}", or "<ref>@{<date>}" for the newest entry older
+// than the date, each from the log as it is after the ones before it have been removed
+fn delete_reflog_entries(args: &[String]) -> anyhow::Result<()> {
+    let mut options = ExpireOptions::default();
+    let mut selectors = Vec::new();
+    for arg in args {
+        if options.parse(arg) {
+            continue;
+        }
+        if arg.starts_with('-') {
+            eprintln!("error: unknown option `{}'", arg.trim_start_matches('-'));
+            reflog_usage();
+        }
+        selectors.push(arg.as_str());
+    }
+    if selectors.is_empty() {
+        eprintln!("error: no reflog specified to delete");
+        std::process::exit(255);
+    }
+
+    let mut failed = false;
+    for spec in selectors {
+        let Some((name, selector)) = spec.strip_suffix('}').and_then(|spec| spec.split_once("@{")) else {
+            eprintln!("error: not a reflog: {}", spec);
+            failed = true;
+            continue;
+        };
+        let Some(refname) = reflog_ref_name(if name.is_empty() { "HEAD" } else { name }) else {
+            eprintln!("error: no reflog for '{}'", spec);
+            failed = true;
+            continue;
+        };
+        let entries = read_reflog(&refname);
+        let target = match selector.parse::<usize>() {
+            Ok(count) => entries.len().checked_sub(count + 1),
+            Err(_) => match parse_date(selector) {
+                Some(time) => entries.iter().rposition(|entry| entry.time < time),
+                None => {
+                    eprintln!("error: no reflog for '{}'", spec);
+                    failed = true;
+                    continue;
+                }
+            },
+        };
+        prune_reflog(&refname, &options, |position, _| Some(position) == target)?;
+    }
+    if failed {
+        std::process::exit(255);
+    }
+    Ok(())
+}
+
+// Show a ref's log, prune old entries from logs, delete single entries, or check a log exists
+pub fn reflog_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "reflog").unwrap();
+    let args = &args[command_index + 1..];
+    match args.first().map(|x| x.as_str()) {
+        Some("show") => show_reflog(&args[1..]),
+        Some("expire") => expire_reflogs(&args[1..]),
+        Some("delete") => delete_reflog_entries(&args[1..]),
+        Some("exists") => {
+            let [name] = &args[1..] else {
+                eprintln!("usage: git reflog exists <ref>");
+                eprintln!();
+                std::process::exit(129);
+            };
+            if !Path::new(&format!(".git/logs/{}", name)).is_file() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Some("-h") => reflog_usage(),
+        _ => show_reflog(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::revision::resolve_revision;
+    use crate::testrepo::{commit, repository, tree};
+
+    fn entry(old_hash: &str, new_hash: &str, time: i64, message: &str) -> ReflogEntry {
+        ReflogEntry {
+            old_hash: old_hash.to_string(),
+            new_hash: new_hash.to_string(),
+            identity: "A U Thor <author@example.com>".to_string(),
+            time,
+            offset: "+0000".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    // Three commits main moved through in turn, at times 100, 200 and 300
+    fn logged_main() -> Vec<String> {
+        let first = commit(&tree(&[]), &[], 1, "first");
+        let second = commit(&tree(&[]), &[&first], 2, "second");
+        let third = commit(&tree(&[]), &[&second], 3, "third");
+        fs::create_dir_all(".git/logs/refs/heads").unwrap();
+        let entries = [entry(NULL_HASH, &first, 100, "one"), entry(&first, &second, 200, "two"), entry(&second, &third, 300, "three")];
+        write_reflog("refs/heads/main", &entries).unwrap();
+        write_ref("refs/heads/main", &third).unwrap();
+        vec![first, second, third]
+    }
+
+    #[test]
+    fn lines() {
+        let hash = "1".repeat(40);
+        let line = format!("{} {} A U Thor <author@example.com> 1700000000 -0230\tcommit: a\tb", NULL_HASH, hash);
+        let entry = parse_reflog_line(&line).unwrap();
+        assert_eq!((entry.identity.as_str(), entry.time, entry.offset.as_str()), ("A U Thor <author@example.com>", 1700000000, "-0230"));
+        assert_eq!(entry.message, "commit: a\tb");
+        assert_eq!(format_reflog_entry(&entry), format!("{}\n", line));
+        assert!(parse_reflog_line("short ids 1 +0000\tx").is_none());
+    }
+
+    #[test]
+    fn logged_refs() {
+        let _repository = repository();
+        let config = read_config_file(".git/config");
+        assert!(should_log(&config, "HEAD") && should_log(&config, "refs/heads/main"));
+        assert!(!should_log(&config, "refs/tags/v1"));
+        fs::write(".git/config", "[core]\n\tlogAllRefUpdates = always\n").unwrap();
+        assert!(should_log(&read_config_file(".git/config"), "refs/tags/v1"));
+        fs::write(".git/config", "[core]\n\tlogAllRefUpdates = false\n").unwrap();
+        assert!(!should_log(&read_config_file(".git/config"), "refs/heads/main"));
+
+        fs::write(".git/config", "").unwrap();
+        append_reflog("refs/heads/new", None, Some(&"2".repeat(40)), "branch:\n  created   here").unwrap();
+        let entries = read_reflog("refs/heads/new");
+        assert_eq!((entries[0].old_hash.as_str(), entries[0].message.as_str()), (NULL_HASH, "branch: created here"));
+        assert_eq!(list_reflogs(), ["refs/heads/new"]);
+    }
+
+    #[test]
+    fn selectors() {
+        let _repository = repository();
+        let commits = logged_main();
+        assert_eq!(resolve_revision("main@{0}").unwrap(), commits[2]);
+        assert_eq!(resolve_revision("main@{2}").unwrap(), commits[0]);
+        assert_eq!(resolve_revision("@{1}").unwrap(), commits[1]);
+        assert_eq!(resolve_revision("main@{3}").unwrap_err().to_string(), "log for 'main' only has 3 entries");
+        assert_eq!(resolve_revision("main@{@250}").unwrap(), commits[1]);
+        assert_eq!(resolve_revision("main@{@300}").unwrap(), commits[2]);
+        // Before the log starts the oldest value is used
+        assert_eq!(resolve_revision("main@{@50}").unwrap(), commits[0]);
+
+        fs::create_dir_all(".git/logs").unwrap();
+        let checkouts = [entry(NULL_HASH, &commits[0], 1, "checkout: moving from main to topic"), entry(&commits[0], &commits[2], 2, "checkout: moving from topic to main")];
+        write_reflog("HEAD", &checkouts).unwrap();
+        fs::write(".git/refs/heads/topic", format!("{}\n", commits[1])).unwrap();
+        assert_eq!(resolve_revision("@{-1}").unwrap(), commits[1]);
+        assert_eq!(resolve_revision("@{-2}").unwrap(), commits[2]);
+        assert!(resolve_revision("@{-3}").is_err());
+    }
+
+    #[test]
+    fn pruning() {
+        let _repository = repository();
+        let commits = logged_main();
+        let options = ExpireOptions { rewrite: true, update_ref: true, ..ExpireOptions::default() };
+        prune_reflog("refs/heads/main", &options, |position, _| position == 1).unwrap();
+        let entries = read_reflog("refs/heads/main");
+        let values: Vec<_> = entries.iter().map(|x| (x.old_hash.as_str(), x.new_hash.as_str())).collect();
+        assert_eq!(values, [(NULL_HASH, commits[0].as_str()), (commits[0].as_str(), commits[2].as_str())]);
+
+        prune_reflog("refs/heads/main", &options, |_, entry| entry.time > 200).unwrap();
+        assert_eq!(read_reflog("refs/heads/main").len(), 1);
+        assert_eq!(resolve_ref("refs/heads/main").as_deref(), Some(commits[0].as_str()));
+
+        let dry_run = ExpireOptions { dry_run: true, ..ExpireOptions::default() };
+        prune_reflog("refs/heads/main", &dry_run, |_, _| true).unwrap();
+        assert_eq!(read_reflog("refs/heads/main").len(), 1);
+    }
+
+    #[test]
+    fn expiry() {
+        assert_eq!(parse_expiry("never").unwrap(), 0);
+        assert_eq!(parse_expiry("all").unwrap(), i64::MAX);
+        assert_eq!(parse_expiry("@1700000000").unwrap(), 1700000000);
+        assert_eq!(parse_expiry("soon").unwrap_err().to_string(), "malformed expiration date 'soon'");
+    }
+}
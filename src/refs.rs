@@ -0,0 +1,303 @@
+use crate::config::Config;
+use crate::reflog::append_reflog;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// Read the raw value of a ref, either a loose file under .git or an entry in packed-refs.
+// Symbolic refs come back as "ref: <target>".
+pub fn read_ref(name: &str) -> Option<String> {
+    let path = format!(".git/{}", name);
+    if Path::new(&path).is_file() {
+        return fs::read_to_string(path).ok().map(|contents| contents.trim_end().to_string());
+    }
+    packed_refs().remove(name)
+}
+
+// Follow a ref through any symbolic refs to the object id it names
+pub fn resolve_ref(name: &str) -> Option<String> {
+    let mut name = name.to_string();
+    for _ in 0..5 {
+        let value = read_ref(&name)?;
+        match value.strip_prefix("ref: ") {
+            Some(target) => name = target.to_string(),
+            None => return Some(value),
+        }
+    }
+    None
+}
+
+// The branch HEAD points at, if HEAD is a symbolic ref
+pub fn head_symbolic_target() -> Option<String> {
+    read_ref("HEAD")?.strip_prefix("ref: ").map(|target| target.to_string())
+}
+
+// Parse .git/packed-refs into ref names and ids, skipping peeled tag lines
+fn packed_refs() -> BTreeMap<String, String> {
+    let mut refs = BTreeMap::new();
+    let contents = fs::read_to_string(".git/packed-refs").unwrap_or_default();
+    for line in contents.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        if let Some((hash, name)) = line.split_once(' ') {
+            refs.insert(name.to_string(), hash.to_string());
+        }
+    }
+    refs
+}
+
+fn collect_loose_refs(directory: &Path, prefix: &str, refs: &mut BTreeMap<String, String>) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        let path = entry.path();
+        if path.is_dir() {
+            collect_loose_refs(&path, &name, refs);
+        } else if let Ok(contents) = fs::read_to_string(&path) {
+            refs.insert(name, contents.trim_end().to_string());
+        }
+    }
+}
+
+// List every ref under refs/ with the object id it resolves to, sorted by name.
+// Loose refs take precedence over packed ones and broken symbolic refs are left out.
+pub fn list_refs() -> Vec<(String, String)> {
+    let mut refs = packed_refs();
+    collect_loose_refs(Path::new(".git/refs"), "refs", &mut refs);
+    refs.into_iter()
+        .filter_map(|(name, value)| match value.strip_prefix("ref: ") {
+            Some(target) => resolve_ref(target).map(|hash| (name, hash)),
+            None => Some((name, value)),
+        })
+        .collect()
+}
+
+// Point a ref at a new value, either an object id or "ref: <target>" for symbolic refs
+pub fn write_ref(name: &str, value: &str) -> anyhow::Result<()> {
+    let path = format!(".git/{}", name);
+    if let Some(parent) = Path::new(&path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock_path = format!("{}.lock", path);
+    fs::write(&lock_path, format!("{}\n", value))?;
+    fs::rename(lock_path, path)?;
+    Ok(())
+}
+
+// Point a ref at a new object id and log the change, in HEAD's log as well when the ref is the
+// branch HEAD is on. A ref that already has the value is left alone, though HEAD's log still
+// records the update the way git's does.
+pub fn update_ref(name: &str, value: &str, message: &str) -> anyhow::Result<()> {
+    let old = resolve_ref(name);
+    if read_ref(name).as_deref() != Some(value) {
+        write_ref(name, value)?;
+        append_reflog(name, old.as_deref(), Some(value), message)?;
+    }
+    if head_symbolic_target().as_deref() == Some(name) {
+        append_reflog("HEAD", old.as_deref(), Some(value), message)?;
+    }
+    Ok(())
+}
+
+// Make a ref, usually HEAD, point at another ref, logging the move when the target exists
+pub fn update_symbolic_ref(name: &str, target: &str, message: &str) -> anyhow::Result<()> {
+    let old = resolve_ref(name);
+    write_ref(name, &format!("ref: {}", target))?;
+    match resolve_ref(target) {
+        Some(new) => append_reflog(name, old.as_deref(), Some(&new), message),
+        None => Ok(()),
+    }
+}
+
+// Remove a ref, both its loose file and any packed-refs entry, along with its log
+pub fn delete_ref(name: &str) -> anyhow::Result<()> {
+    let path = format!(".git/{}", name);
+    if Path::new(&path).is_file() {
+        fs::remove_file(&path)?;
+        remove_empty_parents(Path::new(&path), ".git/refs");
+    }
+    let log_path = format!(".git/logs/{}", name);
+    if Path::new(&log_path).is_file() {
+        fs::remove_file(&log_path)?;
+        remove_empty_parents(Path::new(&log_path), ".git/logs/refs");
+    }
+
+    let contents = fs::read_to_string(".git/packed-refs").unwrap_or_default();
+    let mut kept = String::new();
+    let mut removed = false;
+    let mut skip_peeled = false;
+    for line in contents.lines() {
+        if line.starts_with('^') && skip_peeled {
+            continue;
+        }
+        skip_peeled = line.split_once(' ').map(|(_, packed)| packed) == Some(name);
+        if skip_peeled {
+            removed = true;
+            continue;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+    }
+    if removed {
+        fs::write(".git/packed-refs.lock", kept)?;
+        fs::rename(".git/packed-refs.lock", ".git/packed-refs")?;
+    }
+    Ok(())
+}
+
+// Remove the directories a deleted file leaves empty, up to but not including the top
+fn remove_empty_parents(path: &Path, top: &str) {
+    let mut directory = path.parent();
+    while let Some(current) = directory {
+        if current == Path::new(top) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        directory = current.parent();
+    }
+}
+
+// Point a ref at a value and give it the log another ref had
+fn write_ref_with_log(name: &str, value: &str, log: Option<Vec<u8>>) -> anyhow::Result<()> {
+    write_ref(name, value)?;
+    if let Some(log) = log {
+        let log_path = format!(".git/logs/{}", name);
+        if let Some(parent) = Path::new(&log_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(log_path, log)?;
+    }
+    Ok(())
+}
+
+// Log an entry that leaves a ref at the value it has, in HEAD's log too when HEAD is on it
+fn log_in_place(name: &str, value: &str, message: &str) -> anyhow::Result<()> {
+    append_reflog(name, Some(value), Some(value), message)?;
+    if head_symbolic_target().as_deref() == Some(name) {
+        append_reflog("HEAD", Some(value), Some(value), message)?;
+    }
+    Ok(())
+}
+
+// Move a ref and its log to a new name, logging the rename. When HEAD is on the ref its log
+// records the ref going away and coming back under the new name, as git's does.
+pub fn rename_ref(old: &str, new: &str, message: &str) -> anyhow::Result<()> {
+    let value = read_ref(old).ok_or_else(|| anyhow::anyhow!("refname {} not found", old))?;
+    let log = fs::read(format!(".git/logs/{}", old)).ok();
+    let on_head = head_symbolic_target().as_deref() == Some(old);
+    delete_ref(old)?;
+    if on_head {
+        append_reflog("HEAD", Some(&value), None, message)?;
+    }
+    write_ref_with_log(new, &value, log)?;
+    log_in_place(new, &value, message)?;
+    if on_head && old != new {
+        append_reflog("HEAD", None, Some(&value), message)?;
+    }
+    Ok(())
+}
+
+// Give a ref and its log a second name, logging the copy
+pub fn copy_ref(old: &str, new: &str, message: &str) -> anyhow::Result<()> {
+    let value = read_ref(old).ok_or_else(|| anyhow::anyhow!("refname {} not found", old))?;
+    write_ref_with_log(new, &value, fs::read(format!(".git/logs/{}", old)).ok())?;
+    log_in_place(new, &value, message)
+}
+
+// Drop the refs/heads/, refs/tags/ or refs/remotes/ prefix for display
+pub fn shorten_ref_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+// Map a ref through a fetch refspec such as "+refs/heads/*:refs/remotes/origin/*"
+fn map_refspec(refspec: &str, name: &str) -> Option<String> {
+    let (source, destination) = refspec.trim_start_matches('+').split_once(':')?;
+    match source.split_once('*') {
+        Some((prefix, suffix)) => {
+            let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(destination.replacen('*', matched, 1))
+        }
+        None => (source == name).then(|| destination.to_string()),
+    }
+}
+
+// The full name of the ref a branch tracks, from its branch.<name>.remote and branch.<name>.merge
+// settings mapped through the remote's fetch refspecs. Branches tracking another local branch
+// use remote ".".
+pub fn branch_upstream(config: &Config, branch: &str) -> Option<String> {
+    let remote = config.get(&format!("branch.{}.remote", branch))?;
+    let merge = config.get(&format!("branch.{}.merge", branch))?;
+    if remote == "." {
+        return Some(merge.to_string());
+    }
+    remote_tracking_ref(config, remote, merge)
+}
+
+// The remote-tracking ref a ref of a remote is fetched into, by the remote's fetch refspecs
+pub fn remote_tracking_ref(config: &Config, remote: &str, name: &str) -> Option<String> {
+    config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .into_iter()
+        .find_map(|refspec| map_refspec(refspec, name))
+}
+
+// The remote a branch is pushed to: branch.<name>.pushRemote, remote.pushDefault, the remote
+// it tracks, or origin
+pub fn branch_push_remote<'a>(config: &'a Config, branch: &str) -> &'a str {
+    config
+        .get(&format!("branch.{}.pushRemote", branch))
+        .or_else(|| config.get("remote.pushDefault"))
+        .or_else(|| config.get(&format!("branch.{}.remote", branch)))
+        .unwrap_or("origin")
+}
+
+// The remote-tracking ref standing for where a branch is pushed to, following the remote's
+// push refspecs or else push.default
+pub fn branch_push_ref(config: &Config, branch: &str) -> Option<String> {
+    let remote = branch_push_remote(config, branch);
+    let local = format!("refs/heads/{}", branch);
+    let push_refspecs = config.get_all(&format!("remote.{}.push", remote));
+    if !push_refspecs.is_empty() {
+        let destination = push_refspecs.into_iter().find_map(|refspec| map_refspec(refspec, &local))?;
+        return remote_tracking_ref(config, remote, &destination);
+    }
+    let current = remote_tracking_ref(config, remote, &local);
+    match config.get("push.default").unwrap_or("simple") {
+        "nothing" => None,
+        "matching" | "current" => current,
+        "upstream" | "tracking" => branch_upstream(config, branch),
+        _ => {
+            // simple pushes only to an upstream of the same name
+            let upstream = branch_upstream(config, branch)?;
+            (current? == upstream).then_some(upstream)
+        }
+    }
+}
+
+// The ref on the remote a branch is pushed to, when the remote's push refspecs say
+pub fn branch_push_remote_ref(config: &Config, branch: &str) -> Option<String> {
+    let remote = branch_push_remote(config, branch);
+    let local = format!("refs/heads/{}", branch);
+    config
+        .get_all(&format!("remote.{}.push", remote))
+        .into_iter()
+        .find_map(|refspec| map_refspec(refspec, &local))
+}
+
+// The remote and remote ref a remote-tracking ref is fetched from, found by mapping it back
+// through each remote's fetch refspecs
+pub fn tracking_source(config: &Config, name: &str) -> Option<(String, String)> {
+    config.subsections("remote").into_iter().find_map(|remote| {
+        config.get_all(&format!("remote.{}.fetch", remote)).into_iter().find_map(|refspec| {
+            let (source, destination) = refspec.trim_start_matches('+').split_once(':')?;
+            let merge = map_refspec(&format!("{}:{}", destination, source), name)?;
+            Some((remote.to_string(), merge))
+        })
+    })
+}
@@ -0,0 +1,785 @@
+// Rename and copy detection. Added files are paired with deleted ones, and when looking for
+// copies with files that were kept too, whose contents are alike. This follows git's
+// diffcore-rename: identical contents first, then files sharing a basename, then every
+// remaining pair, scoring how much of the larger file is made of chunks both files share.
+
+use crate::config::Config;
+use crate::diff::{ChangeKind, FileChange, TreeDiff, NULL_HASH};
+use crate::hash_object_contents;
+use crate::patch::{is_binary, side_contents};
+use crate::worktree::{flatten_tree, pathspec_matches};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+// Similarity scores are fractions of MAX_SCORE
+const MAX_SCORE: usize = 60000;
+const DEFAULT_SCORE: usize = 30000;
+const DEFAULT_LIMIT: usize = 1000;
+// Merges compare many more files before giving up on inexact renames
+const MERGE_LIMIT: usize = 7000;
+// How many of the best sources each destination keeps while comparing every pair
+const CANDIDATES: usize = 4;
+const HASHBASE: u32 = 107927;
+const EMPTY_BLOB_HASH: &str = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+
+// What detection looks for: nothing, renames, or renames and copies
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Detect {
+    Off,
+    Renames,
+    Copies,
+}
+
+impl Detect {
+    // The diff.renames and status.renames values: a boolean, or "copies"
+    pub fn parse(value: &str) -> Detect {
+        match value.to_lowercase().as_str() {
+            "copies" | "copy" => Detect::Copies,
+            "false" | "no" | "off" | "0" | "" => Detect::Off,
+            _ => Detect::Renames,
+        }
+    }
+}
+
+// Renames are the rename detection settings of a diff. The minimum score is 0 for the default
+// and a limit of 0 means none. When the limit stopped a search, the largest number of files
+// it would have needed is kept so the caller can advise raising it.
+#[derive(Clone, Debug)]
+pub struct Renames {
+    pub detect: Detect,
+    pub copies_harder: bool,
+    pub score: usize,
+    pub limit: usize,
+    pub empty: bool,
+    pub only_destination: Option<String>,
+    needed_limit: Cell<usize>,
+    degraded: Cell<bool>,
+}
+
+// Turn "50", "50%" or "0.5" into a score, the way -M and -C read their argument
+fn parse_score(value: &str, option: &str) -> usize {
+    let mut number = 0;
+    let mut scale = 1;
+    let mut dot = false;
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        if c == '.' && !dot {
+            scale = 1;
+            dot = true;
+        } else if c == '%' {
+            scale = if dot { scale * 100 } else { 100 };
+            rest = &rest[1..];
+            break;
+        } else if c.is_ascii_digit() {
+            if scale < 100000 {
+                scale *= 10;
+                number = number * 10 + (c as usize - '0' as usize);
+            }
+        } else {
+            break;
+        }
+        rest = &rest[1..];
+    }
+    // Like other option parsing errors this exits with usage status
+    if !rest.is_empty() {
+        eprintln!("error: invalid argument to {}", option);
+        std::process::exit(129);
+    }
+    if number >= scale {
+        MAX_SCORE
+    } else {
+        MAX_SCORE * number / scale
+    }
+}
+
+impl Renames {
+    // No detection, as for the plumbing commands, with the limit from the configuration
+    pub fn new(config: &Config) -> Renames {
+        Renames {
+            detect: Detect::Off,
+            copies_harder: false,
+            score: 0,
+            limit: config.get("diff.renameLimit").and_then(|x| x.parse().ok()).unwrap_or(DEFAULT_LIMIT),
+            empty: true,
+            only_destination: None,
+            needed_limit: Cell::new(0),
+            degraded: Cell::new(false),
+        }
+    }
+
+    // Detection as diff.renames asks for it, which is renames unless configured otherwise
+    pub fn porcelain(config: &Config) -> Renames {
+        let mut renames = Renames::new(config);
+        renames.detect = config.get("diff.renames").map(Detect::parse).unwrap_or(Detect::Renames);
+        renames
+    }
+
+    // Detection for status, where status.renames and status.renameLimit take precedence
+    pub fn for_status(config: &Config) -> Renames {
+        let mut renames = Renames::porcelain(config);
+        if let Some(value) = config.get("status.renames") {
+            renames.detect = Detect::parse(value);
+        }
+        if let Some(limit) = config.get("status.renameLimit").and_then(|x| x.parse().ok()) {
+            renames.limit = limit;
+        }
+        renames
+    }
+
+    // Detection for merges, which only look for renames and never pair up empty files.
+    // merge.renames and merge.renameLimit take precedence over the diff settings.
+    pub fn for_merge(config: &Config) -> Renames {
+        let mut renames = Renames::porcelain(config);
+        if let Some(value) = config.get("merge.renames") {
+            renames.detect = Detect::parse(value);
+        }
+        if renames.detect == Detect::Copies {
+            renames.detect = Detect::Renames;
+        }
+        renames.limit = ["merge.renameLimit", "diff.renameLimit"]
+            .iter()
+            .find_map(|key| config.get(key).and_then(|x| x.parse().ok()))
+            .unwrap_or(MERGE_LIMIT);
+        renames.empty = false;
+        renames
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.detect != Detect::Off
+    }
+
+    // Whether files that did not change are candidate sources of copies
+    pub fn wants_unchanged(&self) -> bool {
+        self.detect == Detect::Copies && self.copies_harder
+    }
+
+    // Take a rename option if arg is one, returning whether it was
+    pub fn parse_option(&mut self, arg: &str) -> anyhow::Result<bool> {
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value)),
+            _ => (arg, None),
+        };
+        match option {
+            "--find-renames" => {
+                self.score = parse_score(value.unwrap_or(""), "find-renames");
+                self.detect = Detect::Renames;
+            }
+            "--find-copies" => {
+                self.score = parse_score(value.unwrap_or(""), "find-copies");
+                self.copies_harder |= self.detect == Detect::Copies;
+                self.detect = Detect::Copies;
+            }
+            "--find-copies-harder" => self.copies_harder = true,
+            "--no-renames" => self.detect = Detect::Off,
+            "--rename-empty" => self.empty = true,
+            "--no-rename-empty" => self.empty = false,
+            _ if arg.starts_with("-M") => {
+                self.score = parse_score(&arg[2..], "find-renames");
+                self.detect = Detect::Renames;
+            }
+            _ if arg.starts_with("-C") => {
+                self.score = parse_score(&arg[2..], "find-copies");
+                // Asking for copies twice looks at unchanged files as well
+                self.copies_harder |= self.detect == Detect::Copies;
+                self.detect = Detect::Copies;
+            }
+            _ if arg.starts_with("-l") && arg.len() > 2 => {
+                self.limit = arg[2..].parse().map_err(|_| anyhow::anyhow!("-l expects a numerical value"))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Looking harder for copies means looking for copies
+    pub fn validate(&mut self) {
+        if self.copies_harder {
+            self.detect = Detect::Copies;
+        }
+    }
+
+    // Warn on stderr when the limit kept detection from looking at every pair
+    pub fn warn_limit(&self, variable: &str) {
+        let needed = self.needed_limit.get();
+        if self.degraded.get() {
+            eprintln!("warning: only found copies from modified paths due to too many files.");
+        } else if needed > 0 {
+            eprintln!("warning: exhaustive rename detection was skipped due to too many files.");
+        } else {
+            return;
+        }
+        if needed > 0 {
+            eprintln!("warning: you may want to set your {} variable to at least {} and retry the command.", variable, needed);
+        }
+    }
+
+    // Pair up the changes. They must be sorted by path; unchanged holds the files that stayed
+    // the same, which are only looked at when copies are searched for harder.
+    pub fn detect(&self, changes: Vec<FileChange>, unchanged: Vec<FileChange>) -> anyhow::Result<Vec<FileChange>> {
+        if !self.enabled() {
+            return Ok(changes);
+        }
+        let mut queue = changes;
+        if self.wants_unchanged() && !unchanged.is_empty() {
+            queue.extend(unchanged);
+            queue.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+        }
+        let mut search = Search::new(self, &queue);
+        if !search.destinations.is_empty() && !search.sources.is_empty() {
+            search.run()?;
+        }
+        Ok(search.write_back(queue))
+    }
+
+    // Compare two trees and pair up what moved between them
+    pub fn diff_trees(&self, tree_diff: &TreeDiff, old_tree: Option<&str>, new_tree: Option<&str>) -> anyhow::Result<Vec<FileChange>> {
+        let changes = tree_diff.diff(old_tree, new_tree)?;
+        let unchanged = match old_tree {
+            Some(old_tree) if self.wants_unchanged() => {
+                let files = flatten_tree(old_tree)?;
+                unchanged_files(files.iter().map(|(path, entry)| (path.as_str(), entry.mode, entry.sha_hash.as_str())), &changes, &tree_diff.paths)
+            }
+            _ => Vec::new(),
+        };
+        self.detect(changes, unchanged)
+    }
+}
+
+// Records for the files of the old side that have no change, within the pathspecs, as
+// candidate sources for copies
+pub fn unchanged_files<'a>(files: impl Iterator<Item = (&'a str, u32, &'a str)>, changes: &[FileChange], paths: &[String]) -> Vec<FileChange> {
+    let changed: std::collections::HashSet<&str> = changes.iter().map(|change| change.old_path.as_str()).collect();
+    files
+        .filter(|(path, _, _)| !changed.contains(path))
+        .filter(|(path, _, _)| paths.is_empty() || paths.iter().any(|spec| pathspec_matches(spec, path)))
+        .map(|(path, mode, hash)| FileChange {
+            kind: ChangeKind::Modified,
+            old_path: path.to_string(),
+            new_path: path.to_string(),
+            old_mode: mode,
+            new_mode: mode,
+            old_hash: hash.to_string(),
+            new_hash: hash.to_string(),
+            score: 0,
+        })
+        .collect()
+}
+
+// A file on one side of the comparison, with its contents and chunk counts read on demand
+struct Spec {
+    path: String,
+    mode: u32,
+    hash: String,
+    data: Option<Vec<u8>>,
+    size: Option<usize>,
+    spans: Option<Vec<(u32, usize)>>,
+}
+
+impl Spec {
+    fn new(path: &str, mode: u32, hash: &str) -> Spec {
+        Spec { path: path.to_string(), mode, hash: hash.to_string(), data: None, size: None, spans: None }
+    }
+
+    fn is_regular(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+
+    fn load(&mut self) -> anyhow::Result<&[u8]> {
+        if self.data.is_none() {
+            let data = side_contents(self.mode, &self.hash, &self.path)?;
+            self.size = Some(data.len());
+            self.data = Some(data);
+        }
+        Ok(self.data.as_deref().unwrap())
+    }
+
+    fn size(&mut self) -> anyhow::Result<usize> {
+        match self.size {
+            Some(size) => Ok(size),
+            None => Ok(self.load()?.len()),
+        }
+    }
+
+    // The object id, hashing files on disk that do not have one yet
+    fn object_id(&mut self) -> anyhow::Result<String> {
+        if self.hash == NULL_HASH && self.mode != 0o160000 {
+            let hash = hash_object_contents(self.load()?, b"blob");
+            return Ok(hash);
+        }
+        Ok(self.hash.clone())
+    }
+
+    fn spans(&mut self) -> anyhow::Result<&[(u32, usize)]> {
+        if self.spans.is_none() {
+            let spans = hash_chars(self.load()?);
+            self.spans = Some(spans);
+        }
+        Ok(self.spans.as_deref().unwrap())
+    }
+}
+
+// Cut the contents into chunks ending at each newline or after 64 bytes and count how many
+// bytes fall into chunks of each hash, sorted by hash. Text ignores the CR of CRLF.
+fn hash_chars(data: &[u8]) -> Vec<(u32, usize)> {
+    let text = !is_binary(data);
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    let (mut accum1, mut accum2, mut n) = (0u32, 0u32, 0);
+    for (index, &c) in data.iter().enumerate() {
+        if text && c == b'\r' && data.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        let old1 = accum1;
+        accum1 = (accum1 << 7) ^ (accum2 >> 25);
+        accum2 = (accum2 << 7) ^ (old1 >> 25);
+        accum1 = accum1.wrapping_add(c as u32);
+        n += 1;
+        if n < 64 && c != b'\n' {
+            continue;
+        }
+        let hash = accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASHBASE;
+        *counts.entry(hash).or_default() += n;
+        n = 0;
+        accum1 = 0;
+        accum2 = 0;
+    }
+    let mut spans: Vec<(u32, usize)> = counts.into_iter().collect();
+    spans.sort_unstable();
+    spans
+}
+
+// Bytes of the destination found in chunks the source has as well
+fn copied_bytes(source: &[(u32, usize)], destination: &[(u32, usize)]) -> usize {
+    let mut copied = 0;
+    let mut d = destination.iter().peekable();
+    for (hash, count) in source {
+        while d.next_if(|(other, _)| other < hash).is_some() {}
+        if let Some((_, other)) = d.next_if(|(other, _)| other == hash) {
+            copied += (*count).min(*other);
+        }
+    }
+    copied
+}
+
+// How alike two files are, out of MAX_SCORE. Pairs whose sizes alone rule out reaching the
+// minimum are not read.
+fn estimate_similarity(source: &mut Spec, destination: &mut Spec, minimum: usize) -> anyhow::Result<usize> {
+    if !source.is_regular() || !destination.is_regular() {
+        return Ok(0);
+    }
+    let source_size = source.size()?;
+    let destination_size = destination.size()?;
+    let max_size = source_size.max(destination_size);
+    let delta_size = max_size - source_size.min(destination_size);
+    if max_size * (MAX_SCORE - minimum) < delta_size * MAX_SCORE {
+        return Ok(0);
+    }
+    let copied = copied_bytes(source.spans()?, destination.spans()?);
+    if destination_size == 0 {
+        return Ok(0);
+    }
+    Ok(copied * MAX_SCORE / max_size)
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+// Whether two paths end in the same file name
+fn basename_same(source: &str, destination: &str) -> bool {
+    basename(source) == basename(destination)
+}
+
+struct Source {
+    spec: Spec,
+    // Index of the change in the queue it came from
+    queue_index: usize,
+    unchanged: bool,
+}
+
+struct Destination {
+    spec: Spec,
+    queue_index: usize,
+    // The queue index of the source it was paired with, and their score
+    pair: Option<(usize, usize)>,
+}
+
+// A score for pairing a destination with a source, ordered best first with unused ones last
+#[derive(Clone, Copy)]
+struct Candidate {
+    destination: Option<usize>,
+    source: usize,
+    score: usize,
+    name_score: bool,
+}
+
+fn candidate_order(a: &Candidate, b: &Candidate) -> std::cmp::Ordering {
+    match (a.destination, b.destination) {
+        (None, None) => std::cmp::Ordering::Equal,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (Some(_), None) => std::cmp::Ordering::Less,
+        _ => b.score.cmp(&a.score).then(b.name_score.cmp(&a.name_score)),
+    }
+}
+
+// Search is one run of detection over a queue of changes
+struct Search<'a> {
+    settings: &'a Renames,
+    minimum: usize,
+    sources: Vec<Source>,
+    destinations: Vec<Destination>,
+    // How many pairs use each source, by queue index; kept files count themselves once
+    used: HashMap<usize, usize>,
+}
+
+impl<'a> Search<'a> {
+    fn new(settings: &'a Renames, queue: &[FileChange]) -> Search<'a> {
+        let copies = settings.detect == Detect::Copies;
+        let empty_blob = |hash: &str| hash == EMPTY_BLOB_HASH;
+        let mut search = Search {
+            settings,
+            minimum: if settings.score == 0 { DEFAULT_SCORE } else { settings.score },
+            sources: Vec::new(),
+            destinations: Vec::new(),
+            used: HashMap::new(),
+        };
+        for (index, change) in queue.iter().enumerate() {
+            match change.kind {
+                ChangeKind::Unmerged => {}
+                ChangeKind::Added => {
+                    if settings.only_destination.as_ref().is_some_and(|path| *path != change.new_path)
+                        || (!settings.empty && empty_blob(&change.new_hash))
+                    {
+                        continue;
+                    }
+                    let spec = Spec::new(&change.new_path, change.new_mode, &change.new_hash);
+                    search.destinations.push(Destination { spec, queue_index: index, pair: None });
+                }
+                _ if !settings.empty && empty_blob(&change.old_hash) => {}
+                ChangeKind::Deleted => {
+                    let spec = Spec::new(&change.old_path, change.old_mode, &change.old_hash);
+                    search.sources.push(Source { spec, queue_index: index, unchanged: false });
+                }
+                _ if copies => {
+                    // A file kept in place is a user of itself
+                    search.used.insert(index, 1);
+                    let unchanged = change.old_hash == change.new_hash && change.old_mode == change.new_mode;
+                    let spec = Spec::new(&change.old_path, change.old_mode, &change.old_hash);
+                    search.sources.push(Source { spec, queue_index: index, unchanged });
+                }
+                _ => {}
+            }
+        }
+        search
+    }
+
+    fn uses(&self, source: usize) -> usize {
+        self.used.get(&self.sources[source].queue_index).copied().unwrap_or(0)
+    }
+
+    fn record(&mut self, destination: usize, source: usize, score: usize) {
+        *self.used.entry(self.sources[source].queue_index).or_default() += 1;
+        self.destinations[destination].pair = Some((self.sources[source].queue_index, score));
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        let copies = self.settings.detect == Detect::Copies;
+        self.find_exact()?;
+        if self.minimum == MAX_SCORE {
+            return Ok(());
+        }
+        if !copies {
+            self.remove_used_sources();
+            self.find_basename_matches()?;
+            self.remove_used_sources();
+        }
+
+        let remaining = self.destinations.iter().filter(|x| x.pair.is_none()).count();
+        if remaining == 0 || self.sources.is_empty() {
+            return Ok(());
+        }
+        let mut skip_unchanged = false;
+        let limit = self.settings.limit;
+        if limit > 0 && remaining * self.sources.len() > limit * limit {
+            let needed = remaining.max(self.sources.len());
+            self.settings.needed_limit.set(self.settings.needed_limit.get().max(needed));
+            let changed_sources = self.sources.iter().filter(|x| !x.unchanged).count();
+            if !self.settings.copies_harder || remaining * changed_sources > limit * limit {
+                return Ok(());
+            }
+            self.settings.degraded.set(true);
+            skip_unchanged = true;
+        }
+
+        let mut matrix = Vec::new();
+        for destination in 0..self.destinations.len() {
+            if self.destinations[destination].pair.is_some() {
+                continue;
+            }
+            let mut best = [Candidate { destination: None, source: 0, score: 0, name_score: false }; CANDIDATES];
+            for source in 0..self.sources.len() {
+                if skip_unchanged && self.sources[source].unchanged {
+                    continue;
+                }
+                let score = estimate_similarity(&mut self.sources[source].spec, &mut self.destinations[destination].spec, self.minimum)?;
+                let name_score = basename_same(&self.sources[source].spec.path, &self.destinations[destination].spec.path);
+                let candidate = Candidate { destination: Some(destination), source, score, name_score };
+                let mut worst = 0;
+                for slot in 1..CANDIDATES {
+                    if candidate_order(&best[slot], &best[worst]).is_gt() {
+                        worst = slot;
+                    }
+                }
+                if candidate_order(&best[worst], &candidate).is_gt() {
+                    best[worst] = candidate;
+                }
+                // Contents are only compared once per pair, so drop them for memory's sake
+                self.sources[source].spec.data = None;
+            }
+            self.destinations[destination].spec.data = None;
+            self.destinations[destination].spec.spans = None;
+            matrix.extend(best);
+        }
+        matrix.sort_by(candidate_order);
+        self.find_renames(&matrix, false);
+        if copies {
+            self.find_renames(&matrix, true);
+        }
+        Ok(())
+    }
+
+    // Pair destinations with sources that have exactly the same contents, preferring sources
+    // nobody used yet and then ones with the same file name
+    fn find_exact(&mut self) -> anyhow::Result<()> {
+        let copies = self.settings.detect == Detect::Copies;
+        let mut by_hash: HashMap<String, Vec<usize>> = HashMap::new();
+        for source in 0..self.sources.len() {
+            let hash = self.sources[source].spec.object_id()?;
+            by_hash.entry(hash).or_default().push(source);
+        }
+        for destination in 0..self.destinations.len() {
+            // Files on disk keep the id worked out for them, which then shows in the output
+            let hash = self.destinations[destination].spec.object_id()?;
+            self.destinations[destination].spec.hash = hash.clone();
+            let Some(sources) = by_hash.get(&hash) else {
+                continue;
+            };
+            let target = &self.destinations[destination].spec;
+            let mut best: Option<(usize, usize)> = None;
+            let mut tries = 100;
+            for &source in sources {
+                let spec = &self.sources[source].spec;
+                // Symlinks and the like only match their own kind
+                if (!spec.is_regular() || !target.is_regular()) && spec.mode != target.mode {
+                    continue;
+                }
+                let used = self.uses(source);
+                if used > 0 && !copies {
+                    continue;
+                }
+                let score = (used == 0) as usize + basename_same(&spec.path, &target.path) as usize;
+                if best.is_none_or(|(_, best_score)| score > best_score) {
+                    best = Some((source, score));
+                    if score == 2 {
+                        break;
+                    }
+                }
+                tries -= 1;
+                if tries == 0 {
+                    break;
+                }
+            }
+            if let Some((source, _)) = best {
+                self.record(destination, source, MAX_SCORE);
+            }
+        }
+        Ok(())
+    }
+
+    // Sources already paired cannot be renamed again
+    fn remove_used_sources(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.sources.retain(|source| used.get(&source.queue_index).copied().unwrap_or(0) == 0);
+        self.used = used;
+    }
+
+    // Pair files whose name is unique among both the sources and destinations left, as long
+    // as they are more alike than the usual minimum asks for
+    fn find_basename_matches(&mut self) -> anyhow::Result<()> {
+        let minimum = self.minimum + (MAX_SCORE - self.minimum) / 2;
+        let mut sources: HashMap<&str, Option<usize>> = HashMap::new();
+        for (index, source) in self.sources.iter().enumerate() {
+            let name = basename(&source.spec.path);
+            sources.entry(name).and_modify(|x| *x = None).or_insert(Some(index));
+        }
+        let mut destinations: HashMap<&str, Option<usize>> = HashMap::new();
+        for (index, destination) in self.destinations.iter().enumerate().filter(|(_, x)| x.pair.is_none()) {
+            let name = basename(&destination.spec.path);
+            destinations.entry(name).and_modify(|x| *x = None).or_insert(Some(index));
+        }
+        let mut pairs = Vec::new();
+        for (name, source) in &sources {
+            if let (Some(source), Some(Some(destination))) = (source, destinations.get(name)) {
+                pairs.push((*source, *destination));
+            }
+        }
+        pairs.sort_unstable();
+        for (source, destination) in pairs {
+            let score = estimate_similarity(&mut self.sources[source].spec, &mut self.destinations[destination].spec, minimum)?;
+            if score >= minimum {
+                self.record(destination, source, score);
+            }
+        }
+        Ok(())
+    }
+
+    // Take the best remaining pairs in order, each destination once. Sources are used once
+    // unless copies are allowed.
+    fn find_renames(&mut self, matrix: &[Candidate], copies: bool) {
+        for candidate in matrix {
+            let Some(destination) = candidate.destination else {
+                break;
+            };
+            if candidate.score < self.minimum {
+                break;
+            }
+            if self.destinations[destination].pair.is_some() || (!copies && self.uses(candidate.source) > 0) {
+                continue;
+            }
+            self.record(destination, candidate.source, candidate.score);
+        }
+    }
+
+    // Rebuild the queue: paired additions become renames or copies, deleted sources that were
+    // used disappear along with unchanged files. The last pair taking a deleted file is its
+    // rename; every other one is a copy.
+    fn write_back(self, queue: Vec<FileChange>) -> Vec<FileChange> {
+        let mut pairs: HashMap<usize, (usize, usize)> = HashMap::new();
+        let mut hashes: HashMap<usize, &str> = HashMap::new();
+        for destination in &self.destinations {
+            if let Some(pair) = destination.pair {
+                pairs.insert(destination.queue_index, pair);
+            }
+            hashes.insert(destination.queue_index, &destination.spec.hash);
+        }
+        let kept: Vec<usize> = queue
+            .iter()
+            .enumerate()
+            .filter(|(index, change)| match change.kind {
+                ChangeKind::Added | ChangeKind::Unmerged => true,
+                ChangeKind::Deleted => self.used.get(index).copied().unwrap_or(0) == 0,
+                _ => change.old_hash != change.new_hash || change.old_mode != change.new_mode,
+            })
+            .map(|(index, _)| index)
+            .collect();
+        let mut used = self.used;
+        let mut output = Vec::new();
+        for index in kept {
+            let mut change = queue[index].clone();
+            if let Some(hash) = hashes.get(&index) {
+                change.new_hash = hash.to_string();
+            }
+            let Some(&(source, score)) = pairs.get(&index) else {
+                output.push(change);
+                continue;
+            };
+            let count = used.get_mut(&source).unwrap();
+            *count -= 1;
+            let source = &queue[source];
+            output.push(FileChange {
+                kind: if *count > 0 { ChangeKind::Copied } else { ChangeKind::Renamed },
+                old_path: source.old_path.clone(),
+                old_mode: source.old_mode,
+                old_hash: source.old_hash.clone(),
+                score: (score * 100 / MAX_SCORE) as u32,
+                ..change
+            });
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{repository, tree};
+
+    fn numbers(range: std::ops::RangeInclusive<usize>) -> String {
+        range.map(|x| format!("{}\n", x)).collect()
+    }
+
+    // The name-status lines of a diff between two trees, as -M or -C print them
+    fn name_status(renames: &Renames) -> Vec<String> {
+        let new_txt = format!("{}ten\n", numbers(1..=9));
+        let src = format!("{}x\n", numbers(100..=119));
+        let old = tree(&[("exact", "same\n"), ("keep", &numbers(200..=220)), ("old.txt", &numbers(1..=10)), ("src", &numbers(100..=120))]);
+        let new = tree(&[
+            ("copy", &numbers(100..=118)),
+            ("copy2", &numbers(200..=220)),
+            ("keep", &numbers(200..=220)),
+            ("moved", "same\n"),
+            ("new.txt", &new_txt),
+            ("src", &src),
+        ]);
+        let changes = renames.diff_trees(&TreeDiff { recursive: true, ..TreeDiff::default() }, Some(&old), Some(&new)).unwrap();
+        changes
+            .iter()
+            .map(|x| match x.is_rename() {
+                true => format!("{}{:03} {} {}", x.kind.letter(), x.score, x.old_path, x.new_path),
+                false => format!("{} {}", x.kind.letter(), x.new_path),
+            })
+            .collect()
+    }
+
+    fn renames(options: &[&str]) -> Renames {
+        let mut renames = Renames::new(&read_config_file(".git/config"));
+        for option in options {
+            assert!(renames.parse_option(option).unwrap());
+        }
+        renames.validate();
+        renames
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(parse_score("", "find-renames"), 0);
+        assert_eq!(parse_score("50", "find-renames"), MAX_SCORE / 2);
+        assert_eq!(parse_score("90%", "find-renames"), MAX_SCORE * 9 / 10);
+        assert_eq!(parse_score("0.75", "find-renames"), MAX_SCORE * 3 / 4);
+        // Digits alone are a fraction, so 100 is a tenth, as in git
+        assert_eq!(parse_score("100", "find-renames"), MAX_SCORE / 10);
+        assert_eq!(parse_score("100%", "find-renames"), MAX_SCORE);
+    }
+
+    #[test]
+    fn options() {
+        let renames = renames(&["-C", "-C"]);
+        assert_eq!(renames.detect, Detect::Copies);
+        assert!(renames.wants_unchanged());
+        let renames = self::renames(&["--find-renames=60%", "-l5"]);
+        assert_eq!((renames.detect, renames.score, renames.limit), (Detect::Renames, MAX_SCORE * 3 / 5, 5));
+        assert_eq!(self::renames(&["-M", "--no-renames"]).detect, Detect::Off);
+        assert_eq!(Detect::parse("Copies"), Detect::Copies);
+        assert_eq!(Detect::parse("false"), Detect::Off);
+    }
+
+    #[test]
+    fn similarity() {
+        // Chunks end at newlines, so files sharing most lines share most bytes
+        let (old, new) = (numbers(1..=10), format!("{}ten\n", numbers(1..=9)));
+        let copied = copied_bytes(&hash_chars(old.as_bytes()), &hash_chars(new.as_bytes()));
+        assert_eq!(copied, 18);
+        // Text ignores the carriage return of CRLF
+        assert_eq!(hash_chars(b"a\r\nb\r\n"), hash_chars(b"a\nb\n"));
+    }
+
+    #[test]
+    fn renames_and_copies() {
+        let _repository = repository();
+        assert_eq!(name_status(&renames(&["-M"])), ["A copy", "A copy2", "R100 exact moved", "R081 old.txt new.txt", "M src"]);
+        assert_eq!(name_status(&renames(&["-C"])), ["C090 src copy", "A copy2", "R100 exact moved", "R081 old.txt new.txt", "M src"]);
+        assert_eq!(name_status(&renames(&["-C", "-C"])), ["C090 src copy", "C100 keep copy2", "R100 exact moved", "R081 old.txt new.txt", "M src"]);
+        assert_eq!(name_status(&renames(&["-M90%"])), ["A copy", "A copy2", "R100 exact moved", "A new.txt", "D old.txt", "M src"]);
+        assert_eq!(name_status(&renames(&[])), ["A copy", "A copy2", "D exact", "A moved", "A new.txt", "D old.txt", "M src"]);
+    }
+}
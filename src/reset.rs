@@ -0,0 +1,443 @@
+// The reset porcelain: move HEAD, or the branch it is on, to another commit and bring the index
+// and working tree along as far as the mode asks, or copy paths from a tree into the index.
+// Working tree updates follow git's unpack-trees rules, refusing to drop local changes that the
+// merge and keep modes promise to preserve.
+
+use crate::checkout::{entry_matches, has_untracked_in_the_way, short_description, tree_entries};
+use crate::config::read_config;
+use crate::diff::{diff_index_to_worktree, ChangeKind};
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::merge::update_head;
+use crate::refs::{delete_ref, read_ref, resolve_ref, update_ref};
+use crate::revision::{ambiguous_argument, peel_to_commit, peel_to_tree, resolve_revision};
+use crate::sequencer::remove_branch_state;
+use crate::worktree::{checkout_file, pathspec_matches, remove_worktree_file, worktree_matches_index, TreeEntry};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+// How far a reset reaches: HEAD alone, the index as well, or the working tree too
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    Soft,
+    Mixed,
+    Hard,
+    Merge,
+    Keep,
+}
+
+impl ResetMode {
+    fn name(&self) -> &'static str {
+        match self {
+            ResetMode::Soft => "soft",
+            ResetMode::Mixed => "mixed",
+            ResetMode::Hard => "hard",
+            ResetMode::Merge => "merge",
+            ResetMode::Keep => "keep",
+        }
+    }
+}
+
+fn reset_usage() -> ! {
+    eprintln!("usage: git reset [--mixed | --soft | --hard | --merge | --keep] [-q] [<commit>]");
+    eprintln!("   or: git reset [-q] [<tree-ish>] [--] <pathspec>...");
+    eprintln!();
+    std::process::exit(129);
+}
+
+fn resolves_to_commit(name: &str) -> bool {
+    resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).is_ok()
+}
+
+fn resolves_to_tree(name: &str) -> bool {
+    resolve_revision(name).and_then(|hash| peel_to_tree(&hash)).is_ok()
+}
+
+// Split the arguments into the revision to reset to and the paths to reset. Without "--" a
+// lone argument must be a commit and a leading one of several a tree-ish, and either way it
+// may not also name a file; otherwise the arguments are all paths and the first must exist.
+fn split_arguments(positional: &[String]) -> anyhow::Result<(Option<String>, Vec<String>)> {
+    let paths = |rest: &[String]| rest.iter().filter(|x| *x != "--").cloned().collect();
+    match positional {
+        [] => Ok((None, Vec::new())),
+        [first, rest @ ..] if first == "--" => Ok((None, paths(rest))),
+        [first, second, rest @ ..] if second == "--" => Ok((Some(first.clone()), paths(rest))),
+        [first, rest @ ..] => {
+            let is_revision = if rest.is_empty() { resolves_to_commit(first) } else { resolves_to_tree(first) };
+            if is_revision {
+                if Path::new(first).exists() {
+                    return Err(ambiguous_argument(first, "both revision and filename"));
+                }
+                return Ok((Some(first.clone()), paths(rest)));
+            }
+            if !Path::new(first).exists() {
+                return Err(ambiguous_argument(first, "unknown revision or path not in the working tree."));
+            }
+            Ok((None, paths(positional)))
+        }
+    }
+}
+
+// The index a mixed reset leaves: the target's version of every selected path, with entries
+// that already matched keeping their cached stat data, and unselected paths left alone
+fn reset_index_entries(index_entries: Vec<IndexEntry>, target: &BTreeMap<String, TreeEntry>, pathspecs: &[String]) -> Vec<IndexEntry> {
+    let selected = |path: &str| pathspecs.is_empty() || pathspecs.iter().any(|pathspec| pathspec_matches(pathspec, path));
+    let mut reset = Vec::new();
+    let mut kept: BTreeSet<String> = BTreeSet::new();
+    for entry in index_entries {
+        if !selected(&entry.path) {
+            reset.push(entry);
+        } else if entry.stage() == 0 && entry_matches(Some(&entry), target.get(&entry.path)) {
+            kept.insert(entry.path.clone());
+            reset.push(entry);
+        }
+    }
+    for (path, entry) in target {
+        if selected(path) && !kept.contains(path) {
+            reset.push(IndexEntry::without_stat(path, entry.mode, &entry.sha_hash));
+        }
+    }
+    reset
+}
+
+// Refresh the stat data of the index against the working tree, listing the paths that still
+// have unstaged changes the way git does after a mixed reset
+fn refresh_and_report(index: &mut [IndexEntry], quiet: bool) {
+    let (changes, _) = diff_index_to_worktree(index);
+    let mut lines: BTreeMap<String, char> = changes
+        .into_iter()
+        .map(|change| {
+            let letter = match change.kind {
+                ChangeKind::Deleted => 'D',
+                ChangeKind::TypeChanged => 'T',
+                _ => 'M',
+            };
+            (change.old_path, letter)
+        })
+        .collect();
+    for entry in index.iter().filter(|entry| entry.stage() != 0) {
+        lines.insert(entry.path.clone(), 'U');
+    }
+    if quiet || lines.is_empty() {
+        return;
+    }
+    println!("Unstaged changes after reset:");
+    for (path, letter) in lines {
+        println!("{}\t{}", letter, path);
+    }
+}
+
+// Bring the working tree and index to the target tree for the hard, merge and keep modes,
+// returning the new index entries. Nothing is written until every path has been checked, and
+// the first path that cannot be updated is reported the way git's unpack-trees does.
+fn update_worktree(
+    mode: ResetMode,
+    head: &BTreeMap<String, TreeEntry>,
+    target: &BTreeMap<String, TreeEntry>,
+    index_entries: Vec<IndexEntry>,
+) -> anyhow::Result<Result<Vec<IndexEntry>, String>> {
+    let unmerged: BTreeSet<String> = index_entries
+        .iter()
+        .filter(|entry| entry.stage() != 0)
+        .map(|entry| entry.path.clone())
+        .collect();
+    let index: BTreeMap<String, IndexEntry> = index_entries
+        .into_iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+
+    let mut paths: BTreeSet<&String> = BTreeSet::new();
+    paths.extend(index.keys());
+    paths.extend(unmerged.iter());
+    paths.extend(target.keys());
+    if mode == ResetMode::Keep {
+        paths.extend(head.keys());
+    }
+
+    let mut keep: Vec<IndexEntry> = Vec::new();
+    let mut removals: Vec<&String> = Vec::new();
+    let mut updates: Vec<(&String, &TreeEntry)> = Vec::new();
+
+    for path in paths {
+        let staged = index.get(path);
+        let new = target.get(path);
+        let conflicted = unmerged.contains(path);
+        let up_to_date = staged.map(worktree_matches_index).unwrap_or(true);
+        let unchanged = match mode {
+            ResetMode::Hard => !conflicted && entry_matches(staged, new) && up_to_date,
+            ResetMode::Merge => !conflicted && entry_matches(staged, new),
+            _ => head.get(path) == new || entry_matches(staged, new),
+        };
+        if unchanged {
+            keep.extend(staged.cloned());
+            continue;
+        }
+
+        if mode == ResetMode::Keep && !entry_matches(staged, head.get(path)) {
+            return Ok(Err(format!("Entry '{}' would be overwritten by merge. Cannot merge.", path)));
+        }
+        if mode != ResetMode::Hard && !conflicted {
+            if !up_to_date {
+                return Ok(Err(format!("Entry '{}' not uptodate. Cannot merge.", path)));
+            }
+            if staged.is_none() && new.is_some() && has_untracked_in_the_way(path, &index) {
+                return Ok(Err(format!("Untracked working tree file '{}' would be overwritten by merge.", path)));
+            }
+        }
+        match new {
+            Some(new) => updates.push((path, new)),
+            None => removals.push(path),
+        }
+    }
+
+    // Remove first so a file can replace a directory of the same name and vice versa
+    for path in removals {
+        remove_worktree_file(path)?;
+    }
+    for (path, entry) in updates {
+        keep.push(checkout_file(path, entry.mode, &entry.sha_hash)?);
+    }
+    Ok(Ok(keep))
+}
+
+// Record the old HEAD in ORIG_HEAD and move HEAD, or the branch it is on, to the commit
+fn reset_refs(rev: &str, commit: &str) -> anyhow::Result<()> {
+    match resolve_ref("HEAD") {
+        Some(old_head) => update_ref("ORIG_HEAD", &old_head, "reset: updating ORIG_HEAD")?,
+        None if read_ref("ORIG_HEAD").is_some() => delete_ref("ORIG_HEAD")?,
+        None => {}
+    }
+    update_head(commit, &format!("reset: moving to {}", rev))
+}
+
+// Reset the index, and for the hard, merge and keep modes the working tree, to a tree,
+// touching only the given paths when there are any
+pub fn reset_index_and_worktree(
+    mode: ResetMode,
+    rev: &str,
+    tree: Option<&str>,
+    pathspecs: &[String],
+    quiet: bool,
+    refresh: bool,
+) -> anyhow::Result<()> {
+    let index_entries = read_index()?;
+    if matches!(mode, ResetMode::Soft | ResetMode::Keep)
+        && (read_ref("MERGE_HEAD").is_some() || index_entries.iter().any(|entry| entry.stage() != 0))
+    {
+        anyhow::bail!("Cannot do a {} reset in the middle of a merge.", mode.name());
+    }
+
+    let target = tree_entries(tree)?;
+    match mode {
+        ResetMode::Soft => {}
+        ResetMode::Mixed => {
+            let mut reset = reset_index_entries(index_entries, &target, pathspecs);
+            if refresh {
+                refresh_and_report(&mut reset, quiet);
+            }
+            write_index(&reset)?;
+        }
+        ResetMode::Hard | ResetMode::Merge | ResetMode::Keep => {
+            let head = match resolve_ref("HEAD") {
+                Some(head) if mode == ResetMode::Keep => tree_entries(Some(&peel_to_tree(&head)?))?,
+                None if mode == ResetMode::Keep => anyhow::bail!("You do not have a valid HEAD."),
+                _ => BTreeMap::new(),
+            };
+            let mut reset = match update_worktree(mode, &head, &target, index_entries)? {
+                Ok(reset) => reset,
+                Err(message) => {
+                    eprintln!("error: {}", message);
+                    anyhow::bail!("Could not reset index file to revision '{}'.", rev);
+                }
+            };
+            // Keep leaves the index at the target even for paths whose local changes it kept
+            if mode == ResetMode::Keep {
+                reset = reset_index_entries(reset, &target, &[]);
+            }
+            write_index(&reset)?;
+        }
+    }
+    Ok(())
+}
+
+// Move HEAD to a commit, taking the index and working tree along as far as the mode reaches,
+// and forget any merge, cherry-pick or revert in progress
+pub fn reset_to_commit(mode: ResetMode, rev: &str, quiet: bool, refresh: bool) -> anyhow::Result<()> {
+    let commit = resolve_revision(rev)
+        .and_then(|hash| peel_to_commit(&hash))
+        .map_err(|_| anyhow::anyhow!("Failed to resolve '{}' as a valid revision.", rev))?;
+    reset_index_and_worktree(mode, rev, Some(&peel_to_tree(&commit)?), &[], quiet, refresh)?;
+    reset_refs(rev, &commit)?;
+    if mode == ResetMode::Hard && !quiet {
+        println!("HEAD is now at {}", short_description(&commit));
+    }
+    remove_branch_state();
+    Ok(())
+}
+
+pub fn reset_command(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == "reset").unwrap();
+    let mut mode = None;
+    let mut quiet = config.get_bool("reset.quiet").unwrap_or(false);
+    let mut refresh = true;
+    let mut positional = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "--soft" => mode = Some(ResetMode::Soft),
+            "--mixed" => mode = Some(ResetMode::Mixed),
+            "--hard" => mode = Some(ResetMode::Hard),
+            "--merge" => mode = Some(ResetMode::Merge),
+            "--keep" => mode = Some(ResetMode::Keep),
+            "-q" | "--quiet" => quiet = true,
+            "--no-quiet" => quiet = false,
+            "--refresh" => refresh = true,
+            "--no-refresh" => refresh = false,
+            "-h" => reset_usage(),
+            "--" => {
+                positional.extend(args[index..].iter().cloned());
+                break;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                eprintln!("error: unknown option `{}'", arg.trim_start_matches('-'));
+                reset_usage();
+            }
+            _ => positional.push(args[index].clone()),
+        }
+        index += 1;
+    }
+
+    let (rev, pathspecs) = split_arguments(&positional)?;
+    if !pathspecs.is_empty() {
+        match mode {
+            Some(ResetMode::Mixed) => eprintln!("warning: --mixed with paths is deprecated; use 'git reset -- <paths>' instead."),
+            Some(mode) => anyhow::bail!("Cannot do {} reset with paths.", mode.name()),
+            None => {}
+        }
+    }
+    let mode = mode.unwrap_or(ResetMode::Mixed);
+
+    // Resetting to HEAD on an unborn branch empties the index instead
+    let unborn = rev.is_none() && resolve_ref("HEAD").is_none();
+    let rev = rev.unwrap_or_else(|| "HEAD".to_string());
+    if pathspecs.is_empty() && !unborn {
+        return reset_to_commit(mode, &rev, quiet, refresh);
+    }
+    let tree = match unborn {
+        true => None,
+        false => Some(
+            resolve_revision(&rev)
+                .and_then(|hash| peel_to_tree(&hash))
+                .map_err(|_| anyhow::anyhow!("Failed to resolve '{}' as a valid tree.", rev))?,
+        ),
+    };
+    reset_index_and_worktree(mode, &rev, tree.as_deref(), &pathspecs, quiet, refresh)?;
+    if pathspecs.is_empty() {
+        remove_branch_state();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{commit, repository, tree};
+    use crate::worktree::flatten_tree;
+    use std::fs;
+
+    // Check out the second of two commits on main: it changes a and adds c
+    fn checked_out() -> (String, String) {
+        let first = commit(&tree(&[("a", "1\n"), ("b", "1\n")]), &[], 1, "first");
+        let second_tree = tree(&[("a", "2\n"), ("b", "1\n"), ("c", "new\n")]);
+        let second = commit(&second_tree, &[&first], 2, "second");
+        fs::write(".git/refs/heads/main", format!("{}\n", second)).unwrap();
+        let mut index = Vec::new();
+        for (path, entry) in flatten_tree(&second_tree).unwrap() {
+            index.push(checkout_file(&path, entry.mode, &entry.sha_hash).unwrap());
+        }
+        write_index(&index).unwrap();
+        (first, second)
+    }
+
+    fn staged() -> Vec<(String, String)> {
+        let contents = |hash: &str| String::from_utf8(crate::read_raw_object(hash).unwrap().1).unwrap();
+        read_index().unwrap().iter().map(|entry| (entry.path.clone(), contents(&entry.sha_hash))).collect()
+    }
+
+    fn files(paths: &[&str]) -> Vec<Option<String>> {
+        paths.iter().map(|path| fs::read_to_string(path).ok()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(path, contents)| (path.to_string(), contents.to_string())).collect()
+    }
+
+    #[test]
+    fn soft_and_mixed() {
+        let _repository = repository();
+        let (first, second) = checked_out();
+        reset_to_commit(ResetMode::Soft, &first, true, true).unwrap();
+        assert_eq!(resolve_ref("HEAD").as_deref(), Some(first.as_str()));
+        assert_eq!(resolve_ref("ORIG_HEAD").as_deref(), Some(second.as_str()));
+        assert_eq!(staged(), pairs(&[("a", "2\n"), ("b", "1\n"), ("c", "new\n")]));
+
+        reset_to_commit(ResetMode::Mixed, &first, true, true).unwrap();
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "1\n")]));
+        assert_eq!(files(&["a", "c"]), [Some("2\n".to_string()), Some("new\n".to_string())]);
+    }
+
+    #[test]
+    fn hard() {
+        let _repository = repository();
+        let (first, _) = checked_out();
+        fs::write("b", "local\n").unwrap();
+        reset_to_commit(ResetMode::Hard, &first, true, true).unwrap();
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "1\n")]));
+        assert_eq!(files(&["a", "b", "c"]), [Some("1\n".to_string()), Some("1\n".to_string()), None]);
+    }
+
+    #[test]
+    fn merge_and_keep() {
+        let _repository = repository();
+        let (first, second) = checked_out();
+        // A local change to a file both commits have alike survives
+        fs::write("b", "local\n").unwrap();
+        reset_to_commit(ResetMode::Merge, &first, true, true).unwrap();
+        assert_eq!(files(&["a", "b", "c"]), [Some("1\n".to_string()), Some("local\n".to_string()), None]);
+        reset_to_commit(ResetMode::Keep, &second, true, true).unwrap();
+        assert_eq!(files(&["a", "b", "c"]), [Some("2\n".to_string()), Some("local\n".to_string()), Some("new\n".to_string())]);
+
+        // One to a file the reset changes stops it
+        fs::write("a", "local\n").unwrap();
+        let error = reset_to_commit(ResetMode::Merge, &first, true, true).unwrap_err();
+        assert_eq!(error.to_string(), format!("Could not reset index file to revision '{}'.", first));
+        let entries = read_index().unwrap();
+        let head = tree_entries(Some(&peel_to_tree(&second).unwrap())).unwrap();
+        let target = tree_entries(Some(&peel_to_tree(&first).unwrap())).unwrap();
+        let refused = update_worktree(ResetMode::Merge, &head, &target, entries.clone()).unwrap();
+        assert_eq!(refused.unwrap_err(), "Entry 'a' not uptodate. Cannot merge.");
+        let refused = update_worktree(ResetMode::Keep, &head, &target, entries).unwrap();
+        assert_eq!(refused.unwrap_err(), "Entry 'a' not uptodate. Cannot merge.");
+        assert_eq!(resolve_ref("HEAD").as_deref(), Some(second.as_str()));
+    }
+
+    #[test]
+    fn paths() {
+        let _repository = repository();
+        let (first, _) = checked_out();
+        let tree = peel_to_tree(&first).unwrap();
+        reset_index_and_worktree(ResetMode::Mixed, &first, Some(&tree), &["a".to_string(), "c".to_string()], true, true).unwrap();
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "1\n")]));
+        assert_eq!(files(&["a"]), [Some("2\n".to_string())]);
+
+        assert_eq!(split_arguments(&[]).unwrap(), (None, vec![]));
+        assert_eq!(split_arguments(&["HEAD".to_string()]).unwrap(), (Some("HEAD".to_string()), vec![]));
+        assert_eq!(split_arguments(&["a".to_string(), "b".to_string()]).unwrap(), (None, vec!["a".to_string(), "b".to_string()]));
+        let arguments = ["HEAD".to_string(), "--".to_string(), "a".to_string()];
+        assert_eq!(split_arguments(&arguments).unwrap(), (Some("HEAD".to_string()), vec!["a".to_string()]));
+        assert!(split_arguments(&["missing".to_string()]).is_err());
+    }
+}
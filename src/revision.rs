@@ -0,0 +1,400 @@
+use crate::pack::packs;
+use crate::date::{format_date, parse_date, DateFormat};
+use crate::diff::NULL_HASH;
+use crate::index::read_index;
+use crate::reflog::{read_reflog, ReflogEntry};
+use crate::refs::{head_symbolic_target, read_ref, resolve_ref};
+use crate::{read_object_from_store, read_raw_object, GitObjectType};
+use std::collections::HashSet;
+use std::fs;
+
+// Every existing ref a short name could stand for, in git's lookup order
+pub fn matching_ref_names(name: &str) -> Vec<String> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ];
+    candidates
+        .into_iter()
+        .filter(|candidate| (candidate == "HEAD" || candidate.starts_with("refs/")) && read_ref(candidate).is_some())
+        .collect()
+}
+
+// Expand a branch, tag or other short ref name using git's lookup order
+pub fn expand_ref_name(name: &str) -> Option<String> {
+    matching_ref_names(name).into_iter().next()
+}
+
+// The shortest name a full ref can be given that no ref earlier in git's lookup order
+// (or, when strict, any other ref) could be mistaken for. The ref itself need not exist,
+// and names are never shortened to a remote through its HEAD.
+pub fn shorten_unambiguous_ref(refname: &str, strict: bool) -> String {
+    let rules = [("", ""), ("refs/", ""), ("refs/tags/", ""), ("refs/heads/", ""), ("refs/remotes/", ""), ("refs/remotes/", "/HEAD")];
+    for rule in (1..rules.len() - 1).rev() {
+        let Some(short) = refname.strip_prefix(rules[rule].0).filter(|short| !short.is_empty()) else {
+            continue;
+        };
+        let checked = if strict { rules.len() } else { rule };
+        let ambiguous = (0..checked)
+            .filter(|&other| other != rule)
+            .any(|other| read_ref(&format!("{}{}{}", rules[other].0, short, rules[other].1)).is_some());
+        if !ambiguous {
+            return short.to_string();
+        }
+    }
+    refname.to_string()
+}
+
+// Every stored object whose id starts with the given hex prefix
+pub fn objects_with_prefix(prefix: &str) -> Vec<String> {
+    let mut matches = Vec::new();
+    let directory = format!(".git/objects/{}", &prefix[..2]);
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let hash = format!("{}{}", &prefix[..2], entry.file_name().to_string_lossy());
+            if hash.starts_with(prefix) && hash.len() == 40 {
+                matches.push(hash);
+            }
+        }
+    }
+    for pack in packs() {
+        let start = pack.ids.partition_point(|id| id.as_str() < prefix);
+        for id in pack.ids[start..].iter().take_while(|id| id.starts_with(prefix)) {
+            matches.push(id.clone());
+        }
+    }
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+// Resolve "@{-<n>}", the branch or commit checked out n switches ago, from the checkouts HEAD's
+// log records
+fn resolve_previous_checkout(spec: &str, count: usize) -> anyhow::Result<String> {
+    let previous = read_reflog("HEAD")
+        .into_iter()
+        .rev()
+        .filter_map(|entry| {
+            let moved = entry.message.strip_prefix("checkout: moving from ")?;
+            moved.split_once(" to ").map(|(from, _)| from.to_string())
+        })
+        .nth(count - 1);
+    match previous {
+        Some(previous) => resolve_revision(&previous),
+        None => Err(ambiguous_argument(spec, "unknown revision or path not in the working tree.")),
+    }
+}
+
+// The value a log entry left its ref with, or the one it had before for an entry recording
+// the ref going away
+fn logged_value(entry: &ReflogEntry) -> String {
+    if entry.new_hash == NULL_HASH { entry.old_hash.clone() } else { entry.new_hash.clone() }
+}
+
+// Resolve "<ref>@{<n>}", the value a ref had n updates ago, or "<ref>@{<date>}", the value it
+// had at a moment, from the ref's log. An empty ref stands for the current branch.
+fn resolve_reflog_selector(spec: &str, ref_name: &str, selector: &str) -> anyhow::Result<String> {
+    let unknown = || ambiguous_argument(spec, "unknown revision or path not in the working tree.");
+    if let Some(count) = selector.strip_prefix('-').filter(|_| ref_name.is_empty()) {
+        let count: usize = count.parse().ok().filter(|&count| count > 0).ok_or_else(unknown)?;
+        return resolve_previous_checkout(spec, count);
+    }
+    let (full_name, display_name) = if ref_name.is_empty() {
+        let full_name = head_symbolic_target().unwrap_or_else(|| "HEAD".to_string());
+        let display_name = full_name.strip_prefix("refs/heads/").unwrap_or(&full_name).to_string();
+        (full_name, display_name)
+    } else {
+        let full_name = matching_ref_names(ref_name)
+            .into_iter()
+            .find(|name| std::path::Path::new(&format!(".git/logs/{}", name)).is_file())
+            .ok_or_else(unknown)?;
+        (full_name, ref_name.to_string())
+    };
+    let entries = read_reflog(&full_name);
+    let Some(oldest) = entries.first() else {
+        return Err(unknown());
+    };
+
+    if let Ok(count) = selector.parse::<usize>() {
+        if count < entries.len() {
+            return Ok(logged_value(&entries[entries.len() - 1 - count]));
+        }
+        if count == entries.len() && oldest.old_hash != NULL_HASH {
+            return Ok(oldest.old_hash.clone());
+        }
+        anyhow::bail!("log for '{}' only has {} entries", display_name, entries.len());
+    }
+    let time = parse_date(selector).ok_or_else(unknown)?;
+    if let Some(entry) = entries.iter().rev().find(|entry| entry.time <= time) {
+        return Ok(logged_value(entry));
+    }
+    eprintln!("warning: log for '{}' only goes back to {}", display_name, format_date(oldest.time, &oldest.offset, &DateFormat::Rfc));
+    Ok(if oldest.old_hash != NULL_HASH { oldest.old_hash.clone() } else { oldest.new_hash.clone() })
+}
+
+fn resolve_base(name: &str) -> anyhow::Result<String> {
+    if let Some((ref_name, selector)) = name.strip_suffix('}').and_then(|name| name.split_once("@{")) {
+        return resolve_reflog_selector(name, ref_name, selector);
+    }
+    let name = if name == "@" { "HEAD" } else { name };
+    let is_hex = name.bytes().all(|x| x.is_ascii_hexdigit());
+    if name.len() == 40 && is_hex {
+        return Ok(name.to_lowercase());
+    }
+    if let Some(hash) = expand_ref_name(name).and_then(|full_name| resolve_ref(&full_name)) {
+        return Ok(hash);
+    }
+    if name.len() >= 4 && name.len() < 40 && is_hex {
+        let matches = objects_with_prefix(&name.to_lowercase());
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches[0].clone()),
+            _ => anyhow::bail!("short object ID {} is ambiguous", name),
+        }
+    }
+    anyhow::bail!("ambiguous argument '{}': unknown revision or path not in the working tree.", name)
+}
+
+// Type of a stored object, such as "commit" or "tree"
+pub fn object_kind(hash: &str) -> anyhow::Result<String> {
+    Ok(String::from_utf8_lossy(&read_raw_object(hash)?.0).into_owned())
+}
+
+// Follow annotated tags until reaching an object of another type
+pub fn peel_tags(hash: &str) -> anyhow::Result<String> {
+    let mut hash = hash.to_string();
+    while let GitObjectType::Tag(tag) = read_object_from_store(&hash)? {
+        hash = tag
+            .target_hash()
+            .ok_or_else(|| anyhow::anyhow!("tag {} has no object line", hash))?
+            .to_string();
+    }
+    Ok(hash)
+}
+
+pub fn peel_to_commit(hash: &str) -> anyhow::Result<String> {
+    let peeled = peel_tags(hash)?;
+    if object_kind(&peeled)? != "commit" {
+        anyhow::bail!("{} is not a commit", hash);
+    }
+    Ok(peeled)
+}
+
+pub fn peel_to_tree(hash: &str) -> anyhow::Result<String> {
+    let peeled = peel_tags(hash)?;
+    match read_object_from_store(&peeled)? {
+        GitObjectType::Tree(_) => Ok(peeled),
+        GitObjectType::Commit(commit) => commit
+            .tree_hash()
+            .map(|tree| tree.to_string())
+            .ok_or_else(|| anyhow::anyhow!("commit {} has no tree", peeled)),
+        _ => anyhow::bail!("{} is not a tree-ish", hash),
+    }
+}
+
+// The error for a command line argument that is neither a revision nor a path
+pub fn ambiguous_argument(arg: &str, reason: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "ambiguous argument '{}': {}\nUse '--' to separate paths from revisions, like this:\n'git <command> [<revision>...] -- [<file>...]'",
+        arg,
+        reason
+    )
+}
+
+// Parents of a commit, in the order they are recorded
+pub fn commit_parents(hash: &str) -> anyhow::Result<Vec<String>> {
+    match read_object_from_store(hash)? {
+        GitObjectType::Commit(commit) => Ok(commit.parent_hashes().into_iter().map(|x| x.to_string()).collect()),
+        _ => anyhow::bail!("{} is not a commit", hash),
+    }
+}
+
+fn read_number(bytes: &[u8], index: &mut usize) -> Option<usize> {
+    let start = *index;
+    while *index < bytes.len() && bytes[*index].is_ascii_digit() {
+        *index += 1;
+    }
+    std::str::from_utf8(&bytes[start..*index]).ok()?.parse().ok()
+}
+
+// The object at a path below a tree, the tree itself for an empty path
+fn tree_entry_at(tree: &str, path: &str) -> Option<String> {
+    let mut hash = tree.to_string();
+    for name in path.split('/').filter(|x| !x.is_empty()) {
+        let GitObjectType::Tree(tree) = read_object_from_store(&hash).ok()? else {
+            return None;
+        };
+        hash = tree.leaves.into_iter().find(|leaf| leaf.path == name)?.sha_hash;
+    }
+    Some(hash)
+}
+
+// Resolve ":<path>" or ":<stage>:<path>", a file as staged in the index
+fn resolve_index_path(spec: &str) -> anyhow::Result<String> {
+    let (stage, path) = match spec.as_bytes() {
+        [stage @ b'0'..=b'3', b':', ..] => (u16::from(stage - b'0'), &spec[2..]),
+        _ => (0, spec),
+    };
+    let entries = read_index()?;
+    if let Some(entry) = entries.iter().find(|entry| entry.path == path && entry.stage() == stage) {
+        return Ok(entry.sha_hash.clone());
+    }
+    if entries.iter().any(|entry| entry.path == path) {
+        anyhow::bail!("path '{}' is in the index, but not at stage {}", path, stage);
+    }
+    if std::path::Path::new(path).exists() {
+        anyhow::bail!("path '{}' exists on disk, but not in the index", path);
+    }
+    anyhow::bail!("path '{}' does not exist (neither on disk nor in the index)", path)
+}
+
+// Resolve a revision such as "main", "HEAD~2", "v1.0^{tree}" or an abbreviated id to a full object id.
+// "<rev>:<path>" names a file or directory in the revision's tree and ":<path>" one in the index.
+pub fn resolve_revision(spec: &str) -> anyhow::Result<String> {
+    if let Some(path) = spec.strip_prefix(':') {
+        return resolve_index_path(path);
+    }
+    // The colon of a reflog date such as "main@{12:00}" does not start a path
+    let braces_end = spec.rfind('}').map_or(0, |x| x + 1);
+    if let Some(colon) = spec[braces_end..].find(':').map(|x| x + braces_end) {
+        let (revision, path) = (&spec[..colon], &spec[colon + 1..]);
+        let tree = peel_to_tree(&resolve_revision(revision)?)?;
+        return tree_entry_at(&tree, path)
+            .ok_or_else(|| anyhow::anyhow!("path '{}' does not exist in '{}'", path, revision));
+    }
+    let base_end = spec.find(['^', '~']).unwrap_or(spec.len());
+    let mut hash = resolve_base(&spec[..base_end])?;
+
+    let bytes = spec.as_bytes();
+    let mut index = base_end;
+    while index < bytes.len() {
+        let operator = bytes[index];
+        index += 1;
+        if operator == b'^' && bytes.get(index) == Some(&b'{') {
+            let close = spec[index..]
+                .find('}')
+                .map(|position| index + position)
+                .ok_or_else(|| anyhow::anyhow!("invalid revision '{}'", spec))?;
+            hash = match &spec[index + 1..close] {
+                "" => peel_tags(&hash)?,
+                "commit" => peel_to_commit(&hash)?,
+                "tree" => peel_to_tree(&hash)?,
+                "object" => hash,
+                kind => {
+                    let peeled = peel_tags(&hash)?;
+                    if object_kind(&peeled)? != kind {
+                        anyhow::bail!("{} cannot be peeled to a {}", spec, kind);
+                    }
+                    peeled
+                }
+            };
+            index = close + 1;
+            continue;
+        }
+
+        let count = read_number(bytes, &mut index);
+        hash = peel_to_commit(&hash)?;
+        if operator == b'^' {
+            match count.unwrap_or(1) {
+                0 => {}
+                parent => {
+                    hash = commit_parents(&hash)?
+                        .get(parent - 1)
+                        .cloned()
+                        .ok_or_else(|| anyhow::anyhow!("revision '{}' does not exist", spec))?;
+                }
+            }
+        } else {
+            for _ in 0..count.unwrap_or(1) {
+                hash = commit_parents(&hash)?
+                    .first()
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("revision '{}' does not exist", spec))?;
+            }
+        }
+    }
+    Ok(hash)
+}
+
+// Every commit reachable from the given one, including itself
+pub fn reachable_commits(hash: &str) -> anyhow::Result<HashSet<String>> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![hash.to_string()];
+    while let Some(hash) = pending.pop() {
+        if seen.insert(hash.clone()) {
+            pending.extend(commit_parents(&hash)?);
+        }
+    }
+    Ok(seen)
+}
+
+// How many commits each side has that the other does not, as (ahead, behind)
+pub fn count_ahead_behind(ours: &str, theirs: &str) -> anyhow::Result<(usize, usize)> {
+    let ours = reachable_commits(ours)?;
+    let theirs = reachable_commits(theirs)?;
+    Ok((ours.difference(&theirs).count(), theirs.difference(&ours).count()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{write_index, IndexEntry};
+    use crate::testrepo::{blob, commit, repository, tree};
+    use crate::worktree::{write_tree, TreeEntry};
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    fn staged(path: &str, stage: u16, contents: &str) -> IndexEntry {
+        let mut entry = IndexEntry::without_stat(path, 0o100644, &blob(contents));
+        entry.flags = stage << 12;
+        entry
+    }
+
+    #[test]
+    fn tree_paths() {
+        let _repository = repository();
+        let dir = tree(&[("b", "b\n")]);
+        let entries = BTreeMap::from([
+            ("a".to_string(), TreeEntry { mode: 0o100644, sha_hash: blob("a\n") }),
+            ("dir/b".to_string(), TreeEntry { mode: 0o100644, sha_hash: blob("b\n") }),
+        ]);
+        let root = write_tree(&entries);
+        let head = commit(&root, &[], 1, "first");
+        fs::write(".git/refs/heads/main", format!("{}\n", head)).unwrap();
+        fs::create_dir_all(".git/logs/refs/heads").unwrap();
+        fs::write(".git/logs/refs/heads/main", format!("{} {} A U Thor <author@example.com> 1 +0000\tcommit\n", NULL_HASH, head)).unwrap();
+
+        assert_eq!(resolve_revision("main:a").unwrap(), blob("a\n"));
+        assert_eq!(resolve_revision("HEAD:dir/b").unwrap(), blob("b\n"));
+        assert_eq!(resolve_revision("main:dir").unwrap(), dir);
+        assert_eq!(resolve_revision("main:dir/").unwrap(), dir);
+        assert_eq!(resolve_revision("main:").unwrap(), root);
+        assert_eq!(resolve_revision(&format!("{}:a", &head[..7])).unwrap(), blob("a\n"));
+        // The path starts after any braces, which may hold a colon of their own
+        assert_eq!(resolve_revision("main@{0}:a").unwrap(), blob("a\n"));
+        assert_eq!(resolve_revision("main:missing").unwrap_err().to_string(), "path 'missing' does not exist in 'main'");
+        assert_eq!(resolve_revision("main:a/b").unwrap_err().to_string(), "path 'a/b' does not exist in 'main'");
+        assert!(resolve_revision("nothing:a").is_err());
+    }
+
+    #[test]
+    fn index_paths() {
+        let _repository = repository();
+        write_index(&[staged("a", 0, "a\n"), staged("c", 1, "base\n"), staged("c", 2, "ours\n"), staged("c", 3, "theirs\n")]).unwrap();
+        fs::write("untracked", "").unwrap();
+
+        assert_eq!(resolve_revision(":a").unwrap(), blob("a\n"));
+        assert_eq!(resolve_revision(":0:a").unwrap(), blob("a\n"));
+        assert_eq!(resolve_revision(":2:c").unwrap(), blob("ours\n"));
+        assert_eq!(resolve_revision(":3:c").unwrap(), blob("theirs\n"));
+        let error = |spec: &str| resolve_revision(spec).unwrap_err().to_string();
+        assert_eq!(error(":c"), "path 'c' is in the index, but not at stage 0");
+        assert_eq!(error(":1:a"), "path 'a' is in the index, but not at stage 1");
+        assert_eq!(error(":untracked"), "path 'untracked' exists on disk, but not in the index");
+        assert_eq!(error(":missing"), "path 'missing' does not exist (neither on disk nor in the index)");
+    }
+}
@@ -0,0 +1,618 @@
+use crate::config::read_config;
+use crate::log::parse_revisions;
+use crate::patch::patch_id;
+use crate::refs::{list_refs, resolve_ref};
+use crate::revision::{object_kind, peel_to_commit, resolve_revision};
+use crate::walk::{Walk, WalkOptions};
+use crate::{read_object_from_store, read_raw_object, GitObjectType};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+
+// ObjectFilter leaves objects out of --objects output the way --filter asks
+#[derive(Clone, Copy)]
+enum ObjectFilter {
+    BlobNone,
+    BlobLimit(usize),
+    TreeDepth(usize),
+}
+
+impl ObjectFilter {
+    fn parse(spec: &str) -> anyhow::Result<ObjectFilter> {
+        if spec == "blob:none" {
+            return Ok(ObjectFilter::BlobNone);
+        }
+        if let Some(limit) = spec.strip_prefix("blob:limit=") {
+            let (digits, unit) = match limit.char_indices().last() {
+                Some((index, unit)) if unit.is_ascii_alphabetic() => (&limit[..index], unit.to_ascii_lowercase()),
+                _ => (limit, ' '),
+            };
+            let scale = match unit {
+                ' ' => 1,
+                'k' => 1 << 10,
+                'm' => 1 << 20,
+                'g' => 1 << 30,
+                _ => anyhow::bail!("invalid filter-spec '{}'", spec),
+            };
+            let limit: usize = digits.parse().map_err(|_| anyhow::anyhow!("invalid filter-spec '{}'", spec))?;
+            return Ok(ObjectFilter::BlobLimit(limit * scale));
+        }
+        if let Some(depth) = spec.strip_prefix("tree:") {
+            return Ok(ObjectFilter::TreeDepth(depth.parse().map_err(|_| anyhow::anyhow!("expected 'tree:<depth>'"))?));
+        }
+        anyhow::bail!("invalid filter-spec '{}'", spec)
+    }
+}
+
+// Missing is what to do on reaching an object that is not in the repository
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Missing {
+    Error,
+    AllowAny,
+    Print,
+}
+
+// Pending is an object named on the command line to be listed after the commits. Trees and
+// blobs are listed under the path they were named by, tags under their own name.
+struct Pending {
+    hash: String,
+    kind: String,
+    name: String,
+}
+
+// Objects lists the trees and blobs reachable from the commits, skipping those reachable
+// from the excluded commits next to them and those the filter leaves out
+struct Objects {
+    filter: Option<ObjectFilter>,
+    missing: Missing,
+    count_only: bool,
+    excluded: HashSet<String>,
+    seen: HashSet<String>,
+    // Objects the filter applies to: those reached from commits rather than named directly
+    filtered: HashSet<String>,
+    // The shallowest depth each tree was reached at, for tree:<depth>
+    depths: HashMap<String, usize>,
+    missing_found: BTreeSet<String>,
+    count: usize,
+    // Set once the reader goes away, as when piped into head
+    closed: bool,
+}
+
+impl Objects {
+    // Leave out a tree and everything in it
+    fn exclude_tree(&mut self, hash: &str) {
+        if !self.excluded.insert(hash.to_string()) {
+            return;
+        }
+        let Ok(GitObjectType::Tree(tree)) = read_object_from_store(hash) else {
+            return;
+        };
+        for leaf in tree.leaves {
+            match leaf.mode.as_slice() {
+                b"040000" => self.exclude_tree(&leaf.sha_hash),
+                b"160000" => {}
+                _ => {
+                    self.excluded.insert(leaf.sha_hash);
+                }
+            }
+        }
+    }
+
+    fn show(&mut self, out: &mut impl Write, hash: &str, name: &str) -> anyhow::Result<()> {
+        self.count += 1;
+        if !self.count_only && writeln!(out, "{} {}", hash, name.split('\n').next().unwrap_or("")).is_err() {
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    // Record an object that could not be read, or stop when missing objects are errors
+    fn missing_object(&mut self, hash: &str, kind: &str) -> anyhow::Result<()> {
+        match self.missing {
+            Missing::Error => anyhow::bail!("missing {} object '{}'", kind, hash),
+            Missing::AllowAny => {}
+            Missing::Print => {
+                self.missing_found.insert(hash.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn tag(&mut self, out: &mut impl Write, hash: &str, name: &str) -> anyhow::Result<()> {
+        if self.closed || self.excluded.contains(hash) || !self.seen.insert(hash.to_string()) {
+            return Ok(());
+        }
+        self.show(out, hash, name)
+    }
+
+    // List a tree before what is in it. Trees only named on the command line are not filtered
+    // and do not count towards the depth of what is in them.
+    fn tree(&mut self, out: &mut impl Write, hash: &str, path: &str, depth: usize) -> anyhow::Result<()> {
+        if self.closed || self.excluded.contains(hash) || self.seen.contains(hash) {
+            return Ok(());
+        }
+        let tree = match read_object_from_store(hash) {
+            Ok(GitObjectType::Tree(tree)) => Some(tree),
+            _ if self.missing == Missing::Error => anyhow::bail!("bad tree object {}", hash),
+            _ => None,
+        };
+        let (shown, descend, child_depth) = match self.filter.filter(|_| self.filtered.contains(hash)) {
+            Some(ObjectFilter::TreeDepth(limit)) => {
+                if self.depths.get(hash).is_some_and(|&seen_at| seen_at <= depth) {
+                    return Ok(());
+                }
+                self.depths.insert(hash.to_string(), depth);
+                (depth < limit, depth < limit, depth + 1)
+            }
+            _ => {
+                self.seen.insert(hash.to_string());
+                (true, true, depth)
+            }
+        };
+        if shown {
+            match tree {
+                Some(_) => self.show(out, hash, path)?,
+                None => self.missing_object(hash, "tree")?,
+            }
+        }
+        let Some(tree) = tree.filter(|_| descend) else {
+            return Ok(());
+        };
+        for leaf in tree.leaves {
+            let leaf_path = if path.is_empty() { leaf.path.clone() } else { format!("{}/{}", path, leaf.path) };
+            match leaf.mode.as_slice() {
+                b"160000" => continue,
+                b"040000" => {
+                    self.filtered.insert(leaf.sha_hash.clone());
+                    self.tree(out, &leaf.sha_hash, &leaf_path, child_depth)?;
+                }
+                _ => {
+                    self.filtered.insert(leaf.sha_hash.clone());
+                    self.blob(out, &leaf.sha_hash, &leaf_path, child_depth)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blob(&mut self, out: &mut impl Write, hash: &str, path: &str, depth: usize) -> anyhow::Result<()> {
+        if self.closed || self.excluded.contains(hash) || self.seen.contains(hash) {
+            return Ok(());
+        }
+        let contents = read_raw_object(hash).ok().map(|(_, contents)| contents);
+        let shown = match self.filter.filter(|_| self.filtered.contains(hash)) {
+            None => true,
+            Some(ObjectFilter::BlobNone) => false,
+            // A blob that cannot be read is kept so that it is reported as missing
+            Some(ObjectFilter::BlobLimit(limit)) => contents.as_ref().is_none_or(|contents| contents.len() < limit),
+            // Blobs too deep are not marked as seen, in case they turn up again higher up
+            Some(ObjectFilter::TreeDepth(limit)) => {
+                if depth >= limit {
+                    return Ok(());
+                }
+                true
+            }
+        };
+        self.seen.insert(hash.to_string());
+        if !shown {
+            return Ok(());
+        }
+        match contents {
+            Some(_) => self.show(out, hash, path),
+            None => self.missing_object(hash, "blob"),
+        }
+    }
+}
+
+// The objects named by revision arguments that the commit walk does not cover: tags, and
+// the trees and blobs they or the arguments themselves point at
+fn pending_objects(args: &[String]) -> anyhow::Result<Vec<Pending>> {
+    let mut named = Vec::new();
+    let mut negate = false;
+    for arg in args.iter().take_while(|x| *x != "--") {
+        match arg.as_str() {
+            "--not" => negate = !negate,
+            "--all" | "--branches" | "--tags" | "--remotes" if !negate => {
+                let prefix = match arg.as_str() {
+                    "--branches" => "refs/heads/",
+                    "--tags" => "refs/tags/",
+                    "--remotes" => "refs/remotes/",
+                    _ => "refs/",
+                };
+                named.extend(list_refs().into_iter().filter(|(name, _)| name.starts_with(prefix)).map(|(_, hash)| (String::new(), hash)));
+                if arg == "--all" {
+                    named.extend(resolve_ref("HEAD").map(|hash| (String::new(), hash)));
+                }
+            }
+            _ if negate || arg.starts_with(['-', '^']) => {}
+            _ => {
+                let ends = match (arg.split_once("..."), arg.split_once("..")) {
+                    (Some((left, right)), _) => vec![left, right],
+                    (None, Some((_, right))) => vec![right],
+                    _ => vec![arg.as_str()],
+                };
+                for end in ends {
+                    let end = if end.is_empty() { "HEAD" } else { end };
+                    if let Ok(hash) = resolve_revision(end) {
+                        let path = end.split_once(':').map(|x| x.1).unwrap_or("");
+                        named.push((path.to_string(), hash));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pending = Vec::new();
+    for (mut path, mut hash) in named {
+        let mut kind = object_kind(&hash)?;
+        while kind == "tag" {
+            let (_, contents) = read_raw_object(&hash)?;
+            let contents = String::from_utf8_lossy(&contents).into_owned();
+            let header = |name: &str| contents.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')).unwrap_or("").to_string();
+            pending.push(Pending { hash: hash.clone(), kind, name: header("tag") });
+            hash = header("object");
+            kind = object_kind(&hash)?;
+            path.clear();
+        }
+        if kind != "commit" {
+            pending.push(Pending { hash, kind, name: path });
+        }
+    }
+    Ok(pending)
+}
+
+// The boundary is the parents of shown commits that are not shown themselves. Like git,
+// collect them as their children are shown and put the latest found first, then order them
+// so that a child comes before its parent
+fn boundary_commits(walk: &mut Walk, shown: &[String], dropped: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+    let taken: HashSet<&String> = shown.iter().chain(dropped).collect();
+    let mut commits: Vec<String> = Vec::new();
+    for hash in shown {
+        for parent in walk.rewritten_parents(hash) {
+            if !taken.contains(parent) && !commits.contains(parent) {
+                commits.push(parent.clone());
+            }
+        }
+    }
+    commits.reverse();
+    boundary_order(walk, commits)
+}
+
+// Put boundary commits in git's graph order: in the given order, except that a commit waits
+// until every boundary child of it has been listed. As in git, only commits the walk read
+// have parents to go by.
+fn boundary_order(walk: &mut Walk, commits: Vec<String>) -> anyhow::Result<Vec<String>> {
+    let mut parents: HashMap<&str, Vec<String>> = HashMap::new();
+    for hash in &commits {
+        let known = if walk.is_uninteresting(hash) { walk.commit(hash)?.parents.clone() } else { walk.rewritten_parents(hash).to_vec() };
+        parents.insert(hash, known);
+    }
+    let mut children: HashMap<&str, usize> = commits.iter().map(|hash| (hash.as_str(), 0)).collect();
+    for parent in parents.values().flatten() {
+        if let Some(count) = children.get_mut(parent.as_str()) {
+            *count += 1;
+        }
+    }
+    let mut stack: Vec<&str> = commits.iter().rev().map(|hash| hash.as_str()).filter(|hash| children[hash] == 0).collect();
+    let mut ordered = Vec::new();
+    while let Some(hash) = stack.pop() {
+        for parent in &parents[hash] {
+            if let Some(count) = children.get_mut(parent.as_str()) {
+                *count -= 1;
+                if *count == 0 {
+                    stack.push(parent);
+                }
+            }
+        }
+        ordered.push(hash.to_string());
+    }
+    Ok(ordered)
+}
+
+fn rev_list_usage() -> ! {
+    eprintln!("usage: git rev-list [<options>] <commit>... [--] [<path>...]");
+    std::process::exit(129);
+}
+
+// List commits reachable from some commits but not others, optionally with the trees and
+// blobs they need, or just count them
+pub fn rev_list_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "rev-list").unwrap();
+    let config = read_config();
+    let mut options = WalkOptions::default();
+    let mut count = false;
+    let mut objects = false;
+    let mut left_right = false;
+    let mut cherry_pick = false;
+    let mut boundary = false;
+    let mut object_filter = None;
+    let mut missing = None;
+
+    let mut arguments = args[command_index + 1..].iter();
+    while let Some(arg) = arguments.next() {
+        if options.parse_option(arg, &mut arguments)? {
+            continue;
+        }
+        if let Some(spec) = arg.strip_prefix("--filter=") {
+            object_filter = Some(ObjectFilter::parse(spec)?);
+        } else if let Some(action) = arg.strip_prefix("--missing=") {
+            // The first action git knows wins, later ones and unknown ones are ignored
+            if missing.is_none() {
+                missing = match action {
+                    "error" | "allow-promisor" => Some(Missing::Error),
+                    "allow-any" => Some(Missing::AllowAny),
+                    "print" => Some(Missing::Print),
+                    _ => None,
+                };
+            }
+        } else {
+            match arg.as_str() {
+                "--count" => count = true,
+                "--objects" => objects = true,
+                "--left-right" => left_right = true,
+                "--cherry-pick" => cherry_pick = true,
+                "--boundary" => boundary = true,
+                _ if arg.starts_with('-') && arg != "-" => rev_list_usage(),
+                _ => options.revision_args.push(arg.clone()),
+            }
+        }
+    }
+    let WalkOptions { mut walk, filter, max_count, skip, reverse, revision_args } = options;
+    if revision_args.first().is_none_or(|x| x == "--") {
+        rev_list_usage();
+    }
+    if object_filter.is_some() && !objects {
+        anyhow::bail!("object filtering requires --objects");
+    }
+    if count && objects && left_right {
+        anyhow::bail!("marked counting and '--objects' cannot be used together");
+    }
+
+    // Trees and blobs named directly have no history, so they are kept out of the walk
+    let pending = if objects { pending_objects(&revision_args)? } else { Vec::new() };
+    let is_object = |arg: &String| !arg.starts_with(['-', '^']) && !arg.contains("..") && resolve_revision(arg).is_ok_and(|x| peel_to_commit(&x).is_err());
+    let separator = revision_args.iter().position(|x| x == "--").unwrap_or(revision_args.len());
+    let (revision_part, paths) = revision_args.split_at(separator);
+    let commit_args: Vec<String> = revision_part.iter().filter(|arg| !is_object(arg)).cloned().collect();
+    let mut left_tips = Vec::new();
+    if !commit_args.is_empty() {
+        let revisions = parse_revisions(&[commit_args, paths.to_vec()].concat())?;
+        walk.include = revisions.include;
+        walk.exclude = revisions.exclude;
+        walk.paths = revisions.paths;
+        left_tips = revisions.left;
+    }
+
+    let list = walk.run()?;
+    let listed: HashSet<&String> = list.iter().collect();
+    let mut interesting = Vec::new();
+    for hash in &list {
+        if !walk.is_hidden(hash) && filter.matches(&*walk.commit(hash)?) {
+            interesting.push(hash.clone());
+        }
+    }
+
+    // Commits on the left of a symmetric difference, and the excluded commits next to them
+    let mut left = HashSet::new();
+    let mut stack = left_tips;
+    while let Some(hash) = stack.pop() {
+        if left.insert(hash.clone()) && listed.contains(&hash) {
+            stack.extend(walk.parents(&hash).iter().cloned());
+        }
+    }
+
+    // --cherry-pick drops commits making the same change as one on the other side. The
+    // changes of the side with fewer commits are the ones looked up.
+    let mut dropped = HashSet::new();
+    if cherry_pick {
+        let left_count = interesting.iter().filter(|hash| left.contains(*hash)).count();
+        let right_count = interesting.len() - left_count;
+        if left_count > 0 && right_count > 0 {
+            let left_first = left_count < right_count;
+            let mut ids: HashMap<String, String> = HashMap::new();
+            for hash in interesting.iter().filter(|hash| left.contains(*hash) == left_first) {
+                let commit = walk.commit(hash)?;
+                if commit.parents.len() < 2 {
+                    ids.entry(patch_id(&config, &commit)?).or_insert_with(|| hash.clone());
+                }
+            }
+            for hash in interesting.iter().filter(|hash| left.contains(*hash) != left_first) {
+                let commit = walk.commit(hash)?;
+                if commit.parents.len() > 1 {
+                    continue;
+                }
+                if let Some(other) = ids.get(&patch_id(&config, &commit)?) {
+                    dropped.insert(other.clone());
+                    dropped.insert(hash.clone());
+                }
+            }
+        }
+    }
+    let mut shown: Vec<String> = interesting.into_iter().filter(|hash| !dropped.contains(hash)).skip(skip).collect();
+    if let Some(max_count) = max_count {
+        shown.truncate(max_count);
+    }
+
+    let mut output: Vec<(String, bool)> = shown.iter().map(|hash| (hash.clone(), false)).collect();
+    if boundary {
+        output.extend(boundary_commits(&mut walk, &shown, &dropped)?.into_iter().map(|hash| (hash, true)));
+    }
+    if reverse {
+        output.reverse();
+    }
+
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let mut objects_list = Objects {
+        filter: object_filter,
+        missing: missing.unwrap_or(Missing::Error),
+        count_only: count,
+        excluded: HashSet::new(),
+        seen: HashSet::new(),
+        filtered: HashSet::new(),
+        depths: HashMap::new(),
+        missing_found: BTreeSet::new(),
+        count: 0,
+        closed: false,
+    };
+    let (mut left_count, mut right_count) = (0, 0);
+    for (hash, is_boundary) in &output {
+        if left.contains(hash) {
+            left_count += 1;
+        } else {
+            right_count += 1;
+        }
+        if count {
+            continue;
+        }
+        let mark = match (is_boundary, left_right) {
+            (true, _) => "-",
+            (false, true) if left.contains(hash) => "<",
+            (false, true) => ">",
+            (false, false) => "",
+        };
+        if writeln!(out, "{}{}", mark, hash).is_err() {
+            return Ok(());
+        }
+    }
+
+    if objects {
+        // What the excluded parents of the walked commits have is not listed
+        for hash in &list {
+            for parent in walk.commit(hash)?.parents.clone() {
+                if walk.is_uninteresting(&parent) {
+                    let tree = walk.commit(&parent)?.tree.clone();
+                    objects_list.exclude_tree(&tree);
+                }
+            }
+        }
+        // The trees of the listed commits are filtered even when also named directly
+        let mut trees = Vec::new();
+        for (hash, _) in &output {
+            trees.push(walk.commit(hash)?.tree.clone());
+        }
+        objects_list.filtered.extend(trees.iter().cloned());
+        for object in &pending {
+            match object.kind.as_str() {
+                "tag" => objects_list.tag(&mut out, &object.hash, &object.name)?,
+                "tree" => objects_list.tree(&mut out, &object.hash, &object.name, 0)?,
+                _ => objects_list.blob(&mut out, &object.hash, &object.name, 0)?,
+            }
+        }
+        for tree in &trees {
+            objects_list.tree(&mut out, tree, "", 0)?;
+        }
+        for hash in &objects_list.missing_found {
+            let _ = writeln!(out, "?{}", hash);
+        }
+    }
+
+    if count {
+        match left_right {
+            true => writeln!(out, "{}\t{}", left_count, right_count)?,
+            false => writeln!(out, "{}", output.len() + objects_list.count)?,
+        }
+    }
+    let _ = out.flush();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testrepo::{commit, repository, tree};
+
+    // A criss-cross: b2 and c2 each merge b1 and c1, with b3, c3 and d on top
+    fn history() -> HashMap<String, String> {
+        let empty = tree(&[]);
+        let mut names: HashMap<&str, String> = HashMap::new();
+        let commits: [(&str, &[&str]); 8] = [
+            ("a", &[]),
+            ("b1", &["a"]),
+            ("c1", &["a"]),
+            ("b2", &["b1", "c1"]),
+            ("c2", &["c1", "b1"]),
+            ("b3", &["b2"]),
+            ("c3", &["c2"]),
+            ("d", &["a"]),
+        ];
+        for (time, (name, parents)) in commits.iter().enumerate() {
+            let parents: Vec<&str> = parents.iter().map(|parent| names[parent].as_str()).collect();
+            names.insert(name, commit(&empty, &parents, time as i64 + 1, name));
+        }
+        names.into_iter().map(|(name, hash)| (hash, name.to_string())).collect()
+    }
+
+    // The commits rev-list --boundary lists for revisions given by name, boundary ones marked
+    fn boundary(names: &HashMap<String, String>, query: &str) -> String {
+        let hash = |name: &str| names.iter().find(|(_, x)| *x == name).unwrap().0.clone();
+        let args: Vec<String> = query
+            .split(' ')
+            .map(|arg| match (arg.strip_prefix('^'), arg.split_once("...")) {
+                (Some(name), _) => format!("^{}", hash(name)),
+                (_, Some((left, right))) => format!("{}...{}", hash(left), hash(right)),
+                _ => hash(arg),
+            })
+            .collect();
+        let revisions = parse_revisions(&args).unwrap();
+        let mut walk = Walk::default();
+        walk.include = revisions.include;
+        walk.exclude = revisions.exclude;
+        let shown = walk.run().unwrap();
+        let edge = boundary_commits(&mut walk, &shown, &HashSet::new()).unwrap();
+        let mut listed: Vec<String> = shown.iter().map(|hash| names[hash].clone()).collect();
+        listed.extend(edge.iter().map(|hash| format!("-{}", names[hash])));
+        listed.join(" ")
+    }
+
+    #[test]
+    fn boundaries() {
+        let _repository = repository();
+        let names = history();
+        assert_eq!(boundary(&names, "b3 ^c2"), "b3 b2 -c1 -b1");
+        assert_eq!(boundary(&names, "b3 ^b1 ^c1"), "b3 b2 -c1 -b1");
+        assert_eq!(boundary(&names, "b3 c3 ^b1"), "c3 b3 c2 b2 c1 -b1 -a");
+        assert_eq!(boundary(&names, "b3 c3 ^a"), "c3 b3 c2 b2 c1 b1 -a");
+        assert_eq!(boundary(&names, "b3 ^d"), "b3 b2 c1 b1 -a");
+        assert_eq!(boundary(&names, "c3 d ^b2"), "d c3 c2 -b1 -c1 -a");
+        assert_eq!(boundary(&names, "c3...b3"), "c3 b3 c2 b2 -b1 -c1");
+    }
+
+    // The paths rev-list --objects lists for a tree with the filter given
+    fn listed(filter: Option<&str>) -> Vec<String> {
+        let big = "0".repeat(100);
+        let root = tree(&[("a", "a\n"), ("big", &big), ("dir/b", "b\n"), ("dir/same", "a\n"), ("dir/sub/c", "c\n")]);
+        let mut objects = Objects {
+            filter: filter.map(|spec| ObjectFilter::parse(spec).unwrap()),
+            missing: Missing::Error,
+            count_only: false,
+            excluded: HashSet::new(),
+            seen: HashSet::new(),
+            filtered: HashSet::from([root.clone()]),
+            depths: HashMap::new(),
+            missing_found: BTreeSet::new(),
+            count: 0,
+            closed: false,
+        };
+        let mut out = Vec::new();
+        objects.tree(&mut out, &root, "", 0).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|line| line[41..].to_string()).collect()
+    }
+
+    #[test]
+    fn objects() {
+        let _repository = repository();
+        assert_eq!(listed(None), ["", "a", "big", "dir", "dir/b", "dir/sub", "dir/sub/c"]);
+        assert_eq!(listed(Some("blob:none")), ["", "dir", "dir/sub"]);
+        assert_eq!(listed(Some("blob:limit=50")), ["", "a", "dir", "dir/b", "dir/sub", "dir/sub/c"]);
+        assert_eq!(listed(Some("blob:limit=1k")), ["", "a", "big", "dir", "dir/b", "dir/sub", "dir/sub/c"]);
+        assert!(listed(Some("tree:0")).is_empty());
+        assert_eq!(listed(Some("tree:1")), [""]);
+        assert_eq!(listed(Some("tree:2")), ["", "a", "big", "dir"]);
+        assert_eq!(listed(Some("tree:3")), ["", "a", "big", "dir", "dir/b", "dir/sub"]);
+
+        let error = |spec: &str| ObjectFilter::parse(spec).err().unwrap().to_string();
+        assert_eq!(error("blob:limit=1x"), "invalid filter-spec 'blob:limit=1x'");
+        assert_eq!(error("tree:x"), "expected 'tree:<depth>'");
+        assert_eq!(error("sparse"), "invalid filter-spec 'sparse'");
+    }
+}
@@ -0,0 +1,860 @@
+// Cherry-pick and revert. Each commit is replayed by a three-way merge of HEAD with the change
+// the commit made (or the reverse of it). A single commit is replayed directly; several commits
+// are driven through a sequencer under .git/sequencer that records what is left to do, so that
+// a sequence stopped by a conflict can be continued, skipped or aborted later.
+
+use crate::checkout::{head_tree, switch_tree, CheckoutConflict};
+use crate::config::{read_config, read_config_file, Config};
+use crate::date::{format_date, DateFormat};
+use crate::diff::{diff_tree_to_index, TreeDiff};
+use crate::ident::{signature, Role};
+use crate::index::read_index;
+use crate::merge::{apply_merge, cleanup_message, merge_trees, remove_merge_state, unmerged_paths, update_head, MergeOptions};
+use crate::patch::DiffOptions;
+use crate::refs::{head_symbolic_target, read_ref, resolve_ref, shorten_ref_name};
+use crate::rename::{Detect, Renames};
+use crate::reset::{reset_to_commit, ResetMode};
+use crate::revision::{peel_to_commit, peel_to_tree, resolve_revision};
+use crate::status::print_long_status;
+use crate::walk::{abbreviate, load_commit, Commit, Walk};
+use crate::worktree::{flatten_tree, write_tree, TreeEntry};
+use crate::write_commit_as;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const SEQUENCER_DIR: &str = ".git/sequencer";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Pick,
+    Revert,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Pick => "cherry-pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    // The word naming the action in the todo list
+    fn command(self) -> &'static str {
+        match self {
+            Action::Pick => "pick",
+            Action::Revert => "revert",
+        }
+    }
+
+    fn from_command(word: &str) -> Option<Action> {
+        match word {
+            "pick" | "p" => Some(Action::Pick),
+            "revert" => Some(Action::Revert),
+            _ => None,
+        }
+    }
+
+    // The pseudo ref naming the commit whose replay stopped
+    fn head_ref(self) -> &'static str {
+        match self {
+            Action::Pick => "CHERRY_PICK_HEAD",
+            Action::Revert => "REVERT_HEAD",
+        }
+    }
+}
+
+// ReplayOptions are the choices a sequence was started with, kept in .git/sequencer/opts so
+// that continuing it later replays the remaining commits the same way
+#[derive(Clone, Default)]
+struct ReplayOptions {
+    no_commit: bool,
+    allow_empty: bool,
+    keep_redundant_commits: bool,
+    record_origin: bool,
+    allow_ff: bool,
+    mainline: Option<usize>,
+    strategy_options: Vec<String>,
+}
+
+impl ReplayOptions {
+    fn save(&self) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        for (name, set) in [
+            ("no-commit", self.no_commit),
+            ("allow-empty", self.allow_empty),
+            ("keep-redundant-commits", self.keep_redundant_commits),
+            ("record-origin", self.record_origin),
+            ("allow-ff", self.allow_ff),
+        ] {
+            if set {
+                lines.push(format!("\t{} = true", name));
+            }
+        }
+        if let Some(mainline) = self.mainline {
+            lines.push(format!("\tmainline = {}", mainline));
+        }
+        lines.extend(self.strategy_options.iter().map(|option| format!("\tstrategy-option = {}", option)));
+        if !lines.is_empty() {
+            fs::write(sequencer_path("opts"), format!("[options]\n{}\n", lines.join("\n")))?;
+        }
+        Ok(())
+    }
+
+    fn load() -> ReplayOptions {
+        let config = read_config_file(&sequencer_path("opts"));
+        let flag = |name: &str| config.get_bool(&format!("options.{}", name)).unwrap_or(false);
+        ReplayOptions {
+            no_commit: flag("no-commit"),
+            allow_empty: flag("allow-empty"),
+            keep_redundant_commits: flag("keep-redundant-commits"),
+            record_origin: flag("record-origin"),
+            allow_ff: flag("allow-ff"),
+            mainline: config.get("options.mainline").and_then(|x| x.parse().ok()),
+            strategy_options: config.get_all("options.strategy-option").into_iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+// Step is one line of the todo list: a commit and whether to pick or revert it
+struct Step {
+    action: Action,
+    commit: String,
+}
+
+fn sequencer_path(name: &str) -> String {
+    format!("{}/{}", SEQUENCER_DIR, name)
+}
+
+fn write_todo(steps: &[Step]) -> anyhow::Result<()> {
+    let mut todo = String::new();
+    for step in steps {
+        let commit = load_commit(&step.commit)?;
+        todo.push_str(&format!("{} {} {}\n", step.action.command(), abbreviate(&commit.hash), first_line(&commit)));
+    }
+    fs::write(sequencer_path("todo"), todo)?;
+    Ok(())
+}
+
+fn read_todo() -> anyhow::Result<Vec<Step>> {
+    let todo = fs::read_to_string(sequencer_path("todo")).map_err(|_| anyhow::anyhow!("could not read '{}'", sequencer_path("todo")))?;
+    let mut steps = Vec::new();
+    for line in todo.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let mut words = line.split_whitespace();
+        let action = words.next().and_then(Action::from_command);
+        let commit = words.next().and_then(|name| resolve_revision(name).ok()).and_then(|hash| peel_to_commit(&hash).ok());
+        match (action, commit) {
+            (Some(action), Some(commit)) => steps.push(Step { action, commit }),
+            _ => anyhow::bail!("invalid line {}: {}", steps.len() + 1, line),
+        }
+    }
+    Ok(steps)
+}
+
+// The command of the next step of the sequence in progress, if there is one
+pub fn sequence_command() -> Option<&'static str> {
+    let todo = fs::read_to_string(sequencer_path("todo")).ok()?;
+    let word = todo.split_whitespace().next()?;
+    Action::from_command(word).map(|action| action.command())
+}
+
+fn sequence_action() -> Option<Action> {
+    sequence_command().and_then(Action::from_command)
+}
+
+fn remove_sequencer_state() {
+    let _ = fs::remove_dir_all(SEQUENCER_DIR);
+}
+
+// Record the commit HEAD is at after a step, so that a later abort or skip can tell whether the
+// user has moved HEAD since
+fn update_abort_safety() -> anyhow::Result<()> {
+    if Path::new(SEQUENCER_DIR).is_dir() {
+        fs::write(sequencer_path("abort-safety"), format!("{}\n", resolve_ref("HEAD").unwrap_or_default()))?;
+    }
+    Ok(())
+}
+
+fn rollback_is_safe() -> bool {
+    let expected = fs::read_to_string(sequencer_path("abort-safety")).map(|x| x.trim().to_string()).unwrap_or_default();
+    resolve_ref("HEAD").unwrap_or_default() == expected
+}
+
+// Forget a merge, cherry-pick or revert in progress, as a reset or checkout does. A sequence of
+// picks is dropped too when the commit being given up on was the last one in it.
+pub fn remove_branch_state() {
+    let replaying = ["CHERRY_PICK_HEAD", "REVERT_HEAD"].iter().any(|name| read_ref(name).is_some());
+    for name in ["CHERRY_PICK_HEAD", "REVERT_HEAD"] {
+        let _ = fs::remove_file(format!(".git/{}", name));
+    }
+    if replaying {
+        let remaining = fs::read_to_string(sequencer_path("todo")).map(|todo| todo.lines().count());
+        if matches!(remaining, Ok(count) if count <= 1) {
+            remove_sequencer_state();
+        }
+    }
+    remove_merge_state();
+}
+
+// Report a step that can't be carried out; the command then fails as a whole
+fn failed(action: Action, message: &str) -> anyhow::Error {
+    eprintln!("error: {}", message);
+    anyhow::anyhow!("{} failed", action.name())
+}
+
+pub fn first_line(commit: &Commit) -> &str {
+    commit.message.trim_start_matches('\n').lines().next().unwrap_or("")
+}
+
+pub fn index_tree() -> anyhow::Result<String> {
+    let entries: BTreeMap<String, TreeEntry> = read_index()?
+        .into_iter()
+        .filter(|entry| entry.stage() == 0)
+        .map(|entry| (entry.path, TreeEntry { mode: entry.mode, sha_hash: entry.sha_hash }))
+        .collect();
+    Ok(write_tree(&entries))
+}
+
+pub fn index_matches_head() -> anyhow::Result<bool> {
+    let head = match head_tree()? {
+        Some(tree) => flatten_tree(&tree)?,
+        None => BTreeMap::new(),
+    };
+    Ok(diff_tree_to_index(&head, &read_index()?).is_empty())
+}
+
+// Whether a message ends in a block of trailers, which a "cherry picked from" line joins
+// rather than starting a paragraph of its own
+fn has_trailer_block(message: &str) -> bool {
+    let message = message.trim_end();
+    let Some((_, last)) = message.rsplit_once("\n\n") else {
+        return false;
+    };
+    last.lines().all(|line| {
+        line.starts_with("(cherry picked from commit ")
+            || line.split_once(": ").map(|(token, _)| !token.is_empty() && token.chars().all(|x| x.is_ascii_alphanumeric() || x == '-')).unwrap_or(false)
+    })
+}
+
+// Print the "[branch abbrev] subject" line of a commit just made, who wrote it when that is not
+// the committer, and the stat of what it changed unless it is a merge
+pub fn print_commit_summary(config: &Config, hash: &str, show_date: bool) -> anyhow::Result<()> {
+    let commit = load_commit(hash)?;
+    let branch = head_symbolic_target().map(|x| shorten_ref_name(&x).to_string()).unwrap_or_else(|| "detached HEAD".to_string());
+    let root = if commit.parents.is_empty() { " (root-commit)" } else { "" };
+    println!("[{}{} {}] {}", branch, root, abbreviate(hash), commit.subject());
+    if commit.author.identity() != commit.committer.identity() {
+        println!(" Author: {}", commit.author.identity());
+    }
+    if show_date {
+        println!(" Date: {}", format_date(commit.author.time, &commit.author.offset, &DateFormat::Default));
+    }
+    if commit.parents.len() > 1 {
+        return Ok(());
+    }
+    let mut options = DiffOptions::new(config)?;
+    options.shortstat = true;
+    options.summary = true;
+    options.renames = Renames::new(config);
+    options.renames.detect = Detect::Renames;
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(peel_to_tree(parent)?),
+        None => None,
+    };
+    let tree_diff = TreeDiff { recursive: true, ..Default::default() };
+    let changes = options.renames.diff_trees(&tree_diff, parent_tree.as_deref(), Some(&commit.tree))?;
+    std::io::stdout().write_all(&options.render(&changes)?)?;
+    Ok(())
+}
+
+// Tell the user a picked commit became empty, leaving the pick in progress for them to decide
+fn report_empty_pick(config: &Config) -> anyhow::Result<()> {
+    eprintln!("The previous cherry-pick is now empty, possibly due to conflict resolution.");
+    eprintln!("If you wish to commit it anyway, use:");
+    eprintln!();
+    eprintln!("    git commit --allow-empty");
+    eprintln!();
+    eprintln!("Otherwise, please use 'git cherry-pick --skip'");
+    print_long_status(config)
+}
+
+// Report local changes that kept the index and working tree from being updated as the step
+// failing
+fn check_update(action: Action, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Err(error) => match error.downcast_ref::<CheckoutConflict>() {
+            Some(conflict) => Err(failed(action, &conflict.to_string())),
+            None => Err(error),
+        },
+        Ok(()) => Ok(()),
+    }
+}
+
+// Replay one commit on top of HEAD. Returns false when the replay stopped for the user to
+// resolve conflicts or decide about an empty commit.
+fn replay_commit(config: &Config, step: &Step, options: &ReplayOptions) -> anyhow::Result<bool> {
+    let action = step.action;
+    let commit = load_commit(&step.commit)?;
+    let label = format!("{} ({})", abbreviate(&commit.hash), first_line(&commit));
+
+    if !unmerged_paths()?.is_empty() {
+        let verb = if action == Action::Pick { "Cherry-picking" } else { "Reverting" };
+        eprintln!("error: {} is not possible because you have unmerged files.", verb);
+        eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+        eprintln!("hint: as appropriate to mark resolution and make a commit.");
+        anyhow::bail!("{} failed", action.name());
+    }
+    let head = resolve_ref("HEAD");
+    if head.is_none() && action == Action::Revert {
+        return Err(failed(action, "can't revert as initial commit"));
+    }
+    let empty_tree = write_tree(&BTreeMap::new());
+    let head_tree = match &head {
+        Some(head) => peel_to_tree(head)?,
+        None => empty_tree.clone(),
+    };
+    if !options.no_commit && !index_matches_head()? {
+        eprintln!("error: your local changes would be overwritten by {}.", action.name());
+        eprintln!("hint: commit your changes or stash them to proceed.");
+        anyhow::bail!("{} failed", action.name());
+    }
+
+    // The parent whose change is replayed; a merge needs to be told which one
+    let parent = match (commit.parents.as_slice(), options.mainline) {
+        ([], _) => None,
+        ([_, _, ..], None) => return Err(failed(action, &format!("commit {} is a merge but no -m option was given.", commit.hash))),
+        (parents, Some(mainline)) if mainline > parents.len() || (parents.len() == 1 && mainline > 1) => {
+            return Err(failed(action, &format!("commit {} does not have parent {}", commit.hash, mainline)));
+        }
+        (parents, mainline) => Some(parents[mainline.unwrap_or(1) - 1].clone()),
+    };
+
+    if options.allow_ff && action == Action::Pick && parent == head {
+        check_update(action, switch_tree(Some(&head_tree), Some(&commit.tree), false, "merge", "merge"))?;
+        update_head(&commit.hash, "cherry-pick: fast-forward")?;
+        return Ok(true);
+    }
+
+    let parent_tree = match &parent {
+        Some(parent) => Some(peel_to_tree(parent)?),
+        None => None,
+    };
+    let (base, base_label, next, next_label, mut message) = match action {
+        Action::Pick => {
+            let mut message = commit.message.clone();
+            if options.record_origin {
+                if !message.ends_with('\n') {
+                    message.push('\n');
+                }
+                if !has_trailer_block(&message) {
+                    message.push('\n');
+                }
+                message.push_str(&format!("(cherry picked from commit {})\n", commit.hash));
+            }
+            (parent_tree, format!("parent of {}", label), commit.tree.clone(), label.clone(), message)
+        }
+        Action::Revert => {
+            let mut message = format!("Revert \"{}\"\n\nThis reverts commit {}", first_line(&commit), commit.hash);
+            if commit.parents.len() > 1 {
+                message.push_str(&format!(", reversing\nchanges made to {}", parent.as_deref().unwrap_or("")));
+            }
+            message.push_str(".\n");
+            let next_label = match parent_tree {
+                Some(_) => format!("parent of {}", label),
+                None => "(empty tree)".to_string(),
+            };
+            (Some(commit.tree.clone()), label.clone(), parent_tree.unwrap_or_else(|| empty_tree.clone()), next_label, message)
+        }
+    };
+
+    // Without committing, the result builds on whatever is already staged
+    let ours = if options.no_commit { index_tree()? } else { head_tree.clone() };
+    let mut merge_options = MergeOptions::new(config, "HEAD", &next_label)?;
+    merge_options.labels[0] = base_label;
+    for option in &options.strategy_options {
+        merge_options.strategy_option(option)?;
+    }
+    let merge = merge_trees(base.as_deref(), &ours, &next, &merge_options)?;
+    check_update(action, apply_merge(Some(&ours), &merge))?;
+    for line in &merge.messages {
+        println!("{}", line);
+    }
+
+    if !merge.clean {
+        message.push_str("\n# Conflicts:\n");
+        for path in merge.conflicts.keys() {
+            message.push_str(&format!("#\t{}\n", path));
+        }
+        fs::write(".git/MERGE_MSG", &message)?;
+        if action == Action::Revert || !options.no_commit {
+            fs::write(format!(".git/{}", action.head_ref()), format!("{}\n", commit.hash))?;
+        }
+        let verb = if action == Action::Pick { "apply" } else { "revert" };
+        eprintln!("error: could not {} {}... {}", verb, abbreviate(&commit.hash), first_line(&commit));
+        if options.no_commit {
+            eprintln!("hint: after resolving the conflicts, mark the corrected paths");
+            eprintln!("hint: with 'git add <paths>' or 'git rm <paths>'");
+        } else {
+            eprintln!("hint: After resolving the conflicts, mark them with");
+            eprintln!("hint: \"git add/rm <pathspec>\", then run");
+            eprintln!("hint: \"git {} --continue\".", action.name());
+            eprintln!("hint: You can instead skip this commit with \"git {} --skip\".", action.name());
+            eprintln!("hint: To abort and get back to the state before \"git {}\",", action.name());
+            eprintln!("hint: run \"git {} --abort\".", action.name());
+        }
+        return Ok(false);
+    }
+    if options.no_commit {
+        fs::write(".git/MERGE_MSG", &message)?;
+        if action == Action::Revert {
+            fs::write(".git/REVERT_HEAD", format!("{}\n", commit.hash))?;
+        }
+        return Ok(true);
+    }
+
+    let tree = merge.tree();
+    let originally_empty = parent_tree_of(&commit)? == commit.tree;
+    if tree == head_tree && !options.keep_redundant_commits && !(options.allow_empty && originally_empty) {
+        fs::write(".git/MERGE_MSG", &message)?;
+        if action == Action::Pick {
+            fs::write(".git/CHERRY_PICK_HEAD", format!("{}\n", commit.hash))?;
+            report_empty_pick(config)?;
+        } else {
+            print_long_status(config)?;
+        }
+        return Ok(false);
+    }
+    let author = match action {
+        Action::Pick => commit.raw_header.lines().find_map(|line| line.strip_prefix("author ")).unwrap_or("").to_string(),
+        Action::Revert => signature(config, Role::Author)?,
+    };
+    let parents: Vec<String> = head.into_iter().collect();
+    let new_commit = write_commit_as(&tree, &parents, &author, &message, config)?;
+    update_head(&new_commit, &format!("{}: {}", action.name(), message.lines().next().unwrap_or("")))?;
+    print_commit_summary(config, &new_commit, true)?;
+    Ok(true)
+}
+
+// The tree a commit's change is made against, empty for a root commit
+fn parent_tree_of(commit: &Commit) -> anyhow::Result<String> {
+    match commit.parents.first() {
+        Some(parent) => peel_to_tree(parent),
+        None => Ok(write_tree(&BTreeMap::new())),
+    }
+}
+
+// Replay the steps left in the todo list one after another, stopping at the first that needs
+// the user. The sequencer goes away once every step is done.
+fn run_sequence(config: &Config, mut steps: Vec<Step>, options: &ReplayOptions) -> anyhow::Result<()> {
+    while !steps.is_empty() {
+        write_todo(&steps)?;
+        let result = replay_commit(config, &steps[0], options);
+        update_abort_safety()?;
+        if !result? {
+            std::process::exit(1);
+        }
+        steps.remove(0);
+    }
+    remove_sequencer_state();
+    Ok(())
+}
+
+// Commit the resolution of a replay that stopped, with the message it left in MERGE_MSG, as
+// "git commit" does when a cherry-pick or revert is in progress. Returns false when there was
+// nothing to commit.
+fn commit_resolution(config: &Config, action: Action, commit: &str) -> anyhow::Result<bool> {
+    let unmerged = unmerged_paths()?;
+    if !unmerged.is_empty() {
+        eprintln!("error: Committing is not possible because you have unmerged files.");
+        eprintln!("hint: Fix them up in the work tree, and then use 'git add/rm <file>'");
+        eprintln!("hint: as appropriate to mark resolution and make a commit.");
+        for path in unmerged {
+            println!("U\t{}", path);
+        }
+        anyhow::bail!("Exiting because of an unresolved conflict.");
+    }
+    let head = resolve_ref("HEAD");
+    let tree = index_tree()?;
+    if Some(&tree) == head_tree()?.as_ref() {
+        if action == Action::Pick {
+            report_empty_pick(config)?;
+        } else {
+            print_long_status(config)?;
+        }
+        return Ok(false);
+    }
+    let message = cleanup_message(&fs::read_to_string(".git/MERGE_MSG").unwrap_or_default());
+    if message.is_empty() {
+        anyhow::bail!("Aborting commit due to empty commit message.");
+    }
+    let author = match action {
+        Action::Pick => load_commit(commit)?.raw_header.lines().find_map(|line| line.strip_prefix("author ")).unwrap_or("").to_string(),
+        Action::Revert => signature(config, Role::Author)?,
+    };
+    let parents: Vec<String> = head.into_iter().collect();
+    let new_commit = write_commit_as(&tree, &parents, &author, &message, config)?;
+    let reflog = match action {
+        Action::Pick => "commit (cherry-pick)",
+        Action::Revert => "commit",
+    };
+    update_head(&new_commit, &format!("{}: {}", reflog, message.lines().next().unwrap_or("")))?;
+    let _ = fs::remove_file(format!(".git/{}", action.head_ref()));
+    let _ = fs::remove_file(".git/MERGE_MSG");
+    print_commit_summary(config, &new_commit, action == Action::Pick)?;
+    Ok(true)
+}
+
+// The cherry-pick or revert that stopped for the user, if there is one
+fn stopped_replay() -> Option<(Action, String)> {
+    [Action::Pick, Action::Revert].into_iter().find_map(|action| read_ref(action.head_ref()).map(|commit| (action, commit)))
+}
+
+fn continue_sequence(config: &Config, action: Action) -> anyhow::Result<()> {
+    let in_sequence = Path::new(&sequencer_path("todo")).is_file();
+    let stopped = stopped_replay();
+    if !in_sequence && stopped.is_none() {
+        return Err(failed(action, "no cherry-pick or revert in progress"));
+    }
+    if let Some((stopped_action, commit)) = &stopped {
+        if !commit_resolution(config, *stopped_action, commit)? {
+            std::process::exit(1);
+        }
+    }
+    if !in_sequence {
+        return Ok(());
+    }
+    if !index_matches_head()? {
+        eprintln!("error: your local changes would be overwritten by {}.", action.name());
+        eprintln!("hint: commit your changes or stash them to proceed.");
+        anyhow::bail!("{} failed", action.name());
+    }
+    let options = ReplayOptions::load();
+    let mut steps = read_todo()?;
+    if !steps.is_empty() {
+        steps.remove(0);
+    }
+    run_sequence(config, steps, &options)
+}
+
+fn skip_commit(config: &Config, action: Action) -> anyhow::Result<()> {
+    if read_ref(action.head_ref()).is_none() {
+        if sequence_action() != Some(action) {
+            return Err(failed(action, &format!("no {} in progress", action.name())));
+        }
+        if !rollback_is_safe() {
+            eprintln!("error: there is nothing to skip");
+            eprintln!("hint: have you committed already?");
+            eprintln!("hint: try \"git {} --continue\"", action.name());
+            anyhow::bail!("{} failed", action.name());
+        }
+    }
+    let head = resolve_ref("HEAD").ok_or_else(|| failed(action, "cannot resolve HEAD"))?;
+    reset_to_commit(ResetMode::Merge, &head, false, true)?;
+    if !Path::new(SEQUENCER_DIR).is_dir() {
+        return Ok(());
+    }
+    update_abort_safety()?;
+    continue_sequence(config, action)
+}
+
+fn abort_sequence(action: Action) -> anyhow::Result<()> {
+    let Ok(saved) = fs::read_to_string(sequencer_path("head")) else {
+        if stopped_replay().is_none() {
+            return Err(failed(action, "no cherry-pick or revert in progress"));
+        }
+        let head = resolve_ref("HEAD").ok_or_else(|| failed(action, "cannot resolve HEAD"))?;
+        return reset_to_commit(ResetMode::Merge, &head, false, true);
+    };
+    let saved = saved.trim();
+    if saved.is_empty() {
+        return Err(failed(action, "cannot abort from a branch yet to be born"));
+    }
+    if saved.len() != 40 || !saved.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(failed(action, &format!("stored pre-cherry-pick HEAD file '{}' is corrupt", sequencer_path("head"))));
+    }
+    if rollback_is_safe() {
+        reset_to_commit(ResetMode::Merge, saved, false, true)?;
+    } else {
+        eprintln!("warning: You seem to have moved HEAD. Not rewinding, check your HEAD!");
+    }
+    remove_sequencer_state();
+    Ok(())
+}
+
+// The commits named on the command line in the order they are replayed: ranges are walked,
+// oldest first for a cherry-pick and newest first for a revert
+fn commits_to_replay(action: Action, names: &[String]) -> anyhow::Result<(Vec<String>, bool)> {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let resolve = |name: &str| -> anyhow::Result<String> {
+        resolve_revision(name).and_then(|hash| peel_to_commit(&hash)).map_err(|_| anyhow::anyhow!("bad revision '{}'", name))
+    };
+    for name in names {
+        if let Some((from, to)) = name.split_once("..").filter(|(_, to)| !to.starts_with('.')) {
+            exclude.push(resolve(if from.is_empty() { "HEAD" } else { from })?);
+            include.push(resolve(if to.is_empty() { "HEAD" } else { to })?);
+        } else if let Some(name) = name.strip_prefix('^') {
+            exclude.push(resolve(name)?);
+        } else {
+            include.push(resolve(name)?);
+        }
+    }
+    // Commits named more than once are replayed once
+    if exclude.is_empty() {
+        let single = names.len() == 1;
+        let mut seen = HashSet::new();
+        include.retain(|commit| seen.insert(commit.clone()));
+        return Ok((include, single));
+    }
+    let mut walk = Walk::default();
+    walk.include = include;
+    walk.exclude = exclude;
+    let mut commits = walk.run()?;
+    if action == Action::Pick {
+        commits.reverse();
+    }
+    Ok((commits, false))
+}
+
+fn usage(action: Action) -> ! {
+    match action {
+        Action::Pick => {
+            eprintln!("usage: git cherry-pick [--edit] [-n] [-m <parent-number>] [-s] [-x] [--ff]");
+            eprintln!("                       [-S[<keyid>]] <commit>...");
+            eprintln!("   or: git cherry-pick (--continue | --skip | --abort | --quit)");
+        }
+        Action::Revert => {
+            eprintln!("usage: git revert [--[no-]edit] [-n] [-m <parent-number>] [-s] [-S[<keyid>]] <commit>...");
+            eprintln!("   or: git revert (--continue | --skip | --abort | --quit)");
+        }
+    }
+    std::process::exit(129);
+}
+
+fn parse_mainline(value: Option<&String>) -> usize {
+    match value.and_then(|x| x.parse::<usize>().ok()).filter(|x| *x > 0) {
+        Some(mainline) => mainline,
+        None => {
+            eprintln!("error: option `mainline' expects a number greater than zero");
+            std::process::exit(129);
+        }
+    }
+}
+
+fn replay_command(action: Action, args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == action.name()).unwrap();
+    let mut options = ReplayOptions::default();
+    let mut subcommand: Option<&str> = None;
+    let mut names = Vec::new();
+
+    let mut index = command_index + 1;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            "--continue" | "--skip" | "--abort" | "--quit" => subcommand = Some(arg),
+            "-n" | "--no-commit" => options.no_commit = true,
+            "--commit" => options.no_commit = false,
+            "-e" | "--edit" | "--no-edit" | "-s" | "--signoff" | "--rerere-autoupdate" | "--no-rerere-autoupdate" => {}
+            "-x" if action == Action::Pick => options.record_origin = true,
+            "--ff" if action == Action::Pick => options.allow_ff = true,
+            "--allow-empty" if action == Action::Pick => options.allow_empty = true,
+            "--allow-empty-message" if action == Action::Pick => {}
+            "--keep-redundant-commits" if action == Action::Pick => options.keep_redundant_commits = true,
+            "-m" | "--mainline" => {
+                index += 1;
+                options.mainline = Some(parse_mainline(args.get(index)));
+            }
+            "-X" | "--strategy-option" => {
+                index += 1;
+                options.strategy_options.push(args.get(index).cloned().unwrap_or_else(|| usage(action)));
+            }
+            "--strategy" => index += 1,
+            "--" => {}
+            _ if arg.starts_with("--mainline=") => options.mainline = Some(parse_mainline(Some(&arg["--mainline=".len()..].to_string()))),
+            _ if arg.starts_with("-m") => options.mainline = Some(parse_mainline(Some(&arg[2..].to_string()))),
+            _ if arg.starts_with("--strategy-option=") => options.strategy_options.push(arg["--strategy-option=".len()..].to_string()),
+            _ if arg.starts_with("-X") => options.strategy_options.push(arg[2..].to_string()),
+            _ if arg.starts_with("--strategy=") || arg.starts_with("--cleanup=") => {}
+            _ if arg.starts_with('-') => usage(action),
+            _ => names.push(arg.to_string()),
+        }
+        index += 1;
+    }
+
+    if let Some(subcommand) = subcommand {
+        if !names.is_empty() {
+            usage(action);
+        }
+        return match subcommand {
+            "--continue" => continue_sequence(&config, action),
+            "--skip" => skip_commit(&config, action),
+            "--abort" => abort_sequence(action),
+            _ => {
+                remove_sequencer_state();
+                remove_branch_state();
+                Ok(())
+            }
+        };
+    }
+    if names.is_empty() {
+        usage(action);
+    }
+    if options.allow_ff {
+        for (name, set) in [("--no-commit", options.no_commit), ("-x", options.record_origin)] {
+            if set {
+                anyhow::bail!("{}: {} cannot be used with --ff", action.name(), name);
+            }
+        }
+    }
+
+    let (commits, single) = commits_to_replay(action, &names)?;
+    if commits.is_empty() {
+        return Err(failed(action, "empty commit set passed"));
+    }
+    if single {
+        let step = Step { action, commit: commits[0].clone() };
+        if !replay_commit(&config, &step, &options)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(running) = sequence_action() {
+        let skip = if stopped_replay().is_some() { "--skip | " } else { "" };
+        eprintln!("error: {} is already in progress", running.name());
+        eprintln!("hint: try \"git {} (--continue | {}--abort | --quit)\"", running.name(), skip);
+        anyhow::bail!("{} failed", action.name());
+    }
+    fs::create_dir_all(SEQUENCER_DIR)?;
+    let head = resolve_ref("HEAD").unwrap_or_default();
+    fs::write(sequencer_path("head"), format!("{}\n", head))?;
+    update_abort_safety()?;
+    options.save()?;
+    let steps = commits.into_iter().map(|commit| Step { action, commit }).collect();
+    run_sequence(&config, steps, &options)
+}
+
+// Apply the changes some existing commits introduce, committing each on top of HEAD
+pub fn cherry_pick_command(args: &[String]) -> anyhow::Result<()> {
+    replay_command(Action::Pick, args)
+}
+
+// Undo the changes some existing commits introduced with new commits on top of HEAD
+pub fn revert_command(args: &[String]) -> anyhow::Result<()> {
+    replay_command(Action::Revert, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::write_index;
+    use crate::testrepo::{commit, repository, tree};
+    use crate::worktree::checkout_file;
+
+    // HEAD on main at a commit adding b to base, with a topic commit changing a line of a
+    // made on base as well
+    fn branches() -> (String, String) {
+        fs::write(".git/config", "[user]\n\tname = C O Mitter\n\temail = committer@example.com\n").unwrap();
+        let base = commit(&tree(&[("a", "1\n2\n3\n")]), &[], 1, "base");
+        let main_tree = tree(&[("a", "1\n2\n3\n"), ("b", "b\n")]);
+        let main = commit(&main_tree, &[&base], 2, "add b");
+        let topic = commit(&tree(&[("a", "1\ntwo\n3\n")]), &[&base], 3, "change two\n\nSigned-off-by: A U Thor <author@example.com>");
+        fs::write(".git/refs/heads/main", format!("{}\n", main)).unwrap();
+        let mut index = Vec::new();
+        for (path, entry) in flatten_tree(&main_tree).unwrap() {
+            index.push(checkout_file(&path, entry.mode, &entry.sha_hash).unwrap());
+        }
+        write_index(&index).unwrap();
+        (main, topic)
+    }
+
+    fn replay(action: Action, commit: &str, options: &ReplayOptions) -> bool {
+        replay_commit(&read_config_file(".git/config"), &Step { action, commit: commit.to_string() }, options).unwrap()
+    }
+
+    #[test]
+    fn picks() {
+        let _repository = repository();
+        let (main, topic) = branches();
+        assert!(replay(Action::Pick, &topic, &ReplayOptions { record_origin: true, ..ReplayOptions::default() }));
+        let picked = load_commit(&resolve_ref("HEAD").unwrap()).unwrap();
+        assert_eq!(picked.parents, [main]);
+        assert_eq!(flatten_tree(&picked.tree).unwrap().len(), 2);
+        assert_eq!(fs::read_to_string("a").unwrap(), "1\ntwo\n3\n");
+        // The origin line joins the trailer block
+        let expected = format!("change two\n\nSigned-off-by: A U Thor <author@example.com>\n(cherry picked from commit {})\n", topic);
+        assert_eq!(picked.message, expected);
+        assert!(picked.raw_header.contains("\nauthor A U Thor <author@example.com> 3 +0000\n"));
+
+        // Picking it again changes nothing, so it stops to ask about the empty commit
+        let head = resolve_ref("HEAD");
+        assert!(!replay(Action::Pick, &topic, &ReplayOptions::default()));
+        assert_eq!(resolve_ref("HEAD"), head);
+        assert_eq!(read_ref("CHERRY_PICK_HEAD").as_deref(), Some(topic.as_str()));
+    }
+
+    #[test]
+    fn reverts() {
+        let _repository = repository();
+        let (main, _) = branches();
+        assert!(replay(Action::Revert, &main, &ReplayOptions::default()));
+        let reverted = load_commit(&resolve_ref("HEAD").unwrap()).unwrap();
+        assert_eq!(reverted.message, format!("Revert \"add b\"\n\nThis reverts commit {}.\n", main));
+        assert!(!Path::new("b").exists());
+    }
+
+    #[test]
+    fn conflicts() {
+        let _repository = repository();
+        let (_, topic) = branches();
+        fs::write("a", "1\nTWO\n3\n").unwrap();
+        let head = resolve_ref("HEAD").unwrap();
+        let changed = commit(&tree(&[("a", "1\nTWO\n3\n"), ("b", "b\n")]), &[&head], 4, "change TWO");
+        fs::write(".git/refs/heads/main", format!("{}\n", changed)).unwrap();
+        let mut index: Vec<_> = read_index().unwrap().into_iter().filter(|entry| entry.path != "a").collect();
+        index.push(checkout_file("a", 0o100644, &crate::write_object_to_store(b"1\nTWO\n3\n", b"blob")).unwrap());
+        index.sort_by(|x, y| x.path.cmp(&y.path));
+        write_index(&index).unwrap();
+
+        assert!(!replay(Action::Pick, &topic, &ReplayOptions::default()));
+        assert_eq!(unmerged_paths().unwrap().into_iter().collect::<Vec<_>>(), ["a"]);
+        assert_eq!(read_ref("CHERRY_PICK_HEAD").as_deref(), Some(topic.as_str()));
+        assert!(fs::read_to_string(".git/MERGE_MSG").unwrap().ends_with("\n# Conflicts:\n#\ta\n"));
+        let label = format!("{} (change two)", abbreviate(&topic));
+        assert_eq!(fs::read_to_string("a").unwrap(), format!("1\n<<<<<<< HEAD\nTWO\n=======\ntwo\n>>>>>>> {}\n3\n", label));
+    }
+
+    #[test]
+    fn sequencer_state() {
+        let _repository = repository();
+        let (main, topic) = branches();
+        fs::create_dir_all(SEQUENCER_DIR).unwrap();
+        write_todo(&[Step { action: Action::Pick, commit: topic.clone() }, Step { action: Action::Revert, commit: main.clone() }]).unwrap();
+        let todo = fs::read_to_string(sequencer_path("todo")).unwrap();
+        assert_eq!(todo, format!("pick {} change two\nrevert {} add b\n", abbreviate(&topic), abbreviate(&main)));
+        let steps = read_todo().unwrap();
+        assert_eq!(steps.iter().map(|x| (x.action.command(), x.commit.as_str())).collect::<Vec<_>>(), [("pick", topic.as_str()), ("revert", main.as_str())]);
+        assert_eq!(sequence_command(), Some("pick"));
+
+        let options = ReplayOptions { record_origin: true, mainline: Some(2), strategy_options: vec!["theirs".to_string()], ..ReplayOptions::default() };
+        options.save().unwrap();
+        let loaded = ReplayOptions::load();
+        assert!(loaded.record_origin && !loaded.no_commit);
+        assert_eq!((loaded.mainline, loaded.strategy_options), (Some(2), vec!["theirs".to_string()]));
+
+        fs::write(sequencer_path("todo"), "squash 1234\n").unwrap();
+        assert_eq!(read_todo().err().unwrap().to_string(), "invalid line 1: squash 1234");
+        remove_sequencer_state();
+        assert_eq!(sequence_command(), None);
+    }
+
+    #[test]
+    fn trailers() {
+        assert!(has_trailer_block("subject\n\nSigned-off-by: A <a@example.com>\n"));
+        assert!(has_trailer_block("subject\n\nbody\n\n(cherry picked from commit 1234)\n"));
+        assert!(!has_trailer_block("subject\n\nsome body text\n"));
+        assert!(!has_trailer_block("subject: with colon\n"));
+    }
+}
@@ -0,0 +1,735 @@
+// Stash: put local changes away in commits kept on refs/stash and bring them back later.
+// A stash is a commit of the working tree whose parents are the commit it was made on, a
+// commit of the index and, when untracked files were stashed too, a parentless commit of
+// just those. Each stash is an entry in the reflog of refs/stash, newest first.
+
+use crate::add::collect_files;
+use crate::checkout::checkout_command;
+use crate::config::{read_config, Config};
+use crate::diff::{TreeDiff, NULL_HASH};
+use crate::ignore::Ignores;
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::merge::{apply_merge, merge_trees, MergeOptions};
+use crate::patch::DiffOptions;
+use crate::reflog::{append_reflog, read_reflog, write_reflog};
+use crate::refs::{delete_ref, head_symbolic_target, read_ref, resolve_ref, shorten_ref_name, update_ref, write_ref};
+use crate::revision::{matching_ref_names, resolve_revision};
+use crate::sequencer::{first_line, index_tree};
+use crate::status::print_long_status;
+use crate::walk::{abbreviate, load_commit};
+use crate::worktree::{checkout_file, flatten_tree, mode_from_metadata, pathspec_matches, read_worktree_contents, remove_worktree_file, worktree_matches_index, write_tree, TreeEntry};
+use crate::{write_commit, write_object_to_store};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const STASH_REF: &str = "refs/stash";
+
+// Usage lines for each subcommand, all of them for "stash -h"
+const USAGE: [(&str, &str); 9] = [
+    ("list", "git stash list"),
+    ("show", "git stash show [-u | --include-untracked | --only-untracked] [<diff-options>] [<stash>]"),
+    ("drop", "git stash drop [-q | --quiet] [<stash>]"),
+    ("pop", "git stash pop [--index] [-q | --quiet] [<stash>]"),
+    ("apply", "git stash apply [--index] [-q | --quiet] [<stash>]"),
+    ("branch", "git stash branch <branchname> [<stash>]"),
+    (
+        "push",
+        "git stash [push [-k | --[no-]keep-index] [-q | --quiet]\n                 [-u | --include-untracked] [-a | --all] [(-m | --message) <message>]\n                 [--] [<pathspec>...]]",
+    ),
+    ("save", "git stash save [-k | --[no-]keep-index] [-q | --quiet]\n                 [-u | --include-untracked] [-a | --all] [<message>]"),
+    ("clear", "git stash clear"),
+];
+
+fn usage(subcommand: Option<&str>) -> ! {
+    let lines: Vec<&str> = USAGE.iter().filter(|(name, _)| subcommand.is_none_or(|x| x == *name)).map(|(_, line)| *line).collect();
+    eprintln!("usage: {}", lines.join("\n   or: "));
+    std::process::exit(129);
+}
+
+fn unknown_option(subcommand: &str, arg: &str) -> ! {
+    match arg.strip_prefix("--") {
+        Some(name) => eprintln!("error: unknown option `{}'", name),
+        None => eprintln!("error: unknown switch `{}'", arg.trim_start_matches('-')),
+    }
+    usage(Some(subcommand));
+}
+
+// Stash is a stashed state as it was named, with the commits and trees it is made of
+struct Stash {
+    name: String,
+    commit: String,
+    base: String,
+    base_tree: String,
+    index_tree: String,
+    tree: String,
+    untracked_tree: Option<String>,
+    // Whether the name picks an entry of the stash reflog, which drop and pop need
+    is_entry: bool,
+}
+
+// Find the stash a command was pointed at, the newest when none was named. A bare number n
+// stands for stash@{n}.
+fn find_stash(names: &[String]) -> anyhow::Result<Stash> {
+    if names.len() > 1 {
+        let quoted: Vec<String> = names.iter().map(|name| format!("'{}'", name)).collect();
+        eprintln!("Too many revisions specified: {}", quoted.join(" "));
+        std::process::exit(1);
+    }
+    let name = match names.first() {
+        None => {
+            if resolve_ref(STASH_REF).is_none() {
+                eprintln!("No stash entries found.");
+                std::process::exit(1);
+            }
+            format!("{}@{{0}}", STASH_REF)
+        }
+        Some(name) if !name.is_empty() && name.bytes().all(|x| x.is_ascii_digit()) => format!("{}@{{{}}}", STASH_REF, name),
+        Some(name) => name.clone(),
+    };
+    let hash = match resolve_revision(&name) {
+        Ok(hash) => hash,
+        // Asking for more entries than the log has is reported as such
+        Err(error) if name.contains("@{") && resolve_ref(STASH_REF).is_some() => return Err(error),
+        Err(_) => {
+            eprintln!("error: {} is not a valid reference", name);
+            std::process::exit(1);
+        }
+    };
+    let not_stash = || anyhow::anyhow!("'{}' is not a stash-like commit", name);
+    let commit = load_commit(&hash).map_err(|_| not_stash())?;
+    let [base, index, rest @ ..] = commit.parents.as_slice() else {
+        return Err(not_stash());
+    };
+    let untracked_tree = match rest.first() {
+        Some(untracked) => Some(load_commit(untracked)?.tree),
+        None => None,
+    };
+    let ref_name = name.split_once("@{").map(|(ref_name, _)| ref_name).unwrap_or(&name);
+    Ok(Stash {
+        is_entry: matching_ref_names(ref_name).iter().any(|candidate| candidate == STASH_REF && read_ref(candidate).is_some()),
+        base_tree: load_commit(base)?.tree,
+        index_tree: load_commit(index)?.tree,
+        base: base.clone(),
+        tree: commit.tree,
+        commit: hash,
+        untracked_tree,
+        name,
+    })
+}
+
+fn require_entry(stash: &Stash) {
+    if !stash.is_entry {
+        eprintln!("error: '{}' is not a stash reference", stash.name);
+        std::process::exit(1);
+    }
+}
+
+fn tree_of(index: &BTreeMap<String, IndexEntry>) -> BTreeMap<String, TreeEntry> {
+    index.iter().map(|(path, entry)| (path.clone(), TreeEntry { mode: entry.mode, sha_hash: entry.sha_hash.clone() })).collect()
+}
+
+// Store a file on disk as a blob and return its tree entry
+fn store_file(path: &str, metadata: &fs::Metadata) -> anyhow::Result<TreeEntry> {
+    let contents = read_worktree_contents(path, metadata)?;
+    Ok(TreeEntry { mode: mode_from_metadata(metadata), sha_hash: write_object_to_store(&contents, b"blob") })
+}
+
+// The files of the working tree: the index with the files the pathspecs pick taken from disk,
+// so deleted files are left out and changed ones stored anew
+fn worktree_entries(index: &BTreeMap<String, IndexEntry>, matches: &dyn Fn(&str) -> bool) -> anyhow::Result<BTreeMap<String, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    for (path, staged) in index {
+        let entry = TreeEntry { mode: staged.mode, sha_hash: staged.sha_hash.clone() };
+        if !matches(path) || worktree_matches_index(staged) {
+            entries.insert(path.clone(), entry);
+            continue;
+        }
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                entries.insert(path.clone(), store_file(path, &metadata)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+// Bring the index and working tree to a tree for the paths picked, removing picked files the
+// tree does not have and leaving alone files already as they should be
+fn restore_paths(target: &BTreeMap<String, TreeEntry>, matches: &dyn Fn(&str) -> bool) -> anyhow::Result<()> {
+    let mut index: BTreeMap<String, IndexEntry> = read_index()?.into_iter().map(|entry| (entry.path.clone(), entry)).collect();
+    let paths: BTreeSet<String> = index.keys().chain(target.keys()).filter(|path| matches(path)).cloned().collect();
+    for path in paths {
+        match target.get(&path) {
+            Some(entry) => {
+                let current = index.get(&path).filter(|staged| staged.mode == entry.mode && staged.sha_hash == entry.sha_hash);
+                if !current.map(worktree_matches_index).unwrap_or(false) {
+                    index.insert(path.clone(), checkout_file(&path, entry.mode, &entry.sha_hash)?);
+                }
+            }
+            None => {
+                index.remove(&path);
+                remove_worktree_file(&path)?;
+            }
+        }
+    }
+    write_index(&index.into_values().collect::<Vec<_>>())
+}
+
+// Replace the index with a tree's entries without touching the working tree, keeping the
+// stat data of entries that stay the same
+fn stage_tree(entries: &BTreeMap<String, TreeEntry>) -> anyhow::Result<()> {
+    let current: BTreeMap<String, IndexEntry> = read_index()?.into_iter().filter(|entry| entry.stage() == 0).map(|entry| (entry.path.clone(), entry)).collect();
+    let staged: Vec<IndexEntry> = entries
+        .iter()
+        .map(|(path, entry)| match current.get(path) {
+            Some(staged) if staged.mode == entry.mode && staged.sha_hash == entry.sha_hash => staged.clone(),
+            _ => IndexEntry::without_stat(path, entry.mode, &entry.sha_hash),
+        })
+        .collect();
+    write_index(&staged)
+}
+
+// Git resets to HEAD along the way, which logs HEAD staying put when it is on a branch
+fn log_reset() -> anyhow::Result<()> {
+    match resolve_ref("HEAD").filter(|_| head_symbolic_target().is_some()) {
+        Some(head) => append_reflog("HEAD", Some(&head), Some(&head), "reset: moving to HEAD"),
+        None => Ok(()),
+    }
+}
+
+// Make a commit the newest stash, creating the stash log if there is none yet
+fn store_stash(commit: &str, message: &str) -> anyhow::Result<()> {
+    let log = Path::new(".git/logs").join(STASH_REF);
+    if !log.is_file() {
+        fs::create_dir_all(log.parent().unwrap())?;
+        fs::write(&log, "")?;
+    }
+    update_ref(STASH_REF, commit, message)
+}
+
+// PushOptions are the switches of "stash push" and "stash save"
+#[derive(Default)]
+struct PushOptions {
+    keep_index: bool,
+    include_untracked: bool,
+    all: bool,
+    quiet: bool,
+    message: Option<String>,
+    pathspecs: Vec<String>,
+}
+
+impl PushOptions {
+    fn parse(&mut self, arg: &str) -> bool {
+        match arg {
+            "-k" | "--keep-index" => self.keep_index = true,
+            "--no-keep-index" => self.keep_index = false,
+            "-u" | "--include-untracked" => (self.include_untracked, self.all) = (true, false),
+            "--no-include-untracked" => self.include_untracked = false,
+            "-a" | "--all" => (self.include_untracked, self.all) = (true, true),
+            "--no-all" => self.all = false,
+            "-q" | "--quiet" => self.quiet = true,
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn push_stash(config: &Config, options: &PushOptions) -> anyhow::Result<()> {
+    let pathspecs = &options.pathspecs;
+    let matches = |path: &str| pathspecs.is_empty() || pathspecs.iter().any(|pathspec| pathspec_matches(pathspec, path));
+    let index_entries = read_index()?;
+    if let Some(pathspec) = pathspecs.iter().filter(|_| !options.include_untracked).find(|pathspec| !index_entries.iter().any(|entry| pathspec_matches(pathspec, &entry.path))) {
+        eprintln!("error: pathspec '{}' did not match any file(s) known to git", pathspec);
+        eprintln!("Did you forget to 'git add'?");
+        std::process::exit(1);
+    }
+    let mut unmerged: Vec<&str> = index_entries.iter().filter(|entry| entry.stage() != 0).map(|entry| entry.path.as_str()).collect();
+    if !unmerged.is_empty() {
+        unmerged.dedup();
+        for path in unmerged {
+            eprintln!("{}: needs merge", path);
+        }
+        std::process::exit(1);
+    }
+    let Some(head) = resolve_ref("HEAD") else {
+        eprintln!("You do not have the initial commit yet");
+        std::process::exit(1);
+    };
+    let head_commit = load_commit(&head)?;
+    let head_entries = flatten_tree(&head_commit.tree)?;
+    let index: BTreeMap<String, IndexEntry> = index_entries.into_iter().map(|entry| (entry.path.clone(), entry)).collect();
+    let staged = tree_of(&index);
+    let worktree = worktree_entries(&index, &matches)?;
+
+    let mut untracked = BTreeSet::new();
+    if options.include_untracked {
+        collect_files("", &mut Ignores::new(config), options.all, &mut untracked);
+        untracked.retain(|path| !index.contains_key(path) && matches(path));
+    }
+
+    let staged_changes = head_entries.keys().chain(staged.keys()).any(|path| matches(path) && head_entries.get(path) != staged.get(path));
+    if !staged_changes && worktree == staged && untracked.is_empty() {
+        println!("No local changes to save");
+        return Ok(());
+    }
+
+    let branch = match head_symbolic_target() {
+        Some(branch) => shorten_ref_name(&branch).to_string(),
+        None => "(no branch)".to_string(),
+    };
+    let summary = format!("{}: {} {}", branch, abbreviate(&head), first_line(&head_commit));
+    let index_commit = write_commit(&write_tree(&staged), std::slice::from_ref(&head), &format!("index on {}\n", summary), config)?;
+    let mut parents = vec![head.clone(), index_commit];
+    if !untracked.is_empty() {
+        let mut entries = BTreeMap::new();
+        for path in &untracked {
+            entries.insert(path.clone(), store_file(path, &fs::symlink_metadata(path)?)?);
+        }
+        let message = format!("untracked files on {}\n", summary);
+        parents.push(write_commit(&write_tree(&entries), &[], &message, config)?);
+    }
+    let message = match &options.message {
+        Some(message) => format!("On {}: {}", branch, message),
+        None => format!("WIP on {}", summary),
+    };
+    let commit = write_commit(&write_tree(&worktree), &parents, &message, config)?;
+    store_stash(&commit, &message)?;
+    if !options.quiet {
+        println!("Saved working directory and index state {}", message);
+    }
+
+    // Take the stashed changes out of the index and working tree
+    for path in &untracked {
+        remove_worktree_file(path)?;
+    }
+    restore_paths(&head_entries, &matches)?;
+    if pathspecs.is_empty() {
+        log_reset()?;
+    }
+    if options.keep_index {
+        restore_paths(&staged, &matches)?;
+    }
+    Ok(())
+}
+
+// Apply a stash to the working tree, and with --index to the index as well, then show the
+// status. Returns whether it applied cleanly.
+fn apply_stash(config: &Config, stash: &Stash, restore_index: bool, quiet: bool) -> anyhow::Result<bool> {
+    let mut unmerged: Vec<String> = read_index()?.into_iter().filter(|entry| entry.stage() != 0).map(|entry| entry.path).collect();
+    if !unmerged.is_empty() {
+        unmerged.dedup();
+        for path in unmerged {
+            eprintln!("{}: needs merge", path);
+        }
+        return Ok(false);
+    }
+    let current = index_tree()?;
+
+    // The staged changes go onto the index first, and must apply without conflicts
+    let mut staged = None;
+    if restore_index && stash.base_tree != stash.index_tree && current != stash.index_tree {
+        let options = MergeOptions::new(config, "HEAD", "stash")?;
+        let merge = merge_trees(Some(&stash.base_tree), &current, &stash.index_tree, &options)?;
+        if !merge.clean {
+            eprintln!("error: conflicts in index. Try without --index.");
+            return Ok(false);
+        }
+        staged = Some(merge.entries);
+        log_reset()?;
+    }
+
+    let ours = if stash.base_tree == current { "Version stash was based on" } else { "Updated upstream" };
+    let mut options = MergeOptions::new(config, ours, "Stashed changes")?;
+    options.labels[0] = "Stash base".to_string();
+    let mut clean = true;
+    if stash.base_tree == stash.tree {
+        println!("Already up to date.");
+    } else {
+        let merge = merge_trees(Some(&stash.base_tree), &current, &stash.tree, &options)?;
+        match apply_merge(Some(&current), &merge) {
+            Ok(()) => {
+                if !quiet {
+                    for line in &merge.messages {
+                        println!("{}", line);
+                    }
+                }
+                clean = merge.clean;
+                if clean {
+                    match &staged {
+                        Some(entries) => stage_tree(entries)?,
+                        None => {
+                            // Leave the changes unstaged, except for files the stash adds
+                            let mut entries = flatten_tree(&current)?;
+                            for (path, entry) in merge.entries {
+                                entries.entry(path).or_insert(entry);
+                            }
+                            stage_tree(&entries)?;
+                        }
+                    }
+                } else if restore_index {
+                    eprintln!("Index was not unstashed.");
+                }
+            }
+            Err(error) => {
+                eprintln!("error: {}", error);
+                clean = false;
+            }
+        }
+    }
+
+    if let Some(untracked_tree) = &stash.untracked_tree {
+        let mut restored = true;
+        for (path, entry) in flatten_tree(untracked_tree)? {
+            if fs::symlink_metadata(&path).is_ok() {
+                eprintln!("{} already exists, no checkout", path);
+                restored = false;
+                continue;
+            }
+            checkout_file(&path, entry.mode, &entry.sha_hash)?;
+        }
+        if !restored {
+            eprintln!("error: could not restore untracked files from stash");
+            clean = false;
+        }
+    }
+    if !quiet {
+        print_long_status(config)?;
+    }
+    Ok(clean)
+}
+
+// Remove a stash from the log, moving refs/stash to the next one or deleting it with the last
+fn drop_stash(stash: &Stash, quiet: bool) -> anyhow::Result<()> {
+    let mut entries = read_reflog(STASH_REF);
+    let Some((_, selector)) = stash.name.strip_suffix('}').and_then(|name| name.split_once("@{")) else {
+        eprintln!("error: not a reflog: {}", stash.name);
+        eprintln!("error: {}: Could not drop stash entry", stash.name);
+        std::process::exit(1);
+    };
+    let position = match selector.parse::<usize>() {
+        Ok(count) => entries.len().checked_sub(count + 1),
+        Err(_) => entries.iter().rposition(|entry| entry.new_hash == stash.commit),
+    };
+    let Some(position) = position else {
+        anyhow::bail!("{}: Could not drop stash entry", stash.name);
+    };
+    entries.remove(position);
+    // Each remaining entry now follows on from the one before it
+    for position in 0..entries.len() {
+        entries[position].old_hash = match position {
+            0 => NULL_HASH.to_string(),
+            _ => entries[position - 1].new_hash.clone(),
+        };
+    }
+    match entries.last() {
+        Some(newest) => {
+            write_reflog(STASH_REF, &entries)?;
+            write_ref(STASH_REF, &newest.new_hash)?;
+        }
+        None => delete_ref(STASH_REF)?,
+    }
+    if !quiet {
+        println!("Dropped {} ({})", stash.name, stash.commit);
+    }
+    Ok(())
+}
+
+fn list_stashes() {
+    for (position, entry) in read_reflog(STASH_REF).iter().rev().enumerate() {
+        println!("stash@{{{}}}: {}", position, entry.message);
+    }
+}
+
+fn show_stash(config: &Config, args: &[String]) -> anyhow::Result<()> {
+    let mut options = DiffOptions::new(config)?;
+    let mut include_untracked = config.get_bool("stash.showIncludeUntracked").unwrap_or(false);
+    let mut only_untracked = false;
+    let mut names = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-u" | "--include-untracked" => (include_untracked, only_untracked) = (true, false),
+            "--no-include-untracked" => include_untracked = false,
+            "--only-untracked" => (include_untracked, only_untracked) = (false, true),
+            _ if arg.starts_with('-') => {
+                if !options.parse_option(arg)? {
+                    usage(Some("show"));
+                }
+            }
+            _ => names.push(arg.clone()),
+        }
+    }
+    if !options.has_format() {
+        options.stat = config.get_bool("stash.showStat").unwrap_or(true);
+        options.patch = config.get_bool("stash.showPatch").unwrap_or(false);
+    }
+    options.validate()?;
+    let stash = find_stash(&names)?;
+
+    let (old, new) = match (&stash.untracked_tree, include_untracked, only_untracked) {
+        (Some(untracked_tree), _, true) => (None, Some(untracked_tree.clone())),
+        (None, _, true) => return Ok(()),
+        (Some(untracked_tree), true, _) => {
+            let mut entries = flatten_tree(&stash.tree)?;
+            entries.extend(flatten_tree(untracked_tree)?);
+            (Some(stash.base_tree.clone()), Some(write_tree(&entries)))
+        }
+        _ => (Some(stash.base_tree.clone()), Some(stash.tree.clone())),
+    };
+    let tree_diff = TreeDiff { recursive: true, ..Default::default() };
+    let changes = options.renames.diff_trees(&tree_diff, old.as_deref(), new.as_deref())?;
+    std::io::stdout().write_all(&options.render(&changes)?)?;
+    options.finish(!changes.is_empty());
+    Ok(())
+}
+
+// Split the arguments of apply, pop and drop into the stash named and the switches
+fn parse_apply_options(subcommand: &str, args: &[String]) -> (Vec<String>, bool, bool) {
+    let mut names = Vec::new();
+    let mut restore_index = false;
+    let mut quiet = false;
+    for arg in args {
+        match arg.as_str() {
+            "--index" if subcommand != "drop" => restore_index = true,
+            "--no-index" if subcommand != "drop" => restore_index = false,
+            "-q" | "--quiet" => quiet = true,
+            _ if arg.starts_with('-') => unknown_option(subcommand, arg),
+            _ => names.push(arg.clone()),
+        }
+    }
+    (names, restore_index, quiet)
+}
+
+pub fn stash_command(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == "stash").unwrap();
+    let args = &args[command_index + 1..];
+    let (subcommand, rest) = match args.first().map(|x| x.as_str()) {
+        None => ("push", args),
+        Some("-h") => usage(None),
+        Some(first) if first.starts_with('-') => ("push", args),
+        Some(first) => (first, &args[1..]),
+    };
+
+    match subcommand {
+        "push" | "save" => {
+            let mut options = PushOptions::default();
+            let mut words = Vec::new();
+            let mut index = 0;
+            while index < rest.len() {
+                let arg = rest[index].as_str();
+                if subcommand == "push" && (arg == "-m" || arg == "--message") {
+                    index += 1;
+                    options.message = Some(rest.get(index).cloned().unwrap_or_else(|| usage(Some(subcommand))));
+                } else if let Some(message) = arg.strip_prefix("--message=").or(arg.strip_prefix("-m").filter(|x| !x.is_empty() && subcommand == "push")) {
+                    options.message = Some(message.to_string());
+                } else if arg == "--" {
+                    words.extend(rest[index + 1..].iter().cloned());
+                    break;
+                } else if !options.parse(arg) {
+                    if arg.starts_with('-') {
+                        unknown_option(subcommand, arg);
+                    }
+                    words.push(arg.to_string());
+                }
+                index += 1;
+            }
+            // Save takes a message where push takes pathspecs
+            match subcommand {
+                "save" if !words.is_empty() => options.message = Some(words.join(" ")),
+                "save" => {}
+                _ => options.pathspecs = words,
+            }
+            push_stash(&config, &options)
+        }
+        "list" => {
+            if let Some(arg) = rest.first() {
+                eprintln!("fatal: unrecognized argument: {}", arg);
+                std::process::exit(1);
+            }
+            list_stashes();
+            Ok(())
+        }
+        "show" => show_stash(&config, rest),
+        "apply" | "pop" => {
+            let (names, restore_index, quiet) = parse_apply_options(subcommand, rest);
+            let stash = find_stash(&names)?;
+            if subcommand == "pop" {
+                require_entry(&stash);
+            }
+            let applied = apply_stash(&config, &stash, restore_index, quiet)?;
+            if !applied {
+                if subcommand == "pop" {
+                    eprintln!("The stash entry is kept in case you need it again.");
+                }
+                std::process::exit(1);
+            }
+            if subcommand == "pop" {
+                drop_stash(&stash, quiet)?;
+            }
+            Ok(())
+        }
+        "drop" => {
+            let (names, _, quiet) = parse_apply_options(subcommand, rest);
+            let stash = find_stash(&names)?;
+            require_entry(&stash);
+            drop_stash(&stash, quiet)
+        }
+        "branch" => {
+            let Some((branch, names)) = rest.split_first() else {
+                eprintln!("No branch name specified");
+                std::process::exit(1);
+            };
+            let stash = find_stash(names)?;
+            if let Err(error) = checkout_command(&["checkout".to_string(), "-b".to_string(), branch.clone(), stash.base.clone()]) {
+                eprintln!("fatal: {:#}", error);
+                std::process::exit(1);
+            }
+            if !apply_stash(&config, &stash, true, false)? {
+                std::process::exit(1);
+            }
+            if stash.is_entry {
+                drop_stash(&stash, false)?;
+            }
+            Ok(())
+        }
+        "clear" => {
+            if let Some(arg) = rest.first() {
+                match arg.starts_with('-') {
+                    true => unknown_option(subcommand, arg),
+                    false => anyhow::bail!("git stash clear with arguments is unimplemented"),
+                }
+            }
+            delete_ref(STASH_REF)
+        }
+        _ => anyhow::bail!("subcommand wasn't specified; 'push' can't be assumed due to unexpected token '{}'", subcommand),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{blob, commit, repository, tree};
+
+    // HEAD on main at a commit of a and b, checked out
+    fn checked_out() -> Config {
+        fs::write(".git/config", "[user]\n\tname = C O Mitter\n\temail = committer@example.com\n").unwrap();
+        let head_tree = tree(&[("a", "1\n"), ("b", "1\n")]);
+        let head = commit(&head_tree, &[], 1, "first");
+        fs::write(".git/refs/heads/main", format!("{}\n", head)).unwrap();
+        let mut index = Vec::new();
+        for (path, entry) in flatten_tree(&head_tree).unwrap() {
+            index.push(checkout_file(&path, entry.mode, &entry.sha_hash).unwrap());
+        }
+        write_index(&index).unwrap();
+        read_config_file(".git/config")
+    }
+
+    // Stage new contents for b and leave a changed on disk only
+    fn change_files() {
+        let mut index: BTreeMap<String, IndexEntry> = read_index().unwrap().into_iter().map(|entry| (entry.path.clone(), entry)).collect();
+        index.insert("b".to_string(), checkout_file("b", 0o100644, &blob("staged\n")).unwrap());
+        write_index(&index.into_values().collect::<Vec<_>>()).unwrap();
+        fs::write("a", "changed\n").unwrap();
+    }
+
+    fn staged() -> Vec<(String, String)> {
+        let contents = |hash: &str| String::from_utf8(crate::read_raw_object(hash).unwrap().1).unwrap();
+        read_index().unwrap().iter().map(|entry| (entry.path.clone(), contents(&entry.sha_hash))).collect()
+    }
+
+    fn files(paths: &[&str]) -> Vec<Option<String>> {
+        paths.iter().map(|path| fs::read_to_string(path).ok()).collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(path, contents)| (path.to_string(), contents.to_string())).collect()
+    }
+
+    #[test]
+    fn push_and_apply() {
+        let _repository = repository();
+        let config = checked_out();
+        change_files();
+        fs::write("u", "untracked\n").unwrap();
+        push_stash(&config, &PushOptions { include_untracked: true, quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(files(&["a", "b", "u"]), [Some("1\n".to_string()), Some("1\n".to_string()), None]);
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "1\n")]));
+
+        let stash = find_stash(&["0".to_string()]).unwrap();
+        assert_eq!(stash.name, "refs/stash@{0}");
+        assert!(stash.is_entry);
+        assert_eq!(stash.base, resolve_ref("HEAD").unwrap());
+        assert_eq!(stash.index_tree, tree(&[("a", "1\n"), ("b", "staged\n")]));
+        assert_eq!(stash.tree, tree(&[("a", "changed\n"), ("b", "staged\n")]));
+        assert_eq!(stash.untracked_tree, Some(tree(&[("u", "untracked\n")])));
+        assert_eq!(read_reflog(STASH_REF)[0].message, "WIP on main: ".to_string() + &abbreviate(&stash.base) + " first");
+
+        // Without --index every change comes back unstaged
+        assert!(apply_stash(&config, &stash, false, true).unwrap());
+        assert_eq!(files(&["a", "b", "u"]), [Some("changed\n".to_string()), Some("staged\n".to_string()), Some("untracked\n".to_string())]);
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "1\n")]));
+
+        // With it the staged ones are staged again, and untracked files in the way stop it
+        restore_paths(&flatten_tree(&stash.base_tree).unwrap(), &|_| true).unwrap();
+        assert!(!apply_stash(&config, &stash, true, true).unwrap());
+        fs::remove_file("u").unwrap();
+        restore_paths(&flatten_tree(&stash.base_tree).unwrap(), &|_| true).unwrap();
+        assert!(apply_stash(&config, &stash, true, true).unwrap());
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "staged\n")]));
+        assert_eq!(files(&["a"]), [Some("changed\n".to_string())]);
+    }
+
+    #[test]
+    fn pathspecs_and_keep_index() {
+        let _repository = repository();
+        let config = checked_out();
+        change_files();
+        let options = PushOptions { quiet: true, pathspecs: vec!["a".to_string()], ..Default::default() };
+        push_stash(&config, &options).unwrap();
+        let stash = find_stash(&[]).unwrap();
+        assert_eq!(stash.tree, tree(&[("a", "changed\n"), ("b", "staged\n")]));
+        assert_eq!(files(&["a", "b"]), [Some("1\n".to_string()), Some("staged\n".to_string())]);
+
+        fs::write("a", "changed\n").unwrap();
+        push_stash(&config, &PushOptions { quiet: true, keep_index: true, message: Some("kept".to_string()), ..Default::default() }).unwrap();
+        assert_eq!(files(&["a", "b"]), [Some("1\n".to_string()), Some("staged\n".to_string())]);
+        assert_eq!(staged(), pairs(&[("a", "1\n"), ("b", "staged\n")]));
+        assert_eq!(read_reflog(STASH_REF).last().unwrap().message, "On main: kept");
+        assert_eq!(find_stash(&["1".to_string()]).unwrap().commit, stash.commit);
+    }
+
+    #[test]
+    fn drops() {
+        let _repository = repository();
+        let config = checked_out();
+        let mut commits = Vec::new();
+        for contents in ["one\n", "two\n", "three\n"] {
+            fs::write("a", contents).unwrap();
+            push_stash(&config, &PushOptions { quiet: true, ..Default::default() }).unwrap();
+            commits.push(resolve_ref(STASH_REF).unwrap());
+        }
+
+        // Dropping from the middle links the entries either side of it
+        drop_stash(&find_stash(&["1".to_string()]).unwrap(), true).unwrap();
+        let entries = read_reflog(STASH_REF);
+        let logged: Vec<&str> = entries.iter().map(|entry| entry.new_hash.as_str()).collect();
+        assert_eq!(logged, [commits[0].as_str(), commits[2].as_str()]);
+        assert_eq!(entries[0].old_hash, NULL_HASH);
+        assert_eq!(entries[1].old_hash, commits[0]);
+        assert_eq!(resolve_ref(STASH_REF).as_deref(), Some(commits[2].as_str()));
+
+        // Dropping the newest moves the ref back, and dropping the last deletes it
+        drop_stash(&find_stash(&[]).unwrap(), true).unwrap();
+        assert_eq!(resolve_ref(STASH_REF).as_deref(), Some(commits[0].as_str()));
+        drop_stash(&find_stash(&[]).unwrap(), true).unwrap();
+        assert_eq!(resolve_ref(STASH_REF), None);
+
+        // A commit without the index and base parents is not a stash
+        let head = resolve_ref("HEAD").unwrap();
+        assert!(find_stash(&[head]).is_err());
+    }
+}
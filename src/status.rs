@@ -0,0 +1,858 @@
+use crate::config::{read_config, Config};
+use crate::diff::{diff_index_to_worktree, diff_tree_to_index, quote_path, ChangeKind, FileChange, NULL_HASH};
+use crate::ignore::Ignores;
+use crate::index::{read_index, write_index, IndexEntry};
+use crate::rebase::{rebase_progress, RebaseProgress};
+use crate::reflog::read_reflog;
+use crate::rename::Renames;
+use crate::refs::{branch_upstream, head_symbolic_target, read_ref, resolve_ref, shorten_ref_name};
+use crate::revision::{count_ahead_behind, expand_ref_name, peel_to_commit, peel_to_tree};
+use crate::sequencer::sequence_command;
+use crate::walk::abbreviate;
+use crate::worktree::{flatten_tree, mode_from_metadata, pathspec_matches};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Long,
+    Short,
+    PorcelainV1,
+    PorcelainV2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum UntrackedMode {
+    No,
+    Normal,
+    All,
+}
+
+fn parse_untracked_mode(value: &str) -> anyhow::Result<UntrackedMode> {
+    match value {
+        "no" | "false" => Ok(UntrackedMode::No),
+        "normal" | "true" => Ok(UntrackedMode::Normal),
+        "all" => Ok(UntrackedMode::All),
+        _ => anyhow::bail!("Invalid untracked files mode '{}'", value),
+    }
+}
+
+// Unmerged is a conflicted path with the mode and id recorded in each of the three index stages
+struct Unmerged {
+    path: String,
+    stages: [Option<(u32, String)>; 3],
+}
+
+impl Unmerged {
+    // Which stages are present, stage 1 in the lowest bit
+    fn stage_mask(&self) -> usize {
+        self.stages.iter().enumerate().filter(|(_, stage)| stage.is_some()).map(|(x, _)| 1 << x).sum()
+    }
+
+    fn code(&self) -> &'static str {
+        match self.stage_mask() {
+            1 => "DD",
+            2 => "AU",
+            3 => "UD",
+            4 => "UA",
+            5 => "DU",
+            6 => "AA",
+            _ => "UU",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self.stage_mask() {
+            1 => "both deleted:",
+            2 => "added by us:",
+            3 => "deleted by them:",
+            4 => "added by them:",
+            5 => "deleted by us:",
+            6 => "both added:",
+            _ => "both modified:",
+        }
+    }
+}
+
+fn unmerged_entries(index: &[IndexEntry]) -> Vec<Unmerged> {
+    let mut unmerged: BTreeMap<&str, Unmerged> = BTreeMap::new();
+    for entry in index.iter().filter(|entry| entry.stage() != 0) {
+        let conflict = unmerged.entry(&entry.path).or_insert_with(|| Unmerged {
+            path: entry.path.clone(),
+            stages: [None, None, None],
+        });
+        conflict.stages[entry.stage() as usize - 1] = Some((entry.mode, entry.sha_hash.clone()));
+    }
+    unmerged.into_values().collect()
+}
+
+// Whether a directory holds any file that is not ignored, so empty directories and
+// directories of ignored files are never reported
+fn has_files(directory: &str, ignores: &mut Ignores) -> bool {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return false;
+    };
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let path = format!("{}/{}", directory, entry.file_name().to_string_lossy());
+        let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+        !ignores.is_ignored(&path, is_dir) && (!is_dir || has_files(&path, ignores))
+    })
+}
+
+// Walk the working tree for files the index does not track. In normal mode a directory with
+// nothing tracked inside is reported once as "dir/"; nested repositories always are.
+fn collect_untracked(
+    directory: &str,
+    tracked: &HashSet<&str>,
+    tracked_directories: &HashSet<String>,
+    mode: UntrackedMode,
+    ignores: &mut Ignores,
+    found: &mut Vec<String>,
+) {
+    let Ok(entries) = fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if directory.is_empty() && name == ".git" {
+            continue;
+        }
+        let path = if directory.is_empty() { name } else { format!("{}/{}", directory, name) };
+        if tracked.contains(path.as_str()) {
+            continue;
+        }
+        let is_dir = entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+        if ignores.is_ignored(&path, is_dir) {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                if Path::new(&path).join(".git").exists() {
+                    found.push(format!("{}/", path));
+                } else if mode == UntrackedMode::Normal && !tracked_directories.contains(&path) {
+                    if has_files(&path, ignores) {
+                        found.push(format!("{}/", path));
+                    }
+                } else {
+                    collect_untracked(&path, tracked, tracked_directories, mode, ignores, found);
+                }
+            }
+            _ => found.push(path),
+        }
+    }
+}
+
+fn untracked_files(index: &[IndexEntry], mode: UntrackedMode, ignores: &mut Ignores) -> Vec<String> {
+    let tracked: HashSet<&str> = index.iter().map(|entry| entry.path.as_str()).collect();
+    let mut tracked_directories = HashSet::new();
+    for entry in index {
+        let mut parent = Path::new(&entry.path).parent();
+        while let Some(directory) = parent.filter(|x| !x.as_os_str().is_empty()) {
+            tracked_directories.insert(directory.to_string_lossy().into_owned());
+            parent = directory.parent();
+        }
+    }
+    let mut found = Vec::new();
+    collect_untracked("", &tracked, &tracked_directories, mode, ignores, &mut found);
+    found.sort();
+    found
+}
+
+// Upstream is the branch the current branch tracks, with its ahead/behind counts
+// or None when the upstream ref no longer exists
+struct Upstream {
+    name: String,
+    counts: Option<(usize, usize)>,
+}
+
+// Status gathers everything a status report needs, whatever the output format
+struct Status {
+    head: Option<String>,
+    branch: Option<String>,
+    upstream: Option<Upstream>,
+    staged: Vec<FileChange>,
+    unstaged: Vec<FileChange>,
+    unmerged: Vec<Unmerged>,
+    untracked: Vec<String>,
+    untracked_mode: UntrackedMode,
+}
+
+// Describe a detached HEAD from the last checkout recorded in its reflog: the ref that was
+// checked out if it still names the commit it did then, otherwise that commit's short id.
+// None when the reflog records no checkout.
+pub fn detached_description(head: Option<&str>) -> Option<String> {
+    let source = read_reflog("HEAD").into_iter().rev().find_map(|entry| {
+        let moved = entry.message.strip_prefix("checkout: moving from ")?;
+        moved.split_once(" to ").map(|(_, target)| (target.to_string(), entry.new_hash))
+    });
+    let (target, checked_out) = source?;
+    let name = expand_ref_name(&target)
+        .filter(|name| {
+            let hash = resolve_ref(name);
+            hash.as_deref() == Some(checked_out.as_str())
+                || hash.and_then(|hash| peel_to_commit(&hash).ok()).as_deref() == Some(checked_out.as_str())
+        })
+        .map(|name| {
+            let name = name.strip_prefix("refs/tags/").unwrap_or(&name);
+            name.strip_prefix("refs/remotes/").unwrap_or(name).to_string()
+        })
+        .filter(|_| target != "HEAD")
+        .unwrap_or_else(|| checked_out[..7].to_string());
+    let at = head == Some(checked_out.as_str());
+    Some(format!("HEAD detached {} {}", if at { "at" } else { "from" }, name))
+}
+
+fn tracking_lines(upstream: &Upstream) -> Vec<String> {
+    let name = &upstream.name;
+    let plural = |count: usize| if count == 1 { "commit" } else { "commits" };
+    match upstream.counts {
+        None => vec![
+            format!("Your branch is based on '{}', but the upstream is gone.", name),
+            "  (use \"git branch --unset-upstream\" to fixup)".to_string(),
+        ],
+        Some((0, 0)) => vec![format!("Your branch is up to date with '{}'.", name)],
+        Some((ahead, 0)) => vec![
+            format!("Your branch is ahead of '{}' by {} {}.", name, ahead, plural(ahead)),
+            "  (use \"git push\" to publish your local commits)".to_string(),
+        ],
+        Some((0, behind)) => vec![
+            format!("Your branch is behind '{}' by {} {}, and can be fast-forwarded.", name, behind, plural(behind)),
+            "  (use \"git pull\" to update your local branch)".to_string(),
+        ],
+        Some((ahead, behind)) => vec![
+            format!("Your branch and '{}' have diverged,", name),
+            format!("and have {} and {} different commits each, respectively.", ahead, behind),
+            "  (use \"git pull\" to merge the remote branch into yours)".to_string(),
+        ],
+    }
+}
+
+fn change_label(kind: ChangeKind) -> &'static str {
+    match kind {
+        ChangeKind::Added => "new file:",
+        ChangeKind::Deleted => "deleted:",
+        ChangeKind::Modified => "modified:",
+        ChangeKind::TypeChanged => "typechange:",
+        ChangeKind::Renamed => "renamed:",
+        ChangeKind::Copied => "copied:",
+        ChangeKind::Unmerged => "unmerged:",
+    }
+}
+
+// The cherry-pick or revert in progress, with the commit being replayed unless it is part of
+// a sequence
+fn replay_in_progress() -> Option<(&'static str, Option<String>)> {
+    let cherry_pick = read_ref("CHERRY_PICK_HEAD");
+    let revert = read_ref("REVERT_HEAD");
+    match sequence_command() {
+        Some("pick") => Some(("cherry-pick", None)),
+        _ if cherry_pick.is_some() => Some(("cherry-pick", cherry_pick)),
+        Some(_) => Some(("revert", None)),
+        None => revert.map(|commit| ("revert", Some(commit))),
+    }
+}
+
+// Whether a bisection is under way, and the branch it started from: a commit it started from
+// is abbreviated
+fn bisect_in_progress() -> Option<Option<String>> {
+    if !Path::new(".git/BISECT_LOG").exists() {
+        return None;
+    }
+    let start = fs::read_to_string(".git/BISECT_START").unwrap_or_default();
+    let start = start.trim_end_matches('\n');
+    let branch = if let Some(name) = start.strip_prefix("refs/heads/") {
+        name.to_string()
+    } else if start.len() == 40 && start.bytes().all(|x| x.is_ascii_hexdigit()) {
+        abbreviate(start)
+    } else {
+        start.to_string()
+    };
+    Some(Some(branch).filter(|x| !x.is_empty()))
+}
+
+// The last commands a rebase has done and the next it has left to do
+fn rebase_todo_lines(rebase: &RebaseProgress) -> Vec<String> {
+    let mut lines = Vec::new();
+    match rebase.done.len() {
+        0 => lines.push("No commands done.".to_string()),
+        1 => lines.push("Last command done (1 command done):".to_string()),
+        count => lines.push(format!("Last commands done ({} commands done):", count)),
+    }
+    lines.extend(rebase.done.iter().skip(rebase.done.len().saturating_sub(2)).map(|line| format!("   {}", line)));
+    if rebase.done.len() > 2 {
+        lines.push("  (see more in file .git/rebase-merge/done)".to_string());
+    }
+    match &rebase.todo {
+        None => lines.push("git-rebase-todo is missing.".to_string()),
+        Some(todo) if todo.is_empty() => lines.push("No commands remaining.".to_string()),
+        Some(todo) => {
+            match todo.len() {
+                1 => lines.push("Next command to do (1 remaining command):".to_string()),
+                count => lines.push(format!("Next commands to do ({} remaining commands):", count)),
+            }
+            lines.extend(todo.iter().take(2).map(|line| format!("   {}", line)));
+            lines.push("  (use \"git rebase --edit-todo\" to view and edit)".to_string());
+        }
+    }
+    lines
+}
+
+// What a rebase has done and has left to do, then what the user is expected to do next
+fn rebase_lines(rebase: &RebaseProgress, status: &Status) -> Vec<String> {
+    let mut lines = rebase_todo_lines(rebase);
+    let rebasing = match &rebase.branch {
+        Some(branch) => format!("You are currently rebasing branch '{}' on '{}'.", branch, rebase.onto),
+        None => "You are currently rebasing.".to_string(),
+    };
+    if !status.unmerged.is_empty() {
+        lines.push(rebasing);
+        lines.push("  (fix conflicts and then run \"git rebase --continue\")".to_string());
+        lines.push("  (use \"git rebase --skip\" to skip this patch)".to_string());
+        lines.push("  (use \"git rebase --abort\" to check out the original branch)".to_string());
+    } else if Path::new(".git/MERGE_MSG").exists() {
+        lines.push(rebasing);
+        lines.push("  (all conflicts fixed: run \"git rebase --continue\")".to_string());
+    } else {
+        match &rebase.branch {
+            Some(branch) => lines.push(format!("You are currently editing a commit while rebasing branch '{}' on '{}'.", branch, rebase.onto)),
+            None => lines.push("You are currently editing a commit during a rebase.".to_string()),
+        }
+        lines.push("  (use \"git commit --amend\" to amend the current commit)".to_string());
+        lines.push("  (use \"git rebase --continue\" once you are satisfied with your changes)".to_string());
+    }
+    lines.push(String::new());
+    lines
+}
+
+fn print_long(status: &Status) {
+    for line in long_status_lines(status, true) {
+        println!("{}", line);
+    }
+}
+
+// The lines of the long format. Without hints the advice in parentheses is left out, as it is
+// from the status shown in a commit message template.
+fn long_status_lines(status: &Status, hints: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    let rebase = rebase_progress();
+    match (&status.branch, &rebase) {
+        (Some(branch), _) => lines.push(format!("On branch {}", branch)),
+        (None, Some(rebase)) => lines.push(format!("interactive rebase in progress; onto {}", rebase.onto)),
+        (None, None) => lines.push(detached_description(status.head.as_deref()).unwrap_or_else(|| "Not currently on any branch.".to_string())),
+    }
+    if status.head.is_none() {
+        lines.extend(["".to_string(), "No commits yet".to_string(), "".to_string()]);
+    }
+    if let Some(upstream) = &status.upstream {
+        lines.extend(tracking_lines(upstream));
+        lines.push(String::new());
+    }
+
+    let merging = Path::new(".git/MERGE_HEAD").exists();
+    let from_commit = !merging && !Path::new(".git/CHERRY_PICK_HEAD").exists();
+    if let Some(rebase) = rebase.as_ref().filter(|_| !merging) {
+        lines.extend(rebase_lines(rebase, status));
+    } else if merging {
+        // A merge a rebase stopped at lists the rebase's progress before the merge state
+        if let Some(rebase) = &rebase {
+            lines.extend(rebase_todo_lines(rebase));
+            lines.push(String::new());
+        }
+        if status.unmerged.is_empty() {
+            lines.push("All conflicts fixed but you are still merging.".to_string());
+            lines.push("  (use \"git commit\" to conclude merge)".to_string());
+        } else {
+            lines.push("You have unmerged paths.".to_string());
+            lines.push("  (fix conflicts and run \"git commit\")".to_string());
+            lines.push("  (use \"git merge --abort\" to abort the merge)".to_string());
+        }
+        lines.push(String::new());
+    } else if let Some((command, commit)) = replay_in_progress() {
+        let (operation, verb) = match command {
+            "cherry-pick" => ("Cherry-pick", "cherry-picking"),
+            _ => ("Revert", "reverting"),
+        };
+        match &commit {
+            Some(commit) => lines.push(format!("You are currently {} commit {}.", verb, abbreviate(commit))),
+            None => lines.push(format!("{} currently in progress.", operation)),
+        }
+        if !status.unmerged.is_empty() {
+            lines.push(format!("  (fix conflicts and run \"git {} --continue\")", command));
+        } else if commit.is_none() {
+            lines.push(format!("  (run \"git {} --continue\" to continue)", command));
+        } else {
+            lines.push(format!("  (all conflicts fixed: run \"git {} --continue\")", command));
+        }
+        lines.push(format!("  (use \"git {} --skip\" to skip this patch)", command));
+        lines.push(format!("  (use \"git {} --abort\" to cancel the {} operation)", command, command));
+        lines.push(String::new());
+    }
+    if let Some(branch) = bisect_in_progress() {
+        match branch {
+            Some(branch) => lines.push(format!("You are currently bisecting, started from branch '{}'.", branch)),
+            None => lines.push("You are currently bisecting.".to_string()),
+        }
+        lines.push("  (use \"git bisect reset\" to get back to the original branch)".to_string());
+        lines.push(String::new());
+    }
+
+    let unstage_hint = if status.head.is_some() {
+        "  (use \"git restore --staged <file>...\" to unstage)"
+    } else {
+        "  (use \"git rm --cached <file>...\" to unstage)"
+    };
+    if !status.staged.is_empty() {
+        lines.push("Changes to be committed:".to_string());
+        if from_commit {
+            lines.push(unstage_hint.to_string());
+        }
+        for change in &status.staged {
+            let path = match change.kind {
+                ChangeKind::Renamed | ChangeKind::Copied => format!("{} -> {}", quote_path(&change.old_path, false), quote_path(&change.new_path, false)),
+                _ => quote_path(&change.new_path, false),
+            };
+            lines.push(format!("\t{:<12}{}", change_label(change.kind), path));
+        }
+        lines.push(String::new());
+    }
+
+    if !status.unmerged.is_empty() {
+        lines.push("Unmerged paths:".to_string());
+        if from_commit {
+            lines.push(unstage_hint.to_string());
+        }
+        let masks: Vec<usize> = status.unmerged.iter().map(|x| x.stage_mask()).collect();
+        let both_deleted = masks.contains(&1);
+        let deleted_modified = masks.contains(&3) || masks.contains(&5);
+        lines.push(
+            match (both_deleted, deleted_modified) {
+                (_, true) => "  (use \"git add/rm <file>...\" as appropriate to mark resolution)",
+                (true, false) => "  (use \"git rm <file>...\" to mark resolution)",
+                (false, false) => "  (use \"git add <file>...\" to mark resolution)",
+            }
+            .to_string(),
+        );
+        for conflict in &status.unmerged {
+            lines.push(format!("\t{:<17}{}", conflict.label(), quote_path(&conflict.path, false)));
+        }
+        lines.push(String::new());
+    }
+
+    if !status.unstaged.is_empty() {
+        lines.push("Changes not staged for commit:".to_string());
+        if status.unstaged.iter().any(|change| change.kind == ChangeKind::Deleted) {
+            lines.push("  (use \"git add/rm <file>...\" to update what will be committed)".to_string());
+        } else {
+            lines.push("  (use \"git add <file>...\" to update what will be committed)".to_string());
+        }
+        lines.push("  (use \"git restore <file>...\" to discard changes in working directory)".to_string());
+        for change in &status.unstaged {
+            lines.push(format!("\t{:<12}{}", change_label(change.kind), quote_path(&change.new_path, false)));
+        }
+        lines.push(String::new());
+    }
+
+    if !status.untracked.is_empty() {
+        lines.push("Untracked files:".to_string());
+        lines.push("  (use \"git add <file>...\" to include in what will be committed)".to_string());
+        for path in &status.untracked {
+            lines.push(format!("\t{}", quote_path(path, false)));
+        }
+        lines.push(String::new());
+    }
+
+    let committable = !status.staged.is_empty();
+    let workdir_dirty = !status.unstaged.is_empty() || !status.unmerged.is_empty();
+    if committable {
+        if status.untracked_mode == UntrackedMode::No {
+            lines.push("Untracked files not listed (use -u option to show untracked files)".to_string());
+        }
+    } else if workdir_dirty {
+        lines.push("no changes added to commit (use \"git add\" and/or \"git commit -a\")".to_string());
+    } else if !status.untracked.is_empty() {
+        lines.push("nothing added to commit but untracked files present (use \"git add\" to track)".to_string());
+    } else if status.head.is_none() {
+        lines.push("nothing to commit (create/copy files and use \"git add\" to track)".to_string());
+    } else if status.untracked_mode == UntrackedMode::No {
+        lines.push("nothing to commit (use -u to show untracked files)".to_string());
+    } else {
+        lines.push("nothing to commit, working tree clean".to_string());
+    }
+    if !hints {
+        lines.retain(|line| !line.starts_with("  ("));
+    }
+    lines
+}
+
+// Staged and unstaged changes of each tracked path, keyed by the path in the index
+fn changes_by_path(status: &Status) -> BTreeMap<&str, (Option<&FileChange>, Option<&FileChange>)> {
+    let mut changes: BTreeMap<&str, (Option<&FileChange>, Option<&FileChange>)> = BTreeMap::new();
+    for change in &status.staged {
+        changes.entry(&change.new_path).or_default().0 = Some(change);
+    }
+    for change in &status.unstaged {
+        changes.entry(&change.new_path).or_default().1 = Some(change);
+    }
+    changes
+}
+
+fn print_short(status: &Status, null_terminated: bool) {
+    let terminator = if null_terminated { '\0' } else { '\n' };
+    let quote = |path: &str| if null_terminated { path.to_string() } else { quote_path(path, true) };
+    let mut lines: Vec<(&str, String)> = Vec::new();
+    for (path, (staged, unstaged)) in changes_by_path(status) {
+        let x = staged.map(|change| change.kind.letter()).unwrap_or(' ');
+        let y = unstaged.map(|change| change.kind.letter()).unwrap_or(' ');
+        let shown = match staged.filter(|change| change.is_rename()) {
+            Some(change) if null_terminated => format!("{}\0{}", path, change.old_path),
+            Some(change) => format!("{} -> {}", quote(&change.old_path), quote(path)),
+            None => quote(path),
+        };
+        lines.push((path, format!("{}{} {}", x, y, shown)));
+    }
+    for conflict in &status.unmerged {
+        lines.push((&conflict.path, format!("{} {}", conflict.code(), quote(&conflict.path))));
+    }
+    lines.sort_by(|a, b| a.0.cmp(b.0));
+    for (_, line) in lines {
+        print!("{}{}", line, terminator);
+    }
+    for path in &status.untracked {
+        print!("?? {}{}", quote(path), terminator);
+    }
+}
+
+fn print_short_branch(status: &Status, null_terminated: bool) {
+    let terminator = if null_terminated { '\0' } else { '\n' };
+    let mut header = match (&status.branch, &status.head) {
+        (Some(branch), None) => format!("## No commits yet on {}", branch),
+        (Some(branch), Some(_)) => format!("## {}", branch),
+        (None, _) => "## HEAD (no branch)".to_string(),
+    };
+    if let Some(upstream) = &status.upstream {
+        header.push_str(&format!("...{}", upstream.name));
+        match upstream.counts {
+            None => header.push_str(" [gone]"),
+            Some((0, 0)) => {}
+            Some((ahead, 0)) => header.push_str(&format!(" [ahead {}]", ahead)),
+            Some((0, behind)) => header.push_str(&format!(" [behind {}]", behind)),
+            Some((ahead, behind)) => header.push_str(&format!(" [ahead {}, behind {}]", ahead, behind)),
+        }
+    }
+    print!("{}{}", header, terminator);
+}
+
+fn worktree_mode(path: &str) -> u32 {
+    fs::symlink_metadata(path).map(|metadata| mode_from_metadata(&metadata)).unwrap_or(0)
+}
+
+fn print_porcelain_v2(status: &Status, show_branch: bool, null_terminated: bool) {
+    let terminator = if null_terminated { '\0' } else { '\n' };
+    let quote = |path: &str| if null_terminated { path.to_string() } else { quote_path(path, false) };
+    if show_branch {
+        print!("# branch.oid {}{}", status.head.as_deref().unwrap_or("(initial)"), terminator);
+        print!("# branch.head {}{}", status.branch.as_deref().unwrap_or("(detached)"), terminator);
+        if let Some(upstream) = &status.upstream {
+            print!("# branch.upstream {}{}", upstream.name, terminator);
+            if let Some((ahead, behind)) = upstream.counts {
+                print!("# branch.ab +{} -{}{}", ahead, behind, terminator);
+            }
+        }
+    }
+
+    // Ordinary changes come first, conflicts after them
+    let mut lines = Vec::new();
+    for (path, (staged, unstaged)) in changes_by_path(status) {
+        let x = staged.map(|change| change.kind.letter()).unwrap_or('.');
+        let y = unstaged.map(|change| change.kind.letter()).unwrap_or('.');
+        let (head_mode, head_hash, index_mode, index_hash) = match (staged, unstaged) {
+            (Some(staged), _) => (staged.old_mode, &staged.old_hash, staged.new_mode, &staged.new_hash),
+            (None, Some(unstaged)) => (unstaged.old_mode, &unstaged.old_hash, unstaged.old_mode, &unstaged.old_hash),
+            (None, None) => continue,
+        };
+        let worktree_mode = match unstaged {
+            Some(unstaged) => unstaged.new_mode,
+            None => index_mode,
+        };
+        let fields = format!("{}{} N... {:06o} {:06o} {:06o} {} {}", x, y, head_mode, index_mode, worktree_mode, head_hash, index_hash);
+        let line = match staged.filter(|change| change.is_rename()) {
+            Some(change) => format!(
+                "2 {} {}{} {}{}{}",
+                fields,
+                change.kind.letter(),
+                change.score,
+                quote(path),
+                if null_terminated { '\0' } else { '\t' },
+                quote(&change.old_path)
+            ),
+            None => format!("1 {} {}", fields, quote(path)),
+        };
+        lines.push(line);
+    }
+    for conflict in &status.unmerged {
+        let modes: Vec<String> = conflict.stages.iter().map(|x| format!("{:06o}", x.as_ref().map(|x| x.0).unwrap_or(0))).collect();
+        let hashes: Vec<&str> = conflict.stages.iter().map(|x| x.as_ref().map(|x| x.1.as_str()).unwrap_or(NULL_HASH)).collect();
+        let line = format!(
+            "u {} N... {} {:06o} {} {}",
+            conflict.code(),
+            modes.join(" "),
+            worktree_mode(&conflict.path),
+            hashes.join(" "),
+            quote(&conflict.path)
+        );
+        lines.push(line);
+    }
+    for line in lines {
+        print!("{}{}", line, terminator);
+    }
+    for path in &status.untracked {
+        print!("? {}{}", quote(path), terminator);
+    }
+}
+
+fn collect_status(config: &Config, renames: &Renames, pathspecs: &[String], untracked_mode: UntrackedMode) -> anyhow::Result<Status> {
+    let head = resolve_ref("HEAD");
+    let branch = head_symbolic_target().map(|target| target.strip_prefix("refs/heads/").unwrap_or(&target).to_string());
+
+    let upstream = match (&branch, &head) {
+        (Some(branch), Some(head)) => match branch_upstream(config, branch) {
+            Some(upstream) => Some(Upstream {
+                name: shorten_ref_name(&upstream).to_string(),
+                counts: match resolve_ref(&upstream) {
+                    Some(hash) => Some(count_ahead_behind(head, &hash)?),
+                    None => None,
+                },
+            }),
+            None => None,
+        },
+        _ => None,
+    };
+
+    let head_entries = match &head {
+        Some(head) => flatten_tree(&peel_to_tree(head)?)?,
+        None => BTreeMap::new(),
+    };
+    let mut index = read_index()?;
+    let selected = |path: &str| pathspecs.is_empty() || pathspecs.iter().any(|spec| pathspec_matches(spec, path.trim_end_matches('/')));
+    let mut staged = diff_tree_to_index(&head_entries, &index);
+    staged.retain(|change| selected(&change.new_path));
+    let staged = renames.detect(staged, Vec::new())?;
+    let (unstaged, refreshed) = diff_index_to_worktree(&mut index);
+    if refreshed {
+        // Saving the fresh stat data is only an optimisation, so a busy index is not an error
+        let _ = write_index(&index);
+    }
+    let unmerged = unmerged_entries(&index);
+    let untracked = match untracked_mode {
+        UntrackedMode::No => Vec::new(),
+        _ => untracked_files(&index, untracked_mode, &mut Ignores::new(config)),
+    };
+
+    Ok(Status {
+        head,
+        branch,
+        upstream,
+        staged,
+        unstaged: unstaged.into_iter().filter(|change| selected(&change.new_path)).collect(),
+        unmerged: unmerged.into_iter().filter(|conflict| selected(&conflict.path)).collect(),
+        untracked: untracked.into_iter().filter(|path| selected(path)).collect(),
+        untracked_mode,
+    })
+}
+
+// Print the long status of the repository as configured, as a command that stops to let
+// the user look at the state it left behind does
+pub fn print_long_status(config: &Config) -> anyhow::Result<()> {
+    let untracked_mode = match config.get("status.showUntrackedFiles") {
+        Some(value) => parse_untracked_mode(value)?,
+        None => UntrackedMode::Normal,
+    };
+    print_long(&collect_status(config, &Renames::for_status(config), &[], untracked_mode)?);
+    Ok(())
+}
+
+// The status as a commit message template shows it, commented out and with the staged changes
+// taken against the parent the commit will have
+pub fn commit_template_status(config: &Config, parent: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let renames = Renames::for_status(config);
+    let mut status = collect_status(config, &renames, &[], UntrackedMode::Normal)?;
+    let parent_entries = match parent {
+        Some(parent) => flatten_tree(&peel_to_tree(parent)?)?,
+        None => BTreeMap::new(),
+    };
+    status.staged = renames.detect(diff_tree_to_index(&parent_entries, &read_index()?), Vec::new())?;
+    Ok(long_status_lines(&status, false)
+        .into_iter()
+        .map(|line| match line.as_str() {
+            "" => "#".to_string(),
+            _ if line.starts_with('\t') => format!("#{}", line),
+            _ => format!("# {}", line),
+        })
+        .collect())
+}
+
+fn status_usage() -> ! {
+    eprintln!("usage: git status [<options>] [--] [<pathspec>...]");
+    eprintln!();
+    std::process::exit(129);
+}
+
+pub fn status_command(args: &[String]) -> anyhow::Result<()> {
+    let command_index = args.iter().position(|x| x == "status").unwrap();
+    let config = read_config();
+    let mut format = None;
+    let mut show_branch = false;
+    let mut null_terminated = false;
+    let mut untracked_mode = match config.get("status.showUntrackedFiles") {
+        Some(value) => parse_untracked_mode(value)?,
+        None => UntrackedMode::Normal,
+    };
+    let mut pathspecs = Vec::new();
+
+    // Split bundled short flags such as "-sb" into separate options
+    let mut options = Vec::new();
+    for arg in &args[command_index + 1..] {
+        if arg.len() > 2 && arg.starts_with('-') && arg[1..].chars().all(|x| "sbz".contains(x)) {
+            options.extend(arg[1..].chars().map(|x| format!("-{}", x)));
+        } else {
+            options.push(arg.clone());
+        }
+    }
+
+    let mut renames = Renames::for_status(&config);
+    let mut dashdash = false;
+    for arg in &options {
+        if dashdash {
+            pathspecs.push(arg.clone());
+            continue;
+        }
+        if arg == "--no-renames" || arg.starts_with("-M") || arg.starts_with("--find-renames") {
+            renames.parse_option(arg)?;
+            continue;
+        }
+        match arg.as_str() {
+            "-s" | "--short" => format = Some(Format::Short),
+            "--long" => format = Some(Format::Long),
+            "--porcelain" | "--porcelain=v1" | "--porcelain=1" => format = Some(Format::PorcelainV1),
+            "--porcelain=v2" | "--porcelain=2" => format = Some(Format::PorcelainV2),
+            "-b" | "--branch" => show_branch = true,
+            "--no-branch" => show_branch = false,
+            "-z" => null_terminated = true,
+            "-u" | "--untracked-files" => untracked_mode = UntrackedMode::All,
+            "-h" => status_usage(),
+            "--" => dashdash = true,
+            arg if arg.starts_with("--porcelain=") => anyhow::bail!("unsupported porcelain version '{}'", &arg["--porcelain=".len()..]),
+            arg if arg.starts_with("--untracked-files=") => {
+                untracked_mode = parse_untracked_mode(&arg["--untracked-files=".len()..])?
+            }
+            arg if arg.starts_with("-u") => untracked_mode = parse_untracked_mode(&arg[2..])?,
+            arg if arg.starts_with("--") => {
+                eprintln!("error: unknown option `{}'", &arg[2..]);
+                status_usage();
+            }
+            arg if arg.len() > 1 && arg.starts_with('-') => {
+                let flag = arg[1..].chars().find(|x| !"sbz".contains(*x)).unwrap_or_default();
+                eprintln!("error: unknown switch `{}'", flag);
+                status_usage();
+            }
+            _ => pathspecs.push(arg.clone()),
+        }
+    }
+    // Machine readable output is implied by -z unless a format was chosen
+    let format = match format {
+        Some(format) => format,
+        None if null_terminated => Format::PorcelainV1,
+        None => Format::Long,
+    };
+
+    let status = collect_status(&config, &renames, &pathspecs, untracked_mode)?;
+    match format {
+        Format::Long => print_long(&status),
+        Format::Short | Format::PorcelainV1 => {
+            if show_branch {
+                print_short_branch(&status, null_terminated);
+            }
+            print_short(&status, null_terminated);
+        }
+        Format::PorcelainV2 => print_porcelain_v2(&status, show_branch, null_terminated),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::read_config_file;
+    use crate::testrepo::{blob, commit, repository, tree};
+
+    fn stage(path: &str, stage: u16) -> IndexEntry {
+        let mut entry = IndexEntry::without_stat(path, 0o100644, &"1".repeat(40));
+        entry.flags = stage << 12;
+        entry
+    }
+
+    #[test]
+    fn unmerged_codes() {
+        let index = [stage("both", 1), stage("both", 2), stage("both", 3), stage("ours", 2), stage("theirs", 3), stage("kept", 0)];
+        let unmerged = unmerged_entries(&index);
+        let codes: Vec<_> = unmerged.iter().map(|x| (x.path.as_str(), x.code(), x.label())).collect();
+        assert_eq!(codes, [("both", "UU", "both modified:"), ("ours", "AU", "added by us:"), ("theirs", "UA", "added by them:")]);
+        let deleted = unmerged_entries(&[stage("a", 1), stage("b", 1), stage("b", 2), stage("c", 1), stage("c", 3)]);
+        assert_eq!(deleted.iter().map(|x| x.code()).collect::<Vec<_>>(), ["DD", "UD", "DU"]);
+    }
+
+    #[test]
+    fn tracking() {
+        let upstream = |counts| Upstream { name: "origin/main".to_string(), counts };
+        assert_eq!(tracking_lines(&upstream(Some((0, 0)))), ["Your branch is up to date with 'origin/main'."]);
+        assert_eq!(tracking_lines(&upstream(Some((1, 0))))[0], "Your branch is ahead of 'origin/main' by 1 commit.");
+        assert_eq!(tracking_lines(&upstream(Some((0, 2))))[0], "Your branch is behind 'origin/main' by 2 commits, and can be fast-forwarded.");
+        assert_eq!(tracking_lines(&upstream(Some((1, 2))))[1], "and have 1 and 2 different commits each, respectively.");
+        assert_eq!(tracking_lines(&upstream(None))[0], "Your branch is based on 'origin/main', but the upstream is gone.");
+    }
+
+    #[test]
+    fn long_status() {
+        let _repository = repository();
+        let head = commit(&tree(&[("a", "1\n"), ("b", "1\n"), ("c", "1\n")]), &[], 1, "base");
+        fs::write(".git/refs/heads/main", format!("{}\n", head)).unwrap();
+        let index: Vec<IndexEntry> =
+            [("a", "2\n"), ("b", "1\n"), ("d", "new\n")].iter().map(|(path, contents)| IndexEntry::without_stat(path, 0o100644, &blob(contents))).collect();
+        write_index(&index).unwrap();
+        for (path, contents) in [("a", "2\n"), ("b", "changed\n"), ("d", "new\n"), ("u", "u\n"), ("dir/x", "x\n")] {
+            fs::create_dir_all(Path::new(path).parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        fs::create_dir("empty").unwrap();
+
+        let config = read_config_file(".git/config");
+        let status = collect_status(&config, &Renames::for_status(&config), &[], UntrackedMode::Normal).unwrap();
+        let expected = [
+            "On branch main",
+            "Changes to be committed:",
+            "  (use \"git restore --staged <file>...\" to unstage)",
+            "\tmodified:   a",
+            "\tdeleted:    c",
+            "\tnew file:   d",
+            "",
+            "Changes not staged for commit:",
+            "  (use \"git add <file>...\" to update what will be committed)",
+            "  (use \"git restore <file>...\" to discard changes in working directory)",
+            "\tmodified:   b",
+            "",
+            "Untracked files:",
+            "  (use \"git add <file>...\" to include in what will be committed)",
+            "\tdir/",
+            "\tu",
+            "",
+        ];
+        assert_eq!(long_status_lines(&status, true), expected);
+        let status = collect_status(&config, &Renames::for_status(&config), &[], UntrackedMode::All).unwrap();
+        assert_eq!(status.untracked, ["dir/x", "u"]);
+        let status = collect_status(&config, &Renames::for_status(&config), &["d".to_string()], UntrackedMode::No).unwrap();
+        assert_eq!((status.staged.len(), status.unstaged.len(), status.untracked.len()), (1, 0, 0));
+    }
+}
@@ -0,0 +1,59 @@
+// A scratch repository for tests of code that reads and writes objects and refs. Those are
+// found through ".git" in the current directory, which every test thread shares, so the
+// repository is made afresh and entered once a run and each test holds a lock while it uses it.
+
+use crate::worktree::{write_tree, TreeEntry};
+use crate::write_object_to_store;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+static LOCK: Mutex<()> = Mutex::new(());
+static ENTERED: OnceLock<()> = OnceLock::new();
+
+// Take the repository for a test, with objects left from earlier tests but no refs, index or
+// files, and HEAD on an unborn main
+pub fn repository() -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    ENTERED.get_or_init(|| {
+        let path = std::env::temp_dir().join("git-starter-rust-tests");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join(".git/objects")).unwrap();
+        std::env::set_current_dir(&path).unwrap();
+    });
+    for entry in fs::read_dir(".").unwrap().chain(fs::read_dir(".git").unwrap()) {
+        let path = entry.unwrap().path();
+        if path.ends_with(".git") || path.ends_with("objects") {
+            continue;
+        }
+        if path.is_dir() {
+            fs::remove_dir_all(path).unwrap();
+        } else {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    fs::create_dir_all(".git/refs/heads").unwrap();
+    fs::create_dir_all(".git/refs/tags").unwrap();
+    fs::write(".git/HEAD", "ref: refs/heads/main\n").unwrap();
+    guard
+}
+
+pub fn blob(contents: &str) -> String {
+    write_object_to_store(contents.as_bytes(), b"blob")
+}
+
+// Write a tree of regular files from their paths and contents
+pub fn tree(files: &[(&str, &str)]) -> String {
+    let entries: BTreeMap<String, TreeEntry> =
+        files.iter().map(|(path, contents)| (path.to_string(), TreeEntry { mode: 0o100644, sha_hash: blob(contents) })).collect();
+    write_tree(&entries)
+}
+
+// Write a commit made at a given time, so tests control the order walks see commits in
+pub fn commit(tree: &str, parents: &[&str], time: i64, message: &str) -> String {
+    let mut lines = vec![format!("tree {}", tree)];
+    lines.extend(parents.iter().map(|parent| format!("parent {}", parent)));
+    lines.push(format!("author A U Thor <author@example.com> {} +0000", time));
+    lines.push(format!("committer C O Mitter <committer@example.com> {} +0000", time));
+    write_object_to_store(format!("{}\n\n{}\n", lines.join("\n"), message).as_bytes(), b"commit")
+}
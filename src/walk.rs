@@ -0,0 +1,533 @@
+use crate::revision::objects_with_prefix;
+use crate::{read_object_from_store, GitObjectType};
+use regex::Regex;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::rc::Rc;
+
+// Signature is a parsed author or committer line: "Name <email> 1700000000 +0200"
+#[derive(Clone, Debug, Default)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: i64,
+    pub offset: String,
+}
+
+impl Signature {
+    pub fn parse(value: &str) -> Signature {
+        let (name, rest) = value.split_once('<').unwrap_or((value, ""));
+        let (email, rest) = rest.split_once('>').unwrap_or((rest, ""));
+        let mut fields = rest.split_whitespace();
+        Signature {
+            name: name.trim().to_string(),
+            email: email.to_string(),
+            time: fields.next().and_then(|x| x.parse().ok()).unwrap_or(0),
+            offset: fields.next().unwrap_or("+0000").to_string(),
+        }
+    }
+
+    // The "Name <email>" part of the line
+    pub fn identity(&self) -> String {
+        format!("{} <{}>", self.name, self.email)
+    }
+}
+
+// Commit is a parsed commit object
+#[derive(Clone, Debug)]
+pub struct Commit {
+    pub hash: String,
+    pub tree: String,
+    pub parents: Vec<String>,
+    pub author: Signature,
+    pub committer: Signature,
+    pub raw_header: String,
+    pub message: String,
+}
+
+impl Commit {
+    // First paragraph of the message joined onto one line
+    pub fn subject(&self) -> String {
+        let message = self.message.trim_start_matches('\n');
+        let paragraph = message.split("\n\n").next().unwrap_or("");
+        paragraph.lines().map(|line| line.trim()).collect::<Vec<_>>().join(" ")
+    }
+
+    // Everything after the first paragraph of the message
+    pub fn body(&self) -> &str {
+        let message = self.message.trim_start_matches('\n');
+        match message.split_once("\n\n") {
+            Some((_, body)) => body.trim_start_matches('\n'),
+            None => "",
+        }
+    }
+}
+
+pub fn load_commit(hash: &str) -> anyhow::Result<Commit> {
+    let commit = match read_object_from_store(hash)? {
+        GitObjectType::Commit(commit) => commit,
+        _ => anyhow::bail!("{} is not a commit", hash),
+    };
+    let (header, message) = commit.commit_data.split_once("\n\n").unwrap_or((&commit.commit_data, ""));
+    let value = |name: &str| {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix(' ')))
+            .unwrap_or("")
+            .to_string()
+    };
+    Ok(Commit {
+        hash: hash.to_string(),
+        tree: value("tree"),
+        parents: commit.parent_hashes().into_iter().map(|x| x.to_string()).collect(),
+        author: Signature::parse(&value("author")),
+        committer: Signature::parse(&value("committer")),
+        raw_header: header.to_string(),
+        message: message.to_string(),
+    })
+}
+
+// Shortest unambiguous prefix of an object id, at least seven characters long
+pub fn abbreviate(hash: &str) -> String {
+    abbreviate_to(hash, 7)
+}
+
+// Shortest unambiguous prefix of an object id with at least the given length
+pub fn abbreviate_to(hash: &str, length: usize) -> String {
+    let mut length = length.clamp(4, hash.len());
+    while length < hash.len() && objects_with_prefix(&hash[..length]).len() > 1 {
+        length += 1;
+    }
+    hash[..length].to_string()
+}
+
+// Sort is the order commits are emitted in once the walk is sorted topologically
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sort {
+    Graph,
+    CommitDate,
+    AuthorDate,
+}
+
+// Filter selects commits by message, identity, date and number of parents
+#[derive(Default)]
+pub struct Filter {
+    pub authors: Vec<Regex>,
+    pub committers: Vec<Regex>,
+    pub grep: Vec<Regex>,
+    pub all_match: bool,
+    pub invert_grep: bool,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub min_parents: usize,
+    pub max_parents: Option<usize>,
+}
+
+impl Filter {
+    pub fn matches(&self, commit: &Commit) -> bool {
+        if self.since.map(|since| commit.committer.time < since).unwrap_or(false)
+            || self.until.map(|until| commit.committer.time > until).unwrap_or(false)
+            || commit.parents.len() < self.min_parents
+            || self.max_parents.map(|max| commit.parents.len() > max).unwrap_or(false)
+        {
+            return false;
+        }
+        let author = commit.author.identity();
+        let committer = commit.committer.identity();
+        if !self.authors.is_empty() && !self.authors.iter().any(|x| x.is_match(&author)) {
+            return false;
+        }
+        if !self.committers.is_empty() && !self.committers.iter().any(|x| x.is_match(&committer)) {
+            return false;
+        }
+        if self.grep.is_empty() {
+            return true;
+        }
+        let matched = if self.all_match {
+            self.grep.iter().all(|x| x.is_match(&commit.message))
+        } else {
+            self.grep.iter().any(|x| x.is_match(&commit.message))
+        };
+        matched != self.invert_grep
+    }
+}
+
+// Turn a POSIX basic regular expression into the extended syntax the regex crate expects:
+// "\+", "\?", "\|", "\(", "\)", "\{" and "\}" are operators, their bare forms are literals
+pub fn basic_to_extended(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(x) = chars.next() {
+        match x {
+            '\\' => match chars.next() {
+                Some(next) if "+?|(){}".contains(next) => result.push(next),
+                Some(next) => {
+                    result.push('\\');
+                    result.push(next);
+                }
+                None => result.push_str("\\\\"),
+            },
+            '+' | '?' | '|' | '(' | ')' | '{' | '}' => {
+                result.push('\\');
+                result.push(x);
+            }
+            _ => result.push(x),
+        }
+    }
+    result
+}
+
+// Walk lists the commits reachable from the included tips but not the excluded ones,
+// simplifying history to the commits that change the given paths
+#[derive(Default)]
+pub struct Walk {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub first_parent: bool,
+    pub ancestry_path: bool,
+    pub topo_order: bool,
+    pub sort: Option<Sort>,
+    pub paths: Vec<String>,
+    commits: HashMap<String, Rc<Commit>>,
+    parents: HashMap<String, Vec<String>>,
+    rewritten: HashMap<String, Vec<String>>,
+    hidden: HashSet<String>,
+    uninteresting: HashSet<String>,
+}
+
+// Entry in the date ordered queue: newest first, ties in insertion order
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Queued {
+    time: i64,
+    sequence: std::cmp::Reverse<usize>,
+    hash: String,
+}
+
+impl Walk {
+    pub fn commit(&mut self, hash: &str) -> anyhow::Result<Rc<Commit>> {
+        if let Some(commit) = self.commits.get(hash) {
+            return Ok(commit.clone());
+        }
+        let commit = Rc::new(load_commit(hash)?);
+        self.commits.insert(hash.to_string(), commit.clone());
+        Ok(commit)
+    }
+
+    // Parents as seen by the walk: only the first with --first-parent, and only the parent
+    // history was simplified to when a path limited walk found the commit unchanged
+    pub fn parents(&self, hash: &str) -> &[String] {
+        self.parents.get(hash).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    // Parents as git records them after path limiting simplified history, which --first-parent
+    // leaves alone
+    pub fn rewritten_parents(&self, hash: &str) -> &[String] {
+        self.rewritten.get(hash).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    // Whether path limiting left a commit out because it changes none of the paths
+    pub fn is_hidden(&self, hash: &str) -> bool {
+        self.hidden.contains(hash)
+    }
+
+    // Whether a commit is reachable from the excluded tips, once the walk has run
+    pub fn is_uninteresting(&self, hash: &str) -> bool {
+        self.uninteresting.contains(hash)
+    }
+
+    // Every commit reachable from the excluded tips
+    fn uninteresting(&mut self) -> anyhow::Result<HashSet<String>> {
+        let mut seen = HashSet::new();
+        let mut pending = self.exclude.clone();
+        while let Some(hash) = pending.pop() {
+            if seen.insert(hash.clone()) {
+                pending.extend(self.commit(&hash)?.parents.iter().cloned());
+            }
+        }
+        Ok(seen)
+    }
+
+    // Check whether two trees agree on every path the walk is limited to
+    fn same_in_paths(&self, old_tree: Option<&str>, new_tree: Option<&str>, prefix: &str) -> anyhow::Result<bool> {
+        if old_tree == new_tree {
+            return Ok(true);
+        }
+        let leaves = |tree: Option<&str>| -> anyhow::Result<HashMap<String, (bool, String)>> {
+            let Some(tree) = tree else {
+                return Ok(HashMap::new());
+            };
+            match read_object_from_store(tree)? {
+                GitObjectType::Tree(tree) => Ok(tree
+                    .leaves
+                    .into_iter()
+                    .map(|leaf| (leaf.path, (leaf.mode == b"040000", format!("{}{}", String::from_utf8_lossy(&leaf.mode), leaf.sha_hash))))
+                    .collect()),
+                _ => anyhow::bail!("{} is not a tree", tree),
+            }
+        };
+        let old_leaves = leaves(old_tree)?;
+        let new_leaves = leaves(new_tree)?;
+        let mut names: Vec<&String> = old_leaves.keys().chain(new_leaves.keys()).collect();
+        names.sort();
+        names.dedup();
+        for name in names {
+            let path = format!("{}{}", prefix, name);
+            let old = old_leaves.get(name);
+            let new = new_leaves.get(name);
+            let selected = self.paths.iter().any(|spec| spec == "." || spec.is_empty() || path == *spec || path.starts_with(&format!("{}/", spec)));
+            if selected {
+                if old.map(|x| &x.1) != new.map(|x| &x.1) {
+                    return Ok(false);
+                }
+                continue;
+            }
+            let leads_to_path = self.paths.iter().any(|spec| spec.starts_with(&format!("{}/", path)));
+            if leads_to_path {
+                let subtree = |entry: Option<&(bool, String)>| entry.filter(|x| x.0).map(|x| x.1[6..].to_string());
+                let old_subtree = subtree(old);
+                let new_subtree = subtree(new);
+                if !self.same_in_paths(old_subtree.as_deref(), new_subtree.as_deref(), &format!("{}/", path))? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Decide which parents of a commit the walk follows, and whether path limiting hides it.
+    // A commit the same as one of its parents is simplified down to just that parent.
+    fn simplify(&mut self, commit: &Commit, uninteresting: &HashSet<String>) -> anyhow::Result<(Vec<String>, bool, bool)> {
+        let parents: Vec<String> = if self.first_parent {
+            commit.parents.iter().take(1).cloned().collect()
+        } else {
+            commit.parents.clone()
+        };
+        if self.paths.is_empty() {
+            return Ok((parents, false, false));
+        }
+        if parents.is_empty() {
+            return Ok((parents, self.same_in_paths(None, Some(&commit.tree), "")?, false));
+        }
+        let mut tree_changed = false;
+        for parent in &parents {
+            let parent_tree = self.commit(parent)?.tree.clone();
+            if self.same_in_paths(Some(&parent_tree), Some(&commit.tree), "")? {
+                if !uninteresting.contains(parent) {
+                    return Ok((vec![parent.clone()], true, true));
+                }
+            } else {
+                tree_changed = true;
+            }
+        }
+        Ok((parents, !tree_changed, false))
+    }
+
+    // Run the walk, returning the commits in output order. Commits hidden by path
+    // limiting are included so callers can rewrite parents through them.
+    pub fn run(&mut self) -> anyhow::Result<Vec<String>> {
+        let uninteresting = self.uninteresting()?;
+        let mut queue = BinaryHeap::new();
+        let mut sequence = 0;
+        let mut queued = HashSet::new();
+        for tip in self.include.clone() {
+            if queued.insert(tip.clone()) {
+                let time = self.commit(&tip)?.committer.time;
+                queue.push(Queued { time, sequence: std::cmp::Reverse(sequence), hash: tip });
+                sequence += 1;
+            }
+        }
+
+        let mut list = Vec::new();
+        while let Some(Queued { hash, .. }) = queue.pop() {
+            if uninteresting.contains(&hash) {
+                continue;
+            }
+            let commit = self.commit(&hash)?;
+            let (parents, hidden, simplified) = self.simplify(&commit, &uninteresting)?;
+            for parent in &parents {
+                if queued.insert(parent.clone()) {
+                    let time = self.commit(parent)?.committer.time;
+                    queue.push(Queued { time, sequence: std::cmp::Reverse(sequence), hash: parent.clone() });
+                    sequence += 1;
+                }
+            }
+            if hidden {
+                self.hidden.insert(hash.clone());
+            }
+            // Sorting still sees every parent of a commit when only the first is followed
+            let rewritten = if simplified { parents.clone() } else { commit.parents.clone() };
+            self.rewritten.insert(hash.clone(), rewritten);
+            self.parents.insert(hash.clone(), parents);
+            list.push(hash);
+        }
+
+        if self.ancestry_path {
+            list = self.limit_to_ancestry_path(list);
+        }
+        if self.topo_order || self.sort.is_some() {
+            list = self.sort_topologically(list)?;
+        }
+        self.uninteresting = uninteresting;
+        Ok(list)
+    }
+
+    // List just the included commits without going on to their parents, as show does. Path
+    // limiting still hides those that change none of the paths.
+    pub fn run_no_walk(&mut self) -> anyhow::Result<Vec<String>> {
+        let uninteresting = HashSet::new();
+        for hash in self.include.clone() {
+            let commit = self.commit(&hash)?;
+            let (parents, hidden, _) = self.simplify(&commit, &uninteresting)?;
+            if hidden {
+                self.hidden.insert(hash.clone());
+            }
+            self.rewritten.insert(hash.clone(), commit.parents.clone());
+            self.parents.insert(hash, parents);
+        }
+        Ok(self.include.clone())
+    }
+
+    // Keep only commits that descend from one of the excluded commits
+    fn limit_to_ancestry_path(&self, list: Vec<String>) -> Vec<String> {
+        let bottoms: HashSet<&String> = self.exclude.iter().collect();
+        let mut on_path: HashSet<String> = HashSet::new();
+        // Parents come later in the list than their children, so walk it backwards
+        for hash in list.iter().rev() {
+            let commit = &self.commits[hash];
+            if commit.parents.iter().any(|parent| bottoms.contains(parent) || on_path.contains(parent)) {
+                on_path.insert(hash.clone());
+            }
+        }
+        list.into_iter().filter(|hash| on_path.contains(hash)).collect()
+    }
+
+    // Order commits so that children always come before their parents. By default a branch
+    // is shown to its end before moving on to the next, with --date-order the newest
+    // available commit is taken next.
+    fn sort_topologically(&mut self, list: Vec<String>) -> anyhow::Result<Vec<String>> {
+        let mut indegree: HashMap<String, usize> = list.iter().map(|hash| (hash.clone(), 1)).collect();
+        for hash in &list {
+            for parent in &self.rewritten[hash] {
+                if let Some(degree) = indegree.get_mut(parent) {
+                    *degree += 1;
+                }
+            }
+        }
+        let sort = self.sort.unwrap_or(Sort::Graph);
+        let date = |walk: &mut Walk, hash: &str| -> anyhow::Result<i64> {
+            let commit = walk.commit(hash)?;
+            Ok(if sort == Sort::AuthorDate { commit.author.time } else { commit.committer.time })
+        };
+
+        let mut stack: Vec<String> = Vec::new();
+        let mut queue = BinaryHeap::new();
+        let mut sequence = 0;
+        for hash in list.iter().filter(|hash| indegree[*hash] == 1) {
+            if sort == Sort::Graph {
+                stack.push(hash.clone());
+            } else {
+                queue.push(Queued { time: date(self, hash)?, sequence: std::cmp::Reverse(sequence), hash: hash.clone() });
+                sequence += 1;
+            }
+        }
+        // The tips are taken in their original order from the end of the stack
+        stack.reverse();
+
+        let mut sorted = Vec::new();
+        loop {
+            let hash = match sort {
+                Sort::Graph => stack.pop(),
+                _ => queue.pop().map(|x| x.hash),
+            };
+            let Some(hash) = hash else {
+                break;
+            };
+            for parent in self.rewritten[&hash].clone() {
+                let Some(degree) = indegree.get_mut(&parent) else {
+                    continue;
+                };
+                if *degree == 0 {
+                    continue;
+                }
+                *degree -= 1;
+                if *degree == 1 {
+                    if sort == Sort::Graph {
+                        stack.push(parent);
+                    } else {
+                        queue.push(Queued { time: date(self, &parent)?, sequence: std::cmp::Reverse(sequence), hash: parent });
+                        sequence += 1;
+                    }
+                }
+            }
+            indegree.insert(hash.clone(), 0);
+            sorted.push(hash);
+        }
+        Ok(sorted)
+    }
+}
+
+// The value of a "--name=value" or "--name value" option, taking a separate value from arguments
+pub fn option_argument<'a>(arg: &str, name: &str, arguments: &mut impl Iterator<Item = &'a String>) -> anyhow::Result<Option<String>> {
+    if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+        return Ok(Some(value.to_string()));
+    }
+    if arg != name {
+        return Ok(None);
+    }
+    match arguments.next() {
+        Some(value) => Ok(Some(value.clone())),
+        None => anyhow::bail!("option '{}' requires a value", name.trim_start_matches('-')),
+    }
+}
+
+// WalkOptions are the revision and history options shared by log and rev-list
+#[derive(Default)]
+pub struct WalkOptions {
+    pub walk: Walk,
+    pub filter: Filter,
+    pub max_count: Option<usize>,
+    pub skip: usize,
+    pub reverse: bool,
+    // Revision arguments that name tips, plus everything after "--"
+    pub revision_args: Vec<String>,
+}
+
+impl WalkOptions {
+    // Take arg if it is one of the shared options, reading a separate value from arguments.
+    // Returns whether it was.
+    pub fn parse_option<'a>(&mut self, arg: &str, arguments: &mut impl Iterator<Item = &'a String>) -> anyhow::Result<bool> {
+        let parse_count = |count: String| count.parse().map_err(|_| anyhow::anyhow!("'{}': not an integer", count));
+        if let Some(count) = option_argument(arg, "-n", arguments)?.or(option_argument(arg, "--max-count", arguments)?) {
+            self.max_count = Some(parse_count(count)?);
+        } else if let Some(count) = arg.strip_prefix("-n").filter(|x| x.parse::<usize>().is_ok()) {
+            self.max_count = count.parse().ok();
+        } else if let Some(count) = arg.strip_prefix('-').filter(|x| x.parse::<usize>().is_ok()) {
+            self.max_count = count.parse().ok();
+        } else if let Some(count) = option_argument(arg, "--skip", arguments)? {
+            self.skip = parse_count(count)?;
+        } else if let Some(count) = arg.strip_prefix("--min-parents=") {
+            self.filter.min_parents = count.parse()?;
+        } else if let Some(count) = arg.strip_prefix("--max-parents=") {
+            self.filter.max_parents = Some(count.parse()?);
+        } else {
+            match arg {
+                "--reverse" => self.reverse = true,
+                "--first-parent" => self.walk.first_parent = true,
+                "--ancestry-path" => self.walk.ancestry_path = true,
+                "--topo-order" => self.walk.sort = Some(Sort::Graph),
+                "--date-order" => self.walk.sort = Some(Sort::CommitDate),
+                "--author-date-order" => self.walk.sort = Some(Sort::AuthorDate),
+                "--merges" => self.filter.min_parents = 2,
+                "--no-merges" => self.filter.max_parents = Some(1),
+                "--no-min-parents" => self.filter.min_parents = 0,
+                "--no-max-parents" => self.filter.max_parents = None,
+                "--" => {
+                    self.revision_args.push(arg.to_string());
+                    self.revision_args.extend(arguments.by_ref().cloned());
+                }
+                "--all" | "--branches" | "--tags" | "--remotes" | "--not" => self.revision_args.push(arg.to_string()),
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
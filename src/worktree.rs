@@ -0,0 +1,188 @@
+use crate::index::IndexEntry;
+use crate::{hash_object_contents, read_object_from_store, write_object_to_store, GitObject, GitObjectType, GitTree, GitTreeLeaf};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+// TreeEntry is a file recorded in a tree, identified by its full path from the root
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub mode: u32,
+    pub sha_hash: String,
+}
+
+// Recursively list the files of a tree, keyed by their slash separated path
+pub fn flatten_tree(tree_hash: &str) -> anyhow::Result<BTreeMap<String, TreeEntry>> {
+    let mut entries = BTreeMap::new();
+    flatten_tree_into(tree_hash, "", &mut entries)?;
+    Ok(entries)
+}
+
+fn flatten_tree_into(tree_hash: &str, prefix: &str, entries: &mut BTreeMap<String, TreeEntry>) -> anyhow::Result<()> {
+    let tree = match read_object_from_store(tree_hash)? {
+        GitObjectType::Tree(tree) => tree,
+        _ => anyhow::bail!("{} is not a tree", tree_hash),
+    };
+    for leaf in tree.leaves {
+        let path = format!("{}{}", prefix, leaf.path);
+        let mode = u32::from_str_radix(&String::from_utf8_lossy(&leaf.mode), 8)?;
+        if mode == 0o040000 {
+            flatten_tree_into(&leaf.sha_hash, &format!("{}/", path), entries)?;
+        } else {
+            entries.insert(path, TreeEntry { mode, sha_hash: leaf.sha_hash });
+        }
+    }
+    Ok(())
+}
+
+// Write the tree objects for a flat list of files keyed by path, the inverse of flatten_tree,
+// returning the id of the root tree
+pub fn write_tree(entries: &BTreeMap<String, TreeEntry>) -> String {
+    let files: Vec<(&str, &TreeEntry)> = entries.iter().map(|(path, entry)| (path.as_str(), entry)).collect();
+    write_subtree(&files)
+}
+
+fn write_subtree(files: &[(&str, &TreeEntry)]) -> String {
+    let mut leaves = Vec::new();
+    let mut index = 0;
+    while index < files.len() {
+        let (path, entry) = files[index];
+        match path.split_once('/') {
+            None => {
+                leaves.push(GitTreeLeaf {
+                    mode: format!("{:06o}", entry.mode).into_bytes(),
+                    path: path.to_string(),
+                    sha_hash: entry.sha_hash.clone(),
+                });
+                index += 1;
+            }
+            Some((directory, _)) => {
+                let prefix = format!("{}/", directory);
+                let mut inner = Vec::new();
+                while index < files.len() && files[index].0.starts_with(&prefix) {
+                    inner.push((&files[index].0[prefix.len()..], files[index].1));
+                    index += 1;
+                }
+                leaves.push(GitTreeLeaf {
+                    mode: b"040000".to_vec(),
+                    path: directory.to_string(),
+                    sha_hash: write_subtree(&inner),
+                });
+            }
+        }
+    }
+    let tree = GitTree { leaves };
+    write_object_to_store(&tree.compress(), tree.fmt())
+}
+
+// The mode git would record for a file on disk
+pub fn mode_from_metadata(metadata: &fs::Metadata) -> u32 {
+    if metadata.file_type().is_symlink() {
+        0o120000
+    } else if metadata.is_dir() {
+        0o160000
+    } else if metadata.mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+// Read the blob contents for a path on disk, the link target for symlinks
+pub fn read_worktree_contents(path: &str, metadata: &fs::Metadata) -> anyhow::Result<Vec<u8>> {
+    if metadata.file_type().is_symlink() {
+        Ok(fs::read_link(path)?.to_string_lossy().into_owned().into_bytes())
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
+
+// Check whether the file on disk still matches its index entry, using the cached
+// stat data first and only hashing the contents when that is inconclusive
+pub fn worktree_matches_index(entry: &IndexEntry) -> bool {
+    let metadata = match fs::symlink_metadata(&entry.path) {
+        Ok(metadata) => metadata,
+        Err(_) => return false,
+    };
+    if entry.mode == 0o160000 {
+        return metadata.is_dir();
+    }
+    if metadata.is_dir() || mode_from_metadata(&metadata) != entry.mode {
+        return false;
+    }
+    if entry.stat_matches(&metadata) {
+        return true;
+    }
+    match read_worktree_contents(&entry.path, &metadata) {
+        Ok(contents) => hash_object_contents(&contents, b"blob") == entry.sha_hash,
+        Err(_) => false,
+    }
+}
+
+// Remove a file and any directories left empty above it
+pub fn remove_worktree_file(path: &str) -> anyhow::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let _ = fs::remove_dir(path);
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    let mut parent = Path::new(path).parent();
+    while let Some(directory) = parent {
+        if directory.as_os_str().is_empty() || fs::remove_dir(directory).is_err() {
+            break;
+        }
+        parent = directory.parent();
+    }
+    Ok(())
+}
+
+// Write a blob to the working tree with the given mode and return its new index entry
+pub fn checkout_file(path: &str, mode: u32, sha_hash: &str) -> anyhow::Result<IndexEntry> {
+    if let Some(parent) = Path::new(path).parent() {
+        // A file may be in the way of a directory the new path needs
+        let mut ancestor = Some(parent);
+        while let Some(directory) = ancestor {
+            if fs::symlink_metadata(directory).map(|x| !x.is_dir()).unwrap_or(false) {
+                fs::remove_file(directory)?;
+            }
+            ancestor = directory.parent();
+        }
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() && mode != 0o160000 => fs::remove_dir_all(path)?,
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path)?,
+        _ => {}
+    }
+
+    if mode == 0o160000 {
+        fs::create_dir_all(path)?;
+    } else {
+        let contents = match read_object_from_store(sha_hash)? {
+            GitObjectType::Blob(blob) => blob.blob_data,
+            _ => anyhow::bail!("{} is not a blob", sha_hash),
+        };
+        if mode == 0o120000 {
+            symlink(String::from_utf8_lossy(&contents).as_ref(), path)?;
+        } else {
+            fs::write(path, contents)?;
+            // New files get the umask's permissions, only the executable bits are ours to set
+            let permissions = fs::metadata(path)?.permissions().mode();
+            let permissions = if mode == 0o100755 { permissions | (permissions & 0o444) >> 2 } else { permissions & !0o111 };
+            fs::set_permissions(path, fs::Permissions::from_mode(permissions))?;
+        }
+    }
+
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(IndexEntry::from_metadata(path, mode, sha_hash, &metadata))
+}
+
+// Check whether a pathspec selects a path: "." selects everything, otherwise
+// the spec must name the path itself or one of its parent directories
+pub fn pathspec_matches(pathspec: &str, path: &str) -> bool {
+    let pathspec = pathspec.trim_end_matches('/');
+    pathspec == "." || pathspec.is_empty() || path == pathspec || path.starts_with(&format!("{}/", pathspec))
+}
@@ -0,0 +1,1062 @@
+// Line based diff algorithms, following git's xdiff so hunks come out the same: Myers'
+// algorithm with its cost heuristics, patience and histogram diff, and the pass that slides
+// changed groups to the most readable position.
+
+use std::collections::HashMap;
+
+// Algorithm selects how the longest common subsequence of lines is searched for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Myers,
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> anyhow::Result<Algorithm> {
+        match name.to_ascii_lowercase().as_str() {
+            "myers" | "default" => Ok(Algorithm::Myers),
+            "minimal" => Ok(Algorithm::Minimal),
+            "patience" => Ok(Algorithm::Patience),
+            "histogram" => Ok(Algorithm::Histogram),
+            _ => anyhow::bail!("option diff-algorithm accepts \"myers\", \"minimal\", \"patience\" and \"histogram\""),
+        }
+    }
+}
+
+// Change is a run of removed and added lines, as zero based start lines and counts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+}
+
+// Split contents into lines, each keeping its newline; the last may lack one
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (index, x) in data.iter().enumerate() {
+        if *x == b'\n' {
+            lines.push(&data[start..=index]);
+            start = index + 1;
+        }
+    }
+    if start < data.len() {
+        lines.push(&data[start..]);
+    }
+    lines
+}
+
+// Changed flags for the lines of one side, with room for a false entry before the first
+// line and after the last so groups can be scanned without bounds checks
+struct Marks(Vec<bool>);
+
+impl Marks {
+    fn new(count: usize) -> Marks {
+        Marks(vec![false; count + 2])
+    }
+
+    fn get(&self, line: i64) -> bool {
+        self.0[(line + 1) as usize]
+    }
+
+    fn set(&mut self, line: i64, value: bool) {
+        self.0[(line + 1) as usize] = value;
+    }
+}
+
+// Integer square root approximation used to size the heuristics
+fn bogosqrt(mut n: i64) -> i64 {
+    let mut i = 1;
+    while n > 0 {
+        i <<= 1;
+        n >>= 2;
+    }
+    i
+}
+
+const MAX_EQLIMIT: i64 = 1024;
+const SIMSCAN_WINDOW: i64 = 100;
+const KPDIS_RUN: i64 = 4;
+const MAX_COST_MIN: i64 = 256;
+const HEUR_MIN_COST: i64 = 256;
+const SNAKE_CNT: i64 = 20;
+const K_HEUR: i64 = 4;
+
+// Whether a line matching several others should be left out of the search because it
+// sits in a run of lines that have no match at all
+fn clean_mmatch(dis: &[u8], i: i64, mut s: i64, mut e: i64) -> bool {
+    if i - s > SIMSCAN_WINDOW {
+        s = i - SIMSCAN_WINDOW;
+    }
+    if e - i > SIMSCAN_WINDOW {
+        e = i + SIMSCAN_WINDOW;
+    }
+    let (mut rdis0, mut rpdis0) = (0, 1);
+    let mut r = 1;
+    while i - r >= s {
+        match dis[(i - r) as usize] {
+            0 => rdis0 += 1,
+            2 => rpdis0 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis0 == 0 {
+        return false;
+    }
+    let (mut rdis1, mut rpdis1) = (0, 1);
+    r = 1;
+    while i + r <= e {
+        match dis[(i + r) as usize] {
+            0 => rdis1 += 1,
+            2 => rpdis1 += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if rdis1 == 0 {
+        return false;
+    }
+    rdis1 += rdis0;
+    rpdis1 += rpdis0;
+    rpdis1 * KPDIS_RUN < rpdis1 + rdis1
+}
+
+struct Split {
+    i1: i64,
+    i2: i64,
+    min_lo: bool,
+    min_hi: bool,
+}
+
+// State for Myers' algorithm over the lines left after discarding the unmatchable ones
+struct Myers<'a> {
+    ha1: &'a [usize],
+    ha2: &'a [usize],
+    kvdf: Vec<i64>,
+    kvdb: Vec<i64>,
+    offset: i64,
+    mxcost: i64,
+}
+
+impl Myers<'_> {
+    fn kf(&self, d: i64) -> i64 {
+        self.kvdf[(d + self.offset) as usize]
+    }
+
+    fn kb(&self, d: i64) -> i64 {
+        self.kvdb[(d + self.offset) as usize]
+    }
+
+    fn set_kf(&mut self, d: i64, value: i64) {
+        self.kvdf[(d + self.offset) as usize] = value;
+    }
+
+    fn set_kb(&mut self, d: i64, value: i64) {
+        self.kvdb[(d + self.offset) as usize] = value;
+    }
+
+    // Find the middle snake of the box, or a good enough split point once the search
+    // gets too expensive
+    fn split(&mut self, off1: i64, lim1: i64, off2: i64, lim2: i64, need_min: bool) -> Split {
+        let (ha1, ha2) = (self.ha1, self.ha2);
+        let dmin = off1 - lim2;
+        let dmax = lim1 - off2;
+        let fmid = off1 - off2;
+        let bmid = lim1 - lim2;
+        let odd = (fmid - bmid) & 1 != 0;
+        let (mut fmin, mut fmax) = (fmid, fmid);
+        let (mut bmin, mut bmax) = (bmid, bmid);
+        self.set_kf(fmid, off1);
+        self.set_kb(bmid, lim1);
+
+        let mut ec = 1;
+        loop {
+            let mut got_snake = false;
+            if fmin > dmin {
+                fmin -= 1;
+                self.set_kf(fmin - 1, -1);
+            } else {
+                fmin += 1;
+            }
+            if fmax < dmax {
+                fmax += 1;
+                self.set_kf(fmax + 1, -1);
+            } else {
+                fmax -= 1;
+            }
+            let mut d = fmax;
+            while d >= fmin {
+                let mut i1 = if self.kf(d - 1) >= self.kf(d + 1) { self.kf(d - 1) + 1 } else { self.kf(d + 1) };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 < lim1 && i2 < lim2 && ha1[i1 as usize] == ha2[i2 as usize] {
+                    i1 += 1;
+                    i2 += 1;
+                }
+                if i1 - prev1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                self.set_kf(d, i1);
+                if odd && bmin <= d && d <= bmax && self.kb(d) <= i1 {
+                    return Split { i1, i2, min_lo: true, min_hi: true };
+                }
+                d -= 2;
+            }
+
+            if bmin > dmin {
+                bmin -= 1;
+                self.set_kb(bmin - 1, i64::MAX);
+            } else {
+                bmin += 1;
+            }
+            if bmax < dmax {
+                bmax += 1;
+                self.set_kb(bmax + 1, i64::MAX);
+            } else {
+                bmax -= 1;
+            }
+            let mut d = bmax;
+            while d >= bmin {
+                let mut i1 = if self.kb(d - 1) < self.kb(d + 1) { self.kb(d - 1) } else { self.kb(d + 1) - 1 };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 > off1 && i2 > off2 && ha1[(i1 - 1) as usize] == ha2[(i2 - 1) as usize] {
+                    i1 -= 1;
+                    i2 -= 1;
+                }
+                if prev1 - i1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                self.set_kb(d, i1);
+                if !odd && fmin <= d && d <= fmax && i1 <= self.kf(d) {
+                    return Split { i1, i2, min_lo: true, min_hi: true };
+                }
+                d -= 2;
+            }
+
+            if need_min {
+                ec += 1;
+                continue;
+            }
+
+            // Past the heuristic threshold, settle for a diagonal that has made good progress
+            // and ends in a long enough snake
+            if got_snake && ec > HEUR_MIN_COST {
+                let mut best = 0;
+                let mut split = Split { i1: 0, i2: 0, min_lo: true, min_hi: false };
+                let mut d = fmax;
+                while d >= fmin {
+                    let dd = if d > fmid { d - fmid } else { fmid - d };
+                    let i1 = self.kf(d);
+                    let i2 = i1 - d;
+                    let v = (i1 - off1) + (i2 - off2) - dd;
+                    if v > K_HEUR * ec && v > best && off1 + SNAKE_CNT <= i1 && i1 < lim1 && off2 + SNAKE_CNT <= i2 && i2 < lim2 {
+                        let mut k = 1;
+                        while ha1[(i1 - k) as usize] == ha2[(i2 - k) as usize] {
+                            if k == SNAKE_CNT {
+                                best = v;
+                                split.i1 = i1;
+                                split.i2 = i2;
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if best > 0 {
+                    return split;
+                }
+
+                let mut split = Split { i1: 0, i2: 0, min_lo: false, min_hi: true };
+                let mut d = bmax;
+                while d >= bmin {
+                    let dd = if d > bmid { d - bmid } else { bmid - d };
+                    let i1 = self.kb(d);
+                    let i2 = i1 - d;
+                    let v = (lim1 - i1) + (lim2 - i2) - dd;
+                    if v > K_HEUR * ec && v > best && off1 < i1 && i1 <= lim1 - SNAKE_CNT && off2 < i2 && i2 <= lim2 - SNAKE_CNT {
+                        let mut k = 0;
+                        while ha1[(i1 + k) as usize] == ha2[(i2 + k) as usize] {
+                            if k == SNAKE_CNT - 1 {
+                                best = v;
+                                split.i1 = i1;
+                                split.i2 = i2;
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if best > 0 {
+                    return split;
+                }
+            }
+
+            // Enough is enough: take whichever path reaches furthest
+            if ec >= self.mxcost {
+                let (mut fbest, mut fbest1) = (-1, -1);
+                let mut d = fmax;
+                while d >= fmin {
+                    let mut i1 = self.kf(d).min(lim1);
+                    let mut i2 = i1 - d;
+                    if lim2 < i2 {
+                        i1 = lim2 + d;
+                        i2 = lim2;
+                    }
+                    if fbest < i1 + i2 {
+                        fbest = i1 + i2;
+                        fbest1 = i1;
+                    }
+                    d -= 2;
+                }
+                let (mut bbest, mut bbest1) = (i64::MAX, i64::MAX);
+                let mut d = bmax;
+                while d >= bmin {
+                    let mut i1 = off1.max(self.kb(d));
+                    let mut i2 = i1 - d;
+                    if i2 < off2 {
+                        i1 = off2 + d;
+                        i2 = off2;
+                    }
+                    if i1 + i2 < bbest {
+                        bbest = i1 + i2;
+                        bbest1 = i1;
+                    }
+                    d -= 2;
+                }
+                return if (lim1 + lim2) - bbest < fbest - (off1 + off2) {
+                    Split { i1: fbest1, i2: fbest - fbest1, min_lo: true, min_hi: false }
+                } else {
+                    Split { i1: bbest1, i2: bbest - bbest1, min_lo: false, min_hi: true }
+                };
+            }
+            ec += 1;
+        }
+    }
+
+    // Divide and conquer: mark the lines of the box that are not on the found path
+    #[allow(clippy::too_many_arguments)]
+    fn compare(&mut self, mut off1: i64, mut lim1: i64, mut off2: i64, mut lim2: i64, need_min: bool, marks1: &mut Vec<usize>, marks2: &mut Vec<usize>) {
+        let (ha1, ha2) = (self.ha1, self.ha2);
+        while off1 < lim1 && off2 < lim2 && ha1[off1 as usize] == ha2[off2 as usize] {
+            off1 += 1;
+            off2 += 1;
+        }
+        while off1 < lim1 && off2 < lim2 && ha1[(lim1 - 1) as usize] == ha2[(lim2 - 1) as usize] {
+            lim1 -= 1;
+            lim2 -= 1;
+        }
+        if off1 == lim1 {
+            marks2.extend((off2..lim2).map(|x| x as usize));
+        } else if off2 == lim2 {
+            marks1.extend((off1..lim1).map(|x| x as usize));
+        } else {
+            let split = self.split(off1, lim1, off2, lim2, need_min);
+            self.compare(off1, split.i1, off2, split.i2, split.min_lo, marks1, marks2);
+            self.compare(split.i1, lim1, split.i2, lim2, split.min_hi, marks1, marks2);
+        }
+    }
+}
+
+// Myers' diff of two line sequences given as equivalence classes, returning which lines of
+// each side are changed. Matching lines at both ends are trimmed first, and lines with no
+// counterpart on the other side are taken out of the search.
+fn myers(a: &[usize], b: &[usize], minimal: bool) -> (Vec<bool>, Vec<bool>) {
+    let (n1, n2) = (a.len() as i64, b.len() as i64);
+    let mut changed1 = vec![false; a.len()];
+    let mut changed2 = vec![false; b.len()];
+
+    let limit = n1.min(n2);
+    let mut start = 0;
+    while start < limit && a[start as usize] == b[start as usize] {
+        start += 1;
+    }
+    let mut trailing = 0;
+    while trailing < limit - start && a[(n1 - 1 - trailing) as usize] == b[(n2 - 1 - trailing) as usize] {
+        trailing += 1;
+    }
+    let end1 = n1 - trailing - 1;
+    let end2 = n2 - trailing - 1;
+
+    let mut count1: HashMap<usize, i64> = HashMap::new();
+    let mut count2: HashMap<usize, i64> = HashMap::new();
+    for x in a {
+        *count1.entry(*x).or_default() += 1;
+    }
+    for x in b {
+        *count2.entry(*x).or_default() += 1;
+    }
+    // 0: no match on the other side, 1: some matches, 2: too many matches to be useful
+    let discard = |lines: &[usize], start: i64, end: i64, other: &HashMap<usize, i64>, total: i64| -> Vec<u8> {
+        let limit = bogosqrt(total).min(MAX_EQLIMIT);
+        let mut dis = vec![0u8; lines.len()];
+        for i in start..=end {
+            let matches = other.get(&lines[i as usize]).copied().unwrap_or(0);
+            dis[i as usize] = if matches == 0 {
+                0
+            } else if matches >= limit {
+                2
+            } else {
+                1
+            };
+        }
+        dis
+    };
+    let dis1 = discard(a, start, end1, &count2, n1);
+    let dis2 = discard(b, start, end2, &count1, n2);
+    let keep = |dis: &[u8], lines: &[usize], end: i64, changed: &mut Vec<bool>| -> (Vec<usize>, Vec<usize>) {
+        let (mut index, mut classes) = (Vec::new(), Vec::new());
+        for i in start..=end {
+            let x = dis[i as usize];
+            if x == 1 || (x == 2 && !clean_mmatch(dis, i, start, end)) {
+                index.push(i as usize);
+                classes.push(lines[i as usize]);
+            } else {
+                changed[i as usize] = true;
+            }
+        }
+        (index, classes)
+    };
+    let (index1, ha1) = keep(&dis1, a, end1, &mut changed1);
+    let (index2, ha2) = keep(&dis2, b, end2, &mut changed2);
+
+    let ndiags = (ha1.len() + ha2.len() + 3) as i64;
+    let mut myers = Myers {
+        ha1: &ha1,
+        ha2: &ha2,
+        kvdf: vec![0; ndiags as usize + 1],
+        kvdb: vec![0; ndiags as usize + 1],
+        offset: ha2.len() as i64 + 1,
+        mxcost: bogosqrt(ndiags).max(MAX_COST_MIN),
+    };
+    let (mut marks1, mut marks2) = (Vec::new(), Vec::new());
+    myers.compare(0, ha1.len() as i64, 0, ha2.len() as i64, minimal, &mut marks1, &mut marks2);
+    for x in marks1 {
+        changed1[index1[x]] = true;
+    }
+    for x in marks2 {
+        changed2[index2[x]] = true;
+    }
+    (changed1, changed2)
+}
+
+// Lines of both sides as equivalence classes plus their changed flags, shared by the
+// recursive algorithms. Line numbers passed around are one based as in xdiff.
+struct Env {
+    a: Vec<usize>,
+    b: Vec<usize>,
+    changed1: Marks,
+    changed2: Marks,
+}
+
+impl Env {
+    fn mark_all(&mut self, line1: usize, count1: usize, line2: usize, count2: usize) {
+        for line in line1..line1 + count1 {
+            self.changed1.set(line as i64 - 1, true);
+        }
+        for line in line2..line2 + count2 {
+            self.changed2.set(line as i64 - 1, true);
+        }
+    }
+
+    // Run Myers' algorithm on a part of the files as if it were whole files
+    fn fall_back(&mut self, line1: usize, count1: usize, line2: usize, count2: usize) {
+        let (changed1, changed2) = myers(&self.a[line1 - 1..line1 - 1 + count1], &self.b[line2 - 1..line2 - 1 + count2], false);
+        for (index, changed) in changed1.into_iter().enumerate() {
+            self.changed1.set((line1 - 1 + index) as i64, changed);
+        }
+        for (index, changed) in changed2.into_iter().enumerate() {
+            self.changed2.set((line2 - 1 + index) as i64, changed);
+        }
+    }
+
+    // Patience diff: anchor on lines that occur exactly once on both sides, in the longest
+    // order they share, and recurse between them
+    fn patience(&mut self, line1: usize, count1: usize, line2: usize, count2: usize) {
+        if count1 == 0 || count2 == 0 {
+            self.mark_all(line1, count1, line2, count2);
+            return;
+        }
+        // Entries in order of first appearance on the old side: (line1, line2) where line2 is
+        // zero without a match and None once the line is known not to be unique
+        let mut entries: Vec<(usize, Option<usize>)> = Vec::new();
+        let mut by_class: HashMap<usize, usize> = HashMap::new();
+        for line in line1..line1 + count1 {
+            match by_class.get(&self.a[line - 1]) {
+                Some(&entry) => entries[entry].1 = None,
+                None => {
+                    by_class.insert(self.a[line - 1], entries.len());
+                    entries.push((line, Some(0)));
+                }
+            }
+        }
+        let mut has_matches = false;
+        for line in line2..line2 + count2 {
+            if let Some(&entry) = by_class.get(&self.b[line - 1]) {
+                has_matches = true;
+                entries[entry].1 = match entries[entry].1 {
+                    Some(0) => Some(line),
+                    _ => None,
+                };
+            }
+        }
+        if !has_matches {
+            self.mark_all(line1, count1, line2, count2);
+            return;
+        }
+
+        // Longest increasing sequence of the unique common lines by their new line numbers
+        let unique: Vec<(usize, usize)> = entries.iter().filter_map(|&(a, b)| b.filter(|b| *b != 0).map(|b| (a, b))).collect();
+        let mut sequence: Vec<usize> = Vec::new();
+        let mut previous: Vec<Option<usize>> = vec![None; unique.len()];
+        for (index, &(_, line)) in unique.iter().enumerate() {
+            let position = sequence.partition_point(|&x| unique[x].1 < line);
+            previous[index] = if position == 0 { None } else { Some(sequence[position - 1]) };
+            if position == sequence.len() {
+                sequence.push(index);
+            } else {
+                sequence[position] = index;
+            }
+        }
+        let Some(&last) = sequence.last() else {
+            self.fall_back(line1, count1, line2, count2);
+            return;
+        };
+        let mut common = vec![unique[last]];
+        let mut cursor = previous[last];
+        while let Some(index) = cursor {
+            common.push(unique[index]);
+            cursor = previous[index];
+        }
+        common.reverse();
+
+        // Walk the common sequence, growing each anchor into the lines around it and
+        // recursing into the gaps
+        let (end1, end2) = (line1 + count1, line2 + count2);
+        let (mut line1, mut line2) = (line1, line2);
+        let mut position = 0;
+        loop {
+            let (mut next1, mut next2) = match common.get(position) {
+                Some(&(a, b)) => (a, b),
+                None => (end1, end2),
+            };
+            if position < common.len() {
+                while next1 > line1 && next2 > line2 && self.a[next1 - 2] == self.b[next2 - 2] {
+                    next1 -= 1;
+                    next2 -= 1;
+                }
+            }
+            while line1 < next1 && line2 < next2 && self.a[line1 - 1] == self.b[line2 - 1] {
+                line1 += 1;
+                line2 += 1;
+            }
+            if next1 > line1 || next2 > line2 {
+                self.patience(line1, next1 - line1, line2, next2 - line2);
+            }
+            if position == common.len() {
+                return;
+            }
+            while position + 1 < common.len() && common[position + 1].0 == common[position].0 + 1 && common[position + 1].1 == common[position].1 + 1 {
+                position += 1;
+            }
+            line1 = common[position].0 + 1;
+            line2 = common[position].1 + 1;
+            position += 1;
+        }
+    }
+
+    // Histogram diff: split around the longest common run whose lines are the rarest on
+    // the old side, then recurse on both sides of it
+    fn histogram(&mut self, mut line1: usize, mut count1: usize, mut line2: usize, mut count2: usize) {
+        const MAX_CHAIN_LENGTH: usize = 64;
+        loop {
+            if count1 == 0 || count2 == 0 {
+                self.mark_all(line1, count1, line2, count2);
+                return;
+            }
+            let end1 = line1 + count1 - 1;
+            let end2 = line2 + count2 - 1;
+
+            // Occurrences of each line on the old side: the first line of each class, the
+            // number of times it appears, and the next line with the same contents
+            let mut first: HashMap<usize, (usize, usize)> = HashMap::new();
+            let mut next = vec![0usize; count1];
+            for line in (line1..=end1).rev() {
+                let entry = first.entry(self.a[line - 1]).or_insert((0, 0));
+                next[line - line1] = entry.0;
+                entry.0 = line;
+                entry.1 = (entry.1 + 1).min(i32::MAX as usize);
+            }
+            let occurrences = |line: usize| first[&self.a[line - 1]].1;
+
+            let (mut begin1, mut begin2, mut lcs_end1, mut lcs_end2) = (0, 0, 0, 0);
+            let mut best_count = MAX_CHAIN_LENGTH + 1;
+            let mut has_common = false;
+            let mut b_ptr = line2;
+            while b_ptr <= end2 {
+                let mut b_next = b_ptr + 1;
+                if let Some(&(first_line, count)) = first.get(&self.b[b_ptr - 1]) {
+                    if count > best_count {
+                        has_common = true;
+                    } else {
+                        has_common = true;
+                        let mut a_start = first_line;
+                        loop {
+                            let np = next[a_start - line1];
+                            let (mut as_, mut bs, mut ae, mut be) = (a_start, b_ptr, a_start, b_ptr);
+                            let mut rc = count;
+                            while line1 < as_ && line2 < bs && self.a[as_ - 2] == self.b[bs - 2] {
+                                as_ -= 1;
+                                bs -= 1;
+                                if rc > 1 {
+                                    rc = rc.min(occurrences(as_));
+                                }
+                            }
+                            while ae < end1 && be < end2 && self.a[ae] == self.b[be] {
+                                ae += 1;
+                                be += 1;
+                                if rc > 1 {
+                                    rc = rc.min(occurrences(ae));
+                                }
+                            }
+                            if b_next <= be {
+                                b_next = be + 1;
+                            }
+                            if lcs_end1 - begin1 < ae - as_ || rc < best_count {
+                                begin1 = as_;
+                                begin2 = bs;
+                                lcs_end1 = ae;
+                                lcs_end2 = be;
+                                best_count = rc;
+                            }
+                            let mut np = np;
+                            while np != 0 && np <= ae {
+                                np = next[np - line1];
+                            }
+                            if np == 0 {
+                                break;
+                            }
+                            a_start = np;
+                        }
+                    }
+                }
+                b_ptr = b_next;
+            }
+
+            if has_common && MAX_CHAIN_LENGTH < best_count {
+                self.fall_back(line1, count1, line2, count2);
+                return;
+            }
+            if begin1 == 0 && begin2 == 0 {
+                self.mark_all(line1, count1, line2, count2);
+                return;
+            }
+            self.histogram(line1, begin1 - line1, line2, begin2 - line2);
+            count1 = end1 - lcs_end1;
+            line1 = lcs_end1 + 1;
+            count2 = end2 - lcs_end2;
+            line2 = lcs_end2 + 1;
+        }
+    }
+}
+
+// Group is a run of changed lines, as the half open range start..end
+#[derive(Clone, Copy)]
+struct Group {
+    start: i64,
+    end: i64,
+}
+
+struct Side<'a> {
+    lines: &'a [&'a [u8]],
+    classes: &'a [usize],
+    changed: &'a mut Marks,
+}
+
+impl Side<'_> {
+    fn len(&self) -> i64 {
+        self.lines.len() as i64
+    }
+
+    fn first_group(&self) -> Group {
+        let mut end = 0;
+        while self.changed.get(end) {
+            end += 1;
+        }
+        Group { start: 0, end }
+    }
+
+    fn next_group(&self, group: &mut Group) -> bool {
+        if group.end == self.len() {
+            return false;
+        }
+        group.start = group.end + 1;
+        group.end = group.start;
+        while self.changed.get(group.end) {
+            group.end += 1;
+        }
+        true
+    }
+
+    fn previous_group(&self, group: &mut Group) -> bool {
+        if group.start == 0 {
+            return false;
+        }
+        group.end = group.start - 1;
+        group.start = group.end;
+        while self.changed.get(group.start - 1) {
+            group.start -= 1;
+        }
+        true
+    }
+
+    fn slide_down(&mut self, group: &mut Group) -> bool {
+        if group.end < self.len() && self.classes[group.start as usize] == self.classes[group.end as usize] {
+            self.changed.set(group.start, false);
+            group.start += 1;
+            self.changed.set(group.end, true);
+            group.end += 1;
+            while self.changed.get(group.end) {
+                group.end += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    fn slide_up(&mut self, group: &mut Group) -> bool {
+        if group.start > 0 && self.classes[(group.start - 1) as usize] == self.classes[(group.end - 1) as usize] {
+            group.start -= 1;
+            self.changed.set(group.start, true);
+            group.end -= 1;
+            self.changed.set(group.end, false);
+            while self.changed.get(group.start - 1) {
+                group.start -= 1;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+const MAX_INDENT: i32 = 200;
+const MAX_BLANKS: i32 = 20;
+const INDENT_HEURISTIC_MAX_SLIDING: i64 = 100;
+
+// Indentation width of a line, or -1 if it is blank
+fn get_indent(line: &[u8]) -> i32 {
+    let mut indent = 0;
+    for &x in line {
+        if !matches!(x, b' ' | b'\t' | b'\n' | b'\r') {
+            return indent;
+        }
+        if x == b' ' {
+            indent += 1;
+        } else if x == b'\t' {
+            indent += 8 - indent % 8;
+        }
+        if indent >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+    -1
+}
+
+struct SplitMeasurement {
+    end_of_file: bool,
+    indent: i32,
+    pre_blank: i32,
+    pre_indent: i32,
+    post_blank: i32,
+    post_indent: i32,
+}
+
+fn measure_split(lines: &[&[u8]], split: i64) -> SplitMeasurement {
+    let count = lines.len() as i64;
+    let (end_of_file, indent) = if split >= count { (true, -1) } else { (false, get_indent(lines[split as usize])) };
+    let (mut pre_blank, mut pre_indent) = (0, -1);
+    let mut i = split - 1;
+    while i >= 0 {
+        pre_indent = get_indent(lines[i as usize]);
+        if pre_indent != -1 {
+            break;
+        }
+        pre_blank += 1;
+        if pre_blank == MAX_BLANKS {
+            pre_indent = 0;
+            break;
+        }
+        i -= 1;
+    }
+    let (mut post_blank, mut post_indent) = (0, -1);
+    let mut i = split + 1;
+    while i < count {
+        post_indent = get_indent(lines[i as usize]);
+        if post_indent != -1 {
+            break;
+        }
+        post_blank += 1;
+        if post_blank == MAX_BLANKS {
+            post_indent = 0;
+            break;
+        }
+        i += 1;
+    }
+    SplitMeasurement { end_of_file, indent, pre_blank, pre_indent, post_blank, post_indent }
+}
+
+// Score of a split point: how indented the code around it is, and a penalty for splitting
+// in a place that reads badly, both smaller for better splits
+#[derive(Clone, Copy, Default)]
+struct SplitScore {
+    effective_indent: i32,
+    penalty: i32,
+}
+
+fn score_add_split(m: &SplitMeasurement, score: &mut SplitScore) {
+    if m.pre_indent == -1 && m.pre_blank == 0 {
+        score.penalty += 1;
+    }
+    if m.end_of_file {
+        score.penalty += 21;
+    }
+    let post_blank = if m.indent == -1 { 1 + m.post_blank } else { 0 };
+    let total_blank = m.pre_blank + post_blank;
+    score.penalty += -30 * total_blank;
+    score.penalty += 6 * post_blank;
+    let indent = if m.indent != -1 { m.indent } else { m.post_indent };
+    let any_blanks = total_blank != 0;
+    score.effective_indent += indent;
+    if indent == -1 || m.pre_indent == -1 || indent == m.pre_indent {
+        return;
+    }
+    score.penalty += if indent > m.pre_indent {
+        // Indented more than the line before
+        if any_blanks { 10 } else { -4 }
+    } else if m.post_indent != -1 && m.post_indent > indent {
+        // Indented less, but the next line is indented more, so likely the start of a block
+        if any_blanks { 17 } else { 24 }
+    } else {
+        // Probably the end of a block
+        if any_blanks { 17 } else { 23 }
+    };
+}
+
+fn score_cmp(s1: &SplitScore, s2: &SplitScore) -> i32 {
+    let cmp_indents = (s1.effective_indent > s2.effective_indent) as i32 - ((s1.effective_indent < s2.effective_indent) as i32);
+    60 * cmp_indents + (s1.penalty - s2.penalty)
+}
+
+// Slide each group of changed lines as far as it goes, merging groups it runs into, then
+// settle it where it lines up with a change on the other side or, failing that, where the
+// indent heuristic says it reads best
+fn compact(side: &mut Side, other: &mut Side, indent_heuristic: bool) {
+    let mut g = side.first_group();
+    let mut go = other.first_group();
+    loop {
+        if g.end != g.start {
+            let mut earliest_end;
+            let mut end_matching_other;
+            let mut group_size;
+            loop {
+                group_size = g.end - g.start;
+                end_matching_other = -1;
+                while side.slide_up(&mut g) {
+                    other.previous_group(&mut go);
+                }
+                earliest_end = g.end;
+                if go.end > go.start {
+                    end_matching_other = g.end;
+                }
+                while side.slide_down(&mut g) {
+                    other.next_group(&mut go);
+                    if go.end > go.start {
+                        end_matching_other = g.end;
+                    }
+                }
+                if group_size == g.end - g.start {
+                    break;
+                }
+            }
+
+            if g.end == earliest_end {
+                // The group could not move
+            } else if end_matching_other != -1 {
+                while go.end == go.start {
+                    side.slide_up(&mut g);
+                    other.previous_group(&mut go);
+                }
+            } else if indent_heuristic {
+                let mut shift = earliest_end;
+                if g.end - group_size - 1 > shift {
+                    shift = g.end - group_size - 1;
+                }
+                if g.end - INDENT_HEURISTIC_MAX_SLIDING > shift {
+                    shift = g.end - INDENT_HEURISTIC_MAX_SLIDING;
+                }
+                let mut best_shift = -1;
+                let mut best_score = SplitScore::default();
+                while shift <= g.end {
+                    let mut score = SplitScore::default();
+                    score_add_split(&measure_split(side.lines, shift), &mut score);
+                    score_add_split(&measure_split(side.lines, shift - group_size), &mut score);
+                    if best_shift == -1 || score_cmp(&score, &best_score) <= 0 {
+                        best_score = score;
+                        best_shift = shift;
+                    }
+                    shift += 1;
+                }
+                while g.end > best_shift {
+                    side.slide_up(&mut g);
+                    other.previous_group(&mut go);
+                }
+            }
+        }
+        if !side.next_group(&mut g) {
+            break;
+        }
+        other.next_group(&mut go);
+    }
+}
+
+// Compare two lists of lines, returning the runs of changed lines in order
+pub fn diff_lines<'a>(old: &[&'a [u8]], new: &[&'a [u8]], algorithm: Algorithm, indent_heuristic: bool) -> Vec<Change> {
+    let mut classes: HashMap<&'a [u8], usize> = HashMap::new();
+    let mut classify = |line: &'a [u8]| -> usize {
+        let next = classes.len();
+        *classes.entry(line).or_insert(next)
+    };
+    let a: Vec<usize> = old.iter().map(|line| classify(line)).collect();
+    let b: Vec<usize> = new.iter().map(|line| classify(line)).collect();
+
+    let mut env = Env { changed1: Marks::new(a.len()), changed2: Marks::new(b.len()), a, b };
+    match algorithm {
+        Algorithm::Myers | Algorithm::Minimal => {
+            let (changed1, changed2) = myers(&env.a, &env.b, algorithm == Algorithm::Minimal);
+            for (index, changed) in changed1.into_iter().enumerate() {
+                env.changed1.set(index as i64, changed);
+            }
+            for (index, changed) in changed2.into_iter().enumerate() {
+                env.changed2.set(index as i64, changed);
+            }
+        }
+        Algorithm::Patience => env.patience(1, old.len(), 1, new.len()),
+        Algorithm::Histogram => env.histogram(1, old.len(), 1, new.len()),
+    }
+
+    let Env { a, b, mut changed1, mut changed2 } = env;
+    {
+        let mut side1 = Side { lines: old, classes: &a, changed: &mut changed1 };
+        let mut side2 = Side { lines: new, classes: &b, changed: &mut changed2 };
+        compact(&mut side1, &mut side2, indent_heuristic);
+        compact(&mut side2, &mut side1, indent_heuristic);
+    }
+
+    // Collect the runs of changes, walking both sides backwards together
+    let mut changes = Vec::new();
+    let (mut i1, mut i2) = (old.len() as i64, new.len() as i64);
+    while i1 >= 0 || i2 >= 0 {
+        if changed1.get(i1 - 1) || changed2.get(i2 - 1) {
+            let (l1, l2) = (i1, i2);
+            while changed1.get(i1 - 1) {
+                i1 -= 1;
+            }
+            while changed2.get(i2 - 1) {
+                i2 -= 1;
+            }
+            changes.push(Change {
+                old_start: i1 as usize,
+                old_count: (l1 - i1) as usize,
+                new_start: i2 as usize,
+                new_count: (l2 - i2) as usize,
+            });
+        }
+        i1 -= 1;
+        i2 -= 1;
+    }
+    changes.reverse();
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The hunk ranges as git prints them with -U0, one line per letter of the inputs
+    fn hunks(old: &str, new: &str, algorithm: Algorithm, indent_heuristic: bool) -> Vec<String> {
+        let old: Vec<String> = old.chars().map(|x| format!("{}\n", x)).collect();
+        let new: Vec<String> = new.chars().map(|x| format!("{}\n", x)).collect();
+        hunks_of_lines(&old.concat(), &new.concat(), algorithm, indent_heuristic)
+    }
+
+    fn hunks_of_lines(old: &str, new: &str, algorithm: Algorithm, indent_heuristic: bool) -> Vec<String> {
+        let range = |start: usize, count: usize| match count {
+            0 => format!("{},0", start),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{}", start + 1, count),
+        };
+        let (old, new) = (split_lines(old.as_bytes()), split_lines(new.as_bytes()));
+        diff_lines(&old, &new, algorithm, indent_heuristic)
+            .into_iter()
+            .map(|x| format!("-{} +{}", range(x.old_start, x.old_count), range(x.new_start, x.new_count)))
+            .collect()
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(split_lines(b"a\nb\n"), [&b"a\n"[..], b"b\n"]);
+        assert_eq!(split_lines(b"a\n\nb"), [&b"a\n"[..], b"\n", b"b"]);
+        assert!(split_lines(b"").is_empty());
+    }
+
+    #[test]
+    fn identical_and_empty() {
+        for algorithm in [Algorithm::Myers, Algorithm::Minimal, Algorithm::Patience, Algorithm::Histogram] {
+            assert!(hunks("abc", "abc", algorithm, true).is_empty());
+            assert_eq!(hunks("", "ab", algorithm, true), ["-0,0 +1,2"]);
+            assert_eq!(hunks("ab", "", algorithm, true), ["-1,2 +0,0"]);
+            assert_eq!(hunks("abc", "axc", algorithm, true), ["-2 +2"]);
+        }
+    }
+
+    #[test]
+    fn myers() {
+        assert_eq!(hunks("axcaa", "xxacb", Algorithm::Myers, true), ["-1 +0,0", "-3,2 +2", "-5,0 +4,2"]);
+        assert_eq!(hunks("bxaaya", "yaybaa", Algorithm::Myers, true), ["-1,3 +1", "-5,0 +4,2"]);
+    }
+
+    #[test]
+    fn patience() {
+        assert_eq!(hunks("axcaa", "xxacb", Algorithm::Patience, true), ["-0,0 +1,2", "-2 +3,0", "-4,2 +5"]);
+        assert_eq!(hunks("bxaaya", "yaybaa", Algorithm::Patience, true), ["-0,0 +1,3", "-2 +4,0", "-5,2 +6,0"]);
+    }
+
+    #[test]
+    fn histogram() {
+        assert_eq!(hunks("axcaa", "xxacb", Algorithm::Histogram, true), ["-1 +0,0", "-2,0 +2,2", "-4,2 +5"]);
+        assert_eq!(hunks("bxaaya", "yaybaa", Algorithm::Histogram, true), ["-1,4 +0,0", "-6,0 +3,4"]);
+    }
+
+    #[test]
+    fn indent_heuristic() {
+        // The added block can slide; the heuristic puts the blank line inside it
+        let old = "1\n2\na\n\nb\n3\n4\n";
+        let new = "1\n2\na\n\nb\na\n\nb\n3\n4\n";
+        assert_eq!(hunks_of_lines(old, new, Algorithm::Myers, true), ["-4,0 +5,3"]);
+        assert_eq!(hunks_of_lines(old, new, Algorithm::Myers, false), ["-5,0 +6,3"]);
+    }
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(Algorithm::parse("Default").unwrap(), Algorithm::Myers);
+        assert_eq!(Algorithm::parse("histogram").unwrap(), Algorithm::Histogram);
+        assert!(Algorithm::parse("fast").is_err());
+    }
+}
@@ -0,0 +1,622 @@
+// Line based three-way merges, following git's xmerge so merged files and their conflict
+// markers come out the same: both sides are diffed against the base, changes that touch the
+// same base lines become conflicts, and conflicts are narrowed down by diffing the two sides.
+
+use crate::config::read_config;
+use crate::patch::is_binary;
+use crate::xdiff::{diff_lines, split_lines, Algorithm, Change};
+use std::borrow::Cow;
+use std::fs;
+use std::io::Write;
+
+// Length of the conflict markers unless asked otherwise
+pub const MARKER_SIZE: usize = 7;
+
+// Style picks how conflicts are shown: both sides, or both sides with the base between them,
+// where zdiff3 moves lines common to both sides out of the conflict first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Merge,
+    Diff3,
+    ZealousDiff3,
+}
+
+impl Style {
+    pub fn parse(name: &str) -> Option<Style> {
+        match name {
+            "merge" => Some(Style::Merge),
+            "diff3" => Some(Style::Diff3),
+            "zdiff3" => Some(Style::ZealousDiff3),
+            _ => None,
+        }
+    }
+}
+
+// Favor settles conflicts without markers by taking one side or both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Favor {
+    Ours,
+    Theirs,
+    Union,
+}
+
+// Level is how hard conflicts are narrowed: not at all, by letting identical changes merge,
+// by diffing the conflicting sides, and by also joining conflicts separated only by lines
+// without letters or digits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Minimal,
+    Eager,
+    Zealous,
+    ZealousAlnum,
+}
+
+// Whitespace differences to ignore when matching lines
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Whitespace {
+    pub all: bool,
+    pub change: bool,
+    pub at_eol: bool,
+    pub cr_at_eol: bool,
+}
+
+fn is_space(x: u8) -> bool {
+    matches!(x, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+impl Whitespace {
+    // The form of a line that is compared, equal for lines that only differ in ignored ways
+    pub fn normalize<'a>(&self, line: &'a [u8]) -> Cow<'a, [u8]> {
+        let trimmed = || {
+            let end = line.iter().rposition(|x| !is_space(*x)).map_or(0, |end| end + 1);
+            &line[..end]
+        };
+        if self.all {
+            Cow::Owned(line.iter().copied().filter(|x| !is_space(*x)).collect())
+        } else if self.change {
+            let mut normalized = Vec::with_capacity(line.len());
+            for x in trimmed() {
+                if !is_space(*x) {
+                    normalized.push(*x);
+                } else if !normalized.last().is_some_and(|last| is_space(*last)) {
+                    normalized.push(b' ');
+                }
+            }
+            Cow::Owned(normalized)
+        } else if self.at_eol {
+            Cow::Borrowed(trimmed())
+        } else if self.cr_at_eol {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            Cow::Borrowed(line.strip_suffix(b"\r").unwrap_or(line))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+}
+
+// MergeFileOptions choose how a merge is done and how its conflicts are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeFileOptions {
+    pub style: Style,
+    pub favor: Option<Favor>,
+    pub level: Level,
+    pub marker_size: usize,
+    pub algorithm: Algorithm,
+    pub whitespace: Whitespace,
+}
+
+impl Default for MergeFileOptions {
+    fn default() -> MergeFileOptions {
+        MergeFileOptions {
+            style: Style::Merge,
+            favor: None,
+            level: Level::ZealousAlnum,
+            marker_size: MARKER_SIZE,
+            algorithm: Algorithm::Myers,
+            whitespace: Whitespace::default(),
+        }
+    }
+}
+
+// Where the lines of a hunk come from
+const CONFLICT: u8 = 0;
+const FROM_OURS: u8 = 1;
+const FROM_THEIRS: u8 = 2;
+const FROM_BOTH: u8 = 3;
+const IDENTICAL: u8 = 4;
+
+// Hunk is a run of base lines changed on at least one side, with the lines standing for it in
+// our and their version
+#[derive(Clone, Copy, Debug)]
+struct Hunk {
+    mode: u8,
+    i0: isize,
+    chg0: isize,
+    i1: isize,
+    chg1: isize,
+    i2: isize,
+    chg2: isize,
+}
+
+// Positions of the three versions
+const BASE: usize = 0;
+const OURS: usize = 1;
+const THEIRS: usize = 2;
+
+struct Merge3<'a> {
+    lines: [Vec<&'a [u8]>; 3],
+    keys: [Vec<Cow<'a, [u8]>>; 3],
+    options: &'a MergeFileOptions,
+}
+
+impl<'a> Merge3<'a> {
+    fn diff(&self, old: usize, old_range: std::ops::Range<usize>, new: usize, new_range: std::ops::Range<usize>) -> Vec<Change> {
+        let old: Vec<&[u8]> = self.keys[old][old_range].iter().map(|x| x.as_ref()).collect();
+        let new: Vec<&[u8]> = self.keys[new][new_range].iter().map(|x| x.as_ref()).collect();
+        diff_lines(&old, &new, self.options.algorithm, false)
+    }
+
+    fn same_line(&self, line1: isize, line2: isize) -> bool {
+        self.keys[OURS][line1 as usize] == self.keys[THEIRS][line2 as usize]
+    }
+
+    // Add a hunk, joining it to the previous one when the two touch on either side
+    fn append(hunks: &mut Vec<Hunk>, hunk: Hunk) {
+        if let Some(last) = hunks.last_mut() {
+            if hunk.i1 <= last.i1 + last.chg1 || hunk.i2 <= last.i2 + last.chg2 {
+                if hunk.mode != last.mode {
+                    last.mode = CONFLICT;
+                }
+                last.chg0 = hunk.i0 + hunk.chg0 - last.i0;
+                last.chg1 = hunk.i1 + hunk.chg1 - last.i1;
+                last.chg2 = hunk.i2 + hunk.chg2 - last.i2;
+                return;
+            }
+        }
+        hunks.push(hunk);
+    }
+
+    // Line up the changes of both sides against the base
+    fn hunks(&self, ours: &[Change], theirs: &[Change], level: Level) -> Vec<Hunk> {
+        let lengths = self.lines.each_ref().map(|lines| lines.len() as isize);
+        let mut hunks = Vec::new();
+        let (mut a, mut b) = (0, 0);
+        while a < ours.len() && b < theirs.len() {
+            let (x1, x2) = (&ours[a], &theirs[b]);
+            let (start1, count1, new_start1, new_count1) = (x1.old_start as isize, x1.old_count as isize, x1.new_start as isize, x1.new_count as isize);
+            let (start2, count2, new_start2, new_count2) = (x2.old_start as isize, x2.old_count as isize, x2.new_start as isize, x2.new_count as isize);
+            if start1 + count1 < start2 {
+                let hunk = Hunk { mode: FROM_OURS, i0: start1, chg0: count1, i1: new_start1, chg1: new_count1, i2: new_start2 - start2 + start1, chg2: count1 };
+                Self::append(&mut hunks, hunk);
+                a += 1;
+                continue;
+            }
+            if start2 + count2 < start1 {
+                let hunk = Hunk { mode: FROM_THEIRS, i0: start2, chg0: count2, i1: new_start1 - start1 + start2, chg1: count2, i2: new_start2, chg2: new_count2 };
+                Self::append(&mut hunks, hunk);
+                b += 1;
+                continue;
+            }
+            let identical = start1 == start2
+                && count1 == count2
+                && new_count1 == new_count2
+                && (0..new_count1).all(|line| self.same_line(new_start1 + line, new_start2 + line));
+            if level == Level::Minimal || !identical {
+                // Widen both changes to cover the same base lines
+                let off = start1 - start2;
+                let ffo = off + count1 - count2;
+                let (mut i0, mut i1, mut i2) = (start1, new_start1, new_start2);
+                if off > 0 {
+                    i0 -= off;
+                    i1 -= off;
+                } else {
+                    i2 += off;
+                }
+                let mut chg0 = start1 + count1 - i0;
+                let mut chg1 = new_start1 + new_count1 - i1;
+                let mut chg2 = new_start2 + new_count2 - i2;
+                if ffo < 0 {
+                    chg0 -= ffo;
+                    chg1 -= ffo;
+                } else {
+                    chg2 += ffo;
+                }
+                Self::append(&mut hunks, Hunk { mode: CONFLICT, i0, chg0, i1, chg1, i2, chg2 });
+            }
+            let (end1, end2) = (start1 + count1, start2 + count2);
+            if end1 >= end2 {
+                b += 1;
+            }
+            if end2 >= end1 {
+                a += 1;
+            }
+        }
+        for x1 in &ours[a..] {
+            let (start, count) = (x1.old_start as isize, x1.old_count as isize);
+            let i2 = start + lengths[THEIRS] - lengths[BASE];
+            Self::append(&mut hunks, Hunk { mode: FROM_OURS, i0: start, chg0: count, i1: x1.new_start as isize, chg1: x1.new_count as isize, i2, chg2: count });
+        }
+        for x2 in &theirs[b..] {
+            let (start, count) = (x2.old_start as isize, x2.old_count as isize);
+            let i1 = start + lengths[OURS] - lengths[BASE];
+            Self::append(&mut hunks, Hunk { mode: FROM_THEIRS, i0: start, chg0: count, i1, chg1: count, i2: x2.new_start as isize, chg2: x2.new_count as isize });
+        }
+        hunks
+    }
+
+    // Split each conflict into the parts where the two sides really differ, dropping
+    // conflicts where both sides made the same change
+    fn refine(&self, hunks: Vec<Hunk>) -> Vec<Hunk> {
+        let mut refined = Vec::with_capacity(hunks.len());
+        for mut hunk in hunks {
+            if hunk.mode != CONFLICT || hunk.chg1 == 0 || hunk.chg2 == 0 {
+                refined.push(hunk);
+                continue;
+            }
+            let (i1, i2) = (hunk.i1 as usize, hunk.i2 as usize);
+            let changes = self.diff(OURS, i1..i1 + hunk.chg1 as usize, THEIRS, i2..i2 + hunk.chg2 as usize);
+            if changes.is_empty() {
+                hunk.mode = IDENTICAL;
+                refined.push(hunk);
+                continue;
+            }
+            for change in changes {
+                hunk.i1 = (i1 + change.old_start) as isize;
+                hunk.chg1 = change.old_count as isize;
+                hunk.i2 = (i2 + change.new_start) as isize;
+                hunk.chg2 = change.new_count as isize;
+                refined.push(hunk);
+            }
+        }
+        refined
+    }
+
+    // Take the lines both sides agree on at the start and end out of each conflict
+    fn refine_zealous_diff3(&self, hunks: &mut [Hunk]) {
+        for hunk in hunks.iter_mut().filter(|hunk| hunk.mode == CONFLICT) {
+            while hunk.chg1 > 0 && hunk.chg2 > 0 && self.same_line(hunk.i1, hunk.i2) {
+                hunk.chg1 -= 1;
+                hunk.chg2 -= 1;
+                hunk.i1 += 1;
+                hunk.i2 += 1;
+            }
+            while hunk.chg1 > 0 && hunk.chg2 > 0 && self.same_line(hunk.i1 + hunk.chg1 - 1, hunk.i2 + hunk.chg2 - 1) {
+                hunk.chg1 -= 1;
+                hunk.chg2 -= 1;
+            }
+        }
+    }
+
+    // Join conflicts separated by fewer than four lines, which reads no worse than keeping
+    // them apart, and at the most zealous level also those separated only by lines without
+    // any letter or digit
+    fn simplify(&self, hunks: Vec<Hunk>, if_no_alnum: bool) -> Vec<Hunk> {
+        let mut simplified: Vec<Hunk> = Vec::with_capacity(hunks.len());
+        for hunk in hunks {
+            if let Some(last) = simplified.last_mut() {
+                let (begin, end) = (last.i1 + last.chg1, hunk.i1);
+                let has_alnum = || self.lines[OURS][begin as usize..end as usize].iter().any(|line| line.iter().any(|x| x.is_ascii_alphanumeric()));
+                if last.mode == CONFLICT && hunk.mode == CONFLICT && (end - begin <= 3 || (if_no_alnum && !has_alnum())) {
+                    last.chg1 = hunk.i1 + hunk.chg1 - last.i1;
+                    last.chg2 = hunk.i2 + hunk.chg2 - last.i2;
+                    continue;
+                }
+            }
+            simplified.push(hunk);
+        }
+        simplified
+    }
+
+    // Whether a line of one version ends in CR LF, or None when that cannot be told
+    fn ends_in_crlf(&self, version: usize, line: usize) -> Option<bool> {
+        let lines = &self.lines[version];
+        let crlf = |line: &[u8]| line.ends_with(b"\r\n");
+        if line + 1 < lines.len() {
+            return Some(crlf(lines[line]));
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        if lines[line].ends_with(b"\n") {
+            return Some(crlf(lines[line]));
+        }
+        if line == 0 {
+            return None;
+        }
+        Some(crlf(lines[line - 1]))
+    }
+
+    // Whether lines added around a hunk should end in CR LF, following the lines before it
+    fn needs_cr(&self, hunk: &Hunk) -> bool {
+        let before = |start: isize| if start > 0 { start as usize - 1 } else { 0 };
+        let mut needs_cr = self.ends_in_crlf(OURS, before(hunk.i1));
+        if needs_cr != Some(false) {
+            needs_cr = self.ends_in_crlf(THEIRS, before(hunk.i2));
+        }
+        if needs_cr != Some(false) {
+            needs_cr = self.ends_in_crlf(BASE, 0);
+        }
+        needs_cr.unwrap_or(false)
+    }
+
+    // Copy lines of a version, giving the last one a line end when asked to
+    fn copy(&self, output: &mut Vec<u8>, version: usize, start: isize, count: isize, needs_cr: bool, add_newline: bool) {
+        if count < 1 {
+            return;
+        }
+        let lines = &self.lines[version][start as usize..(start + count) as usize];
+        for line in lines {
+            output.extend_from_slice(line);
+        }
+        if add_newline && !lines[lines.len() - 1].ends_with(b"\n") {
+            if needs_cr {
+                output.push(b'\r');
+            }
+            output.push(b'\n');
+        }
+    }
+
+    fn marker(&self, output: &mut Vec<u8>, marker: u8, label: Option<&str>, needs_cr: bool) {
+        output.extend(std::iter::repeat_n(marker, self.options.marker_size));
+        if let Some(label) = label {
+            output.push(b' ');
+            output.extend_from_slice(label.as_bytes());
+        }
+        if needs_cr {
+            output.push(b'\r');
+        }
+        output.push(b'\n');
+    }
+
+    // Write the merged file, returning how many conflicts it holds
+    fn write(&self, hunks: &mut [Hunk], labels: [Option<&str>; 3], output: &mut Vec<u8>) -> usize {
+        let mut line = 0;
+        for hunk in hunks.iter_mut() {
+            if hunk.mode == CONFLICT {
+                if let Some(favor) = self.options.favor {
+                    hunk.mode = match favor {
+                        Favor::Ours => FROM_OURS,
+                        Favor::Theirs => FROM_THEIRS,
+                        Favor::Union => FROM_BOTH,
+                    };
+                }
+            }
+            if hunk.mode == CONFLICT {
+                let needs_cr = self.needs_cr(hunk);
+                self.copy(output, OURS, line, hunk.i1 - line, false, false);
+                self.marker(output, b'<', labels[OURS], needs_cr);
+                self.copy(output, OURS, hunk.i1, hunk.chg1, needs_cr, true);
+                if self.options.style != Style::Merge {
+                    self.marker(output, b'|', labels[BASE], needs_cr);
+                    self.copy(output, BASE, hunk.i0, hunk.chg0, needs_cr, true);
+                }
+                self.marker(output, b'=', None, needs_cr);
+                self.copy(output, THEIRS, hunk.i2, hunk.chg2, needs_cr, true);
+                self.marker(output, b'>', labels[THEIRS], needs_cr);
+            } else if hunk.mode & FROM_BOTH != 0 {
+                self.copy(output, OURS, line, hunk.i1 - line, false, false);
+                if hunk.mode & FROM_OURS != 0 {
+                    let needs_cr = self.needs_cr(hunk);
+                    self.copy(output, OURS, hunk.i1, hunk.chg1, needs_cr, hunk.mode & FROM_THEIRS != 0);
+                }
+                if hunk.mode & FROM_THEIRS != 0 {
+                    self.copy(output, THEIRS, hunk.i2, hunk.chg2, false, false);
+                }
+            } else {
+                continue;
+            }
+            line = hunk.i1 + hunk.chg1;
+        }
+        self.copy(output, OURS, line, self.lines[OURS].len() as isize - line, false, false);
+        hunks.iter().filter(|hunk| hunk.mode == CONFLICT).count()
+    }
+}
+
+// Merge the changes made to base in ours and in theirs, returning the merged contents and the
+// number of conflicts marked in them. Labels follow the conflict markers.
+pub fn merge_file(base: &[u8], ours: &[u8], theirs: &[u8], labels: [Option<&str>; 3], options: &MergeFileOptions) -> (Vec<u8>, usize) {
+    let lines = [split_lines(base), split_lines(ours), split_lines(theirs)];
+    let keys = lines.each_ref().map(|lines| lines.iter().map(|line| options.whitespace.normalize(line)).collect());
+    let merge = Merge3 { lines, keys, options };
+
+    let ours_changes = merge.diff(BASE, 0..merge.lines[BASE].len(), OURS, 0..merge.lines[OURS].len());
+    let theirs_changes = merge.diff(BASE, 0..merge.lines[BASE].len(), THEIRS, 0..merge.lines[THEIRS].len());
+    if ours_changes.is_empty() {
+        return (theirs.to_vec(), 0);
+    }
+    if theirs_changes.is_empty() {
+        return (ours.to_vec(), 0);
+    }
+
+    // Showing the base alongside the sides makes no sense for joined or split conflicts
+    let level = match options.style {
+        Style::Diff3 => options.level.min(Level::Eager),
+        _ => options.level,
+    };
+    let mut hunks = merge.hunks(&ours_changes, &theirs_changes, level);
+    if options.style == Style::ZealousDiff3 {
+        merge.refine_zealous_diff3(&mut hunks);
+    } else if level >= Level::Zealous {
+        hunks = merge.refine(hunks);
+        hunks = merge.simplify(hunks, level > Level::Zealous);
+    }
+    let mut output = Vec::with_capacity(ours.len().max(theirs.len()));
+    let conflicts = merge.write(&mut hunks, labels, &mut output);
+    (output, conflicts)
+}
+
+fn merge_file_usage() -> ! {
+    eprintln!("usage: git merge-file [<options>] [-L <name1> [-L <orig> [-L <name2>]]] <file1> <orig-file> <file2>");
+    std::process::exit(129);
+}
+
+// Merge the changes leading from one file to another into a third, in place or to stdout;
+// the exit code is the number of conflicts
+pub fn merge_file_command(args: &[String]) -> anyhow::Result<()> {
+    let config = read_config();
+    let command_index = args.iter().position(|x| x == "merge-file").unwrap();
+    let mut options = MergeFileOptions::default();
+    if let Some(style) = config.get("merge.conflictstyle") {
+        options.style = Style::parse(style).ok_or_else(|| anyhow::anyhow!("unknown style '{}' given for 'merge.conflictstyle'", style))?;
+    }
+    let mut to_stdout = false;
+    let mut quiet = false;
+    let mut names: Vec<String> = Vec::new();
+    let mut files = Vec::new();
+
+    let mut index = command_index + 1;
+    let mut only_files = false;
+    while index < args.len() {
+        let arg = args[index].as_str();
+        match arg {
+            _ if only_files || !arg.starts_with('-') || arg == "-" => files.push(arg.to_string()),
+            "--" => only_files = true,
+            "-p" | "--stdout" => to_stdout = true,
+            "-q" | "--quiet" => quiet = true,
+            "--diff3" => options.style = Style::Diff3,
+            "--zdiff3" => options.style = Style::ZealousDiff3,
+            "--ours" => options.favor = Some(Favor::Ours),
+            "--theirs" => options.favor = Some(Favor::Theirs),
+            "--union" => options.favor = Some(Favor::Union),
+            "-w" | "--ignore-all-space" => options.whitespace.all = true,
+            "-b" | "--ignore-space-change" => options.whitespace.change = true,
+            "--ignore-space-at-eol" => options.whitespace.at_eol = true,
+            "--ignore-cr-at-eol" => options.whitespace.cr_at_eol = true,
+            "-L" => {
+                index += 1;
+                let name = args.get(index).ok_or_else(|| anyhow::anyhow!("switch `L' requires a value"))?;
+                names.push(name.clone());
+            }
+            "--marker-size" => {
+                index += 1;
+                let size = args.get(index).ok_or_else(|| anyhow::anyhow!("option `marker-size' requires a value"))?;
+                options.marker_size = size.parse().map_err(|_| anyhow::anyhow!("option `marker-size' expects a numerical value"))?;
+            }
+            _ if arg.starts_with("--marker-size=") => {
+                let size = &arg["--marker-size=".len()..];
+                options.marker_size = size.parse().map_err(|_| anyhow::anyhow!("option `marker-size' expects a numerical value"))?;
+            }
+            _ if arg.starts_with("--diff-algorithm=") => options.algorithm = Algorithm::parse(&arg["--diff-algorithm=".len()..])?,
+            _ if arg.starts_with("-L") => names.push(arg[2..].to_string()),
+            _ => merge_file_usage(),
+        }
+        index += 1;
+    }
+    if files.len() != 3 || names.len() > 3 {
+        merge_file_usage();
+    }
+    if options.marker_size == 0 {
+        options.marker_size = MARKER_SIZE;
+    }
+
+    // Files are given as ours, base and theirs
+    let mut contents = Vec::new();
+    for (position, file) in files.iter().enumerate() {
+        let data = if file == "-" {
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut data)?;
+            data
+        } else {
+            fs::read(file).map_err(|error| anyhow::anyhow!("could not open '{}' for reading: {}", file, error))?
+        };
+        if is_binary(&data) {
+            if !quiet {
+                eprintln!("error: Cannot merge binary files: {}", file);
+            }
+            std::process::exit(255);
+        }
+        if names.len() <= position {
+            names.push(file.clone());
+        }
+        contents.push(data);
+    }
+    let labels = [Some(names[1].as_str()), Some(names[0].as_str()), Some(names[2].as_str())];
+    let (merged, conflicts) = merge_file(&contents[1], &contents[0], &contents[2], labels, &options);
+
+    if to_stdout {
+        std::io::stdout().write_all(&merged)?;
+    } else {
+        fs::write(&files[0], &merged).map_err(|error| anyhow::anyhow!("Could not write to {}: {}", files[0], error))?;
+    }
+    if conflicts > 0 {
+        std::process::exit(conflicts.min(127) as i32);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str, options: MergeFileOptions) -> (String, usize) {
+        let labels = [Some("base"), Some("ours"), Some("theirs")];
+        let (merged, conflicts) = merge_file(base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), labels, &options);
+        (String::from_utf8(merged).unwrap(), conflicts)
+    }
+
+    fn style(style: Style) -> MergeFileOptions {
+        MergeFileOptions { style, ..MergeFileOptions::default() }
+    }
+
+    #[test]
+    fn clean_merges() {
+        let base = "1\n2\n3\n4\n5\n6\n";
+        let merged = merge(base, "1\ntwo\n3\n4\n5\n6\n", "1\n2\n3\n4\n5\nsix\n", MergeFileOptions::default());
+        assert_eq!(merged, ("1\ntwo\n3\n4\n5\nsix\n".to_string(), 0));
+        // The same change made on both sides merges cleanly
+        let merged = merge("1\n2\n3\n", "1\nnew\n3\n", "1\nnew\n3\n", MergeFileOptions::default());
+        assert_eq!(merged, ("1\nnew\n3\n".to_string(), 0));
+        assert_eq!(merge(base, base, "x\n", MergeFileOptions::default()), ("x\n".to_string(), 0));
+    }
+
+    #[test]
+    fn merge_markers() {
+        let merged = merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", style(Style::Merge));
+        assert_eq!(merged, ("a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nc\n".to_string(), 1));
+        // Lines both sides added alike are taken out of the conflict
+        let merged = merge("a\nb\nc\n", "a\nx\ny\nz\nc\n", "a\nx\nw\nz\nc\n", style(Style::Merge));
+        assert_eq!(merged, ("a\nx\n<<<<<<< ours\ny\n=======\nw\n>>>>>>> theirs\nz\nc\n".to_string(), 1));
+    }
+
+    #[test]
+    fn diff3_markers() {
+        let merged = merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", style(Style::Diff3));
+        assert_eq!(merged, ("a\n<<<<<<< ours\nx\n||||||| base\nb\n=======\ny\n>>>>>>> theirs\nc\n".to_string(), 1));
+        // The base only makes sense next to whole sides, so nothing is narrowed
+        let merged = merge("a\nb\nc\n", "a\nx\ny\nz\nc\n", "a\nx\nw\nz\nc\n", style(Style::Diff3));
+        let expected = "a\n<<<<<<< ours\nx\ny\nz\n||||||| base\nb\n=======\nx\nw\nz\n>>>>>>> theirs\nc\n";
+        assert_eq!(merged, (expected.to_string(), 1));
+    }
+
+    #[test]
+    fn zdiff3_markers() {
+        let merged = merge("a\nb\nc\n", "a\nx\ny\nz\nc\n", "a\nx\nw\nz\nc\n", style(Style::ZealousDiff3));
+        let expected = "a\nx\n<<<<<<< ours\ny\n||||||| base\nb\n=======\nw\n>>>>>>> theirs\nz\nc\n";
+        assert_eq!(merged, (expected.to_string(), 1));
+    }
+
+    #[test]
+    fn joined_conflicts() {
+        // Conflicts a few lines apart become one, here with shorter markers
+        let options = MergeFileOptions { marker_size: 3, ..MergeFileOptions::default() };
+        let merged = merge("a\nb\nc\nd\ne\n", "a\nB\nc\nD\ne\n", "a\nb2\nc\nd2\ne\n", options);
+        assert_eq!(merged, ("a\n<<< ours\nB\nc\nD\n===\nb2\nc\nd2\n>>> theirs\ne\n".to_string(), 1));
+    }
+
+    #[test]
+    fn favored_sides() {
+        let favor = |favor| MergeFileOptions { favor: Some(favor), ..MergeFileOptions::default() };
+        assert_eq!(merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", favor(Favor::Union)), ("a\nx\ny\nc\n".to_string(), 0));
+        assert_eq!(merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", favor(Favor::Ours)), ("a\nx\nc\n".to_string(), 0));
+        assert_eq!(merge("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", favor(Favor::Theirs)), ("a\ny\nc\n".to_string(), 0));
+    }
+
+    #[test]
+    fn ignored_whitespace() {
+        let normalize = |whitespace: Whitespace, line: &str| String::from_utf8(whitespace.normalize(line.as_bytes()).into_owned()).unwrap();
+        assert_eq!(normalize(Whitespace { all: true, ..Whitespace::default() }, " a \t b\n"), "ab");
+        assert_eq!(normalize(Whitespace { change: true, ..Whitespace::default() }, "a \t b  \n"), "a b");
+        assert_eq!(normalize(Whitespace { at_eol: true, ..Whitespace::default() }, "a  b \r\n"), "a  b");
+        assert_eq!(normalize(Whitespace { cr_at_eol: true, ..Whitespace::default() }, "a \r\n"), "a ");
+    }
+}